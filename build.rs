@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The gRPC service is generated from `proto/ownership.proto` with the vendored `protoc`, so
+    // building the crate needs no protobuf compiler installed.
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/ownership.proto"], &["proto"])?;
+    }
+
+    Ok(())
+}
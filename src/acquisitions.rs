@@ -0,0 +1,189 @@
+use crate::{amount::Amount, decimals::DecimalsRegistry, store::Store, RpcTransport, WorkerConfig};
+use futures::{stream, StreamExt, TryStreamExt};
+use mongodb::bson::DateTime;
+use std::{
+    collections::{BTreeSet, HashMap},
+    error, fmt,
+    io::{self, Write},
+};
+use web3::{
+    types::{BlockId, BlockNumber, H160, H256, U64},
+    Web3,
+};
+
+/// Whether a transfer brought tokens to the owner or took them away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquisitionKind {
+    Acquisition,
+    Disposal,
+}
+
+impl fmt::Display for AcquisitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcquisitionKind::Acquisition => write!(f, "acquisition"),
+            AcquisitionKind::Disposal => write!(f, "disposal"),
+        }
+    }
+}
+
+/// A transfer from or to an owner, from the owner's point of view.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcquisitionRecord {
+    /// Unix timestamp of the block in seconds.
+    pub timestamp: u64,
+    pub block_number: u64,
+    pub transaction_hash: H256,
+    pub log_index: i64,
+    pub kind: AcquisitionKind,
+    pub contract_address: H160,
+    pub token_type: String,
+    pub token_id: Option<String>,
+    /// Transferred amount in the smallest unit of the token.
+    pub amount: Amount,
+    /// Decimals of the contract, its override or the `decimals()` of ERC20 contracts, when known.
+    pub decimals: Option<u32>,
+    /// Sender of an acquisition or recipient of a disposal, the zero address for mints and burns.
+    /// Revealed when owners are encrypted, and left as stored when they are hashed.
+    pub counterparty: H160,
+    /// Balance of the token after the transfer, counted from the first recorded transfer.
+    pub balance: Amount,
+}
+
+/// Reads the acquisitions and disposals of `owner` from the transfer history, restricted to
+/// `contracts` when any are given, for the blocks from `from_block` to `to_block` when set.
+///
+/// Balances count every recorded transfer up to the block, including the ones before
+/// `from_block`. Transfers from the owner to itself change nothing and are left out. Blocks are
+/// dated with the timestamps the provider returns, `backfill_concurrency` blocks at a time.
+pub(crate) async fn history(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    config: &WorkerConfig,
+    owner: H160,
+    contracts: &[H160],
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+) -> Result<Vec<AcquisitionRecord>, Box<dyn error::Error + Send + Sync>> {
+    let owner_protection = &config.owner_protection;
+    let stored_owner = owner_protection.protect(owner);
+
+    let transfers: Vec<_> = store
+        .owner_transfers(stored_owner, contracts, to_block.map(U64::from))
+        .await?
+        .try_collect()
+        .await?;
+
+    let decimals = DecimalsRegistry::new(store, &config.decimals_overrides);
+    let mut balances: HashMap<(H160, Option<String>), Amount> = HashMap::new();
+    let mut records = Vec::new();
+
+    for transfer in transfers {
+        if transfer.from == transfer.to {
+            continue;
+        }
+
+        let amount = transfer
+            .amount
+            .unwrap_or_else(|| Amount::from_f64(transfer.quantity));
+        let (kind, counterparty, change) = if transfer.to == stored_owner {
+            (AcquisitionKind::Acquisition, transfer.from, amount)
+        } else {
+            (AcquisitionKind::Disposal, transfer.to, -amount)
+        };
+
+        let balance = balances
+            .entry((transfer.contract_address, transfer.token_id.clone()))
+            .or_default();
+        *balance = *balance + change;
+
+        if from_block.is_some_and(|from_block| (transfer.block_number as u64) < from_block) {
+            continue;
+        }
+
+        let contract_decimals = decimals.decimals(transfer.contract_address).await?;
+
+        records.push(AcquisitionRecord {
+            timestamp: 0,
+            block_number: transfer.block_number as u64,
+            transaction_hash: transfer.transaction_hash,
+            log_index: transfer.log_index,
+            kind,
+            contract_address: transfer.contract_address,
+            token_type: transfer.token_type,
+            token_id: transfer.token_id,
+            amount,
+            decimals: contract_decimals,
+            counterparty: if counterparty == H160::zero() {
+                counterparty
+            } else {
+                owner_protection
+                    .reveal(counterparty)
+                    .unwrap_or(counterparty)
+            },
+            balance: *balance,
+        });
+    }
+
+    let block_numbers: BTreeSet<_> = records.iter().map(|record| record.block_number).collect();
+    let timestamps: HashMap<u64, u64> = stream::iter(block_numbers)
+        .map(|block_number| async move {
+            let block = web3
+                .eth()
+                .block(BlockId::Number(BlockNumber::Number(block_number.into())))
+                .await?
+                .ok_or_else(|| format!("Block {} not found", block_number))?;
+
+            Ok::<_, Box<dyn error::Error + Send + Sync>>((block_number, block.timestamp.as_u64()))
+        })
+        .buffer_unordered(config.backfill_concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    for record in &mut records {
+        record.timestamp = timestamps[&record.block_number];
+    }
+
+    Ok(records)
+}
+
+/// Writes records as CSV with a header line. Dates are in RFC 3339, amounts are exact, in whole
+/// units of the token when its decimals are known and in its smallest unit otherwise.
+pub fn write_acquisitions_csv(
+    records: &[AcquisitionRecord],
+    mut writer: impl Write,
+) -> io::Result<()> {
+    writeln!(
+        writer,
+        "date,block_number,transaction_hash,log_index,kind,contract_address,token_type,token_id,quantity,amount,decimals,counterparty,balance"
+    )?;
+
+    for record in records {
+        let quantity = |amount: Amount| match record.decimals {
+            Some(decimals) => amount.to_decimal_string(decimals),
+            None => amount.to_string(),
+        };
+
+        writeln!(
+            writer,
+            "{},{},{:#x},{},{},{:#x},{},{},{},{},{},{:#x},{}",
+            DateTime::from_millis(record.timestamp as i64 * 1000).to_rfc3339_string(),
+            record.block_number,
+            record.transaction_hash,
+            record.log_index,
+            record.kind,
+            record.contract_address,
+            record.token_type,
+            record.token_id.as_deref().unwrap_or_default(),
+            quantity(record.amount),
+            record.amount,
+            record
+                .decimals
+                .map_or(String::new(), |decimals| decimals.to_string()),
+            record.counterparty,
+            quantity(record.balance),
+        )?;
+    }
+
+    Ok(())
+}
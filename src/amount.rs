@@ -0,0 +1,175 @@
+use crate::approvals::u256_to_f64;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    ops::{Add, Neg, Sub},
+    str::FromStr,
+};
+use web3::types::U256;
+
+/// An exact signed token quantity, wide enough for any `uint256` amount a contract emits.
+///
+/// Amounts are stored as decimal strings, since MongoDB has no 256-bit integer type, so sums are
+/// computed here rather than with `$inc`. Negative amounts only appear in deltas and in the
+/// balances of contracts whose events do not add up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Amount {
+    /// Never set for zero, so every amount has a single representation.
+    negative: bool,
+    magnitude: U256,
+}
+
+impl Amount {
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_zero()
+    }
+
+    pub fn is_positive(&self) -> bool {
+        !self.negative && !self.is_zero()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn magnitude(&self) -> U256 {
+        self.magnitude
+    }
+
+    /// The amount as a float, for queries, aggregates and comparisons that tolerate rounding.
+    pub fn to_f64(&self) -> f64 {
+        let value = u256_to_f64(self.magnitude);
+
+        if self.negative {
+            -value
+        } else {
+            value
+        }
+    }
+
+    /// The amount in whole units of a token with `decimals` decimals, exactly, such as `1.5` for
+    /// `1500000` with 6 decimals. Trailing zeros of the fraction are left out.
+    pub fn to_decimal_string(&self, decimals: u32) -> String {
+        let digits = self.magnitude.to_string();
+        let decimals = decimals as usize;
+        let digits = format!("{:0>width$}", digits, width = decimals + 1);
+        let (units, fraction) = digits.split_at(digits.len() - decimals);
+        let fraction = fraction.trim_end_matches('0');
+        let sign = if self.negative { "-" } else { "" };
+
+        if fraction.is_empty() {
+            format!("{}{}", sign, units)
+        } else {
+            format!("{}{}.{}", sign, units, fraction)
+        }
+    }
+
+    /// Rounds a float quantity to the nearest amount, for quantities stored before exact amounts
+    /// were. Quantities that are not finite are taken as zero.
+    pub(crate) fn from_f64(quantity: f64) -> Self {
+        if !quantity.is_finite() {
+            return Self::zero();
+        }
+
+        // Formatting without decimals prints the exact integer the float rounds to.
+        format!("{:.0}", quantity).parse().unwrap_or_default()
+    }
+
+    fn new(negative: bool, magnitude: U256) -> Self {
+        Self {
+            negative: negative && !magnitude.is_zero(),
+            magnitude,
+        }
+    }
+}
+
+impl From<U256> for Amount {
+    fn from(magnitude: U256) -> Self {
+        Self::new(false, magnitude)
+    }
+}
+
+impl Neg for Amount {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self::new(!self.negative, self.magnitude)
+    }
+}
+
+/// Saturates at a magnitude of `2^256 - 1`, which only the balances of contracts emitting
+/// arbitrary amounts can reach.
+impl Add for Amount {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        if self.negative == other.negative {
+            return Self::new(
+                self.negative,
+                self.magnitude.saturating_add(other.magnitude),
+            );
+        }
+
+        if self.magnitude >= other.magnitude {
+            Self::new(self.negative, self.magnitude - other.magnitude)
+        } else {
+            Self::new(other.negative, other.magnitude - self.magnitude)
+        }
+    }
+}
+
+impl Sub for Amount {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self + -other
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            write!(f, "-{}", self.magnitude)
+        } else {
+            write!(f, "{}", self.magnitude)
+        }
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match value.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value),
+        };
+
+        if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(format!("Invalid amount {}", value));
+        }
+
+        let magnitude =
+            U256::from_dec_str(digits).map_err(|_| format!("Invalid amount {}", value))?;
+
+        Ok(Self::new(negative, magnitude))
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
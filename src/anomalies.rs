@@ -0,0 +1,85 @@
+//! Data quality issues of every contract, summarized for a single triage view.
+
+use crate::{openapi, store::Store};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+use web3::types::H160;
+
+/// Number of issues of each kind found for a contract.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub(crate) struct ContractAnomalies {
+    #[schema(value_type = openapi::Address)]
+    pub contract_address: H160,
+    /// Ownerships with a negative quantity.
+    pub negative_balances: i64,
+    /// Supplies that went below zero.
+    pub negative_supplies: i64,
+    /// Contract or token metadata given up on.
+    pub failed_metadata: i64,
+    /// Block ranges on which the quorum provider disagreed about the contract's logs.
+    pub quorum_mismatches: i64,
+    /// Logs that could not be decoded.
+    pub dead_letters: i64,
+}
+
+impl ContractAnomalies {
+    pub(crate) fn total(&self) -> i64 {
+        self.negative_balances
+            + self.negative_supplies
+            + self.failed_metadata
+            + self.quorum_mismatches
+            + self.dead_letters
+    }
+}
+
+/// Every contract with at least one issue, those with the most issues first.
+pub(crate) async fn summarize(store: &Store) -> mongodb::error::Result<Vec<ContractAnomalies>> {
+    let (negative_balances, negative_supplies, failed_metadata, quorum_mismatches, dead_letters) =
+        tokio::try_join!(
+            store.negative_balance_counts(),
+            store.negative_supply_counts(),
+            store.failed_metadata_counts(),
+            store.quorum_mismatch_counts(),
+            store.dead_letter_counts(),
+        )?;
+
+    let mut contracts: BTreeMap<H160, ContractAnomalies> = BTreeMap::new();
+
+    for (contract_address, count) in negative_balances {
+        entry(&mut contracts, contract_address).negative_balances = count;
+    }
+
+    for (contract_address, count) in negative_supplies {
+        entry(&mut contracts, contract_address).negative_supplies = count;
+    }
+
+    for (contract_address, count) in failed_metadata {
+        entry(&mut contracts, contract_address).failed_metadata = count;
+    }
+
+    for (contract_address, count) in quorum_mismatches {
+        entry(&mut contracts, contract_address).quorum_mismatches = count;
+    }
+
+    for (contract_address, count) in dead_letters {
+        entry(&mut contracts, contract_address).dead_letters = count;
+    }
+
+    let mut contracts: Vec<_> = contracts.into_values().collect();
+    contracts.sort_by_key(|anomalies| std::cmp::Reverse(anomalies.total()));
+
+    Ok(contracts)
+}
+
+fn entry(
+    contracts: &mut BTreeMap<H160, ContractAnomalies>,
+    contract_address: H160,
+) -> &mut ContractAnomalies {
+    contracts
+        .entry(contract_address)
+        .or_insert_with(|| ContractAnomalies {
+            contract_address,
+            ..Default::default()
+        })
+}
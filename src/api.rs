@@ -0,0 +1,1860 @@
+use crate::{
+    amount::Amount,
+    anomalies::{self, ContractAnomalies},
+    auth::{self, ApiKey, RateLimiter, Role},
+    capabilities::ProviderCapabilities,
+    checksum::format_checksum,
+    control::WorkerControl,
+    decimals::DecimalsRegistry,
+    graphql::{self, GraphQlRequest},
+    instrumentation::{Instrumentation, SlowOperation},
+    openapi,
+    ownership::OwnershipDelta,
+    privacy::OwnerProtection,
+    processor::{self, REGISTRABLE_TOKEN_TYPES},
+    snapshot::{self, SnapshotHolding},
+    store::{
+        ContractAddress, DeltaFeedEntry, DeltaFeedKind, HolderCursor, HolderSort, Store,
+        TokenMetadata, TokenOwnership, TokenSupply,
+    },
+    verification::{AssertionSigner, AttestationSigner, OwnershipAssertion},
+    webhooks::{WebhookCondition, WebhookMetric, WebhookRule},
+    RpcTransport, WorkerConfig,
+};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use futures::{stream, StreamExt};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::RwLock,
+    time::{sleep, timeout},
+};
+use utoipa::{
+    openapi::{
+        path::Operation,
+        security::{Http, HttpAuthScheme, SecurityRequirement, SecurityScheme},
+        ContentBuilder, ResponseBuilder,
+    },
+    IntoParams, OpenApi, PartialSchema, ToSchema,
+};
+use utoipa_swagger_ui::SwaggerUi;
+use web3::{
+    types::{H160, H256, U256, U64},
+    Web3,
+};
+
+/// Maximum number of delta feed entries returned by a single `/sync/deltas` request.
+const MAX_DELTAS_LIMIT: i64 = 1000;
+
+/// Maximum number of holders returned by a single page of `/owners/{contract}`.
+const MAX_HOLDERS_LIMIT: i64 = 1000;
+
+/// Maximum number of operator actions returned by a single `/control/actions` request.
+const MAX_ACTIONS_LIMIT: i64 = 1000;
+
+/// Maximum number of issues of each kind returned by a single `/control/anomalies/{contract}`
+/// request.
+const MAX_ANOMALIES_LIMIT: i64 = 1000;
+
+/// Time `/changes` waits before looking for new feed entries once it streamed every entry.
+const CHANGES_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time `/healthz` waits for MongoDB and the provider to answer.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub(crate) struct ApiState {
+    pub store: Store,
+    pub decimals: DecimalsRegistry,
+    /// Held for writing by the logs worker while it applies a block, so readers only ever observe
+    /// the ownership model at a block boundary.
+    pub block_lock: Arc<RwLock<()>>,
+    pub delta_feed_enabled: bool,
+    pub latest_block: Arc<Mutex<Option<U64>>>,
+    pub control: Arc<WorkerControl>,
+    /// Keys accepted by the control API, along with the keys stored in `api_keys`.
+    pub api_keys: Arc<[ApiKey]>,
+    /// Whether the endpoints outside the control API also need an API key.
+    pub require_api_key: bool,
+    /// Requests per second allowed to each key without a rate limit of its own, unlimited when
+    /// `None`.
+    pub api_rate_limit: Option<f64>,
+    /// Requests a key may make at once before its rate limit applies.
+    pub api_rate_burst: u32,
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Applied to queried owners so they match the stored ones.
+    pub owner_protection: OwnerProtection,
+    pub assertion_signer: Option<AssertionSigner>,
+    pub attestation_signer: Option<AttestationSigner>,
+    pub instrumentation: Arc<Instrumentation>,
+    pub provider_capabilities: ProviderCapabilities,
+    pub web3: Web3<RpcTransport>,
+    /// Blocks the checkpoint may lag behind `latest_block` while `/readyz` reports ready.
+    pub max_ready_lag: u64,
+    /// Whether the top holders are materialized, which `/contracts/{contract}/top-holders` reads.
+    pub top_holders_enabled: bool,
+    /// Number of holders materialized per contract.
+    pub top_holders_size: i64,
+}
+
+/// Tag of the endpoints open to anyone, even with `require_api_key`.
+const HEALTH_TAG: &str = "health";
+
+/// The endpoints of the API, described from their handlers and the types they exchange. The
+/// handlers of the control API name the role they need in `x-required-role`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Token ownership worker API",
+        description = "Ownership of the indexed tokens, the sync feeds and the control API."
+    ),
+    paths(
+        healthz,
+        readyz,
+        snapshot,
+        deltas,
+        checksum,
+        changes,
+        verify_ownership,
+        contract_decimals,
+        top_holders,
+        owners,
+        balance,
+        token_owner,
+        graphql,
+        status,
+        pause,
+        resume,
+        reindex,
+        register_contract,
+        operator_actions,
+        anomalies,
+        contract_anomalies,
+        webhook_rules,
+        create_webhook_rule,
+        delete_webhook_rule,
+    ),
+    components(schemas(HolderSort))
+)]
+struct ApiDoc;
+
+/// OpenAPI document of the API, with the API key and the responses answered without it added to
+/// the endpoints that need one.
+fn api_document(require_api_key: bool) -> utoipa::openapi::OpenApi {
+    let mut document = ApiDoc::openapi();
+    // The crate declares no license, which would be written as an empty one.
+    document.info.license = None;
+
+    document
+        .components
+        .get_or_insert_with(Default::default)
+        .add_security_scheme(
+            "apiKey",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+
+    for path in document.paths.paths.values_mut() {
+        for operation in [
+            &mut path.get,
+            &mut path.post,
+            &mut path.put,
+            &mut path.delete,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            add_access(operation, require_api_key);
+        }
+    }
+
+    document
+}
+
+/// Adds the API key an operation needs, and the responses of requests failing before its handler.
+fn add_access(operation: &mut Operation, require_api_key: bool) {
+    if operation.tags.iter().flatten().any(|tag| tag == HEALTH_TAG) {
+        return;
+    }
+
+    let role_required = operation
+        .extensions
+        .as_ref()
+        .is_some_and(|extensions| extensions.contains_key("x-required-role"));
+
+    let mut responses = vec![(500, "MongoDB or the provider failed")];
+
+    if role_required || require_api_key {
+        operation.security = Some(vec![SecurityRequirement::new(
+            "apiKey",
+            Vec::<String>::new(),
+        )]);
+        responses.push((401, "Missing or unknown API key"));
+        responses.push((429, "The API key exceeded its rate limit"));
+    }
+
+    if role_required {
+        responses.push((403, "The API key lacks the required role"));
+    }
+
+    for (status, description) in responses {
+        let content = ContentBuilder::new().schema(Some(String::schema())).build();
+        operation.responses.responses.insert(
+            status.to_string(),
+            ResponseBuilder::new()
+                .description(description)
+                .content("text/plain", content)
+                .build()
+                .into(),
+        );
+    }
+}
+
+/// OpenAPI 3.1 document of the API, as served at `/openapi.json`, for generating clients.
+///
+/// The control API needs an API key, sent as a bearer token, with the role named by the
+/// `x-required-role` of each operation, and so do the other endpoints besides the health checks
+/// with `require_api_key`.
+pub fn openapi_document(config: &WorkerConfig) -> serde_json::Value {
+    serde_json::to_value(api_document(config.require_api_key))
+        .expect("OpenAPI documents serialize to JSON")
+}
+
+pub(crate) async fn serve(
+    port: u16,
+    state: ApiState,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let public = Router::new()
+        .route("/sync/snapshot", get(snapshot))
+        .route("/sync/deltas", get(deltas))
+        .route("/sync/checksum", get(checksum))
+        .route("/changes", get(changes))
+        .route("/verify-ownership", get(verify_ownership))
+        .route("/contracts/{contract}/decimals", get(contract_decimals))
+        .route("/contracts/{contract}/top-holders", get(top_holders))
+        .route("/owners/{contract}", get(owners))
+        .route("/balance/{contract}/{owner}", get(balance))
+        .route("/tokens/{contract}/{token_id}/owner", get(token_owner))
+        .route("/graphql", post(graphql));
+    let public = match state.require_api_key {
+        true => public.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_api_key,
+        )),
+        false => public,
+    };
+
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", api_document(state.require_api_key)))
+        .merge(public)
+        .merge(with_role(
+            Router::new()
+                .route("/control/status", get(status))
+                .route("/control/webhooks", get(webhook_rules))
+                .route("/control/anomalies", get(anomalies))
+                .route("/control/anomalies/{contract}", get(contract_anomalies)),
+            &state,
+            Role::Viewer,
+        ))
+        .merge(with_role(
+            Router::new()
+                .route("/control/pause", post(pause))
+                .route("/control/resume", post(resume))
+                .route("/control/webhooks", post(create_webhook_rule))
+                .route("/control/webhooks/{id}", delete(delete_webhook_rule)),
+            &state,
+            Role::Operator,
+        ))
+        .merge(with_role(
+            Router::new()
+                .route("/control/reindex", post(reindex))
+                .route("/control/contracts/{contract}", put(register_contract))
+                .route("/control/actions", get(operator_actions)),
+            &state,
+            Role::Admin,
+        ))
+        .with_state(state);
+
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+
+    println!("API listening on port {}", port);
+
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+/// Whether a dependency answered a health check.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum Check {
+    Ok,
+    Failed,
+}
+
+/// Outcome of the health check of each dependency.
+#[derive(Debug, Serialize, ToSchema)]
+struct Health {
+    mongodb: Check,
+    rpc: Check,
+}
+
+/// Liveness probe: answers `200 OK` while MongoDB and the provider both answer within
+/// `HEALTH_CHECK_TIMEOUT`, and `503 Service Unavailable` naming the failing one otherwise.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = HEALTH_TAG,
+    responses(
+        (status = 200, description = "MongoDB and the provider answer", body = Health),
+        (status = 503, description = "MongoDB or the provider do not answer", body = Health),
+    )
+)]
+async fn healthz(State(state): State<ApiState>) -> Response {
+    let (mongodb, rpc) = tokio::join!(
+        timeout(HEALTH_CHECK_TIMEOUT, state.store.ping()),
+        timeout(HEALTH_CHECK_TIMEOUT, state.web3.eth().block_number()),
+    );
+
+    let mongodb = match mongodb {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) => Some(error.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+    let rpc = match rpc {
+        Ok(Ok(_)) => None,
+        Ok(Err(error)) => Some(error.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+
+    for (dependency, error) in [("MongoDB", &mongodb), ("the provider", &rpc)] {
+        if let Some(error) = error {
+            eprintln!(
+                "Error: Health check could not reach {}... {}",
+                dependency, error
+            );
+        }
+    }
+
+    let check = |error: &Option<String>| match error {
+        None => Check::Ok,
+        Some(_) => Check::Failed,
+    };
+
+    (
+        if mongodb.is_none() && rpc.is_none() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(Health {
+            mongodb: check(&mongodb),
+            rpc: check(&rpc),
+        }),
+    )
+        .into_response()
+}
+
+/// How far the checkpoint lags behind the chain head.
+#[derive(Debug, Serialize, ToSchema)]
+struct Readiness {
+    ready: bool,
+    last_processed_block: Option<u64>,
+    latest_block: Option<u64>,
+    /// Blocks between the checkpoint and the chain head, null while either is unknown.
+    lag: Option<u64>,
+    /// Lag up to which the worker reports ready.
+    max_lag: u64,
+}
+
+/// Readiness probe: answers `200 OK` while the checkpoint is at most `max_ready_lag` blocks behind
+/// the chain head, and `503 Service Unavailable` while the worker catches up, before the head is
+/// known and when the checkpoint cannot be read.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = HEALTH_TAG,
+    responses(
+        (status = 200, description = "The worker is caught up", body = Readiness),
+        (status = 503, description = "The worker is catching up", body = Readiness),
+    )
+)]
+async fn readyz(State(state): State<ApiState>) -> Response {
+    let last_processed_block = state.store.last_processed_block().await;
+    let latest_block = *state.latest_block.lock().unwrap();
+
+    let lag = match (&last_processed_block, latest_block) {
+        (Ok(Some(last_processed_block)), Some(latest_block)) => {
+            Some(latest_block.saturating_sub(*last_processed_block).as_u64())
+        }
+        _ => None,
+    };
+    let ready = lag.is_some_and(|lag| lag <= state.max_ready_lag);
+
+    if let Err(error) = &last_processed_block {
+        eprintln!("Error: Readiness check failed... {}", error);
+    }
+
+    (
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(Readiness {
+            ready,
+            last_processed_block: last_processed_block
+                .ok()
+                .flatten()
+                .map(|block_number| block_number.as_u64()),
+            latest_block: latest_block.map(|block_number| block_number.as_u64()),
+            lag,
+            max_lag: state.max_ready_lag,
+        }),
+    )
+        .into_response()
+}
+
+/// First line of a snapshot: the delta feed sequence to follow it from, and its block.
+#[derive(Debug, Serialize, ToSchema)]
+struct SnapshotHeader {
+    sequence: i64,
+    block_number: Option<u64>,
+}
+
+/// A line of a snapshot, the header then the ownerships.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+enum SnapshotLine {
+    Header(SnapshotHeader),
+    Ownership(TokenOwnership),
+}
+
+/// Streams every token ownership as newline delimited JSON, preceded by a header line with the
+/// block and delta feed sequence the snapshot was taken at. Consumers continue from that sequence
+/// with `/sync/deltas`.
+///
+/// The logs worker is paused for as long as the snapshot is being streamed.
+#[utoipa::path(
+    get,
+    path = "/sync/snapshot",
+    tag = "sync",
+    responses(
+        (
+            status = 200,
+            description = "A header line, then an ownership per line",
+            content_type = "application/x-ndjson",
+            body = SnapshotLine,
+        ),
+        (status = 404, description = "Differential sync is disabled, start the worker with --delta-feed-retention", body = String),
+    )
+)]
+async fn snapshot(State(state): State<ApiState>) -> Response {
+    if !state.delta_feed_enabled {
+        return differential_sync_disabled();
+    }
+
+    let guard = state.block_lock.clone().read_owned().await;
+
+    let (sequence, block_number, ownerships) = match tokio::try_join!(
+        state.store.latest_feed_sequence(),
+        state.store.last_processed_block(),
+        state.store.ownerships(),
+    ) {
+        Ok(snapshot) => snapshot,
+        Err(error) => return internal_error(error),
+    };
+
+    let header = SnapshotLine::Header(SnapshotHeader {
+        sequence,
+        block_number: block_number.map(|block_number| block_number.as_u64()),
+    });
+
+    let lines = stream::once(async move { Ok(json_line(&header)) })
+        .chain(ownerships.map(|ownership| {
+            ownership.map(|ownership| json_line(&SnapshotLine::Ownership(ownership)))
+        }))
+        .map(move |line| {
+            let _ = &guard;
+            line
+        });
+
+    Body::from_stream(lines).into_response()
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct DeltasQuery {
+    pub after: i64,
+    pub limit: Option<i64>,
+}
+
+/// Entries of the delta feed, in order.
+#[derive(Debug, Serialize, ToSchema)]
+struct DeltaFeedPage {
+    entries: Vec<DeltaFeedEntry>,
+}
+
+/// Returns the delta feed entries following the `after` sequence, in order.
+#[utoipa::path(
+    get,
+    path = "/sync/deltas",
+    tag = "sync",
+    params(DeltasQuery),
+    responses(
+        (status = 200, description = "Up to `limit` entries", body = DeltaFeedPage),
+        (status = 404, description = "Differential sync is disabled, start the worker with --delta-feed-retention", body = String),
+        (status = 410, description = "The requested deltas have been pruned or the feed was reset, request a new snapshot", body = String),
+    )
+)]
+async fn deltas(State(state): State<ApiState>, Query(query): Query<DeltasQuery>) -> Response {
+    if !state.delta_feed_enabled {
+        return differential_sync_disabled();
+    }
+
+    match feed_gap(&state.store, query.after).await {
+        Ok(Some(gap)) => return (StatusCode::GONE, gap).into_response(),
+        Ok(None) => {}
+        Err(error) => return internal_error(error),
+    }
+
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_DELTAS_LIMIT);
+
+    match state.store.feed_entries(query.after, limit).await {
+        Ok(entries) => Json(DeltaFeedPage { entries }).into_response(),
+        Err(error) => internal_error(error),
+    }
+}
+
+/// Why the delta feed cannot be followed after the `after` sequence, `None` when it can.
+async fn feed_gap(store: &Store, after: i64) -> mongodb::error::Result<Option<String>> {
+    let (oldest_sequence, latest_sequence) =
+        tokio::try_join!(store.oldest_feed_sequence(), store.latest_feed_sequence())?;
+
+    if oldest_sequence.is_some_and(|oldest_sequence| after + 1 < oldest_sequence) {
+        Ok(Some(format!(
+            "Deltas after sequence {} have been pruned, request a new snapshot",
+            after
+        )))
+    } else if after > latest_sequence {
+        Ok(Some(format!(
+            "Sequence {} is ahead of the feed, which has been reset, request a new snapshot",
+            after
+        )))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ChangesQuery {
+    #[param(value_type = Option<openapi::Address>)]
+    pub contract: Option<H160>,
+    #[param(value_type = Option<openapi::Address>)]
+    pub owner: Option<H160>,
+    pub after: Option<i64>,
+}
+
+/// Streams the delta feed entries following the `after` sequence, or the `Last-Event-ID` a
+/// reconnecting client sends, then every entry committed from then on, as server-sent events.
+/// Only the deltas of `contract` and `owner` are sent when given, and entries left without deltas
+/// are skipped.
+#[utoipa::path(
+    get,
+    path = "/changes",
+    tag = "sync",
+    params(
+        ChangesQuery,
+        ("Last-Event-ID" = Option<i64>, Header, description = "Sequence of the last event received"),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "An `apply` or `revert` event per entry, or a `reset` event once the \
+                           entries to send next are pruned",
+            content_type = "text/event-stream",
+            body = DeltaFeedEntry,
+        ),
+        (status = 404, description = "Differential sync is disabled, start the worker with --delta-feed-retention", body = String),
+        (status = 410, description = "The requested deltas have been pruned or the feed was reset, request a new snapshot", body = String),
+    )
+)]
+async fn changes(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<ChangesQuery>,
+) -> Response {
+    if !state.delta_feed_enabled {
+        return differential_sync_disabled();
+    }
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+
+    let after = match last_event_id.or(query.after) {
+        Some(after) => match feed_gap(&state.store, after).await {
+            Ok(Some(gap)) => return (StatusCode::GONE, gap).into_response(),
+            Ok(None) => after,
+            Err(error) => return internal_error(error),
+        },
+        None => match state.store.latest_feed_sequence().await {
+            Ok(latest_sequence) => latest_sequence,
+            Err(error) => return internal_error(error),
+        },
+    };
+
+    let owner = query
+        .owner
+        .map(|owner| state.owner_protection.protect(owner));
+    let matches = move |delta: &OwnershipDelta| {
+        query
+            .contract
+            .is_none_or(|contract| delta.contract_address == contract)
+            && owner.is_none_or(|owner| delta.owner == owner)
+    };
+
+    let events = stream::unfold(
+        Some((after, VecDeque::new())),
+        move |cursor: Option<(i64, VecDeque<DeltaFeedEntry>)>| {
+            let store = state.store.clone();
+
+            async move {
+                let (mut after, mut pending) = cursor?;
+
+                loop {
+                    while let Some(mut entry) = pending.pop_front() {
+                        after = entry.sequence;
+                        entry.deltas.retain(|delta| matches(delta));
+
+                        if !entry.deltas.is_empty() {
+                            let event = Event::default()
+                                .id(entry.sequence.to_string())
+                                .event(match entry.kind {
+                                    DeltaFeedKind::Apply => "apply",
+                                    DeltaFeedKind::Revert => "revert",
+                                })
+                                .json_data(&entry);
+
+                            return Some((event, Some((after, pending))));
+                        }
+                    }
+
+                    // A client reading slower than the feed is pruned has missed entries.
+                    match feed_gap(&store, after).await {
+                        Ok(Some(gap)) => {
+                            return Some((Ok(Event::default().event("reset").data(gap)), None))
+                        }
+                        Ok(None) => {}
+                        Err(error) => {
+                            eprintln!("Error: Could not stream the delta feed... {}", error);
+                            return None;
+                        }
+                    }
+
+                    match store.feed_entries(after, MAX_DELTAS_LIMIT).await {
+                        Ok(entries) if entries.is_empty() => sleep(CHANGES_POLL_INTERVAL).await,
+                        Ok(entries) => pending.extend(entries),
+                        Err(error) => {
+                            eprintln!("Error: Could not stream the delta feed... {}", error);
+                            return None;
+                        }
+                    }
+                }
+            }
+        },
+    );
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Checksum of the ownership model and of each contract.
+#[derive(Debug, Serialize, ToSchema)]
+struct Checksums {
+    block_number: Option<u64>,
+    checksum: String,
+    /// Checksum of each contract, by address.
+    contracts: BTreeMap<String, String>,
+}
+
+/// Returns the checksum of the ownership model and of each contract, with the block they were
+/// taken at. Deployments whose checksums differ at the same block disagree on the contracts whose
+/// checksums differ.
+#[utoipa::path(
+    get,
+    path = "/sync/checksum",
+    tag = "sync",
+    responses(
+        (status = 200, description = "The checksums, at the block they were taken at", body = Checksums),
+    )
+)]
+async fn checksum(State(state): State<ApiState>) -> Response {
+    let _guard = state.block_lock.read().await;
+
+    let (block_number, stats) = match tokio::try_join!(
+        state.store.last_processed_block(),
+        state.store.checksummed_contract_stats(),
+    ) {
+        Ok(checksums) => checksums,
+        Err(error) => return internal_error(error),
+    };
+
+    let checksum = stats
+        .iter()
+        .fold(0, |checksum, stats| checksum ^ stats.checksum);
+    let contracts = stats
+        .iter()
+        .map(|stats| {
+            (
+                format!("{:#x}", stats.contract_address),
+                format_checksum(stats.checksum),
+            )
+        })
+        .collect();
+
+    Json(Checksums {
+        block_number: block_number.map(|block_number| block_number.as_u64()),
+        checksum: format_checksum(checksum),
+        contracts,
+    })
+    .into_response()
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct VerifyOwnershipQuery {
+    #[param(value_type = openapi::Address)]
+    pub owner: H160,
+    #[param(value_type = openapi::Address)]
+    pub contract: H160,
+    pub token_id: Option<String>,
+    pub min_quantity: Option<f64>,
+}
+
+/// An ownership assertion, with the message signed and the signatures of the configured signers.
+#[derive(Debug, Serialize, ToSchema)]
+struct SignedAssertion {
+    assertion: OwnershipAssertion,
+    message: String,
+    /// HMAC-SHA256 of `message` with the verification key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attestation: Option<Attestation>,
+}
+
+/// EIP-191 signature of an assertion's message with the attestation key.
+#[derive(Debug, Serialize, ToSchema)]
+struct Attestation {
+    #[schema(value_type = openapi::Address)]
+    signer: H160,
+    signature: String,
+}
+
+/// Answers whether an owner holds a token of a contract, or a specific token when `token_id` is
+/// given, with at least `min_quantity` of it. The answer is signed together with the block it was
+/// evaluated at and the time it was issued, with the HMAC key and as an EIP-191 attestation with
+/// the worker's key, whichever are configured.
+#[utoipa::path(
+    get,
+    path = "/verify-ownership",
+    tag = "ownership",
+    params(VerifyOwnershipQuery),
+    responses(
+        (status = 200, description = "The signed assertion", body = SignedAssertion),
+        (
+            status = 404,
+            description = "Ownership verification is disabled, start the worker with \
+                           --verification-key or --attestation-key",
+            body = String,
+        ),
+    )
+)]
+async fn verify_ownership(
+    State(state): State<ApiState>,
+    Query(query): Query<VerifyOwnershipQuery>,
+) -> Response {
+    if state.assertion_signer.is_none() && state.attestation_signer.is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            "Ownership verification is disabled, start the worker with --verification-key or --attestation-key",
+        )
+            .into_response();
+    }
+
+    let owner = state.owner_protection.protect(query.owner);
+
+    let _guard = state.block_lock.read().await;
+
+    let quantity = match &query.token_id {
+        Some(token_id) => {
+            state
+                .store
+                .ownership_quantity(query.contract, Some(token_id), owner)
+                .await
+        }
+        None => state.store.owner_quantity(query.contract, owner).await,
+    };
+
+    let quantity = match quantity {
+        Ok(quantity) => quantity,
+        Err(error) => return internal_error(error),
+    };
+
+    let block_number = match state.store.last_processed_block().await {
+        Ok(block_number) => block_number,
+        Err(error) => return internal_error(error),
+    };
+
+    let assertion = OwnershipAssertion {
+        owner: query.owner,
+        contract_address: query.contract,
+        token_id: query.token_id,
+        min_quantity: query.min_quantity,
+        owns: match query.min_quantity {
+            Some(min_quantity) => quantity >= min_quantity,
+            None => quantity > 0.0,
+        },
+        block_number: block_number.map(|block_number| block_number.as_u64()),
+        issued_at: DateTime::now().timestamp_millis() / 1000,
+    };
+
+    Json(SignedAssertion {
+        message: assertion.message(),
+        signature: state
+            .assertion_signer
+            .as_ref()
+            .map(|signer| signer.sign(&assertion)),
+        attestation: state.attestation_signer.as_ref().map(|signer| Attestation {
+            signer: signer.address(),
+            signature: signer.sign(&assertion),
+        }),
+        assertion,
+    })
+    .into_response()
+}
+
+/// Decimals the amounts of a contract are rendered with.
+#[derive(Debug, Serialize, ToSchema)]
+struct ContractDecimals {
+    #[schema(value_type = openapi::Address)]
+    contract_address: H160,
+    decimals: Option<u32>,
+    overridden: bool,
+}
+
+/// Returns the decimals amounts of a contract are rendered with in whole units, and whether they
+/// come from an override rather than the contract's `decimals()`. `decimals` is null when neither
+/// is known, and amounts are then only rendered in the smallest unit of the token.
+#[utoipa::path(
+    get,
+    path = "/contracts/{contract}/decimals",
+    tag = "ownership",
+    params(("contract" = openapi::Address, Path, description = "Address of the contract")),
+    responses((status = 200, description = "The decimals of the contract", body = ContractDecimals))
+)]
+async fn contract_decimals(State(state): State<ApiState>, Path(contract): Path<H160>) -> Response {
+    match state.decimals.decimals(contract).await {
+        Ok(decimals) => Json(ContractDecimals {
+            contract_address: contract,
+            decimals,
+            overridden: state.decimals.is_overridden(contract),
+        })
+        .into_response(),
+        Err(error) => internal_error(error),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct TopHoldersQuery {
+    pub limit: Option<i64>,
+}
+
+/// Largest holders of a contract, as of the last refresh of the materialized top holders.
+#[derive(Debug, Serialize, ToSchema)]
+struct RankedHolders {
+    #[schema(value_type = openapi::Address)]
+    contract_address: H160,
+    holder_count: Option<i64>,
+    block_number: Option<i64>,
+    #[schema(format = DateTime)]
+    refreshed_at: Option<String>,
+    holders: Vec<RankedHolder>,
+}
+
+/// Quantity of a contract's tokens held by an owner across every token id, and its share of the
+/// supply.
+#[derive(Debug, Serialize, ToSchema)]
+struct RankedHolder {
+    rank: usize,
+    #[schema(value_type = openapi::Address)]
+    owner: H160,
+    amount: Amount,
+    display_amount: Option<String>,
+    share: Option<f64>,
+}
+
+/// Returns the `limit` largest holders of a contract across every token id, with their share of
+/// the supply, as of the last refresh of the materialized top holders. `refreshed_at` and
+/// `block_number` are null, and `holders` empty, until a refresh materialized the contract.
+#[utoipa::path(
+    get,
+    path = "/contracts/{contract}/top-holders",
+    tag = "ownership",
+    params(("contract" = openapi::Address, Path, description = "Address of the contract"), TopHoldersQuery),
+    responses(
+        (status = 200, description = "Up to `limit` holders, largest first", body = RankedHolders),
+        (
+            status = 404,
+            description = "Top holders are disabled, start the worker with \
+                           --top-holders-refresh-interval",
+            body = String,
+        ),
+    )
+)]
+async fn top_holders(
+    State(state): State<ApiState>,
+    Path(contract): Path<H160>,
+    Query(query): Query<TopHoldersQuery>,
+) -> Response {
+    if !state.top_holders_enabled {
+        return (
+            StatusCode::NOT_FOUND,
+            "Top holders are disabled, start the worker with --top-holders-refresh-interval",
+        )
+            .into_response();
+    }
+
+    let limit = query.limit.unwrap_or(100).clamp(1, state.top_holders_size);
+
+    let (top_holders, decimals) = match tokio::try_join!(
+        state.store.top_holders(contract),
+        state.decimals.decimals(contract),
+    ) {
+        Ok(top_holders) => top_holders,
+        Err(error) => return internal_error(error),
+    };
+
+    let holders: Vec<_> = top_holders
+        .iter()
+        .flat_map(|top_holders| {
+            top_holders
+                .holders
+                .iter()
+                .take(limit as usize)
+                .enumerate()
+                .map(|(rank, holder)| {
+                    let amount = holder.exact_amount();
+                    RankedHolder {
+                        rank: rank + 1,
+                        owner: holder.owner,
+                        amount,
+                        display_amount: decimals.map(|decimals| amount.to_decimal_string(decimals)),
+                        share: (top_holders.supply > 0.0)
+                            .then(|| holder.quantity / top_holders.supply),
+                    }
+                })
+        })
+        .collect();
+
+    Json(RankedHolders {
+        contract_address: contract,
+        holder_count: top_holders
+            .as_ref()
+            .map(|top_holders| top_holders.holder_count),
+        block_number: top_holders
+            .as_ref()
+            .and_then(|top_holders| top_holders.block_number),
+        refreshed_at: top_holders
+            .as_ref()
+            .map(|top_holders| top_holders.refreshed_at.to_rfc3339_string()),
+        holders,
+    })
+    .into_response()
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct OwnersQuery {
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub sort: Option<HolderSort>,
+    pub min_quantity: Option<f64>,
+}
+
+/// Holders of a contract, at the block they were read at.
+#[derive(Debug, Serialize, ToSchema)]
+struct ContractHolders {
+    #[schema(value_type = openapi::Address)]
+    contract_address: H160,
+    block_number: Option<u64>,
+    holders: Vec<SnapshotHolding>,
+}
+
+/// A page of the holders of a contract.
+#[derive(Debug, Serialize, ToSchema)]
+struct HoldersPage {
+    #[serde(flatten)]
+    holders: ContractHolders,
+    /// Cursor the following page starts after, null on the last page.
+    next_cursor: Option<String>,
+}
+
+/// Every holder of a contract, or a page of them.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+enum Owners {
+    All(ContractHolders),
+    Page(HoldersPage),
+}
+
+/// Returns the holders of a contract with a positive amount, ordered by token id and owner, at a
+/// block boundary.
+///
+/// With any of `limit`, `cursor`, `sort` or `min_quantity`, returns a page of up to `limit` holders
+/// holding at least `min_quantity` instead, by owner or by balance, largest first, with the
+/// `next_cursor` the following page starts after, null on the last page.
+#[utoipa::path(
+    get,
+    path = "/owners/{contract}",
+    tag = "ownership",
+    params(("contract" = openapi::Address, Path, description = "Address of the contract"), OwnersQuery),
+    responses(
+        (status = 200, description = "The holders, or a page of them", body = Owners),
+        (status = 400, description = "The cursor is invalid or belongs to another sort order", body = String),
+    )
+)]
+async fn owners(
+    State(state): State<ApiState>,
+    Path(contract): Path<H160>,
+    Query(query): Query<OwnersQuery>,
+) -> Response {
+    if query.limit.is_some()
+        || query.cursor.is_some()
+        || query.sort.is_some()
+        || query.min_quantity.is_some()
+    {
+        return owners_page(state, contract, query).await;
+    }
+
+    let _guard = state.block_lock.read().await;
+
+    let holders = match snapshot::current_holdings(&state.store, &state.decimals, contract).await {
+        Ok(holders) => holders,
+        Err(error) => return internal_error(error),
+    };
+
+    let block_number = match state.store.last_processed_block().await {
+        Ok(block_number) => block_number,
+        Err(error) => return internal_error(error),
+    };
+
+    Json(Owners::All(ContractHolders {
+        contract_address: contract,
+        block_number: block_number.map(|block_number| block_number.as_u64()),
+        holders,
+    }))
+    .into_response()
+}
+
+async fn owners_page(state: ApiState, contract: H160, query: OwnersQuery) -> Response {
+    let sort = query.sort.unwrap_or(HolderSort::Owner);
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_HOLDERS_LIMIT);
+
+    let after = match query.cursor.as_deref().map(str::parse::<HolderCursor>) {
+        Some(Ok(cursor)) if cursor.matches(sort) => Some(cursor),
+        Some(Ok(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "The cursor belongs to a page in another sort order",
+            )
+                .into_response()
+        }
+        Some(Err(error)) => return (StatusCode::BAD_REQUEST, error).into_response(),
+        None => None,
+    };
+
+    let decimals = match state.decimals.decimals(contract).await {
+        Ok(decimals) => decimals,
+        Err(error) => return internal_error(error),
+    };
+
+    let _guard = state.block_lock.read().await;
+
+    let mut ownerships = match state
+        .store
+        .contract_holders_page(
+            contract,
+            sort,
+            query.min_quantity.unwrap_or_default(),
+            after.as_ref(),
+            limit + 1,
+        )
+        .await
+    {
+        Ok(ownerships) => ownerships,
+        Err(error) => return internal_error(error),
+    };
+
+    let block_number = match state.store.last_processed_block().await {
+        Ok(block_number) => block_number,
+        Err(error) => return internal_error(error),
+    };
+
+    let next_cursor = (ownerships.len() > limit as usize).then(|| {
+        ownerships.truncate(limit as usize);
+        HolderCursor::following(sort, &ownerships[ownerships.len() - 1]).to_string()
+    });
+
+    let holders: Vec<_> = ownerships
+        .into_iter()
+        .map(|ownership| {
+            let amount = ownership.exact_amount();
+            SnapshotHolding {
+                token_id: ownership.token_id,
+                owner: ownership.owner,
+                amount,
+                display_amount: decimals.map(|decimals| amount.to_decimal_string(decimals)),
+            }
+        })
+        .collect();
+
+    Json(Owners::Page(HoldersPage {
+        holders: ContractHolders {
+            contract_address: contract,
+            block_number: block_number.map(|block_number| block_number.as_u64()),
+            holders,
+        },
+        next_cursor,
+    }))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct BalanceQuery {
+    pub token_id: Option<String>,
+}
+
+/// Amount of a contract's tokens an owner holds.
+#[derive(Debug, Serialize, ToSchema)]
+struct Balance {
+    #[schema(value_type = openapi::Address)]
+    contract_address: H160,
+    #[schema(value_type = openapi::Address)]
+    owner: H160,
+    token_id: Option<String>,
+    amount: Amount,
+    display_amount: Option<String>,
+    block_number: Option<u64>,
+}
+
+/// Returns the amount of a contract's tokens an owner holds across every token id, or of a single
+/// token with `token_id`, zero when the owner holds none.
+#[utoipa::path(
+    get,
+    path = "/balance/{contract}/{owner}",
+    tag = "ownership",
+    params(
+        ("contract" = openapi::Address, Path, description = "Address of the contract"),
+        ("owner" = openapi::Address, Path, description = "Address of the owner"),
+        BalanceQuery,
+    ),
+    responses((status = 200, description = "The balance of the owner", body = Balance))
+)]
+async fn balance(
+    State(state): State<ApiState>,
+    Path((contract, owner)): Path<(H160, H160)>,
+    Query(query): Query<BalanceQuery>,
+) -> Response {
+    let stored_owner = state.owner_protection.protect(owner);
+
+    let _guard = state.block_lock.read().await;
+
+    let amount = match &query.token_id {
+        Some(token_id) => {
+            state
+                .store
+                .ownership_amount(contract, Some(token_id), stored_owner)
+                .await
+        }
+        None => state.store.owner_amount(contract, stored_owner).await,
+    };
+
+    let amount = match amount {
+        Ok(amount) => amount,
+        Err(error) => return internal_error(error),
+    };
+
+    let (block_number, display_amount) = match tokio::try_join!(
+        state.store.last_processed_block(),
+        state.decimals.display_amount(contract, amount),
+    ) {
+        Ok(balance) => balance,
+        Err(error) => return internal_error(error),
+    };
+
+    Json(Balance {
+        contract_address: contract,
+        owner,
+        token_id: query.token_id,
+        amount,
+        display_amount,
+        block_number: block_number.map(|block_number| block_number.as_u64()),
+    })
+    .into_response()
+}
+
+/// Owners of a token id.
+#[derive(Debug, Serialize, ToSchema)]
+struct TokenOwners {
+    #[schema(value_type = openapi::Address)]
+    contract_address: H160,
+    token_id: String,
+    owners: Vec<TokenHolder>,
+    block_number: Option<u64>,
+}
+
+/// Amount of a token id an owner holds.
+#[derive(Debug, Serialize, ToSchema)]
+struct TokenHolder {
+    #[schema(value_type = openapi::Address)]
+    owner: H160,
+    amount: Amount,
+    display_amount: Option<String>,
+}
+
+/// Returns the owners of a token id with a positive amount, a single one for ERC721 tokens and
+/// possibly several for ERC1155 tokens, or `404 Not Found` when nobody holds it.
+#[utoipa::path(
+    get,
+    path = "/tokens/{contract}/{token_id}/owner",
+    tag = "ownership",
+    params(("contract" = openapi::Address, Path, description = "Address of the contract"), ("token_id" = String, Path, description = "Decimal token id")),
+    responses(
+        (status = 200, description = "The owners of the token", body = TokenOwners),
+        (status = 404, description = "Nobody holds this token", body = String),
+    )
+)]
+async fn token_owner(
+    State(state): State<ApiState>,
+    Path((contract, token_id)): Path<(H160, String)>,
+) -> Response {
+    let _guard = state.block_lock.read().await;
+
+    let (block_number, ownerships, decimals) = match tokio::try_join!(
+        state.store.last_processed_block(),
+        state.store.token_owners(contract, &token_id),
+        state.decimals.decimals(contract),
+    ) {
+        Ok(token) => token,
+        Err(error) => return internal_error(error),
+    };
+
+    let owners: Vec<_> = ownerships
+        .iter()
+        .map(|ownership| (ownership.owner, ownership.exact_amount()))
+        .filter(|(_, amount)| amount.is_positive())
+        .map(|(owner, amount)| TokenHolder {
+            owner,
+            amount,
+            display_amount: decimals.map(|decimals| amount.to_decimal_string(decimals)),
+        })
+        .collect();
+
+    if owners.is_empty() {
+        return (StatusCode::NOT_FOUND, "Nobody holds this token").into_response();
+    }
+
+    Json(TokenOwners {
+        contract_address: contract,
+        token_id,
+        owners,
+        block_number: block_number.map(|block_number| block_number.as_u64()),
+    })
+    .into_response()
+}
+
+/// Executes a GraphQL query over the ownership model at a block boundary.
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "ownership",
+    request_body = GraphQlRequest,
+    responses(
+        (status = 200, description = "`data`, along with the `errors` of the fields that failed", body = Object),
+        (status = 400, description = "The query does not parse or validate, with its `errors`", body = Object),
+    )
+)]
+async fn graphql(State(state): State<ApiState>, Json(request): Json<GraphQlRequest>) -> Response {
+    let _guard = state.block_lock.read().await;
+
+    let response = graphql::execute(
+        &state.store,
+        &state.decimals,
+        &state.owner_protection,
+        request,
+    )
+    .await;
+
+    if graphql::is_request_error(&response) {
+        (StatusCode::BAD_REQUEST, Json(response)).into_response()
+    } else {
+        Json(response).into_response()
+    }
+}
+
+fn with_role(router: Router<ApiState>, state: &ApiState, role: Role) -> Router<ApiState> {
+    router.route_layer(middleware::from_fn_with_state(
+        (state.clone(), role),
+        auth::require_role,
+    ))
+}
+
+/// Progress of the logs worker, and whether it is paused.
+#[derive(Debug, Serialize, ToSchema)]
+struct WorkerStatus {
+    paused: bool,
+    reindex_requested: bool,
+    last_processed_block: Option<u64>,
+    latest_block: Option<u64>,
+    slowest_operations: Vec<SlowOperation>,
+    provider: ProviderCapabilities,
+}
+
+/// Reports the progress of the logs worker and whether it is paused.
+#[utoipa::path(
+    get,
+    path = "/control/status",
+    tag = "control",
+    extensions(("x-required-role" = json!("viewer"))),
+    responses((status = 200, description = "The status of the worker", body = WorkerStatus))
+)]
+async fn status(State(state): State<ApiState>) -> Response {
+    let last_processed_block = match state.store.last_processed_block().await {
+        Ok(last_processed_block) => last_processed_block,
+        Err(error) => return internal_error(error),
+    };
+
+    let latest_block = *state.latest_block.lock().unwrap();
+
+    Json(WorkerStatus {
+        paused: state.control.is_paused(),
+        reindex_requested: state.control.is_reindex_requested(),
+        last_processed_block: last_processed_block.map(|block_number| block_number.as_u64()),
+        latest_block: latest_block.map(|block_number| block_number.as_u64()),
+        slowest_operations: state.instrumentation.slowest_operations(),
+        provider: state.provider_capabilities.clone(),
+    })
+    .into_response()
+}
+
+/// Whether the logs worker is paused.
+#[derive(Debug, Serialize, ToSchema)]
+struct PauseState {
+    paused: bool,
+    /// Whether the request paused or resumed the worker, rather than finding it so already.
+    changed: bool,
+}
+
+/// Pauses the logs worker once the block it is processing is committed.
+#[utoipa::path(
+    post,
+    path = "/control/pause",
+    tag = "control",
+    extensions(("x-required-role" = json!("operator"))),
+    responses((status = 200, description = "The worker is paused", body = PauseState))
+)]
+async fn pause(State(state): State<ApiState>) -> Response {
+    let changed = state.control.pause();
+    Json(PauseState {
+        paused: true,
+        changed,
+    })
+    .into_response()
+}
+
+/// Resumes the logs worker.
+#[utoipa::path(
+    post,
+    path = "/control/resume",
+    tag = "control",
+    extensions(("x-required-role" = json!("operator"))),
+    responses((status = 200, description = "The worker is resumed", body = PauseState))
+)]
+async fn resume(State(state): State<ApiState>) -> Response {
+    let changed = state.control.resume();
+    Json(PauseState {
+        paused: false,
+        changed,
+    })
+    .into_response()
+}
+
+/// Asks the logs worker to wipe the ownership model, the journal and the delta feed, then index
+/// again from the start block. Contract classifications are kept.
+#[utoipa::path(
+    post,
+    path = "/control/reindex",
+    tag = "control",
+    extensions(("x-required-role" = json!("admin"))),
+    responses((status = 202, description = "The reindex is requested", body = ReindexRequest))
+)]
+async fn reindex(State(state): State<ApiState>) -> Response {
+    state.control.request_reindex();
+    (
+        StatusCode::ACCEPTED,
+        Json(ReindexRequest {
+            reindex_requested: true,
+        }),
+    )
+        .into_response()
+}
+
+/// Whether a reindex is requested.
+#[derive(Debug, Serialize, ToSchema)]
+struct ReindexRequest {
+    reindex_requested: bool,
+}
+
+/// Token type registered for a contract, and the block to index it from.
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct RegisterContract {
+    pub token_type: String,
+    pub start_block: Option<u64>,
+}
+
+/// Registers the token type of a contract, overriding its classification, and evicts its cached
+/// classification so the logs worker decodes its next logs as that type.
+#[utoipa::path(
+    put,
+    path = "/control/contracts/{contract}",
+    tag = "control",
+    extensions(("x-required-role" = json!("admin"))),
+    params(("contract" = openapi::Address, Path, description = "Address of the contract")),
+    request_body = RegisterContract,
+    responses(
+        (status = 200, description = "The classification of the contract", body = ContractAddress),
+        (status = 400, description = "The token type cannot be registered", body = String),
+    )
+)]
+async fn register_contract(
+    State(state): State<ApiState>,
+    Path(contract): Path<H160>,
+    Json(request): Json<RegisterContract>,
+) -> Response {
+    if !REGISTRABLE_TOKEN_TYPES.contains(&request.token_type.as_str()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "The token type must be one of {}",
+                REGISTRABLE_TOKEN_TYPES.join(", ")
+            ),
+        )
+            .into_response();
+    }
+
+    match processor::register_contract(
+        &state.web3,
+        &state.store,
+        contract,
+        &request.token_type,
+        request.start_block.map(U64::from),
+    )
+    .await
+    {
+        Ok(contract_address) => Json(contract_address).into_response(),
+        Err(error) => internal_error(error),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct OperatorActionsQuery {
+    pub actor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Operator actions, most recent first.
+#[derive(Debug, Serialize, ToSchema)]
+struct OperatorActions {
+    actions: Vec<OperatorActionEntry>,
+}
+
+/// A data mutating action taken through the control API or the CLI.
+#[derive(Debug, Serialize, ToSchema)]
+struct OperatorActionEntry {
+    /// Name of the API key, or `cli:<user>` for CLI commands.
+    actor: String,
+    /// Role the action was authorized with, null for CLI commands.
+    role: Option<Role>,
+    action: String,
+    outcome: String,
+    #[schema(format = DateTime)]
+    performed_at: String,
+}
+
+/// Returns the audit trail of operator actions, most recent first.
+#[utoipa::path(
+    get,
+    path = "/control/actions",
+    tag = "control",
+    extensions(("x-required-role" = json!("admin"))),
+    params(OperatorActionsQuery),
+    responses((status = 200, description = "Up to `limit` actions", body = OperatorActions))
+)]
+async fn operator_actions(
+    State(state): State<ApiState>,
+    Query(query): Query<OperatorActionsQuery>,
+) -> Response {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_ACTIONS_LIMIT);
+
+    match state
+        .store
+        .operator_actions(query.actor.as_deref(), limit)
+        .await
+    {
+        Ok(actions) => {
+            let actions: Vec<_> = actions
+                .into_iter()
+                .map(|action| OperatorActionEntry {
+                    actor: action.actor,
+                    role: action.role,
+                    action: action.action,
+                    outcome: action.outcome,
+                    performed_at: action.performed_at.to_rfc3339_string(),
+                })
+                .collect();
+
+            Json(OperatorActions { actions }).into_response()
+        }
+        Err(error) => internal_error(error),
+    }
+}
+
+/// Data quality issues of every contract.
+#[derive(Debug, Serialize, ToSchema)]
+struct AnomalySummary {
+    total: i64,
+    contracts: Vec<ContractAnomalySummary>,
+}
+
+/// Number of data quality issues of each kind found for a contract, and where to list them.
+#[derive(Debug, Serialize, ToSchema)]
+struct ContractAnomalySummary {
+    #[serde(flatten)]
+    anomalies: ContractAnomalies,
+    total: i64,
+    /// Path of the issues of the contract.
+    details: String,
+}
+
+/// Summarizes the data quality issues of every contract, those with the most issues first, with a
+/// link to the issues of each contract.
+#[utoipa::path(
+    get,
+    path = "/control/anomalies",
+    tag = "control",
+    extensions(("x-required-role" = json!("viewer"))),
+    responses((status = 200, description = "The issues of every contract", body = AnomalySummary))
+)]
+async fn anomalies(State(state): State<ApiState>) -> Response {
+    match anomalies::summarize(&state.store).await {
+        Ok(contracts) => {
+            let total: i64 = contracts.iter().map(|anomalies| anomalies.total()).sum();
+
+            let contracts = contracts
+                .into_iter()
+                .map(|anomalies| ContractAnomalySummary {
+                    total: anomalies.total(),
+                    details: format!("/control/anomalies/{:#x}", anomalies.contract_address),
+                    anomalies,
+                })
+                .collect();
+
+            Json(AnomalySummary { total, contracts }).into_response()
+        }
+        Err(error) => internal_error(error),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ContractAnomaliesQuery {
+    pub limit: Option<i64>,
+}
+
+/// Data quality issues of a contract.
+#[derive(Debug, Serialize, ToSchema)]
+struct ContractIssues {
+    #[schema(value_type = openapi::Address)]
+    contract_address: H160,
+    negative_balances: Vec<TokenOwnership>,
+    negative_supplies: Vec<TokenSupply>,
+    failed_metadata: Vec<TokenMetadata>,
+    quorum_mismatches: Vec<QuorumMismatchEntry>,
+    dead_letters: Vec<DeadLetterEntry>,
+}
+
+/// Logs on which the primary and the quorum providers disagreed, identified as
+/// `<transaction hash>:<log index>`.
+#[derive(Debug, Serialize, ToSchema)]
+struct QuorumMismatchEntry {
+    from_block: i64,
+    to_block: i64,
+    primary_only: Vec<String>,
+    quorum_only: Vec<String>,
+    #[schema(format = DateTime)]
+    detected_at: String,
+}
+
+/// A log that could not be decoded.
+#[derive(Debug, Serialize, ToSchema)]
+struct DeadLetterEntry {
+    block_number: i64,
+    #[schema(value_type = openapi::Hash)]
+    transaction_hash: H256,
+    #[schema(value_type = openapi::Quantity)]
+    log_index: U256,
+    error: String,
+    attempts: i32,
+    #[schema(format = DateTime)]
+    recorded_at: String,
+}
+
+/// Lists the data quality issues of a contract, up to `limit` of each kind.
+#[utoipa::path(
+    get,
+    path = "/control/anomalies/{contract}",
+    tag = "control",
+    extensions(("x-required-role" = json!("viewer"))),
+    params(("contract" = openapi::Address, Path, description = "Address of the contract"), ContractAnomaliesQuery),
+    responses((status = 200, description = "Up to `limit` issues of each kind", body = ContractIssues))
+)]
+async fn contract_anomalies(
+    State(state): State<ApiState>,
+    Path(contract): Path<H160>,
+    Query(query): Query<ContractAnomaliesQuery>,
+) -> Response {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_ANOMALIES_LIMIT);
+
+    match tokio::try_join!(
+        state.store.negative_balances(contract, limit),
+        state.store.negative_supplies(contract, limit),
+        state.store.failed_metadata(contract, limit),
+        state.store.quorum_mismatches(contract, limit),
+        state.store.contract_dead_letters(contract, limit),
+    ) {
+        Ok((
+            negative_balances,
+            negative_supplies,
+            failed_metadata,
+            quorum_mismatches,
+            dead_letters,
+        )) => {
+            let quorum_mismatches = quorum_mismatches
+                .into_iter()
+                .map(|mismatch| QuorumMismatchEntry {
+                    from_block: mismatch.from_block,
+                    to_block: mismatch.to_block,
+                    primary_only: mismatch.primary_only,
+                    quorum_only: mismatch.quorum_only,
+                    detected_at: mismatch.detected_at.to_rfc3339_string(),
+                })
+                .collect();
+
+            let dead_letters = dead_letters
+                .into_iter()
+                .map(|dead_letter| DeadLetterEntry {
+                    block_number: dead_letter.block_number,
+                    transaction_hash: dead_letter.transaction_hash,
+                    log_index: dead_letter.log_index,
+                    error: dead_letter.error,
+                    attempts: dead_letter.attempts,
+                    recorded_at: dead_letter.recorded_at.to_rfc3339_string(),
+                })
+                .collect();
+
+            Json(ContractIssues {
+                contract_address: contract,
+                negative_balances,
+                negative_supplies,
+                failed_metadata,
+                quorum_mismatches,
+                dead_letters,
+            })
+            .into_response()
+        }
+        Err(error) => internal_error(error),
+    }
+}
+
+/// The registered webhook rules.
+#[derive(Debug, Serialize, ToSchema)]
+struct WebhookRules {
+    rules: Vec<WebhookRuleEntry>,
+}
+
+/// A registered webhook rule.
+#[derive(Debug, Serialize, ToSchema)]
+struct WebhookRuleEntry {
+    /// Hex object id of the rule.
+    id: String,
+    #[schema(value_type = openapi::Address)]
+    contract_address: H160,
+    metric: WebhookMetric,
+    condition: WebhookCondition,
+    url: String,
+    reference_value: f64,
+}
+
+impl From<&WebhookRule> for WebhookRuleEntry {
+    fn from(rule: &WebhookRule) -> Self {
+        Self {
+            id: rule.id.to_hex(),
+            contract_address: rule.contract_address,
+            metric: rule.metric,
+            condition: rule.condition,
+            url: rule.url.clone(),
+            reference_value: rule.reference_value,
+        }
+    }
+}
+
+/// Lists the registered webhook rules.
+#[utoipa::path(
+    get,
+    path = "/control/webhooks",
+    tag = "control",
+    extensions(("x-required-role" = json!("viewer"))),
+    responses((status = 200, description = "Every rule", body = WebhookRules))
+)]
+async fn webhook_rules(State(state): State<ApiState>) -> Response {
+    match state.store.all_webhook_rules().await {
+        Ok(rules) => Json(WebhookRules {
+            rules: rules.iter().map(WebhookRuleEntry::from).collect(),
+        })
+        .into_response(),
+        Err(error) => internal_error(error),
+    }
+}
+
+/// A webhook rule to register, against the current value of its metric.
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct CreateWebhookRule {
+    #[schema(value_type = openapi::Address)]
+    pub contract_address: H160,
+    pub metric: WebhookMetric,
+    pub condition: WebhookCondition,
+    pub url: String,
+}
+
+/// Registers a webhook rule, using the current value of the metric as its reference value.
+#[utoipa::path(
+    post,
+    path = "/control/webhooks",
+    tag = "control",
+    extensions(("x-required-role" = json!("operator"))),
+    request_body = CreateWebhookRule,
+    responses((status = 201, description = "The registered rule", body = WebhookRuleEntry))
+)]
+async fn create_webhook_rule(
+    State(state): State<ApiState>,
+    Json(request): Json<CreateWebhookRule>,
+) -> Response {
+    let reference_value = match state
+        .store
+        .contract_stats(&[request.contract_address])
+        .await
+    {
+        Ok(stats) => stats
+            .first()
+            .map(|stats| request.metric.value(stats))
+            .unwrap_or(0.0),
+        Err(error) => return internal_error(error),
+    };
+
+    let rule = WebhookRule {
+        id: ObjectId::new(),
+        contract_address: request.contract_address,
+        metric: request.metric,
+        condition: request.condition,
+        url: request.url,
+        reference_value,
+    };
+
+    match state.store.insert_webhook_rule(&rule).await {
+        Ok(()) => (StatusCode::CREATED, Json(WebhookRuleEntry::from(&rule))).into_response(),
+        Err(error) => internal_error(error),
+    }
+}
+
+/// Deletes a webhook rule.
+#[utoipa::path(
+    delete,
+    path = "/control/webhooks/{id}",
+    tag = "control",
+    extensions(("x-required-role" = json!("operator"))),
+    params(("id" = String, Path, description = "Hex object id of the rule")),
+    responses(
+        (status = 204, description = "The rule was deleted"),
+        (status = 400, description = "The id is not an object id", body = String),
+        (status = 404, description = "No rule has this id", body = String),
+    )
+)]
+async fn delete_webhook_rule(State(state): State<ApiState>, Path(id): Path<String>) -> Response {
+    let id = match ObjectId::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid webhook rule id").into_response(),
+    };
+
+    match state.store.delete_webhook_rule(id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Unknown webhook rule").into_response(),
+        Err(error) => internal_error(error),
+    }
+}
+
+fn json_line<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(value).unwrap();
+    line.push(b'\n');
+    line
+}
+
+fn differential_sync_disabled() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        "Differential sync is disabled, start the worker with --delta-feed-retention",
+    )
+        .into_response()
+}
+
+fn internal_error(error: impl std::fmt::Display) -> Response {
+    eprintln!("Error: API request failed... {}", error);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+}
@@ -0,0 +1,155 @@
+use crate::{ownership::OwnershipDelta, privacy::OwnerProtection, processor::Signatures};
+use serde::{Deserialize, Serialize};
+use web3::{
+    ethabi::{decode, param_type::ParamType, Token},
+    types::{Address, Log, H160, U256},
+};
+
+/// A change to who may move an owner's tokens, decoded from an `Approval` or `ApprovalForAll`
+/// event. Unlike ownership deltas, changes replace the previous approval.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ApprovalChange {
+    /// ERC20 allowance of `spender` over the balance of `owner`, removed when set to 0.
+    Allowance {
+        contract_address: H160,
+        owner: H160,
+        spender: H160,
+        amount: f64,
+    },
+    /// ERC721 approval of a single token, removed when `approved` is the zero address and by every
+    /// transfer of the token.
+    Token {
+        contract_address: H160,
+        token_id: String,
+        owner: H160,
+        approved: H160,
+    },
+    /// ERC721 and ERC1155 approval of an operator over every token of `owner`.
+    Operator {
+        contract_address: H160,
+        owner: H160,
+        operator: H160,
+        approved: bool,
+    },
+}
+
+impl ApprovalChange {
+    fn protect_owner(self, owner_protection: &OwnerProtection) -> Self {
+        match self {
+            ApprovalChange::Allowance {
+                contract_address,
+                owner,
+                spender,
+                amount,
+            } => ApprovalChange::Allowance {
+                contract_address,
+                owner: owner_protection.protect(owner),
+                spender,
+                amount,
+            },
+            ApprovalChange::Token {
+                contract_address,
+                token_id,
+                owner,
+                approved,
+            } => ApprovalChange::Token {
+                contract_address,
+                token_id,
+                owner: owner_protection.protect(owner),
+                approved,
+            },
+            ApprovalChange::Operator {
+                contract_address,
+                owner,
+                operator,
+                approved,
+            } => ApprovalChange::Operator {
+                contract_address,
+                owner: owner_protection.protect(owner),
+                operator,
+                approved,
+            },
+        }
+    }
+}
+
+/// Decodes an `Approval` or `ApprovalForAll` log, telling ERC20 and ERC721 approvals apart by
+/// whether the third parameter is indexed. Returns `None` for logs of other events.
+pub(crate) fn decode_approval_log(
+    signatures: &Signatures,
+    owner_protection: &OwnerProtection,
+    log: &Log,
+) -> Option<Vec<ApprovalChange>> {
+    let change =
+        if log.topics.first() == Some(&signatures.approval) {
+            match log.topics.len() {
+                3 => decode(&[ParamType::Uint(256)], &log.data.0)
+                    .ok()
+                    .and_then(|decoded| match decoded[0] {
+                        Token::Uint(amount) => Some(ApprovalChange::Allowance {
+                            contract_address: log.address,
+                            owner: Address::from(log.topics[1]),
+                            spender: Address::from(log.topics[2]),
+                            amount: u256_to_f64(amount),
+                        }),
+                        _ => None,
+                    }),
+                4 => Some(ApprovalChange::Token {
+                    contract_address: log.address,
+                    token_id: U256::from_big_endian(log.topics[3].as_bytes()).to_string(),
+                    owner: Address::from(log.topics[1]),
+                    approved: Address::from(log.topics[2]),
+                }),
+                _ => None,
+            }
+        } else if log.topics.first() == Some(&signatures.approval_for_all) {
+            match (log.topics.len(), decode(&[ParamType::Bool], &log.data.0)) {
+                (3, Ok(decoded)) => match decoded[0] {
+                    Token::Bool(approved) => Some(ApprovalChange::Operator {
+                        contract_address: log.address,
+                        owner: Address::from(log.topics[1]),
+                        operator: Address::from(log.topics[2]),
+                        approved,
+                    }),
+                    _ => None,
+                },
+                _ => None,
+            }
+        } else {
+            return None;
+        };
+
+    Some(
+        change
+            .into_iter()
+            .map(|change| change.protect_owner(owner_protection))
+            .collect(),
+    )
+}
+
+/// The single token approvals cleared by ERC721 transfers, which reset the approved address
+/// without necessarily emitting an `Approval`.
+pub(crate) fn cleared_by_transfers(deltas: &[OwnershipDelta]) -> Vec<ApprovalChange> {
+    deltas
+        .iter()
+        .filter(|delta| delta.token_type == "ERC721" && delta.quantity < 0.0)
+        .filter_map(|delta| {
+            Some(ApprovalChange::Token {
+                contract_address: delta.contract_address,
+                token_id: delta.token_id.clone()?,
+                owner: delta.owner,
+                approved: Address::default(),
+            })
+        })
+        .collect()
+}
+
+/// Converts without overflowing, since unlimited allowances are commonly set to `2^256 - 1`.
+pub(crate) fn u256_to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0.0, |total, word| total * 2f64.powi(64) + *word as f64)
+}
@@ -0,0 +1,358 @@
+#[cfg(feature = "api")]
+use crate::{api::ApiState, store::OperatorAction};
+#[cfg(feature = "api")]
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "api")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "api")]
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+
+/// Permission level of an API key. Every role is granted the permissions of the roles below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Read-only access to the worker status.
+    Viewer,
+    /// Can pause and resume the logs worker.
+    Operator,
+    /// Can run destructive operations such as a full reindex.
+    Admin,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role {}", role)),
+        }
+    }
+}
+
+/// A named key granting a role on the control API, given as `name:role:key`. Only the hash of the
+/// key is kept, so bearer tokens are matched by their hash rather than compared with the secret.
+#[cfg(feature = "api")]
+#[derive(Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub role: Role,
+    key_hash: String,
+}
+
+#[cfg(feature = "api")]
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("role", &self.role)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "api")]
+impl std::str::FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str(api_key: &str) -> Result<Self, Self::Err> {
+        let mut parts = api_key.splitn(3, ':');
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(role), Some(key)) if !name.is_empty() && !key.is_empty() => {
+                Ok(Self {
+                    name: name.to_string(),
+                    role: role.parse()?,
+                    key_hash: hash_api_key(key),
+                })
+            }
+            _ => Err("API keys are given as name:role:key".to_string()),
+        }
+    }
+}
+
+/// The API key a request was authorized with, added to the request extensions.
+#[cfg(feature = "api")]
+#[derive(Debug, Clone)]
+pub(crate) struct Caller {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Lowercase hex SHA-256 hash API keys minted with `create-api-key` are stored by.
+#[cfg(feature = "api")]
+pub(crate) fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Token buckets of the API keys, by key hash, so keys sharing a name, such as a reminted key and
+/// the revoked one it replaces, do not share a bucket.
+#[cfg(feature = "api")]
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+#[cfg(feature = "api")]
+impl RateLimiter {
+    /// Takes a request from the bucket of `key_hash`, which holds up to `burst` requests and
+    /// refills at `rate` requests per second. Returns the time until the next request is allowed
+    /// when the bucket is empty.
+    fn acquire(&self, key_hash: &str, rate: f64, burst: f64) -> Result<(), Duration> {
+        self.acquire_at(key_hash, rate, burst, Instant::now())
+    }
+
+    fn acquire_at(
+        &self,
+        key_hash: &str,
+        rate: f64,
+        burst: f64,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let (tokens, refilled_at) = buckets.entry(key_hash.to_string()).or_insert((burst, now));
+        *tokens = (*tokens + now.duration_since(*refilled_at).as_secs_f64() * rate).min(burst);
+        *refilled_at = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            // Capped, so a rate of zero waits an hour rather than forever.
+            Err(Duration::from_secs_f64(
+                ((1.0 - *tokens) / rate).min(3600.0),
+            ))
+        }
+    }
+}
+
+/// `Retry-After` value of a rate limited request, in whole seconds rounded up so the client does
+/// not retry before its bucket refilled.
+#[cfg(feature = "api")]
+fn retry_after(wait: Duration) -> u64 {
+    (wait.as_secs_f64().ceil() as u64).max(1)
+}
+
+/// Finds the API key of the request's bearer token among the `--api-key` keys, then among the
+/// unrevoked stored keys, and takes the request from the rate limit of the key. Returns the
+/// caller, or the `401`, `429` or `500` response rejecting the request.
+#[cfg(feature = "api")]
+async fn authenticate(state: &ApiState, headers: &HeaderMap) -> Result<Caller, Response> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let token = match token {
+        Some(token) => token,
+        None => {
+            return Err((StatusCode::UNAUTHORIZED, "Missing or unknown API key").into_response())
+        }
+    };
+
+    let token_hash = hash_api_key(token);
+
+    let (caller, rate_limit) = match state
+        .api_keys
+        .iter()
+        .find(|api_key| api_key.key_hash == token_hash)
+    {
+        Some(api_key) => (
+            Caller {
+                name: api_key.name.clone(),
+                role: api_key.role,
+            },
+            state.api_rate_limit,
+        ),
+        None => match state.store.active_api_key(&token_hash).await {
+            Ok(Some(api_key)) => (
+                Caller {
+                    name: api_key.name,
+                    role: api_key.role,
+                },
+                api_key.rate_limit.or(state.api_rate_limit),
+            ),
+            Ok(None) => {
+                return Err((StatusCode::UNAUTHORIZED, "Missing or unknown API key").into_response())
+            }
+            Err(error) => {
+                eprintln!("Error: Could not look up the API key... {}", error);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        },
+    };
+
+    if let Some(rate_limit) = rate_limit {
+        if let Err(wait) =
+            state
+                .rate_limiter
+                .acquire(&token_hash, rate_limit, state.api_rate_burst.max(1) as f64)
+        {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after(wait).to_string())],
+                "Rate limit exceeded",
+            )
+                .into_response());
+        }
+    }
+
+    Ok(caller)
+}
+
+/// Rejects requests without a known API key, whatever its role, when `--require-api-key` guards
+/// the public endpoints.
+#[cfg(feature = "api")]
+pub(crate) async fn require_api_key(
+    State(state): State<ApiState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match authenticate(&state, request.headers()).await {
+        Ok(caller) => {
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+        Err(response) => response,
+    }
+}
+
+/// Rejects requests whose bearer token does not match an API key with at least the required role.
+///
+/// Requests that are not `GET`s are logged once handled, with the caller and the response status,
+/// and recorded in the `operator_actions` collection.
+#[cfg(feature = "api")]
+pub(crate) async fn require_role(
+    State((state, required_role)): State<(ApiState, Role)>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let caller = match authenticate(&state, request.headers()).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+
+    if caller.role < required_role {
+        println!(
+            "Audit: {} ({:?}) denied {} {}",
+            caller.name,
+            caller.role,
+            request.method(),
+            request.uri().path()
+        );
+
+        return (
+            StatusCode::FORBIDDEN,
+            format!("The {:?} role is required", required_role),
+        )
+            .into_response();
+    }
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    request.extensions_mut().insert(caller.clone());
+
+    let response = next.run(request).await;
+
+    if method != axum::http::Method::GET {
+        println!(
+            "Audit: {} ({:?}) {} {} -> {}",
+            caller.name,
+            caller.role,
+            method,
+            path,
+            response.status()
+        );
+
+        let action = OperatorAction {
+            actor: caller.name,
+            role: Some(caller.role),
+            action: format!("{} {}", method, path),
+            outcome: response.status().to_string(),
+            performed_at: mongodb::bson::DateTime::now(),
+        };
+
+        if let Err(error) = state.store.record_operator_action(&action).await {
+            eprintln!("Error: Could not record the operator action... {}", error);
+        }
+    }
+
+    response
+}
+
+#[cfg(all(test, feature = "api"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_requests_once_the_burst_is_spent() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire_at("key", 1.0, 3.0, now), Ok(()));
+        }
+
+        assert_eq!(
+            limiter.acquire_at("key", 1.0, 3.0, now),
+            Err(Duration::from_secs(1))
+        );
+        assert_eq!(limiter.acquire_at("other key", 1.0, 3.0, now), Ok(()));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert_eq!(limiter.acquire_at("key", 2.0, 1.0, now), Ok(()));
+        assert_eq!(
+            limiter.acquire_at("key", 2.0, 1.0, now),
+            Err(Duration::from_millis(500))
+        );
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.acquire_at("key", 2.0, 1.0, later), Ok(()));
+
+        // An idle bucket refills up to the burst only.
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(limiter.acquire_at("key", 2.0, 1.0, much_later), Ok(()));
+        assert!(limiter.acquire_at("key", 2.0, 1.0, much_later).is_err());
+    }
+
+    #[test]
+    fn caps_the_wait_of_a_zero_rate() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert_eq!(limiter.acquire_at("key", 0.0, 1.0, now), Ok(()));
+
+        let wait = limiter.acquire_at("key", 0.0, 1.0, now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(3600));
+        assert_eq!(retry_after(wait), 3600);
+    }
+
+    #[test]
+    fn rounds_retry_after_up_to_whole_seconds() {
+        assert_eq!(retry_after(Duration::ZERO), 1);
+        assert_eq!(retry_after(Duration::from_millis(200)), 1);
+        assert_eq!(retry_after(Duration::from_secs(1)), 1);
+        assert_eq!(retry_after(Duration::from_millis(1500)), 2);
+    }
+}
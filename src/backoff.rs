@@ -0,0 +1,68 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// Delays between the retries of a failed MongoDB operation, doubled after every consecutive
+/// failure up to `max_delay`, so replica set elections and network blips are waited out without
+/// hammering the server while it recovers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// Longest delay between two retries.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Consecutive failures of a loop, waited out with the delays of a `RetryPolicy`.
+pub(crate) struct Backoff {
+    policy: RetryPolicy,
+    failures: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+        }
+    }
+
+    /// The delay before the next retry, between half and all of the exponential delay so workers
+    /// failing together do not retry together.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .policy
+            .initial_delay
+            .saturating_mul(2_u32.saturating_pow(self.failures.min(31)))
+            .min(self.policy.max_delay);
+
+        self.failures = self.failures.saturating_add(1);
+
+        let half = delay / 2;
+        let jitter = RandomState::new().build_hasher().finish() % (half.as_millis() as u64 + 1);
+
+        half + Duration::from_millis(jitter)
+    }
+
+    /// Sleeps for the next delay.
+    pub(crate) async fn wait(&mut self) {
+        sleep(self.next_delay()).await;
+    }
+
+    /// Starts over from the initial delay after a success.
+    pub(crate) fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
@@ -0,0 +1,481 @@
+//! Point-in-time backups of the ownership model, dumped on a cron schedule and uploaded to S3 or
+//! to Google Cloud Storage through its S3 compatible API, independently of MongoDB backups.
+
+use crate::{
+    parquet::{parquet_schema, record_batch, writer_properties, ParquetColumn},
+    store::Store,
+};
+use chrono::{DateTime, Utc};
+use croner::{
+    parser::{CronParser, Seconds, Year},
+    Cron,
+};
+use mongodb::{
+    bson::{Bson, Document},
+    options::SessionOptions,
+    Client, ClientSession, Collection,
+};
+use object_store::{
+    aws::AmazonS3Builder, buffered::BufWriter, path::Path, ObjectStore, ObjectStoreExt,
+};
+use parquet::arrow::ArrowWriter;
+use serde_json::json;
+use std::{error, mem, str::FromStr, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, time::sleep};
+
+/// Size of the parts of multipart uploads, and of the largest objects uploaded with a single
+/// request. S3 takes up to 10,000 parts, so collections of up to 100 GB can be backed up.
+const UPLOAD_PART_SIZE: usize = 10 * 1024 * 1024;
+
+/// Documents of a collection written to a Parquet backup per row group.
+const PARQUET_ROW_GROUP_SIZE: usize = 65536;
+
+/// Encoding of the dumped collections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupFormat {
+    /// The documents of each collection one after the other, as `mongodump` writes them, which
+    /// `mongorestore` restores.
+    Bson,
+    /// One Parquet file per collection with a column per top-level field, for analytics tools.
+    Parquet,
+}
+
+impl BackupFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            BackupFormat::Bson => "bson",
+            BackupFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for BackupFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bson" => Ok(BackupFormat::Bson),
+            "parquet" => Ok(BackupFormat::Parquet),
+            _ => Err(format!("Unknown backup format {}", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for BackupFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// A cron schedule of five fields, minute, hour, day of the month, month and day of the week, in
+/// UTC, parsed by `croner`. Fields take `*`, values, ranges such as `1-5`, steps such as `*/15`
+/// and lists of them, and `@hourly`, `@daily`, `@weekly` and `@monthly` stand for their usual
+/// schedules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    cron: Cron,
+}
+
+impl CronSchedule {
+    /// The first time of the schedule after `timestamp`, in seconds since the Unix epoch.
+    pub(crate) fn next_after(&self, timestamp: u64) -> Option<u64> {
+        let time = DateTime::<Utc>::from_timestamp(timestamp as i64, 0)?;
+
+        self.cron
+            .find_next_occurrence(&time, false)
+            .ok()
+            .map(|next| next.timestamp() as u64)
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let cron = CronParser::builder()
+            .seconds(Seconds::Disallowed)
+            .year(Year::Disallowed)
+            .build()
+            .parse(value)
+            .map_err(|error| format!("Invalid schedule {}... {}", value, error))?;
+        let schedule = CronSchedule { cron };
+
+        if schedule.next_after(0).is_none() {
+            return Err(format!("The schedule {} never fires", value));
+        }
+
+        Ok(schedule)
+    }
+}
+
+/// Object storage service a backup destination is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageProvider {
+    S3,
+    Gcs,
+}
+
+/// Bucket and key prefix backups are uploaded under, written `s3://bucket/prefix` or
+/// `gs://bucket/prefix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupDestination {
+    pub provider: StorageProvider,
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl FromStr for BackupDestination {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (provider, location) = if let Some(location) = value.strip_prefix("s3://") {
+            (StorageProvider::S3, location)
+        } else if let Some(location) = value.strip_prefix("gs://") {
+            (StorageProvider::Gcs, location)
+        } else {
+            return Err(format!(
+                "Invalid backup destination {}, expected s3://<bucket>/<prefix> or gs://<bucket>/<prefix>",
+                value
+            ));
+        };
+
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            return Err(format!("Missing bucket in backup destination {}", value));
+        }
+
+        Ok(BackupDestination {
+            provider,
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+}
+
+/// An S3 compatible bucket objects are uploaded to through `object_store`, which signs requests
+/// with AWS Signature Version 4 as Google Cloud Storage accepts with HMAC keys.
+#[derive(Debug, Clone)]
+pub struct ObjectStorage {
+    bucket: Arc<dyn ObjectStore>,
+    destination: BackupDestination,
+}
+
+impl ObjectStorage {
+    /// Storage of `destination`, at the endpoint of its provider for `region` unless `endpoint` is
+    /// given, such as a MinIO server. The region defaults to `us-east-1` on S3 and `auto` on GCS.
+    pub fn new(
+        destination: BackupDestination,
+        endpoint: Option<String>,
+        region: Option<String>,
+        access_key_id: String,
+        secret_access_key: String,
+    ) -> Result<Self, object_store::Error> {
+        let region = region.unwrap_or_else(|| match destination.provider {
+            StorageProvider::S3 => "us-east-1".to_string(),
+            StorageProvider::Gcs => "auto".to_string(),
+        });
+        let endpoint = endpoint.or_else(|| match destination.provider {
+            StorageProvider::S3 => None,
+            StorageProvider::Gcs => Some("https://storage.googleapis.com".to_string()),
+        });
+
+        let mut bucket = AmazonS3Builder::new()
+            .with_bucket_name(&destination.bucket)
+            .with_region(region)
+            .with_access_key_id(access_key_id)
+            .with_secret_access_key(secret_access_key);
+        if let Some(endpoint) = endpoint {
+            bucket = bucket
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint.trim_end_matches('/'));
+        }
+
+        Ok(Self {
+            bucket: Arc::new(bucket.build()?),
+            destination,
+        })
+    }
+
+    /// Path of an object, from `key` relative to the prefix of the destination.
+    fn path(&self, key: &str) -> Path {
+        match self.destination.prefix.as_str() {
+            "" => Path::from(key),
+            prefix => Path::from(format!("{}/{}", prefix, key)),
+        }
+    }
+
+    /// Location of an object, for logs.
+    fn location(&self, path: &Path) -> String {
+        let scheme = match self.destination.provider {
+            StorageProvider::S3 => "s3",
+            StorageProvider::Gcs => "gs",
+        };
+
+        format!("{}://{}/{}", scheme, self.destination.bucket, path)
+    }
+
+    /// Uploads an object under `key` with a single request.
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+    ) -> Result<String, Box<dyn error::Error + Send + Sync>> {
+        let path = self.path(key);
+        self.bucket.put(&path, body.into()).await?;
+
+        Ok(self.location(&path))
+    }
+
+    /// Writer uploading an object under `key`. Objects up to `UPLOAD_PART_SIZE` are uploaded with a
+    /// single request once the writer is shut down, and larger ones as a multipart upload in parts
+    /// of that size.
+    fn writer(&self, key: &str) -> BufWriter {
+        BufWriter::with_capacity(self.bucket.clone(), self.path(key), UPLOAD_PART_SIZE)
+    }
+}
+
+/// A backup uploaded to object storage.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// Last block applied to the backed up ownership model.
+    pub block_number: Option<u64>,
+    /// Location of the manifest of the backup.
+    pub manifest: String,
+    /// Number of documents of each backed up collection.
+    pub collections: Vec<(String, usize)>,
+}
+
+/// Dumps the collections of the ownership model and uploads them under
+/// `<prefix>/<timestamp>-block-<n>/`, one object per collection and a `manifest.json` uploaded
+/// last, so a backup without a manifest is incomplete.
+///
+/// The collections and the checkpoint are read in a snapshot session, so they all hold the
+/// ownership model at the same point in time while blocks keep being applied, and are streamed to
+/// object storage rather than read into memory.
+pub(crate) async fn back_up(
+    store: &Store,
+    client: &Client,
+    storage: &ObjectStorage,
+    format: BackupFormat,
+) -> Result<Backup, Box<dyn error::Error + Send + Sync>> {
+    let taken_at = Utc::now();
+
+    let mut session = client
+        .start_session(Some(SessionOptions::builder().snapshot(true).build()))
+        .await?;
+    let block_number = store
+        .last_processed_block_with_session(&mut session)
+        .await?
+        .map(|block_number| block_number.as_u64());
+
+    let directory = format!(
+        "{}-block-{}",
+        taken_at.format("%Y%m%dT%H%M%SZ"),
+        block_number.unwrap_or_default()
+    );
+
+    let mut collections = Vec::new();
+    for (name, collection) in store.ownership_model_collections() {
+        let mut writer = storage.writer(&format!("{}/{}.{}", directory, name, format.extension()));
+
+        let dumped = match format {
+            BackupFormat::Bson => dump_bson(store, &collection, &mut session, &mut writer).await,
+            BackupFormat::Parquet => {
+                dump_parquet(store, &collection, &mut session, &mut writer).await
+            }
+        };
+        let count = match dumped {
+            Ok(count) => count,
+            Err(error) => {
+                // Parts of a multipart upload are kept, and billed, until it is aborted.
+                let _ = writer.abort().await;
+                return Err(error);
+            }
+        };
+
+        writer.shutdown().await?;
+        collections.push((name, count));
+    }
+
+    let manifest = json!({
+        "block_number": block_number,
+        "taken_at": taken_at.timestamp(),
+        "format": format.to_string(),
+        "collections": collections
+            .iter()
+            .map(|(name, count)| (name.clone(), json!(count)))
+            .collect::<serde_json::Map<_, _>>(),
+    });
+    let manifest = storage
+        .put(
+            &format!("{}/manifest.json", directory),
+            serde_json::to_vec_pretty(&manifest)?,
+        )
+        .await?;
+
+    Ok(Backup {
+        block_number,
+        manifest,
+        collections,
+    })
+}
+
+/// Backs up the ownership model at every time of `schedule`. Failed backups are logged and not
+/// retried before the next time of the schedule.
+pub(crate) async fn run_backups(
+    store: Store,
+    client: Client,
+    storage: ObjectStorage,
+    format: BackupFormat,
+    schedule: CronSchedule,
+) {
+    loop {
+        let now = Utc::now().timestamp() as u64;
+        let next = match schedule.next_after(now) {
+            Some(next) => next,
+            None => return,
+        };
+
+        sleep(Duration::from_secs(next - now)).await;
+
+        match back_up(&store, &client, &storage, format).await {
+            Ok(backup) => println!(
+                "Backed up the ownership model after block {} to {}",
+                backup.block_number.unwrap_or_default(),
+                backup.manifest
+            ),
+            Err(error) => eprintln!("Error: Could not back up the ownership model... {}", error),
+        }
+    }
+}
+
+/// Writes the documents of a collection one after the other, and returns how many there are.
+async fn dump_bson(
+    store: &Store,
+    collection: &Collection<Document>,
+    session: &mut ClientSession,
+    writer: &mut BufWriter,
+) -> Result<usize, Box<dyn error::Error + Send + Sync>> {
+    let mut documents = store.documents_with_session(collection, session).await?;
+    let mut count = 0;
+    let mut bytes = Vec::new();
+
+    while let Some(document) = documents.next(session).await {
+        bytes.clear();
+        document?.to_writer(&mut bytes)?;
+        writer.write_all(&bytes).await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Writes the documents of a collection as a Parquet file, and returns how many there are. The
+/// columns are found in a first pass over the collection, and the rows written in a second pass
+/// in row groups of `PARQUET_ROW_GROUP_SIZE` documents.
+async fn dump_parquet(
+    store: &Store,
+    collection: &Collection<Document>,
+    session: &mut ClientSession,
+    writer: &mut BufWriter,
+) -> Result<usize, Box<dyn error::Error + Send + Sync>> {
+    let mut layout = ParquetLayout::default();
+    let mut documents = store.documents_with_session(collection, session).await?;
+    while let Some(document) = documents.next(session).await {
+        layout.add(&document?);
+    }
+
+    let schema = parquet_schema(&layout.columns(&[]));
+    let mut parquet = ArrowWriter::try_new(Vec::new(), schema.clone(), Some(writer_properties()))?;
+    let mut documents = store.documents_with_session(collection, session).await?;
+    let mut row_group = Vec::with_capacity(PARQUET_ROW_GROUP_SIZE);
+    let mut count = 0;
+
+    loop {
+        let document = documents.next(session).await.transpose()?;
+        let last = document.is_none();
+        row_group.extend(document);
+
+        if row_group.len() == PARQUET_ROW_GROUP_SIZE || (last && !row_group.is_empty()) {
+            count += row_group.len();
+            parquet.write(&record_batch(schema.clone(), layout.columns(&row_group))?)?;
+            parquet.flush()?;
+            row_group.clear();
+        }
+        if last {
+            parquet.finish()?;
+        }
+
+        // The encoded row groups, and the footer once finished, are moved out of the buffer of
+        // the Parquet writer, which keeps counting the bytes written for the offsets.
+        parquet.sync()?;
+        writer.write_all(&mem::take(parquet.inner_mut())).await?;
+
+        if last {
+            return Ok(count);
+        }
+    }
+}
+
+/// Top-level fields of the documents of a collection in the order they first appear, and whether
+/// each only holds numbers, which makes it a double column rather than a string column.
+#[derive(Debug, Default)]
+struct ParquetLayout {
+    fields: Vec<(String, bool)>,
+}
+
+impl ParquetLayout {
+    fn add(&mut self, document: &Document) {
+        for (name, value) in document {
+            let numeric = matches!(
+                value,
+                Bson::Double(_) | Bson::Int32(_) | Bson::Int64(_) | Bson::Null
+            );
+
+            match self.fields.iter_mut().find(|(field, _)| field == name) {
+                Some((_, field_numeric)) => *field_numeric &= numeric,
+                None => self.fields.push((name.clone(), numeric)),
+            }
+        }
+    }
+
+    /// Columns of `documents`, with ObjectIds in hexadecimal and nested values in relaxed extended
+    /// JSON in string columns.
+    fn columns(&self, documents: &[Document]) -> Vec<(String, ParquetColumn)> {
+        self.fields
+            .iter()
+            .map(|(name, numeric)| {
+                let values = documents
+                    .iter()
+                    .map(|document| document.get(name).filter(|value| **value != Bson::Null));
+
+                let column = if *numeric {
+                    ParquetColumn::Double(
+                        values
+                            .map(|value| match value {
+                                Some(Bson::Double(value)) => Some(*value),
+                                Some(Bson::Int32(value)) => Some(*value as f64),
+                                Some(Bson::Int64(value)) => Some(*value as f64),
+                                _ => None,
+                            })
+                            .collect(),
+                    )
+                } else {
+                    ParquetColumn::Utf8(
+                        values
+                            .map(|value| {
+                                value.map(|value| match value {
+                                    Bson::String(value) => value.clone(),
+                                    Bson::ObjectId(id) => id.to_hex(),
+                                    value => value.clone().into_relaxed_extjson().to_string(),
+                                })
+                            })
+                            .collect(),
+                    )
+                };
+
+                (name.clone(), column)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,177 @@
+use crate::store::ContractAddress;
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use web3::types::H160;
+
+/// Classifications read from `contract_addresses`, so a busy block does not look its contracts up
+/// in MongoDB once per log.
+///
+/// The worker evicts the classifications it changes itself. Entries expire after `ttl`, so changes
+/// made by other processes or directly in the collection, such as a new self transfer policy, are
+/// picked up within that time.
+pub(crate) enum ClassificationCache {
+    /// Held by the process, evicting the least recently used classifications once full.
+    Memory {
+        entries: Mutex<LruCache<H160, (ContractAddress, Instant)>>,
+        ttl: Duration,
+    },
+    /// Held by a Redis server and shared by every process connected to it, such as block job
+    /// workers. Keys are prefixed with the database name and collection prefix, so deployments can
+    /// share a server.
+    #[cfg(feature = "redis")]
+    Redis {
+        connection: redis::aio::ConnectionManager,
+        prefix: String,
+        ttl: Duration,
+    },
+}
+
+impl std::fmt::Debug for ClassificationCache {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClassificationCache::Memory { ttl, .. } => formatter
+                .debug_struct("Memory")
+                .field("ttl", ttl)
+                .finish_non_exhaustive(),
+            #[cfg(feature = "redis")]
+            ClassificationCache::Redis { prefix, ttl, .. } => formatter
+                .debug_struct("Redis")
+                .field("prefix", prefix)
+                .field("ttl", ttl)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+impl ClassificationCache {
+    /// An in-process cache of up to `capacity` classifications, `None` when `capacity` is 0.
+    pub(crate) fn memory(capacity: usize, ttl: Duration) -> Option<Self> {
+        Some(ClassificationCache::Memory {
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(capacity)?)),
+            ttl,
+        })
+    }
+
+    #[cfg(feature = "redis")]
+    pub(crate) async fn redis(
+        url: &str,
+        database_name: &str,
+        collection_prefix: &str,
+        ttl: Duration,
+    ) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+
+        Ok(ClassificationCache::Redis {
+            connection: redis::aio::ConnectionManager::new(client).await?,
+            prefix: format!("{}:{}contract_address:", database_name, collection_prefix),
+            ttl,
+        })
+    }
+
+    /// The cached classification of a contract. Failing to reach Redis counts as a miss, so the
+    /// worker keeps reading from MongoDB while Redis is down.
+    pub(crate) async fn get(&self, address: H160) -> Option<ContractAddress> {
+        match self {
+            ClassificationCache::Memory { entries, ttl } => {
+                let mut entries = entries.lock().unwrap();
+
+                match entries.get(&address) {
+                    Some((contract_address, cached_at)) if cached_at.elapsed() < *ttl => {
+                        Some(contract_address.clone())
+                    }
+                    Some(_) => {
+                        entries.pop(&address);
+                        None
+                    }
+                    None => None,
+                }
+            }
+            #[cfg(feature = "redis")]
+            ClassificationCache::Redis {
+                connection, prefix, ..
+            } => {
+                let cached: redis::RedisResult<Option<String>> = redis::cmd("GET")
+                    .arg(format!("{}{:#x}", prefix, address))
+                    .query_async(&mut connection.clone())
+                    .await;
+
+                match cached {
+                    Ok(cached) => cached.and_then(|cached| serde_json::from_str(&cached).ok()),
+                    Err(error) => {
+                        eprintln!(
+                            "Warning: Could not read the classification of {:#x} from Redis... {}",
+                            address, error
+                        );
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn put(&self, contract_address: &ContractAddress) {
+        match self {
+            ClassificationCache::Memory { entries, .. } => {
+                entries.lock().unwrap().put(
+                    contract_address.address,
+                    (contract_address.clone(), Instant::now()),
+                );
+            }
+            #[cfg(feature = "redis")]
+            ClassificationCache::Redis {
+                connection,
+                prefix,
+                ttl,
+            } => {
+                let cached = match serde_json::to_string(contract_address) {
+                    Ok(cached) => cached,
+                    Err(_) => return,
+                };
+
+                let result: redis::RedisResult<()> = redis::cmd("SET")
+                    .arg(format!("{}{:#x}", prefix, contract_address.address))
+                    .arg(cached)
+                    .arg("EX")
+                    .arg(ttl.as_secs().max(1))
+                    .query_async(&mut connection.clone())
+                    .await;
+
+                if let Err(error) = result {
+                    eprintln!(
+                        "Warning: Could not cache the classification of {:#x} in Redis... {}",
+                        contract_address.address, error
+                    );
+                }
+            }
+        }
+    }
+
+    /// Drops the classification of a contract the worker is changing.
+    pub(crate) async fn evict(&self, address: H160) {
+        match self {
+            ClassificationCache::Memory { entries, .. } => {
+                entries.lock().unwrap().pop(&address);
+            }
+            #[cfg(feature = "redis")]
+            ClassificationCache::Redis {
+                connection, prefix, ..
+            } => {
+                let result: redis::RedisResult<()> = redis::cmd("DEL")
+                    .arg(format!("{}{:#x}", prefix, address))
+                    .query_async(&mut connection.clone())
+                    .await;
+
+                if let Err(error) = result {
+                    eprintln!(
+                        "Warning: Could not evict the classification of {:#x} from Redis, it expires on its own... {}",
+                        address, error
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{amount::Amount, processor::Signatures, store::Store, RpcTransport, WorkerConfig};
+use std::{
+    error,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use web3::{
+    ethabi::{encode, Token},
+    types::{Bytes, TransactionReceipt, TransactionRequest, H160, H256, U256, U64},
+    Web3,
+};
+
+/// Constructor of a canary, returning the 120 bytes of runtime code following it.
+const CANARY_CONSTRUCTOR: &str = "61007880600c6000396000f3";
+
+/// Runtime code of a canary. `supportsInterface(bytes4)` answers true for ERC165 and for the
+/// interface id replacing `________`. Any other call emits a log from its calldata: the number of
+/// topics as a single byte, 3 or 4, then the topics, then the data of the log.
+const CANARY_RUNTIME: &str = concat!(
+    "60003560e01c6301ffc9a714605a57",
+    "60003560f81c80600314602657600414603f57600080fd",
+    "5b50604135602135600135606136038060616000376000a300",
+    "5b606135604135602135600135608136038060816000376000a400",
+    "5b60043560e01c8063________14906301ffc9a714176000526020",
+    "6000f3",
+);
+
+const ERC20_INTERFACE_ID: &str = "36372b07";
+const ERC721_INTERFACE_ID: &str = "80ac58cd";
+const ERC1155_INTERFACE_ID: &str = "d9b67a26";
+
+/// Time between polls of transaction receipts and of the last processed block.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Outcome of a self-test.
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    /// Token type and address of every canary deployed.
+    pub contracts: Vec<(String, H160)>,
+    /// Block of the last scripted transfer.
+    pub block_number: u64,
+    /// Number of classifications and balances checked.
+    pub checks: usize,
+    /// Checks that failed, empty when the pipeline works end to end.
+    pub failures: Vec<String>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A log for a canary to emit.
+struct ScriptedLog {
+    canary: H160,
+    topics: Vec<H256>,
+    data: Vec<u8>,
+}
+
+impl ScriptedLog {
+    fn calldata(&self) -> Vec<u8> {
+        let mut calldata = vec![self.topics.len() as u8];
+        for topic in &self.topics {
+            calldata.extend_from_slice(topic.as_bytes());
+        }
+        calldata.extend_from_slice(&self.data);
+        calldata
+    }
+}
+
+/// Deploys an ERC20, an ERC721 and an ERC1155 canary from the first account the provider unlocks,
+/// emits scripted mints and transfers through them, waits up to `timeout` for the worker running
+/// against `store` to process their last block, then checks the classifications and balances it
+/// stored. Refuses to run on mainnet.
+pub(crate) async fn self_test(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    config: &WorkerConfig,
+    timeout: Duration,
+) -> Result<SelfTestReport, Box<dyn error::Error + Send + Sync>> {
+    let chain_id = web3.eth().chain_id().await?;
+    if chain_id == U256::one() {
+        return Err("The self-test deploys contracts and does not run on mainnet".into());
+    }
+
+    let deployer = *web3
+        .eth()
+        .accounts()
+        .await?
+        .first()
+        .ok_or("The provider has no unlocked account to deploy the canary contracts from")?;
+    let alice = H160::from_low_u64_be(0xa11ce);
+    let bob = H160::from_low_u64_be(0xb0b);
+    let zero = H160::zero();
+
+    println!(
+        "Deploying canary contracts from {:#x} on chain {}",
+        deployer, chain_id
+    );
+
+    let erc20 = deploy(web3, deployer, ERC20_INTERFACE_ID).await?;
+    let erc721 = deploy(web3, deployer, ERC721_INTERFACE_ID).await?;
+    let erc1155 = deploy(web3, deployer, ERC1155_INTERFACE_ID).await?;
+
+    let signatures = Signatures::new();
+    let erc20_transfer = |from: H160, to: H160, value: u64| ScriptedLog {
+        canary: erc20,
+        topics: vec![signatures.erc_20_and_721_transfer, from.into(), to.into()],
+        data: encode(&[Token::Uint(value.into())]),
+    };
+    let erc721_transfer = |from: H160, to: H160, token_id: u64| ScriptedLog {
+        canary: erc721,
+        topics: vec![
+            signatures.erc_20_and_721_transfer,
+            from.into(),
+            to.into(),
+            H256::from_low_u64_be(token_id),
+        ],
+        data: Vec::new(),
+    };
+    let erc1155_transfer = |from: H160, to: H160, ids: &[u64], values: &[u64]| {
+        let uints = |numbers: &[u64]| {
+            numbers
+                .iter()
+                .map(|number| Token::Uint((*number).into()))
+                .collect::<Vec<_>>()
+        };
+
+        if ids.len() == 1 {
+            ScriptedLog {
+                canary: erc1155,
+                topics: vec![
+                    signatures.erc_1155_transfer_single,
+                    deployer.into(),
+                    from.into(),
+                    to.into(),
+                ],
+                data: encode(&[uints(ids).remove(0), uints(values).remove(0)]),
+            }
+        } else {
+            ScriptedLog {
+                canary: erc1155,
+                topics: vec![
+                    signatures.erc_1155_transfer_batch,
+                    deployer.into(),
+                    from.into(),
+                    to.into(),
+                ],
+                data: encode(&[Token::Array(uints(ids)), Token::Array(uints(values))]),
+            }
+        }
+    };
+
+    let script = [
+        erc20_transfer(zero, deployer, 1000),
+        erc20_transfer(deployer, alice, 300),
+        erc20_transfer(alice, bob, 100),
+        erc721_transfer(zero, alice, 1),
+        erc721_transfer(zero, alice, 2),
+        erc721_transfer(alice, bob, 1),
+        erc1155_transfer(zero, alice, &[7], &[10]),
+        erc1155_transfer(alice, bob, &[7], &[4]),
+        erc1155_transfer(zero, bob, &[8, 9], &[1, 2]),
+    ];
+
+    let mut block_number = U64::zero();
+    for log in &script {
+        let receipt = send(web3, deployer, Some(log.canary), log.calldata()).await?;
+        block_number = block_number.max(receipt.block_number.unwrap_or_default());
+    }
+
+    println!(
+        "Emitted {} canary transfers, waiting for the worker to process block {}",
+        script.len(),
+        block_number
+    );
+
+    let started_at = Instant::now();
+    while store.last_processed_block().await?.unwrap_or_default() < block_number {
+        if started_at.elapsed() > timeout {
+            return Err(format!(
+                "The worker did not process block {} within {} seconds, is it running against this database and chain?",
+                block_number,
+                timeout.as_secs()
+            )
+            .into());
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+
+    let token_types = [(erc20, "ERC20"), (erc721, "ERC721"), (erc1155, "ERC1155")];
+    let balances = [
+        (erc20, None, deployer, 700),
+        (erc20, None, alice, 200),
+        (erc20, None, bob, 100),
+        (erc721, Some("1"), alice, 0),
+        (erc721, Some("1"), bob, 1),
+        (erc721, Some("2"), alice, 1),
+        (erc1155, Some("7"), alice, 6),
+        (erc1155, Some("7"), bob, 4),
+        (erc1155, Some("8"), bob, 1),
+        (erc1155, Some("9"), bob, 2),
+    ];
+
+    let mut failures = Vec::new();
+
+    for (canary, token_type) in token_types {
+        let classification = store
+            .contract_address(canary)
+            .await?
+            .map(|contract| contract.token_type);
+
+        if classification.as_deref() != Some(token_type) {
+            failures.push(format!(
+                "Canary {:#x} is classified as {}, expected {}",
+                canary,
+                classification.as_deref().unwrap_or("nothing"),
+                token_type
+            ));
+        }
+    }
+
+    for (canary, token_id, owner, expected) in balances {
+        let amount = store
+            .ownership_amount(canary, token_id, config.owner_protection.protect(owner))
+            .await?;
+        let expected = Amount::from(U256::from(expected));
+
+        if amount != expected {
+            failures.push(format!(
+                "Owner {:#x} holds {} of token {} of canary {:#x}, expected {}",
+                owner,
+                amount,
+                token_id.unwrap_or("-"),
+                canary,
+                expected
+            ));
+        }
+    }
+
+    Ok(SelfTestReport {
+        contracts: token_types
+            .iter()
+            .map(|(canary, token_type)| (token_type.to_string(), *canary))
+            .collect(),
+        block_number: block_number.as_u64(),
+        checks: token_types.len() + balances.len(),
+        failures,
+    })
+}
+
+/// Deploys a canary reporting `interface_id` through ERC165.
+async fn deploy(
+    web3: &Web3<RpcTransport>,
+    deployer: H160,
+    interface_id: &str,
+) -> Result<H160, Box<dyn error::Error + Send + Sync>> {
+    let code = hex::decode(format!(
+        "{}{}",
+        CANARY_CONSTRUCTOR,
+        CANARY_RUNTIME.replace("________", interface_id)
+    ))?;
+    let receipt = send(web3, deployer, None, code).await?;
+
+    receipt.contract_address.ok_or_else(|| {
+        format!(
+            "Transaction {:#x} deployed no canary contract",
+            receipt.transaction_hash
+        )
+        .into()
+    })
+}
+
+/// Sends a transaction from an unlocked account and waits for its receipt, failing when it
+/// reverted.
+async fn send(
+    web3: &Web3<RpcTransport>,
+    from: H160,
+    to: Option<H160>,
+    data: Vec<u8>,
+) -> Result<TransactionReceipt, Box<dyn error::Error + Send + Sync>> {
+    let transaction_hash = web3
+        .eth()
+        .send_transaction(TransactionRequest {
+            from,
+            to,
+            data: Some(Bytes(data)),
+            ..Default::default()
+        })
+        .await?;
+
+    loop {
+        match web3.eth().transaction_receipt(transaction_hash).await? {
+            Some(receipt) if receipt.status == Some(U64::one()) => return Ok(receipt),
+            Some(_) => return Err(format!("Transaction {:#x} reverted", transaction_hash).into()),
+            None => sleep(POLL_INTERVAL).await,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::RpcTransport;
+use jsonrpc_core::{Call, Value};
+use serde::Serialize;
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+use web3::{
+    transports::Http,
+    types::{H160, U64},
+    BatchTransport, Transport, Web3,
+};
+
+/// Batch sizes probed, in order, until the provider rejects one.
+const BATCH_SIZES: [usize; 4] = [2, 10, 100, 1000];
+
+/// What the RPC provider supports, probed once on startup.
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub(crate) struct ProviderCapabilities {
+    /// `web3_clientVersion`, `None` when the provider does not tell.
+    pub client_version: Option<String>,
+    /// `eth_getBlockReceipts`, fetching the receipts of a block in one call.
+    pub block_receipts: bool,
+    /// `trace_block`, the trace API of Erigon, Nethermind and OpenEthereum.
+    pub trace_block: bool,
+    /// `debug_traceBlockByNumber`, the tracer of Geth.
+    pub debug_trace_block: bool,
+    /// Whether `eth_getBlockByNumber` accepts the `finalized` tag.
+    pub finalized_tag: bool,
+    /// Largest JSON-RPC batch accepted among `BATCH_SIZES`, `None` when batches are rejected.
+    pub max_batch_size: Option<usize>,
+    /// Largest `eth_getLogs` block range accepted, up to the configured `log_range_size`.
+    pub max_log_range: Option<u64>,
+}
+
+impl ProviderCapabilities {
+    /// Probes the provider at `endpoint`, which `web3` is connected to. Ranges of logs are probed
+    /// from `log_range_size` blocks down.
+    pub(crate) async fn probe(
+        web3: &Web3<RpcTransport>,
+        endpoint: &str,
+        log_range_size: u64,
+    ) -> Self {
+        let transport = web3.transport();
+
+        let client_version = match transport.execute("web3_clientVersion", vec![]).await {
+            Ok(Value::String(client_version)) => Some(client_version),
+            _ => None,
+        };
+
+        // The genesis block has no transactions, so probing it is cheap even where tracing is not.
+        let genesis = Value::String("0x0".to_string());
+
+        Self {
+            client_version,
+            block_receipts: supports(transport, "eth_getBlockReceipts", vec![genesis.clone()])
+                .await,
+            trace_block: supports(transport, "trace_block", vec![genesis.clone()]).await,
+            debug_trace_block: supports(
+                transport,
+                "debug_traceBlockByNumber",
+                vec![genesis, Value::Object(Default::default())],
+            )
+            .await,
+            finalized_tag: matches!(finalized_block(web3).await, Ok(Some(_))),
+            max_batch_size: max_batch_size(endpoint).await,
+            max_log_range: max_log_range(web3, log_range_size).await,
+        }
+    }
+
+    /// Logs what was detected and the strategy it selects.
+    pub(crate) fn log(&self, log_range_size: u64) {
+        let supported = |supported: bool| {
+            if supported {
+                "supported"
+            } else {
+                "unsupported"
+            }
+        };
+
+        println!(
+            "Detected provider {}: eth_getBlockReceipts {}, trace_block {}, debug_traceBlockByNumber {}, finalized tag {}, batches {}",
+            self.client_version.as_deref().unwrap_or("of unknown version"),
+            supported(self.block_receipts),
+            supported(self.trace_block),
+            supported(self.debug_trace_block),
+            supported(self.finalized_tag),
+            match self.max_batch_size {
+                Some(max_batch_size) => format!("of at least {} calls", max_batch_size),
+                None => "unsupported".to_string(),
+            },
+        );
+
+        match self.max_log_range {
+            Some(max_log_range) if max_log_range < log_range_size => println!(
+                "Fetching logs in ranges of {} blocks, the most the provider accepts, instead of {}",
+                max_log_range, log_range_size
+            ),
+            Some(_) => println!("Fetching logs in ranges of {} blocks", log_range_size),
+            None => eprintln!(
+                "Warning: Could not probe the eth_getLogs range limit of the provider, fetching logs in ranges of {} blocks",
+                log_range_size
+            ),
+        }
+
+        if self.finalized_tag {
+            println!("Fetching blocks up to the finalized block in ranges");
+        }
+    }
+}
+
+/// Whether the provider implements a method. Errors other than an unknown method, such as a
+/// pruned node missing the genesis state, still tell it is implemented.
+async fn supports(transport: &RpcTransport, method: &str, params: Vec<Value>) -> bool {
+    match transport.execute(method, params).await {
+        Ok(_) => true,
+        Err(web3::Error::Rpc(error)) => {
+            let message = error.message.to_lowercase();
+
+            error.code != jsonrpc_core::ErrorCode::MethodNotFound
+                && ![
+                    "not supported",
+                    "unsupported",
+                    "does not exist",
+                    "not available",
+                    "not allowed",
+                    "not whitelisted",
+                ]
+                .iter()
+                .any(|pattern| message.contains(pattern))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Number of the block the provider reports as finalized, `None` when it does not know one.
+pub(crate) async fn finalized_block(web3: &Web3<RpcTransport>) -> web3::Result<Option<U64>> {
+    let block = web3
+        .transport()
+        .execute(
+            "eth_getBlockByNumber",
+            vec![Value::String("finalized".to_string()), Value::Bool(false)],
+        )
+        .await?;
+
+    Ok(block
+        .get("number")
+        .and_then(Value::as_str)
+        .and_then(|number| U64::from_str_radix(number.trim_start_matches("0x"), 16).ok()))
+}
+
+/// Largest batch of `eth_chainId` calls among `BATCH_SIZES` the provider answers in full. Batches
+/// are sent over a plain HTTP transport, since the provider's transport may wrap them away.
+async fn max_batch_size(endpoint: &str) -> Option<usize> {
+    let http = Http::new(endpoint).ok()?;
+    let mut max_batch_size = None;
+
+    for batch_size in BATCH_SIZES {
+        let requests: Vec<_> = (0..batch_size)
+            .map(|_| http.prepare("eth_chainId", vec![]))
+            .collect::<Vec<(_, Call)>>();
+
+        match http.send_batch(requests).await {
+            Ok(responses)
+                if responses.len() == batch_size && responses.iter().all(Result::is_ok) =>
+            {
+                max_batch_size = Some(batch_size)
+            }
+            _ => break,
+        }
+    }
+
+    max_batch_size
+}
+
+/// Largest range of blocks, halving from `log_range_size`, whose logs the provider returns. The
+/// logs of an address nothing is deployed at are requested, so only the range limits of the
+/// provider apply and not its result limits. `None` when the provider could not be reached or
+/// rejects every range.
+async fn max_log_range(web3: &Web3<RpcTransport>, log_range_size: u64) -> Option<u64> {
+    let latest_block = web3.eth().block_number().await.ok()?;
+    let mut range = log_range_size.clamp(1, latest_block.as_u64().max(1));
+
+    loop {
+        let filter = web3::types::FilterBuilder::default()
+            .address(vec![H160::from_low_u64_be(0xdead)])
+            .from_block((latest_block + 1 - range).into())
+            .to_block(latest_block.into())
+            .build();
+
+        match web3.eth().logs(filter).await {
+            Ok(_) => return Some(range),
+            Err(web3::Error::Rpc(_)) if range > 1 => range /= 2,
+            Err(_) => return None,
+        }
+    }
+}
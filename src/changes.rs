@@ -0,0 +1,242 @@
+use crate::{
+    amount::Amount,
+    schema::OwnershipSchema,
+    store::{Store, TokenOwnership},
+};
+use futures::stream::{self, Stream, StreamExt};
+use mongodb::{
+    bson::{Bson, Document},
+    options::ClientOptions,
+    Client, Cursor, Database,
+};
+use serde::Serialize;
+use std::{error, time::Duration};
+use web3::types::H160;
+
+/// Time the server waits for new changes before answering with an empty batch.
+const MAX_AWAIT_TIME: Duration = Duration::from_secs(1);
+
+/// What happened to an ownership document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnershipChangeKind {
+    Inserted,
+    /// Updated or replaced.
+    Updated,
+    Deleted,
+}
+
+/// A change to a document of the ownership collection, read from its change stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct OwnershipChange {
+    pub kind: OwnershipChangeKind,
+    /// `_id` of the ownership document.
+    pub id: Bson,
+    /// The ownership after the change, `None` once deleted, including when an updated ownership
+    /// was deleted before it could be looked up.
+    pub ownership: Option<TokenOwnership>,
+    /// The ownership before an update or a delete, only known when pre-images are requested and
+    /// the collection records them.
+    pub previous: Option<TokenOwnership>,
+    /// Token following the changes after this one with `OwnershipChanges::changes`.
+    pub resume_token: Document,
+}
+
+impl OwnershipChange {
+    /// Contract, token id and owner of the ownership, unknown for deletes without a pre-image.
+    pub fn ownership_key(&self) -> Option<(H160, Option<&str>, H160)> {
+        self.ownership
+            .as_ref()
+            .or(self.previous.as_ref())
+            .map(|ownership| {
+                (
+                    ownership.contract_address,
+                    ownership.token_id.as_deref(),
+                    ownership.owner,
+                )
+            })
+    }
+
+    /// How much the exact amount held changed, unknown for updates and deletes without a
+    /// pre-image.
+    pub fn amount_change(&self) -> Option<Amount> {
+        let amount = |ownership: &Option<TokenOwnership>| {
+            ownership.as_ref().map(TokenOwnership::exact_amount)
+        };
+
+        match self.kind {
+            OwnershipChangeKind::Inserted => amount(&self.ownership),
+            OwnershipChangeKind::Updated => {
+                Some(amount(&self.ownership)? - amount(&self.previous)?)
+            }
+            OwnershipChangeKind::Deleted => amount(&self.previous).map(|amount| -amount),
+        }
+    }
+}
+
+/// Typed changes of the ownership model for other Rust services, tailed from a MongoDB change
+/// stream on the ownership collection instead of diffing its documents. Change streams need a
+/// replica set or a sharded cluster.
+///
+/// Unlike the delta feed, changes are per document rather than per block, so a block touching an
+/// ownership several times may show up as a single change, and reverted blocks show up as changes
+/// like any other.
+#[derive(Debug, Clone)]
+pub struct OwnershipChanges {
+    store: Store,
+    retry_interval: Duration,
+    pre_images: bool,
+}
+
+impl OwnershipChanges {
+    pub fn new(database: &Database, retry_interval: Duration) -> Self {
+        Self {
+            store: Store::new(database),
+            retry_interval,
+            pre_images: false,
+        }
+    }
+
+    pub async fn connect(
+        database_host: String,
+        database_name: String,
+        retry_interval: Duration,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let client = Client::with_options(ClientOptions::parse(database_host).await?)?;
+
+        Ok(Self::new(&client.database(&database_name), retry_interval))
+    }
+
+    /// Prefix the worker's collection names start with.
+    pub fn with_collection_prefix(self, collection_prefix: &str) -> Self {
+        Self {
+            store: self.store.with_collection_prefix(collection_prefix),
+            ..self
+        }
+    }
+
+    /// Collection and field names the worker stores the ownership model under.
+    pub fn with_ownership_schema(self, ownership_schema: OwnershipSchema) -> Self {
+        Self {
+            store: self.store.with_ownership_schema(ownership_schema),
+            ..self
+        }
+    }
+
+    /// Requests the ownerships before updates and deletes, which MongoDB 6.0 records once
+    /// `changeStreamPreAndPostImages` is enabled on the collection.
+    pub fn with_pre_images(self) -> Self {
+        Self {
+            pre_images: true,
+            ..self
+        }
+    }
+
+    /// The changes following the change of `resume_token`, or following the opening of the stream
+    /// without one. Failed reads are reported and the stream is resumed after the last change it
+    /// yielded.
+    ///
+    /// The stream ends when the change stream is invalidated, because the ownership collection
+    /// was dropped or renamed.
+    pub fn changes(
+        &self,
+        resume_token: Option<Document>,
+    ) -> impl Stream<Item = OwnershipChange> + Send + 'static {
+        let changes = self.clone();
+
+        stream::unfold(
+            (changes, resume_token, None::<Cursor<Document>>),
+            |(changes, mut resume_token, mut cursor)| async move {
+                loop {
+                    let events = match &mut cursor {
+                        Some(events) => events,
+                        None => match changes
+                            .store
+                            .watch_ownerships(
+                                resume_token.clone(),
+                                changes.pre_images,
+                                MAX_AWAIT_TIME,
+                            )
+                            .await
+                        {
+                            Ok(events) => cursor.insert(events),
+                            Err(error) => {
+                                eprintln!(
+                                    "Error: Could not open the change stream of the ownership collection, retrying... {}",
+                                    error
+                                );
+                                tokio::time::sleep(changes.retry_interval).await;
+                                continue;
+                            }
+                        },
+                    };
+
+                    let event = match events.next().await {
+                        Some(Ok(event)) => event,
+                        Some(Err(error)) => {
+                            eprintln!(
+                                "Error: Could not read the change stream of the ownership collection, resuming... {}",
+                                error
+                            );
+                            cursor = None;
+                            tokio::time::sleep(changes.retry_interval).await;
+                            continue;
+                        }
+                        None => {
+                            cursor = None;
+                            continue;
+                        }
+                    };
+
+                    if let Ok(token) = event.get_document("_id") {
+                        resume_token = Some(token.clone());
+                    }
+
+                    match event.get_str("operationType").unwrap_or_default() {
+                        "invalidate" => {
+                            eprintln!(
+                                "Error: The change stream of the ownership collection was invalidated, the collection was dropped or renamed"
+                            );
+                            return None;
+                        }
+                        "insert" | "update" | "replace" | "delete" => {}
+                        _ => continue,
+                    }
+
+                    match changes.change(event) {
+                        Ok(change) => return Some((change, (changes, resume_token, cursor))),
+                        Err(error) => eprintln!(
+                            "Warning: Skipping a change of the ownership collection which could not be read... {}",
+                            error
+                        ),
+                    }
+                }
+            },
+        )
+    }
+
+    /// Reads an insert, update, replace or delete event of the change stream.
+    fn change(&self, event: Document) -> mongodb::error::Result<OwnershipChange> {
+        let ownership = |field: &str| match event.get_document(field) {
+            Ok(document) => self.store.stored_ownership(document.clone()).map(Some),
+            Err(_) => Ok(None),
+        };
+
+        Ok(OwnershipChange {
+            kind: match event.get_str("operationType").unwrap_or_default() {
+                "insert" => OwnershipChangeKind::Inserted,
+                "delete" => OwnershipChangeKind::Deleted,
+                _ => OwnershipChangeKind::Updated,
+            },
+            id: event
+                .get_document("documentKey")
+                .ok()
+                .and_then(|key| key.get("_id"))
+                .cloned()
+                .unwrap_or(Bson::Null),
+            ownership: ownership("fullDocument")?,
+            previous: ownership("fullDocumentBeforeChange")?,
+            resume_token: event.get_document("_id").cloned().unwrap_or_default(),
+        })
+    }
+}
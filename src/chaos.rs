@@ -0,0 +1,220 @@
+//! Fault injection used to exercise the worker's recovery paths, only compiled with the `chaos`
+//! feature.
+
+use crate::{
+    amount::Amount,
+    ownership::OwnershipDelta,
+    storage::{JournaledBlock, OwnershipStore, StorageResult},
+    store::ClassificationMethod,
+};
+use futures::future::{self, BoxFuture, FutureExt};
+use jsonrpc_core::{Call, Value};
+use std::{future::Future, io, sync::Arc};
+use web3::{
+    types::{Log, H160, H256, U64},
+    RequestId, Transport,
+};
+
+/// Probabilities, between 0 and 1, of each injected fault.
+#[derive(Debug, Clone, Default)]
+pub struct ChaosConfig {
+    /// Chance of an RPC call failing before it reaches the provider.
+    pub rpc_failure_rate: f64,
+    /// Chance of a MongoDB operation timing out before it reaches the database.
+    pub mongo_timeout_rate: f64,
+    /// Chance of an ownership store operation failing, either before it reaches the store or once
+    /// it went through, as when the connection drops before the answer arrives.
+    pub store_failure_rate: f64,
+    /// Chance of each fetched log being returned twice.
+    pub duplicate_log_rate: f64,
+    /// Chance of a processed block being marked as orphaned, forcing a rollback and re-index.
+    pub reorg_rate: f64,
+}
+
+impl ChaosConfig {
+    pub(crate) fn rpc_failure(&self) -> bool {
+        roll(self.rpc_failure_rate)
+    }
+
+    pub(crate) fn mongo_timeout(&self) -> Option<mongodb::error::Error> {
+        if roll(self.mongo_timeout_rate) {
+            eprintln!("Chaos: Injecting MongoDB timeout");
+            Some(io::Error::new(io::ErrorKind::TimedOut, "chaos: injected MongoDB timeout").into())
+        } else {
+            None
+        }
+    }
+
+    fn store_failure(&self, when: &str) -> StorageResult<()> {
+        if roll(self.store_failure_rate) {
+            eprintln!("Chaos: Failing ownership store operation {}", when);
+            Err(format!("chaos: injected ownership store failure {}", when).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn duplicate_logs(&self, logs: Vec<Log>) -> Vec<Log> {
+        logs.into_iter()
+            .flat_map(|log| {
+                if roll(self.duplicate_log_rate) {
+                    eprintln!("Chaos: Duplicating log {:?}", log.transaction_hash);
+                    vec![log.clone(), log]
+                } else {
+                    vec![log]
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn reorg(&self) -> bool {
+        roll(self.reorg_rate)
+    }
+}
+
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
+
+/// Transport wrapper failing a configurable share of RPC calls.
+#[derive(Debug, Clone)]
+pub struct ChaosTransport<T> {
+    inner: T,
+    config: Arc<ChaosConfig>,
+}
+
+impl<T> ChaosTransport<T> {
+    pub fn new(inner: T, config: Arc<ChaosConfig>) -> Self {
+        Self { inner, config }
+    }
+}
+
+impl<T> Transport for ChaosTransport<T>
+where
+    T: Transport,
+    T::Out: Send + 'static,
+{
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        self.inner.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        if self.config.rpc_failure() {
+            eprintln!("Chaos: Failing RPC request {}", id);
+            return future::ready(Err(web3::Error::Transport(
+                "chaos: injected RPC failure".to_string(),
+            )))
+            .boxed();
+        }
+
+        self.inner.send(id, request).boxed()
+    }
+}
+
+/// Ownership store wrapper failing a configurable share of its operations.
+#[derive(Debug)]
+pub struct ChaosStore {
+    inner: Arc<dyn OwnershipStore>,
+    config: Arc<ChaosConfig>,
+}
+
+impl ChaosStore {
+    pub fn new(inner: Arc<dyn OwnershipStore>, config: Arc<ChaosConfig>) -> Self {
+        Self { inner, config }
+    }
+
+    /// Runs an operation of the inner store, failing before or after it.
+    fn inject<'a, T: Send + 'a>(
+        &'a self,
+        operation: impl Future<Output = StorageResult<T>> + Send + 'a,
+    ) -> BoxFuture<'a, StorageResult<T>> {
+        async move {
+            self.config.store_failure("before it went through")?;
+            let output = operation.await?;
+            self.config.store_failure("after it went through")?;
+
+            Ok(output)
+        }
+        .boxed()
+    }
+}
+
+impl OwnershipStore for ChaosStore {
+    fn apply_deltas<'a>(
+        &'a self,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        self.inject(self.inner.apply_deltas(block_number, block_hash, deltas))
+    }
+
+    fn revert_deltas<'a>(
+        &'a self,
+        block_hash: H256,
+        inverse_deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        self.inject(self.inner.revert_deltas(block_hash, inverse_deltas))
+    }
+
+    fn ownership_amount<'a>(
+        &'a self,
+        contract_address: H160,
+        token_id: Option<&'a str>,
+        owner: H160,
+    ) -> BoxFuture<'a, StorageResult<Amount>> {
+        self.inject(
+            self.inner
+                .ownership_amount(contract_address, token_id, owner),
+        )
+    }
+
+    fn checkpoint(&self) -> BoxFuture<'_, StorageResult<Option<U64>>> {
+        self.inject(self.inner.checkpoint())
+    }
+
+    fn set_checkpoint(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        self.inject(self.inner.set_checkpoint(block_number))
+    }
+
+    fn apply_block<'a>(&'a self, block: &'a JournaledBlock) -> BoxFuture<'a, StorageResult<()>> {
+        self.inject(self.inner.apply_block(block))
+    }
+
+    fn journaled_block(
+        &self,
+        block_number: U64,
+    ) -> BoxFuture<'_, StorageResult<Option<JournaledBlock>>> {
+        self.inject(self.inner.journaled_block(block_number))
+    }
+
+    fn revert_block(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        self.inject(self.inner.revert_block(block_number))
+    }
+
+    fn prune_journal(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        self.inject(self.inner.prune_journal(block_number))
+    }
+
+    fn token_type(&self, contract_address: H160) -> BoxFuture<'_, StorageResult<Option<String>>> {
+        self.inject(self.inner.token_type(contract_address))
+    }
+
+    fn set_token_type<'a>(
+        &'a self,
+        contract_address: H160,
+        token_type: &'a str,
+        classified_by: ClassificationMethod,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        self.inject(
+            self.inner
+                .set_token_type(contract_address, token_type, classified_by),
+        )
+    }
+
+    fn reset(&self) -> BoxFuture<'_, StorageResult<()>> {
+        self.inject(self.inner.reset())
+    }
+}
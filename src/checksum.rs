@@ -0,0 +1,54 @@
+//! Rolling checksum of the ownership model, so deployments indexing the same chain can compare
+//! their models without exchanging them.
+//!
+//! The checksum of a contract is the XOR of a 64 bit hash of every ownership with a non-zero
+//! amount. XOR is its own inverse and does not depend on order, so applying a delta only XORs out
+//! the hash of the ownership's previous amount and XORs in the hash of its new one.
+
+use crate::amount::Amount;
+use web3::{signing::keccak256, types::H160};
+
+/// Hash of an ownership, the first 8 bytes of the Keccak-256 of its contract, token id, owner and
+/// amount. Ownerships with a zero amount count as missing, whether they are kept or deleted.
+fn ownership_hash(
+    contract_address: H160,
+    token_id: Option<&str>,
+    owner: H160,
+    amount: Amount,
+) -> i64 {
+    if amount.is_zero() {
+        return 0;
+    }
+
+    let hash = keccak256(
+        format!(
+            "{:#x}:{}:{:#x}:{}",
+            contract_address,
+            token_id.unwrap_or_default(),
+            owner,
+            amount
+        )
+        .as_bytes(),
+    );
+
+    i64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+/// The value to XOR into the checksum of a contract when the amount of one of its ownerships goes
+/// from `previous` to `amount`.
+pub(crate) fn checksum_change(
+    contract_address: H160,
+    token_id: Option<&str>,
+    owner: H160,
+    previous: Amount,
+    amount: Amount,
+) -> i64 {
+    ownership_hash(contract_address, token_id, owner, previous)
+        ^ ownership_hash(contract_address, token_id, owner, amount)
+}
+
+/// The checksum as it is shown, 16 hex digits.
+#[cfg(feature = "api")]
+pub(crate) fn format_checksum(checksum: i64) -> String {
+    format!("{:016x}", checksum as u64)
+}
@@ -0,0 +1,237 @@
+//! Export of the transfer history to ClickHouse, only compiled with the `clickhouse` feature.
+//!
+//! Transfers are exported once their block is deeper than the reorg depth, so the ClickHouse table
+//! only ever gets final transfers and nothing has to be deleted from it after a reorg.
+
+use crate::{
+    backoff::{Backoff, RetryPolicy},
+    store::{Store, TransferRecord},
+    START_BLOCK,
+};
+use futures::TryStreamExt;
+use serde_json::json;
+use std::{error, time::Duration};
+use tokio::time::sleep;
+use web3::types::U64;
+
+/// `sync_state` id of the last block whose transfers were exported.
+const EXPORT_ID: &str = "export:clickhouse";
+
+/// Blocks whose transfers are exported before the export checkpoint moves.
+const EXPORT_RANGE: u64 = 10_000;
+
+/// Time waited before looking for final blocks again once every final block was exported.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A ClickHouse table transfers are inserted into over the HTTP interface, in `JSONEachRow`
+/// batches.
+#[derive(Debug, Clone)]
+pub struct ClickHouseSink {
+    client: reqwest::Client,
+    url: String,
+    table: String,
+    user: Option<String>,
+    password: Option<String>,
+    batch_size: usize,
+}
+
+impl ClickHouseSink {
+    /// Sink inserting into `table`, which may be qualified with its database, of the server at
+    /// `url`, such as `http://localhost:8123`, `batch_size` transfers per `INSERT`.
+    pub fn new(
+        url: String,
+        table: String,
+        user: Option<String>,
+        password: Option<String>,
+        batch_size: usize,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            table,
+            user,
+            password,
+            batch_size: batch_size.max(1),
+        }
+    }
+
+    async fn execute(
+        &self,
+        query: &str,
+        body: String,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .query(&[("query", query)])
+            .body(body);
+        if let Some(user) = &self.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(
+                format!("ClickHouse answered {}: {}", status, response.text().await?).into(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Creates the table when it does not exist. Transfers exported again after a failure or a
+    /// reindex are merged away by their id.
+    pub async fn create_table(&self) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                `id` String,
+                `transaction_hash` String,
+                `log_index` UInt32,
+                `block_number` UInt64,
+                `block_hash` String,
+                `contract_address` String,
+                `token_type` LowCardinality(String),
+                `token_id` Nullable(String),
+                `from` String,
+                `to` String,
+                `quantity` Float64,
+                `amount` Nullable(UInt256)
+            )
+            ENGINE = ReplacingMergeTree
+            ORDER BY (`contract_address`, `block_number`, `id`)",
+            self.table
+        );
+
+        self.execute(&query, String::new()).await
+    }
+
+    /// Inserts `transfers` in one `INSERT`.
+    pub async fn insert(
+        &self,
+        transfers: &[TransferRecord],
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        if transfers.is_empty() {
+            return Ok(());
+        }
+
+        let mut rows = String::new();
+        for transfer in transfers {
+            // UInt256 is read from a JSON string, which holds amounts beyond the f64 range exactly.
+            let row = json!({
+                "id": transfer.id,
+                "transaction_hash": format!("{:#x}", transfer.transaction_hash),
+                "log_index": transfer.log_index,
+                "block_number": transfer.block_number,
+                "block_hash": format!("{:#x}", transfer.block_hash),
+                "contract_address": format!("{:#x}", transfer.contract_address),
+                "token_type": transfer.token_type,
+                "token_id": transfer.token_id,
+                "from": format!("{:#x}", transfer.from),
+                "to": format!("{:#x}", transfer.to),
+                "quantity": transfer.quantity,
+                "amount": transfer.amount.map(|amount| amount.to_string()),
+            });
+            rows.push_str(&row.to_string());
+            rows.push('\n');
+        }
+
+        self.execute(
+            &format!("INSERT INTO {} FORMAT JSONEachRow", self.table),
+            rows,
+        )
+        .await
+    }
+}
+
+/// Exports the transfers of the final blocks after the export checkpoint, up to `EXPORT_RANGE`
+/// blocks of them. Returns whether any block was left to export.
+async fn export_final_transfers(
+    store: &Store,
+    sink: &ClickHouseSink,
+    reorg_depth: u64,
+) -> Result<bool, Box<dyn error::Error + Send + Sync>> {
+    let final_block = match store
+        .last_processed_block()
+        .await?
+        .and_then(|checkpoint| checkpoint.checked_sub(U64::from(reorg_depth)))
+    {
+        Some(final_block) => final_block,
+        None => return Ok(false),
+    };
+
+    let exported_block = store.export_checkpoint(EXPORT_ID).await?;
+    let from_block = exported_block.map_or(U64::from(START_BLOCK), |block| block + 1);
+    if from_block > final_block {
+        return Ok(false);
+    }
+    let to_block = final_block.min(from_block + EXPORT_RANGE - 1);
+
+    let mut transfers = store.block_range_transfers(from_block, to_block).await?;
+    let mut batch = Vec::with_capacity(sink.batch_size);
+    let mut exported = 0;
+
+    while let Some(transfer) = transfers.try_next().await? {
+        batch.push(transfer);
+
+        if batch.len() == sink.batch_size {
+            sink.insert(&batch).await?;
+            exported += batch.len();
+            batch.clear();
+        }
+    }
+    sink.insert(&batch).await?;
+    exported += batch.len();
+
+    store.set_export_checkpoint(EXPORT_ID, to_block).await?;
+
+    if exported > 0 {
+        println!(
+            "Exported {} transfers of blocks {} to {} to ClickHouse",
+            exported, from_block, to_block
+        );
+    }
+
+    Ok(true)
+}
+
+/// Creates the table, then exports the transfers of every block once it is final, for as long as
+/// the worker runs.
+pub(crate) async fn run_export(
+    store: Store,
+    sink: ClickHouseSink,
+    reorg_depth: u64,
+    storage_retry: RetryPolicy,
+) {
+    let mut backoff = Backoff::new(storage_retry);
+
+    while let Err(error) = sink.create_table().await {
+        eprintln!(
+            "Error: Could not create the ClickHouse table, retrying... {}",
+            error
+        );
+        backoff.wait().await;
+    }
+
+    backoff.reset();
+
+    loop {
+        match export_final_transfers(&store, &sink, reorg_depth).await {
+            Ok(true) => backoff.reset(),
+            Ok(false) => {
+                backoff.reset();
+                sleep(POLL_INTERVAL).await;
+            }
+            Err(error) => {
+                eprintln!(
+                    "Error: Could not export transfers to ClickHouse, retrying... {}",
+                    error
+                );
+                backoff.wait().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use mongodb::options::{
+    Acknowledgment, ReadConcern, ReadPreference, ReadPreferenceOptions, WriteConcern,
+};
+
+/// Parses a write concern as MongoDB names its `w` option: `majority`, a number of members, or a
+/// custom write concern of the replica set's tags. Unacknowledged writes, `0`, are refused, since
+/// the worker relies on the outcome of its writes.
+pub fn parse_write_concern(value: &str) -> Result<WriteConcern, String> {
+    let w = match value.parse::<u32>() {
+        Ok(0) => return Err("Unacknowledged writes are not supported".to_string()),
+        Ok(nodes) => Acknowledgment::Nodes(nodes),
+        Err(_) if value.is_empty() => return Err("Empty write concern".to_string()),
+        Err(_) => Acknowledgment::from(value.to_string()),
+    };
+
+    Ok(WriteConcern::builder().w(w).build())
+}
+
+/// Parses a read concern level: `local`, `available`, `majority` or `linearizable`.
+pub fn parse_read_concern(value: &str) -> Result<ReadConcern, String> {
+    match value {
+        "local" => Ok(ReadConcern::local()),
+        "available" => Ok(ReadConcern::available()),
+        "majority" => Ok(ReadConcern::majority()),
+        "linearizable" => Ok(ReadConcern::linearizable()),
+        _ => Err(format!("Unknown read concern {}", value)),
+    }
+}
+
+/// Parses a read preference mode as MongoDB names it: `primary`, `primaryPreferred`, `secondary`,
+/// `secondaryPreferred` or `nearest`.
+pub fn parse_read_preference(value: &str) -> Result<ReadPreference, String> {
+    let options = ReadPreferenceOptions::default();
+
+    match value {
+        "primary" => Ok(ReadPreference::Primary),
+        "primaryPreferred" => Ok(ReadPreference::PrimaryPreferred { options }),
+        "secondary" => Ok(ReadPreference::Secondary { options }),
+        "secondaryPreferred" => Ok(ReadPreference::SecondaryPreferred { options }),
+        "nearest" => Ok(ReadPreference::Nearest { options }),
+        _ => Err(format!("Unknown read preference {}", value)),
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+/// Operator requests shared between the control API and the logs worker.
+#[derive(Debug, Default)]
+pub(crate) struct WorkerControl {
+    paused: AtomicBool,
+    reindex_requested: AtomicBool,
+    changed: Notify,
+}
+
+impl WorkerControl {
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the worker was running before the call.
+    pub(crate) fn pause(&self) -> bool {
+        !self.paused.swap(true, Ordering::SeqCst)
+    }
+
+    /// Returns whether the worker was paused before the call.
+    #[cfg(feature = "api")]
+    pub(crate) fn resume(&self) -> bool {
+        let was_paused = self.paused.swap(false, Ordering::SeqCst);
+        self.changed.notify_waiters();
+        was_paused
+    }
+
+    pub(crate) fn is_reindex_requested(&self) -> bool {
+        self.reindex_requested.load(Ordering::SeqCst)
+    }
+
+    /// Asks the logs worker to wipe the ownership model and index again from the start block.
+    pub(crate) fn request_reindex(&self) {
+        self.reindex_requested.store(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+
+    /// Clears a pending reindex request, returning whether there was one.
+    pub(crate) fn take_reindex(&self) -> bool {
+        self.reindex_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Waits until the worker is resumed or a reindex is requested.
+    pub(crate) async fn wait_while_paused(&self) {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            if !self.is_paused() || self.is_reindex_requested() {
+                return;
+            }
+
+            changed.await;
+        }
+    }
+}
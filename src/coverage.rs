@@ -0,0 +1,195 @@
+use crate::store::{CoverageKind, CoverageSegment, Store};
+use serde::Serialize;
+use std::{
+    error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use web3::types::{H160, H256};
+
+/// A range of blocks, both ends included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BlockRange {
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+/// Blocks a backfill applied for some contracts only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RestrictedRange {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub contracts: Vec<H160>,
+}
+
+/// Blocks rolled back after a reorg orphaned them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReorgRange {
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Hashes the blocks were orphaned with, oldest first.
+    pub orphaned_blocks: Vec<H256>,
+    /// Whether the live worker processed the canonical blocks since.
+    pub reindexed: bool,
+}
+
+/// Which blocks of a range were processed, from the coverage recorded in `block_coverage`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoverageReport {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub last_processed_block: Option<u64>,
+    /// Whether every block of the range was processed for every contract of the filter.
+    pub contiguous: bool,
+    /// Blocks processed for every contract of the filter, by the live worker up to its checkpoint
+    /// or by unrestricted backfills and block jobs.
+    pub indexed: Vec<BlockRange>,
+    /// Blocks of the range no segment of `indexed` covers.
+    pub gaps: Vec<BlockRange>,
+    pub restricted: Vec<RestrictedRange>,
+    pub reorgs: Vec<ReorgRange>,
+    /// Unix timestamp in seconds.
+    pub generated_at: u64,
+}
+
+/// Reports the coverage of the blocks from `from_block` to `to_block`, the last processed block by
+/// default.
+pub(crate) async fn report(
+    store: &Store,
+    from_block: u64,
+    to_block: Option<u64>,
+) -> Result<CoverageReport, Box<dyn error::Error + Send + Sync>> {
+    let last_processed_block = store
+        .last_processed_block()
+        .await?
+        .map(|block_number| block_number.as_u64());
+
+    let to_block = match to_block.or(last_processed_block) {
+        Some(to_block) => to_block,
+        None => {
+            return Err("No block is processed yet, the last block of the report is needed".into())
+        }
+    };
+
+    if to_block < from_block {
+        return Err(format!(
+            "The report ends at block {}, before its first block {}",
+            to_block, from_block
+        )
+        .into());
+    }
+
+    let segments = store.coverage_segments().await?;
+
+    let clip = |segment: &CoverageSegment, last_block: u64| {
+        let range = BlockRange {
+            from_block: (segment.from_block as u64).max(from_block),
+            to_block: (segment.to_block as u64).min(last_block).min(to_block),
+        };
+        (range.from_block <= range.to_block).then_some(range)
+    };
+
+    // Live coverage is recorded before the checkpoint moves, and blocks past it are processed
+    // again, so it only counts up to the checkpoint.
+    let mut indexed: Vec<_> = segments
+        .iter()
+        .filter_map(|segment| match segment.kind {
+            CoverageKind::Live => clip(segment, last_processed_block?),
+            CoverageKind::Backfill if segment.contracts.is_empty() => clip(segment, u64::MAX),
+            _ => None,
+        })
+        .collect();
+    indexed.sort_by_key(|range| range.from_block);
+    let indexed = merge(indexed);
+
+    let mut gaps = Vec::new();
+    let mut next_block = from_block;
+    for range in &indexed {
+        if range.from_block > next_block {
+            gaps.push(BlockRange {
+                from_block: next_block,
+                to_block: range.from_block - 1,
+            });
+        }
+        next_block = next_block.max(range.to_block.saturating_add(1));
+    }
+    if next_block <= to_block {
+        gaps.push(BlockRange {
+            from_block: next_block,
+            to_block,
+        });
+    }
+
+    let restricted = segments
+        .iter()
+        .filter(|segment| segment.kind == CoverageKind::Backfill && !segment.contracts.is_empty())
+        .filter_map(|segment| {
+            let range = clip(segment, u64::MAX)?;
+            Some(RestrictedRange {
+                from_block: range.from_block,
+                to_block: range.to_block,
+                contracts: segment.contracts.clone(),
+            })
+        })
+        .collect();
+
+    // Rollbacks record their orphaned blocks one by one, merged back into the ranges they orphaned.
+    let mut reorgs: Vec<ReorgRange> = Vec::new();
+    for segment in segments
+        .iter()
+        .filter(|segment| segment.kind == CoverageKind::Reorg)
+    {
+        let range = match clip(segment, u64::MAX) {
+            Some(range) => range,
+            None => continue,
+        };
+
+        match reorgs.last_mut() {
+            Some(reorg) if reorg.to_block + 1 == range.from_block => {
+                reorg.to_block = range.to_block;
+                reorg
+                    .orphaned_blocks
+                    .extend_from_slice(&segment.orphaned_blocks);
+            }
+            _ => reorgs.push(ReorgRange {
+                from_block: range.from_block,
+                to_block: range.to_block,
+                orphaned_blocks: segment.orphaned_blocks.clone(),
+                reindexed: false,
+            }),
+        }
+    }
+    for reorg in &mut reorgs {
+        reorg.reindexed = last_processed_block.is_some_and(|block| block >= reorg.to_block);
+    }
+
+    Ok(CoverageReport {
+        from_block,
+        to_block,
+        last_processed_block,
+        contiguous: gaps.is_empty(),
+        indexed,
+        gaps,
+        restricted,
+        reorgs,
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    })
+}
+
+/// Merges sorted ranges that overlap or follow each other.
+fn merge(ranges: Vec<BlockRange>) -> Vec<BlockRange> {
+    let mut merged: Vec<BlockRange> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.from_block <= last.to_block.saturating_add(1) => {
+                last.to_block = last.to_block.max(range.to_block);
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
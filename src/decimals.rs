@@ -0,0 +1,105 @@
+use crate::{amount::Amount, store::Store};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use web3::types::H160;
+
+/// Decimals of the contracts whose amounts are rendered in whole units, so every output surface
+/// divides amounts by the same `10^decimals`. Overrides configured with `--decimals-override` win
+/// over the `decimals()` the worker cached for the contract, for contracts whose `decimals()`
+/// reverts or is wrong, and for NFT contracts meant to be displayed in fractions.
+#[derive(Debug, Clone)]
+pub(crate) struct DecimalsRegistry {
+    store: Store,
+    overrides: Arc<HashMap<H160, u32>>,
+    /// Decimals never change once cached, so they are kept without a TTL. Contracts without
+    /// decimals are looked up again, since the worker may cache them later.
+    cache: Arc<Mutex<HashMap<H160, u32>>>,
+}
+
+impl DecimalsRegistry {
+    pub fn new(store: &Store, overrides: &[(H160, u32)]) -> Self {
+        Self {
+            store: store.clone(),
+            overrides: Arc::new(overrides.iter().copied().collect()),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The same overrides for the contracts of another store.
+    pub fn with_store(&self, store: &Store) -> Self {
+        Self {
+            store: store.clone(),
+            overrides: self.overrides.clone(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Decimals of a contract, `None` when it has no override and the worker has not cached its
+    /// `decimals()`. Negative decimals reported by a contract are taken as zero.
+    pub async fn decimals(&self, contract_address: H160) -> mongodb::error::Result<Option<u32>> {
+        if let Some(decimals) = self.overrides.get(&contract_address) {
+            return Ok(Some(*decimals));
+        }
+
+        if let Some(decimals) = self.cache.lock().unwrap().get(&contract_address) {
+            return Ok(Some(*decimals));
+        }
+
+        let decimals = self
+            .store
+            .contract_address(contract_address)
+            .await?
+            .and_then(|contract| contract.decimals)
+            .map(|decimals| decimals.max(0) as u32);
+
+        if let Some(decimals) = decimals {
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(contract_address, decimals);
+        }
+
+        Ok(decimals)
+    }
+
+    /// Whether the decimals of a contract come from an override.
+    #[cfg(feature = "api")]
+    pub fn is_overridden(&self, contract_address: H160) -> bool {
+        self.overrides.contains_key(&contract_address)
+    }
+
+    /// An amount of a contract in whole units, such as `1.5` for `1500000` with 6 decimals, `None`
+    /// when the decimals of the contract are unknown.
+    pub async fn display_amount(
+        &self,
+        contract_address: H160,
+        amount: Amount,
+    ) -> mongodb::error::Result<Option<String>> {
+        Ok(self
+            .decimals(contract_address)
+            .await?
+            .map(|decimals| amount.to_decimal_string(decimals)))
+    }
+}
+
+/// Parses a decimals override as `<contract>=<decimals>`, such as `0x…=6`.
+pub fn parse_decimals_override(value: &str) -> Result<(H160, u32), String> {
+    let (contract_address, decimals) = value.split_once('=').ok_or_else(|| {
+        format!(
+            "Invalid decimals override {}, expected <contract>=<decimals>",
+            value
+        )
+    })?;
+
+    let contract_address = H160::from_str(contract_address.trim())
+        .map_err(|_| format!("Invalid contract address in decimals override {}", value))?;
+    let decimals = decimals
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid decimals in decimals override {}", value))?;
+
+    Ok((contract_address, decimals))
+}
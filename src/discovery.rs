@@ -0,0 +1,32 @@
+//! Events of the contracts the worker classifies for the first time, so the contract set it
+//! discovers on its own can be reviewed and curated.
+
+use crate::store::ClassificationMethod;
+use serde::Serialize;
+use std::fmt;
+use web3::types::{H160, H256, U256};
+
+/// A contract the worker had never stored, classified from the log that revealed it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContractDiscovered {
+    pub contract_address: H160,
+    /// `ERC20`, `ERC721`, `ERC1155`, or `UNKNOWN` for contracts whose logs are ignored.
+    pub token_type: String,
+    pub classified_by: ClassificationMethod,
+    /// Implementation of the EIP-1967 proxy that reported the interface, when the contract is one.
+    pub implementation_address: Option<H160>,
+    /// Block, transaction and index of the log that revealed the contract.
+    pub block_number: u64,
+    pub transaction_hash: H256,
+    pub log_index: U256,
+    /// Unix timestamp in seconds.
+    pub discovered_at: u64,
+}
+
+/// Receives the contracts the worker discovers, once each, as soon as they are classified.
+///
+/// Hooks are called from the logs worker while it decodes a block, so they should hand slow work,
+/// such as I/O, off to another task.
+pub trait ContractDiscoveryHook: fmt::Debug + Send + Sync {
+    fn contract_discovered(&self, event: &ContractDiscovered);
+}
@@ -0,0 +1,379 @@
+use crate::{
+    approvals,
+    logs_worker::{self, LogsWorker},
+    ownership::SelfTransferPolicy,
+    processor,
+    store::{
+        self, ClassificationMethod, ContractAddress, ContractStats, TokenOwnership, TokenSupply,
+    },
+};
+use mongodb::bson::{self, doc, oid::ObjectId, Document};
+use std::{
+    collections::{HashMap, HashSet},
+    error,
+    time::{Duration, Instant},
+};
+use web3::types::{H160, U64};
+
+/// Number of block ranges sampled by default.
+pub const DEFAULT_ESTIMATE_SAMPLES: u64 = 20;
+
+/// Number of pings averaged into the round trip time of the database.
+const LATENCY_PROBES: u32 = 5;
+
+/// Round trips made for every block with logs besides its deltas: the journal entry, the applied
+/// block marker, the coverage and the checkpoint.
+const BLOCK_ROUND_TRIPS: u64 = 4;
+
+/// Round trips made for a delta of an owner: reading and writing the ownership and the contract
+/// holding, and updating the contract stats. ERC721 deltas make one more to delete emptied
+/// ownerships.
+const OWNERSHIP_DELTA_ROUND_TRIPS: u64 = 5;
+
+/// Round trips made for a delta of the zero address: reading and writing the supply.
+const SUPPLY_DELTA_ROUND_TRIPS: u64 = 2;
+
+/// Projected size of a collection after indexing the estimated range.
+#[derive(Debug, Clone)]
+pub struct CollectionEstimate {
+    pub collection: String,
+    pub documents: u64,
+    /// Average BSON size of the documents of the samples, in bytes.
+    pub average_document_size: u64,
+    /// Set when `documents` scales up distinct documents of the samples, such as ownerships, which
+    /// the rest of the range touches again instead of adding as often, so fewer are stored.
+    pub upper_bound: bool,
+}
+
+impl CollectionEstimate {
+    /// Projected size of the documents in bytes, before compression and without indexes.
+    pub fn size(&self) -> u64 {
+        self.documents * self.average_document_size
+    }
+}
+
+/// Projection of what indexing a block range stores and how long it takes, from samples of its
+/// logs spread evenly over the range.
+#[derive(Debug, Clone)]
+pub struct CapacityEstimate {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub sampled_blocks: u64,
+    pub sampled_logs: u64,
+    pub logs: u64,
+    pub transfers: u64,
+    pub collections: Vec<CollectionEstimate>,
+    /// Time spent fetching the logs of the range, `backfill_concurrency` ranges at a time, at the
+    /// speed the samples were fetched.
+    pub fetch_time: Duration,
+    /// Time spent applying the writes of the range one after the other, at the round trip time of
+    /// the database.
+    pub write_time: Duration,
+}
+
+impl CapacityEstimate {
+    /// Projected size of every collection in bytes.
+    pub fn size(&self) -> u64 {
+        self.collections.iter().map(CollectionEstimate::size).sum()
+    }
+
+    /// Projected time of indexing the range. Ranges are fetched while earlier ones are written, so
+    /// the slower of both sets the pace.
+    pub fn indexing_time(&self) -> Duration {
+        self.fetch_time.max(self.write_time)
+    }
+}
+
+/// Documents of a collection seen in the samples.
+#[derive(Debug, Default)]
+struct SampledCollection {
+    documents: u64,
+    bytes: u64,
+}
+
+impl SampledCollection {
+    fn add(&mut self, document: Document) {
+        self.documents += 1;
+        self.bytes += document_size(document);
+    }
+
+    fn estimate(&self, collection: &str, scale: f64, upper_bound: bool) -> CollectionEstimate {
+        CollectionEstimate {
+            collection: collection.to_string(),
+            documents: (self.documents as f64 * scale).round() as u64,
+            average_document_size: self.bytes.checked_div(self.documents).unwrap_or_default(),
+            upper_bound,
+        }
+    }
+}
+
+/// Samples `samples` ranges of `sample_size` blocks spread evenly from `from_block` to
+/// `to_block`, or the whole range when it is not larger, and projects the documents, storage and
+/// time indexing it takes. Ranges are as large as the ones the worker fetches by default, so they
+/// are fetched as fast.
+///
+/// Contracts are not classified, their token type is inferred from the shape of their events, so
+/// the samples neither write to the database nor make calls besides `eth_getLogs`.
+pub(crate) async fn estimate(
+    logs_worker: &LogsWorker,
+    from_block: u64,
+    to_block: u64,
+    samples: u64,
+    sample_size: Option<u64>,
+) -> Result<CapacityEstimate, Box<dyn error::Error + Send + Sync>> {
+    if to_block < from_block {
+        return Err(format!("Block {} is after block {}", from_block, to_block).into());
+    }
+
+    let blocks = to_block - from_block + 1;
+    let samples = samples.max(1);
+    let sample_size = sample_size
+        .unwrap_or(logs_worker.config.log_range_size)
+        .clamp(1, blocks);
+
+    let ranges: Vec<(u64, u64)> = if samples.saturating_mul(sample_size) >= blocks {
+        (from_block..=to_block)
+            .step_by(sample_size as usize)
+            .map(|range_start| (range_start, (range_start + sample_size - 1).min(to_block)))
+            .collect()
+    } else {
+        let stride = blocks / samples;
+        (0..samples)
+            .map(|sample| {
+                let range_start = from_block + sample * stride;
+                (range_start, range_start + sample_size - 1)
+            })
+            .collect()
+    };
+
+    let config = &logs_worker.config;
+    let signatures = &logs_worker.signatures;
+    let addresses = config.contract_filter.addresses(&logs_worker.store).await?;
+
+    let mut sampled_blocks = 0;
+    let mut sampled_logs = 0;
+    let mut transfers = 0;
+    let mut fetch_time = Duration::ZERO;
+    let mut round_trips = 0;
+
+    let mut ownerships = SampledCollection::default();
+    let mut holdings = SampledCollection::default();
+    let mut supplies = SampledCollection::default();
+    let mut contracts = SampledCollection::default();
+    let mut stats = SampledCollection::default();
+    let mut transfer_records = SampledCollection::default();
+    let mut approval_records = SampledCollection::default();
+
+    let mut ownership_keys = HashSet::new();
+    let mut holding_keys = HashSet::new();
+    let mut supply_keys = HashSet::new();
+    let mut approval_keys = HashSet::new();
+    let mut contract_types = HashMap::new();
+
+    for (range_start, range_end) in ranges {
+        println!("Sampling blocks {} to {}", range_start, range_end);
+
+        let started_at = Instant::now();
+        let logs = logs_worker
+            .range_logs(
+                U64::from(range_start),
+                U64::from(range_end),
+                addresses.as_deref(),
+            )
+            .await?;
+        fetch_time += started_at.elapsed();
+
+        sampled_blocks += range_end - range_start + 1;
+
+        let logs = processor::drop_paired_erc_777_logs(
+            signatures,
+            processor::dedup_logs(config.contract_filter.retain_logs(logs)),
+        );
+
+        sampled_logs += logs.len() as u64;
+
+        let blocks_with_logs: HashSet<_> = logs.iter().map(|log| log.block_number).collect();
+        round_trips += blocks_with_logs.len() as u64 * BLOCK_ROUND_TRIPS;
+
+        for log in &logs {
+            if config.index_approvals {
+                if let Some(changes) =
+                    approvals::decode_approval_log(signatures, &config.owner_protection, log)
+                {
+                    for change in changes {
+                        let (filter, approval) =
+                            store::approval_record(&change, log.block_number.unwrap_or_default());
+                        round_trips += 1;
+
+                        if let Some(approval) = approval {
+                            if approval_keys.insert(filter.to_string()) {
+                                approval_records.add(bson::to_document(&approval)?);
+                            }
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            let log_transfers = match processor::decode_wrapped_token_log(
+                signatures,
+                &config.wrapped_tokens,
+                log,
+            ) {
+                Some(transfers) => transfers.unwrap_or_default(),
+                None => processor::decode_transfers_by_shape(signatures, log),
+            };
+
+            for (position, transfer) in log_transfers.into_iter().enumerate() {
+                if !config
+                    .contract_filter
+                    .retains_token(transfer.contract_address, transfer.token_id.as_deref())
+                {
+                    continue;
+                }
+
+                transfers += 1;
+
+                contract_types
+                    .entry(transfer.contract_address)
+                    .or_insert_with(|| transfer.token_type.clone());
+
+                for delta in transfer.deltas(SelfTransferPolicy::default()) {
+                    let contract_address = format!("{:#x}", delta.contract_address);
+
+                    if delta.owner == H160::zero() {
+                        round_trips += SUPPLY_DELTA_ROUND_TRIPS;
+
+                        let token_id = (delta.token_type == "ERC1155")
+                            .then(|| delta.token_id.clone())
+                            .flatten();
+
+                        if supply_keys.insert((contract_address, token_id.clone())) {
+                            supplies.add(bson::to_document(&TokenSupply {
+                                contract_address: delta.contract_address,
+                                token_type: delta.token_type.clone(),
+                                token_id,
+                                supply: delta.quantity.abs(),
+                                amount: Some(delta.exact_amount()),
+                            })?);
+                        }
+                        continue;
+                    }
+
+                    round_trips += OWNERSHIP_DELTA_ROUND_TRIPS;
+                    if delta.token_type == "ERC721" {
+                        round_trips += 1;
+                    }
+
+                    if ownership_keys.insert((
+                        contract_address.clone(),
+                        delta.token_id.clone(),
+                        delta.owner,
+                    )) {
+                        let ownership = bson::to_document(&TokenOwnership {
+                            contract_address: delta.contract_address,
+                            token_id: delta.token_id.clone(),
+                            owner: delta.owner,
+                            quantity: delta.quantity.abs(),
+                            amount: (!config.ownership_schema.is_compatibility())
+                                .then(|| delta.exact_amount()),
+                            custody: delta.custody,
+                            creator: None,
+                        })?;
+                        ownerships.add(config.ownership_schema.stored_document(ownership));
+                    }
+
+                    if holding_keys.insert((contract_address.clone(), delta.owner)) {
+                        holdings.add(doc! {
+                            "contract_address": &contract_address,
+                            "owner": format!("{:#x}", delta.owner),
+                            "quantity": delta.quantity.abs(),
+                            "amount": delta.exact_amount().to_string(),
+                        });
+                    }
+                }
+
+                if config.transfer_history {
+                    if let Some(record) = logs_worker::transfer_record(log, position, transfer) {
+                        round_trips += 1;
+                        transfer_records.add(bson::to_document(&record)?);
+                    }
+                }
+            }
+        }
+    }
+
+    for (address, token_type) in contract_types {
+        contracts.add(bson::to_document(&ContractAddress {
+            address,
+            token_type,
+            additional_token_types: Vec::new(),
+            self_transfer_policy: SelfTransferPolicy::default(),
+            classified_by: Some(ClassificationMethod::Erc165),
+            implementation_address: None,
+            volume_capped: false,
+            decimals: None,
+            start_block: None,
+        })?);
+        stats.add(bson::to_document(&ContractStats {
+            contract_address: address,
+            holder_count: 0,
+            supply: 0.0,
+            checksum: 0,
+        })?);
+    }
+
+    let scale = blocks as f64 / sampled_blocks as f64;
+
+    let mut collections = vec![
+        ownerships.estimate(config.ownership_schema.collection(), scale, true),
+        holdings.estimate("contract_holdings", scale, true),
+        stats.estimate("contract_stats", scale, true),
+        supplies.estimate("token_supplies", scale, true),
+        contracts.estimate("contract_addresses", scale, true),
+    ];
+    if config.transfer_history {
+        collections.push(transfer_records.estimate("transfers", scale, false));
+    }
+    if config.index_approvals {
+        collections.push(approval_records.estimate("approvals", scale, true));
+    }
+
+    let round_trip_time = round_trip_time(logs_worker).await?;
+
+    Ok(CapacityEstimate {
+        from_block,
+        to_block,
+        sampled_blocks,
+        sampled_logs,
+        logs: (sampled_logs as f64 * scale).round() as u64,
+        transfers: (transfers as f64 * scale).round() as u64,
+        collections,
+        fetch_time: fetch_time.mul_f64(scale / config.backfill_concurrency.max(1) as f64),
+        write_time: round_trip_time.mul_f64(round_trips as f64 * scale),
+    })
+}
+
+/// Average time of a round trip to the database.
+async fn round_trip_time(logs_worker: &LogsWorker) -> mongodb::error::Result<Duration> {
+    let started_at = Instant::now();
+
+    for _ in 0..LATENCY_PROBES {
+        logs_worker.store.ping().await?;
+    }
+
+    Ok(started_at.elapsed() / LATENCY_PROBES)
+}
+
+/// Size of a document once stored, including the `_id` MongoDB adds to documents without one.
+fn document_size(mut document: Document) -> u64 {
+    if !document.contains_key("_id") {
+        document.insert("_id", ObjectId::new());
+    }
+
+    let mut bytes = Vec::new();
+    match document.to_writer(&mut bytes) {
+        Ok(()) => bytes.len() as u64,
+        Err(_) => 0,
+    }
+}
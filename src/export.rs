@@ -0,0 +1,149 @@
+use crate::{
+    parquet::{write_parquet, ParquetColumn},
+    snapshot::SnapshotHolding,
+};
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+};
+use web3::types::H160;
+
+/// File format holders are exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    /// Uncompressed row groups of optional columns, amounts as UTF-8 strings and quantities as
+    /// doubles.
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!(
+                "Unknown export format {}, expected csv or parquet",
+                value
+            )),
+        }
+    }
+}
+
+/// A column of a holder export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolderColumn {
+    ContractAddress,
+    /// Empty for fungible tokens.
+    TokenId,
+    Owner,
+    /// Exact amount held, in the smallest unit of the token.
+    Amount,
+    /// `amount` as a float.
+    Quantity,
+    /// Amount held in whole units, empty when the decimals of the contract are unknown.
+    DisplayAmount,
+}
+
+/// Columns exported when none are selected.
+pub const DEFAULT_HOLDER_COLUMNS: [HolderColumn; 4] = [
+    HolderColumn::TokenId,
+    HolderColumn::Owner,
+    HolderColumn::Amount,
+    HolderColumn::DisplayAmount,
+];
+
+impl fmt::Display for HolderColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HolderColumn::ContractAddress => write!(f, "contract_address"),
+            HolderColumn::TokenId => write!(f, "token_id"),
+            HolderColumn::Owner => write!(f, "owner"),
+            HolderColumn::Amount => write!(f, "amount"),
+            HolderColumn::Quantity => write!(f, "quantity"),
+            HolderColumn::DisplayAmount => write!(f, "display_amount"),
+        }
+    }
+}
+
+impl FromStr for HolderColumn {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "contract_address" => Ok(HolderColumn::ContractAddress),
+            "token_id" => Ok(HolderColumn::TokenId),
+            "owner" => Ok(HolderColumn::Owner),
+            "amount" => Ok(HolderColumn::Amount),
+            "quantity" => Ok(HolderColumn::Quantity),
+            "display_amount" => Ok(HolderColumn::DisplayAmount),
+            _ => Err(format!("Unknown holder column {}", value)),
+        }
+    }
+}
+
+/// Writes the holdings of a contract with the `columns` selected, in their order. CSV files start
+/// with a header line naming the columns, and null values are left empty.
+pub fn write_holders(
+    contract_address: H160,
+    holdings: &[SnapshotHolding],
+    columns: &[HolderColumn],
+    format: ExportFormat,
+    mut writer: impl Write + Send,
+) -> io::Result<()> {
+    let text = |column: HolderColumn, holding: &SnapshotHolding| match column {
+        HolderColumn::ContractAddress => Some(format!("{:#x}", contract_address)),
+        HolderColumn::TokenId => holding.token_id.clone(),
+        HolderColumn::Owner => Some(format!("{:#x}", holding.owner)),
+        HolderColumn::Amount => Some(holding.amount.to_string()),
+        HolderColumn::Quantity => Some(holding.amount.to_f64().to_string()),
+        HolderColumn::DisplayAmount => holding.display_amount.clone(),
+    };
+
+    match format {
+        ExportFormat::Csv => {
+            let header: Vec<_> = columns.iter().map(HolderColumn::to_string).collect();
+            writeln!(writer, "{}", header.join(","))?;
+
+            for holding in holdings {
+                let values: Vec<_> = columns
+                    .iter()
+                    .map(|column| text(*column, holding).unwrap_or_default())
+                    .collect();
+                writeln!(writer, "{}", values.join(","))?;
+            }
+
+            Ok(())
+        }
+        ExportFormat::Parquet => {
+            let names: Vec<_> = columns.iter().map(HolderColumn::to_string).collect();
+            let parquet_columns: Vec<_> = columns
+                .iter()
+                .zip(&names)
+                .map(|(column, name)| {
+                    let values = match column {
+                        HolderColumn::Quantity => ParquetColumn::Double(
+                            holdings
+                                .iter()
+                                .map(|holding| Some(holding.amount.to_f64()))
+                                .collect(),
+                        ),
+                        _ => ParquetColumn::Utf8(
+                            holdings
+                                .iter()
+                                .map(|holding| text(*column, holding))
+                                .collect(),
+                        ),
+                    };
+
+                    (name.as_str(), values)
+                })
+                .collect();
+
+            write_parquet(parquet_columns, writer)
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{
+    ownership::OwnershipDelta,
+    store::{DeltaFeedEntry, Store},
+};
+use futures::stream::{self, Stream, StreamExt};
+use mongodb::{options::ClientOptions, Client, Database};
+use std::{collections::VecDeque, error, time::Duration};
+
+/// Number of feed entries read with a single query.
+const DEFAULT_BATCH_SIZE: i64 = 100;
+
+/// Read access to the delta feed for other Rust services, which follow the changes of the ownership
+/// model as streams instead of polling `GET /sync/deltas`.
+///
+/// Streams read the next batch of entries only once the previous one was consumed, so a slow
+/// consumer holds at most a batch in memory and never falls behind a buffer of its own.
+#[derive(Debug, Clone)]
+pub struct DeltaFeed {
+    store: Store,
+    poll_interval: Duration,
+    batch_size: i64,
+}
+
+impl DeltaFeed {
+    pub fn new(database: &Database, poll_interval: Duration) -> Self {
+        Self {
+            store: Store::new(database),
+            poll_interval,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    pub async fn connect(
+        database_host: String,
+        database_name: String,
+        poll_interval: Duration,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let client = Client::with_options(ClientOptions::parse(database_host).await?)?;
+
+        Ok(Self::new(&client.database(&database_name), poll_interval))
+    }
+
+    /// Prefix the worker's collection names start with.
+    pub fn with_collection_prefix(self, collection_prefix: &str) -> Self {
+        Self {
+            store: self.store.with_collection_prefix(collection_prefix),
+            ..self
+        }
+    }
+
+    /// Maximum number of entries read with a single query.
+    pub fn with_batch_size(self, batch_size: i64) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+
+    /// Sequence of the newest entry, to follow the feed from now on.
+    pub async fn latest_sequence(&self) -> mongodb::error::Result<i64> {
+        self.store.latest_feed_sequence().await
+    }
+
+    /// The entries following sequence `after`, waiting `poll_interval` for new entries whenever the
+    /// consumer caught up. Failed reads are reported and retried.
+    ///
+    /// The stream ends when the entries following the last one it yielded are no longer in the
+    /// feed, because they were pruned or the feed was reset by a reindex. The consumer then needs
+    /// a new snapshot, like a `410 Gone` response of `GET /sync/deltas`.
+    pub fn entries(&self, after: i64) -> impl Stream<Item = DeltaFeedEntry> + Send + 'static {
+        let feed = self.clone();
+
+        stream::unfold(
+            (feed, after, VecDeque::<DeltaFeedEntry>::new()),
+            |(feed, mut after, mut entries)| async move {
+                loop {
+                    if let Some(entry) = entries.pop_front() {
+                        after = entry.sequence;
+                        return Some((entry, (feed, after, entries)));
+                    }
+
+                    let batch = match feed.store.feed_entries(after, feed.batch_size).await {
+                        Ok(batch) => batch,
+                        Err(error) => {
+                            eprintln!(
+                                "Error: Could not read the delta feed, retrying... {}",
+                                error
+                            );
+                            tokio::time::sleep(feed.poll_interval).await;
+                            continue;
+                        }
+                    };
+
+                    match batch.first() {
+                        Some(entry) if entry.sequence != after + 1 => {
+                            eprintln!(
+                                "Error: Deltas after sequence {} have been pruned, a new snapshot is needed",
+                                after
+                            );
+                            return None;
+                        }
+                        Some(_) => entries.extend(batch),
+                        None => match feed.store.latest_feed_sequence().await {
+                            Ok(latest_sequence) if latest_sequence < after => {
+                                eprintln!(
+                                    "Error: Sequence {} is ahead of the feed, which has been reset, a new snapshot is needed",
+                                    after
+                                );
+                                return None;
+                            }
+                            _ => tokio::time::sleep(feed.poll_interval).await,
+                        },
+                    }
+                }
+            },
+        )
+    }
+
+    /// The deltas of the entries following sequence `after`, in the order they were applied.
+    /// Rolled back blocks are followed by their inverse deltas, so summing the deltas always gives
+    /// the current ownership model.
+    pub fn deltas(&self, after: i64) -> impl Stream<Item = OwnershipDelta> + Send + 'static {
+        self.entries(after)
+            .flat_map(|entry| stream::iter(entry.deltas))
+    }
+}
@@ -0,0 +1,174 @@
+use crate::store::Store;
+use std::{collections::HashSet, fs, path::Path, str::FromStr};
+use web3::types::{Log, H160, U256};
+
+/// Contracts the worker restricts indexing to.
+#[derive(Debug, Clone)]
+pub enum ContractAllowlist {
+    /// A fixed set of contract addresses.
+    Addresses(Vec<H160>),
+    /// The contracts registered in the `contract_addresses` collection, read again on every batch.
+    Registered,
+}
+
+/// Whether a token id rule restricts indexing to its range or excludes the range from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenIdRuleKind {
+    Include,
+    Exclude,
+}
+
+/// A range of token ids of a contract, bounds included, indexed or left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenIdRule {
+    pub contract_address: H160,
+    pub kind: TokenIdRuleKind,
+    pub from: U256,
+    pub to: U256,
+}
+
+impl TokenIdRule {
+    fn contains(&self, token_id: U256) -> bool {
+        self.from <= token_id && token_id <= self.to
+    }
+}
+
+/// Which contracts have their logs indexed.
+///
+/// The allowlist is pushed down to the `address` field of `eth_getLogs` filters, so logs of other
+/// contracts are never fetched. The denylist is applied to fetched logs before classification, and
+/// the token id rules to the transfers decoded from them.
+#[derive(Debug, Clone, Default)]
+pub struct ContractFilter {
+    /// Every contract is indexed when unset.
+    pub allowlist: Option<ContractAllowlist>,
+    pub denylist: HashSet<H160>,
+    /// Only the token ids within an include rule of a contract are indexed when it has any, and
+    /// token ids within an exclude rule never are.
+    pub token_id_rules: Vec<TokenIdRule>,
+}
+
+impl ContractFilter {
+    /// Resolves the addresses logs are fetched for, `None` meaning every contract.
+    pub(crate) async fn addresses(
+        &self,
+        store: &Store,
+    ) -> mongodb::error::Result<Option<Vec<H160>>> {
+        let addresses = match &self.allowlist {
+            None => return Ok(None),
+            Some(ContractAllowlist::Addresses(addresses)) => addresses.clone(),
+            Some(ContractAllowlist::Registered) => store.registered_contract_addresses().await?,
+        };
+
+        Ok(Some(
+            addresses
+                .into_iter()
+                .filter(|address| !self.denylist.contains(address))
+                .collect(),
+        ))
+    }
+
+    pub(crate) fn retain_logs(&self, mut logs: Vec<Log>) -> Vec<Log> {
+        if !self.denylist.is_empty() {
+            logs.retain(|log| !self.denylist.contains(&log.address));
+        }
+
+        logs
+    }
+
+    /// Whether transfers of a token are indexed under the token id rules. Fungible tokens, which
+    /// have no token id, always are.
+    pub(crate) fn retains_token(&self, contract_address: H160, token_id: Option<&str>) -> bool {
+        let token_id = match token_id.and_then(|token_id| U256::from_dec_str(token_id).ok()) {
+            Some(token_id) => token_id,
+            None => return true,
+        };
+
+        let mut rules = self
+            .token_id_rules
+            .iter()
+            .filter(|rule| rule.contract_address == contract_address)
+            .peekable();
+
+        if rules.peek().is_none() {
+            return true;
+        }
+
+        let mut included = None;
+
+        for rule in rules {
+            match rule.kind {
+                TokenIdRuleKind::Exclude if rule.contains(token_id) => return false,
+                TokenIdRuleKind::Exclude => {}
+                TokenIdRuleKind::Include => {
+                    included = Some(included.unwrap_or(false) || rule.contains(token_id))
+                }
+            }
+        }
+
+        included.unwrap_or(true)
+    }
+}
+
+/// Reads token id rules from a file with one rule per line, such as `<contract> include 1-10000`
+/// or `<contract> exclude 42`. Blank lines and lines starting with `#` are skipped.
+pub fn read_token_id_rules(path: impl AsRef<Path>) -> Result<Vec<TokenIdRule>, String> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("Could not read {}: {}", path.display(), error))?;
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            parse_token_id_rule(line)
+                .ok_or_else(|| format!("Invalid token id rule {} in {}", line, path.display()))
+        })
+        .collect()
+}
+
+fn parse_token_id_rule(line: &str) -> Option<TokenIdRule> {
+    let mut fields = line.split_whitespace();
+
+    let contract_address = H160::from_str(fields.next()?).ok()?;
+    let kind = match fields.next()? {
+        "include" => TokenIdRuleKind::Include,
+        "exclude" => TokenIdRuleKind::Exclude,
+        _ => return None,
+    };
+    let range = fields.next()?;
+    let (from, to) = range.split_once('-').unwrap_or((range, range));
+
+    if fields.next().is_some() {
+        return None;
+    }
+
+    let from = U256::from_dec_str(from).ok()?;
+    let to = U256::from_dec_str(to).ok()?;
+
+    (from <= to).then_some(TokenIdRule {
+        contract_address,
+        kind,
+        from,
+        to,
+    })
+}
+
+/// Reads contract addresses from a file with one address per line. Blank lines and lines starting
+/// with `#` are skipped.
+pub fn read_address_file(path: impl AsRef<Path>) -> Result<Vec<H160>, String> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("Could not read {}: {}", path.display(), error))?;
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            H160::from_str(line)
+                .map_err(|_| format!("Invalid contract address {} in {}", line, path.display()))
+        })
+        .collect()
+}
@@ -0,0 +1,738 @@
+//! The GraphQL endpoint of the API, only compiled with the `api` feature.
+//!
+//! The schema is declared with `async-graphql`, which parses, validates and executes queries,
+//! without mutations or subscriptions. Every field is resolved from the same collections as the
+//! REST endpoints, so nested queries such as the ERC721 tokens of an owner with their metadata take
+//! a single request instead of one per contract and token.
+
+use crate::{
+    amount::Amount,
+    decimals::DecimalsRegistry,
+    privacy::OwnerProtection,
+    store::{
+        ContractAddress, MetadataStatus, Store, TokenMetadata, TokenOwnership, TransferRecord,
+    },
+};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Error, Json as JsonScalar, Object, Request,
+    Response, Result, Schema, Variables,
+};
+use futures::{StreamExt, TryStreamExt};
+use mongodb::bson::Bson;
+use serde::Deserialize;
+use serde_json::{Map, Value as Json};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+};
+use utoipa::ToSchema;
+use web3::types::{H160, U64};
+
+/// Items returned by a list field when `first` is not given.
+const DEFAULT_FIRST: i64 = 100;
+
+/// Maximum `first` of a list field.
+const MAX_FIRST: i64 = 1000;
+
+/// Maximum nesting of selection sets in a query.
+const MAX_DEPTH: usize = 10;
+
+/// Maximum number of objects resolved for a query, so nested lists cannot multiply into a scan of
+/// the whole model.
+const MAX_OBJECTS: usize = 10_000;
+
+/// The schema `POST /graphql` executes queries against, built on the first query.
+static SCHEMA: OnceLock<Schema<Query, EmptyMutation, EmptySubscription>> = OnceLock::new();
+
+/// A GraphQL request, as POSTed to `/graphql`.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GraphQlRequest {
+    query: String,
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    variables: Option<Map<String, Json>>,
+    #[serde(default)]
+    operation_name: Option<String>,
+}
+
+/// What the resolvers of a query read from, along with the classifications read so far and the
+/// number of objects resolved.
+struct QueryContext {
+    store: Store,
+    decimals: DecimalsRegistry,
+    owner_protection: OwnerProtection,
+    /// Classifications read for this query, `None` for addresses that are not classified.
+    contracts: Mutex<HashMap<H160, Option<ContractAddress>>>,
+    objects: AtomicUsize,
+}
+
+impl QueryContext {
+    fn of<'a>(ctx: &Context<'a>) -> &'a Self {
+        ctx.data_unchecked::<Self>()
+    }
+
+    /// Counts `count` more objects resolved, failing once the query resolves too many.
+    fn resolve_objects(&self, count: usize) -> Result<()> {
+        if self.objects.fetch_add(count, Ordering::Relaxed) + count > MAX_OBJECTS {
+            return Err(Error::new(format!(
+                "The query resolves more than {} objects",
+                MAX_OBJECTS
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn contract(&self, address: H160) -> Result<Option<ContractAddress>> {
+        if let Some(contract) = self.contracts.lock().unwrap().get(&address) {
+            return Ok(contract.clone());
+        }
+
+        let contract = self
+            .store
+            .contract_address(address)
+            .await
+            .map_err(internal_error)?;
+        self.contracts
+            .lock()
+            .unwrap()
+            .insert(address, contract.clone());
+
+        Ok(contract)
+    }
+
+    async fn contract_node(&self, address: H160) -> Result<Option<Contract>> {
+        let contract = self.contract(address).await?;
+        if contract.is_some() {
+            self.resolve_objects(1)?;
+        }
+
+        Ok(contract.map(Contract))
+    }
+
+    async fn metadata_node(
+        &self,
+        contract_address: H160,
+        token_id: Option<&str>,
+    ) -> Result<Option<Metadata>> {
+        let id = match token_id {
+            Some(token_id) => format!("{:#x}:{}", contract_address, token_id),
+            None => format!("{:#x}", contract_address),
+        };
+
+        let metadata = self
+            .store
+            .token_metadata(&id)
+            .await
+            .map_err(internal_error)?;
+        if metadata.is_some() {
+            self.resolve_objects(1)?;
+        }
+
+        Ok(metadata.map(Metadata))
+    }
+
+    async fn display_amount(
+        &self,
+        contract_address: H160,
+        amount: Option<Amount>,
+    ) -> Result<Option<String>> {
+        match amount {
+            Some(amount) => Ok(self
+                .decimals
+                .display_amount(contract_address, amount)
+                .await
+                .map_err(internal_error)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Ownerships with a positive amount, limited to the first `first` of them.
+    fn held(&self, ownerships: Vec<TokenOwnership>, first: usize) -> Result<Vec<Ownership>> {
+        let held: Vec<_> = ownerships
+            .into_iter()
+            .filter(|ownership| ownership.exact_amount().is_positive())
+            .take(first)
+            .map(Ownership)
+            .collect();
+        self.resolve_objects(held.len())?;
+
+        Ok(held)
+    }
+
+    /// Recorded transfers of a contract or of an owner, and of both when both are given.
+    async fn transfers(
+        &self,
+        contract_address: Option<H160>,
+        owner: Option<H160>,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        first: i64,
+    ) -> Result<Vec<Transfer>> {
+        let first = clamp_first(first);
+        let to_block = to_block.map(U64::from);
+
+        let transfers = match (contract_address, owner) {
+            (_, Some(owner)) => {
+                let contracts: Vec<_> = contract_address.into_iter().collect();
+                self.store
+                    .owner_transfers(owner, &contracts, to_block)
+                    .await
+            }
+            (Some(contract_address), None) => {
+                self.store
+                    .contract_transfers(
+                        contract_address,
+                        from_block
+                            .and_then(|block| block.checked_sub(1))
+                            .map(U64::from),
+                        to_block,
+                    )
+                    .await
+            }
+            (None, None) => {
+                return Err(Error::new("Transfers need a `contract` or an `owner`"));
+            }
+        }
+        .map_err(internal_error)?;
+
+        let transfers: Vec<_> = transfers
+            .try_filter(|transfer| {
+                let after_from_block =
+                    from_block.is_none_or(|from_block| transfer.block_number >= from_block as i64);
+                async move { after_from_block }
+            })
+            .take(first)
+            .try_collect()
+            .await
+            .map_err(internal_error)?;
+        self.resolve_objects(transfers.len())?;
+
+        Ok(transfers.into_iter().map(Transfer).collect())
+    }
+}
+
+/// Logs a failed read, which is reported to the client without its details.
+fn internal_error(error: impl Display) -> Error {
+    eprintln!("Error: GraphQL query failed... {}", error);
+    Error::new("Internal server error")
+}
+
+fn parse_address(name: &str, address: &str) -> Result<H160> {
+    address
+        .parse()
+        .map_err(|_| Error::new(format!("Argument `{}` must be an address", name)))
+}
+
+fn parse_optional_address(name: &str, address: Option<String>) -> Result<Option<H160>> {
+    address
+        .map(|address| parse_address(name, &address))
+        .transpose()
+}
+
+/// The `first` items of a list field to return.
+fn clamp_first(first: i64) -> usize {
+    first.clamp(1, MAX_FIRST) as usize
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    /// Last block applied.
+    async fn block_number(&self, ctx: &Context<'_>) -> Result<Option<u64>> {
+        Ok(QueryContext::of(ctx)
+            .store
+            .last_processed_block()
+            .await
+            .map_err(internal_error)?
+            .map(|block_number| block_number.as_u64()))
+    }
+
+    /// A classified contract.
+    async fn contract(&self, ctx: &Context<'_>, address: String) -> Result<Option<Contract>> {
+        QueryContext::of(ctx)
+            .contract_node(parse_address("address", &address)?)
+            .await
+    }
+
+    /// Classified contracts, of `tokenType` when given.
+    async fn contracts(
+        &self,
+        ctx: &Context<'_>,
+        token_type: Option<String>,
+        #[graphql(default_with = "DEFAULT_FIRST")] first: i64,
+    ) -> Result<Vec<Contract>> {
+        let context = QueryContext::of(ctx);
+
+        let contracts: Vec<_> = context
+            .store
+            .token_contracts()
+            .await
+            .map_err(internal_error)?
+            .into_iter()
+            .filter(|contract| {
+                token_type
+                    .as_ref()
+                    .is_none_or(|token_type| contract.token_type == *token_type)
+            })
+            .take(clamp_first(first))
+            .collect();
+        context.resolve_objects(contracts.len())?;
+
+        let mut cache = context.contracts.lock().unwrap();
+        for contract in &contracts {
+            cache.insert(contract.address, Some(contract.clone()));
+        }
+
+        Ok(contracts.into_iter().map(Contract).collect())
+    }
+
+    /// An owner, whether or not it holds anything.
+    async fn owner(&self, address: String) -> Result<Owner> {
+        Ok(Owner(parse_address("address", &address)?))
+    }
+
+    /// Recorded transfers of a contract or of an owner, and of both when both are given.
+    async fn transfers(
+        &self,
+        ctx: &Context<'_>,
+        contract: Option<String>,
+        owner: Option<String>,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        #[graphql(default_with = "DEFAULT_FIRST")] first: i64,
+    ) -> Result<Vec<Transfer>> {
+        let context = QueryContext::of(ctx);
+        let owner = parse_optional_address("owner", owner)?
+            .map(|owner| context.owner_protection.protect(owner));
+
+        context
+            .transfers(
+                parse_optional_address("contract", contract)?,
+                owner,
+                from_block,
+                to_block,
+                first,
+            )
+            .await
+    }
+}
+
+struct Contract(ContractAddress);
+
+#[Object]
+impl Contract {
+    async fn address(&self) -> String {
+        format!("{:#x}", self.0.address)
+    }
+
+    async fn token_type(&self) -> &str {
+        &self.0.token_type
+    }
+
+    /// Token types besides `tokenType` of the `Transfer` events the contract emitted.
+    async fn additional_token_types(&self) -> &[String] {
+        &self.0.additional_token_types
+    }
+
+    async fn decimals(&self, ctx: &Context<'_>) -> Result<Option<u32>> {
+        QueryContext::of(ctx)
+            .decimals
+            .decimals(self.0.address)
+            .await
+            .map_err(internal_error)
+    }
+
+    /// `name()` of the contract, once the metadata task fetched it.
+    async fn name(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(self
+            .metadata(ctx)
+            .await?
+            .and_then(|metadata| metadata.0.name))
+    }
+
+    /// `symbol()` of the contract, once the metadata task fetched it.
+    async fn symbol(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(self
+            .metadata(ctx)
+            .await?
+            .and_then(|metadata| metadata.0.symbol))
+    }
+
+    /// Owners holding a positive amount.
+    async fn holder_count(&self, ctx: &Context<'_>) -> Result<Option<i64>> {
+        Ok(self.stats(ctx).await?.map(|(holder_count, _)| holder_count))
+    }
+
+    /// Sum of the quantities held.
+    async fn supply(&self, ctx: &Context<'_>) -> Result<Option<f64>> {
+        Ok(self.stats(ctx).await?.map(|(_, supply)| supply))
+    }
+
+    async fn metadata(&self, ctx: &Context<'_>) -> Result<Option<Metadata>> {
+        QueryContext::of(ctx)
+            .metadata_node(self.0.address, None)
+            .await
+    }
+
+    /// Ownerships of the contract with a positive amount, of `owner` and of `tokenId` when given.
+    async fn ownerships(
+        &self,
+        ctx: &Context<'_>,
+        owner: Option<String>,
+        token_id: Option<String>,
+        #[graphql(default_with = "DEFAULT_FIRST")] first: i64,
+    ) -> Result<Vec<Ownership>> {
+        let context = QueryContext::of(ctx);
+        let first = clamp_first(first);
+        let owner = parse_optional_address("owner", owner)?
+            .map(|owner| context.owner_protection.protect(owner));
+        let contract_address = self.0.address;
+
+        let ownerships = match (owner, &token_id) {
+            (Some(owner), _) => {
+                let mut ownerships = context
+                    .store
+                    .owner_ownerships(owner, Some(contract_address))
+                    .await
+                    .map_err(internal_error)?;
+                if token_id.is_some() {
+                    ownerships.retain(|ownership| ownership.token_id == token_id);
+                }
+                ownerships
+            }
+            (None, Some(token_id)) => context
+                .store
+                .token_owners(contract_address, token_id)
+                .await
+                .map_err(internal_error)?,
+            (None, None) => context
+                .store
+                .contract_ownerships(contract_address)
+                .await
+                .map_err(internal_error)?
+                .try_filter(|ownership| {
+                    let held = ownership.exact_amount().is_positive();
+                    async move { held }
+                })
+                .take(first)
+                .try_collect()
+                .await
+                .map_err(internal_error)?,
+        };
+
+        context.held(ownerships, first)
+    }
+
+    /// Recorded transfers of the contract, of `owner` when given.
+    async fn transfers(
+        &self,
+        ctx: &Context<'_>,
+        owner: Option<String>,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        #[graphql(default_with = "DEFAULT_FIRST")] first: i64,
+    ) -> Result<Vec<Transfer>> {
+        let context = QueryContext::of(ctx);
+        let owner = parse_optional_address("owner", owner)?
+            .map(|owner| context.owner_protection.protect(owner));
+
+        context
+            .transfers(Some(self.0.address), owner, from_block, to_block, first)
+            .await
+    }
+}
+
+impl Contract {
+    /// Holder count and supply of the contract, `None` before its stats are computed.
+    async fn stats(&self, ctx: &Context<'_>) -> Result<Option<(i64, f64)>> {
+        Ok(QueryContext::of(ctx)
+            .store
+            .contract_stats(&[self.0.address])
+            .await
+            .map_err(internal_error)?
+            .into_iter()
+            .next()
+            .map(|stats| (stats.holder_count, stats.supply)))
+    }
+}
+
+struct Owner(H160);
+
+#[Object]
+impl Owner {
+    async fn address(&self) -> String {
+        format!("{:#x}", self.0)
+    }
+
+    /// Ownerships of the owner with a positive amount, of `contract` and of `tokenType` when
+    /// given.
+    async fn ownerships(
+        &self,
+        ctx: &Context<'_>,
+        contract: Option<String>,
+        token_type: Option<String>,
+        #[graphql(default_with = "DEFAULT_FIRST")] first: i64,
+    ) -> Result<Vec<Ownership>> {
+        let context = QueryContext::of(ctx);
+
+        let mut ownerships = context
+            .store
+            .owner_ownerships(
+                context.owner_protection.protect(self.0),
+                parse_optional_address("contract", contract)?,
+            )
+            .await
+            .map_err(internal_error)?;
+
+        if let Some(token_type) = token_type {
+            let mut matching = Vec::new();
+            for ownership in ownerships {
+                let contract = context.contract(ownership.contract_address).await?;
+                if contract.is_some_and(|contract| contract.token_type == token_type) {
+                    matching.push(ownership);
+                }
+            }
+            ownerships = matching;
+        }
+
+        context.held(ownerships, clamp_first(first))
+    }
+
+    /// Recorded transfers from or to the owner, of `contract` when given.
+    async fn transfers(
+        &self,
+        ctx: &Context<'_>,
+        contract: Option<String>,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        #[graphql(default_with = "DEFAULT_FIRST")] first: i64,
+    ) -> Result<Vec<Transfer>> {
+        let context = QueryContext::of(ctx);
+
+        context
+            .transfers(
+                parse_optional_address("contract", contract)?,
+                Some(context.owner_protection.protect(self.0)),
+                from_block,
+                to_block,
+                first,
+            )
+            .await
+    }
+}
+
+struct Ownership(TokenOwnership);
+
+#[Object(name = "TokenOwnership")]
+impl Ownership {
+    async fn contract(&self, ctx: &Context<'_>) -> Result<Option<Contract>> {
+        QueryContext::of(ctx)
+            .contract_node(self.0.contract_address)
+            .await
+    }
+
+    async fn token_id(&self) -> Option<&str> {
+        self.0.token_id.as_deref()
+    }
+
+    async fn owner(&self) -> String {
+        format!("{:#x}", self.0.owner)
+    }
+
+    /// Exact amount held, in the smallest unit of the token.
+    async fn amount(&self) -> String {
+        self.0.exact_amount().to_string()
+    }
+
+    /// `amount` in whole units of the token, when the decimals of the contract are known.
+    async fn display_amount(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        QueryContext::of(ctx)
+            .display_amount(self.0.contract_address, Some(self.0.exact_amount()))
+            .await
+    }
+
+    async fn quantity(&self) -> f64 {
+        self.0.quantity
+    }
+
+    async fn custody(&self) -> bool {
+        self.0.custody
+    }
+
+    /// Creator the token id of a shared ERC1155 contract encodes.
+    async fn creator(&self) -> Option<String> {
+        self.0.creator.map(|creator| format!("{:#x}", creator))
+    }
+
+    async fn metadata(&self, ctx: &Context<'_>) -> Result<Option<Metadata>> {
+        QueryContext::of(ctx)
+            .metadata_node(self.0.contract_address, self.0.token_id.as_deref())
+            .await
+    }
+}
+
+struct Transfer(TransferRecord);
+
+#[Object]
+impl Transfer {
+    async fn id(&self) -> &str {
+        &self.0.id
+    }
+
+    async fn transaction_hash(&self) -> String {
+        format!("{:#x}", self.0.transaction_hash)
+    }
+
+    async fn log_index(&self) -> i64 {
+        self.0.log_index
+    }
+
+    async fn block_number(&self) -> i64 {
+        self.0.block_number
+    }
+
+    async fn block_hash(&self) -> String {
+        format!("{:#x}", self.0.block_hash)
+    }
+
+    async fn contract(&self, ctx: &Context<'_>) -> Result<Option<Contract>> {
+        QueryContext::of(ctx)
+            .contract_node(self.0.contract_address)
+            .await
+    }
+
+    async fn token_type(&self) -> &str {
+        &self.0.token_type
+    }
+
+    async fn token_id(&self) -> Option<&str> {
+        self.0.token_id.as_deref()
+    }
+
+    async fn from(&self) -> String {
+        format!("{:#x}", self.0.from)
+    }
+
+    async fn to(&self) -> String {
+        format!("{:#x}", self.0.to)
+    }
+
+    /// Exact transferred amount, unset for transfers recorded before exact amounts were.
+    async fn amount(&self) -> Option<String> {
+        self.0.amount.map(|amount| amount.to_string())
+    }
+
+    async fn display_amount(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        QueryContext::of(ctx)
+            .display_amount(self.0.contract_address, self.0.amount)
+            .await
+    }
+
+    async fn quantity(&self) -> f64 {
+        self.0.quantity
+    }
+}
+
+struct Metadata(TokenMetadata);
+
+#[Object(name = "TokenMetadata")]
+impl Metadata {
+    async fn token_id(&self) -> Option<&str> {
+        self.0.token_id.as_deref()
+    }
+
+    /// `pending`, `fetched` or `failed`.
+    async fn status(&self) -> &str {
+        match self.0.status {
+            MetadataStatus::Pending => "pending",
+            MetadataStatus::Fetched => "fetched",
+            MetadataStatus::Failed => "failed",
+        }
+    }
+
+    async fn name(&self) -> Option<&str> {
+        self.0.name.as_deref()
+    }
+
+    async fn symbol(&self) -> Option<&str> {
+        self.0.symbol.as_deref()
+    }
+
+    async fn decimals(&self) -> Option<i32> {
+        self.0.decimals
+    }
+
+    async fn token_uri(&self) -> Option<&str> {
+        self.0.token_uri.as_deref()
+    }
+
+    async fn image(&self) -> Option<&str> {
+        self.0.image.as_deref()
+    }
+
+    /// JSON document `tokenUri` points to.
+    async fn document(&self) -> Option<JsonScalar<Json>> {
+        self.0
+            .document
+            .clone()
+            .map(|document| JsonScalar(Bson::Document(document).into_relaxed_extjson()))
+    }
+
+    async fn fetched_at(&self) -> Option<String> {
+        self.0
+            .fetched_at
+            .map(|fetched_at| fetched_at.to_rfc3339_string())
+    }
+
+    async fn error(&self) -> Option<&str> {
+        self.0.error.as_deref()
+    }
+}
+
+/// Whether the request was rejected before execution, as a query that cannot be parsed or
+/// validated is, which is answered with a `400 Bad Request`.
+pub(crate) fn is_request_error(response: &Response) -> bool {
+    response.data == async_graphql::Value::Null
+        && !response.errors.is_empty()
+        && response.errors.iter().all(|error| error.path.is_empty())
+}
+
+/// Executes a query. Request errors, such as a syntax error or an unknown operation, are answered
+/// without data, while field errors null their field and are listed in `errors`.
+pub(crate) async fn execute(
+    store: &Store,
+    decimals: &DecimalsRegistry,
+    owner_protection: &OwnerProtection,
+    request: GraphQlRequest,
+) -> Response {
+    let mut graphql_request = Request::new(request.query)
+        .variables(Variables::from_json(Json::Object(
+            request.variables.unwrap_or_default(),
+        )))
+        .data(QueryContext {
+            store: store.clone(),
+            decimals: decimals.clone(),
+            owner_protection: owner_protection.clone(),
+            contracts: Mutex::new(HashMap::new()),
+            objects: AtomicUsize::new(0),
+        });
+    if let Some(operation_name) = request.operation_name {
+        graphql_request = graphql_request.operation_name(operation_name);
+    }
+
+    SCHEMA
+        .get_or_init(|| {
+            Schema::build(Query, EmptyMutation, EmptySubscription)
+                .limit_depth(MAX_DEPTH)
+                .finish()
+        })
+        .execute(graphql_request)
+        .await
+}
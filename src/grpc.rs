@@ -0,0 +1,311 @@
+//! The gRPC service described by `proto/ownership.proto`, only compiled with the `grpc` feature.
+//!
+//! The messages and the service trait are generated from the proto file by `tonic-prost-build` and
+//! served with `tonic`, so internal consumers get typed, streaming access to the ownership model
+//! without the JSON of the API.
+
+use crate::{
+    decimals::DecimalsRegistry,
+    privacy::OwnerProtection,
+    store::{DeltaFeedEntry, DeltaFeedKind, Store, TokenOwnership},
+};
+use futures::TryStreamExt;
+use proto::{
+    feed_entry::Kind,
+    ownership_server::{Ownership, OwnershipServer},
+    ContractOwnershipsRequest, FeedEntry, LookupReply, LookupRequest, WatchChangesRequest,
+};
+use std::{error, fmt::Display, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    select,
+    sync::{mpsc, RwLock},
+    time::sleep,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+use web3::types::H160;
+
+mod proto {
+    tonic::include_proto!("ownership.v1");
+}
+
+/// Delta feed entries read at once by `WatchChanges`.
+const FEED_BATCH_SIZE: i64 = 100;
+
+/// Time `WatchChanges` waits before looking for new feed entries once it streamed every entry.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response messages buffered per call before waiting for the client to read them.
+const STREAM_BUFFER: usize = 16;
+
+/// The response messages of a call, sent by the task answering it.
+type ResponseStream<T> = ReceiverStream<Result<T, Status>>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct GrpcState {
+    pub store: Store,
+    pub decimals: DecimalsRegistry,
+    /// Held for writing by the logs worker while it applies a block, so lookups only ever observe
+    /// the ownership model at a block boundary.
+    pub block_lock: Arc<RwLock<()>>,
+    pub delta_feed_enabled: bool,
+    /// Applied to looked up owners so they match the stored ones.
+    pub owner_protection: OwnerProtection,
+}
+
+fn internal(error: impl Display) -> Status {
+    eprintln!("Error: gRPC call failed... {}", error);
+    Status::internal("Internal server error")
+}
+
+pub(crate) async fn serve(
+    port: u16,
+    state: GrpcState,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    println!("gRPC listening on port {}", port);
+
+    Server::builder()
+        .add_service(OwnershipServer::new(state))
+        .serve(SocketAddr::from(([0, 0, 0, 0], port)))
+        .await?;
+
+    Ok(())
+}
+
+/// Runs `respond` in a task sending its messages to the returned stream, ending the call with the
+/// error it returns.
+fn respond<T, F>(respond: impl FnOnce(mpsc::Sender<Result<T, Status>>) -> F) -> ResponseStream<T>
+where
+    T: Send + 'static,
+    F: std::future::Future<Output = Result<(), Status>> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+
+    let future = respond(sender.clone());
+    tokio::spawn(async move {
+        if let Err(status) = future.await {
+            let _ = sender.send(Err(status)).await;
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+/// Sends a response message, failing with `CANCELLED` once the client is gone.
+async fn send<T>(sender: &mpsc::Sender<Result<T, Status>>, message: T) -> Result<(), Status> {
+    sender
+        .send(Ok(message))
+        .await
+        .map_err(|_| Status::cancelled("The client cancelled the call"))
+}
+
+fn parse_address(name: &str, value: &str) -> Result<H160, Status> {
+    value
+        .parse()
+        .map_err(|_| Status::invalid_argument(format!("`{}` must be an address", name)))
+}
+
+#[tonic::async_trait]
+impl Ownership for GrpcState {
+    type LookupStream = ResponseStream<LookupReply>;
+    type ContractOwnershipsStream = ResponseStream<proto::TokenOwnership>;
+    type WatchChangesStream = ResponseStream<FeedEntry>;
+
+    async fn lookup(
+        &self,
+        request: Request<Streaming<LookupRequest>>,
+    ) -> Result<Response<Self::LookupStream>, Status> {
+        let state = self.clone();
+        let mut requests = request.into_inner();
+
+        Ok(Response::new(respond(move |sender| async move {
+            while let Some(request) = requests.message().await? {
+                send(&sender, state.lookup_reply(request).await?).await?;
+            }
+            Ok(())
+        })))
+    }
+
+    async fn contract_ownerships(
+        &self,
+        request: Request<ContractOwnershipsRequest>,
+    ) -> Result<Response<Self::ContractOwnershipsStream>, Status> {
+        let contract = parse_address("contract_address", &request.get_ref().contract_address)?;
+        let state = self.clone();
+
+        Ok(Response::new(respond(move |sender| async move {
+            state.stream_ownerships(contract, &sender).await
+        })))
+    }
+
+    async fn watch_changes(
+        &self,
+        request: Request<WatchChangesRequest>,
+    ) -> Result<Response<Self::WatchChangesStream>, Status> {
+        if !self.delta_feed_enabled {
+            return Err(Status::failed_precondition(
+                "Live changes need the delta feed, start the worker with --delta-feed-retention",
+            ));
+        }
+
+        let after = match request.get_ref().after_sequence {
+            Some(after) => after,
+            None => self.store.latest_feed_sequence().await.map_err(internal)?,
+        };
+        let state = self.clone();
+
+        Ok(Response::new(respond(move |sender| async move {
+            state.stream_changes(after, &sender).await
+        })))
+    }
+}
+
+impl GrpcState {
+    /// Answers a `LookupRequest` with the amount held at the last block applied.
+    async fn lookup_reply(&self, request: LookupRequest) -> Result<LookupReply, Status> {
+        let contract = parse_address("contract_address", &request.contract_address)?;
+        let owner = parse_address("owner", &request.owner)?;
+        let stored_owner = self.owner_protection.protect(owner);
+
+        let (amount, block_number) = {
+            let _guard = self.block_lock.read().await;
+
+            let amount = match &request.token_id {
+                Some(token_id) => {
+                    self.store
+                        .ownership_amount(contract, Some(token_id), stored_owner)
+                        .await
+                }
+                None => self.store.owner_amount(contract, stored_owner).await,
+            }
+            .map_err(internal)?;
+
+            let block_number = self.store.last_processed_block().await.map_err(internal)?;
+
+            (amount, block_number)
+        };
+
+        let display_amount = self
+            .decimals
+            .display_amount(contract, amount)
+            .await
+            .map_err(internal)?;
+
+        Ok(LookupReply {
+            contract_address: format!("{:#x}", contract),
+            owner: format!("{:#x}", owner),
+            token_id: request.token_id,
+            amount: amount.to_string(),
+            display_amount,
+            block_number: block_number.map_or(0, |block_number| block_number.as_u64()),
+        })
+    }
+
+    /// Streams the ownerships of a contract with a positive amount. The logs worker is paused at a
+    /// block boundary for as long as they are streamed, as for a snapshot.
+    async fn stream_ownerships(
+        &self,
+        contract: H160,
+        sender: &mpsc::Sender<Result<proto::TokenOwnership, Status>>,
+    ) -> Result<(), Status> {
+        let _guard = self.block_lock.read().await;
+
+        let mut ownerships = self
+            .store
+            .contract_ownerships(contract)
+            .await
+            .map_err(internal)?;
+
+        while let Some(ownership) = ownerships.try_next().await.map_err(internal)? {
+            if ownership.exact_amount().is_positive() {
+                send(sender, encode_ownership(&ownership)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Streams the delta feed after `after`, then follows it until the client cancels the call.
+    /// The call fails with `OUT_OF_RANGE` once the entries it needs are pruned or the feed is
+    /// reset, after which the client needs a new snapshot.
+    async fn stream_changes(
+        &self,
+        mut after: i64,
+        sender: &mpsc::Sender<Result<FeedEntry, Status>>,
+    ) -> Result<(), Status> {
+        loop {
+            let (oldest_sequence, latest_sequence) = tokio::try_join!(
+                self.store.oldest_feed_sequence(),
+                self.store.latest_feed_sequence(),
+            )
+            .map_err(internal)?;
+
+            if oldest_sequence.is_some_and(|oldest_sequence| after + 1 < oldest_sequence) {
+                return Err(Status::out_of_range(format!(
+                    "Entries after sequence {} have been pruned, take a new snapshot",
+                    after
+                )));
+            }
+            if after > latest_sequence {
+                return Err(Status::out_of_range(format!(
+                    "Sequence {} is ahead of the feed, which has been reset, take a new snapshot",
+                    after
+                )));
+            }
+
+            let entries = self
+                .store
+                .feed_entries(after, FEED_BATCH_SIZE)
+                .await
+                .map_err(internal)?;
+
+            if entries.is_empty() {
+                select! {
+                    _ = sleep(POLL_INTERVAL) => {}
+                    _ = sender.closed() => return Ok(()),
+                }
+                continue;
+            }
+
+            for entry in &entries {
+                send(sender, encode_feed_entry(entry)).await?;
+                after = entry.sequence;
+            }
+        }
+    }
+}
+
+fn encode_ownership(ownership: &TokenOwnership) -> proto::TokenOwnership {
+    proto::TokenOwnership {
+        contract_address: format!("{:#x}", ownership.contract_address),
+        token_id: ownership.token_id.clone(),
+        owner: format!("{:#x}", ownership.owner),
+        amount: ownership.exact_amount().to_string(),
+        quantity: ownership.quantity,
+    }
+}
+
+fn encode_feed_entry(entry: &DeltaFeedEntry) -> FeedEntry {
+    FeedEntry {
+        sequence: entry.sequence,
+        kind: match entry.kind {
+            DeltaFeedKind::Apply => Kind::Apply,
+            DeltaFeedKind::Revert => Kind::Revert,
+        }
+        .into(),
+        block_number: entry.block_number as u64,
+        block_hash: format!("{:#x}", entry.block_hash),
+        deltas: entry
+            .deltas
+            .iter()
+            .map(|delta| proto::OwnershipDelta {
+                contract_address: format!("{:#x}", delta.contract_address),
+                token_type: delta.token_type.clone(),
+                token_id: delta.token_id.clone(),
+                owner: format!("{:#x}", delta.owner),
+                amount: delta.exact_amount().to_string(),
+                quantity: delta.quantity,
+            })
+            .collect(),
+    }
+}
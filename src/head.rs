@@ -0,0 +1,110 @@
+use crate::RpcTransport;
+use futures::StreamExt;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::sleep};
+use web3::{
+    transports::WebSocket,
+    types::{FilterBuilder, H160, H256, U64},
+    Web3,
+};
+
+/// Interval between two `eth_blockNumber` polls when no WebSocket subscription is active.
+const POLL_INTERVAL: Duration = Duration::from_millis(60000);
+
+/// Keeps `latest_block` up to date with the chain head and wakes the logs worker through
+/// `new_block` whenever a new head or a matching log is seen.
+///
+/// With a WebSocket endpoint the head is followed through `newHeads` and `logs` subscriptions. When
+/// the socket cannot be opened or drops, the head is polled over HTTP until the next reconnection
+/// attempt.
+///
+/// The `logs` subscription is restricted to `addresses` when given.
+pub(crate) async fn track_latest_block(
+    web3: Web3<RpcTransport>,
+    ws_endpoint: Option<String>,
+    topics: Vec<H256>,
+    addresses: Option<Vec<H160>>,
+    latest_block: Arc<Mutex<Option<U64>>>,
+    new_block: Arc<Notify>,
+) {
+    loop {
+        if let Some(ws_endpoint) = &ws_endpoint {
+            match subscribe(
+                ws_endpoint,
+                topics.clone(),
+                addresses.clone(),
+                &latest_block,
+                &new_block,
+            )
+            .await
+            {
+                Ok(()) => eprintln!("Error: WebSocket subscription closed, polling over HTTP..."),
+                Err(error) => eprintln!(
+                    "Error: WebSocket subscription failed, polling over HTTP... {}",
+                    error
+                ),
+            }
+        }
+
+        match web3.eth().block_number().await {
+            Ok(value) => {
+                *latest_block.lock().unwrap() = Some(value);
+                new_block.notify_one();
+            }
+            Err(_) => {
+                eprintln!("Error: Could not get the current block number, retrying...");
+                continue;
+            }
+        };
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Follows the chain head over WebSocket until the socket drops.
+async fn subscribe(
+    ws_endpoint: &str,
+    topics: Vec<H256>,
+    addresses: Option<Vec<H160>>,
+    latest_block: &Mutex<Option<U64>>,
+    new_block: &Notify,
+) -> web3::Result<()> {
+    let web3 = Web3::new(WebSocket::new(ws_endpoint).await?);
+
+    let heads = web3.eth_subscribe().subscribe_new_heads().await?;
+    let mut filter = FilterBuilder::default().topics(Some(topics), None, None, None);
+
+    if let Some(addresses) = addresses {
+        filter = filter.address(addresses);
+    }
+
+    let logs = web3.eth_subscribe().subscribe_logs(filter.build()).await?;
+
+    futures::pin_mut!(heads, logs);
+
+    println!("Subscribed to new heads and logs over WebSocket");
+
+    loop {
+        tokio::select! {
+            head = heads.next() => match head {
+                Some(head) => {
+                    if let Some(number) = head?.number {
+                        *latest_block.lock().unwrap() = Some(number);
+                        new_block.notify_one();
+                    }
+                }
+                None => return Ok(()),
+            },
+            log = logs.next() => match log {
+                Some(log) => {
+                    log?;
+                    new_block.notify_one();
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
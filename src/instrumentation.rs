@@ -0,0 +1,291 @@
+use futures::future::{BoxFuture, FutureExt};
+use jsonrpc_core::{serde_json, Call, Value};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    event::command::{
+        CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+    },
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+use web3::{types::U64, RequestId, Transport};
+
+/// Number of slow operations listed by the status endpoint.
+const SLOWEST_OPERATIONS: usize = 20;
+
+/// Time a slow operation stays listed by the status endpoint.
+const SLOWEST_OPERATIONS_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Length above which the context of a slow operation is truncated.
+const MAX_CONTEXT_LENGTH: usize = 500;
+
+/// Command fields the driver adds to every command, which tell nothing about the operation.
+const DRIVER_FIELDS: [&str; 6] = [
+    "lsid",
+    "txnNumber",
+    "ordered",
+    "readConcern",
+    "writeConcern",
+    "autocommit",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OperationKind {
+    Mongo,
+    Rpc,
+}
+
+/// A MongoDB command or RPC call that took longer than the slow operation threshold.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub(crate) struct SlowOperation {
+    pub kind: OperationKind,
+    /// Command and collection of a MongoDB command, such as `update token_ownerships`, or method
+    /// of an RPC call.
+    pub operation: String,
+    /// Filters of a MongoDB command or parameters of an RPC call, truncated.
+    pub context: String,
+    /// Block the logs worker was processing, which ranges fetched concurrently make approximate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_number: Option<u64>,
+    pub duration_ms: u64,
+    /// Unix timestamp in seconds.
+    pub recorded_at: u64,
+    #[serde(skip)]
+    recorded: Instant,
+}
+
+/// Times every MongoDB command and RPC call of the worker, logging the ones slower than
+/// `threshold` with their context and listing the slowest recent ones for the status endpoint.
+#[derive(Debug)]
+pub(crate) struct Instrumentation {
+    threshold: Duration,
+    /// Block the logs worker is processing, 0 before the first one.
+    current_block: AtomicU64,
+    /// Operation and context of the MongoDB commands in flight, by request id.
+    commands: Mutex<HashMap<i32, (String, String)>>,
+    slowest: Mutex<Vec<SlowOperation>>,
+}
+
+impl Instrumentation {
+    pub(crate) fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            current_block: AtomicU64::new(0),
+            commands: Default::default(),
+            slowest: Default::default(),
+        }
+    }
+
+    pub(crate) fn set_current_block(&self, block_number: U64) {
+        self.current_block
+            .store(block_number.as_u64(), Ordering::Relaxed);
+    }
+
+    /// Logs and lists an operation when it took longer than the threshold. `context` is only
+    /// rendered then.
+    fn record(
+        &self,
+        kind: OperationKind,
+        operation: String,
+        context: impl FnOnce() -> String,
+        duration: Duration,
+    ) {
+        if duration < self.threshold {
+            return;
+        }
+
+        let block_number = match self.current_block.load(Ordering::Relaxed) {
+            0 => None,
+            block_number => Some(block_number),
+        };
+
+        let slow_operation = SlowOperation {
+            kind,
+            operation,
+            context: truncate(context()),
+            block_number,
+            duration_ms: duration.as_millis() as u64,
+            recorded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            recorded: Instant::now(),
+        };
+
+        eprintln!(
+            "Warning: Slow {} {} took {} ms at block {}... {}",
+            match kind {
+                OperationKind::Mongo => "MongoDB command",
+                OperationKind::Rpc => "RPC call",
+            },
+            slow_operation.operation,
+            slow_operation.duration_ms,
+            block_number.map_or("-".to_string(), |block_number| block_number.to_string()),
+            slow_operation.context
+        );
+
+        let mut slowest = self.slowest.lock().unwrap();
+        slowest.retain(|operation| operation.recorded.elapsed() < SLOWEST_OPERATIONS_WINDOW);
+        slowest.push(slow_operation);
+        slowest.sort_by_key(|operation| std::cmp::Reverse(operation.duration_ms));
+        slowest.truncate(SLOWEST_OPERATIONS);
+    }
+
+    /// The slowest operations of the last hour, slowest first.
+    #[cfg(feature = "api")]
+    pub(crate) fn slowest_operations(&self) -> Vec<SlowOperation> {
+        self.slowest
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|operation| operation.recorded.elapsed() < SLOWEST_OPERATIONS_WINDOW)
+            .cloned()
+            .collect()
+    }
+
+    fn finish_command(&self, request_id: i32, command_name: &str, duration: Duration) {
+        let (operation, context) = self
+            .commands
+            .lock()
+            .unwrap()
+            .remove(&request_id)
+            .unwrap_or_else(|| (command_name.to_string(), String::new()));
+
+        self.record(OperationKind::Mongo, operation, || context, duration);
+    }
+}
+
+impl CommandEventHandler for Instrumentation {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        let operation = match event.command.get_str(&event.command_name) {
+            Ok(collection) => format!("{} {}", event.command_name, collection),
+            Err(_) => event.command_name.clone(),
+        };
+
+        self.commands.lock().unwrap().insert(
+            event.request_id,
+            (
+                operation,
+                command_context(&event.command_name, &event.command),
+            ),
+        );
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.finish_command(event.request_id, &event.command_name, event.duration);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.finish_command(event.request_id, &event.command_name, event.duration);
+    }
+}
+
+/// The fields of a command that describe the operation, such as its filter. Arrays of documents,
+/// such as the statements of an update or the documents of an insert, are reduced to their count
+/// and the filter of their first statement.
+fn command_context(command_name: &str, command: &Document) -> String {
+    let mut context = Document::new();
+
+    for (key, value) in command {
+        if key == command_name || key.starts_with('$') || DRIVER_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+
+        match value {
+            Bson::Array(items) => {
+                let mut summary = doc! { "count": items.len() as i64 };
+                if let Some(filter) = items
+                    .first()
+                    .and_then(Bson::as_document)
+                    .and_then(|statement| statement.get("q"))
+                {
+                    summary.insert("q", filter.clone());
+                }
+                context.insert(key, summary);
+            }
+            value => {
+                context.insert(key, value.clone());
+            }
+        }
+    }
+
+    truncate(context.to_string())
+}
+
+fn truncate(mut context: String) -> String {
+    if context.len() > MAX_CONTEXT_LENGTH {
+        let mut end = MAX_CONTEXT_LENGTH;
+        while !context.is_char_boundary(end) {
+            end -= 1;
+        }
+        context.truncate(end);
+        context.push('…');
+    }
+
+    context
+}
+
+/// Transport wrapper timing the RPC calls of a provider.
+#[derive(Debug, Clone)]
+pub(crate) struct InstrumentedTransport<T> {
+    inner: T,
+    instrumentation: Arc<Instrumentation>,
+}
+
+impl<T> InstrumentedTransport<T> {
+    pub(crate) fn new(inner: T, instrumentation: Arc<Instrumentation>) -> Self {
+        Self {
+            inner,
+            instrumentation,
+        }
+    }
+}
+
+impl<T> Transport for InstrumentedTransport<T>
+where
+    T: Transport,
+    T::Out: Send + 'static,
+{
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        self.inner.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        let call = match &request {
+            Call::MethodCall(call) => Some(call.clone()),
+            _ => None,
+        };
+        let instrumentation = self.instrumentation.clone();
+        let started_at = Instant::now();
+
+        self.inner
+            .send(id, request)
+            .map(move |response| {
+                if let Some(call) = call {
+                    instrumentation.record(
+                        OperationKind::Rpc,
+                        call.method,
+                        || serde_json::to_string(&call.params).unwrap_or_default(),
+                        started_at.elapsed(),
+                    );
+                }
+
+                response
+            })
+            .boxed()
+    }
+}
@@ -0,0 +1,202 @@
+//! Resolution of the JSON documents NFT token URIs point to.
+
+use crate::store::{Pin, PinStatus};
+use mongodb::bson::{self, Document};
+use serde::Deserialize;
+use serde_json::json;
+use std::{error, time::Duration};
+
+/// Gateway IPFS token URIs are fetched from when none is configured.
+pub const DEFAULT_IPFS_GATEWAY: &str = "https://ipfs.io/ipfs/";
+
+/// Time a gateway or server is given to answer with a whole document.
+const DOCUMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Downloads metadata documents, fetching IPFS content from a list of gateways.
+#[derive(Debug, Clone)]
+pub(crate) struct DocumentResolver {
+    client: reqwest::Client,
+    gateways: Vec<String>,
+    size_limit: usize,
+}
+
+impl DocumentResolver {
+    pub(crate) fn new(gateways: Vec<String>, size_limit: usize) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(DOCUMENT_TIMEOUT)
+                .build()
+                .expect("The HTTP client is built without custom TLS settings"),
+            gateways,
+            size_limit,
+        }
+    }
+
+    /// Downloads the JSON object a token URI points to. IPFS URIs, including links to any public
+    /// gateway, are fetched from the configured gateways in turn until one of them answers.
+    ///
+    /// Returns `None` for URIs that are neither IPFS nor HTTP, such as `data:` and `ar://` URIs.
+    pub(crate) async fn resolve(
+        &self,
+        uri: &str,
+    ) -> Result<Option<Document>, Box<dyn error::Error + Send + Sync>> {
+        let urls = match ipfs_path(uri) {
+            Some(path) => self
+                .gateways
+                .iter()
+                .map(|gateway| format!("{}/{}", gateway.trim_end_matches('/'), path))
+                .collect(),
+            None if uri.starts_with("https://") || uri.starts_with("http://") => {
+                vec![uri.to_string()]
+            }
+            None => return Ok(None),
+        };
+
+        let mut last_error = None;
+
+        for url in urls {
+            match self.download(&url).await {
+                Ok(document) => return Ok(Some(document)),
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| "No IPFS gateway is configured".into()))
+    }
+
+    async fn download(&self, url: &str) -> Result<Document, Box<dyn error::Error + Send + Sync>> {
+        let mut response = self.client.get(url).send().await?.error_for_status()?;
+
+        if response.content_length().unwrap_or_default() > self.size_limit as u64 {
+            return Err(format!(
+                "{} exceeds the size limit of {} bytes",
+                url, self.size_limit
+            )
+            .into());
+        }
+
+        // The announced length is not trusted, since servers may leave it out or stream more.
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+
+            if body.len() > self.size_limit {
+                return Err(format!(
+                    "{} exceeds the size limit of {} bytes",
+                    url, self.size_limit
+                )
+                .into());
+            }
+        }
+
+        match serde_json::from_slice(&body)? {
+            serde_json::Value::Object(object) => Ok(bson::to_document(&object)?),
+            _ => Err(format!("{} is not a JSON object", url).into()),
+        }
+    }
+}
+
+/// The CID of an IPFS URI, without the path within it.
+pub(crate) fn ipfs_cid(uri: &str) -> Option<&str> {
+    ipfs_path(uri)?
+        .split(['/', '?', '#'])
+        .next()
+        .filter(|cid| !cid.is_empty())
+}
+
+/// The content path of an IPFS URI, accepting `ipfs://<cid>/<path>`, the legacy
+/// `ipfs://ipfs/<cid>/<path>` and HTTP links to the `/ipfs/` path of a gateway.
+pub(crate) fn ipfs_path(uri: &str) -> Option<&str> {
+    if let Some(path) = uri.strip_prefix("ipfs://") {
+        let path = path.strip_prefix("ipfs/").unwrap_or(path);
+        return Some(path).filter(|path| !path.is_empty());
+    }
+
+    let location = uri
+        .strip_prefix("https://")
+        .or_else(|| uri.strip_prefix("http://"))?;
+
+    location
+        .split_once("/ipfs/")
+        .map(|(_, path)| path)
+        .filter(|path| !path.is_empty())
+}
+
+/// Time the pinning service is given to answer a request.
+const PINNING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pin request status returned by the pinning service.
+#[derive(Debug, Deserialize)]
+struct PinResponse {
+    requestid: String,
+    status: PinStatus,
+}
+
+/// Client of a service implementing the IPFS Pinning Service API, which keeps the content token
+/// metadata references available on IPFS.
+#[derive(Debug, Clone)]
+pub(crate) struct PinningService {
+    client: reqwest::Client,
+    endpoint: String,
+    token: Option<String>,
+}
+
+impl PinningService {
+    pub(crate) fn new(endpoint: String, token: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(PINNING_TIMEOUT)
+                .build()
+                .expect("The HTTP client is built without custom TLS settings"),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    /// Requests a CID to be pinned under `name`. A failed request is recorded as a failed pin
+    /// rather than returned, since it should not fail the metadata it belongs to.
+    pub(crate) async fn pin(&self, cid: &str, name: &str) -> Pin {
+        let request = self
+            .authorize(self.client.post(format!("{}/pins", self.endpoint)))
+            .json(&json!({ "cid": cid, "name": name }));
+
+        match Self::send(request).await {
+            Ok(response) => Pin {
+                cid: cid.to_string(),
+                request_id: Some(response.requestid),
+                status: response.status,
+                error: None,
+            },
+            Err(error) => Pin {
+                cid: cid.to_string(),
+                request_id: None,
+                status: PinStatus::Failed,
+                error: Some(error.to_string()),
+            },
+        }
+    }
+
+    /// Current status of a pin request.
+    pub(crate) async fn status(
+        &self,
+        request_id: &str,
+    ) -> Result<PinStatus, Box<dyn error::Error + Send + Sync>> {
+        let request = self.authorize(
+            self.client
+                .get(format!("{}/pins/{}", self.endpoint, request_id)),
+        );
+
+        Ok(Self::send(request).await?.status)
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn send(request: reqwest::RequestBuilder) -> Result<PinResponse, reqwest::Error> {
+        request.send().await?.error_for_status()?.json().await
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{
+    logs_worker::LogsWorker,
+    store::{BlockJob, BlockJobStatus, Store},
+};
+use mongodb::bson::oid::ObjectId;
+use std::{error, time::Duration};
+use web3::types::{H160, U64};
+
+/// Number of times a job is claimed before it is marked failed.
+pub(crate) const MAX_BLOCK_JOB_ATTEMPTS: i32 = 5;
+
+/// Time an idle worker waits before looking for a claimable job again.
+pub(crate) const BLOCK_JOB_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Name of a worker process when none is given, the host name and process id.
+pub fn default_worker_id() -> String {
+    format!(
+        "{}-{}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string()),
+        std::process::id()
+    )
+}
+
+/// Splits `from_block` to `to_block` into jobs of at most `job_size` blocks.
+pub(crate) fn split(
+    from_block: u64,
+    to_block: u64,
+    job_size: u64,
+    contracts: &[H160],
+) -> Vec<BlockJob> {
+    let job_size = job_size.max(1);
+
+    (from_block..=to_block)
+        .step_by(job_size as usize)
+        .map(|job_start| BlockJob {
+            id: ObjectId::new(),
+            from_block: job_start as i64,
+            to_block: (job_start + job_size - 1).min(to_block) as i64,
+            next_block: job_start as i64,
+            contracts: contracts.to_vec(),
+            status: BlockJobStatus::Pending,
+            claimed_by: None,
+            lease_expires_at: None,
+            attempts: 0,
+            error: None,
+            head_handoff: false,
+        })
+        .collect()
+}
+
+/// Backfills the blocks of a claimed job from its `next_block`, one log range at a time. Progress
+/// is recorded and the lease extended after every range, and the job is abandoned as soon as the
+/// lease turns out to be lost.
+pub(crate) async fn run(
+    logs_worker: &LogsWorker,
+    store: &Store,
+    job: &BlockJob,
+    worker_id: &str,
+    lease: Duration,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let range_size = logs_worker.config.log_range_size.max(1);
+    let to_block = job.to_block as u64;
+    let mut next_block = job.next_block as u64;
+
+    while next_block <= to_block {
+        let range_end = (next_block + range_size - 1).min(to_block);
+
+        logs_worker
+            .backfill(
+                U64::from(next_block),
+                U64::from(range_end),
+                job.head_handoff,
+            )
+            .await?;
+
+        next_block = range_end + 1;
+
+        if !store
+            .advance_block_job(job.id, worker_id, U64::from(next_block), lease)
+            .await?
+        {
+            return Err(format!(
+                "Lost the lease of the job for blocks {} to {}",
+                job.from_block, job.to_block
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
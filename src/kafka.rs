@@ -0,0 +1,406 @@
+//! Publishing of the delta feed to a Kafka topic, only compiled with the `kafka` feature.
+//!
+//! Records are produced with `rskafka`, uncompressed and without TLS or SASL, and acknowledged by
+//! every in-sync replica. Avro messages are encoded with `apache-avro`.
+
+use crate::{
+    amount::Amount,
+    backoff::{Backoff, RetryPolicy},
+    ownership::OwnershipDelta,
+    store::{DeltaFeedEntry, DeltaFeedKind, Store, TransferRecord},
+};
+use apache_avro::{GenericSingleObjectWriter, Schema};
+use rskafka::{
+    chrono::Utc,
+    client::{
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+        Client, ClientBuilder,
+    },
+    record::Record,
+    BackoffConfig,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, error, str::FromStr, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::sleep};
+use web3::types::{H160, H256, U64};
+
+/// `sync_state` id of the sequence of the last delta feed entry published.
+const EXPORT_ID: &str = "export:kafka";
+
+/// Delta feed entries published per poll.
+const FEED_BATCH_SIZE: i64 = 100;
+
+/// Time waited before reading the delta feed again once every entry was published.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time `rskafka` retries a request for before the error is returned and the export backs off.
+const REQUEST_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Largest record batch sent, below the 1 MB `message.max.bytes` brokers accept by default.
+const MAX_BATCH_BYTES: usize = 900_000;
+
+const CLIENT_ID: &str = "token_ownership_worker";
+
+/// Schema of the Avro messages, in Parsing Canonical Form so its fingerprint is the one consumers
+/// compute.
+pub const KAFKA_AVRO_SCHEMA: &str = concat!(
+    r#"{"name":"token_ownership_worker.OwnershipEvent","type":"record","fields":["#,
+    r#"{"name":"type","type":"string"},"#,
+    r#"{"name":"kind","type":"string"},"#,
+    r#"{"name":"sequence","type":"long"},"#,
+    r#"{"name":"block_number","type":"long"},"#,
+    r#"{"name":"block_hash","type":"string"},"#,
+    r#"{"name":"transaction_hash","type":["null","string"]},"#,
+    r#"{"name":"log_index","type":["null","long"]},"#,
+    r#"{"name":"contract_address","type":"string"},"#,
+    r#"{"name":"token_type","type":"string"},"#,
+    r#"{"name":"token_id","type":["null","string"]},"#,
+    r#"{"name":"from","type":["null","string"]},"#,
+    r#"{"name":"to","type":["null","string"]},"#,
+    r#"{"name":"owner","type":["null","string"]},"#,
+    r#"{"name":"amount","type":["null","string"]},"#,
+    r#"{"name":"quantity","type":"double"}]}"#,
+);
+
+/// Encoding of the published messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KafkaFormat {
+    /// One JSON object per message.
+    Json,
+    /// Avro single-object encoding of `KAFKA_AVRO_SCHEMA`: the `C3 01` marker, the CRC-64-AVRO
+    /// fingerprint of the schema and the binary encoded record.
+    Avro,
+}
+
+impl FromStr for KafkaFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(KafkaFormat::Json),
+            "avro" => Ok(KafkaFormat::Avro),
+            _ => Err(format!("Unknown Kafka format {}", value)),
+        }
+    }
+}
+
+/// A transfer or ownership delta of a delta feed entry, as published. Transfers and deltas of
+/// `revert` entries undo those of the orphaned block.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct OwnershipEvent {
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    kind: DeltaFeedKind,
+    sequence: i64,
+    block_number: i64,
+    block_hash: H256,
+    transaction_hash: Option<H256>,
+    log_index: Option<i64>,
+    contract_address: H160,
+    token_type: String,
+    token_id: Option<String>,
+    from: Option<H160>,
+    to: Option<H160>,
+    owner: Option<H160>,
+    /// Exact amount, signed for deltas, `None` for those recorded before exact amounts were.
+    amount: Option<Amount>,
+    quantity: f64,
+}
+
+impl OwnershipEvent {
+    fn transfer(entry: &DeltaFeedEntry, transfer: TransferRecord) -> Self {
+        Self {
+            event_type: "transfer",
+            kind: entry.kind,
+            sequence: entry.sequence,
+            block_number: entry.block_number,
+            block_hash: entry.block_hash,
+            transaction_hash: Some(transfer.transaction_hash),
+            log_index: Some(transfer.log_index),
+            contract_address: transfer.contract_address,
+            token_type: transfer.token_type,
+            token_id: transfer.token_id,
+            from: Some(transfer.from),
+            to: Some(transfer.to),
+            owner: None,
+            amount: transfer.amount,
+            quantity: transfer.quantity,
+        }
+    }
+
+    fn delta(entry: &DeltaFeedEntry, delta: &OwnershipDelta) -> Self {
+        Self {
+            event_type: "ownership_delta",
+            kind: entry.kind,
+            sequence: entry.sequence,
+            block_number: entry.block_number,
+            block_hash: entry.block_hash,
+            transaction_hash: None,
+            log_index: None,
+            contract_address: delta.contract_address,
+            token_type: delta.token_type.clone(),
+            token_id: delta.token_id.clone(),
+            from: None,
+            to: None,
+            owner: Some(delta.owner),
+            amount: delta.amount,
+            quantity: delta.quantity,
+        }
+    }
+
+    fn encode(
+        &self,
+        format: KafkaFormat,
+        schema: &Schema,
+    ) -> Result<Vec<u8>, Box<dyn error::Error + Send + Sync>> {
+        match format {
+            KafkaFormat::Json => Ok(serde_json::to_vec(self)?),
+            KafkaFormat::Avro => {
+                let record = apache_avro::to_value(self)?.resolve(schema)?;
+
+                let mut bytes = Vec::new();
+                GenericSingleObjectWriter::new_with_capacity(schema, 256)?
+                    .write_value(record, &mut bytes)?;
+
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// The client of the brokers and of the partitions of the topic, dropped on any failure so the
+/// next publish starts from fresh metadata.
+#[derive(Debug)]
+struct KafkaClients {
+    client: Client,
+    /// Client of each partition of the topic, by partition index, connected on first use.
+    partitions: BTreeMap<i32, Option<Arc<PartitionClient>>>,
+}
+
+/// Publishes messages to the partitions of a Kafka topic, keyed by contract address so the
+/// messages of a contract stay in order within their partition.
+#[derive(Debug, Clone)]
+pub struct KafkaProducer {
+    brokers: Vec<String>,
+    topic: String,
+    format: KafkaFormat,
+    schema: Arc<Schema>,
+    clients: Arc<Mutex<Option<KafkaClients>>>,
+}
+
+impl KafkaProducer {
+    /// Producer of `topic`, bootstrapped from the comma separated `host:port` addresses of
+    /// `brokers`.
+    pub fn new(brokers: &str, topic: String, format: KafkaFormat) -> Self {
+        Self {
+            brokers: brokers
+                .split(',')
+                .map(|broker| broker.trim().to_string())
+                .filter(|broker| !broker.is_empty())
+                .collect(),
+            topic,
+            format,
+            schema: Arc::new(
+                Schema::parse_str(KAFKA_AVRO_SCHEMA).expect("KAFKA_AVRO_SCHEMA is a valid schema"),
+            ),
+            clients: Default::default(),
+        }
+    }
+
+    /// Publishes `events` in order, each partition's in a batch or more, once every in-sync
+    /// replica stored them.
+    pub(crate) async fn publish(
+        &self,
+        events: &[OwnershipEvent],
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let mut clients = self.clients.lock().await;
+
+        let result = self.publish_with(&mut clients, events).await;
+        if result.is_err() {
+            *clients = None;
+        }
+
+        result
+    }
+
+    async fn publish_with(
+        &self,
+        clients: &mut Option<KafkaClients>,
+        events: &[OwnershipEvent],
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let clients = match clients {
+            Some(clients) => clients,
+            None => clients.insert(self.connect().await?),
+        };
+
+        let partitions: Vec<i32> = clients.partitions.keys().copied().collect();
+
+        let mut records: BTreeMap<i32, Vec<Record>> = BTreeMap::new();
+        for event in events {
+            let key = format!("{:#x}", event.contract_address).into_bytes();
+            let hash = murmur2::murmur2(&key, murmur2::KAFKA_SEED);
+            let partition = partitions[(hash & 0x7fff_ffff) as usize % partitions.len()];
+            records.entry(partition).or_default().push(Record {
+                key: Some(key),
+                value: Some(event.encode(self.format, &self.schema)?),
+                headers: BTreeMap::new(),
+                timestamp: Utc::now(),
+            });
+        }
+
+        for (partition, records) in records {
+            let partition_client = match &clients.partitions[&partition] {
+                Some(partition_client) => partition_client.clone(),
+                None => {
+                    let partition_client = Arc::new(
+                        clients
+                            .client
+                            .partition_client(
+                                self.topic.clone(),
+                                partition,
+                                UnknownTopicHandling::Error,
+                            )
+                            .await?,
+                    );
+                    clients
+                        .partitions
+                        .insert(partition, Some(partition_client.clone()));
+                    partition_client
+                }
+            };
+
+            let mut batch = Vec::new();
+            let mut size = 0;
+            for record in records {
+                let record_size = record.approximate_size();
+                if !batch.is_empty() && size + record_size >= MAX_BATCH_BYTES {
+                    partition_client
+                        .produce(std::mem::take(&mut batch), Compression::NoCompression)
+                        .await?;
+                    size = 0;
+                }
+
+                size += record_size;
+                batch.push(record);
+            }
+            partition_client
+                .produce(batch, Compression::NoCompression)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Connects to the first bootstrap broker that answers and reads the partitions of the topic.
+    async fn connect(&self) -> Result<KafkaClients, Box<dyn error::Error + Send + Sync>> {
+        if self.brokers.is_empty() {
+            return Err("No Kafka broker given".into());
+        }
+
+        let client = ClientBuilder::new(self.brokers.clone())
+            .client_id(CLIENT_ID)
+            .backoff_config(BackoffConfig {
+                deadline: Some(REQUEST_DEADLINE),
+                ..Default::default()
+            })
+            .build()
+            .await?;
+
+        let topic = client
+            .list_topics()
+            .await?
+            .into_iter()
+            .find(|topic| topic.name == self.topic)
+            .ok_or_else(|| format!("Kafka did not describe topic {}", self.topic))?;
+        if topic.partitions.is_empty() {
+            return Err(format!("Topic {} has no partitions", self.topic).into());
+        }
+
+        Ok(KafkaClients {
+            client,
+            partitions: topic
+                .partitions
+                .into_iter()
+                .map(|partition| (partition, None))
+                .collect(),
+        })
+    }
+}
+
+/// Publishes the delta feed entries after the last one published. Returns whether there were any.
+async fn publish_feed_entries(
+    store: &Store,
+    producer: &KafkaProducer,
+    transfer_history: bool,
+) -> Result<bool, Box<dyn error::Error + Send + Sync>> {
+    let after = store
+        .export_checkpoint(EXPORT_ID)
+        .await?
+        .map_or(0, |sequence| sequence.as_u64() as i64);
+
+    let entries = store.feed_entries(after, FEED_BATCH_SIZE).await?;
+    let (first, last) = match (entries.first(), entries.last()) {
+        (Some(first), Some(last)) => (first.sequence, last.sequence),
+        _ => return Ok(false),
+    };
+
+    if first != after + 1 {
+        eprintln!(
+            "Warning: Delta feed entries {} to {} were pruned before being published to Kafka",
+            after + 1,
+            first - 1
+        );
+    }
+
+    let mut events = Vec::new();
+    for entry in &entries {
+        if transfer_history {
+            for transfer in store.block_transfers(entry.block_hash).await? {
+                events.push(OwnershipEvent::transfer(entry, transfer));
+            }
+        }
+        for delta in &entry.deltas {
+            events.push(OwnershipEvent::delta(entry, delta));
+        }
+    }
+
+    producer.publish(&events).await?;
+
+    store
+        .set_export_checkpoint(EXPORT_ID, U64::from(last as u64))
+        .await?;
+
+    Ok(true)
+}
+
+/// Publishes every delta feed entry, with the transfers of its block when the transfer history is
+/// recorded, for as long as the worker runs.
+pub(crate) async fn run_export(
+    store: Store,
+    producer: KafkaProducer,
+    transfer_history: bool,
+    storage_retry: RetryPolicy,
+) {
+    let mut backoff = Backoff::new(storage_retry);
+
+    loop {
+        match publish_feed_entries(&store, &producer, transfer_history).await {
+            Ok(true) => backoff.reset(),
+            Ok(false) => {
+                backoff.reset();
+                sleep(POLL_INTERVAL).await;
+            }
+            Err(error) => {
+                eprintln!(
+                    "Error: Could not publish the delta feed to Kafka, retrying... {}",
+                    error
+                );
+                backoff.wait().await;
+            }
+        }
+    }
+}
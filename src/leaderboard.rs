@@ -0,0 +1,89 @@
+//! Top holders of every contract, materialized periodically into `top_holders` so leaderboards
+//! read a single document instead of sorting the holdings of a contract on every request.
+
+use crate::store::{Store, TopHolders};
+use mongodb::bson::DateTime;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::sleep};
+use web3::types::H160;
+
+/// Materializes the `size` largest holders of every contract every `interval`. Contracts whose
+/// holder count, supply and checksum did not change since their last refresh are skipped, which
+/// compatibility mode, without checksums, cannot tell for transfers between holders, so it
+/// refreshes every contract.
+pub(crate) async fn refresh_top_holders(
+    store: Store,
+    block_lock: Arc<RwLock<()>>,
+    interval: Duration,
+    size: i64,
+    compatibility: bool,
+) {
+    loop {
+        match refresh(&store, &block_lock, size, compatibility).await {
+            Ok(0) => {}
+            Ok(refreshed) => println!("Refreshed the top holders of {} contracts", refreshed),
+            Err(error) => eprintln!(
+                "Error: Could not refresh the top holders, retrying... {}",
+                error
+            ),
+        }
+
+        sleep(interval).await;
+    }
+}
+
+/// Refreshes the top holders of the contracts that changed. Returns the number of contracts
+/// refreshed.
+async fn refresh(
+    store: &Store,
+    block_lock: &RwLock<()>,
+    size: i64,
+    compatibility: bool,
+) -> mongodb::error::Result<usize> {
+    let (stats, versions) =
+        tokio::try_join!(store.all_contract_stats(), store.top_holders_versions())?;
+
+    let contracts: HashSet<H160> = stats.iter().map(|stats| stats.contract_address).collect();
+    let removed: Vec<_> = versions
+        .keys()
+        .filter(|contract| !contracts.contains(contract))
+        .copied()
+        .collect();
+    if !removed.is_empty() {
+        store.remove_top_holders(&removed).await?;
+    }
+
+    let mut refreshed = 0;
+
+    for stats in stats {
+        let version = (stats.holder_count, stats.supply, stats.checksum);
+        if !compatibility && versions.get(&stats.contract_address) == Some(&version) {
+            continue;
+        }
+
+        // Read at a block boundary, so the holders and the block match.
+        let (holders, block_number) = {
+            let _guard = block_lock.read().await;
+            tokio::try_join!(
+                store.largest_contract_holdings(stats.contract_address, size),
+                store.last_processed_block(),
+            )?
+        };
+
+        store
+            .replace_top_holders(&TopHolders {
+                contract_address: stats.contract_address,
+                holders,
+                holder_count: stats.holder_count,
+                supply: stats.supply,
+                checksum: stats.checksum,
+                block_number: block_number.map(|block_number| block_number.as_u64() as i64),
+                refreshed_at: DateTime::now(),
+            })
+            .await?;
+
+        refreshed += 1;
+    }
+
+    Ok(refreshed)
+}
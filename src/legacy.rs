@@ -0,0 +1,202 @@
+//! Decoders for NFT contracts deployed before ERC721, which emit non-standard events and never
+//! pass the ERC165 `supportsInterface` checks.
+
+use std::{collections::HashMap, str::FromStr, sync::OnceLock};
+use web3::{
+    ethabi::{decode, param_type::ParamType, Token},
+    signing::keccak256,
+    types::{Address, Log, H256, U256},
+};
+
+/// A token movement decoded from a legacy contract's events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LegacyTransfer {
+    pub token_id: U256,
+    pub from: Address,
+    pub to: Address,
+}
+
+/// Decodes the ownership changes of a legacy contract.
+pub(crate) trait LegacyDecoder: Send + Sync {
+    /// Topics the contract's ownership changes are decoded from, besides the standard transfer
+    /// events the worker already listens to.
+    fn topics(&self) -> Vec<H256>;
+
+    /// Decodes a log of the contract. `block_logs` holds every log of the same block, for events
+    /// that can only be interpreted together with the other logs of their transaction.
+    fn decode(&self, log: &Log, block_logs: &[Log]) -> Vec<LegacyTransfer>;
+}
+
+/// A contract whose logs are decoded by a custom decoder and which is tracked as ERC721.
+pub(crate) struct LegacyContract {
+    pub name: &'static str,
+    pub decoder: Box<dyn LegacyDecoder>,
+}
+
+/// The legacy contract table. Supporting another contract means implementing `LegacyDecoder` for
+/// it and adding a row here.
+fn legacy_contracts() -> &'static HashMap<Address, LegacyContract> {
+    static LEGACY_CONTRACTS: OnceLock<HashMap<Address, LegacyContract>> = OnceLock::new();
+
+    LEGACY_CONTRACTS.get_or_init(|| {
+        let contracts: [(&str, LegacyContract); 2] = [
+            (
+                "0xb47e3cd837ddf8e4c57f05d70ab865de6e193bbb",
+                LegacyContract {
+                    name: "CryptoPunks",
+                    decoder: Box::new(CryptoPunks::new()),
+                },
+            ),
+            (
+                "0x06012c8cf97bead5deae237070f9587f8e7a266d",
+                LegacyContract {
+                    name: "CryptoKitties",
+                    decoder: Box::new(CryptoKitties::new()),
+                },
+            ),
+        ];
+
+        contracts
+            .into_iter()
+            .map(|(address, contract)| (Address::from_str(address).unwrap(), contract))
+            .collect()
+    })
+}
+
+pub(crate) fn find(address: Address) -> Option<&'static LegacyContract> {
+    legacy_contracts().get(&address)
+}
+
+/// Topics of every legacy contract, added to the logs filter.
+pub(crate) fn topics() -> Vec<H256> {
+    let mut topics: Vec<H256> = legacy_contracts()
+        .values()
+        .flat_map(|contract| contract.decoder.topics())
+        .collect();
+    topics.sort();
+    topics.dedup();
+    topics
+}
+
+fn topic(signature: &str) -> H256 {
+    H256::from(keccak256(signature.as_bytes()))
+}
+
+fn decode_uint(data: &[u8]) -> Option<U256> {
+    match decode(&[ParamType::Uint(256)], data).ok()?.first()? {
+        Token::Uint(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// CryptoPunks moves punks with `PunkTransfer` and `PunkBought`, assigns them with `Assign`, and
+/// also emits an ERC20 style `Transfer` of value 1 without the punk index, which is ignored.
+///
+/// `PunkBought` events emitted when a bid is accepted carry the zero address as buyer, so the
+/// buyer is taken from the `Transfer` the contract emitted in the same transaction.
+struct CryptoPunks {
+    assign: H256,
+    punk_transfer: H256,
+    punk_bought: H256,
+    transfer: H256,
+}
+
+impl CryptoPunks {
+    fn new() -> Self {
+        Self {
+            assign: topic("Assign(address,uint256)"),
+            punk_transfer: topic("PunkTransfer(address,address,uint256)"),
+            punk_bought: topic("PunkBought(uint256,uint256,address,address)"),
+            transfer: topic("Transfer(address,address,uint256)"),
+        }
+    }
+}
+
+impl LegacyDecoder for CryptoPunks {
+    fn topics(&self) -> Vec<H256> {
+        vec![self.assign, self.punk_transfer, self.punk_bought]
+    }
+
+    fn decode(&self, log: &Log, block_logs: &[Log]) -> Vec<LegacyTransfer> {
+        let transfer = if log.topics[0] == self.assign && log.topics.len() == 2 {
+            decode_uint(&log.data.0).map(|token_id| LegacyTransfer {
+                token_id,
+                from: Address::default(),
+                to: Address::from(log.topics[1]),
+            })
+        } else if log.topics[0] == self.punk_transfer && log.topics.len() == 3 {
+            decode_uint(&log.data.0).map(|token_id| LegacyTransfer {
+                token_id,
+                from: Address::from(log.topics[1]),
+                to: Address::from(log.topics[2]),
+            })
+        } else if log.topics[0] == self.punk_bought && log.topics.len() == 4 {
+            let from = Address::from(log.topics[2]);
+            let mut to = Address::from(log.topics[3]);
+
+            if to == Address::default() {
+                if let Some(transfer) = block_logs.iter().find(|other| {
+                    other.transaction_hash == log.transaction_hash
+                        && other.address == log.address
+                        && other.topics.len() == 3
+                        && other.topics[0] == self.transfer
+                        && Address::from(other.topics[1]) == from
+                }) {
+                    to = Address::from(transfer.topics[2]);
+                }
+            }
+
+            Some(LegacyTransfer {
+                token_id: U256::from_big_endian(log.topics[1].as_bytes()),
+                from,
+                to,
+            })
+        } else {
+            None
+        };
+
+        transfer.into_iter().collect()
+    }
+}
+
+/// CryptoKitties emits `Transfer(address,address,uint256)` with every parameter unindexed, births
+/// included as transfers from the zero address.
+struct CryptoKitties {
+    transfer: H256,
+}
+
+impl CryptoKitties {
+    fn new() -> Self {
+        Self {
+            transfer: topic("Transfer(address,address,uint256)"),
+        }
+    }
+}
+
+impl LegacyDecoder for CryptoKitties {
+    fn topics(&self) -> Vec<H256> {
+        Vec::new()
+    }
+
+    fn decode(&self, log: &Log, _block_logs: &[Log]) -> Vec<LegacyTransfer> {
+        if log.topics.len() != 1 || log.topics[0] != self.transfer {
+            return Vec::new();
+        }
+
+        match decode(
+            &[ParamType::Address, ParamType::Address, ParamType::Uint(256)],
+            &log.data.0,
+        )
+        .as_deref()
+        {
+            Ok([Token::Address(from), Token::Address(to), Token::Uint(token_id)]) => {
+                vec![LegacyTransfer {
+                    token_id: *token_id,
+                    from: *from,
+                    to: *to,
+                }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
@@ -1,49 +1,575 @@
+mod acquisitions;
+mod amount;
+#[cfg(feature = "api")]
+mod anomalies;
+#[cfg(feature = "api")]
+mod api;
+mod approvals;
+mod auth;
+mod backoff;
+#[cfg(feature = "backups")]
+mod backups;
+mod cache;
+mod canary;
+mod capabilities;
+mod changes;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+mod checksum;
+#[cfg(feature = "clickhouse")]
+mod clickhouse;
+mod concern;
+mod control;
+mod coverage;
+mod decimals;
+mod discovery;
+mod estimate;
+#[cfg(feature = "export")]
+mod export;
+mod feed;
+mod filter;
+#[cfg(feature = "api")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod head;
+mod instrumentation;
+#[cfg(feature = "ipfs")]
+mod ipfs;
+mod jobs;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod leaderboard;
+mod legacy;
+mod logs_worker;
+mod memory;
+mod metadata;
+mod migrations;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "api")]
+mod openapi;
+mod ownership;
+#[cfg(feature = "export")]
+mod parquet;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod privacy;
+mod processor;
+mod pruning;
+mod quorum;
+mod reader;
+mod reconciliation;
+mod reorg;
+mod schema;
+#[cfg(feature = "api")]
+mod schema_export;
+#[cfg(feature = "simulation")]
+mod simulation;
+mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod standalone;
+mod status;
+mod storage;
+pub mod store;
+#[cfg(feature = "api")]
+mod verification;
+mod webhooks;
+
+pub use acquisitions::{write_acquisitions_csv, AcquisitionKind, AcquisitionRecord};
+pub use amount::Amount;
+#[cfg(feature = "api")]
+pub use api::openapi_document;
+pub use approvals::ApprovalChange;
+#[cfg(feature = "api")]
+pub use auth::ApiKey;
+pub use auth::Role;
+pub use backoff::RetryPolicy;
+#[cfg(feature = "backups")]
+pub use backups::{
+    Backup, BackupDestination, BackupFormat, CronSchedule, ObjectStorage, StorageProvider,
+};
+pub use canary::SelfTestReport;
+pub use changes::{OwnershipChange, OwnershipChangeKind, OwnershipChanges};
+#[cfg(feature = "clickhouse")]
+pub use clickhouse::ClickHouseSink;
+pub use concern::{parse_read_concern, parse_read_preference, parse_write_concern};
+pub use coverage::{BlockRange, CoverageReport, ReorgRange, RestrictedRange};
+pub use decimals::parse_decimals_override;
+pub use discovery::{ContractDiscovered, ContractDiscoveryHook};
+pub use estimate::{CapacityEstimate, CollectionEstimate, DEFAULT_ESTIMATE_SAMPLES};
+#[cfg(feature = "export")]
+pub use export::{write_holders, ExportFormat, HolderColumn, DEFAULT_HOLDER_COLUMNS};
+pub use feed::DeltaFeed;
+pub use filter::{
+    read_address_file, read_token_id_rules, ContractAllowlist, ContractFilter, TokenIdRule,
+    TokenIdRuleKind,
+};
+#[cfg(feature = "ipfs")]
+pub use ipfs::DEFAULT_IPFS_GATEWAY;
+pub use jobs::default_worker_id;
+#[cfg(feature = "kafka")]
+pub use kafka::{KafkaFormat, KafkaProducer, KAFKA_AVRO_SCHEMA};
+pub use logs_worker::DeadLetterRetry;
+pub use memory::{MemoryStore, OwnershipKey, SupplyKey};
+pub use metadata::substitute_token_id;
+pub use migrations::SCHEMA_VERSION;
+#[cfg(feature = "nats")]
+pub use nats::NatsPublisher;
+pub use ownership::{OwnershipDelta, SelfTransferPolicy, ZeroBalancePolicy};
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStore;
+pub use privacy::{OwnerProtection, OwnerProtectionMode};
+pub use reader::OwnershipReader;
+pub use reconciliation::{ContractVerification, OwnershipMismatch};
+pub use schema::{FieldCase, OwnershipSchema};
+#[cfg(feature = "api")]
+pub use schema_export::{json_schema, protobuf_definitions};
+#[cfg(feature = "simulation")]
+pub use simulation::SimulationMode;
+pub use snapshot::{OwnerHolding, SnapshotHolding, SnapshotSource};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use standalone::StandaloneWorker;
+pub use status::SyncStatus;
+pub use storage::{JournaledBlock, OwnershipStore, StorageResult};
+#[cfg(feature = "api")]
+pub use verification::{AssertionSigner, AttestationSigner};
+pub use webhooks::{WebhookCondition, WebhookMetric, WebhookRule};
+
+#[cfg(feature = "api")]
+use api::ApiState;
+use backoff::Backoff;
+use cache::ClassificationCache;
+use capabilities::ProviderCapabilities;
+use control::WorkerControl;
+use decimals::DecimalsRegistry;
+use futures::future::try_join_all;
+use instrumentation::{Instrumentation, InstrumentedTransport};
+use logs_worker::LogsWorker;
+use metadata::MetadataWorker;
 use mongodb::{
     bson::doc,
-    options::{ClientOptions, UpdateOptions},
+    event::command::CommandEventHandler,
+    options::{
+        ClientOptions, DatabaseOptions, ReadConcern, ReadPreference, SelectionCriteria,
+        WriteConcern,
+    },
     Client, Database,
 };
-use num_traits::cast::ToPrimitive;
-use serde::{Deserialize, Serialize};
+use processor::Signatures;
+use quorum::Quorum;
 use std::{
-    error, panic,
+    error,
     sync::{Arc, Mutex},
     time::Duration,
 };
-use tokio::{task, time::sleep, try_join};
+#[cfg(feature = "api")]
+use store::StoredApiKey;
+use store::{ContractAddress, OperatorAction, RawLog, Store};
+use tokio::{
+    select,
+    sync::{Notify, RwLock},
+    task,
+};
 use web3::{
-    contract::{Contract, Options},
-    ethabi::{decode, param_type::ParamType, Token},
-    signing::keccak256,
     transports::Http,
-    types::{Address, BlockNumber, FilterBuilder, Log, H160, H256, U64},
+    types::{H160, U64},
     Web3,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ContractAddress {
-    address: H160,
-    token_type: String,
+/// Transport reaching the providers, timed for slow call logging.
+type HttpTransport = instrumentation::InstrumentedTransport<Http>;
+
+/// Transport reaching the providers, or answering from a recording with the `simulation` feature.
+#[cfg(not(feature = "simulation"))]
+type ProviderTransport = HttpTransport;
+#[cfg(feature = "simulation")]
+type ProviderTransport = simulation::SimulationTransport<HttpTransport>;
+
+/// Transport of the RPC calls made by the worker.
+#[cfg(not(feature = "chaos"))]
+type RpcTransport = ProviderTransport;
+#[cfg(feature = "chaos")]
+type RpcTransport = chaos::ChaosTransport<ProviderTransport>;
+
+/// Recording shared by the transports of every provider.
+#[cfg(not(feature = "simulation"))]
+type SharedSimulation = ();
+#[cfg(feature = "simulation")]
+type SharedSimulation = Option<Arc<simulation::Simulation>>;
+
+/// Block the logs worker starts from when no checkpoint has been stored yet.
+const START_BLOCK: u64 = 14282071;
+
+/// Address of the WETH contract on mainnet.
+pub const MAINNET_WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+
+/// Address of the OpenSea Shared Storefront, a shared ERC1155 contract, on mainnet.
+pub const MAINNET_OPENSEA_SHARED_STOREFRONT: &str = "0x495f947276749ce646f68ac8c248420045cb7b5e";
+
+/// Tunables of the worker that are not connection settings.
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Number of processed blocks whose hashes and deltas are journaled for reorg rollbacks.
+    pub reorg_depth: u64,
+    /// Confirmation depth beyond which a reorg raises an alarm, since blocks downstream services
+    /// considered final were rolled back. No alarm is raised when unset.
+    pub reorg_alarm_depth: Option<u64>,
+    /// Pauses the logs worker after a reorg raising an alarm, until it is resumed.
+    pub pause_on_reorg_alarm: bool,
+    /// Maximum number of blocks requested by a single `eth_getLogs` call while catching up on
+    /// blocks deeper than the reorg depth.
+    pub log_range_size: u64,
+    /// Number of log ranges fetched and decoded concurrently while catching up. Their deltas are
+    /// still applied in block order.
+    pub backfill_concurrency: usize,
+    /// WebSocket endpoint used to follow new heads and logs through subscriptions instead of
+    /// polling.
+    pub ws_endpoint: Option<String>,
+    /// Port of the HTTP API, which is not started when unset.
+    #[cfg(feature = "api")]
+    pub api_port: Option<u16>,
+    /// Keys accepted by the control API, each granting a role, along with the keys minted with
+    /// `create_api_key`.
+    #[cfg(feature = "api")]
+    pub api_keys: Vec<ApiKey>,
+    /// Whether the endpoints outside the control API, but the health checks, also need an API key
+    /// of any role.
+    #[cfg(feature = "api")]
+    pub require_api_key: bool,
+    /// Requests per second allowed to each API key without a rate limit of its own, unlimited
+    /// when `None`.
+    #[cfg(feature = "api")]
+    pub api_rate_limit: Option<f64>,
+    /// Requests an API key may make at once before its rate limit applies.
+    #[cfg(feature = "api")]
+    pub api_rate_burst: u32,
+    /// Signs the answers of the ownership verification endpoint, which is disabled when unset.
+    #[cfg(feature = "api")]
+    pub assertion_signer: Option<AssertionSigner>,
+    /// Signs EIP-191 attestations of the ownership verification endpoint's answers.
+    #[cfg(feature = "api")]
+    pub attestation_signer: Option<AttestationSigner>,
+    /// Blocks the checkpoint may lag behind the chain head before `/readyz` reports the worker as
+    /// not ready.
+    #[cfg(feature = "api")]
+    pub max_ready_lag: u64,
+    /// Number of blocks kept in the delta feed used by differential sync, which is disabled when
+    /// unset.
+    pub delta_feed_retention: Option<u64>,
+    /// Maximum number of ownership deltas kept in memory while MongoDB rejects writes. Block
+    /// processing pauses once the buffer is full.
+    pub write_buffer_size: usize,
+    /// Number of tasks the deltas of a block are written by, each writing the deltas of the
+    /// contracts whose address hashes to it, in order. Contracts share no document, so their
+    /// writes run concurrently.
+    pub writer_tasks: usize,
+    /// Second JSON RPC endpoint every fetched log is checked against. Logs are only applied when
+    /// both providers return the same ones.
+    pub quorum_rpc_endpoint: Option<String>,
+    /// Whether the worker only indexes blocks within the reorg depth of the head, handing older
+    /// blocks off to block job workers so the head is never delayed by a backlog.
+    pub priority_head: bool,
+    /// Whether the worker takes over from the one indexing the database, which hands the
+    /// checkpoint over at a block boundary, and only then migrates the data model. Without it, the
+    /// worker waits for the checkpoint lease of another worker to expire.
+    pub handoff: bool,
+    /// Number of blocks per job handed off when `priority_head` is set.
+    pub block_job_size: u64,
+    /// Wrapped token contracts whose `Deposit` and `Withdrawal` events are applied as mints and
+    /// burns.
+    pub wrapped_tokens: Vec<H160>,
+    /// Shared ERC1155 contracts whose token ids encode their creator, which is recorded on their
+    /// ownerships.
+    pub shared_contracts: Vec<H160>,
+    /// Whether `Approval` and `ApprovalForAll` events are indexed into the `approvals` collection.
+    pub index_approvals: bool,
+    /// Whether every decoded transfer is appended to the `transfers` collection.
+    pub transfer_history: bool,
+    /// Whether every log matching the contract filter is archived as the provider returned it in
+    /// the `raw_logs` collection.
+    pub raw_log_archive: bool,
+    /// Record the balance every block leaves each owner it changed in the `balance_history`
+    /// collection.
+    pub balance_history: bool,
+    /// Number of logs a contract may emit in a block before its balances are reconciled instead
+    /// of following its events, no cap when `None`.
+    pub event_volume_cap: Option<u64>,
+    /// Number of blocks between reconciliations of the balances of volume capped contracts.
+    pub reconciliation_interval: u64,
+    /// Whether the metadata of classified contracts and minted NFTs is fetched into the
+    /// `token_metadata` collection.
+    pub token_metadata: bool,
+    /// Whether the JSON documents the URIs of minted NFTs point to are downloaded along with their
+    /// metadata.
+    #[cfg(feature = "ipfs")]
+    pub resolve_token_uris: bool,
+    /// Gateways IPFS token URIs are fetched from, in order of preference.
+    #[cfg(feature = "ipfs")]
+    pub ipfs_gateways: Vec<String>,
+    /// Size in bytes above which token URI documents are not downloaded.
+    #[cfg(feature = "ipfs")]
+    pub metadata_document_size_limit: usize,
+    /// Endpoint of an IPFS Pinning Service API the content of resolved token URIs and images is
+    /// pinned with, nothing is pinned when `None`.
+    #[cfg(feature = "ipfs")]
+    pub pinning_service: Option<String>,
+    /// Access token of the pinning service.
+    #[cfg(feature = "ipfs")]
+    pub pinning_token: Option<String>,
+    /// Contracts whose logs are indexed.
+    pub contract_filter: ContractFilter,
+    /// Protection applied to owner addresses before ownership deltas are stored.
+    pub owner_protection: OwnerProtection,
+    /// Collection and field names the ownership model is stored under.
+    pub ownership_schema: OwnershipSchema,
+    /// Prefix of the name of every collection, so workers indexing other chains or environments
+    /// can share a database.
+    pub collection_prefix: String,
+    /// What becomes of ERC20 and ERC1155 ownerships whose balance drops to zero.
+    pub zero_balance_policy: ZeroBalancePolicy,
+    /// Period of the cleanup pruning ownerships left with a zero balance. They are pruned as soon
+    /// as their balance drops to zero when unset.
+    pub zero_balance_cleanup_interval: Option<Duration>,
+    /// Largest float quantity counted as a zero balance in compatibility mode, whose quantities
+    /// can keep rounding residue.
+    pub zero_balance_tolerance: f64,
+    /// Period of the refresh materializing the largest holders of every contract into
+    /// `top_holders`, disabled when unset.
+    pub top_holders_refresh_interval: Option<Duration>,
+    /// Number of holders materialized per contract, the largest `limit` of
+    /// `/contracts/{contract}/top-holders`.
+    pub top_holders_size: i64,
+    /// Databases the ownership model is mirrored to, next to MongoDB.
+    pub ownership_stores: Vec<Arc<dyn OwnershipStore>>,
+    /// Called with every contract the worker classifies for the first time.
+    pub contract_discovery_hooks: Vec<Arc<dyn ContractDiscoveryHook>>,
+    /// URL every contract the worker classifies for the first time is posted to.
+    #[cfg(feature = "webhooks")]
+    pub contract_discovery_webhook: Option<String>,
+    /// Number of contract classifications cached in memory, none when 0.
+    pub classification_cache_size: usize,
+    /// Time a cached contract classification is used before it is read from MongoDB again.
+    pub classification_cache_ttl: Duration,
+    /// Duration above which a MongoDB command or RPC call is logged and listed by the status
+    /// endpoint as slow.
+    pub slow_operation_threshold: Duration,
+    /// Delays between the retries of the MongoDB operations of the worker loops, which wait out
+    /// failovers and network errors with exponential backoff instead of stopping.
+    pub storage_retry: RetryPolicy,
+    /// Probe the RPC provider on startup, fetching logs in ranges it accepts and blocks up to its
+    /// finalized block in ranges.
+    pub detect_provider_capabilities: bool,
+    /// Apply the ownership changes and checkpoint of each block in one transaction when MongoDB is
+    /// a replica set or sharded cluster, so a crash never leaves a block half applied.
+    pub block_transactions: bool,
+    /// Migrate the data model of a database written by an older worker on startup, instead of
+    /// refusing to start.
+    pub schema_migrations: bool,
+    /// Decimals amounts of a contract are rendered with by the API and exports, instead of the
+    /// `decimals()` cached for it.
+    pub decimals_overrides: Vec<(H160, u32)>,
+    /// Acknowledgment the live worker waits for on its writes, the connection string's by
+    /// default.
+    pub write_concern: Option<WriteConcern>,
+    /// Acknowledgment backfills, block jobs and dead letter retries wait for on their writes,
+    /// `write_concern` by default. Work that can be run again can trade durability for
+    /// throughput with a looser one.
+    pub backfill_write_concern: Option<WriteConcern>,
+    /// Read concern of every read, the connection string's by default.
+    pub read_concern: Option<ReadConcern>,
+    /// Members the HTTP API and reports read from, the connection string's by default. Writers
+    /// always read the primary, since they add to the amounts they read.
+    pub read_preference: Option<ReadPreference>,
+    /// Bounds of the connection pool to each MongoDB server, the connection string's by default.
+    pub max_pool_size: Option<u32>,
+    pub min_pool_size: Option<u32>,
+    /// Redis server contract classifications are cached in instead of memory, shared by every
+    /// process connected to it.
+    #[cfg(feature = "redis")]
+    pub redis_url: Option<String>,
+    /// Bucket backups of the ownership model are uploaded to, none are taken when `None`.
+    #[cfg(feature = "backups")]
+    pub backup_storage: Option<ObjectStorage>,
+    /// Times the live worker backs up the ownership model at, only on demand when `None`.
+    #[cfg(feature = "backups")]
+    pub backup_schedule: Option<CronSchedule>,
+    /// Encoding of the backed up collections.
+    #[cfg(feature = "backups")]
+    pub backup_format: BackupFormat,
+    /// ClickHouse table the transfers of final blocks are exported to, which needs
+    /// `transfer_history`.
+    #[cfg(feature = "clickhouse")]
+    pub clickhouse: Option<ClickHouseSink>,
+    /// Kafka topic the delta feed is published to, with the transfers of each block when
+    /// `transfer_history` is set. Needs `delta_feed_retention`.
+    #[cfg(feature = "kafka")]
+    pub kafka: Option<KafkaProducer>,
+    /// JetStream the deltas of every log are published to as blocks are written and rolled back,
+    /// holding back block processing while NATS is unavailable.
+    #[cfg(feature = "nats")]
+    pub nats: Option<NatsPublisher>,
+    /// Port of the gRPC service, which is not started when unset.
+    #[cfg(feature = "grpc")]
+    pub grpc_port: Option<u16>,
+    /// Faults injected into RPC calls, MongoDB and ownership store operations, fetched logs and
+    /// processed blocks.
+    #[cfg(feature = "chaos")]
+    pub chaos: chaos::ChaosConfig,
+    /// Recording the RPC traffic is written to or replayed from.
+    #[cfg(feature = "simulation")]
+    pub simulation: Option<SimulationMode>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TokenOwnership {
-    contract_address: H160,
-    token_id: String,
-    owner: H160,
-    quantity: f64,
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            reorg_depth: 64,
+            reorg_alarm_depth: None,
+            pause_on_reorg_alarm: false,
+            log_range_size: 2000,
+            backfill_concurrency: 1,
+            ws_endpoint: None,
+            #[cfg(feature = "api")]
+            api_port: None,
+            #[cfg(feature = "api")]
+            api_keys: Vec::new(),
+            #[cfg(feature = "api")]
+            require_api_key: false,
+            #[cfg(feature = "api")]
+            api_rate_limit: None,
+            #[cfg(feature = "api")]
+            api_rate_burst: 20,
+            #[cfg(feature = "api")]
+            assertion_signer: None,
+            #[cfg(feature = "api")]
+            attestation_signer: None,
+            #[cfg(feature = "api")]
+            max_ready_lag: 50,
+            delta_feed_retention: None,
+            write_buffer_size: 100000,
+            writer_tasks: 1,
+            quorum_rpc_endpoint: None,
+            priority_head: false,
+            handoff: false,
+            block_job_size: 10000,
+            wrapped_tokens: Vec::new(),
+            shared_contracts: Vec::new(),
+            index_approvals: false,
+            transfer_history: false,
+            raw_log_archive: false,
+            balance_history: false,
+            event_volume_cap: None,
+            reconciliation_interval: 100,
+            token_metadata: false,
+            #[cfg(feature = "ipfs")]
+            resolve_token_uris: false,
+            #[cfg(feature = "ipfs")]
+            ipfs_gateways: vec![DEFAULT_IPFS_GATEWAY.to_string()],
+            #[cfg(feature = "ipfs")]
+            metadata_document_size_limit: 1048576,
+            #[cfg(feature = "ipfs")]
+            pinning_service: None,
+            #[cfg(feature = "ipfs")]
+            pinning_token: None,
+            contract_filter: ContractFilter::default(),
+            owner_protection: OwnerProtection::default(),
+            ownership_schema: OwnershipSchema::default(),
+            collection_prefix: String::new(),
+            zero_balance_policy: ZeroBalancePolicy::Keep,
+            zero_balance_cleanup_interval: None,
+            zero_balance_tolerance: 0.0,
+            top_holders_refresh_interval: None,
+            top_holders_size: 100,
+            ownership_stores: Vec::new(),
+            contract_discovery_hooks: Vec::new(),
+            #[cfg(feature = "webhooks")]
+            contract_discovery_webhook: None,
+            classification_cache_size: 10000,
+            classification_cache_ttl: Duration::from_secs(60),
+            slow_operation_threshold: Duration::from_secs(1),
+            storage_retry: RetryPolicy::default(),
+            detect_provider_capabilities: true,
+            block_transactions: true,
+            schema_migrations: true,
+            decimals_overrides: Vec::new(),
+            write_concern: None,
+            backfill_write_concern: None,
+            read_concern: None,
+            read_preference: None,
+            max_pool_size: None,
+            min_pool_size: None,
+            #[cfg(feature = "redis")]
+            redis_url: None,
+            #[cfg(feature = "backups")]
+            backup_storage: None,
+            #[cfg(feature = "backups")]
+            backup_schedule: None,
+            #[cfg(feature = "backups")]
+            backup_format: BackupFormat::Bson,
+            #[cfg(feature = "clickhouse")]
+            clickhouse: None,
+            #[cfg(feature = "kafka")]
+            kafka: None,
+            #[cfg(feature = "nats")]
+            nats: None,
+            #[cfg(feature = "grpc")]
+            grpc_port: None,
+            #[cfg(feature = "chaos")]
+            chaos: chaos::ChaosConfig::default(),
+            #[cfg(feature = "simulation")]
+            simulation: None,
+        }
+    }
 }
 
-#[derive(Debug)]
-struct ERC1155DecodedData {
-    token_id: String,
-    quantity: f64,
+impl WorkerConfig {
+    /// Store of `database` blocks are applied through, with the ownership model options and, with
+    /// `chaos`, the fault injection of this config.
+    pub(crate) fn store(
+        &self,
+        database: &Database,
+        classification_cache: Option<Arc<ClassificationCache>>,
+    ) -> Store {
+        let store = Store::new(database)
+            .with_collection_prefix(&self.collection_prefix)
+            .with_ownership_schema(self.ownership_schema.clone())
+            .with_shared_contracts(self.shared_contracts.clone())
+            .with_classification_cache(classification_cache)
+            .with_zero_balance_pruning(
+                self.zero_balance_policy,
+                self.zero_balance_cleanup_interval.is_none(),
+                self.zero_balance_tolerance,
+            )
+            .with_balance_history(self.balance_history);
+        #[cfg(feature = "chaos")]
+        let store = store.with_chaos(Arc::new(self.chaos.clone()));
+
+        store
+    }
 }
 
 #[derive(Debug)]
 pub struct Worker {
+    /// Client of `database`, which backups read snapshots of the ownership model with.
+    #[cfg(feature = "backups")]
+    client: Client,
     database: Database,
-    web3: Web3<Http>,
+    /// `database` with the write concern of backfills.
+    backfill_database: Database,
+    /// `database` with the read preference of the HTTP API and reports.
+    read_database: Database,
+    /// Client of `database` blocks are applied in transactions with, `None` when MongoDB does not
+    /// support them or they are disabled.
+    transaction_client: Option<Client>,
+    web3: Web3<RpcTransport>,
+    quorum: Option<Quorum>,
+    classification_cache: Option<Arc<ClassificationCache>>,
+    instrumentation: Arc<Instrumentation>,
+    capabilities: ProviderCapabilities,
+    config: WorkerConfig,
 }
 
 impl Worker {
@@ -51,9 +577,86 @@ impl Worker {
         database_host: String,
         database_name: String,
         ethereum_json_rpc_api_endpoint: String,
+        mut config: WorkerConfig,
     ) -> Result<Self, Box<dyn error::Error>> {
-        let database = get_database(database_host, database_name).await?;
-        let web3 = get_web3_http(ethereum_json_rpc_api_endpoint).await?;
+        let instrumentation = Arc::new(Instrumentation::new(config.slow_operation_threshold));
+
+        let (client, database) =
+            get_database(database_host, database_name, &config, &instrumentation).await?;
+
+        let backfill_database = client.database_with_options(
+            database.name(),
+            DatabaseOptions::builder()
+                .write_concern(config.backfill_write_concern.clone())
+                .build(),
+        );
+        let read_database = client.database_with_options(
+            database.name(),
+            DatabaseOptions::builder()
+                .selection_criteria(
+                    config
+                        .read_preference
+                        .clone()
+                        .map(SelectionCriteria::ReadPreference),
+                )
+                .build(),
+        );
+
+        #[cfg(not(feature = "simulation"))]
+        let simulation = ();
+        #[cfg(feature = "simulation")]
+        let simulation = match &config.simulation {
+            Some(mode) => Some(Arc::new(simulation::Simulation::open(mode)?)),
+            None => None,
+        };
+
+        let web3 = get_web3_http(
+            ethereum_json_rpc_api_endpoint.clone(),
+            "primary",
+            &config,
+            &instrumentation,
+            &simulation,
+        )
+        .await?;
+
+        // Probes would not match the responses of a recorded run.
+        #[cfg(feature = "simulation")]
+        let detect_provider_capabilities =
+            config.detect_provider_capabilities && config.simulation.is_none();
+        #[cfg(not(feature = "simulation"))]
+        let detect_provider_capabilities = config.detect_provider_capabilities;
+
+        let capabilities = if detect_provider_capabilities {
+            let capabilities = ProviderCapabilities::probe(
+                &web3,
+                &ethereum_json_rpc_api_endpoint,
+                config.log_range_size,
+            )
+            .await;
+            capabilities.log(config.log_range_size);
+
+            if let Some(max_log_range) = capabilities.max_log_range {
+                config.log_range_size = config.log_range_size.min(max_log_range);
+            }
+
+            capabilities
+        } else {
+            ProviderCapabilities::default()
+        };
+
+        let quorum = match &config.quorum_rpc_endpoint {
+            Some(endpoint) => Some(Quorum {
+                web3: get_web3_http(
+                    endpoint.clone(),
+                    "quorum",
+                    &config,
+                    &instrumentation,
+                    &simulation,
+                )
+                .await?,
+            }),
+            None => None,
+        };
 
         database
             .run_command(
@@ -64,7 +667,68 @@ impl Worker {
             )
             .await?;
 
-        Ok(Self { database, web3 })
+        let store = Store::new(&database)
+            .with_collection_prefix(&config.collection_prefix)
+            .with_ownership_schema(config.ownership_schema.clone());
+        store.ensure_indexes().await?;
+        if config.raw_log_archive {
+            store.create_raw_log_archive().await?;
+        }
+        // A worker taking over migrates once the worker it replaces stopped writing.
+        if config.handoff {
+            migrations::check(&store, config.schema_migrations).await
+        } else {
+            migrations::migrate(&store, config.schema_migrations).await
+        }
+        .map_err(|error| error as Box<dyn error::Error>)?;
+
+        let transaction_client = if !config.block_transactions {
+            println!("Applying blocks without transactions");
+            None
+        } else if supports_transactions(&database).await? {
+            println!("Applying each block in a transaction");
+            Some(client.clone())
+        } else {
+            println!("Applying blocks without transactions, MongoDB is not a replica set");
+            None
+        };
+
+        #[cfg(feature = "redis")]
+        let classification_cache = match &config.redis_url {
+            Some(url) => Some(
+                ClassificationCache::redis(
+                    url,
+                    database.name(),
+                    &config.collection_prefix,
+                    config.classification_cache_ttl,
+                )
+                .await?,
+            ),
+            None => ClassificationCache::memory(
+                config.classification_cache_size,
+                config.classification_cache_ttl,
+            ),
+        };
+        #[cfg(not(feature = "redis"))]
+        let classification_cache = ClassificationCache::memory(
+            config.classification_cache_size,
+            config.classification_cache_ttl,
+        );
+
+        Ok(Self {
+            #[cfg(feature = "backups")]
+            client,
+            database,
+            backfill_database,
+            read_database,
+            transaction_client,
+            web3,
+            quorum,
+            classification_cache: classification_cache.map(Arc::new),
+            instrumentation,
+            capabilities,
+            config,
+        })
     }
 
     pub async fn start(self) {
@@ -74,422 +738,969 @@ impl Worker {
 
         let logs_worker_web3 = self.web3.clone();
 
-        let latest_block_worker = task::spawn(async move {
-            loop {
-                *latest_block.lock().unwrap() = match self.web3.eth().block_number().await {
-                    Ok(value) => Some(value),
-                    Err(_) => {
-                        eprintln!("Error: Could not get the current block number, retrying...");
-                        continue;
-                    }
-                };
-                sleep(Duration::from_millis(60000)).await;
+        let store = self
+            .config
+            .store(&self.database, self.classification_cache.clone())
+            .with_transactions(self.transaction_client.clone());
+
+        let block_lock = Arc::new(RwLock::new(()));
+
+        let control = Arc::new(WorkerControl::default());
+
+        #[cfg(feature = "api")]
+        let api_store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone())
+            .with_classification_cache(self.classification_cache.clone());
+        #[cfg(all(feature = "api", feature = "chaos"))]
+        let api_store = api_store.with_chaos(Arc::new(self.config.chaos.clone()));
+
+        #[cfg(feature = "api")]
+        let api_state = ApiState {
+            decimals: DecimalsRegistry::new(&api_store, &self.config.decimals_overrides),
+            store: api_store,
+            block_lock: block_lock.clone(),
+            delta_feed_enabled: self.config.delta_feed_retention.is_some(),
+            latest_block: latest_block.clone(),
+            control: control.clone(),
+            api_keys: self.config.api_keys.clone().into(),
+            require_api_key: self.config.require_api_key,
+            api_rate_limit: self.config.api_rate_limit,
+            api_rate_burst: self.config.api_rate_burst,
+            rate_limiter: Default::default(),
+            owner_protection: self.config.owner_protection.clone(),
+            assertion_signer: self.config.assertion_signer.clone(),
+            attestation_signer: self.config.attestation_signer.clone(),
+            web3: self.web3.clone(),
+            max_ready_lag: self.config.max_ready_lag,
+            instrumentation: self.instrumentation.clone(),
+            provider_capabilities: self.capabilities.clone(),
+            top_holders_enabled: self.config.top_holders_refresh_interval.is_some(),
+            top_holders_size: self.config.top_holders_size,
+        };
+
+        let new_block = Arc::new(Notify::new());
+
+        let logs_worker_new_block = new_block.clone();
+
+        // Allowlists read from the database can change while running, so only a fixed one is
+        // worth narrowing the logs subscription to.
+        let subscription_addresses = match &self.config.contract_filter.allowlist {
+            Some(ContractAllowlist::Addresses(addresses)) if !addresses.is_empty() => {
+                Some(addresses.clone())
+            }
+            _ => None,
+        };
+
+        let decimals_worker = task::spawn(metadata::cache_missing_decimals(
+            self.web3.clone(),
+            store.clone(),
+            self.config.storage_retry,
+        ));
+
+        let pruning_interval = self
+            .config
+            .zero_balance_cleanup_interval
+            .filter(|_| self.config.zero_balance_policy != ZeroBalancePolicy::Keep);
+        let pruning_store = store.clone();
+        let pruning_worker = task::spawn(async move {
+            if let Some(interval) = pruning_interval {
+                pruning::prune_zero_balances(pruning_store, interval).await;
             }
         });
 
-        let logs_worker = task::spawn(async move {
-            let contract_address_collection = self
-                .database
-                .collection::<ContractAddress>("contract_addresses");
-            let token_ownership_collection = self
-                .database
-                .collection::<TokenOwnership>("token_ownerships");
-
-            let mut current_block = U64::from(14282071 as u64);
-
-            let erc_20_and_721_transfer_signature =
-                H256::from(keccak256("Transfer(address,address,uint256)".as_bytes()));
-            let erc_1155_transfer_single_signature = H256::from(keccak256(
-                "TransferSingle(address,address,address,uint256,uint256)".as_bytes(),
-            ));
-            let erc_1155_transfer_batch_signature = H256::from(keccak256(
-                "TransferBatch(address,address,address,uint256[],uint256[])".as_bytes(),
-            ));
-
-            loop {
-                let latest_block = *logs_worker_latest_block.lock().unwrap();
-
-                if let Some(latest_block) = latest_block {
-                    if current_block <= latest_block {
-                        println!(
-                            "Processing block {} of {} blocks",
-                            current_block, latest_block
-                        );
-
-                        let signatures_filter = vec![
-                            erc_20_and_721_transfer_signature,
-                            erc_1155_transfer_single_signature,
-                            erc_1155_transfer_batch_signature,
-                        ];
-
-                        let filter = FilterBuilder::default()
-                            .from_block(BlockNumber::Number(current_block))
-                            .to_block(BlockNumber::Number(current_block))
-                            .topics(Some(signatures_filter), None, None, None)
-                            .build();
-
-                        let logs: Vec<Log> = match logs_worker_web3.eth().logs(filter).await {
-                            Ok(logs) => logs,
-                            Err(error) => {
-                                eprintln!("Error: Could not get the logs, retrying... {}", error);
-                                continue;
-                            }
-                        };
-
-                        for log in logs {
-                            let contract = Contract::from_json(
-                                logs_worker_web3.eth(),
-                                log.address,
-                                include_bytes!("supports_interface_abi.json"),
-                            )
-                            .unwrap();
-
-                            let erc_721_interface_id: [u8; 4] =
-                                hex::decode("80ac58cd").unwrap()[0..4].try_into().unwrap();
-
-                            let erc_1155_interface_id: [u8; 4] =
-                                hex::decode("d9b67a26").unwrap()[0..4].try_into().unwrap();
-
-                            let mut token_type: Option<String> = None;
-
-                            match contract_address_collection
-                                .find_one(
-                                    doc! {
-                                        "address": format!("{:#x}", log.address),
-                                    },
-                                    None,
-                                )
-                                .await
-                            {
-                                Ok(contract_address) => {
-                                    if let Some(contract_address) = contract_address {
-                                        token_type = Some(contract_address.token_type)
-                                    }
-                                }
-                                Err(_) => {
-                                    panic!()
-                                }
-                            }
-
-                            if let None = token_type {
-                                token_type = if log.topics[0] == erc_20_and_721_transfer_signature
-                                    && log.topics.len() == 3
-                                {
-                                    Some("ERC20".to_string())
-                                } else if log.topics[0] == erc_20_and_721_transfer_signature
-                                    && log.topics.len() == 4
-                                {
-                                    let supports_interface: bool = match contract
-                                        .query(
-                                            "supportsInterface",
-                                            (erc_721_interface_id,),
-                                            None,
-                                            Options::default(),
-                                            None,
-                                        )
-                                        .await
-                                    {
-                                        Ok(value) => value,
-                                        Err(_) => {
-                                            continue;
-                                        }
-                                    };
-
-                                    if supports_interface {
-                                        Some("ERC721".to_string())
-                                    } else {
-                                        None
-                                    }
-                                } else if log.topics[0] == erc_1155_transfer_single_signature
-                                    || log.topics[0] == erc_1155_transfer_batch_signature
-                                {
-                                    let supports_interface: bool = match contract
-                                        .query(
-                                            "supportsInterface",
-                                            (erc_1155_interface_id,),
-                                            None,
-                                            Options::default(),
-                                            None,
-                                        )
-                                        .await
-                                    {
-                                        Ok(value) => value,
-                                        Err(_) => {
-                                            continue;
-                                        }
-                                    };
-
-                                    if supports_interface {
-                                        Some("ERC1155".to_string())
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    None
-                                };
-                            }
-
-                            if let Some(token_type) = &token_type {
-                                contract_address_collection
-                                    .update_one(
-                                        doc! {
-                                            "address": format!("{:#x}", log.address),
-                                        },
-                                        doc! {
-                                            "$set": {
-                                                "token_type": token_type,
-                                            }
-                                        },
-                                        UpdateOptions::builder().upsert(true).build(),
-                                    )
-                                    .await
-                                    .unwrap();
-
-                                if token_type == "ERC20" {
-                                    if Address::from(log.topics[1]) != Address::default() {
-                                        let decoded_quantity = match decode(
-                                            &vec![ParamType::Uint(256)],
-                                            &log.data.0,
-                                        ) {
-                                            Ok(decoded) => match decoded[0] {
-                                                Token::Uint(decoded_quantity) => decoded_quantity,
-                                                _ => panic!(),
-                                            },
-                                            Err(_) => panic!(),
-                                        };
-
-                                        let quantity = decoded_quantity.as_u128().to_f64().unwrap();
-
-                                        if quantity > 0.0 {
-                                            token_ownership_collection.update_one(
-                                                doc! {
-                                                    "contract_address": format!("{:#x}", log.address),
-                                                    "owner": format!("{:#x}", Address::from(log.topics[1])),
-                                                },
-                                                doc! {
-                                                    "$inc": {
-                                                        "quantity": -quantity
-                                                    }
-
-                                                },
-                                                UpdateOptions::builder().upsert(true).build(),
-                                            ).await.unwrap();
-
-                                            token_ownership_collection.update_one(
-                                                doc! {
-                                                    "contract_address": format!("{:#x}", log.address),
-                                                    "owner": format!("{:#x}", Address::from(log.topics[2])),
-                                                },
-                                                doc! {
-                                                    "$inc": {
-                                                        "quantity": quantity
-                                                    }
-
-                                                },
-                                                UpdateOptions::builder().upsert(true).build(),
-                                            ).await.unwrap();
-                                        }
-                                    }
-                                } else if token_type == "ERC721" {
-                                    let decoded_token_id = match decode(
-                                        &vec![ParamType::Uint(256)],
-                                        &log.topics[3].as_bytes(),
-                                    ) {
-                                        Ok(decoded) => match decoded[0] {
-                                            Token::Uint(token_id) => token_id,
-                                            _ => panic!(),
-                                        },
-                                        Err(_) => panic!(),
-                                    };
-
-                                    let token_id = decoded_token_id.to_string();
-
-                                    if Address::from(log.topics[1]) != Address::default()
-                                        && Address::from(log.topics[2]) != Address::default()
-                                    {
-                                        token_ownership_collection.delete_many(doc! {
-                                            "contract_address": format!("{:#x}", log.address),
-                                            "owner": format!("{:#x}", Address::from(log.topics[1])),
-                                            "token_id": &token_id
-                                        }, None).await.unwrap();
-
-                                        token_ownership_collection
-                                            .insert_one(
-                                                TokenOwnership {
-                                                    contract_address: log.address,
-                                                    token_id,
-                                                    owner: Address::from(log.topics[2]),
-                                                    quantity: 1.0,
-                                                },
-                                                None,
-                                            )
-                                            .await
-                                            .unwrap();
-                                    } else if Address::from(log.topics[2]) == Address::default() {
-                                        token_ownership_collection
-                                        .delete_many(
-                                            doc! {
-                                                "contract_address": format!("{:#x}", log.address),
-                                                "token_id": token_id,
-                                            },
-                                            None,
-                                        )
-                                        .await.unwrap();
-                                    }
-                                } else if token_type == "ERC1155" {
-                                    let mut transferred_tokens: Vec<ERC1155DecodedData> =
-                                        Vec::new();
-
-                                    if log.topics[0] == erc_1155_transfer_single_signature {
-                                        match decode(
-                                            &vec![ParamType::Uint(256), ParamType::Uint(256)],
-                                            &log.data.0,
-                                        ) {
-                                            Ok(decoded) => {
-                                                if let (
-                                                    Token::Uint(token_id),
-                                                    Token::Uint(quantity),
-                                                ) =
-                                                    (decoded[0].to_owned(), decoded[1].to_owned())
-                                                {
-                                                    transferred_tokens.push(ERC1155DecodedData {
-                                                        token_id: token_id.to_string(),
-                                                        quantity: quantity
-                                                            .as_u128()
-                                                            .to_f64()
-                                                            .unwrap(),
-                                                    })
-                                                }
-                                            }
-                                            Err(_) => panic!(),
-                                        };
-                                    } else if log.topics[0] == erc_1155_transfer_batch_signature {
-                                        match decode(
-                                            &vec![
-                                                ParamType::Array(Box::new(ParamType::Uint(256))),
-                                                ParamType::Array(Box::new(ParamType::Uint(256))),
-                                            ],
-                                            &log.data.0,
-                                        ) {
-                                            Ok(decoded) => {
-                                                if let (
-                                                    Token::Array(token_ids),
-                                                    Token::Array(quantities),
-                                                ) =
-                                                    (decoded[0].to_owned(), decoded[1].to_owned())
-                                                {
-                                                    for (index, _) in token_ids.iter().enumerate() {
-                                                        if let (
-                                                            Token::Uint(token_id),
-                                                            Token::Uint(quantity),
-                                                        ) = (
-                                                            token_ids[index].to_owned(),
-                                                            quantities[index].to_owned(),
-                                                        ) {
-                                                            transferred_tokens.push(
-                                                                ERC1155DecodedData {
-                                                                    token_id: token_id.to_string(),
-                                                                    quantity: quantity
-                                                                        .as_u128()
-                                                                        .to_f64()
-                                                                        .unwrap(),
-                                                                },
-                                                            )
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                            Err(_) => panic!(),
-                                        };
-
-                                        for transferred_token in transferred_tokens {
-                                            if Address::from(log.topics[2]) != Address::default()
-                                                && Address::from(log.topics[3])
-                                                    != Address::default()
-                                            {
-                                                if transferred_token.quantity > 0.0 {
-                                                    token_ownership_collection.update_one(
-                                                            doc! {
-                                                                "contract_address": format!("{:#x}", log.address),
-                                                                "owner": format!("{:#x}", Address::from(log.topics[2])),
-                                                                "token_id": &transferred_token.token_id,
-                                                            },
-                                                            doc! {
-                                                                "$inc": {
-                                                                    "quantity": -transferred_token.quantity
-                                                            }
-                                                        },
-                                                            UpdateOptions::builder().upsert(true).build(),
-                                                        ).await.unwrap();
-
-                                                    token_ownership_collection.update_one(
-                                                            doc! {
-                                                                "contract_address": format!("{:#x}", log.address),
-                                                                "owner": format!("{:#x}", Address::from(log.topics[3])),
-                                                                "token_id": transferred_token.token_id,
-                                                            },
-                                                            doc! {
-                                                                "$inc": {
-                                                                    "quantity": transferred_token.quantity
-                                                                }
-                                                            },
-                                                            UpdateOptions::builder().upsert(true).build(),
-                                                        ).await.unwrap();
-                                                }
-                                            } else if Address::from(log.topics[3])
-                                                == Address::default()
-                                                && Address::from(log.topics[2])
-                                                    != Address::default()
-                                            {
-                                                token_ownership_collection
-                                                        .delete_many(
-                                                        doc! {
-                                                    "contract_address": format!("{:#x}", log.address),
-                                                    "token_id": transferred_token.token_id,
-                                                             },
-                                                            None,
-                                                        )
-                                                        .await.unwrap();
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        current_block += U64::from(1 as u8);
-                    } else {
-                        println!("Waiting for new blocks");
-                        sleep(Duration::from_millis(5000)).await;
-                        continue;
+        let top_holders_refresh = self.config.top_holders_refresh_interval.map(|interval| {
+            leaderboard::refresh_top_holders(
+                store.clone(),
+                block_lock.clone(),
+                interval,
+                self.config.top_holders_size,
+                self.config.ownership_schema.is_compatibility(),
+            )
+        });
+        let top_holders_worker = task::spawn(async move {
+            if let Some(top_holders_refresh) = top_holders_refresh {
+                top_holders_refresh.await;
+            }
+        });
+
+        #[cfg(feature = "backups")]
+        let backups = self
+            .config
+            .backup_storage
+            .clone()
+            .zip(self.config.backup_schedule.clone());
+        #[cfg(feature = "backups")]
+        let (backup_store, backup_client) = (store.clone(), self.client.clone());
+        #[cfg(feature = "backups")]
+        let backup_format = self.config.backup_format;
+        #[cfg(feature = "backups")]
+        let backup_worker = task::spawn(async move {
+            if let Some((storage, schedule)) = backups {
+                backups::run_backups(
+                    backup_store,
+                    backup_client,
+                    storage,
+                    backup_format,
+                    schedule,
+                )
+                .await;
+            }
+        });
+        #[cfg(not(feature = "backups"))]
+        let backup_worker = task::spawn(async {});
+
+        #[cfg(feature = "clickhouse")]
+        let clickhouse = self.config.clickhouse.clone();
+        #[cfg(feature = "clickhouse")]
+        let (clickhouse_store, reorg_depth, storage_retry) = (
+            store.clone(),
+            self.config.reorg_depth,
+            self.config.storage_retry,
+        );
+        #[cfg(feature = "clickhouse")]
+        let clickhouse_worker = task::spawn(async move {
+            if let Some(sink) = clickhouse {
+                clickhouse::run_export(clickhouse_store, sink, reorg_depth, storage_retry).await;
+            }
+        });
+        #[cfg(not(feature = "clickhouse"))]
+        let clickhouse_worker = task::spawn(async {});
+
+        #[cfg(feature = "kafka")]
+        let kafka = self.config.kafka.clone();
+        #[cfg(feature = "kafka")]
+        let (kafka_store, transfer_history, storage_retry) = (
+            store.clone(),
+            self.config.transfer_history,
+            self.config.storage_retry,
+        );
+        #[cfg(feature = "kafka")]
+        let kafka_worker = task::spawn(async move {
+            if let Some(producer) = kafka {
+                kafka::run_export(kafka_store, producer, transfer_history, storage_retry).await;
+            }
+        });
+        #[cfg(not(feature = "kafka"))]
+        let kafka_worker = task::spawn(async {});
+
+        #[cfg(feature = "grpc")]
+        let grpc_port = self.config.grpc_port;
+        #[cfg(feature = "grpc")]
+        let grpc_store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+        #[cfg(feature = "grpc")]
+        let grpc_state = grpc::GrpcState {
+            decimals: DecimalsRegistry::new(&grpc_store, &self.config.decimals_overrides),
+            store: grpc_store,
+            block_lock: block_lock.clone(),
+            delta_feed_enabled: self.config.delta_feed_retention.is_some(),
+            owner_protection: self.config.owner_protection.clone(),
+        };
+        #[cfg(feature = "grpc")]
+        let grpc_worker = task::spawn(async move {
+            if let Some(grpc_port) = grpc_port {
+                if let Err(error) = grpc::serve(grpc_port, grpc_state).await {
+                    eprintln!("Error: gRPC service stopped... {}", error);
+                }
+            }
+        });
+        #[cfg(not(feature = "grpc"))]
+        let grpc_worker = task::spawn(async {});
+
+        let metadata_worker = self.config.token_metadata.then(|| MetadataWorker {
+            web3: self.web3.clone(),
+            store: store.clone(),
+            storage_retry: self.config.storage_retry,
+            #[cfg(feature = "ipfs")]
+            resolver: self.config.resolve_token_uris.then(|| {
+                ipfs::DocumentResolver::new(
+                    self.config.ipfs_gateways.clone(),
+                    self.config.metadata_document_size_limit,
+                )
+            }),
+            #[cfg(feature = "ipfs")]
+            pinning: self
+                .config
+                .resolve_token_uris
+                .then(|| self.config.pinning_service.clone())
+                .flatten()
+                .map(|endpoint| {
+                    ipfs::PinningService::new(endpoint, self.config.pinning_token.clone())
+                }),
+        });
+
+        let latest_block_worker = task::spawn(head::track_latest_block(
+            self.web3,
+            self.config.ws_endpoint.clone(),
+            Signatures::new().filter_topics(&self.config),
+            subscription_addresses,
+            latest_block,
+            new_block,
+        ));
+
+        let logs_worker = task::spawn(
+            LogsWorker {
+                web3: logs_worker_web3,
+                quorum: self.quorum,
+                store,
+                config: self.config.clone(),
+                latest_block: logs_worker_latest_block,
+                new_block: logs_worker_new_block,
+                block_lock,
+                control,
+                signatures: Signatures::new(),
+                pending_blocks: Default::default(),
+                pending_revert: Default::default(),
+                instrumentation: self.instrumentation.clone(),
+                finalized_tag: self.capabilities.finalized_tag,
+                backfill_contracts: Vec::new(),
+                #[cfg(feature = "webhooks")]
+                webhooks: Default::default(),
+            }
+            .run(),
+        );
+
+        #[cfg(feature = "api")]
+        let api_worker = task::spawn(async move {
+            if let Some(api_port) = self.config.api_port {
+                if let Err(error) = api::serve(api_port, api_state).await {
+                    eprintln!("Error: API stopped... {}", error);
+                }
+            }
+        });
+
+        let metadata_worker = task::spawn(async move {
+            if let Some(metadata_worker) = metadata_worker {
+                metadata_worker.run().await;
+            }
+        });
+
+        #[cfg(not(feature = "api"))]
+        let api_worker = task::spawn(async {});
+
+        let mut workers = [
+            latest_block_worker,
+            api_worker,
+            metadata_worker,
+            decimals_worker,
+            pruning_worker,
+            top_holders_worker,
+            backup_worker,
+            clickhouse_worker,
+            kafka_worker,
+            grpc_worker,
+        ];
+
+        // The logs worker only returns once it handed the checkpoint over to another worker, which
+        // stops this one.
+        let result = select! {
+            result = logs_worker => result,
+            result = try_join_all(workers.iter_mut()) => result.map(|_| ()),
+        };
+
+        for worker in &workers {
+            worker.abort();
+        }
+
+        if result.is_err() {
+            eprintln!("Fatal Error: Worker stopped unexpectedly");
+        }
+    }
+
+    /// Applies the blocks from `from_block` to `to_block` again without moving the checkpoint of
+    /// the live worker, restricted to `contracts` when any are given.
+    ///
+    /// Blocks already indexed for those contracts are counted twice, so this is meant for blocks
+    /// the live worker skipped or contracts it did not index.
+    pub async fn backfill(
+        self,
+        from_block: u64,
+        to_block: u64,
+        contracts: Vec<H160>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let store = self
+            .config
+            .store(&self.backfill_database, self.classification_cache.clone());
+
+        let logs_worker = self.backfill_logs_worker(store.clone(), &contracts);
+
+        let result = logs_worker
+            .backfill(U64::from(from_block), U64::from(to_block), false)
+            .await;
+
+        let mut action = format!("backfill {} to {}", from_block, to_block);
+        for contract in &contracts {
+            action.push_str(&format!(" {:#x}", contract));
+        }
+
+        record_cli_action(&store, action, &result).await?;
+
+        result
+    }
+
+    /// Decodes the logs recorded in `dead_letters` again, after a decoder fix, applying the ones
+    /// that decode now.
+    pub async fn retry_dead_letters(
+        self,
+    ) -> Result<DeadLetterRetry, Box<dyn error::Error + Send + Sync>> {
+        let store = self
+            .config
+            .store(&self.backfill_database, self.classification_cache.clone());
+
+        let logs_worker = self.backfill_logs_worker(store.clone(), &[]);
+
+        let result = logs_worker.retry_dead_letters().await;
+
+        record_cli_action(&store, "retry dead letters".to_string(), &result).await?;
+
+        result
+    }
+
+    /// Reverts the journaled blocks after `to_block` and moves the checkpoint back to it, so they
+    /// are indexed again. Returns the number of blocks reverted.
+    ///
+    /// Meant for incident response while the live worker is stopped. Only the blocks still in the
+    /// journal can be rolled back.
+    pub async fn rollback(self, to_block: u64) -> Result<u64, Box<dyn error::Error + Send + Sync>> {
+        let store = self
+            .config
+            .store(&self.database, self.classification_cache.clone());
+
+        let result = reorg::rollback_to(&store, &self.config, U64::from(to_block)).await;
+
+        record_cli_action(&store, format!("rollback to {}", to_block), &result).await?;
+
+        result
+    }
+
+    /// The logs archived for the blocks from `from_block` to `to_block` by `raw_log_archive`,
+    /// ordered by block and log index, for replaying their decoding.
+    pub async fn archived_logs(
+        self,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<RawLog>, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        Ok(store
+            .raw_logs(U64::from(from_block), U64::from(to_block))
+            .await?)
+    }
+
+    /// The holders of a contract after block `block_number`, as of the recorded transfer history,
+    /// for airdrops and governance snapshots.
+    pub async fn snapshot(
+        self,
+        contract_address: H160,
+        block_number: u64,
+        source: SnapshotSource,
+    ) -> Result<Vec<SnapshotHolding>, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        let decimals = DecimalsRegistry::new(&store, &self.config.decimals_overrides);
+
+        snapshot::holdings_at(
+            &store,
+            &decimals,
+            contract_address,
+            U64::from(block_number),
+            source,
+        )
+        .await
+    }
+
+    /// Backs up the ownership model to `backup_storage` now, from a snapshot of the database.
+    #[cfg(feature = "backups")]
+    pub async fn back_up(self) -> Result<Backup, Box<dyn error::Error + Send + Sync>> {
+        let storage = self
+            .config
+            .backup_storage
+            .clone()
+            .ok_or("No backup destination is configured")?;
+
+        let store = Store::new(&self.database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        backups::back_up(&store, &self.client, &storage, self.config.backup_format).await
+    }
+
+    /// The current holders of a contract, or its holders after block `block_number` when given, as
+    /// `snapshot` computes them, for exports.
+    pub async fn holders(
+        self,
+        contract_address: H160,
+        block_number: Option<u64>,
+        source: SnapshotSource,
+    ) -> Result<Vec<SnapshotHolding>, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+        let decimals = DecimalsRegistry::new(&store, &self.config.decimals_overrides);
+
+        match block_number {
+            Some(block_number) => {
+                snapshot::holdings_at(
+                    &store,
+                    &decimals,
+                    contract_address,
+                    U64::from(block_number),
+                    source,
+                )
+                .await
+            }
+            None => snapshot::current_holdings(&store, &decimals, contract_address).await,
+        }
+    }
+
+    /// The current holders of a contract holding at least `min_quantity`, largest first, up to
+    /// `limit` of them when set, with the last processed block they were read at.
+    pub async fn largest_holders(
+        self,
+        contract_address: H160,
+        min_quantity: f64,
+        limit: Option<i64>,
+    ) -> Result<(Option<u64>, Vec<SnapshotHolding>), Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+        let decimals = DecimalsRegistry::new(&store, &self.config.decimals_overrides);
+
+        let holdings =
+            snapshot::largest_holdings(&store, &decimals, contract_address, min_quantity, limit)
+                .await?;
+        let block_number = store.last_processed_block().await?;
+
+        Ok((
+            block_number.map(|block_number| block_number.as_u64()),
+            holdings,
+        ))
+    }
+
+    /// What `owner` currently holds, across every contract or of `contract_address` when set,
+    /// with the last processed block it was read at.
+    pub async fn owner_holdings(
+        self,
+        owner: H160,
+        contract_address: Option<H160>,
+    ) -> Result<(Option<u64>, Vec<OwnerHolding>), Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+        let decimals = DecimalsRegistry::new(&store, &self.config.decimals_overrides);
+
+        let holdings = snapshot::owner_holdings(
+            &store,
+            &decimals,
+            self.config.owner_protection.protect(owner),
+            contract_address,
+        )
+        .await?;
+        let block_number = store.last_processed_block().await?;
+
+        Ok((
+            block_number.map(|block_number| block_number.as_u64()),
+            holdings,
+        ))
+    }
+
+    /// The acquisitions and disposals of `owner` recorded in the transfer history, restricted to
+    /// `contracts` when any are given, in the blocks from `from_block` to `to_block` when set.
+    pub async fn acquisitions(
+        self,
+        owner: H160,
+        contracts: Vec<H160>,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+    ) -> Result<Vec<AcquisitionRecord>, Box<dyn error::Error + Send + Sync>> {
+        let store =
+            Store::new(&self.read_database).with_collection_prefix(&self.config.collection_prefix);
+
+        acquisitions::history(
+            &self.web3,
+            &store,
+            &self.config,
+            owner,
+            &contracts,
+            from_block,
+            to_block,
+        )
+        .await
+    }
+
+    /// Reports which blocks from `from_block` to `to_block`, the last processed block by default,
+    /// were processed, and the gaps and reorgs among them.
+    pub async fn coverage(
+        self,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+    ) -> Result<CoverageReport, Box<dyn error::Error + Send + Sync>> {
+        let store =
+            Store::new(&self.read_database).with_collection_prefix(&self.config.collection_prefix);
+
+        coverage::report(&store, from_block.unwrap_or(START_BLOCK), to_block).await
+    }
+
+    /// The last block the live worker applied and the latest block of the provider, the lag
+    /// between them in blocks and time, the blocks per second the live worker moved through over
+    /// the last `throughput_window` and the number of documents of every collection.
+    pub async fn status(
+        self,
+        throughput_window: Duration,
+    ) -> Result<SyncStatus, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        status::sync_status(&self.web3, &store, throughput_window).await
+    }
+
+    /// Compares the stored ownerships of a contract, the first `limit` of them when given, with
+    /// the balances the contract reports at the last processed block.
+    pub async fn verify(
+        self,
+        contract_address: H160,
+        limit: Option<usize>,
+    ) -> Result<ContractVerification, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        let block_number = store
+            .last_processed_block()
+            .await?
+            .ok_or("No block has been processed yet")?;
+
+        reconciliation::verify_contract(
+            &self.web3,
+            &store,
+            &self.config.owner_protection,
+            contract_address,
+            block_number,
+            limit,
+        )
+        .await
+    }
+
+    /// Registers the token type of a contract, overriding its classification, and the block before
+    /// which its logs are ignored. Workers in other processes pick it up once their cached
+    /// classification expires, at once when they share a Redis cache.
+    pub async fn register_contract(
+        self,
+        contract_address: H160,
+        token_type: String,
+        start_block: Option<u64>,
+    ) -> Result<ContractAddress, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_classification_cache(self.classification_cache.clone());
+
+        let result = processor::register_contract(
+            &self.web3,
+            &store,
+            contract_address,
+            &token_type,
+            start_block.map(U64::from),
+        )
+        .await;
+
+        let mut action = format!("register {:#x} as {}", contract_address, token_type);
+        if let Some(start_block) = start_block {
+            action.push_str(&format!(" from block {}", start_block));
+        }
+
+        record_cli_action(&store, action, &result).await?;
+
+        result
+    }
+
+    /// Mints an API key granting `role`, allowed `rate_limit` requests per second instead of the
+    /// `api_rate_limit`, and returns its secret, which is only stored hashed.
+    #[cfg(feature = "api")]
+    pub async fn create_api_key(
+        self,
+        name: String,
+        role: Role,
+        rate_limit: Option<f64>,
+    ) -> Result<String, Box<dyn error::Error + Send + Sync>> {
+        let store =
+            Store::new(&self.database).with_collection_prefix(&self.config.collection_prefix);
+
+        let result = async {
+            if rate_limit.is_some_and(|rate_limit| rate_limit <= 0.0) {
+                return Err("The rate limit has to be positive".into());
+            }
+            if store.api_key(&name).await?.is_some() {
+                return Err(format!("An API key named {} exists already", name).into());
+            }
+
+            let key = hex::encode(rand::random::<[u8; 32]>());
+            store
+                .insert_api_key(&StoredApiKey {
+                    name: name.clone(),
+                    role,
+                    key_hash: auth::hash_api_key(&key),
+                    rate_limit,
+                    created_at: mongodb::bson::DateTime::now(),
+                    revoked_at: None,
+                })
+                .await?;
+
+            Ok::<_, Box<dyn error::Error + Send + Sync>>(key)
+        }
+        .await;
+
+        record_cli_action(
+            &store,
+            format!("create API key {} ({:?})", name, role),
+            &result,
+        )
+        .await?;
+
+        result
+    }
+
+    /// Revokes an API key minted with `create_api_key`, which the API rejects from its next
+    /// request. Returns whether an unrevoked key of that name existed.
+    #[cfg(feature = "api")]
+    pub async fn revoke_api_key(
+        self,
+        name: String,
+    ) -> Result<bool, Box<dyn error::Error + Send + Sync>> {
+        let store =
+            Store::new(&self.database).with_collection_prefix(&self.config.collection_prefix);
+
+        let result = store
+            .revoke_api_key(&name)
+            .await
+            .map_err(|error| error.into());
+
+        record_cli_action(&store, format!("revoke API key {}", name), &result).await?;
+
+        result
+    }
+
+    /// Every API key minted with `create_api_key`, revoked ones included, ordered by name.
+    #[cfg(feature = "api")]
+    pub async fn api_keys(self) -> Result<Vec<StoredApiKey>, Box<dyn error::Error + Send + Sync>> {
+        let store =
+            Store::new(&self.read_database).with_collection_prefix(&self.config.collection_prefix);
+
+        Ok(store.api_keys().await?)
+    }
+
+    /// Drops everything stored about a contract but its classification and indexes its logs again
+    /// from `from_block`, the first block the worker indexes by default, up to the last processed
+    /// block. Returns that block.
+    ///
+    /// Meant for repairing a contract while the live worker is stopped. Ownership stores are not
+    /// reset.
+    pub async fn reset_contract(
+        self,
+        contract_address: H160,
+        from_block: Option<u64>,
+    ) -> Result<u64, Box<dyn error::Error + Send + Sync>> {
+        let store = self
+            .config
+            .store(&self.backfill_database, self.classification_cache.clone());
+
+        let logs_worker = self.backfill_logs_worker(store.clone(), &[contract_address]);
+
+        let result = async {
+            let to_block = store
+                .last_processed_block()
+                .await?
+                .ok_or("No block has been processed yet")?;
+
+            store.reset_contract(contract_address).await?;
+
+            logs_worker
+                .backfill(
+                    U64::from(from_block.unwrap_or(START_BLOCK)),
+                    to_block,
+                    false,
+                )
+                .await?;
+
+            Ok(to_block.as_u64())
+        }
+        .await;
+
+        record_cli_action(
+            &store,
+            format!("reset contract {:#x}", contract_address),
+            &result,
+        )
+        .await?;
+
+        result
+    }
+
+    /// Deploys canary ERC20, ERC721 and ERC1155 contracts on a development or test network, emits
+    /// scripted transfers through them and waits up to `timeout` for the worker running against
+    /// this database to process them, then checks the classifications and balances it stored.
+    pub async fn self_test(
+        self,
+        timeout: Duration,
+    ) -> Result<SelfTestReport, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        canary::self_test(&self.web3, &store, &self.config, timeout).await
+    }
+
+    /// Projects the documents, storage and time indexing the blocks from `from_block` to
+    /// `to_block` takes, restricted to `contracts` when any are given, from `samples` ranges of
+    /// `sample_size` blocks, `log_range_size` by default. Nothing is written to the database.
+    pub async fn estimate(
+        self,
+        from_block: u64,
+        to_block: u64,
+        contracts: Vec<H160>,
+        samples: u64,
+        sample_size: Option<u64>,
+    ) -> Result<CapacityEstimate, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.read_database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        let mut logs_worker = self.backfill_logs_worker(store, &contracts);
+        // Quorum checks record mismatches, and samples leave the database untouched.
+        logs_worker.quorum = None;
+
+        estimate::estimate(&logs_worker, from_block, to_block, samples, sample_size).await
+    }
+
+    /// Splits the blocks from `from_block` to `to_block` into jobs of `job_size` blocks, restricted
+    /// to `contracts` when any are given, for worker processes started with `work_block_jobs` to
+    /// claim. Returns the number of jobs enqueued.
+    pub async fn enqueue_block_jobs(
+        self,
+        from_block: u64,
+        to_block: u64,
+        job_size: u64,
+        contracts: Vec<H160>,
+    ) -> Result<usize, Box<dyn error::Error + Send + Sync>> {
+        let store = Store::new(&self.database)
+            .with_collection_prefix(&self.config.collection_prefix)
+            .with_ownership_schema(self.config.ownership_schema.clone());
+
+        let jobs = jobs::split(from_block, to_block, job_size, &contracts);
+        let result = store
+            .insert_block_jobs(&jobs)
+            .await
+            .map(|()| jobs.len())
+            .map_err(Into::into);
+
+        let mut action = format!(
+            "enqueue block jobs {} to {} of {} blocks",
+            from_block, to_block, job_size
+        );
+        for contract in &contracts {
+            action.push_str(&format!(" {:#x}", contract));
+        }
+
+        record_cli_action(&store, action, &result).await?;
+
+        if let Ok(count) = &result {
+            println!("Enqueued {} block jobs", count);
+        }
+
+        result
+    }
+
+    /// Claims and backfills block jobs until none are pending or claimed by other workers anymore.
+    ///
+    /// A job is held for `lease` between two progress updates. Jobs of workers that died are
+    /// claimed again once their lease expires and resume from the last recorded block.
+    pub async fn work_block_jobs(
+        self,
+        worker_id: String,
+        lease: Duration,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let store = self
+            .config
+            .store(&self.backfill_database, self.classification_cache.clone());
+
+        println!("Working block jobs as {}", worker_id);
+
+        let mut backoff = Backoff::new(self.config.storage_retry);
+
+        loop {
+            let job = match store.claim_block_job(&worker_id, lease).await {
+                Ok(Some(job)) => {
+                    backoff.reset();
+                    job
+                }
+                Ok(None) => {
+                    if !store.has_open_block_jobs().await? {
+                        println!("No block jobs left");
+                        return Ok(());
                     }
-                } else {
-                    println!("Waiting for latest block");
-                    sleep(Duration::from_millis(5000)).await;
+
+                    tokio::time::sleep(jobs::BLOCK_JOB_POLL_INTERVAL).await;
                     continue;
                 }
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not claim a block job... retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                    continue;
+                }
+            };
+
+            println!(
+                "Claimed the job for blocks {} to {}, resuming from block {}",
+                job.from_block, job.to_block, job.next_block
+            );
+
+            let logs_worker = self.backfill_logs_worker(store.clone(), &job.contracts);
+
+            let outcome = match jobs::run(&logs_worker, &store, &job, &worker_id, lease).await {
+                Ok(()) => store.complete_block_job(job.id, &worker_id).await,
+                Err(error) => {
+                    eprintln!(
+                        "Error: Job for blocks {} to {} failed... {}",
+                        job.from_block, job.to_block, error
+                    );
+                    store
+                        .release_block_job(
+                            &job,
+                            &worker_id,
+                            &error.to_string(),
+                            jobs::MAX_BLOCK_JOB_ATTEMPTS,
+                        )
+                        .await
+                }
+            };
+
+            if let Err(error) = outcome {
+                eprintln!(
+                    "Error: Could not record the outcome of the job for blocks {} to {}, it will be claimed again once its lease expires... {}",
+                    job.from_block, job.to_block, error
+                );
             }
-        });
+        }
+    }
 
-        match try_join!(latest_block_worker, logs_worker) {
-            Ok(_) => {}
-            Err(_) => eprintln!("Fatal Error: Worker stopped unexpectedly"),
+    /// A logs worker that only backfills, restricted to `contracts` when any are given.
+    fn backfill_logs_worker(&self, store: Store, contracts: &[H160]) -> LogsWorker {
+        let mut config = self.config.clone();
+
+        if !contracts.is_empty() {
+            config.contract_filter.allowlist =
+                Some(ContractAllowlist::Addresses(contracts.to_vec()));
+        }
+
+        LogsWorker {
+            web3: self.web3.clone(),
+            quorum: self.quorum.clone(),
+            store,
+            config,
+            latest_block: Default::default(),
+            new_block: Default::default(),
+            block_lock: Default::default(),
+            control: Default::default(),
+            signatures: Signatures::new(),
+            pending_blocks: Default::default(),
+            pending_revert: Default::default(),
+            instrumentation: self.instrumentation.clone(),
+            finalized_tag: false,
+            backfill_contracts: contracts.to_vec(),
+            #[cfg(feature = "webhooks")]
+            webhooks: Default::default(),
         }
     }
 }
 
-async fn get_database(host: String, database: String) -> Result<Database, Box<dyn error::Error>> {
-    let client_options = ClientOptions::parse(host).await?;
+/// Records a command run from the CLI in the audit trail, as `cli:$USER`.
+async fn record_cli_action<T>(
+    store: &Store,
+    action: String,
+    result: &Result<T, Box<dyn error::Error + Send + Sync>>,
+) -> mongodb::error::Result<()> {
+    store
+        .record_operator_action(&OperatorAction {
+            actor: format!(
+                "cli:{}",
+                std::env::var("USER").unwrap_or_else(|_| "unknown".to_string())
+            ),
+            role: None,
+            action,
+            outcome: match result {
+                Ok(_) => "ok".to_string(),
+                Err(error) => format!("error: {}", error),
+            },
+            performed_at: mongodb::bson::DateTime::now(),
+        })
+        .await
+}
+
+/// Reports every command to `instrumentation`.
+async fn get_database(
+    host: String,
+    database: String,
+    config: &WorkerConfig,
+    instrumentation: &Arc<Instrumentation>,
+) -> Result<(Client, Database), Box<dyn error::Error>> {
+    let mut client_options = ClientOptions::parse(host).await?;
+    client_options.command_event_handler =
+        Some(instrumentation.clone() as Arc<dyn CommandEventHandler>);
+
+    if config.write_concern.is_some() {
+        client_options.write_concern = config.write_concern.clone();
+    }
+    if config.read_concern.is_some() {
+        client_options.read_concern = config.read_concern.clone();
+    }
+    if config.max_pool_size.is_some() {
+        client_options.max_pool_size = config.max_pool_size;
+    }
+    if config.min_pool_size.is_some() {
+        client_options.min_pool_size = config.min_pool_size;
+    }
 
     let client = Client::with_options(client_options)?;
 
     let db = client.database(&database);
 
-    Ok(db)
+    Ok((client, db))
+}
+
+/// Whether the deployment supports multi-document transactions, which replica set members and
+/// `mongos` routers do and standalone servers do not.
+async fn supports_transactions(database: &Database) -> mongodb::error::Result<bool> {
+    let hello = match database.run_command(doc! { "hello": 1 }, None).await {
+        Ok(hello) => hello,
+        // Servers before 4.4.2 only know the legacy name.
+        Err(_) => database.run_command(doc! { "isMaster": 1 }, None).await?,
+    };
+
+    Ok(hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid"))
 }
 
-async fn get_web3_http(http_endpoint: String) -> Result<Web3<Http>, Box<dyn error::Error>> {
-    let transport = Http::new(&http_endpoint)?;
+/// `provider` names the provider in recordings of its traffic.
+async fn get_web3_http(
+    http_endpoint: String,
+    #[allow(unused_variables)] provider: &'static str,
+    #[allow(unused_variables)] config: &WorkerConfig,
+    instrumentation: &Arc<Instrumentation>,
+    #[allow(unused_variables)] simulation: &SharedSimulation,
+) -> Result<Web3<RpcTransport>, Box<dyn error::Error>> {
+    let transport = InstrumentedTransport::new(Http::new(&http_endpoint)?, instrumentation.clone());
+    #[cfg(feature = "simulation")]
+    let transport = simulation::SimulationTransport::new(transport, provider, simulation.clone());
+    #[cfg(feature = "chaos")]
+    let transport = chaos::ChaosTransport::new(transport, Arc::new(config.chaos.clone()));
     let web3 = Web3::new(transport);
     Ok(web3)
 }
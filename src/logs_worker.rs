@@ -0,0 +1,1799 @@
+#[cfg(feature = "webhooks")]
+use crate::webhooks::WebhookNotifier;
+use crate::{
+    amount::Amount,
+    approvals::{self, ApprovalChange},
+    backoff::Backoff,
+    capabilities,
+    control::WorkerControl,
+    discovery::ContractDiscovered,
+    instrumentation::Instrumentation,
+    jobs, migrations,
+    ownership::{OwnershipDelta, SelfTransferPolicy, Transfer},
+    privacy::OwnerProtectionMode,
+    processor::{self, LogError, Signatures},
+    quorum::Quorum,
+    reconciliation,
+    reorg::{self, PendingRevert},
+    store::{
+        CoverageKind, DeltaBatch, DeltaFeedKind, JournalEntry, JournaledLog, ReconciliationEntry,
+        Store, TokenMetadata, TransferRecord,
+    },
+    RpcTransport, WorkerConfig, START_BLOCK,
+};
+use futures::{stream, Stream, StreamExt};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Notify, RwLock},
+    time::sleep,
+};
+use web3::{
+    types::{BlockId, BlockNumber, FilterBuilder, Log, H160, H256, U64},
+    Web3,
+};
+
+/// Lease held by the live worker, the only one moving the checkpoint.
+const CHECKPOINT_LEASE: &str = "head_worker";
+
+/// Time the checkpoint lease stays held without being renewed, which happens before every block.
+const CHECKPOINT_LEASE_DURATION: Duration = Duration::from_secs(60);
+
+/// Time the finalized block is relied on before it is read again.
+const FINALIZED_BLOCK_REFRESH: Duration = Duration::from_secs(30);
+
+/// The hash and decoded logs of each block of a range, by block number.
+type DecodedRange =
+    Result<BTreeMap<U64, (H256, Vec<JournaledLog>)>, Box<dyn error::Error + Send + Sync>>;
+
+/// Outcome of `retry_dead_letters`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeadLetterRetry {
+    /// Dead letters decoded and applied.
+    pub applied: usize,
+    /// Dead letters that still could not be decoded, and are kept.
+    pub failed: usize,
+    /// Dead letters of blocks orphaned since, dropped.
+    pub orphaned: usize,
+}
+
+/// Processes the logs of every block from the checkpoint up to the chain head.
+pub(crate) struct LogsWorker {
+    pub web3: Web3<RpcTransport>,
+    /// Provider the logs are checked against before being applied, when quorum mode is enabled.
+    pub quorum: Option<Quorum>,
+    pub store: Store,
+    pub config: WorkerConfig,
+    pub latest_block: Arc<Mutex<Option<U64>>>,
+    pub new_block: Arc<Notify>,
+    pub block_lock: Arc<RwLock<()>>,
+    pub control: Arc<WorkerControl>,
+    pub signatures: Signatures,
+    /// Processed blocks waiting for MongoDB to accept their writes, oldest first.
+    pub pending_blocks: tokio::sync::Mutex<VecDeque<PendingBlock>>,
+    /// Progress of the block a failed reorg rollback was reverting, resumed by the next attempt.
+    pub pending_revert: tokio::sync::Mutex<Option<PendingRevert>>,
+    /// Told the block being processed, which slow operations are logged with.
+    pub instrumentation: Arc<Instrumentation>,
+    /// Whether the provider reports a finalized block, up to which blocks are fetched in ranges
+    /// even when they are within the reorg depth.
+    pub finalized_tag: bool,
+    /// Contracts a backfill is restricted to, recorded with the coverage of its blocks. Empty when
+    /// it applies every contract of the filter.
+    pub backfill_contracts: Vec<H160>,
+    #[cfg(feature = "webhooks")]
+    pub webhooks: WebhookNotifier,
+}
+
+impl LogsWorker {
+    /// Processes blocks until the checkpoint is handed over to a successor, or forever.
+    pub(crate) async fn run(self) {
+        let worker_id = jobs::default_worker_id();
+
+        self.take_over(&worker_id, self.config.handoff).await;
+
+        if self.config.handoff {
+            if let Err(error) =
+                migrations::migrate(&self.store, self.config.schema_migrations).await
+            {
+                eprintln!(
+                    "Fatal Error: Could not migrate the data model after taking over... {}",
+                    error
+                );
+                return;
+            }
+        }
+
+        let mut current_block = self.resume_block().await;
+
+        let reorg_depth = U64::from(self.config.reorg_depth);
+
+        let mut cached_finalized_block = None;
+
+        let mut backoff = Backoff::new(self.config.storage_retry);
+
+        loop {
+            match self
+                .store
+                .acquire_lease(CHECKPOINT_LEASE, &worker_id, CHECKPOINT_LEASE_DURATION)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("Warning: Lost the checkpoint lease to another worker");
+                    self.pending_blocks.lock().await.clear();
+                    self.take_over(&worker_id, false).await;
+                    current_block = self.resume_block().await;
+                    continue;
+                }
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not renew the checkpoint lease, retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                    continue;
+                }
+            }
+
+            match self
+                .store
+                .lease_successor(CHECKPOINT_LEASE, &worker_id)
+                .await
+            {
+                Ok(Some(successor)) => match self.hand_over(&worker_id, &successor).await {
+                    Ok(true) => {
+                        println!(
+                            "Handed the checkpoint over to {} before block {}",
+                            successor, current_block
+                        );
+                        return;
+                    }
+                    Ok(false) => continue,
+                    Err(error) => {
+                        eprintln!(
+                            "Error: Could not hand the checkpoint over to {}, retrying... {}",
+                            successor, error
+                        );
+                        backoff.wait().await;
+                        continue;
+                    }
+                },
+                Ok(None) => {}
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not read the checkpoint lease, retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                    continue;
+                }
+            }
+
+            if self.control.is_paused() && !self.control.is_reindex_requested() {
+                println!("Paused at block {}", current_block);
+                self.control.wait_while_paused().await;
+                continue;
+            }
+
+            if self.control.take_reindex() {
+                self.pending_blocks.lock().await.clear();
+
+                let _block_guard = self.block_lock.write().await;
+
+                match self.reset().await {
+                    Ok(()) => {
+                        println!("Reindexing from block {}", START_BLOCK);
+                        current_block = U64::from(START_BLOCK);
+                    }
+                    Err(error) => {
+                        eprintln!(
+                            "Error: Could not reset the ownership model, retrying... {}",
+                            error
+                        );
+                        self.control.request_reindex();
+                        backoff.wait().await;
+                    }
+                }
+
+                continue;
+            }
+
+            let latest_block = *self.latest_block.lock().unwrap();
+
+            let latest_block = match latest_block {
+                Some(latest_block) => latest_block,
+                None => {
+                    println!("Waiting for latest block");
+                    wait_for_new_block(&self.new_block).await;
+                    continue;
+                }
+            };
+
+            if current_block > latest_block {
+                println!("Waiting for new blocks");
+                wait_for_new_block(&self.new_block).await;
+                continue;
+            }
+
+            let addresses = match self.config.contract_filter.addresses(&self.store).await {
+                Ok(addresses) => addresses,
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not read the contract allowlist, retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                    continue;
+                }
+            };
+
+            // Blocks deeper than the reorg depth, or finalized, are final enough to be fetched in
+            // ranges without journaling, the ones closer to the head go through the reorg checks
+            // one by one.
+            let mut final_block = latest_block.checked_sub(reorg_depth);
+            if self.finalized_tag
+                && final_block.is_none_or(|final_block| current_block > final_block)
+            {
+                let finalized_block = self
+                    .finalized_block(&mut cached_finalized_block)
+                    .await
+                    .map(|finalized_block| finalized_block.min(latest_block));
+                final_block = final_block.max(finalized_block);
+            }
+
+            let result = match final_block {
+                Some(final_block) if current_block <= final_block && self.config.priority_head => {
+                    self.hand_off(current_block, final_block).await
+                }
+                Some(final_block) if current_block <= final_block => {
+                    self.process_ranges(
+                        current_block,
+                        final_block,
+                        latest_block,
+                        addresses.as_deref(),
+                    )
+                    .await
+                }
+                _ => {
+                    println!(
+                        "Processing block {} of {} blocks",
+                        current_block, latest_block
+                    );
+
+                    self.process_block(current_block, addresses.as_deref())
+                        .await
+                }
+            };
+
+            match result {
+                Ok(next_block) => {
+                    current_block = next_block;
+                    backoff.reset();
+                }
+                Err(error) => {
+                    eprintln!("Error: Could not process the block, retrying... {}", error);
+                    backoff.wait().await;
+                }
+            }
+        }
+    }
+
+    /// Waits for the checkpoint lease, asking its holder to hand it over when `request` is set
+    /// rather than waiting for it to expire.
+    async fn take_over(&self, worker_id: &str, request: bool) {
+        let mut requested = false;
+
+        loop {
+            match self
+                .store
+                .acquire_lease(CHECKPOINT_LEASE, worker_id, CHECKPOINT_LEASE_DURATION)
+                .await
+            {
+                Ok(true) => return,
+                Ok(false) if request && !requested => {
+                    match self.store.request_lease(CHECKPOINT_LEASE, worker_id).await {
+                        Ok(()) => {
+                            println!(
+                                "Asked the worker holding the checkpoint lease to hand it over"
+                            );
+                            requested = true;
+                        }
+                        Err(error) => eprintln!(
+                            "Error: Could not ask for the checkpoint lease, retrying... {}",
+                            error
+                        ),
+                    }
+                }
+                Ok(false) => println!("Waiting for the checkpoint lease held by another worker"),
+                Err(error) => eprintln!(
+                    "Error: Could not acquire the checkpoint lease, retrying... {}",
+                    error
+                ),
+            }
+
+            sleep(Duration::from_millis(5000)).await;
+        }
+    }
+
+    /// The block after the checkpoint.
+    async fn resume_block(&self) -> U64 {
+        let mut backoff = Backoff::new(self.config.storage_retry);
+
+        loop {
+            match self.store.last_processed_block().await {
+                Ok(Some(last_processed_block)) => {
+                    println!(
+                        "Resuming after checkpoint at block {}",
+                        last_processed_block
+                    );
+                    return last_processed_block + 1;
+                }
+                Ok(None) => return U64::from(START_BLOCK),
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not read the checkpoint, retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                }
+            }
+        }
+    }
+
+    /// Writes the pending blocks and hands the checkpoint lease over to `successor`, at a block
+    /// boundary so neither worker applies a block the other does. Returns `false` when the lease
+    /// was lost meanwhile.
+    async fn hand_over(
+        &self,
+        worker_id: &str,
+        successor: &str,
+    ) -> Result<bool, Box<dyn error::Error + Send + Sync>> {
+        self.flush(&mut *self.pending_blocks.lock().await).await?;
+
+        Ok(self
+            .store
+            .hand_over_lease(
+                CHECKPOINT_LEASE,
+                worker_id,
+                successor,
+                CHECKPOINT_LEASE_DURATION,
+            )
+            .await?)
+    }
+
+    /// Raises an alarm when the reorg detected at `block_number`, which resumes from `next_block`,
+    /// rolled back more blocks than the alarm depth, pausing the worker when configured to.
+    fn check_reorg_depth(&self, block_number: U64, next_block: U64) {
+        let alarm_depth = match self.config.reorg_alarm_depth {
+            Some(alarm_depth) => alarm_depth,
+            None => return,
+        };
+
+        let depth = block_number.saturating_sub(next_block).as_u64();
+        if depth <= alarm_depth {
+            return;
+        }
+
+        eprintln!(
+            "Alarm: Reorg at block {} rolled back {} blocks, more than the confirmation depth of {} blocks",
+            block_number, depth, alarm_depth
+        );
+
+        if self.config.pause_on_reorg_alarm {
+            self.control.pause();
+            println!(
+                "Paused after the reorg alarm, resume the worker or roll back further with rollback --to-block"
+            );
+        }
+    }
+
+    /// The block the provider reports as finalized, read again once `FINALIZED_BLOCK_REFRESH` has
+    /// passed since `cached` was read. `None` when it could not be read.
+    async fn finalized_block(&self, cached: &mut Option<(U64, Instant)>) -> Option<U64> {
+        if let Some((finalized_block, read_at)) = cached {
+            if read_at.elapsed() < FINALIZED_BLOCK_REFRESH {
+                return Some(*finalized_block);
+            }
+        }
+
+        match capabilities::finalized_block(&self.web3).await {
+            Ok(Some(finalized_block)) => {
+                *cached = Some((finalized_block, Instant::now()));
+                Some(finalized_block)
+            }
+            Ok(None) => None,
+            Err(error) => {
+                eprintln!(
+                    "Warning: Could not read the finalized block, relying on the reorg depth... {}",
+                    error
+                );
+                cached.map(|(finalized_block, _)| finalized_block)
+            }
+        }
+    }
+
+    /// Processes a single block after checking that it extends the journaled chain, rolling back
+    /// orphaned blocks otherwise.
+    ///
+    /// Returns the next block to process.
+    async fn process_block(
+        &self,
+        block_number: U64,
+        addresses: Option<&[H160]>,
+    ) -> Result<U64, Box<dyn error::Error + Send + Sync>> {
+        self.instrumentation.set_current_block(block_number);
+
+        // The reorg check below reads the journal, which has to include every processed block.
+        self.flush(&mut *self.pending_blocks.lock().await).await?;
+
+        let block = match self
+            .web3
+            .eth()
+            .block(BlockId::Number(BlockNumber::Number(block_number)))
+            .await?
+        {
+            Some(block) => block,
+            None => {
+                println!("Waiting for block {}", block_number);
+                sleep(Duration::from_millis(5000)).await;
+                return Ok(block_number);
+            }
+        };
+
+        // Pending blocks have no hash, and neither do mined ones from a misbehaving provider.
+        let block_hash = block
+            .hash
+            .ok_or_else(|| format!("Block {} has no hash", block_number))?;
+
+        if let Some(parent) = self.store.journal_entry(block_number - 1).await? {
+            if parent.block_hash != block.parent_hash {
+                println!("Reorg detected at block {}", block_number);
+
+                let _block_guard = self.block_lock.write().await;
+
+                let next_block = reorg::rollback(
+                    &self.web3,
+                    &self.store,
+                    &self.config,
+                    block_number - 1,
+                    &mut *self.pending_revert.lock().await,
+                )
+                .await
+                .map_err(|error| format!("Could not roll back the reorg... {}", error))?;
+
+                self.check_reorg_depth(block_number, next_block);
+                return Ok(next_block);
+            }
+        }
+
+        let logs = match self.logs_filter(addresses) {
+            Some(filter) => {
+                let filter = filter.block_hash(block_hash).build();
+                let logs = self.web3.eth().logs(filter.clone()).await?;
+
+                if let Some(quorum) = &self.quorum {
+                    quorum
+                        .verify(&self.store, filter, block_number, block_number, &logs)
+                        .await?;
+                }
+
+                logs
+            }
+            None => Vec::new(),
+        };
+
+        let logs = self.decode_logs(logs).await?;
+
+        let reconciliation = self.reconcile(block_number, block_number).await;
+
+        self.commit_block(
+            PendingBlock::new(
+                block_number,
+                Some(block_hash),
+                Some(block.parent_hash),
+                logs,
+            )
+            .with_reconciliation(reconciliation),
+        )
+        .await;
+
+        Ok(block_number + 1)
+    }
+
+    /// Enqueues the blocks from `from_block` to `to_block` as block jobs for `work-jobs` processes
+    /// and moves the checkpoint past them, so the blocks near the head are indexed right away.
+    ///
+    /// Returns the next block to process.
+    async fn hand_off(
+        &self,
+        from_block: U64,
+        to_block: U64,
+    ) -> Result<U64, Box<dyn error::Error + Send + Sync>> {
+        // Blocks handed off before a restart whose checkpoint was not written yet.
+        let from_block = match self.store.last_head_handoff_block().await? {
+            Some(last_block) if last_block >= from_block => last_block + 1,
+            _ => from_block,
+        };
+
+        if from_block <= to_block {
+            let mut jobs = jobs::split(
+                from_block.as_u64(),
+                to_block.as_u64(),
+                self.config.block_job_size,
+                &[],
+            );
+            for job in &mut jobs {
+                job.head_handoff = true;
+            }
+
+            self.store.insert_block_jobs(&jobs).await?;
+
+            println!(
+                "Handed blocks {} to {} off as {} block jobs",
+                from_block,
+                to_block,
+                jobs.len()
+            );
+        }
+
+        // The block jobs record the coverage of the blocks once they backfill them.
+        self.commit_block(PendingBlock::new(to_block, None, None, Vec::new()).handed_off())
+            .await;
+
+        Ok(to_block + 1)
+    }
+
+    /// Processes the blocks from `from_block` to `to_block` in ranges of up to `log_range_size`
+    /// blocks. Up to `backfill_concurrency` ranges are fetched and decoded at the same time, but
+    /// their deltas are applied strictly in block order.
+    ///
+    /// Stops early when the worker is paused or a range cannot be fetched, and returns the next
+    /// block to process.
+    async fn process_ranges(
+        &self,
+        from_block: U64,
+        to_block: U64,
+        latest_block: U64,
+        addresses: Option<&[H160]>,
+    ) -> Result<U64, Box<dyn error::Error + Send + Sync>> {
+        let mut decoded_ranges = self.stream_ranges(from_block, to_block, addresses);
+
+        let mut next_block = from_block;
+
+        while let Some((range_start, range_end, blocks)) = decoded_ranges.next().await {
+            let blocks = match blocks {
+                Ok(blocks) => blocks,
+                Err(error) if next_block == from_block => return Err(error),
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not fetch blocks {} to {}, retrying... {}",
+                        range_start, range_end, error
+                    );
+                    break;
+                }
+            };
+
+            println!(
+                "Processing blocks {} to {} of {} blocks",
+                range_start, range_end, latest_block
+            );
+
+            for (block_number, (block_hash, logs)) in blocks {
+                self.commit_block(PendingBlock::new(
+                    block_number,
+                    Some(block_hash),
+                    None,
+                    logs,
+                ))
+                .await;
+            }
+
+            let reconciliation = self.reconcile(range_start, range_end).await;
+
+            self.commit_block(
+                PendingBlock::new(range_end, None, None, Vec::new())
+                    .covering_from(range_start)
+                    .with_reconciliation(reconciliation),
+            )
+            .await;
+
+            next_block = range_end + 1;
+
+            if self.control.is_paused() || self.control.is_reindex_requested() {
+                break;
+            }
+        }
+
+        Ok(next_block)
+    }
+
+    /// Decodes the dead letters of the blocks up to the checkpoint again, applying the logs that
+    /// decode now like a backfill applies them, and adding their deltas to the journal and the
+    /// delta feed. Dead letters of blocks orphaned since are dropped, and the ones of blocks after
+    /// the checkpoint are left to the live worker.
+    pub(crate) async fn retry_dead_letters(
+        &self,
+    ) -> Result<DeadLetterRetry, Box<dyn error::Error + Send + Sync>> {
+        let mut retry = DeadLetterRetry::default();
+
+        let last_processed_block = match self.store.last_processed_block().await? {
+            Some(last_processed_block) => last_processed_block,
+            None => return Ok(retry),
+        };
+
+        for dead_letter in self.store.dead_letters(last_processed_block).await? {
+            let block_number = U64::from(dead_letter.block_number as u64);
+            let block_hash = dead_letter.block_hash;
+
+            let canonical_block = self
+                .web3
+                .eth()
+                .block(BlockId::Number(BlockNumber::Number(block_number)))
+                .await?;
+
+            if canonical_block.and_then(|block| block.hash) != Some(block_hash) {
+                println!(
+                    "Dropping dead letter {} of orphaned block {}",
+                    dead_letter.id, block_number
+                );
+                self.store.remove_dead_letter(&dead_letter.id).await?;
+                retry.orphaned += 1;
+                continue;
+            }
+
+            // With owner protection, dead letters are stored with their owners protected, so the
+            // log is fetched from the provider again to decode it.
+            let log = if self.config.owner_protection.mode() == OwnerProtectionMode::Plain {
+                dead_letter.log
+            } else {
+                let filter = FilterBuilder::default()
+                    .block_hash(block_hash)
+                    .address(vec![dead_letter.contract_address])
+                    .build();
+
+                match self
+                    .web3
+                    .eth()
+                    .logs(filter)
+                    .await?
+                    .into_iter()
+                    .find(|log| log.log_index == Some(dead_letter.log_index))
+                {
+                    Some(log) => log,
+                    None => {
+                        retry.failed += 1;
+                        continue;
+                    }
+                }
+            };
+
+            let (logs, dead_lettered) = self.decode_and_count_logs(vec![log]).await?;
+            if dead_lettered > 0 {
+                retry.failed += 1;
+                continue;
+            }
+
+            let _block_guard = self.block_lock.write().await;
+
+            self.backfill_block(block_number, block_hash, &logs, false)
+                .await?;
+
+            for log in &logs {
+                self.store
+                    .journal_log(block_number, block_hash, log)
+                    .await?;
+            }
+
+            let deltas: Vec<_> = logs.iter().flat_map(|log| log.deltas.clone()).collect();
+            if self.config.delta_feed_retention.is_some() && !deltas.is_empty() {
+                self.store
+                    .append_dead_letter_feed_entry(
+                        &dead_letter.id,
+                        block_number,
+                        block_hash,
+                        deltas,
+                    )
+                    .await?;
+            }
+
+            println!(
+                "Applied dead letter {} of block {}",
+                dead_letter.id, block_number
+            );
+            self.store.remove_dead_letter(&dead_letter.id).await?;
+            retry.applied += 1;
+        }
+
+        Ok(retry)
+    }
+
+    /// Applies the blocks from `from_block` to `to_block` again, fetching and decoding up to
+    /// `backfill_concurrency` ranges at the same time.
+    ///
+    /// The checkpoint, the journal and the delta feed are left untouched, so this can run next to
+    /// the live worker. Stops at the first error, after reporting the last block applied.
+    pub(crate) async fn backfill(
+        &self,
+        from_block: U64,
+        to_block: U64,
+        idempotent: bool,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let addresses = self.config.contract_filter.addresses(&self.store).await?;
+        let addresses = addresses.as_deref();
+
+        let mut decoded_ranges = self.stream_ranges(from_block, to_block, addresses);
+
+        while let Some((range_start, range_end, blocks)) = decoded_ranges.next().await {
+            println!(
+                "Backfilling blocks {} to {} of {} blocks",
+                range_start, range_end, to_block
+            );
+
+            let blocks = match blocks {
+                Ok(blocks) => blocks,
+                Err(error) => {
+                    eprintln!(
+                        "Error: Backfill stopped, blocks before {} are applied",
+                        range_start
+                    );
+                    return Err(error);
+                }
+            };
+
+            for (block_number, (block_hash, logs)) in blocks {
+                let _block_guard = self.block_lock.write().await;
+
+                if let Err(error) = self
+                    .backfill_block(block_number, block_hash, &logs, idempotent)
+                    .await
+                {
+                    eprintln!(
+                        "Error: Backfill stopped, blocks before {} are applied and block {} may be partially applied",
+                        block_number, block_number
+                    );
+                    return Err(error);
+                }
+            }
+
+            self.store
+                .record_coverage(
+                    CoverageKind::Backfill,
+                    range_start,
+                    range_end,
+                    &self.backfill_contracts,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the deltas of a backfilled block. When `idempotent`, blocks and logs already applied
+    /// are skipped and recorded as applied, like the blocks of the live worker.
+    async fn backfill_block(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+        logs: &[JournaledLog],
+        idempotent: bool,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        // Mirrors skip the blocks they applied themselves, even those MongoDB already has.
+        if !self.config.ownership_stores.is_empty() {
+            let deltas: Vec<_> = logs.iter().flat_map(|log| log.deltas.clone()).collect();
+            for ownership_store in &self.config.ownership_stores {
+                ownership_store
+                    .apply_deltas(block_number, idempotent.then_some(block_hash), &deltas)
+                    .await?;
+            }
+        }
+
+        if idempotent && self.store.is_block_applied(block_hash).await? {
+            println!(
+                "Skipping block {} ({:#x}) whose deltas were already applied",
+                block_number, block_hash
+            );
+            return Ok(());
+        }
+
+        let mut unapplied_logs = if idempotent {
+            self.store.unapplied_logs(block_hash, logs).await?
+        } else {
+            logs.iter().collect()
+        };
+        unapplied_logs.retain(|log| !log.deltas.is_empty());
+
+        let deltas: Vec<_> = unapplied_logs
+            .iter()
+            .flat_map(|log| log.deltas.clone())
+            .collect();
+        self.store
+            .apply_delta_batches(&mut DeltaBatch::partitioned(
+                &deltas,
+                self.config.writer_tasks,
+                block_number,
+            ))
+            .await?;
+
+        if idempotent {
+            self.store
+                .mark_logs_applied(block_number, block_hash, &unapplied_logs)
+                .await?;
+        }
+
+        let transfers: Vec<_> = logs.iter().flat_map(|log| log.transfers.clone()).collect();
+        self.store.append_transfers(&transfers).await?;
+
+        let reconciliations: Vec<_> = logs
+            .iter()
+            .flat_map(|log| log.reconciliations.clone())
+            .collect();
+        self.store.queue_reconciliations(&reconciliations).await?;
+
+        if idempotent {
+            self.store
+                .mark_block_applied(block_number, block_hash)
+                .await?;
+            self.store.forget_applied_logs(block_hash).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reconciles the queued balances of volume capped contracts at `to_block`, when the blocks from
+    /// `from_block` to `to_block` close a reconciliation interval.
+    ///
+    /// Waits for the blocks still queued for MongoDB to be written, since the corrections are
+    /// relative to the stored balances. Failures are reported and leave the balances queued.
+    async fn reconcile(
+        &self,
+        from_block: U64,
+        to_block: U64,
+    ) -> Option<(Vec<OwnershipDelta>, Vec<String>)> {
+        let interval = self.config.reconciliation_interval.max(1);
+
+        if self.config.event_volume_cap.is_none()
+            || to_block.as_u64() / interval == from_block.as_u64().saturating_sub(1) / interval
+            || !self.pending_blocks.lock().await.is_empty()
+        {
+            return None;
+        }
+
+        match reconciliation::reconcile(
+            &self.web3,
+            &self.store,
+            &self.config.owner_protection,
+            to_block,
+        )
+        .await
+        {
+            Ok((deltas, reconciled)) => {
+                if !reconciled.is_empty() {
+                    println!(
+                        "Reconciled {} balances of volume capped contracts at block {}",
+                        reconciled.len(),
+                        to_block
+                    );
+                }
+                Some((deltas, reconciled))
+            }
+            Err(error) => {
+                eprintln!(
+                    "Error: Could not reconcile the balances of volume capped contracts at block {}... {}",
+                    to_block, error
+                );
+                None
+            }
+        }
+    }
+
+    /// Splits the blocks from `from_block` to `to_block` into ranges of up to `log_range_size`
+    /// blocks and decodes up to `backfill_concurrency` of them at the same time, yielding each
+    /// range with its decoded blocks in block order.
+    fn stream_ranges<'a>(
+        &'a self,
+        from_block: U64,
+        to_block: U64,
+        addresses: Option<&'a [H160]>,
+    ) -> impl Stream<Item = (U64, U64, DecodedRange)> + 'a {
+        let range_size = self.config.log_range_size.max(1);
+
+        let ranges = (from_block.as_u64()..=to_block.as_u64())
+            .step_by(range_size as usize)
+            .map(move |range_start| {
+                let range_start = U64::from(range_start);
+                (range_start, (range_start + range_size - 1).min(to_block))
+            });
+
+        stream::iter(ranges)
+            .map(move |(range_start, range_end)| async move {
+                (
+                    range_start,
+                    range_end,
+                    self.decode_range(range_start, range_end, addresses).await,
+                )
+            })
+            .buffered(self.config.backfill_concurrency.max(1))
+    }
+
+    /// Fetches the logs of a range with as few `eth_getLogs` calls as the provider allows and
+    /// decodes them into the deltas of each block, grouped by log.
+    async fn decode_range(
+        &self,
+        from_block: U64,
+        to_block: U64,
+        addresses: Option<&[H160]>,
+    ) -> DecodedRange {
+        self.instrumentation.set_current_block(from_block);
+
+        let mut blocks: BTreeMap<U64, (H256, Vec<Log>)> = BTreeMap::new();
+
+        for log in self.range_logs(from_block, to_block, addresses).await? {
+            if let (Some(block_number), Some(block_hash)) = (log.block_number, log.block_hash) {
+                blocks
+                    .entry(block_number)
+                    .or_insert_with(|| (block_hash, Vec::new()))
+                    .1
+                    .push(log);
+            }
+        }
+
+        let mut decoded_blocks = BTreeMap::new();
+
+        for (block_number, (block_hash, logs)) in blocks {
+            decoded_blocks.insert(block_number, (block_hash, self.decode_logs(logs).await?));
+        }
+
+        Ok(decoded_blocks)
+    }
+
+    /// Fetches the logs of a range, splitting it in halves whenever the provider rejects it for
+    /// returning too many results.
+    pub(crate) async fn range_logs(
+        &self,
+        from_block: U64,
+        to_block: U64,
+        addresses: Option<&[H160]>,
+    ) -> Result<Vec<Log>, Box<dyn error::Error + Send + Sync>> {
+        let base_filter = match self.logs_filter(addresses) {
+            Some(filter) => filter,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut pending_ranges = vec![(from_block, to_block)];
+        let mut logs = Vec::new();
+
+        while let Some((from_block, to_block)) = pending_ranges.pop() {
+            let filter = base_filter
+                .clone()
+                .from_block(BlockNumber::Number(from_block))
+                .to_block(BlockNumber::Number(to_block))
+                .build();
+
+            match self.web3.eth().logs(filter.clone()).await {
+                Ok(range_logs) => {
+                    if let Some(quorum) = &self.quorum {
+                        quorum
+                            .verify(&self.store, filter, from_block, to_block, &range_logs)
+                            .await?;
+                    }
+
+                    logs.extend(range_logs)
+                }
+                Err(error) if from_block < to_block && is_too_many_results(&error) => {
+                    let middle_block = from_block + (to_block - from_block) / 2;
+
+                    println!(
+                        "Splitting blocks {} to {} at block {}",
+                        from_block, to_block, middle_block
+                    );
+
+                    pending_ranges.push((middle_block + 1, to_block));
+                    pending_ranges.push((from_block, middle_block));
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(logs)
+    }
+
+    /// Filter matching the transfer events of the allowed contracts, `None` when no contract is
+    /// allowed and there is nothing to fetch.
+    fn logs_filter(&self, addresses: Option<&[H160]>) -> Option<FilterBuilder> {
+        let filter = FilterBuilder::default().topics(
+            Some(self.signatures.filter_topics(&self.config)),
+            None,
+            None,
+            None,
+        );
+
+        match addresses {
+            None => Some(filter),
+            Some([]) => None,
+            Some(addresses) => Some(filter.address(addresses.to_vec())),
+        }
+    }
+
+    /// Decodes the logs of a block into the ownership deltas they imply, with owners protected.
+    ///
+    /// Logs the provider reports as removed revert the deltas journaled for them instead, and are
+    /// skipped when there is nothing to revert. Logs of volume capped contracts queue the balances
+    /// they touch for reconciliation instead of implying deltas. Logs that cannot be decoded are
+    /// recorded in `dead_letters` and left out.
+    async fn decode_logs(&self, logs: Vec<Log>) -> mongodb::error::Result<Vec<JournaledLog>> {
+        Ok(self.decode_and_count_logs(logs).await?.0)
+    }
+
+    /// Decodes logs like `decode_logs`, also returning how many of them could not be decoded and
+    /// were dead-lettered instead.
+    async fn decode_and_count_logs(
+        &self,
+        logs: Vec<Log>,
+    ) -> mongodb::error::Result<(Vec<JournaledLog>, usize)> {
+        #[cfg(feature = "chaos")]
+        let logs = self.config.chaos.duplicate_logs(logs);
+
+        let logs = processor::dedup_logs(self.config.contract_filter.retain_logs(logs));
+
+        if self.config.raw_log_archive {
+            self.store.archive_raw_logs(&logs).await?;
+        }
+
+        let logs = processor::drop_paired_erc_777_logs(&self.signatures, logs);
+
+        let (over_cap, volume_capped) = match self.config.event_volume_cap {
+            Some(cap) => {
+                let over_cap = reconciliation::over_cap(&logs, cap);
+                let mut volume_capped: HashSet<_> = self
+                    .store
+                    .volume_capped_contracts()
+                    .await?
+                    .into_iter()
+                    .collect();
+                volume_capped.extend(&over_cap);
+                (over_cap, volume_capped)
+            }
+            None => Default::default(),
+        };
+
+        let mut decoded_logs = Vec::new();
+        let mut minted_tokens = Vec::new();
+        let mut dead_lettered = 0;
+
+        for log in &logs {
+            let transaction_hash = log.transaction_hash.unwrap_or_default();
+            let log_index = log.log_index.unwrap_or_default();
+
+            if log.is_removed() {
+                match self
+                    .store
+                    .journaled_log_deltas(transaction_hash, log_index)
+                    .await?
+                {
+                    Some(deltas) => {
+                        println!(
+                            "Reverting removed log {:#x}:{}",
+                            transaction_hash, log_index
+                        );
+
+                        decoded_logs.push(JournaledLog {
+                            transaction_hash,
+                            log_index,
+                            removed: true,
+                            deltas: deltas.iter().rev().map(|delta| delta.inverse()).collect(),
+                            approvals: Vec::new(),
+                            transfers: Vec::new(),
+                            reconciliations: Vec::new(),
+                        });
+                    }
+                    None => eprintln!(
+                        "Warning: Skipping removed log {:#x}:{} which has no deltas to revert",
+                        transaction_hash, log_index
+                    ),
+                }
+
+                continue;
+            }
+
+            if let Some(approvals) =
+                approvals::decode_approval_log(&self.signatures, &self.config.owner_protection, log)
+            {
+                decoded_logs.push(JournaledLog {
+                    transaction_hash,
+                    log_index,
+                    removed: false,
+                    deltas: Vec::new(),
+                    approvals,
+                    transfers: Vec::new(),
+                    reconciliations: Vec::new(),
+                });
+
+                continue;
+            }
+
+            let decoded = match processor::decode_wrapped_token_log(
+                &self.signatures,
+                &self.config.wrapped_tokens,
+                log,
+            ) {
+                Some(transfers) => transfers
+                    .map(|transfers| {
+                        let deltas = transfers
+                            .iter()
+                            .flat_map(|transfer| transfer.deltas(SelfTransferPolicy::default()))
+                            .collect();
+                        (transfers, deltas, None)
+                    })
+                    .map_err(LogError::Undecodable),
+                None => {
+                    processor::process_log(&self.web3, &self.store, &self.signatures, log, &logs)
+                        .await
+                }
+            };
+
+            let (transfers, deltas, discovered) = match decoded {
+                Ok(decoded) => decoded,
+                Err(LogError::Database(error)) => return Err(error),
+                Err(LogError::Undecodable(error)) => {
+                    eprintln!(
+                        "Warning: Dead-lettering log {:#x}:{} of {:#x} which could not be decoded... {}",
+                        transaction_hash, log_index, log.address, error
+                    );
+                    self.store
+                        .record_dead_letter(
+                            &self
+                                .config
+                                .owner_protection
+                                .protect_log(log, self.signatures.address_topics(log)),
+                            &error,
+                        )
+                        .await?;
+                    dead_lettered += 1;
+                    continue;
+                }
+            };
+
+            if let Some(discovered) = discovered {
+                self.contract_discovered(&discovered);
+            }
+
+            let filter = &self.config.contract_filter;
+            let transfers: Vec<_> = transfers
+                .into_iter()
+                .filter(|transfer| {
+                    filter.retains_token(transfer.contract_address, transfer.token_id.as_deref())
+                })
+                .map(|transfer| self.config.owner_protection.protect_transfer(transfer))
+                .collect();
+            let deltas: Vec<_> = deltas
+                .into_iter()
+                .filter(|delta| {
+                    filter.retains_token(delta.contract_address, delta.token_id.as_deref())
+                })
+                .collect();
+
+            if self.config.token_metadata {
+                minted_tokens.extend(
+                    transfers
+                        .iter()
+                        .filter(|transfer| {
+                            transfer.from == H160::zero()
+                                && matches!(transfer.token_type.as_str(), "ERC721" | "ERC1155")
+                        })
+                        .map(|transfer| {
+                            TokenMetadata::pending(
+                                transfer.contract_address,
+                                &transfer.token_type,
+                                transfer.token_id.clone(),
+                            )
+                        }),
+                );
+            }
+
+            let (deltas, reconciliations) = if volume_capped.contains(&log.address) {
+                (
+                    Vec::new(),
+                    reconciliation::queue_entries(&transfers, log.block_number.unwrap_or_default()),
+                )
+            } else {
+                let deltas = deltas
+                    .into_iter()
+                    .map(|delta| self.config.owner_protection.protect_delta(delta))
+                    .collect();
+                (deltas, Vec::new())
+            };
+
+            let approvals = if self.config.index_approvals {
+                approvals::cleared_by_transfers(&deltas)
+            } else {
+                Vec::new()
+            };
+
+            let transfers = if self.config.transfer_history {
+                transfers
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(position, transfer)| transfer_record(log, position, transfer))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            decoded_logs.push(JournaledLog {
+                transaction_hash,
+                log_index,
+                removed: false,
+                deltas,
+                approvals,
+                transfers,
+                reconciliations,
+            });
+        }
+
+        self.store.queue_token_metadata(&minted_tokens).await?;
+
+        // Flagged once the contracts are classified, since only classified contracts are stored.
+        for address in over_cap {
+            println!(
+                "Capping {:#x}, which exceeded the event volume cap, its balances are reconciled from now on",
+                address
+            );
+            self.store.set_volume_capped(address).await?;
+        }
+
+        Ok((decoded_logs, dead_lettered))
+    }
+
+    /// Drops the ownership model for a reindex, in MongoDB and in every mirrored store.
+    async fn reset(&self) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        self.store.reset().await?;
+
+        for ownership_store in &self.config.ownership_stores {
+            ownership_store.reset().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues the mutations of a processed block, then writes every queued block MongoDB accepts.
+    ///
+    /// Blocks that cannot be written stay queued and are retried in order with the next block.
+    /// Once the queue holds `write_buffer_size` deltas, this waits for MongoDB to come back so no
+    /// further block is processed.
+    async fn commit_block(&self, block: PendingBlock) {
+        let mut pending_blocks = self.pending_blocks.lock().await;
+        pending_blocks.push_back(block);
+
+        loop {
+            let error = match self.flush(&mut pending_blocks).await {
+                Ok(()) => return,
+                Err(error) => error,
+            };
+
+            let buffered_deltas: usize = pending_blocks.iter().map(PendingBlock::weight).sum();
+
+            if buffered_deltas < self.config.write_buffer_size {
+                eprintln!(
+                    "Error: Could not write block {}, buffering {} deltas... {}",
+                    pending_blocks[0].block_number, buffered_deltas, error
+                );
+                return;
+            }
+
+            eprintln!(
+                "Error: Write buffer is full with {} deltas, waiting for MongoDB... {}",
+                buffered_deltas, error
+            );
+            sleep(Duration::from_millis(5000)).await;
+        }
+    }
+
+    /// Writes the queued blocks in order, stopping at the first one that fails, and evaluates the
+    /// webhook rules of the contracts each written block touched.
+    async fn flush(
+        &self,
+        pending_blocks: &mut VecDeque<PendingBlock>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        while let Some(block) = pending_blocks.front_mut() {
+            self.write_block(block).await?;
+
+            let _block = pending_blocks.pop_front().unwrap();
+
+            #[cfg(feature = "webhooks")]
+            self.evaluate_webhooks(&_block).await;
+        }
+
+        Ok(())
+    }
+
+    /// Logs a contract classified for the first time and hands it to the discovery hooks and
+    /// webhook.
+    fn contract_discovered(&self, discovered: &ContractDiscovered) {
+        println!(
+            "Discovered {} contract {:#x}, classified by {}, in transaction {:#x} of block {}",
+            discovered.token_type,
+            discovered.contract_address,
+            discovered.classified_by,
+            discovered.transaction_hash,
+            discovered.block_number
+        );
+
+        for hook in &self.config.contract_discovery_hooks {
+            hook.contract_discovered(discovered);
+        }
+
+        #[cfg(feature = "webhooks")]
+        if let Some(url) = &self.config.contract_discovery_webhook {
+            match serde_json::to_value(discovered) {
+                Ok(body) => self.webhooks.post(
+                    url,
+                    body,
+                    format!(
+                        "the discovery webhook of contract {:#x}",
+                        discovered.contract_address
+                    ),
+                ),
+                Err(error) => eprintln!(
+                    "Error: Could not serialize the discovery of contract {:#x}... {}",
+                    discovered.contract_address, error
+                ),
+            }
+        }
+    }
+
+    /// Evaluates the webhook rules of the contracts a written block touched.
+    #[cfg(feature = "webhooks")]
+    async fn evaluate_webhooks(&self, block: &PendingBlock) {
+        if block.deltas.is_empty() {
+            return;
+        }
+
+        let contracts = block
+            .deltas
+            .iter()
+            .map(|delta| delta.contract_address)
+            .collect();
+
+        if let Err(error) = self
+            .webhooks
+            .evaluate(&self.store, block.block_number, contracts)
+            .await
+        {
+            eprintln!(
+                "Error: Could not evaluate the webhooks of block {}... {}",
+                block.block_number, error
+            );
+        }
+    }
+
+    /// The logs with deltas of a block that were not applied before a restart, which are only
+    /// known from the markers of their logs.
+    async fn unapplied_logs<'a>(
+        &self,
+        block_hash: Option<H256>,
+        logs: &'a [JournaledLog],
+    ) -> mongodb::error::Result<Vec<&'a JournaledLog>> {
+        let mut unapplied_logs = match block_hash {
+            Some(block_hash) => self.store.unapplied_logs(block_hash, logs).await?,
+            None => logs.iter().collect(),
+        };
+        unapplied_logs.retain(|log| !log.deltas.is_empty());
+
+        Ok(unapplied_logs)
+    }
+
+    /// Applies the deltas of a block to the ownership model, then moves the checkpoint to the
+    /// block. Blocks with a known parent hash are journaled for reorg rollbacks. When the store
+    /// applies blocks in transactions, the deltas are applied with the checkpoint instead.
+    ///
+    /// Progress is recorded in the block, so calling this again after a failure resumes where the
+    /// previous attempt stopped.
+    async fn write_block(
+        &self,
+        block: &mut PendingBlock,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let _block_guard = self.block_lock.write().await;
+
+        self.instrumentation.set_current_block(block.block_number);
+
+        if let Some(block_hash) = block.block_hash {
+            if !block.applied && self.store.is_block_applied(block_hash).await? {
+                println!(
+                    "Skipping block {} ({:#x}) whose deltas were already applied",
+                    block.block_number, block_hash
+                );
+                block.applied_approvals = block.approvals.len();
+                block.feed_appended = true;
+                #[cfg(feature = "nats")]
+                {
+                    block.nats_published = true;
+                }
+                block.applied = true;
+            }
+        }
+
+        // In a transaction the deltas are applied with the checkpoint, once everything else is.
+        let in_transaction = self.store.applies_blocks_in_transactions();
+
+        if !block.applied && !in_transaction {
+            let unapplied_logs = self.unapplied_logs(block.block_hash, &block.logs).await?;
+
+            let batches = match &mut block.batches {
+                Some(batches) => batches,
+                None => block.batches.insert(DeltaBatch::partitioned(
+                    &unapplied_deltas(
+                        block.block_number,
+                        &block.logs,
+                        &block.deltas,
+                        &unapplied_logs,
+                    ),
+                    self.config.writer_tasks,
+                    block.block_number,
+                )),
+            };
+
+            self.store.apply_delta_batches(batches).await?;
+
+            if let Some(block_hash) = block.block_hash {
+                self.store
+                    .mark_logs_applied(block.block_number, block_hash, &unapplied_logs)
+                    .await?;
+            }
+        }
+
+        // Mirrors record the blocks they applied themselves, so a block written again is skipped.
+        if let Some(block_hash) = block.block_hash {
+            while let Some(ownership_store) = self.config.ownership_stores.get(block.mirrored) {
+                ownership_store
+                    .apply_deltas(block.block_number, Some(block_hash), &block.deltas)
+                    .await?;
+                block.mirrored += 1;
+            }
+        }
+
+        while let Some(approval) = block.approvals.get(block.applied_approvals) {
+            self.store
+                .apply_approval(approval, block.block_number)
+                .await?;
+            block.applied_approvals += 1;
+        }
+
+        if !block.transfers_appended {
+            self.store.append_transfers(&block.transfers).await?;
+            for log in block.logs.iter().filter(|log| log.removed) {
+                self.store
+                    .mark_log_transfers_removed(log.transaction_hash, log.log_index)
+                    .await?;
+            }
+            block.transfers_appended = true;
+        }
+
+        if !block.reconciliations_queued {
+            self.store.remove_reconciliations(&block.reconciled).await?;
+            self.store
+                .queue_reconciliations(&block.reconciliations)
+                .await?;
+            block.reconciliations_queued = true;
+        }
+
+        // Published before the block is marked applied, so a block found applied was published.
+        #[cfg(feature = "nats")]
+        if let (Some(nats), Some(block_hash)) = (&self.config.nats, block.block_hash) {
+            if !block.nats_published {
+                nats.publish_block(
+                    DeltaFeedKind::Apply,
+                    block.block_number,
+                    block_hash,
+                    &block.logs,
+                    &block.deltas,
+                )
+                .await?;
+                block.nats_published = true;
+            }
+        }
+
+        if let (Some(delta_feed_retention), Some(block_hash)) = (
+            self.config.delta_feed_retention.map(U64::from),
+            block.block_hash,
+        ) {
+            if !block.feed_appended {
+                self.store
+                    .append_feed_entry(
+                        DeltaFeedKind::Apply,
+                        block.block_number,
+                        block_hash,
+                        block.deltas.clone(),
+                    )
+                    .await?;
+                block.feed_appended = true;
+            }
+
+            if block.block_number > delta_feed_retention {
+                self.store
+                    .prune_feed(block.block_number - delta_feed_retention)
+                    .await?;
+            }
+        }
+
+        if let Some(block_hash) = block.block_hash {
+            if !block.applied && !in_transaction {
+                self.store
+                    .mark_block_applied(block.block_number, block_hash)
+                    .await?;
+                self.store.forget_applied_logs(block_hash).await?;
+                block.applied = true;
+            }
+        }
+
+        if let (Some(block_hash), Some(parent_hash)) = (block.block_hash, block.parent_hash) {
+            let reorg_depth = U64::from(self.config.reorg_depth);
+
+            self.store
+                .push_journal_entry(&JournalEntry {
+                    block_number: block.block_number.as_u64() as i64,
+                    block_hash,
+                    parent_hash,
+                    deltas: block.deltas.clone(),
+                    logs: block.logs.clone(),
+                })
+                .await?;
+
+            if block.block_number > reorg_depth {
+                self.store
+                    .prune_journal(block.block_number - reorg_depth)
+                    .await?;
+            }
+        }
+
+        // Recorded before the checkpoint, which bounds the live coverage of reports, so a crash in
+        // between leaves no gap.
+        if let Some(first_covered_block) = block.first_covered_block {
+            self.store
+                .record_coverage(
+                    CoverageKind::Live,
+                    first_covered_block,
+                    block.block_number,
+                    &[],
+                )
+                .await?;
+        }
+
+        if !block.applied && in_transaction {
+            let unapplied_logs = self.unapplied_logs(block.block_hash, &block.logs).await?;
+            let deltas = unapplied_deltas(
+                block.block_number,
+                &block.logs,
+                &block.deltas,
+                &unapplied_logs,
+            );
+
+            self.store
+                .apply_block_in_transaction(block.block_number, block.block_hash, &deltas)
+                .await?;
+            if let Some(block_hash) = block.block_hash {
+                self.store.forget_applied_logs(block_hash).await?;
+            }
+            block.applied = true;
+        } else {
+            self.store
+                .set_last_processed_block(block.block_number)
+                .await?;
+        }
+        for ownership_store in &self.config.ownership_stores {
+            ownership_store.set_checkpoint(block.block_number).await?;
+        }
+
+        #[cfg(feature = "chaos")]
+        if block.parent_hash.is_some() && self.config.chaos.reorg() {
+            eprintln!("Chaos: Orphaning block {}", block.block_number);
+            // The orphaned hash takes over the applied block record, so the rollback reverts the
+            // deltas and the block is applied again under its own hash.
+            let orphaned_hash = rand::random::<[u8; 32]>().into();
+            if let Some(block_hash) = block.block_hash {
+                self.store.unmark_block_applied(block_hash).await?;
+            }
+            self.store
+                .mark_block_applied(block.block_number, orphaned_hash)
+                .await?;
+            self.store
+                .set_journal_block_hash(block.block_number, orphaned_hash)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The deltas of a block not applied before a restart, those of `unapplied_logs` followed by the
+/// deltas of its reconciliation.
+fn unapplied_deltas(
+    block_number: U64,
+    logs: &[JournaledLog],
+    deltas: &[OwnershipDelta],
+    unapplied_logs: &[&JournaledLog],
+) -> Vec<OwnershipDelta> {
+    let logs_with_deltas = logs.iter().filter(|log| !log.deltas.is_empty()).count();
+    if unapplied_logs.len() < logs_with_deltas {
+        println!(
+            "Skipping {} logs of block {} whose deltas were already applied",
+            logs_with_deltas - unapplied_logs.len(),
+            block_number
+        );
+    }
+
+    let log_deltas: usize = logs.iter().map(|log| log.deltas.len()).sum();
+    let mut unapplied_deltas: Vec<_> = unapplied_logs
+        .iter()
+        .flat_map(|log| log.deltas.clone())
+        .collect();
+    unapplied_deltas.extend_from_slice(&deltas[log_deltas..]);
+
+    unapplied_deltas
+}
+
+/// A processed block whose mutations are waiting to be written.
+pub(crate) struct PendingBlock {
+    block_number: U64,
+    /// Unset for the checkpoint closing a range of blocks, which carries no deltas.
+    block_hash: Option<H256>,
+    /// Set for blocks near the head, which are journaled.
+    parent_hash: Option<H256>,
+    deltas: Vec<OwnershipDelta>,
+    /// The same deltas grouped by the log they were decoded from, for the journal.
+    logs: Vec<JournaledLog>,
+    /// The deltas not applied before a restart, collapsed and partitioned by contract once the
+    /// block is first written and keeping the writes that went through, so a retried write never
+    /// applies them twice.
+    batches: Option<Vec<DeltaBatch>>,
+    /// Number of mirrored ownership stores the deltas are applied to.
+    mirrored: usize,
+    approvals: Vec<ApprovalChange>,
+    applied_approvals: usize,
+    transfers: Vec<TransferRecord>,
+    transfers_appended: bool,
+    /// Balances of volume capped contracts queued by the block's logs.
+    reconciliations: Vec<ReconciliationEntry>,
+    /// Queue entries settled by the reconciliation deltas appended to `deltas`.
+    reconciled: Vec<String>,
+    reconciliations_queued: bool,
+    feed_appended: bool,
+    #[cfg(feature = "nats")]
+    nats_published: bool,
+    /// First block whose processing the block completes, recorded as covered before the checkpoint
+    /// moves. `None` for blocks handed off to block jobs.
+    first_covered_block: Option<U64>,
+    /// Whether the block is recorded as applied, or was found to be so already.
+    applied: bool,
+}
+
+impl PendingBlock {
+    fn new(
+        block_number: U64,
+        block_hash: Option<H256>,
+        parent_hash: Option<H256>,
+        logs: Vec<JournaledLog>,
+    ) -> Self {
+        Self {
+            block_number,
+            block_hash,
+            parent_hash,
+            deltas: logs.iter().flat_map(|log| log.deltas.clone()).collect(),
+            approvals: logs.iter().flat_map(|log| log.approvals.clone()).collect(),
+            transfers: logs.iter().flat_map(|log| log.transfers.clone()).collect(),
+            reconciliations: logs
+                .iter()
+                .flat_map(|log| log.reconciliations.clone())
+                .collect(),
+            reconciled: Vec::new(),
+            logs,
+            batches: None,
+            mirrored: 0,
+            applied_approvals: 0,
+            transfers_appended: false,
+            reconciliations_queued: false,
+            feed_appended: false,
+            #[cfg(feature = "nats")]
+            nats_published: false,
+            first_covered_block: Some(block_number),
+            applied: false,
+        }
+    }
+
+    /// Covers every block of the range the block closes, from `range_start`.
+    fn covering_from(mut self, range_start: U64) -> Self {
+        self.first_covered_block = Some(range_start);
+        self
+    }
+
+    /// Covers no block, leaving the blocks up to this one to the block jobs they were handed to.
+    fn handed_off(mut self) -> Self {
+        self.first_covered_block = None;
+        self
+    }
+
+    /// Appends the deltas of a reconciliation to the block, applied after the deltas of its logs.
+    fn with_reconciliation(
+        mut self,
+        reconciliation: Option<(Vec<OwnershipDelta>, Vec<String>)>,
+    ) -> Self {
+        if let Some((deltas, reconciled)) = reconciliation {
+            self.deltas.extend(deltas);
+            self.reconciled = reconciled;
+        }
+        self
+    }
+
+    /// Share of the write buffer taken by the block. Blocks without deltas count as one so the
+    /// buffer stays bounded.
+    fn weight(&self) -> usize {
+        if self
+            .batches
+            .as_ref()
+            .is_some_and(|batches| batches.iter().all(DeltaBatch::is_written))
+        {
+            1
+        } else {
+            self.deltas.len().max(1)
+        }
+    }
+}
+
+/// Records a transfer decoded from a log, `None` for logs of pending blocks.
+pub(crate) fn transfer_record(
+    log: &Log,
+    position: usize,
+    transfer: Transfer,
+) -> Option<TransferRecord> {
+    let block_hash = log.block_hash?;
+    let log_index = log.log_index?.as_u64() as i64;
+    let quantity = transfer.quantity();
+
+    Some(TransferRecord {
+        id: format!("{:#x}:{}:{}", block_hash, log_index, position),
+        transaction_hash: log.transaction_hash?,
+        log_index,
+        block_number: log.block_number?.as_u64() as i64,
+        block_hash,
+        contract_address: transfer.contract_address,
+        token_type: transfer.token_type,
+        token_id: transfer.token_id,
+        from: transfer.from,
+        to: transfer.to,
+        quantity,
+        amount: Some(Amount::from(transfer.amount)),
+        removed: false,
+    })
+}
+
+/// Sleeps until the head tracker reports a new block, or for at most 5 seconds.
+async fn wait_for_new_block(new_block: &Notify) {
+    tokio::select! {
+        _ = new_block.notified() => {}
+        _ = sleep(Duration::from_millis(5000)) => {}
+    }
+}
+
+/// Whether the provider rejected an `eth_getLogs` range for matching too many logs.
+pub(crate) fn is_too_many_results(error: &web3::Error) -> bool {
+    let message = match error {
+        web3::Error::Rpc(error) => error.message.to_lowercase(),
+        _ => return false,
+    };
+
+    [
+        "query returned more than",
+        "response size exceeded",
+        "response size is larger",
+        "too many results",
+        "exceeds max results",
+        "block range is too large",
+        "range too large",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
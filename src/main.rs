@@ -1,11 +1,38 @@
-pub mod lib;
-use clap::Parser;
-use lib::Worker;
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
+use mongodb::options::{ReadConcern, ReadPreference, WriteConcern};
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(feature = "simulation")]
+use token_ownership_worker::SimulationMode;
+use token_ownership_worker::{
+    default_worker_id, json_schema, openapi_document, parse_decimals_override, parse_read_concern,
+    parse_read_preference, parse_write_concern, protobuf_definitions, read_address_file,
+    read_token_id_rules, write_acquisitions_csv, write_holders, ApiKey, AssertionSigner,
+    AttestationSigner, BackupDestination, BackupFormat, CapacityEstimate, ClickHouseSink,
+    ContractAllowlist, ContractFilter, CoverageReport, CronSchedule, ExportFormat, FieldCase,
+    HolderColumn, KafkaFormat, KafkaProducer, NatsPublisher, ObjectStorage, OwnerHolding,
+    OwnerProtection, OwnerProtectionMode, OwnershipChanges, OwnershipSchema, OwnershipStore,
+    PostgresStore, RetryPolicy, Role, SnapshotHolding, SnapshotSource, SqliteStore,
+    StandaloneWorker, SyncStatus, Worker, WorkerConfig, ZeroBalancePolicy,
+    DEFAULT_ESTIMATE_SAMPLES, DEFAULT_HOLDER_COLUMNS, DEFAULT_IPFS_GATEWAY,
+    MAINNET_OPENSEA_SHARED_STOREFRONT, MAINNET_WETH,
+};
+use web3::types::H160;
 
 /// Token ownership model builder
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// MongoDB Database host
     #[clap(
         short,
@@ -25,13 +52,1580 @@ struct Args {
         default_value = "https://mainnet.infura.io/v3/58b6195ca6e942b9b3e4d539e352b9e6"
     )]
     rpc: String,
+
+    /// Second Ethereum JSON RPC endpoint, from an independent provider, every fetched log is checked against
+    #[clap(long)]
+    quorum_rpc: Option<String>,
+
+    /// Ethereum WebSocket endpoint used to subscribe to new heads and logs instead of polling
+    #[clap(long)]
+    ws: Option<String>,
+
+    /// Number of recent blocks journaled for reorg rollbacks
+    #[clap(long, default_value_t = 64)]
+    reorg_depth: u64,
+
+    /// Raise an alarm when a reorg rolls back more blocks than this confirmation depth
+    #[clap(long)]
+    reorg_alarm_depth: Option<u64>,
+
+    /// Pause the logs worker after a reorg raising an alarm, until it is resumed through the API
+    #[clap(long, requires = "reorg-alarm-depth")]
+    pause_on_reorg_alarm: bool,
+
+    /// Maximum number of blocks fetched by a single eth_getLogs call while catching up
+    #[clap(long, default_value_t = 2000)]
+    log_range_size: u64,
+
+    /// Number of eth_getLogs ranges fetched and decoded concurrently while catching up
+    #[clap(long, default_value_t = 1)]
+    backfill_concurrency: usize,
+
+    /// Port of the HTTP API, disabled when not set
+    #[clap(long)]
+    api_port: Option<u16>,
+
+    /// Control API key given as name:role:key, where role is viewer, operator or admin
+    #[clap(long = "api-key", multiple_occurrences = true)]
+    api_keys: Vec<ApiKey>,
+
+    /// Require an API key of any role on every endpoint of the API but the health checks, not only on the control API
+    #[clap(long)]
+    require_api_key: bool,
+
+    /// Requests per second allowed to each API key without a rate limit of its own, unlimited when not set
+    #[clap(long)]
+    api_rate_limit: Option<f64>,
+
+    /// Requests an API key may make at once before its rate limit applies
+    #[clap(long, default_value_t = 20)]
+    api_rate_burst: u32,
+
+    /// Key the answers of the ownership verification endpoint are signed with, disabled when not set
+    #[clap(long, env = "VERIFICATION_KEY", hide_env_values = true)]
+    verification_key: Option<String>,
+
+    /// Hex encoded secp256k1 private key the answers of the ownership verification endpoint are attested with as EIP-191 messages
+    #[clap(long, env = "ATTESTATION_KEY", hide_env_values = true)]
+    attestation_key: Option<String>,
+
+    /// Blocks the worker may lag behind the chain head before /readyz reports it as not ready
+    #[clap(long, default_value_t = 50)]
+    max_ready_lag: u64,
+
+    /// Number of blocks kept in the delta feed for differential sync, disabled when not set
+    #[clap(long)]
+    delta_feed_retention: Option<u64>,
+
+    /// Maximum number of ownership deltas buffered in memory while MongoDB is unavailable
+    #[clap(long, default_value_t = 100000)]
+    write_buffer_size: usize,
+
+    /// Number of tasks writing the deltas of a block concurrently, each for the contracts whose address hashes to it
+    #[clap(long, default_value_t = 1)]
+    writer_tasks: usize,
+
+    /// Index only the blocks near the head and hand older blocks off as block jobs to work-jobs processes
+    #[clap(long)]
+    priority_head: bool,
+
+    /// Take over from the worker indexing the database, which stops once it handed the checkpoint over at a block boundary, for upgrades without downtime
+    #[clap(long)]
+    handoff: bool,
+
+    /// Number of blocks per job handed off by --priority-head
+    #[clap(long, default_value_t = 10000)]
+    block_job_size: u64,
+
+    /// Wrapped token contract whose Deposit and Withdrawal events are applied as mints and burns, can be repeated
+    #[clap(long = "wrapped-token", multiple_occurrences = true, default_value = MAINNET_WETH)]
+    wrapped_tokens: Vec<H160>,
+
+    /// Shared ERC1155 contract whose token ids encode their creator, recorded as the creator of its ownerships, can be repeated
+    #[clap(long = "shared-contract", multiple_occurrences = true, default_value = MAINNET_OPENSEA_SHARED_STOREFRONT)]
+    shared_contracts: Vec<H160>,
+
+    /// Index Approval and ApprovalForAll events into the approvals collection
+    #[clap(long)]
+    index_approvals: bool,
+
+    /// Append every decoded transfer to the transfers collection
+    #[clap(long)]
+    transfer_history: bool,
+
+    /// Archive every log matching the contract filter, as the provider returned it, in the raw_logs collection
+    #[clap(long)]
+    raw_log_archive: bool,
+
+    /// Record the balance each block leaves every owner it changed in the balance_history collection
+    #[clap(long)]
+    balance_history: bool,
+
+    /// Number of logs a contract may emit in a block before its balances are reconciled instead of following its events
+    #[clap(long)]
+    event_volume_cap: Option<u64>,
+
+    /// Number of blocks between reconciliations of the balances of volume capped contracts
+    #[clap(long, default_value_t = 100)]
+    reconciliation_interval: u64,
+
+    /// Fetch the name, symbol and decimals of classified contracts and the URI of minted NFTs into the token_metadata collection
+    #[clap(long)]
+    token_metadata: bool,
+
+    /// Download the JSON documents the URIs of minted NFTs point to, including ipfs:// URIs, along with their metadata
+    #[clap(long, requires = "token-metadata")]
+    resolve_token_uris: bool,
+
+    /// IPFS gateway token URIs are fetched from, tried in the given order, can be repeated
+    #[clap(long = "ipfs-gateway", multiple_occurrences = true, default_value = DEFAULT_IPFS_GATEWAY)]
+    ipfs_gateways: Vec<String>,
+
+    /// Size in bytes above which token URI documents are not downloaded
+    #[clap(long, default_value_t = 1048576)]
+    metadata_document_size_limit: usize,
+
+    /// Endpoint of an IPFS Pinning Service API to pin the IPFS content of resolved token URIs and images with
+    #[clap(long, requires = "resolve-token-uris")]
+    pinning_service: Option<String>,
+
+    /// Access token of the pinning service
+    #[clap(long, env = "PINNING_TOKEN", hide_env_values = true)]
+    pinning_token: Option<String>,
+
+    /// PostgreSQL connection URL the ownership model is mirrored to
+    #[clap(long, env = "POSTGRES_URL", hide_env_values = true)]
+    postgres: Option<String>,
+
+    /// Index the ownership model into the --postgres database, or the --sqlite file, alone, without MongoDB
+    #[clap(long)]
+    standalone: bool,
+
+    /// Number of contract classifications cached in memory, 0 disables the cache
+    #[clap(long, default_value_t = 10000)]
+    classification_cache_size: usize,
+
+    /// Seconds a cached contract classification is used before it is read from MongoDB again
+    #[clap(long, default_value_t = 60)]
+    classification_cache_ttl: u64,
+
+    /// Milliseconds above which a MongoDB command or RPC call is logged as slow and listed by the status endpoint
+    #[clap(long, default_value_t = 1000)]
+    slow_operation_ms: u64,
+
+    /// Milliseconds before the first retry of a failed MongoDB operation, doubled after every further failure
+    #[clap(long, default_value_t = 500)]
+    storage_retry_delay_ms: u64,
+
+    /// Longest milliseconds between two retries of a failed MongoDB operation
+    #[clap(long, default_value_t = 30000)]
+    storage_retry_max_delay_ms: u64,
+
+    /// Skip probing the RPC provider on startup and fetch logs in ranges of --log-range-size blocks as given
+    #[clap(long)]
+    skip_provider_detection: bool,
+
+    /// Apply blocks without MongoDB transactions, even when MongoDB is a replica set
+    #[clap(long)]
+    disable_block_transactions: bool,
+
+    /// Refuse to start on a database written by an older worker instead of migrating its data model
+    #[clap(long)]
+    disable_schema_migrations: bool,
+
+    /// Decimals the API and exports render the amounts of a contract with, as <contract>=<decimals>, instead of its decimals(), can be repeated
+    #[clap(long = "decimals-override", multiple_occurrences = true, parse(try_from_str = parse_decimals_override))]
+    decimals_overrides: Vec<(H160, u32)>,
+
+    /// Write concern of the live worker: majority, a number of members or a custom write concern, the connection string's by default
+    #[clap(long, parse(try_from_str = parse_write_concern))]
+    write_concern: Option<WriteConcern>,
+
+    /// Write concern of backfills, block jobs and dead letter retries, --write-concern by default
+    #[clap(long, parse(try_from_str = parse_write_concern))]
+    backfill_write_concern: Option<WriteConcern>,
+
+    /// Read concern of every read: local, available, majority or linearizable, the connection string's by default
+    #[clap(long, parse(try_from_str = parse_read_concern))]
+    read_concern: Option<ReadConcern>,
+
+    /// Members the HTTP API and reports read from: primary, primaryPreferred, secondary, secondaryPreferred or nearest, the connection string's by default
+    #[clap(long, parse(try_from_str = parse_read_preference))]
+    read_preference: Option<ReadPreference>,
+
+    /// Maximum number of connections to each MongoDB server, the connection string's by default
+    #[clap(long)]
+    max_pool_size: Option<u32>,
+
+    /// Minimum number of connections kept open to each MongoDB server, the connection string's by default
+    #[clap(long)]
+    min_pool_size: Option<u32>,
+
+    /// Redis URL contract classifications are cached in instead of memory, shared by every worker process
+    #[clap(long, env = "REDIS_URL", hide_env_values = true)]
+    redis: Option<String>,
+
+    /// HTTP interface of the ClickHouse server the transfers of final blocks are exported to, such as http://localhost:8123
+    #[clap(long, requires = "transfer-history")]
+    clickhouse_url: Option<String>,
+
+    /// ClickHouse table the transfers are exported to, qualified with its database unless it is in the default one
+    #[clap(long, default_value = "transfers")]
+    clickhouse_table: String,
+
+    /// ClickHouse user the transfers are exported as
+    #[clap(long, env = "CLICKHOUSE_USER")]
+    clickhouse_user: Option<String>,
+
+    /// Password of the ClickHouse user
+    #[clap(long, env = "CLICKHOUSE_PASSWORD", hide_env_values = true)]
+    clickhouse_password: Option<String>,
+
+    /// Transfers inserted into ClickHouse per INSERT
+    #[clap(long, default_value_t = 10000)]
+    clickhouse_batch_size: usize,
+
+    /// Comma separated host:port addresses of the Kafka brokers the delta feed is published to
+    #[clap(long, requires_all = &["kafka-topic", "delta-feed-retention"])]
+    kafka_brokers: Option<String>,
+
+    /// Kafka topic the delta feed, and the transfer history with --transfer-history, is published to
+    #[clap(long, requires = "kafka-brokers")]
+    kafka_topic: Option<String>,
+
+    /// Encoding of the Kafka messages: json or avro
+    #[clap(long, default_value = "json")]
+    kafka_format: KafkaFormat,
+
+    /// Comma separated nats://[user:password@|token@]host:port URLs of the NATS servers the deltas of every log are published to
+    #[clap(long)]
+    nats_url: Option<String>,
+
+    /// Chain the NATS subjects are named after, as ownership.<chain>.<contract address>
+    #[clap(long, default_value = "mainnet")]
+    nats_chain: String,
+
+    /// JetStream stream the deltas are published to, created to capture ownership.<chain>.> when missing
+    #[clap(long, default_value = "OWNERSHIP")]
+    nats_stream: String,
+
+    /// Port of the gRPC service described by proto/ownership.proto, disabled when not set
+    #[clap(long)]
+    grpc_port: Option<u16>,
+
+    /// Bucket and prefix backups of the ownership model are uploaded to, as s3://<bucket>/<prefix> or gs://<bucket>/<prefix>
+    #[clap(long, requires_all = &["backup-access-key-id", "backup-secret-access-key"])]
+    backup_destination: Option<BackupDestination>,
+
+    /// Cron schedule of the backups in UTC, such as "0 3 * * *" or @daily, backups are only taken by the backup command when unset
+    #[clap(long, requires = "backup-destination")]
+    backup_schedule: Option<CronSchedule>,
+
+    /// Format of the backed up collections: bson or parquet
+    #[clap(long, default_value = "bson")]
+    backup_format: BackupFormat,
+
+    /// S3 compatible endpoint backups are uploaded to instead of the provider's, such as a MinIO server
+    #[clap(long)]
+    backup_endpoint: Option<String>,
+
+    /// Region of the backup bucket, us-east-1 on S3 and auto on GCS by default
+    #[clap(long)]
+    backup_region: Option<String>,
+
+    /// Access key id of the backup bucket, an HMAC key on GCS
+    #[clap(long, env = "BACKUP_ACCESS_KEY_ID", hide_env_values = true)]
+    backup_access_key_id: Option<String>,
+
+    /// Secret access key of the backup bucket, the secret of the HMAC key on GCS
+    #[clap(long, env = "BACKUP_SECRET_ACCESS_KEY", hide_env_values = true)]
+    backup_secret_access_key: Option<String>,
+
+    /// SQLite file the ownership model is mirrored to, created when missing
+    #[clap(long)]
+    sqlite: Option<PathBuf>,
+
+    /// Prefix of the name of every collection, such as mainnet_, so workers indexing other chains or environments can share a database
+    #[clap(long, default_value = "")]
+    collection_prefix: String,
+
+    /// Collection the ownership model is stored in, after the collection prefix
+    #[clap(long, default_value = "token_ownerships")]
+    ownership_collection: String,
+
+    /// Case of the ownership document fields, snake or camel
+    #[clap(long, default_value = "snake")]
+    field_case: FieldCase,
+
+    /// Stored name of an ownership document field as field=name, e.g. owner=holder, can be repeated
+    #[clap(long = "field-name", multiple_occurrences = true)]
+    field_names: Vec<String>,
+
+    /// Leave worker specific fields such as custody out of the ownership documents
+    #[clap(long)]
+    schema_compat: bool,
+
+    /// What becomes of ERC20 and ERC1155 ownerships whose balance drops to zero: keep, delete or archive
+    #[clap(long, default_value = "keep")]
+    zero_balance_policy: ZeroBalancePolicy,
+
+    /// Seconds between cleanups of the ownerships left with a zero balance, which are pruned as soon as their balance drops to zero when not set
+    #[clap(long)]
+    zero_balance_cleanup_interval: Option<u64>,
+
+    /// Largest float quantity counted as a zero balance with --schema-compat, whose quantities can keep rounding residue
+    #[clap(long, default_value_t = 0.0)]
+    zero_balance_tolerance: f64,
+
+    /// Seconds between refreshes of the largest holders of every contract served by /contracts/{contract}/top-holders, disabled when not set
+    #[clap(long)]
+    top_holders_refresh_interval: Option<u64>,
+
+    /// Number of holders materialized per contract by the top holders refresh, the largest limit of /contracts/{contract}/top-holders
+    #[clap(long, default_value_t = 100)]
+    top_holders_size: i64,
+
+    /// File of contract addresses, one per line, to restrict indexing to
+    #[clap(long, conflicts_with = "contract-allowlist-registered")]
+    contract_allowlist: Option<String>,
+
+    /// Restrict indexing to the contracts registered in the contract_addresses collection
+    #[clap(long)]
+    contract_allowlist_registered: bool,
+
+    /// File of contract addresses, one per line, whose logs are ignored
+    #[clap(long)]
+    contract_denylist: Option<String>,
+
+    /// File of token id ranges to include or exclude per contract, one `<contract> include|exclude <from>[-<to>]` rule per line
+    #[clap(long)]
+    token_id_rules: Option<String>,
+
+    /// How owner addresses are stored: plain, hash or encrypt
+    #[clap(long, default_value = "plain")]
+    owner_protection: OwnerProtectionMode,
+
+    /// Key used by the hash and encrypt owner protection modes
+    #[clap(long, env = "OWNER_PROTECTION_KEY", hide_env_values = true)]
+    owner_protection_key: Option<String>,
+
+    /// URL every contract classified for the first time is posted to as JSON
+    #[cfg(feature = "webhooks")]
+    #[clap(long)]
+    contract_discovery_webhook: Option<String>,
+
+    /// Chance of failing an RPC call
+    #[cfg(feature = "chaos")]
+    #[clap(long, default_value_t = 0.0)]
+    chaos_rpc_failure_rate: f64,
+
+    /// Chance of timing out a MongoDB operation
+    #[cfg(feature = "chaos")]
+    #[clap(long, default_value_t = 0.0)]
+    chaos_mongo_timeout_rate: f64,
+
+    /// Chance of failing an ownership store operation of the standalone worker, before or after it went through
+    #[cfg(feature = "chaos")]
+    #[clap(long, default_value_t = 0.0)]
+    chaos_store_failure_rate: f64,
+
+    /// Chance of duplicating a fetched log
+    #[cfg(feature = "chaos")]
+    #[clap(long, default_value_t = 0.0)]
+    chaos_duplicate_log_rate: f64,
+
+    /// Chance of orphaning a processed block
+    #[cfg(feature = "chaos")]
+    #[clap(long, default_value_t = 0.0)]
+    chaos_reorg_rate: f64,
+
+    /// File the responses of the RPC providers are appended to
+    #[cfg(feature = "simulation")]
+    #[clap(long, conflicts_with = "replay-rpc")]
+    record_rpc: Option<PathBuf>,
+
+    /// File of recorded RPC responses the providers are replaced with
+    #[cfg(feature = "simulation")]
+    #[clap(long, conflicts_with = "ws")]
+    replay_rpc: Option<PathBuf>,
+}
+
+/// Commands other than running the worker, which is the default
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the worker, the same as giving no command
+    Run,
+    /// Show the lag behind the chain head in blocks and time, the recent throughput of the live worker and the number of documents of every collection
+    Status {
+        /// Seconds of recently applied blocks the throughput is measured over
+        #[clap(long, default_value_t = 300)]
+        throughput_window_seconds: u64,
+    },
+    /// Print the current holders of a contract, largest first, as a table or as JSON
+    Holders {
+        /// Contract whose holders are printed
+        contract: H160,
+
+        /// Smallest quantity held by the holders printed, in the smallest unit of the token
+        #[clap(long)]
+        min: Option<f64>,
+
+        /// Number of holders printed, every holder by default
+        #[clap(long)]
+        limit: Option<i64>,
+
+        /// Print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print what an owner currently holds, across every contract or of one, as a table or as JSON
+    Balance {
+        /// Owner whose holdings are printed
+        owner: H160,
+
+        /// Contract to restrict the holdings to
+        #[clap(long)]
+        contract: Option<H160>,
+
+        /// Print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+    /// Compare the stored ownerships of a contract with the balances it reports at the last processed block
+    Verify {
+        /// Contract whose ownerships are compared
+        #[clap(long)]
+        contract: H160,
+
+        /// Number of ownerships compared, every ownership with a positive amount by default
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// Drop everything stored about a contract but its classification and index its logs again up to the last processed block, with the live worker stopped
+    Reset {
+        /// Contract to reset
+        #[clap(long)]
+        contract: H160,
+
+        /// First block indexed again, the first block the worker indexes by default
+        #[clap(long)]
+        from_block: Option<u64>,
+    },
+    /// Register the token type of a contract, overriding its classification
+    Classify {
+        /// Contract to register
+        #[clap(long)]
+        contract: H160,
+
+        /// Token type of the contract: ERC20, ERC721 or ERC1155
+        #[clap(long)]
+        token_type: String,
+
+        /// Block before which the logs of the contract are ignored
+        #[clap(long)]
+        start_block: Option<u64>,
+    },
+    /// Reprocess a block range without touching the live worker's checkpoint
+    Backfill {
+        /// First block to reprocess
+        #[clap(long)]
+        from: u64,
+
+        /// Last block to reprocess
+        #[clap(long)]
+        to: u64,
+
+        /// Contract address to restrict the backfill to, can be repeated
+        #[clap(long = "contract", multiple_occurrences = true)]
+        contracts: Vec<H160>,
+    },
+    /// Decode the logs recorded in dead_letters again after a decoder fix, applying the ones that decode now
+    RetryDeadLetters,
+    /// Revert the journaled blocks after a block and move the checkpoint back to it, with the live worker stopped
+    Rollback {
+        /// Last block kept, the blocks after it are indexed again
+        #[clap(long)]
+        to_block: u64,
+    },
+    /// Mint an API key and print it, it cannot be shown again
+    CreateApiKey {
+        /// Name the key is recorded and revoked by
+        #[clap(long)]
+        name: String,
+
+        /// Role granted by the key: viewer, operator or admin
+        #[clap(long, default_value = "viewer")]
+        role: Role,
+
+        /// Requests per second allowed to the key, --api-rate-limit by default
+        #[clap(long)]
+        rate_limit: Option<f64>,
+    },
+    /// Revoke an API key minted with create-api-key
+    RevokeApiKey {
+        /// Name of the key
+        #[clap(long)]
+        name: String,
+    },
+    /// List the API keys minted with create-api-key
+    ApiKeys,
+    /// Split a block range into jobs that processes started with work-jobs claim and backfill
+    EnqueueJobs {
+        /// First block to backfill
+        #[clap(long)]
+        from: u64,
+
+        /// Last block to backfill
+        #[clap(long)]
+        to: u64,
+
+        /// Number of blocks per job
+        #[clap(long, default_value_t = 10000)]
+        job_size: u64,
+
+        /// Contract address to restrict the jobs to, can be repeated
+        #[clap(long = "contract", multiple_occurrences = true)]
+        contracts: Vec<H160>,
+    },
+    /// Project the documents, storage and time indexing a block range takes from samples of its logs, without writing anything
+    Estimate {
+        /// First block of the range
+        #[clap(long)]
+        from_block: u64,
+
+        /// Last block of the range
+        #[clap(long)]
+        to_block: u64,
+
+        /// File of contract addresses, one per line, to restrict the estimate to
+        #[clap(long)]
+        contracts: Option<PathBuf>,
+
+        /// Number of block ranges sampled, spread evenly over the range
+        #[clap(long, default_value_t = DEFAULT_ESTIMATE_SAMPLES)]
+        samples: u64,
+
+        /// Number of blocks of a sampled range, --log-range-size by default
+        #[clap(long)]
+        sample_size: Option<u64>,
+    },
+    /// Write the holders of a contract at a past block, from the transfer history, to a file of JSON lines
+    Snapshot {
+        /// Contract whose holders are written
+        #[clap(long)]
+        contract: H160,
+
+        /// Block after which the holdings are taken
+        #[clap(long)]
+        block: u64,
+
+        /// Replay the transfer history from genesis instead of undoing the transfers after the block
+        #[clap(long)]
+        replay: bool,
+
+        /// File the holders are written to
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Write the holders of a contract, now or at a past block, to a CSV or Parquet file for analytics tools
+    Export {
+        /// Contract whose holders are written
+        #[clap(long)]
+        contract: H160,
+
+        /// Block after which the holdings are taken, as by snapshot, the current holders by default
+        #[clap(long)]
+        block: Option<u64>,
+
+        /// Replay the transfer history from genesis instead of undoing the transfers after the block
+        #[clap(long, requires = "block")]
+        replay: bool,
+
+        /// Format of the file: csv or parquet
+        #[clap(long, default_value = "csv")]
+        format: ExportFormat,
+
+        /// Comma separated columns, in order: contract_address, token_id, owner, amount, quantity and display_amount, token_id,owner,amount,display_amount by default
+        #[clap(long, use_value_delimiter = true)]
+        columns: Vec<HolderColumn>,
+
+        /// File the holders are written to
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Write the logs archived by --raw-log-archive for a range of blocks as JSON lines, in the JSON-RPC format of eth_getLogs
+    RawLogs {
+        /// First block of the range
+        #[clap(long)]
+        from_block: u64,
+
+        /// Last block of the range
+        #[clap(long)]
+        to_block: u64,
+
+        /// File the logs are written to
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Back up the ownership model to --backup-destination now, from a snapshot of the database
+    Backup,
+    /// Write the acquisitions and disposals of an owner, from the transfer history, to a CSV file for accounting tools
+    Acquisitions {
+        /// Owner whose transfers are written
+        #[clap(long)]
+        owner: H160,
+
+        /// Contract address to restrict the export to, can be repeated
+        #[clap(long = "contract", multiple_occurrences = true)]
+        contracts: Vec<H160>,
+
+        /// First block of the export
+        #[clap(long)]
+        from_block: Option<u64>,
+
+        /// Last block of the export
+        #[clap(long)]
+        to_block: Option<u64>,
+
+        /// CSV file the records are written to
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Report the block ranges processed, the gaps between them and the blocks replaced by reorgs
+    Coverage {
+        /// First block of the report, the first block the worker indexes by default
+        #[clap(long)]
+        from_block: Option<u64>,
+
+        /// Last block of the report, the last processed block by default
+        #[clap(long)]
+        to_block: Option<u64>,
+
+        /// File the report is written to as JSON
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the JSON Schema of the stored documents and of the API and webhook payloads, without connecting to MongoDB or the provider
+    Schema {
+        /// Write protobuf definitions of the same types instead
+        #[clap(long)]
+        protobuf: bool,
+
+        /// Write the OpenAPI document of the REST API instead, as served at /openapi.json
+        #[clap(long, conflicts_with = "protobuf")]
+        openapi: bool,
+
+        /// File the schema is written to
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Print the changes of the ownership model as JSON lines, tailed from a change stream of the ownership collection
+    Watch {
+        /// Include the ownerships before updates and deletes, which needs pre-images enabled on the collection
+        #[clap(long)]
+        pre_images: bool,
+
+        /// Resume token of the last change printed, as JSON, to print the changes after it
+        #[clap(long)]
+        resume_after: Option<String>,
+    },
+    /// Deploy canary token contracts on a development or test network, emit scripted transfers and check the balances the running worker stores for them
+    SelfTest {
+        /// Seconds to wait for the running worker to process the scripted transfers
+        #[clap(long, default_value_t = 120)]
+        timeout_seconds: u64,
+    },
+    /// Claim and backfill enqueued block jobs until none are left
+    WorkJobs {
+        /// Name recorded on claimed jobs, the host name and process id by default
+        #[clap(long)]
+        worker_id: Option<String>,
+
+        /// Seconds a job stays claimed without progress before other workers may claim it
+        #[clap(long, default_value_t = 600)]
+        lease_seconds: u64,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let worker = Worker::new(args.host, args.name, args.rpc).await.unwrap();
+    let owner_protection =
+        OwnerProtection::new(args.owner_protection, args.owner_protection_key).unwrap();
+
+    assert!(
+        args.event_volume_cap.is_none() || owner_protection.mode() != OwnerProtectionMode::Hash,
+        "--event-volume-cap requires owners that can be revealed, which hash owner protection does not allow"
+    );
+
+    assert!(
+        !args.raw_log_archive || owner_protection.mode() == OwnerProtectionMode::Plain,
+        "--raw-log-archive keeps the owners of the archived logs in plaintext, which owner protection does not allow"
+    );
+
+    let allowlist = match args.contract_allowlist {
+        Some(path) => Some(ContractAllowlist::Addresses(
+            read_address_file(path).unwrap(),
+        )),
+        None if args.contract_allowlist_registered => Some(ContractAllowlist::Registered),
+        None => None,
+    };
+
+    let denylist = match args.contract_denylist {
+        Some(path) => read_address_file(path).unwrap().into_iter().collect(),
+        None => Default::default(),
+    };
+
+    let token_id_rules = match args.token_id_rules {
+        Some(path) => read_token_id_rules(path).unwrap(),
+        None => Vec::new(),
+    };
+
+    let mut ownership_stores: Vec<Arc<dyn OwnershipStore>> = Vec::new();
+    if let Some(url) = &args.postgres {
+        ownership_stores.push(Arc::new(PostgresStore::connect(url).await.unwrap()));
+    }
+    if let Some(path) = &args.sqlite {
+        ownership_stores.push(Arc::new(SqliteStore::open(path).await.unwrap()));
+    }
+
+    if args
+        .api_rate_limit
+        .is_some_and(|rate_limit| rate_limit <= 0.0)
+    {
+        eprintln!("Error: --api-rate-limit has to be positive");
+        std::process::exit(1);
+    }
+
+    if args.top_holders_size < 1 {
+        eprintln!("Error: --top-holders-size has to be positive");
+        std::process::exit(1);
+    }
+
+    let config = WorkerConfig {
+        reorg_depth: args.reorg_depth,
+        reorg_alarm_depth: args.reorg_alarm_depth,
+        pause_on_reorg_alarm: args.pause_on_reorg_alarm,
+        log_range_size: args.log_range_size,
+        backfill_concurrency: args.backfill_concurrency,
+        ws_endpoint: args.ws,
+        api_port: args.api_port,
+        api_keys: args.api_keys,
+        require_api_key: args.require_api_key,
+        api_rate_limit: args.api_rate_limit,
+        api_rate_burst: args.api_rate_burst,
+        assertion_signer: args
+            .verification_key
+            .map(|key| AssertionSigner::new(key).unwrap()),
+        attestation_signer: args
+            .attestation_key
+            .map(|key| AttestationSigner::new(&key).unwrap()),
+        max_ready_lag: args.max_ready_lag,
+        delta_feed_retention: args.delta_feed_retention,
+        write_buffer_size: args.write_buffer_size,
+        writer_tasks: args.writer_tasks,
+        quorum_rpc_endpoint: args.quorum_rpc,
+        priority_head: args.priority_head,
+        handoff: args.handoff,
+        block_job_size: args.block_job_size,
+        wrapped_tokens: args.wrapped_tokens,
+        shared_contracts: args.shared_contracts,
+        index_approvals: args.index_approvals,
+        transfer_history: args.transfer_history,
+        raw_log_archive: args.raw_log_archive,
+        balance_history: args.balance_history,
+        event_volume_cap: args.event_volume_cap,
+        reconciliation_interval: args.reconciliation_interval,
+        token_metadata: args.token_metadata,
+        resolve_token_uris: args.resolve_token_uris,
+        ipfs_gateways: args.ipfs_gateways,
+        metadata_document_size_limit: args.metadata_document_size_limit,
+        pinning_service: args.pinning_service,
+        pinning_token: args.pinning_token,
+        contract_filter: ContractFilter {
+            allowlist,
+            denylist,
+            token_id_rules,
+        },
+        owner_protection,
+        ownership_schema: OwnershipSchema::new(
+            args.ownership_collection,
+            args.field_case,
+            &args.field_names,
+            args.schema_compat,
+        )
+        .unwrap(),
+        collection_prefix: args.collection_prefix,
+        zero_balance_policy: args.zero_balance_policy,
+        zero_balance_cleanup_interval: args.zero_balance_cleanup_interval.map(Duration::from_secs),
+        zero_balance_tolerance: args.zero_balance_tolerance,
+        top_holders_refresh_interval: args.top_holders_refresh_interval.map(Duration::from_secs),
+        top_holders_size: args.top_holders_size,
+        ownership_stores,
+        contract_discovery_hooks: Vec::new(),
+        classification_cache_size: args.classification_cache_size,
+        classification_cache_ttl: Duration::from_secs(args.classification_cache_ttl),
+        slow_operation_threshold: Duration::from_millis(args.slow_operation_ms),
+        storage_retry: RetryPolicy {
+            initial_delay: Duration::from_millis(args.storage_retry_delay_ms),
+            max_delay: Duration::from_millis(args.storage_retry_max_delay_ms),
+        },
+        detect_provider_capabilities: !args.skip_provider_detection,
+        block_transactions: !args.disable_block_transactions,
+        schema_migrations: !args.disable_schema_migrations,
+        decimals_overrides: args.decimals_overrides,
+        write_concern: args.write_concern,
+        backfill_write_concern: args.backfill_write_concern,
+        read_concern: args.read_concern,
+        read_preference: args.read_preference,
+        max_pool_size: args.max_pool_size,
+        min_pool_size: args.min_pool_size,
+        redis_url: args.redis,
+        backup_storage: args.backup_destination.map(|destination| {
+            ObjectStorage::new(
+                destination,
+                args.backup_endpoint,
+                args.backup_region,
+                args.backup_access_key_id.unwrap_or_default(),
+                args.backup_secret_access_key.unwrap_or_default(),
+            )
+            .unwrap()
+        }),
+        backup_schedule: args.backup_schedule,
+        backup_format: args.backup_format,
+        kafka: args
+            .kafka_brokers
+            .zip(args.kafka_topic)
+            .map(|(brokers, topic)| KafkaProducer::new(&brokers, topic, args.kafka_format)),
+        nats: args.nats_url.map(|url| {
+            NatsPublisher::new(&url, args.nats_chain, args.nats_stream).unwrap_or_else(|error| {
+                eprintln!("Error: {}", error);
+                std::process::exit(1);
+            })
+        }),
+        grpc_port: args.grpc_port,
+        clickhouse: args.clickhouse_url.map(|url| {
+            ClickHouseSink::new(
+                url,
+                args.clickhouse_table,
+                args.clickhouse_user,
+                args.clickhouse_password,
+                args.clickhouse_batch_size,
+            )
+        }),
+        #[cfg(feature = "webhooks")]
+        contract_discovery_webhook: args.contract_discovery_webhook,
+        #[cfg(feature = "chaos")]
+        chaos: token_ownership_worker::chaos::ChaosConfig {
+            rpc_failure_rate: args.chaos_rpc_failure_rate,
+            mongo_timeout_rate: args.chaos_mongo_timeout_rate,
+            store_failure_rate: args.chaos_store_failure_rate,
+            duplicate_log_rate: args.chaos_duplicate_log_rate,
+            reorg_rate: args.chaos_reorg_rate,
+        },
+        #[cfg(feature = "simulation")]
+        simulation: match (args.record_rpc, args.replay_rpc) {
+            (Some(path), _) => Some(SimulationMode::Record(path)),
+            (None, Some(path)) => Some(SimulationMode::Replay(path)),
+            (None, None) => None,
+        },
+    };
+
+    if let Some(Command::Schema {
+        protobuf,
+        openapi,
+        output,
+    }) = &args.command
+    {
+        let mut file = BufWriter::new(File::create(output).unwrap());
+        if *protobuf {
+            write!(file, "{}", protobuf_definitions(&config)).unwrap();
+        } else if *openapi {
+            serde_json::to_writer_pretty(&mut file, &openapi_document(&config)).unwrap();
+            writeln!(file).unwrap();
+        } else {
+            serde_json::to_writer_pretty(&mut file, &json_schema(&config)).unwrap();
+            writeln!(file).unwrap();
+        }
+        file.flush().unwrap();
+
+        println!("Wrote the schema to {}", output.display());
+        return;
+    }
+
+    if let Some(Command::Watch {
+        pre_images,
+        resume_after,
+    }) = &args.command
+    {
+        let mut changes =
+            OwnershipChanges::connect(args.host.clone(), args.name.clone(), Duration::from_secs(1))
+                .await
+                .unwrap()
+                .with_collection_prefix(&config.collection_prefix)
+                .with_ownership_schema(config.ownership_schema.clone());
+        if *pre_images {
+            changes = changes.with_pre_images();
+        }
+
+        let resume_token = resume_after
+            .as_ref()
+            .map(|resume_token| serde_json::from_str(resume_token).unwrap());
+
+        let mut stdout = std::io::stdout();
+        let mut changes = Box::pin(changes.changes(resume_token));
+        while let Some(change) = changes.next().await {
+            serde_json::to_writer(&mut stdout, &change).unwrap();
+            writeln!(stdout).unwrap();
+        }
+        return;
+    }
+
+    if args.standalone {
+        assert!(
+            matches!(args.command, None | Some(Command::Run)),
+            "--standalone only runs the worker, the other commands read MongoDB"
+        );
+
+        let store: Arc<dyn OwnershipStore> = match (&args.postgres, &args.sqlite) {
+            (Some(url), None) => Arc::new(PostgresStore::connect(url).await.unwrap()),
+            (None, Some(path)) => Arc::new(SqliteStore::open(path).await.unwrap()),
+            _ => panic!("--standalone indexes into either --postgres or --sqlite"),
+        };
+
+        StandaloneWorker::new(args.rpc, store, config)
+            .await
+            .unwrap()
+            .run(None)
+            .await;
+        return;
+    }
+
+    let worker = Worker::new(args.host, args.name, args.rpc, config)
+        .await
+        .unwrap();
+
+    match args.command {
+        None | Some(Command::Run) => worker.start().await,
+        Some(Command::Status {
+            throughput_window_seconds,
+        }) => {
+            let status = worker
+                .status(Duration::from_secs(throughput_window_seconds))
+                .await
+                .unwrap();
+
+            print_status(&status, throughput_window_seconds);
+        }
+        Some(Command::Holders {
+            contract,
+            min,
+            limit,
+            json,
+        }) => {
+            let (block_number, holdings) = worker
+                .largest_holders(contract, min.unwrap_or_default(), limit)
+                .await
+                .unwrap();
+
+            if json {
+                let holders = json!({
+                    "contract_address": contract,
+                    "block_number": block_number,
+                    "holders": holdings,
+                });
+                println!("{}", serde_json::to_string_pretty(&holders).unwrap());
+            } else {
+                print_holders(contract, block_number, &holdings);
+            }
+        }
+        Some(Command::Balance {
+            owner,
+            contract,
+            json,
+        }) => {
+            let (block_number, holdings) = worker.owner_holdings(owner, contract).await.unwrap();
+
+            if json {
+                let balance = json!({
+                    "owner": owner,
+                    "block_number": block_number,
+                    "holdings": holdings,
+                });
+                println!("{}", serde_json::to_string_pretty(&balance).unwrap());
+            } else {
+                print_owner_holdings(owner, block_number, &holdings);
+            }
+        }
+        Some(Command::Verify { contract, limit }) => {
+            let verification = worker.verify(contract, limit).await.unwrap();
+
+            for mismatch in &verification.mismatches {
+                eprintln!(
+                    "Mismatch: token {} of {:#x} stored {} on chain {}",
+                    mismatch.token_id.as_deref().unwrap_or("-"),
+                    mismatch.owner,
+                    mismatch.stored,
+                    mismatch.on_chain
+                );
+            }
+
+            println!(
+                "Checked {} {} ownerships of {:#x} at block {}: {} mismatches, {} unanswered",
+                verification.checked,
+                verification.token_type,
+                verification.contract_address,
+                verification.block_number,
+                verification.mismatches.len(),
+                verification.unanswered
+            );
+
+            if !verification.mismatches.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Reset {
+            contract,
+            from_block,
+        }) => {
+            let to_block = worker.reset_contract(contract, from_block).await.unwrap();
+            println!(
+                "Reset {:#x} and indexed it again up to block {}",
+                contract, to_block
+            );
+        }
+        Some(Command::Classify {
+            contract,
+            token_type,
+            start_block,
+        }) => {
+            let contract_address = worker
+                .register_contract(contract, token_type, start_block)
+                .await
+                .unwrap();
+            println!(
+                "Registered {:#x} as {}",
+                contract_address.address, contract_address.token_type
+            );
+        }
+        Some(Command::Backfill {
+            from,
+            to,
+            contracts,
+        }) => worker.backfill(from, to, contracts).await.unwrap(),
+        Some(Command::RetryDeadLetters) => {
+            let retry = worker.retry_dead_letters().await.unwrap();
+            println!(
+                "Applied {} dead letters, {} still cannot be decoded and {} of orphaned blocks were dropped",
+                retry.applied, retry.failed, retry.orphaned
+            );
+        }
+        Some(Command::Rollback { to_block }) => {
+            let reverted = worker.rollback(to_block).await.unwrap();
+            println!(
+                "Rolled back {} blocks, resuming after block {}",
+                reverted, to_block
+            );
+        }
+        Some(Command::CreateApiKey {
+            name,
+            role,
+            rate_limit,
+        }) => {
+            let key = worker.create_api_key(name, role, rate_limit).await.unwrap();
+            println!("{}", key);
+        }
+        Some(Command::RevokeApiKey { name }) => {
+            if !worker.revoke_api_key(name.clone()).await.unwrap() {
+                eprintln!("No unrevoked API key is named {}", name);
+                std::process::exit(1);
+            }
+            println!("Revoked API key {}", name);
+        }
+        Some(Command::ApiKeys) => {
+            for api_key in worker.api_keys().await.unwrap() {
+                let rate_limit = api_key.rate_limit.map_or_else(
+                    || "default".to_string(),
+                    |rate_limit| format!("{}/s", rate_limit),
+                );
+                let state = match api_key.revoked_at {
+                    Some(revoked_at) => format!("revoked {}", revoked_at.to_rfc3339_string()),
+                    None => "active".to_string(),
+                };
+
+                println!(
+                    "{}\t{:?}\t{}\tcreated {}\t{}",
+                    api_key.name,
+                    api_key.role,
+                    rate_limit,
+                    api_key.created_at.to_rfc3339_string(),
+                    state
+                );
+            }
+        }
+        Some(Command::EnqueueJobs {
+            from,
+            to,
+            job_size,
+            contracts,
+        }) => {
+            worker
+                .enqueue_block_jobs(from, to, job_size, contracts)
+                .await
+                .unwrap();
+        }
+        Some(Command::Estimate {
+            from_block,
+            to_block,
+            contracts,
+            samples,
+            sample_size,
+        }) => {
+            let contracts = match contracts {
+                Some(path) => read_address_file(path).unwrap(),
+                None => Vec::new(),
+            };
+
+            let estimate = worker
+                .estimate(from_block, to_block, contracts, samples, sample_size)
+                .await
+                .unwrap();
+
+            print_estimate(&estimate);
+        }
+        Some(Command::Snapshot {
+            contract,
+            block,
+            replay,
+            output,
+        }) => {
+            let source = if replay {
+                SnapshotSource::Replay
+            } else {
+                SnapshotSource::Rewind
+            };
+
+            let holdings = worker.snapshot(contract, block, source).await.unwrap();
+
+            let mut file = BufWriter::new(File::create(&output).unwrap());
+            writeln!(
+                file,
+                "{}",
+                json!({ "contract_address": contract, "block_number": block })
+            )
+            .unwrap();
+            for holding in &holdings {
+                writeln!(file, "{}", serde_json::to_string(holding).unwrap()).unwrap();
+            }
+            file.flush().unwrap();
+
+            println!(
+                "Wrote {} holdings of {:#x} at block {} to {}",
+                holdings.len(),
+                contract,
+                block,
+                output.display()
+            );
+        }
+        Some(Command::Export {
+            contract,
+            block,
+            replay,
+            format,
+            columns,
+            output,
+        }) => {
+            let source = if replay {
+                SnapshotSource::Replay
+            } else {
+                SnapshotSource::Rewind
+            };
+
+            let holdings = worker.holders(contract, block, source).await.unwrap();
+            let columns = if columns.is_empty() {
+                DEFAULT_HOLDER_COLUMNS.to_vec()
+            } else {
+                columns
+            };
+
+            let mut file = BufWriter::new(File::create(&output).unwrap());
+            write_holders(contract, &holdings, &columns, format, &mut file).unwrap();
+            file.flush().unwrap();
+
+            println!(
+                "Wrote {} holdings of {:#x} to {}",
+                holdings.len(),
+                contract,
+                output.display()
+            );
+        }
+        Some(Command::RawLogs {
+            from_block,
+            to_block,
+            output,
+        }) => {
+            let raw_logs = worker.archived_logs(from_block, to_block).await.unwrap();
+
+            let mut file = BufWriter::new(File::create(&output).unwrap());
+            for raw_log in &raw_logs {
+                writeln!(
+                    file,
+                    "{}",
+                    serde_json::to_string(&raw_log.to_log()).unwrap()
+                )
+                .unwrap();
+            }
+            file.flush().unwrap();
+
+            println!(
+                "Wrote {} logs of blocks {} to {} to {}",
+                raw_logs.len(),
+                from_block,
+                to_block,
+                output.display()
+            );
+        }
+        Some(Command::Backup) => {
+            let backup = worker.back_up().await.unwrap();
+
+            for (collection, count) in &backup.collections {
+                println!("Backed up {} documents of {}", count, collection);
+            }
+            println!(
+                "Backed up the ownership model after block {} to {}",
+                backup.block_number.unwrap_or_default(),
+                backup.manifest
+            );
+        }
+        Some(Command::Acquisitions {
+            owner,
+            contracts,
+            from_block,
+            to_block,
+            output,
+        }) => {
+            let records = worker
+                .acquisitions(owner, contracts, from_block, to_block)
+                .await
+                .unwrap();
+
+            let mut file = BufWriter::new(File::create(&output).unwrap());
+            write_acquisitions_csv(&records, &mut file).unwrap();
+            file.flush().unwrap();
+
+            println!(
+                "Wrote {} acquisitions and disposals of {:#x} to {}",
+                records.len(),
+                owner,
+                output.display()
+            );
+        }
+        Some(Command::Coverage {
+            from_block,
+            to_block,
+            output,
+        }) => {
+            let report = worker.coverage(from_block, to_block).await.unwrap();
+
+            print_coverage(&report);
+
+            if let Some(output) = output {
+                let mut file = BufWriter::new(File::create(&output).unwrap());
+                serde_json::to_writer_pretty(&mut file, &report).unwrap();
+                writeln!(file).unwrap();
+                file.flush().unwrap();
+
+                println!("Wrote the coverage report to {}", output.display());
+            }
+        }
+        Some(Command::SelfTest { timeout_seconds }) => {
+            let report = worker
+                .self_test(Duration::from_secs(timeout_seconds))
+                .await
+                .unwrap();
+
+            for (token_type, contract) in &report.contracts {
+                println!("Deployed {} canary {:#x}", token_type, contract);
+            }
+            for failure in &report.failures {
+                eprintln!("Alarm: {}", failure);
+            }
+
+            if !report.passed() {
+                eprintln!(
+                    "Self-test failed {} of {} checks up to block {}",
+                    report.failures.len(),
+                    report.checks,
+                    report.block_number
+                );
+                std::process::exit(1);
+            }
+
+            println!(
+                "Self-test passed {} checks up to block {}",
+                report.checks, report.block_number
+            );
+        }
+        Some(Command::Schema { .. } | Command::Watch { .. }) => unreachable!(),
+        Some(Command::WorkJobs {
+            worker_id,
+            lease_seconds,
+        }) => {
+            let worker_id = worker_id.unwrap_or_else(default_worker_id);
+
+            worker
+                .work_block_jobs(worker_id, Duration::from_secs(lease_seconds))
+                .await
+                .unwrap()
+        }
+    }
+}
+
+fn print_status(status: &SyncStatus, throughput_window_seconds: u64) {
+    println!(
+        "Last processed block {}, latest block {}",
+        status
+            .last_processed_block
+            .map_or("-".to_string(), |block_number| block_number.to_string()),
+        status.latest_block
+    );
+    println!(
+        "Lag: {} blocks, {} seconds",
+        status.lag().map_or("-".to_string(), |lag| lag.to_string()),
+        status
+            .lag_seconds
+            .map_or("-".to_string(), |lag_seconds| lag_seconds.to_string())
+    );
+    match status.blocks_per_second {
+        Some(blocks_per_second) => println!(
+            "Throughput: {:.2} blocks per second over the last {} seconds",
+            blocks_per_second, throughput_window_seconds
+        ),
+        None => println!(
+            "Throughput: no blocks applied over the last {} seconds",
+            throughput_window_seconds
+        ),
+    }
+
+    let width = status
+        .collection_counts
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or_default();
+
+    println!("Collections:");
+    for (name, count) in &status.collection_counts {
+        println!("  {:<width$}  {}", name, count, width = width);
+    }
+}
+
+fn print_holders(contract: H160, block_number: Option<u64>, holdings: &[SnapshotHolding]) {
+    println!(
+        "{} holders of {:#x} at block {}",
+        holdings.len(),
+        contract,
+        block_number.map_or("-".to_string(), |block_number| block_number.to_string())
+    );
+
+    if holdings.is_empty() {
+        return;
+    }
+
+    let token_id_width = column_width(
+        "Token",
+        holdings.iter().map(|holding| holding.token_id.as_deref()),
+    );
+    let amount_width = column_width(
+        "Amount",
+        holdings
+            .iter()
+            .map(|holding| Some(holding.amount.to_string())),
+    );
+
+    println!();
+    println!(
+        "{:<42} {:<token_id_width$} {:>amount_width$} Display amount",
+        "Owner", "Token", "Amount"
+    );
+    for holding in holdings {
+        println!(
+            "{:<42} {:<token_id_width$} {:>amount_width$} {}",
+            format!("{:#x}", holding.owner),
+            holding.token_id.as_deref().unwrap_or("-"),
+            holding.amount.to_string(),
+            holding.display_amount.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+fn print_owner_holdings(owner: H160, block_number: Option<u64>, holdings: &[OwnerHolding]) {
+    println!(
+        "{:#x} holds {} tokens at block {}",
+        owner,
+        holdings.len(),
+        block_number.map_or("-".to_string(), |block_number| block_number.to_string())
+    );
+
+    if holdings.is_empty() {
+        return;
+    }
+
+    let token_id_width = column_width(
+        "Token",
+        holdings.iter().map(|holding| holding.token_id.as_deref()),
+    );
+    let amount_width = column_width(
+        "Amount",
+        holdings
+            .iter()
+            .map(|holding| Some(holding.amount.to_string())),
+    );
+
+    println!();
+    println!(
+        "{:<42} {:<token_id_width$} {:>amount_width$} Display amount",
+        "Contract", "Token", "Amount"
+    );
+    for holding in holdings {
+        println!(
+            "{:<42} {:<token_id_width$} {:>amount_width$} {}",
+            format!("{:#x}", holding.contract_address),
+            holding.token_id.as_deref().unwrap_or("-"),
+            holding.amount.to_string(),
+            holding.display_amount.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+/// Width of a table column fitting its header and every value, absent ones printed as `-`.
+fn column_width<T: AsRef<str>>(header: &str, values: impl Iterator<Item = Option<T>>) -> usize {
+    values
+        .map(|value| value.as_ref().map_or(1, |value| value.as_ref().len()))
+        .fold(header.len(), usize::max)
+}
+
+fn print_coverage(report: &CoverageReport) {
+    println!(
+        "Blocks {} to {}, last processed block {}",
+        report.from_block,
+        report.to_block,
+        report
+            .last_processed_block
+            .map_or("-".to_string(), |block_number| block_number.to_string())
+    );
+
+    if report.contiguous {
+        println!("Every block was processed");
+    } else {
+        let missing: u64 = report
+            .gaps
+            .iter()
+            .map(|gap| gap.to_block - gap.from_block + 1)
+            .sum();
+        println!("{} blocks were not processed", missing);
+    }
+
+    println!();
+    for range in &report.indexed {
+        println!("Processed {} to {}", range.from_block, range.to_block);
+    }
+    for gap in &report.gaps {
+        println!("Gap       {} to {}", gap.from_block, gap.to_block);
+    }
+    for range in &report.restricted {
+        let contracts: Vec<_> = range
+            .contracts
+            .iter()
+            .map(|contract| format!("{:#x}", contract))
+            .collect();
+        println!(
+            "Backfilled {} to {} for {}",
+            range.from_block,
+            range.to_block,
+            contracts.join(", ")
+        );
+    }
+    for reorg in &report.reorgs {
+        println!(
+            "Reorg     {} to {}, {}",
+            reorg.from_block,
+            reorg.to_block,
+            if reorg.reindexed {
+                "processed again"
+            } else {
+                "not processed again yet"
+            }
+        );
+    }
+}
+
+fn print_estimate(estimate: &CapacityEstimate) {
+    println!(
+        "Blocks {} to {}, projected from {} sampled blocks with {} logs",
+        estimate.from_block, estimate.to_block, estimate.sampled_blocks, estimate.sampled_logs
+    );
+    println!(
+        "{} logs and {} transfers",
+        estimate.logs, estimate.transfers
+    );
+    println!();
+    println!(
+        "{:<24} {:>16} {:>12} {:>12}",
+        "Collection", "Documents", "Average", "Size"
+    );
+    for collection in &estimate.collections {
+        let bound = if collection.upper_bound { "up to " } else { "" };
+        println!(
+            "{:<24} {:>16} {:>12} {:>12}",
+            collection.collection,
+            format!("{}{}", bound, collection.documents),
+            format_size(collection.average_document_size),
+            format_size(collection.size()),
+        );
+    }
+    println!(
+        "{:<24} {:>16} {:>12} {:>12}",
+        "Total",
+        "",
+        "",
+        format_size(estimate.size())
+    );
+    println!();
+    println!(
+        "Fetching logs: {}, writing: {}, indexing: {}",
+        format_duration(estimate.fetch_time),
+        format_duration(estimate.write_time),
+        format_duration(estimate.indexing_time())
+    );
+}
+
+/// Formats a size in bytes with the largest binary unit it reaches.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
 
-    worker.start().await;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        3600..=86399 => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+        _ => format!("{}d {}h", seconds / 86400, seconds % 86400 / 3600),
+    }
 }
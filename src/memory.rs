@@ -0,0 +1,260 @@
+//! The ownership model in memory, for tests asserting the ownerships a run of the worker produces
+//! without any database.
+
+use crate::{
+    amount::Amount,
+    ownership::OwnershipDelta,
+    storage::{JournaledBlock, OwnershipStore, StorageResult},
+    store::ClassificationMethod,
+};
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Mutex, MutexGuard},
+};
+use web3::types::{H160, H256, U64};
+
+/// Contract address, token id, `None` for fungible tokens, and owner of an ownership.
+pub type OwnershipKey = (H160, Option<String>, H160);
+
+/// Contract address and token id, `None` for fungible tokens and ERC721 contracts, of a supply.
+pub type SupplyKey = (H160, Option<String>);
+
+#[derive(Debug, Default)]
+struct MemoryModel {
+    ownerships: BTreeMap<OwnershipKey, Amount>,
+    supplies: BTreeMap<SupplyKey, Amount>,
+    applied_blocks: HashSet<H256>,
+    journal: BTreeMap<U64, JournaledBlock>,
+    token_types: HashMap<H160, String>,
+    checkpoint: Option<U64>,
+}
+
+/// Keeps the ownership model in maps, applying deltas like the PostgreSQL and SQLite stores do:
+/// fungible balances are kept when they drop to zero, while emptied ERC721 ownerships are removed.
+///
+/// Tests run a `StandaloneWorker` on it, or add it to `ownership_stores` next to MongoDB, behind an
+/// `Arc` they keep a clone of, and compare `ownerships` with the ownerships expected from their
+/// fixture logs once the worker processed them.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    model: Mutex<MemoryModel>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every ownership with its exact amount.
+    pub fn ownerships(&self) -> BTreeMap<OwnershipKey, Amount> {
+        self.model().ownerships.clone()
+    }
+
+    /// The supply of every minted token.
+    pub fn supplies(&self) -> BTreeMap<SupplyKey, Amount> {
+        self.model().supplies.clone()
+    }
+
+    /// The token type of every classified contract.
+    pub fn token_types(&self) -> HashMap<H160, String> {
+        self.model().token_types.clone()
+    }
+
+    fn model(&self) -> MutexGuard<'_, MemoryModel> {
+        // A test panicking while holding the lock leaves the maps consistent, deltas being applied
+        // without awaiting.
+        self.model
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MemoryModel {
+    fn apply_deltas(&mut self, block_hash: Option<H256>, deltas: &[OwnershipDelta]) {
+        if let Some(block_hash) = block_hash {
+            if !self.applied_blocks.insert(block_hash) {
+                return;
+            }
+        }
+
+        for delta in deltas {
+            self.apply_delta(delta);
+        }
+    }
+
+    fn revert_deltas(&mut self, block_hash: H256, inverse_deltas: &[OwnershipDelta]) {
+        if !self.applied_blocks.remove(&block_hash) {
+            return;
+        }
+
+        for delta in inverse_deltas {
+            self.apply_delta(delta);
+        }
+    }
+
+    fn apply_delta(&mut self, delta: &OwnershipDelta) {
+        if delta.owner == H160::zero() {
+            let token_id = delta
+                .token_id
+                .clone()
+                .filter(|_| delta.token_type == "ERC1155");
+            let supply = self
+                .supplies
+                .entry((delta.contract_address, token_id))
+                .or_default();
+            *supply = *supply - delta.exact_amount();
+
+            return;
+        }
+
+        let key = (delta.contract_address, delta.token_id.clone(), delta.owner);
+        let amount = self.ownerships.get(&key).copied().unwrap_or_default() + delta.exact_amount();
+
+        if delta.token_type == "ERC721" && amount.is_zero() {
+            self.ownerships.remove(&key);
+        } else {
+            self.ownerships.insert(key, amount);
+        }
+    }
+}
+
+impl OwnershipStore for MemoryStore {
+    fn apply_deltas<'a>(
+        &'a self,
+        _block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            self.model().apply_deltas(block_hash, deltas);
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn revert_deltas<'a>(
+        &'a self,
+        block_hash: H256,
+        inverse_deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            self.model().revert_deltas(block_hash, inverse_deltas);
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn ownership_amount<'a>(
+        &'a self,
+        contract_address: H160,
+        token_id: Option<&'a str>,
+        owner: H160,
+    ) -> BoxFuture<'a, StorageResult<Amount>> {
+        async move {
+            let key = (contract_address, token_id.map(str::to_string), owner);
+
+            Ok(self
+                .model()
+                .ownerships
+                .get(&key)
+                .copied()
+                .unwrap_or_default())
+        }
+        .boxed()
+    }
+
+    fn checkpoint(&self) -> BoxFuture<'_, StorageResult<Option<U64>>> {
+        async move { Ok(self.model().checkpoint) }.boxed()
+    }
+
+    fn set_checkpoint(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            self.model().checkpoint = Some(block_number);
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn apply_block<'a>(&'a self, block: &'a JournaledBlock) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let mut model = self.model();
+
+            model.apply_deltas(Some(block.block_hash), &block.deltas);
+            model.journal.insert(block.block_number, block.clone());
+            model.checkpoint = Some(block.block_number);
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn journaled_block(
+        &self,
+        block_number: U64,
+    ) -> BoxFuture<'_, StorageResult<Option<JournaledBlock>>> {
+        async move { Ok(self.model().journal.get(&block_number).cloned()) }.boxed()
+    }
+
+    fn revert_block(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let mut model = self.model();
+
+            if let Some(block) = model.journal.remove(&block_number) {
+                model.revert_deltas(block.block_hash, &block.inverse_deltas());
+                model.checkpoint = Some(block_number - U64::from(1u8));
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn prune_journal(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let mut model = self.model();
+            model.journal = model.journal.split_off(&block_number);
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn token_type(&self, contract_address: H160) -> BoxFuture<'_, StorageResult<Option<String>>> {
+        async move { Ok(self.model().token_types.get(&contract_address).cloned()) }.boxed()
+    }
+
+    fn set_token_type<'a>(
+        &'a self,
+        contract_address: H160,
+        token_type: &'a str,
+        _classified_by: ClassificationMethod,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            self.model()
+                .token_types
+                .insert(contract_address, token_type.to_string());
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn reset(&self) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let mut model = self.model();
+            let token_types = std::mem::take(&mut model.token_types);
+
+            *model = MemoryModel {
+                token_types,
+                ..MemoryModel::default()
+            };
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
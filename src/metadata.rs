@@ -0,0 +1,413 @@
+#[cfg(feature = "ipfs")]
+use crate::ipfs::{self, DocumentResolver, PinningService};
+#[cfg(feature = "ipfs")]
+use crate::store::{Pin, PinStatus};
+use crate::{
+    backoff::{Backoff, RetryPolicy},
+    processor,
+    store::{MetadataStatus, Store, TokenMetadata},
+    RpcTransport,
+};
+use mongodb::bson::DateTime;
+use std::{error, time::Duration};
+use tokio::time::sleep;
+use web3::{
+    ethabi::{decode, param_type::ParamType, Token},
+    types::U256,
+    Web3,
+};
+
+/// Placeholder ERC1155 metadata URIs use for the token id.
+const ID_PLACEHOLDER: &str = "{id}";
+
+/// Resolves an ERC1155 `uri(id)` value for a token, replacing every `{id}` with the token id as 64
+/// lowercase hex digits without a `0x` prefix, as EIP-1155 requires. URIs without the placeholder
+/// are returned as is.
+pub fn substitute_token_id(uri: &str, token_id: U256) -> String {
+    if !uri.contains(ID_PLACEHOLDER) {
+        return uri.to_string();
+    }
+
+    uri.replace(ID_PLACEHOLDER, &format!("{:064x}", token_id))
+}
+
+/// Maximum number of metadata fetched per poll.
+const METADATA_BATCH_SIZE: i64 = 100;
+
+/// Time waited before polling again when no metadata is due.
+const METADATA_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Attempts after which metadata is given up on.
+const MAX_METADATA_ATTEMPTS: i32 = 8;
+
+/// Delay before the second attempt, doubled with every further attempt.
+const METADATA_RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+
+/// Caches the decimals of ERC20 contracts classified before decimals were cached, or whose
+/// decimals could not be fetched when they were classified.
+pub(crate) async fn cache_missing_decimals(
+    web3: Web3<RpcTransport>,
+    store: Store,
+    storage_retry: RetryPolicy,
+) {
+    let mut backoff = Backoff::new(storage_retry);
+
+    let contracts = loop {
+        match store.contracts_without_decimals().await {
+            Ok(contracts) => break contracts,
+            Err(error) => {
+                eprintln!(
+                    "Error: Could not read the contracts without decimals, retrying... {}",
+                    error
+                );
+                backoff.wait().await;
+            }
+        }
+    };
+
+    if !contracts.is_empty() {
+        println!("Caching the decimals of {} contracts", contracts.len());
+    }
+
+    for address in contracts {
+        if let Err(error) = processor::cache_decimals(&web3, &store, address).await {
+            eprintln!(
+                "Error: Could not cache the decimals of {:#x}... {}",
+                address, error
+            );
+        }
+    }
+}
+
+/// Fetches the metadata queued in `token_metadata`: name, symbol and decimals of every classified
+/// contract, and the URI of every minted NFT.
+pub(crate) struct MetadataWorker {
+    pub web3: Web3<RpcTransport>,
+    pub store: Store,
+    pub storage_retry: RetryPolicy,
+    /// Downloads the documents token URIs point to, which are left out when unset.
+    #[cfg(feature = "ipfs")]
+    pub resolver: Option<DocumentResolver>,
+    /// Pins the IPFS content of resolved token URIs and images, which is left unpinned when unset.
+    #[cfg(feature = "ipfs")]
+    pub pinning: Option<PinningService>,
+}
+
+impl MetadataWorker {
+    pub(crate) async fn run(self) {
+        let mut backoff = Backoff::new(self.storage_retry);
+
+        // Contracts classified before metadata was fetched, or while it was disabled.
+        loop {
+            match self.queue_token_contracts().await {
+                Ok(()) => break,
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not queue the metadata of the classified contracts, retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                }
+            }
+        }
+
+        loop {
+            let due = match self.store.due_token_metadata(METADATA_BATCH_SIZE).await {
+                Ok(due) => {
+                    backoff.reset();
+                    due
+                }
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not read the metadata queue, retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                    continue;
+                }
+            };
+
+            if due.is_empty() {
+                #[cfg(feature = "ipfs")]
+                if let Some(pinning) = &self.pinning {
+                    self.refresh_pins(pinning).await;
+                }
+
+                sleep(METADATA_POLL_INTERVAL).await;
+                continue;
+            }
+
+            for metadata in due {
+                let metadata = self.fetch(metadata).await;
+
+                if let Err(error) = self.store.save_token_metadata(&metadata).await {
+                    eprintln!(
+                        "Error: Could not save the metadata of {}, retrying... {}",
+                        metadata.id, error
+                    );
+                }
+            }
+        }
+    }
+
+    async fn queue_token_contracts(&self) -> mongodb::error::Result<()> {
+        let metadata: Vec<_> = self
+            .store
+            .token_contracts()
+            .await?
+            .into_iter()
+            .map(|contract| TokenMetadata::pending(contract.address, &contract.token_type, None))
+            .collect();
+
+        self.store.queue_token_metadata(&metadata).await
+    }
+
+    /// Makes an attempt at fetching the metadata. Failures to reach the provider, or the gateways
+    /// and servers of token URI documents, schedule another attempt with exponential backoff, until
+    /// `MAX_METADATA_ATTEMPTS` is reached.
+    async fn fetch(&self, metadata: TokenMetadata) -> TokenMetadata {
+        let attempts = metadata.attempts + 1;
+
+        let fetched = match &metadata.token_id {
+            Some(token_id) => self.fetch_token(&metadata, token_id).await,
+            None => self.fetch_contract(&metadata).await,
+        };
+
+        match fetched {
+            Ok(fetched) => TokenMetadata {
+                status: MetadataStatus::Fetched,
+                attempts,
+                next_attempt_at: None,
+                fetched_at: Some(DateTime::now()),
+                error: None,
+                ..fetched
+            },
+            Err(error) if attempts >= MAX_METADATA_ATTEMPTS => {
+                eprintln!(
+                    "Error: Giving up on the metadata of {} after {} attempts... {}",
+                    metadata.id, attempts, error
+                );
+                TokenMetadata {
+                    status: MetadataStatus::Failed,
+                    attempts,
+                    next_attempt_at: None,
+                    error: Some(error.to_string()),
+                    ..metadata
+                }
+            }
+            Err(error) => {
+                let delay = METADATA_RETRY_BASE_DELAY * 2u32.pow(attempts as u32 - 1);
+                TokenMetadata {
+                    attempts,
+                    next_attempt_at: Some(DateTime::from_millis(
+                        DateTime::now().timestamp_millis() + delay.as_millis() as i64,
+                    )),
+                    error: Some(error.to_string()),
+                    ..metadata
+                }
+            }
+        }
+    }
+
+    async fn fetch_contract(
+        &self,
+        metadata: &TokenMetadata,
+    ) -> Result<TokenMetadata, Box<dyn error::Error + Send + Sync>> {
+        let address = metadata.contract_address;
+
+        let name = processor::call(&self.web3, address, "name()", &[], None).await?;
+        let symbol = processor::call(&self.web3, address, "symbol()", &[], None).await?;
+        let decimals = processor::decimals(&self.web3, address).await?;
+
+        Ok(TokenMetadata {
+            name: name.as_deref().and_then(decode_string),
+            symbol: symbol.as_deref().and_then(decode_string),
+            decimals,
+            ..metadata.clone()
+        })
+    }
+
+    async fn fetch_token(
+        &self,
+        metadata: &TokenMetadata,
+        token_id: &str,
+    ) -> Result<TokenMetadata, Box<dyn error::Error + Send + Sync>> {
+        let token_id = match U256::from_dec_str(token_id) {
+            Ok(token_id) => token_id,
+            Err(_) => return Ok(metadata.clone()),
+        };
+
+        let token_uri = if metadata.token_type == "ERC1155" {
+            processor::call(
+                &self.web3,
+                metadata.contract_address,
+                "uri(uint256)",
+                &[processor::word(token_id)],
+                None,
+            )
+            .await?
+            .as_deref()
+            .and_then(decode_string)
+            .map(|uri| substitute_token_id(&uri, token_id))
+        } else {
+            processor::call(
+                &self.web3,
+                metadata.contract_address,
+                "tokenURI(uint256)",
+                &[processor::word(token_id)],
+                None,
+            )
+            .await?
+            .as_deref()
+            .and_then(decode_string)
+        };
+
+        #[cfg(feature = "ipfs")]
+        if let (Some(resolver), Some(uri)) = (&self.resolver, &token_uri) {
+            let document = resolver.resolve(uri).await?;
+            let image = document
+                .as_ref()
+                .and_then(|document| document.get_str("image").ok())
+                .map(str::to_string);
+
+            let pins = match &self.pinning {
+                Some(pinning) => {
+                    self.pin(pinning, metadata, [Some(uri), image.as_ref()])
+                        .await
+                }
+                None => metadata.pins.clone(),
+            };
+
+            return Ok(TokenMetadata {
+                image,
+                document,
+                pins,
+                token_uri,
+                ..metadata.clone()
+            });
+        }
+
+        Ok(TokenMetadata {
+            token_uri,
+            ..metadata.clone()
+        })
+    }
+}
+
+#[cfg(feature = "ipfs")]
+impl MetadataWorker {
+    /// Pins the CIDs of the IPFS URIs among `uris`. CIDs already pinned, or being pinned, by an
+    /// earlier attempt are not requested again.
+    async fn pin(
+        &self,
+        pinning: &PinningService,
+        metadata: &TokenMetadata,
+        uris: [Option<&String>; 2],
+    ) -> Vec<Pin> {
+        let mut pins: Vec<Pin> = Vec::new();
+
+        for cid in uris
+            .into_iter()
+            .flatten()
+            .filter_map(|uri| ipfs::ipfs_cid(uri))
+        {
+            if pins.iter().any(|pin| pin.cid == cid) {
+                continue;
+            }
+
+            let pinned = metadata
+                .pins
+                .iter()
+                .find(|pin| pin.cid == cid && pin.status != PinStatus::Failed);
+
+            pins.push(match pinned {
+                Some(pin) => pin.clone(),
+                None => pinning.pin(cid, &metadata.id).await,
+            });
+        }
+
+        pins
+    }
+
+    /// Updates the pins still queued or being pinned with their status at the pinning service.
+    async fn refresh_pins(&self, pinning: &PinningService) {
+        let unsettled = match self.store.unsettled_pins(METADATA_BATCH_SIZE).await {
+            Ok(unsettled) => unsettled,
+            Err(error) => {
+                eprintln!("Error: Could not read the unsettled pins... {}", error);
+                return;
+            }
+        };
+
+        for metadata in unsettled {
+            let mut pins = metadata.pins;
+
+            for pin in pins.iter_mut().filter(|pin| !pin.status.is_settled()) {
+                let request_id = match &pin.request_id {
+                    Some(request_id) => request_id,
+                    None => continue,
+                };
+
+                match pinning.status(request_id).await {
+                    Ok(status) => pin.status = status,
+                    Err(error) => eprintln!(
+                        "Error: Could not check the pin of {} for {}... {}",
+                        pin.cid, metadata.id, error
+                    ),
+                }
+            }
+
+            if let Err(error) = self.store.set_pins(&metadata.id, &pins).await {
+                eprintln!(
+                    "Error: Could not save the pins of {}... {}",
+                    metadata.id, error
+                );
+            }
+        }
+    }
+}
+
+/// Decodes a string return value. Some early tokens return their name and symbol as a `bytes32`
+/// padded with zeros instead.
+fn decode_string(output: &[u8]) -> Option<String> {
+    if let Ok(decoded) = decode(&[ParamType::String], output) {
+        if let Some(Token::String(value)) = decoded.into_iter().next() {
+            return Some(value);
+        }
+    }
+
+    if output.len() != 32 {
+        return None;
+    }
+
+    let end = output.iter().position(|byte| *byte == 0).unwrap_or(32);
+    String::from_utf8(output[..end].to_vec())
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_the_token_id_as_padded_lowercase_hex() {
+        // The example of EIP-1155.
+        assert_eq!(
+            substitute_token_id("https://token-cdn-domain/{id}.json", U256::from(314592)),
+            "https://token-cdn-domain/000000000000000000000000000000000000000000000000000000000004cce0.json"
+        );
+        assert_eq!(
+            substitute_token_id("ipfs://{id}/{id}.json", U256::from(255)),
+            "ipfs://00000000000000000000000000000000000000000000000000000000000000ff/00000000000000000000000000000000000000000000000000000000000000ff.json"
+        );
+    }
+
+    #[test]
+    fn keeps_uris_without_the_placeholder() {
+        assert_eq!(
+            substitute_token_id("https://token-cdn-domain/1.json", U256::from(314592)),
+            "https://token-cdn-domain/1.json"
+        );
+    }
+}
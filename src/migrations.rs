@@ -0,0 +1,169 @@
+use crate::{jobs::default_worker_id, store::Store};
+use std::{error, time::Duration};
+
+/// Lease of the `leases` collection held while migrating, so worker processes starting together
+/// migrate the data model once.
+const MIGRATION_LEASE: &str = "schema_migration";
+
+/// How long a migrating worker holds the lease without renewing it, renewed before every
+/// migration. Migrations redo the same writes when a worker that lost the lease runs them again.
+const MIGRATION_LEASE_DURATION: Duration = Duration::from_secs(600);
+
+/// Time between attempts to take the lease while another worker migrates.
+const MIGRATION_LEASE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A change of the stored data model that documents written by older versions of the worker are
+/// upgraded with. Migrations run in order and only once, and are numbered by their position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Migration {
+    /// Ownerships, holdings and supplies stored before exact amounts were only have a float
+    /// quantity.
+    ExactAmounts,
+    /// Contract checksums only cover the ownerships changed since checksums were maintained.
+    ContractChecksums,
+    /// Holders per token are only counted from the deltas applied since they were.
+    TokenHolderCounts,
+    /// Portfolios only list the tokens gained since they were maintained.
+    Portfolios,
+}
+
+const MIGRATIONS: [Migration; 4] = [
+    Migration::ExactAmounts,
+    Migration::ContractChecksums,
+    Migration::TokenHolderCounts,
+    Migration::Portfolios,
+];
+
+/// Version of the data model this worker writes, the number of its migrations.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+impl Migration {
+    fn description(&self) -> &'static str {
+        match self {
+            Migration::ExactAmounts => "backfilling exact amounts from float quantities",
+            Migration::ContractChecksums => "recomputing contract checksums from the ownerships",
+            Migration::TokenHolderCounts => "counting the holders of every token",
+            Migration::Portfolios => "building the portfolio of every owner",
+        }
+    }
+
+    /// Applies the migration, returning the number of documents it updated.
+    async fn apply(&self, store: &Store) -> mongodb::error::Result<u64> {
+        match self {
+            Migration::ExactAmounts => store.backfill_exact_amounts().await,
+            Migration::ContractChecksums => store.recompute_checksums().await,
+            Migration::TokenHolderCounts => store.rebuild_token_holder_counts().await,
+            Migration::Portfolios => store.rebuild_portfolios().await,
+        }
+    }
+}
+
+/// Brings the data model of the database up to `SCHEMA_VERSION`, running the migrations after the
+/// version recorded in the `schema_version` document of `sync_state`. A database the worker has
+/// not written to yet is stamped with the current version, and one without a version but with
+/// data is migrated from the start.
+///
+/// A database at a newer version, written by a newer worker, is an error, and so is one at an
+/// older version without `migrate`.
+pub(crate) async fn migrate(
+    store: &Store,
+    migrate: bool,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let version = match store.schema_version().await? {
+        Some(version) => version,
+        None if store.is_empty().await? => {
+            store.set_schema_version(SCHEMA_VERSION).await?;
+            return Ok(());
+        }
+        None => 0,
+    };
+
+    check_version(version, migrate)?;
+
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let holder = default_worker_id();
+
+    while !store
+        .acquire_lease(MIGRATION_LEASE, &holder, MIGRATION_LEASE_DURATION)
+        .await?
+    {
+        println!("Waiting for another worker to migrate the data model");
+        tokio::time::sleep(MIGRATION_LEASE_RETRY_INTERVAL).await;
+    }
+
+    let result = run_migrations(store, &holder).await;
+
+    store.release_lease(MIGRATION_LEASE, &holder).await?;
+
+    result
+}
+
+/// Checks that the data model can be brought up to `SCHEMA_VERSION` without migrating it yet,
+/// for a worker taking over from one still writing the current data model.
+pub(crate) async fn check(
+    store: &Store,
+    migrate: bool,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    match store.schema_version().await? {
+        Some(version) => check_version(version, migrate),
+        None => Ok(()),
+    }
+}
+
+/// Refuses a database at a newer version than `SCHEMA_VERSION`, and one at an older version
+/// without `migrate`.
+fn check_version(version: u32, migrate: bool) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "The database is at schema version {}, written by a newer worker than this one at version {}",
+            version, SCHEMA_VERSION
+        )
+        .into());
+    }
+
+    if version < SCHEMA_VERSION && !migrate {
+        return Err(format!(
+            "The database is at schema version {}, this worker needs version {} and schema migrations are disabled",
+            version, SCHEMA_VERSION
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Runs the migrations after the recorded version, read again now that the lease is held since
+/// another worker may have migrated in the meantime.
+async fn run_migrations(
+    store: &Store,
+    holder: &str,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let version = store.schema_version().await?.unwrap_or_default();
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = index as u32 + 1;
+
+        store
+            .acquire_lease(MIGRATION_LEASE, holder, MIGRATION_LEASE_DURATION)
+            .await?;
+
+        println!(
+            "Migrating the data model to schema version {}, {}",
+            version,
+            migration.description()
+        );
+
+        let updated = migration.apply(store).await?;
+        store.set_schema_version(version).await?;
+
+        println!(
+            "Migrated the data model to schema version {}, {} documents updated",
+            version, updated
+        );
+    }
+
+    Ok(())
+}
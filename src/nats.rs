@@ -0,0 +1,395 @@
+//! Publishing of ownership deltas to NATS JetStream, only compiled with the `nats` feature.
+//!
+//! The publisher uses the JetStream context of `async-nats`: it publishes the deltas of every log
+//! to the subject of its contract with a `Nats-Msg-Id` header, so a message published again after
+//! a retry or a restart is dropped by the deduplication window of the stream, and waits for the
+//! acknowledgement of every message.
+
+use crate::{
+    ownership::OwnershipDelta,
+    store::{DeltaFeedKind, JournaledLog},
+};
+use async_nats::{
+    jetstream::{self, context::GetStreamErrorKind, message::PublishMessage, stream},
+    ConnectOptions, ServerAddr,
+};
+use serde::Serialize;
+use std::{error, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use web3::types::{H160, H256, U256, U64};
+
+/// Time a server has to accept the connection, answer a request or acknowledge a message.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Messages published before waiting for their acknowledgements.
+const PUBLISH_BATCH_SIZE: usize = 256;
+
+const CLIENT_NAME: &str = "token_ownership_worker";
+
+/// Characters subject tokens cannot hold.
+const SUBJECT_RESERVED: &[char] = &['.', '*', '>'];
+
+/// A JetStream context of a connection, with the largest payload its server accepts.
+struct NatsConnection {
+    context: jetstream::Context,
+    max_payload: usize,
+}
+
+impl NatsConnection {
+    /// Publishes `messages` with their ids, waiting for JetStream to acknowledge every one.
+    async fn publish(
+        &self,
+        messages: &[NatsMessage],
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let mut acknowledgements = Vec::with_capacity(messages.len());
+        for message in messages {
+            let publish = PublishMessage::build()
+                .payload(message.payload.clone().into())
+                .message_id(&message.id);
+            acknowledgements.push(
+                self.context
+                    .send_publish(message.subject.clone(), publish)
+                    .await?,
+            );
+        }
+
+        for (acknowledgement, message) in acknowledgements.into_iter().zip(messages) {
+            acknowledgement.await.map_err(|error| {
+                format!(
+                    "JetStream did not acknowledge message {}: {}",
+                    message.id, error
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A message ready to be published.
+struct NatsMessage {
+    subject: String,
+    id: String,
+    payload: Vec<u8>,
+}
+
+/// The deltas of a contract applied or reverted for a log, as published.
+#[derive(Debug, Clone, Serialize)]
+struct NatsEvent {
+    kind: DeltaFeedKind,
+    block_number: i64,
+    block_hash: H256,
+    /// `None` for the deltas of a reconciliation, which were not decoded from a log.
+    transaction_hash: Option<H256>,
+    log_index: Option<U256>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    removed: bool,
+    contract_address: H160,
+    deltas: Vec<OwnershipDelta>,
+}
+
+/// Publishes the deltas of every block to the JetStream subjects of their contracts,
+/// `ownership.<chain>.<contract address>`.
+#[derive(Clone)]
+pub struct NatsPublisher {
+    servers: Vec<ServerAddr>,
+    chain: String,
+    stream: String,
+    connection: Arc<Mutex<Option<NatsConnection>>>,
+}
+
+impl std::fmt::Debug for NatsPublisher {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter
+            .debug_struct("NatsPublisher")
+            .field(
+                "servers",
+                &self
+                    .servers
+                    .iter()
+                    .map(|server| format!("{}:{}", server.host(), server.port()))
+                    .collect::<Vec<_>>(),
+            )
+            .field("chain", &self.chain)
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl NatsPublisher {
+    /// Publisher to the comma separated `nats://[user:password@|token@]host:port` URLs of
+    /// `servers`, into `stream`, which is created to capture `ownership.<chain>.>` when missing.
+    pub fn new(servers: &str, chain: String, stream: String) -> Result<Self, String> {
+        let servers = servers
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(|server| {
+                server
+                    .parse::<ServerAddr>()
+                    .map_err(|error| format!("Invalid NATS URL {}: {}", server, error))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if servers.is_empty() {
+            return Err("No NATS server given".to_string());
+        }
+        for (name, token) in [("chain", &chain), ("stream", &stream)] {
+            if token.is_empty()
+                || token.contains(SUBJECT_RESERVED)
+                || token.contains(char::is_whitespace)
+            {
+                return Err(format!(
+                    "NATS {} {} cannot be empty or hold '.', '*', '>' or whitespace",
+                    name, token
+                ));
+            }
+        }
+
+        Ok(Self {
+            servers,
+            chain,
+            stream,
+            connection: Default::default(),
+        })
+    }
+
+    /// Publishes the deltas of a block, one message per log and contract followed by one per
+    /// contract of its reconciliation. For a `Revert`, the inverse deltas of the journaled block
+    /// are published, newest log first.
+    pub(crate) async fn publish_block(
+        &self,
+        kind: DeltaFeedKind,
+        block_number: U64,
+        block_hash: H256,
+        logs: &[JournaledLog],
+        deltas: &[OwnershipDelta],
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let log_deltas: usize = logs.iter().map(|log| log.deltas.len()).sum();
+        let reconciliation = deltas.get(log_deltas..).unwrap_or_default();
+
+        let mut events = Vec::new();
+        for log in logs {
+            let id = format!("{:#x}:{}", log.transaction_hash, log.log_index);
+            for event in
+                self.contract_events(kind, block_number, block_hash, Some(log), &log.deltas)
+            {
+                events.push((id.clone(), event));
+            }
+        }
+        let id = format!("reconciliation:{}", block_number);
+        for event in self.contract_events(kind, block_number, block_hash, None, reconciliation) {
+            events.push((id.clone(), event));
+        }
+
+        if kind == DeltaFeedKind::Revert {
+            events.reverse();
+        }
+
+        let mut connection = self.connection.lock().await;
+
+        let result = async {
+            let connection = match &mut *connection {
+                Some(connection) => connection,
+                None => connection.insert(self.connect().await?),
+            };
+
+            let mut messages = Vec::new();
+            for (id, event) in events {
+                self.encode(
+                    &event,
+                    message_id(&id, kind, &event),
+                    connection.max_payload,
+                    &mut messages,
+                )?;
+            }
+
+            for batch in messages.chunks(PUBLISH_BATCH_SIZE) {
+                connection.publish(batch).await?;
+            }
+
+            Ok::<_, Box<dyn error::Error + Send + Sync>>(())
+        }
+        .await;
+
+        if result.is_err() {
+            *connection = None;
+        }
+
+        result
+    }
+
+    /// Connects to the first server accepting the connection and makes sure the stream exists.
+    /// Credentials are read from the first URL holding them, `user:password@` or `token@`.
+    async fn connect(&self) -> Result<NatsConnection, Box<dyn error::Error + Send + Sync>> {
+        let credentials = self
+            .servers
+            .iter()
+            .find_map(|server| Some((server.username()?, server.password())));
+        let options = match credentials {
+            Some((user, Some(password))) => {
+                ConnectOptions::with_user_and_password(user.to_string(), password.to_string())
+            }
+            Some((token, None)) => ConnectOptions::with_token(token.to_string()),
+            None => ConnectOptions::new(),
+        };
+
+        let client = options
+            .name(CLIENT_NAME)
+            .connection_timeout(REQUEST_TIMEOUT)
+            .request_timeout(Some(REQUEST_TIMEOUT))
+            .connect(self.servers.clone())
+            .await?;
+
+        let info = client.server_info();
+        if !info.headers {
+            return Err(format!(
+                "NATS server {}:{} does not support headers, which deduplication needs",
+                info.host, info.port
+            )
+            .into());
+        }
+        let max_payload = info.max_payload;
+
+        let context = jetstream::ContextBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .build(client);
+
+        let subjects = format!("ownership.{}.>", self.chain);
+        match context.get_stream(&self.stream).await {
+            Ok(_) => {}
+            Err(error) if matches!(error.kind(), GetStreamErrorKind::JetStream(error) if error.code() == 404) =>
+            {
+                context
+                    .create_stream(stream::Config {
+                        name: self.stream.clone(),
+                        subjects: vec![subjects.clone()],
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(|error| {
+                        format!(
+                            "JetStream could not create stream {}: {}",
+                            self.stream, error
+                        )
+                    })?;
+                println!("Created JetStream stream {} for {}", self.stream, subjects);
+            }
+            Err(error) => {
+                return Err(format!(
+                    "JetStream could not describe stream {}: {}",
+                    self.stream, error
+                )
+                .into())
+            }
+        }
+
+        Ok(NatsConnection {
+            context,
+            max_payload,
+        })
+    }
+
+    /// The deltas of a log, or of a reconciliation when `log` is `None`, split by contract in the
+    /// order the contracts first appear.
+    fn contract_events(
+        &self,
+        kind: DeltaFeedKind,
+        block_number: U64,
+        block_hash: H256,
+        log: Option<&JournaledLog>,
+        deltas: &[OwnershipDelta],
+    ) -> Vec<NatsEvent> {
+        let mut events: Vec<NatsEvent> = Vec::new();
+
+        for delta in deltas {
+            let delta = match kind {
+                DeltaFeedKind::Apply => delta.clone(),
+                DeltaFeedKind::Revert => delta.inverse(),
+            };
+
+            match events
+                .iter_mut()
+                .find(|event| event.contract_address == delta.contract_address)
+            {
+                Some(event) => event.deltas.push(delta),
+                None => events.push(NatsEvent {
+                    kind,
+                    block_number: block_number.as_u64() as i64,
+                    block_hash,
+                    transaction_hash: log.map(|log| log.transaction_hash),
+                    log_index: log.map(|log| log.log_index),
+                    removed: log.is_some_and(|log| log.removed),
+                    contract_address: delta.contract_address,
+                    deltas: vec![delta],
+                }),
+            }
+        }
+
+        if kind == DeltaFeedKind::Revert {
+            for event in &mut events {
+                event.deltas.reverse();
+            }
+        }
+
+        events
+    }
+
+    /// Encodes `event` with `id`, splitting its deltas in halves, numbered after the id, until
+    /// every part fits in `max_payload`.
+    fn encode(
+        &self,
+        event: &NatsEvent,
+        id: String,
+        max_payload: usize,
+        messages: &mut Vec<NatsMessage>,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let payload = serde_json::to_vec(event)?;
+
+        if payload.len() <= max_payload {
+            messages.push(NatsMessage {
+                subject: format!("ownership.{}.{:#x}", self.chain, event.contract_address),
+                id,
+                payload,
+            });
+            return Ok(());
+        }
+
+        if event.deltas.len() < 2 {
+            return Err(format!(
+                "Message {} of {} bytes exceeds the NATS max_payload of {} bytes",
+                id,
+                payload.len(),
+                max_payload
+            )
+            .into());
+        }
+
+        let (first, second) = event.deltas.split_at(event.deltas.len() / 2);
+        for (part, deltas) in [first, second].into_iter().enumerate() {
+            let event = NatsEvent {
+                deltas: deltas.to_vec(),
+                ..event.clone()
+            };
+            self.encode(&event, format!("{}.{}", id, part), max_payload, messages)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `Nats-Msg-Id` of an event: the `<transaction hash>:<log index>` of its log, or
+/// `reconciliation:<block number>`, with the contract address, the block hash, so the log
+/// included again in another block after a reorg is not taken for a duplicate, and whether it
+/// reverts the block.
+fn message_id(id: &str, kind: DeltaFeedKind, event: &NatsEvent) -> String {
+    let suffix = match kind {
+        DeltaFeedKind::Apply => "",
+        DeltaFeedKind::Revert => ":revert",
+    };
+
+    format!(
+        "{}:{:#x}@{:#x}{}",
+        id, event.contract_address, event.block_hash, suffix
+    )
+}
@@ -0,0 +1,98 @@
+//! OpenAPI schemas of the types the data model borrows from `web3` and `bson`, which the types
+//! deriving `ToSchema` name with `#[schema(value_type = ...)]`, and of `Amount`.
+
+use crate::amount::Amount;
+use std::borrow::Cow;
+use utoipa::{
+    openapi::{
+        schema::{ObjectBuilder, Schema, Type},
+        RefOr,
+    },
+    PartialSchema, ToSchema,
+};
+
+/// Implements `ToSchema` for a type described by a string matching `pattern`.
+macro_rules! string_schema {
+    ($type:ty, $name:literal, $pattern:literal, $description:literal) => {
+        impl PartialSchema for $type {
+            fn schema() -> RefOr<Schema> {
+                ObjectBuilder::new()
+                    .schema_type(Type::String)
+                    .pattern(Some($pattern))
+                    .description(Some($description))
+                    .into()
+            }
+        }
+
+        impl ToSchema for $type {
+            fn name() -> Cow<'static, str> {
+                Cow::Borrowed($name)
+            }
+        }
+    };
+}
+
+/// An `H160`.
+pub(crate) struct Address;
+
+/// An `H256`.
+pub(crate) struct Hash;
+
+/// A `U64` or `U256`.
+pub(crate) struct Quantity;
+
+/// A MongoDB date.
+pub(crate) struct DateTime;
+
+string_schema!(
+    Address,
+    "Address",
+    "^0x[0-9a-f]{40}$",
+    "`0x` followed by 40 lowercase hex digits."
+);
+string_schema!(
+    Hash,
+    "Hash",
+    "^0x[0-9a-f]{64}$",
+    "`0x` followed by 64 lowercase hex digits."
+);
+string_schema!(
+    Quantity,
+    "Quantity",
+    "^0x(0|[1-9a-f][0-9a-f]*)$",
+    "`0x` followed by hex digits, without leading zeros."
+);
+string_schema!(
+    Amount,
+    "Amount",
+    "^(0|-?[1-9][0-9]*)$",
+    "Exact decimal integer, possibly negative."
+);
+
+impl PartialSchema for DateTime {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .property(
+                "$date",
+                ObjectBuilder::new()
+                    .property(
+                        "$numberLong",
+                        ObjectBuilder::new()
+                            .schema_type(Type::String)
+                            .pattern(Some("^-?[0-9]+$")),
+                    )
+                    .required("$numberLong"),
+            )
+            .required("$date")
+            .description(Some(
+                "Date in canonical Extended JSON, in milliseconds since the Unix epoch.",
+            ))
+            .into()
+    }
+}
+
+impl ToSchema for DateTime {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("DateTime")
+    }
+}
@@ -0,0 +1,145 @@
+use crate::amount::Amount;
+#[cfg(feature = "api")]
+use crate::openapi;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+use web3::types::{Address, H160, U256};
+
+/// How transfers into and out of a token contract's own address are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTransferPolicy {
+    /// The contract is recorded as a regular owner of its own tokens.
+    #[default]
+    Owner,
+    /// The contract address is handled like the zero address, so tokens sent to it are burned.
+    Burn,
+    /// Tokens held by the contract are kept in a bucket flagged with `custody: true`.
+    Custody,
+}
+
+impl SelfTransferPolicy {
+    pub(crate) fn resolve_owner(self, contract_address: Address, owner: Address) -> Address {
+        if self == SelfTransferPolicy::Burn && owner == contract_address {
+            Address::default()
+        } else {
+            owner
+        }
+    }
+
+    pub(crate) fn is_custody(self, contract_address: Address, owner: Address) -> bool {
+        self == SelfTransferPolicy::Custody && owner == contract_address
+    }
+}
+
+/// What becomes of ERC20 and ERC1155 ownerships whose balance drops to zero. ERC721 ownerships are
+/// always removed then, and archived first under `Archive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZeroBalancePolicy {
+    /// The ownership is kept with a zero balance.
+    #[default]
+    Keep,
+    Delete,
+    /// The ownership is moved to `archived_ownerships`.
+    Archive,
+}
+
+impl std::str::FromStr for ZeroBalancePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "keep" => Ok(ZeroBalancePolicy::Keep),
+            "delete" => Ok(ZeroBalancePolicy::Delete),
+            "archive" => Ok(ZeroBalancePolicy::Archive),
+            _ => Err(format!("Unknown zero balance policy {}", policy)),
+        }
+    }
+}
+
+/// A single change to the quantity of a token held by an owner.
+///
+/// Every ownership mutation is expressed as a delta so it can be journaled and reverted exactly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct OwnershipDelta {
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub contract_address: H160,
+    pub token_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub owner: H160,
+    /// `amount` as a float.
+    pub quantity: f64,
+    /// Exact change, `None` for deltas journaled before exact amounts were, whose quantity is
+    /// rounded instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Amount>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub custody: bool,
+}
+
+impl OwnershipDelta {
+    /// Returns the delta that undoes this one.
+    pub fn inverse(&self) -> Self {
+        Self {
+            quantity: -self.quantity,
+            amount: self.amount.map(|amount| -amount),
+            ..self.clone()
+        }
+    }
+
+    /// The exact change applied by the delta.
+    pub fn exact_amount(&self) -> Amount {
+        self.amount
+            .unwrap_or_else(|| Amount::from_f64(self.quantity))
+    }
+}
+
+/// A decoded token movement, before any ownership policy has been applied.
+#[derive(Debug, Clone)]
+pub(crate) struct Transfer {
+    pub contract_address: Address,
+    pub token_type: String,
+    pub token_id: Option<String>,
+    pub from: Address,
+    pub to: Address,
+    pub amount: U256,
+}
+
+impl Transfer {
+    /// Turns the transfer into the deltas it applies to the ownership model.
+    ///
+    /// Mints and burns are transfers from and to the zero address, whose deltas change the supply
+    /// of the token instead of an ownership.
+    pub(crate) fn deltas(&self, policy: SelfTransferPolicy) -> Vec<OwnershipDelta> {
+        let from = policy.resolve_owner(self.contract_address, self.from);
+        let to = policy.resolve_owner(self.contract_address, self.to);
+
+        if self.amount.is_zero() {
+            return Vec::new();
+        }
+
+        let delta = |owner: Address, amount: Amount| OwnershipDelta {
+            contract_address: self.contract_address,
+            token_type: self.token_type.clone(),
+            token_id: self.token_id.clone(),
+            owner,
+            quantity: amount.to_f64(),
+            amount: Some(amount),
+            custody: policy.is_custody(self.contract_address, owner),
+        };
+
+        let amount = Amount::from(self.amount);
+
+        vec![delta(from, -amount), delta(to, amount)]
+    }
+
+    /// The transferred amount as a float.
+    pub(crate) fn quantity(&self) -> f64 {
+        Amount::from(self.amount).to_f64()
+    }
+}
@@ -0,0 +1,77 @@
+//! Parquet files of optional UTF-8 string and double columns for the exports and backups, written
+//! uncompressed by the `parquet` crate.
+
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{arrow::ArrowWriter, errors::ParquetError, file::properties::WriterProperties};
+use std::{
+    io::{self, Write},
+    sync::Arc,
+};
+
+/// Values of a column, `None` for nulls.
+pub(crate) enum ParquetColumn {
+    Utf8(Vec<Option<String>>),
+    Double(Vec<Option<f64>>),
+}
+
+impl ParquetColumn {
+    fn data_type(&self) -> DataType {
+        match self {
+            ParquetColumn::Utf8(_) => DataType::Utf8,
+            ParquetColumn::Double(_) => DataType::Float64,
+        }
+    }
+
+    fn into_array(self) -> ArrayRef {
+        match self {
+            ParquetColumn::Utf8(values) => Arc::new(StringArray::from(values)),
+            ParquetColumn::Double(values) => Arc::new(Float64Array::from(values)),
+        }
+    }
+}
+
+/// Schema of `columns`, every column optional.
+pub(crate) fn parquet_schema(columns: &[(impl AsRef<str>, ParquetColumn)]) -> SchemaRef {
+    Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|(name, column)| Field::new(name.as_ref(), column.data_type(), true))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// The rows of `columns`, named and of equal length, in `schema`.
+pub(crate) fn record_batch(
+    schema: SchemaRef,
+    columns: Vec<(impl AsRef<str>, ParquetColumn)>,
+) -> Result<RecordBatch, ParquetError> {
+    let arrays = columns
+        .into_iter()
+        .map(|(_, column)| column.into_array())
+        .collect();
+
+    Ok(RecordBatch::try_new(schema, arrays)?)
+}
+
+/// Properties of the files written, uncompressed and naming the worker as their writer.
+pub(crate) fn writer_properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_created_by("token_ownership_worker".to_string())
+        .build()
+}
+
+/// Writes `columns`, named and of equal length, as a Parquet file.
+pub(crate) fn write_parquet(
+    columns: Vec<(impl AsRef<str>, ParquetColumn)>,
+    writer: impl Write + Send,
+) -> io::Result<()> {
+    let schema = parquet_schema(&columns);
+    let batch = record_batch(schema.clone(), columns)?;
+
+    let mut writer = ArrowWriter::try_new(writer, schema, Some(writer_properties()))?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
@@ -0,0 +1,443 @@
+//! The ownership model in PostgreSQL, only compiled with the `postgres` feature.
+//!
+//! Amounts are stored as `NUMERIC(78, 0)`, which holds any `uint256`, and summed by PostgreSQL
+//! itself, so every block is applied in a single transaction together with its marker, and with
+//! its journal entry and the checkpoint when the worker runs on PostgreSQL alone.
+
+use crate::{
+    amount::Amount,
+    ownership::OwnershipDelta,
+    storage::{JournaledBlock, OwnershipStore, StorageResult},
+    store::ClassificationMethod,
+};
+use futures::future::{BoxFuture, FutureExt};
+use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, Row, Transaction};
+use std::str::FromStr;
+use web3::types::{H160, H256, U64};
+
+const SCHEMA: [&str; 6] = [
+    "CREATE TABLE IF NOT EXISTS token_ownerships (
+        contract_address TEXT NOT NULL,
+        token_id TEXT NOT NULL DEFAULT '',
+        owner TEXT NOT NULL,
+        amount NUMERIC(78, 0) NOT NULL,
+        quantity DOUBLE PRECISION GENERATED ALWAYS AS (amount::DOUBLE PRECISION) STORED,
+        custody BOOLEAN NOT NULL DEFAULT FALSE,
+        PRIMARY KEY (contract_address, token_id, owner)
+    )",
+    "CREATE TABLE IF NOT EXISTS token_supplies (
+        contract_address TEXT NOT NULL,
+        token_id TEXT NOT NULL DEFAULT '',
+        token_type TEXT NOT NULL,
+        supply NUMERIC(78, 0) NOT NULL,
+        PRIMARY KEY (contract_address, token_id)
+    )",
+    "CREATE TABLE IF NOT EXISTS applied_blocks (
+        block_hash TEXT PRIMARY KEY,
+        block_number BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS sync_state (
+        id TEXT PRIMARY KEY,
+        block_number BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS block_journal (
+        block_number BIGINT PRIMARY KEY,
+        block_hash TEXT NOT NULL,
+        parent_hash TEXT NOT NULL,
+        deltas JSONB NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS contract_addresses (
+        address TEXT PRIMARY KEY,
+        token_type TEXT NOT NULL,
+        classified_by TEXT NOT NULL
+    )",
+];
+
+/// `id` of the checkpoint row in `sync_state`.
+const CHECKPOINT_ID: &str = "logs_worker";
+
+/// Writes the ownership model to PostgreSQL. Fungible tokens are stored with an empty `token_id`,
+/// since primary keys cannot hold nulls.
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    /// Connects to the database and creates the tables that do not exist yet.
+    pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+        let pool = PgPoolOptions::new().connect(url).await?;
+
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+
+        Ok(Self { pool })
+    }
+
+    /// Records the block as applied and applies its deltas, unless it was applied already.
+    async fn apply_block_deltas(
+        transaction: &mut Transaction<'_, Postgres>,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &[OwnershipDelta],
+    ) -> Result<(), sqlx::Error> {
+        if let Some(block_hash) = block_hash {
+            let marked = sqlx::query(
+                "INSERT INTO applied_blocks (block_hash, block_number) VALUES ($1, $2)
+                ON CONFLICT DO NOTHING",
+            )
+            .bind(format!("{:#x}", block_hash))
+            .bind(block_number.as_u64() as i64)
+            .execute(&mut **transaction)
+            .await?
+            .rows_affected();
+
+            if marked == 0 {
+                return Ok(());
+            }
+        }
+
+        for delta in deltas {
+            Self::apply_delta(transaction, delta).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the applied block record and applies the inverse deltas, unless the block was not
+    /// recorded as applied.
+    async fn revert_block_deltas(
+        transaction: &mut Transaction<'_, Postgres>,
+        block_hash: H256,
+        inverse_deltas: &[OwnershipDelta],
+    ) -> Result<(), sqlx::Error> {
+        let unmarked = sqlx::query("DELETE FROM applied_blocks WHERE block_hash = $1")
+            .bind(format!("{:#x}", block_hash))
+            .execute(&mut **transaction)
+            .await?
+            .rows_affected();
+
+        if unmarked == 0 {
+            return Ok(());
+        }
+
+        for delta in inverse_deltas {
+            Self::apply_delta(transaction, delta).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_checkpoint(
+        transaction: &mut Transaction<'_, Postgres>,
+        block_number: U64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO sync_state (id, block_number) VALUES ($1, $2)
+            ON CONFLICT (id) DO UPDATE SET block_number = EXCLUDED.block_number",
+        )
+        .bind(CHECKPOINT_ID)
+        .bind(block_number.as_u64() as i64)
+        .execute(&mut **transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn apply_delta(
+        transaction: &mut Transaction<'_, Postgres>,
+        delta: &OwnershipDelta,
+    ) -> Result<(), sqlx::Error> {
+        let contract_address = format!("{:#x}", delta.contract_address);
+        let token_id = delta.token_id.clone().unwrap_or_default();
+        let amount = delta.exact_amount();
+
+        if delta.owner == H160::zero() {
+            let token_id = if delta.token_type == "ERC1155" {
+                token_id
+            } else {
+                String::new()
+            };
+
+            sqlx::query(
+                "INSERT INTO token_supplies (contract_address, token_id, token_type, supply)
+                VALUES ($1, $2, $3, $4::NUMERIC)
+                ON CONFLICT (contract_address, token_id)
+                DO UPDATE SET supply = token_supplies.supply + EXCLUDED.supply",
+            )
+            .bind(&contract_address)
+            .bind(&token_id)
+            .bind(&delta.token_type)
+            .bind((-amount).to_string())
+            .execute(&mut **transaction)
+            .await?;
+
+            return Ok(());
+        }
+
+        let owner = format!("{:#x}", delta.owner);
+
+        sqlx::query(
+            "INSERT INTO token_ownerships (contract_address, token_id, owner, amount, custody)
+            VALUES ($1, $2, $3, $4::NUMERIC, $5)
+            ON CONFLICT (contract_address, token_id, owner)
+            DO UPDATE SET
+                amount = token_ownerships.amount + EXCLUDED.amount,
+                custody = token_ownerships.custody OR EXCLUDED.custody",
+        )
+        .bind(&contract_address)
+        .bind(&token_id)
+        .bind(&owner)
+        .bind(amount.to_string())
+        .bind(delta.custody)
+        .execute(&mut **transaction)
+        .await?;
+
+        if delta.token_type == "ERC721" {
+            sqlx::query(
+                "DELETE FROM token_ownerships
+                WHERE contract_address = $1 AND token_id = $2 AND owner = $3 AND amount = 0",
+            )
+            .bind(&contract_address)
+            .bind(&token_id)
+            .bind(&owner)
+            .execute(&mut **transaction)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl OwnershipStore for PostgresStore {
+    fn apply_deltas<'a>(
+        &'a self,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+            Self::apply_block_deltas(&mut transaction, block_number, block_hash, deltas).await?;
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn revert_deltas<'a>(
+        &'a self,
+        block_hash: H256,
+        inverse_deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+            Self::revert_block_deltas(&mut transaction, block_hash, inverse_deltas).await?;
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn ownership_amount<'a>(
+        &'a self,
+        contract_address: H160,
+        token_id: Option<&'a str>,
+        owner: H160,
+    ) -> BoxFuture<'a, StorageResult<Amount>> {
+        async move {
+            let row = sqlx::query(
+                "SELECT amount::TEXT AS amount FROM token_ownerships
+                WHERE contract_address = $1 AND token_id = $2 AND owner = $3",
+            )
+            .bind(format!("{:#x}", contract_address))
+            .bind(token_id.unwrap_or_default())
+            .bind(format!("{:#x}", owner))
+            .fetch_optional(&self.pool)
+            .await?;
+
+            match row {
+                Some(row) => Ok(row.try_get::<String, _>("amount")?.parse()?),
+                None => Ok(Amount::zero()),
+            }
+        }
+        .boxed()
+    }
+
+    fn checkpoint(&self) -> BoxFuture<'_, StorageResult<Option<U64>>> {
+        async move {
+            let block_number: Option<i64> =
+                sqlx::query_scalar("SELECT block_number FROM sync_state WHERE id = $1")
+                    .bind(CHECKPOINT_ID)
+                    .fetch_optional(&self.pool)
+                    .await?;
+
+            Ok(block_number.map(|block_number| U64::from(block_number as u64)))
+        }
+        .boxed()
+    }
+
+    fn set_checkpoint(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+            Self::write_checkpoint(&mut transaction, block_number).await?;
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn apply_block<'a>(&'a self, block: &'a JournaledBlock) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+
+            Self::apply_block_deltas(
+                &mut transaction,
+                block.block_number,
+                Some(block.block_hash),
+                &block.deltas,
+            )
+            .await?;
+
+            sqlx::query(
+                "INSERT INTO block_journal (block_number, block_hash, parent_hash, deltas)
+                VALUES ($1, $2, $3, $4::JSONB)
+                ON CONFLICT (block_number) DO UPDATE SET
+                    block_hash = EXCLUDED.block_hash,
+                    parent_hash = EXCLUDED.parent_hash,
+                    deltas = EXCLUDED.deltas",
+            )
+            .bind(block.block_number.as_u64() as i64)
+            .bind(format!("{:#x}", block.block_hash))
+            .bind(format!("{:#x}", block.parent_hash))
+            .bind(serde_json::to_string(&block.deltas)?)
+            .execute(&mut *transaction)
+            .await?;
+
+            Self::write_checkpoint(&mut transaction, block.block_number).await?;
+
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn journaled_block(
+        &self,
+        block_number: U64,
+    ) -> BoxFuture<'_, StorageResult<Option<JournaledBlock>>> {
+        async move {
+            let row = sqlx::query(
+                "SELECT block_hash, parent_hash, deltas::TEXT AS deltas FROM block_journal
+                WHERE block_number = $1",
+            )
+            .bind(block_number.as_u64() as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+            match row {
+                Some(row) => Ok(Some(JournaledBlock {
+                    block_number,
+                    block_hash: H256::from_str(&row.try_get::<String, _>("block_hash")?)?,
+                    parent_hash: H256::from_str(&row.try_get::<String, _>("parent_hash")?)?,
+                    deltas: serde_json::from_str(&row.try_get::<String, _>("deltas")?)?,
+                })),
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    fn revert_block(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let block = match self.journaled_block(block_number).await? {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+
+            let mut transaction = self.pool.begin().await?;
+
+            Self::revert_block_deltas(&mut transaction, block.block_hash, &block.inverse_deltas())
+                .await?;
+
+            sqlx::query("DELETE FROM block_journal WHERE block_number = $1")
+                .bind(block_number.as_u64() as i64)
+                .execute(&mut *transaction)
+                .await?;
+
+            Self::write_checkpoint(&mut transaction, block_number - U64::from(1u8)).await?;
+
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn prune_journal(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            sqlx::query("DELETE FROM block_journal WHERE block_number < $1")
+                .bind(block_number.as_u64() as i64)
+                .execute(&self.pool)
+                .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn token_type(&self, contract_address: H160) -> BoxFuture<'_, StorageResult<Option<String>>> {
+        async move {
+            Ok(
+                sqlx::query_scalar("SELECT token_type FROM contract_addresses WHERE address = $1")
+                    .bind(format!("{:#x}", contract_address))
+                    .fetch_optional(&self.pool)
+                    .await?,
+            )
+        }
+        .boxed()
+    }
+
+    fn set_token_type<'a>(
+        &'a self,
+        contract_address: H160,
+        token_type: &'a str,
+        classified_by: ClassificationMethod,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            sqlx::query(
+                "INSERT INTO contract_addresses (address, token_type, classified_by)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (address) DO UPDATE SET
+                    token_type = EXCLUDED.token_type,
+                    classified_by = EXCLUDED.classified_by",
+            )
+            .bind(format!("{:#x}", contract_address))
+            .bind(token_type)
+            .bind(classified_by.to_string())
+            .execute(&self.pool)
+            .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn reset(&self) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            sqlx::query(
+                "TRUNCATE token_ownerships, token_supplies, applied_blocks, sync_state, block_journal",
+            )
+            .execute(&self.pool)
+            .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,193 @@
+use crate::ownership::{OwnershipDelta, Transfer};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use web3::types::{Address, Log, H256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Number of Feistel rounds used by the reversible mode.
+const FEISTEL_ROUNDS: u8 = 8;
+
+/// How owner addresses are written to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnerProtectionMode {
+    /// Owners are stored as is.
+    #[default]
+    Plain,
+    /// Owners are replaced by a keyed hash. The same owner always maps to the same pseudonym, but
+    /// the address cannot be recovered.
+    Hash,
+    /// Owners are encrypted with a keyed permutation and can be revealed again with the same key.
+    Encrypt,
+}
+
+impl std::str::FromStr for OwnerProtectionMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "plain" => Ok(OwnerProtectionMode::Plain),
+            "hash" => Ok(OwnerProtectionMode::Hash),
+            "encrypt" => Ok(OwnerProtectionMode::Encrypt),
+            _ => Err(format!("Unknown owner protection mode {}", mode)),
+        }
+    }
+}
+
+/// Protects owner addresses before they reach storage.
+///
+/// Both protected forms are 20 bytes long, so they are stored in the same format as plain addresses
+/// and identical owners keep matching in queries.
+#[derive(Clone)]
+pub struct OwnerProtection {
+    mode: OwnerProtectionMode,
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for OwnerProtection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnerProtection")
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for OwnerProtection {
+    fn default() -> Self {
+        Self {
+            mode: OwnerProtectionMode::Plain,
+            key: Vec::new(),
+        }
+    }
+}
+
+impl OwnerProtection {
+    /// Fails when a protecting mode is requested without a key.
+    pub fn new(mode: OwnerProtectionMode, key: Option<String>) -> Result<Self, String> {
+        let key = match (mode, key) {
+            (OwnerProtectionMode::Plain, _) => Vec::new(),
+            (_, Some(key)) if !key.is_empty() => key.into_bytes(),
+            _ => return Err(format!("Owner protection mode {:?} requires a key", mode)),
+        };
+
+        Ok(Self { mode, key })
+    }
+
+    pub fn mode(&self) -> OwnerProtectionMode {
+        self.mode
+    }
+
+    /// Returns the form an owner address is stored as.
+    pub fn protect(&self, owner: Address) -> Address {
+        match self.mode {
+            OwnerProtectionMode::Plain => owner,
+            OwnerProtectionMode::Hash => {
+                let mut mac = self.mac();
+                mac.update(b"owner-hash");
+                mac.update(owner.as_bytes());
+                Address::from_slice(&mac.finalize().into_bytes()[..20])
+            }
+            OwnerProtectionMode::Encrypt => {
+                let bytes = owner.as_bytes();
+                let (mut left, mut right) = (bytes[..10].to_vec(), bytes[10..].to_vec());
+
+                for round in 0..FEISTEL_ROUNDS {
+                    let mixed = xor(&left, &self.round_function(round, &right));
+                    left = right;
+                    right = mixed;
+                }
+
+                Address::from_slice(&[left, right].concat())
+            }
+        }
+    }
+
+    /// Recovers the owner address from its stored form. Returns `None` when the mode is not
+    /// reversible.
+    pub fn reveal(&self, stored: Address) -> Option<Address> {
+        match self.mode {
+            OwnerProtectionMode::Plain => Some(stored),
+            OwnerProtectionMode::Hash => None,
+            OwnerProtectionMode::Encrypt => {
+                let bytes = stored.as_bytes();
+                let (mut left, mut right) = (bytes[..10].to_vec(), bytes[10..].to_vec());
+
+                for round in (0..FEISTEL_ROUNDS).rev() {
+                    let mixed = xor(&right, &self.round_function(round, &left));
+                    right = left;
+                    left = mixed;
+                }
+
+                Some(Address::from_slice(&[left, right].concat()))
+            }
+        }
+    }
+
+    /// Protects the owner of a delta. Supply deltas of the zero address are left as they are.
+    pub(crate) fn protect_delta(&self, delta: OwnershipDelta) -> OwnershipDelta {
+        OwnershipDelta {
+            owner: self.protect_non_zero(delta.owner),
+            ..delta
+        }
+    }
+
+    /// Protects both sides of a transfer, except the zero address of mints and burns.
+    pub(crate) fn protect_transfer(&self, transfer: Transfer) -> Transfer {
+        Transfer {
+            from: self.protect_non_zero(transfer.from),
+            to: self.protect_non_zero(transfer.to),
+            ..transfer
+        }
+    }
+
+    /// Protects the topics of a log after its event signature, up to `address_topics` of them,
+    /// holding an address. Topics holding anything else, as malformed logs may, are left as is.
+    pub(crate) fn protect_log(&self, log: &Log, address_topics: usize) -> Log {
+        let topics = log
+            .topics
+            .iter()
+            .enumerate()
+            .map(|(position, topic)| {
+                if (1..=address_topics).contains(&position) && topic[..12].iter().all(|b| *b == 0) {
+                    H256::from(self.protect_non_zero(Address::from_slice(&topic[12..])))
+                } else {
+                    *topic
+                }
+            })
+            .collect();
+
+        Log {
+            topics,
+            ..log.clone()
+        }
+    }
+
+    fn protect_non_zero(&self, owner: Address) -> Address {
+        if owner == Address::zero() {
+            owner
+        } else {
+            self.protect(owner)
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    fn round_function(&self, round: u8, half: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(b"owner-encrypt");
+        mac.update(&[round]);
+        mac.update(half);
+        mac.finalize().into_bytes()[..half.len()].to_vec()
+    }
+}
+
+fn xor(left: &[u8], right: &[u8]) -> Vec<u8> {
+    left.iter()
+        .zip(right)
+        .map(|(left, right)| left ^ right)
+        .collect()
+}
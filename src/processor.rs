@@ -0,0 +1,926 @@
+use crate::{
+    discovery::ContractDiscovered,
+    legacy,
+    ownership::{OwnershipDelta, SelfTransferPolicy, Transfer},
+    store::{ClassificationMethod, ContractAddress, Store, TokenMetadata, UNKNOWN_TOKEN_TYPE},
+    RpcTransport, WorkerConfig,
+};
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use web3::{
+    contract::{Contract, Options},
+    ethabi::{decode, param_type::ParamType, Token},
+    signing::keccak256,
+    types::{Address, BlockId, Bytes, CallRequest, Log, H256, U256, U64},
+    Web3,
+};
+
+/// Topic signatures of the events the worker listens to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Signatures {
+    pub erc_20_and_721_transfer: H256,
+    pub erc_1155_transfer_single: H256,
+    pub erc_1155_transfer_batch: H256,
+    pub erc_777_sent: H256,
+    pub erc_777_minted: H256,
+    pub erc_777_burned: H256,
+    pub wrapped_token_deposit: H256,
+    pub wrapped_token_withdrawal: H256,
+    pub approval: H256,
+    pub approval_for_all: H256,
+}
+
+impl Signatures {
+    pub(crate) fn new() -> Self {
+        Self {
+            erc_20_and_721_transfer: H256::from(keccak256(
+                "Transfer(address,address,uint256)".as_bytes(),
+            )),
+            erc_1155_transfer_single: H256::from(keccak256(
+                "TransferSingle(address,address,address,uint256,uint256)".as_bytes(),
+            )),
+            erc_1155_transfer_batch: H256::from(keccak256(
+                "TransferBatch(address,address,address,uint256[],uint256[])".as_bytes(),
+            )),
+            erc_777_sent: H256::from(keccak256(
+                "Sent(address,address,address,uint256,bytes,bytes)".as_bytes(),
+            )),
+            erc_777_minted: H256::from(keccak256(
+                "Minted(address,address,uint256,bytes,bytes)".as_bytes(),
+            )),
+            erc_777_burned: H256::from(keccak256(
+                "Burned(address,address,uint256,bytes,bytes)".as_bytes(),
+            )),
+            wrapped_token_deposit: H256::from(keccak256("Deposit(address,uint256)".as_bytes())),
+            wrapped_token_withdrawal: H256::from(keccak256(
+                "Withdrawal(address,uint256)".as_bytes(),
+            )),
+            approval: H256::from(keccak256("Approval(address,address,uint256)".as_bytes())),
+            approval_for_all: H256::from(keccak256(
+                "ApprovalForAll(address,address,bool)".as_bytes(),
+            )),
+        }
+    }
+
+    /// Topics of the standard transfer events, followed by the ones of legacy contracts.
+    pub(crate) fn topics(&self) -> Vec<H256> {
+        let mut topics = vec![
+            self.erc_20_and_721_transfer,
+            self.erc_1155_transfer_single,
+            self.erc_1155_transfer_batch,
+            self.erc_777_sent,
+            self.erc_777_minted,
+            self.erc_777_burned,
+        ];
+        topics.extend(legacy::topics());
+        topics
+    }
+
+    /// Topics of the logs filter: the transfer events, plus the wrapped token and approval events
+    /// when they are indexed, since they are emitted by many more contracts.
+    pub(crate) fn filter_topics(&self, config: &WorkerConfig) -> Vec<H256> {
+        let mut topics = self.topics();
+
+        if !config.wrapped_tokens.is_empty() {
+            topics.extend([self.wrapped_token_deposit, self.wrapped_token_withdrawal]);
+        }
+
+        if config.index_approvals {
+            topics.extend([self.approval, self.approval_for_all]);
+        }
+
+        topics
+    }
+
+    /// Number of topics after the event signature of a log holding addresses: the operator, sender
+    /// and recipient of ERC1155 transfers and ERC777 sends, the two sides of other events. The
+    /// third topic of an ERC721 `Transfer` or `Approval` is a token id.
+    pub(crate) fn address_topics(&self, log: &Log) -> usize {
+        match log.topics.first() {
+            Some(topic)
+                if *topic == self.erc_1155_transfer_single
+                    || *topic == self.erc_1155_transfer_batch
+                    || *topic == self.erc_777_sent =>
+            {
+                3
+            }
+            _ => 2,
+        }
+    }
+
+    fn is_erc_777(&self, topic: H256) -> bool {
+        topic == self.erc_777_sent || topic == self.erc_777_minted || topic == self.erc_777_burned
+    }
+}
+
+/// Drops logs returned more than once by the provider, keeping the first occurrence. A log and its
+/// removal are distinct.
+pub(crate) fn dedup_logs(mut logs: Vec<Log>) -> Vec<Log> {
+    let mut seen_logs = HashSet::new();
+    logs.retain(|log| seen_logs.insert((log.transaction_hash, log.log_index, log.is_removed())));
+    logs
+}
+
+/// Drops the ERC777 logs of contracts that also emitted an ERC20 `Transfer` in the same
+/// transaction, since ERC20 compatible ERC777 tokens emit both for every movement.
+pub(crate) fn drop_paired_erc_777_logs(signatures: &Signatures, mut logs: Vec<Log>) -> Vec<Log> {
+    let erc_20_transfers: HashSet<_> = logs
+        .iter()
+        .filter(|log| log.topics.len() == 3 && log.topics[0] == signatures.erc_20_and_721_transfer)
+        .map(|log| (log.transaction_hash, log.address))
+        .collect();
+
+    logs.retain(|log| {
+        log.topics.is_empty()
+            || !signatures.is_erc_777(log.topics[0])
+            || !erc_20_transfers.contains(&(log.transaction_hash, log.address))
+    });
+
+    logs
+}
+
+/// Why a log could not be processed.
+#[derive(Debug)]
+pub(crate) enum LogError {
+    /// Reading or storing the classification of its contract failed, and processing it again may
+    /// succeed.
+    Database(mongodb::error::Error),
+    /// The log does not have the layout of the event its contract's type emits.
+    Undecodable(String),
+}
+
+impl From<mongodb::error::Error> for LogError {
+    fn from(error: mongodb::error::Error) -> Self {
+        LogError::Database(error)
+    }
+}
+
+/// Decodes the `Deposit` and `Withdrawal` logs of wrapped token contracts, which mint and burn
+/// without emitting a `Transfer`. The same events of other contracts are ignored.
+///
+/// Returns `None` for logs of other events.
+pub(crate) fn decode_wrapped_token_log(
+    signatures: &Signatures,
+    wrapped_tokens: &[Address],
+    log: &Log,
+) -> Option<Result<Vec<Transfer>, String>> {
+    let is_deposit = if log.topics.first() == Some(&signatures.wrapped_token_deposit) {
+        true
+    } else if log.topics.first() == Some(&signatures.wrapped_token_withdrawal) {
+        false
+    } else {
+        return None;
+    };
+
+    if !wrapped_tokens.contains(&log.address) || log.topics.len() != 2 {
+        return Some(Ok(Vec::new()));
+    }
+
+    let quantity = match decode_uint(&log.data.0) {
+        Ok(quantity) => quantity,
+        Err(error) => return Some(Err(format!("Invalid wrapped token amount... {}", error))),
+    };
+
+    let owner = Address::from(log.topics[1]);
+
+    // Deposits mint and withdrawals burn.
+    let (from, to) = if is_deposit {
+        (Address::zero(), owner)
+    } else {
+        (owner, Address::zero())
+    };
+
+    Some(Ok(vec![Transfer {
+        contract_address: log.address,
+        token_type: "ERC20".to_string(),
+        token_id: None,
+        from,
+        to,
+        amount: quantity,
+    }]))
+}
+
+/// Classifies the contract that emitted the log and decodes its transfers, along with the
+/// ownership deltas they imply under the contract's self transfer policy and the contract, when
+/// the log is the first one it was stored from.
+///
+/// `block_logs` holds every log of the block, which legacy contract decoders may need.
+pub(crate) async fn process_log(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    signatures: &Signatures,
+    log: &Log,
+    block_logs: &[Log],
+) -> Result<
+    (
+        Vec<Transfer>,
+        Vec<OwnershipDelta>,
+        Option<ContractDiscovered>,
+    ),
+    LogError,
+> {
+    let legacy_contract = legacy::find(log.address);
+    let mut discovered = None;
+
+    let (token_type, additional_token_types, self_transfer_policy) =
+        match store.contract_address(log.address).await? {
+            // Logs before the block an operator registered the contract from are ignored.
+            Some(contract_address)
+                if contract_address.start_block.is_some_and(|start_block| {
+                    log.block_number.unwrap_or_default().as_u64() < start_block as u64
+                }) =>
+            {
+                return Ok((Vec::new(), Vec::new(), discovered));
+            }
+            Some(contract_address) => (
+                Some(contract_address.token_type),
+                contract_address.additional_token_types,
+                contract_address.self_transfer_policy,
+            ),
+            None => {
+                let classification = match legacy_contract {
+                    Some(legacy_contract) => {
+                        println!(
+                            "Classifying {:#x} as the legacy {} contract",
+                            log.address, legacy_contract.name
+                        );
+                        Some(Classification::new("ERC721", ClassificationMethod::Legacy))
+                    }
+                    // Unreachable providers leave the contract unclassified until its next log.
+                    None => classify(web3, signatures, log).await.ok().flatten(),
+                };
+
+                if let Some(classification) = &classification {
+                    let stored = store
+                        .set_token_type(
+                            log.address,
+                            &classification.token_type,
+                            classification.classified_by,
+                            classification.implementation_address,
+                        )
+                        .await?;
+
+                    if stored {
+                        discovered = Some(ContractDiscovered {
+                            contract_address: log.address,
+                            token_type: classification.token_type.clone(),
+                            classified_by: classification.classified_by,
+                            implementation_address: classification.implementation_address,
+                            block_number: log.block_number.unwrap_or_default().as_u64(),
+                            transaction_hash: log.transaction_hash.unwrap_or_default(),
+                            log_index: log.log_index.unwrap_or_default(),
+                            discovered_at: SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs(),
+                        });
+                    }
+
+                    if classification.token_type == "ERC20" && legacy_contract.is_none() {
+                        cache_decimals(web3, store, log.address).await?;
+                    }
+
+                    if classification.token_type != UNKNOWN_TOKEN_TYPE {
+                        store
+                            .queue_token_metadata(&[TokenMetadata::pending(
+                                log.address,
+                                &classification.token_type,
+                                None,
+                            )])
+                            .await?;
+                    }
+                }
+
+                (
+                    classification.map(|classification| classification.token_type),
+                    Vec::new(),
+                    SelfTransferPolicy::default(),
+                )
+            }
+        };
+
+    let token_type = match token_type {
+        Some(token_type) if token_type != UNKNOWN_TOKEN_TYPE => token_type,
+        _ => return Ok((Vec::new(), Vec::new(), discovered)),
+    };
+
+    // Semi-fungible contracts emit ERC20 and ERC721 transfers alike, each decoded as the type of
+    // its shape rather than failing to decode as the type the contract was classified as.
+    let token_type = match transfer_shape(signatures, log) {
+        Some(shape)
+            if shape != token_type
+                && legacy_contract.is_none()
+                && matches!(token_type.as_str(), "ERC20" | "ERC721") =>
+        {
+            if !additional_token_types.iter().any(|other| other == shape) {
+                println!(
+                    "Contract {:#x}, classified as {}, also emits {} transfers",
+                    log.address, token_type, shape
+                );
+                store.add_token_type(log.address, shape).await?;
+
+                if shape == "ERC20" {
+                    cache_decimals(web3, store, log.address).await?;
+                }
+            }
+
+            shape.to_string()
+        }
+        _ => token_type,
+    };
+
+    let transfers = decode_classified_log(signatures, log, block_logs, &token_type)
+        .map_err(LogError::Undecodable)?;
+
+    let deltas = transfers
+        .iter()
+        .flat_map(|transfer| transfer.deltas(self_transfer_policy))
+        .collect();
+
+    Ok((transfers, deltas, discovered))
+}
+
+/// Decodes the transfers of a log of a contract classified as `token_type`, with the decoder of
+/// the contract when it is a legacy one.
+pub(crate) fn decode_classified_log(
+    signatures: &Signatures,
+    log: &Log,
+    block_logs: &[Log],
+    token_type: &str,
+) -> Result<Vec<Transfer>, String> {
+    match legacy::find(log.address) {
+        Some(legacy_contract) => Ok(legacy_contract
+            .decoder
+            .decode(log, block_logs)
+            .into_iter()
+            .map(|transfer| Transfer {
+                contract_address: log.address,
+                token_type: "ERC721".to_string(),
+                token_id: Some(transfer.token_id.to_string()),
+                from: transfer.from,
+                to: transfer.to,
+                amount: U256::one(),
+            })
+            .collect()),
+        None => decode_transfers(signatures, log, token_type),
+    }
+}
+
+/// Stores the decimals of a newly classified ERC20 contract. Failing to reach the provider only
+/// leaves them uncached, to be fetched again when the worker restarts.
+pub(crate) async fn cache_decimals(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    address: Address,
+) -> mongodb::error::Result<()> {
+    match decimals(web3, address).await {
+        Ok(decimals) => store.set_decimals(address, decimals).await,
+        Err(error) => {
+            eprintln!(
+                "Warning: Could not fetch the decimals of {:#x}... {}",
+                address, error
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Token types an operator can register a contract as.
+pub(crate) const REGISTRABLE_TOKEN_TYPES: [&str; 3] = ["ERC20", "ERC721", "ERC1155"];
+
+/// Registers the token type of a contract on behalf of an operator, overriding its classification,
+/// then caches its decimals and queues its metadata as a classification does. The ownerships
+/// already stored for the contract are left as they are.
+pub(crate) async fn register_contract(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    address: Address,
+    token_type: &str,
+    start_block: Option<U64>,
+) -> Result<ContractAddress, Box<dyn std::error::Error + Send + Sync>> {
+    if !REGISTRABLE_TOKEN_TYPES.contains(&token_type) {
+        return Err(format!(
+            "Unknown token type {}, expected one of {}",
+            token_type,
+            REGISTRABLE_TOKEN_TYPES.join(", ")
+        )
+        .into());
+    }
+
+    let contract_address = store
+        .register_contract(address, token_type, start_block)
+        .await?;
+
+    if token_type == "ERC20"
+        && contract_address.decimals.is_none()
+        && legacy::find(address).is_none()
+    {
+        cache_decimals(web3, store, address).await?;
+    }
+
+    store
+        .queue_token_metadata(&[TokenMetadata::pending(address, token_type, None)])
+        .await?;
+
+    Ok(store
+        .contract_address(address)
+        .await?
+        .unwrap_or(contract_address))
+}
+
+/// Slot holding the implementation address of EIP-1967 proxies.
+const EIP_1967_IMPLEMENTATION_SLOT: &str =
+    "360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc";
+
+/// The outcome of classifying a contract.
+pub(crate) struct Classification {
+    pub(crate) token_type: String,
+    pub(crate) classified_by: ClassificationMethod,
+    /// Implementation of the EIP-1967 proxy that reported the interface, when the contract is one.
+    pub(crate) implementation_address: Option<Address>,
+}
+
+impl Classification {
+    pub(crate) fn new(token_type: &str, classified_by: ClassificationMethod) -> Self {
+        Self {
+            token_type: token_type.to_string(),
+            classified_by,
+            implementation_address: None,
+        }
+    }
+}
+
+/// Determines the token type of the contract that emitted the log, `None` when it could not be
+/// determined for now, in which case the next log of the contract is classified again. Fails when
+/// the provider could not be reached to probe the contract.
+pub(crate) async fn classify(
+    web3: &Web3<RpcTransport>,
+    signatures: &Signatures,
+    log: &Log,
+) -> Result<Option<Classification>, web3::Error> {
+    let erc_721_interface_id: [u8; 4] = hex::decode("80ac58cd").unwrap()[0..4].try_into().unwrap();
+
+    let erc_1155_interface_id: [u8; 4] = hex::decode("d9b67a26").unwrap()[0..4].try_into().unwrap();
+
+    // ERC777 tokens are fungible and share the ERC20 balance model.
+    if (log.topics[0] == signatures.erc_20_and_721_transfer && log.topics.len() == 3)
+        || signatures.is_erc_777(log.topics[0])
+    {
+        Ok(Some(Classification::new(
+            "ERC20",
+            ClassificationMethod::Event,
+        )))
+    } else if log.topics[0] == signatures.erc_20_and_721_transfer && log.topics.len() == 4 {
+        match classify_by_interface(web3, log.address, erc_721_interface_id, "ERC721").await {
+            Ok(Some(classification)) => Ok(Some(classification)),
+            Err(web3::contract::Error::Api(error)) if !matches!(error, web3::Error::Rpc(_)) => {
+                Err(error)
+            }
+            // Many NFT contracts predate ERC165 and revert or answer false.
+            _ => classify_erc_721_heuristically(web3, log).await,
+        }
+    } else if log.topics[0] == signatures.erc_1155_transfer_single
+        || log.topics[0] == signatures.erc_1155_transfer_batch
+    {
+        match classify_by_interface(web3, log.address, erc_1155_interface_id, "ERC1155").await {
+            Ok(classification) => Ok(classification),
+            Err(web3::contract::Error::Api(error)) if !matches!(error, web3::Error::Rpc(_)) => {
+                Err(error)
+            }
+            Err(_) => Ok(None),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Classifies the contract as `token_type` when it reports the interface through ERC165. When it
+/// does not and the contract is an EIP-1967 proxy, its implementation is asked instead, since
+/// some proxies fail to forward `supportsInterface`.
+async fn classify_by_interface(
+    web3: &Web3<RpcTransport>,
+    address: Address,
+    interface_id: [u8; 4],
+    token_type: &str,
+) -> Result<Option<Classification>, web3::contract::Error> {
+    let reported = supports_interface(web3, address, interface_id).await;
+
+    if let Ok(true) = reported {
+        return Ok(Some(Classification::new(
+            token_type,
+            ClassificationMethod::Erc165,
+        )));
+    }
+
+    if let Some(implementation_address) = proxy_implementation(web3, address).await {
+        if let Ok(true) = supports_interface(web3, implementation_address, interface_id).await {
+            println!(
+                "Classifying the proxy {:#x} as {} through its implementation {:#x}",
+                address, token_type, implementation_address
+            );
+
+            return Ok(Some(Classification {
+                implementation_address: Some(implementation_address),
+                ..Classification::new(token_type, ClassificationMethod::Erc165)
+            }));
+        }
+    }
+
+    reported.map(|_| None)
+}
+
+async fn supports_interface(
+    web3: &Web3<RpcTransport>,
+    address: Address,
+    interface_id: [u8; 4],
+) -> Result<bool, web3::contract::Error> {
+    let contract = Contract::from_json(
+        web3.eth(),
+        address,
+        include_bytes!("supports_interface_abi.json"),
+    )
+    .unwrap();
+
+    contract
+        .query(
+            "supportsInterface",
+            (interface_id,),
+            None,
+            Options::default(),
+            None,
+        )
+        .await
+}
+
+/// Reads the implementation address from the EIP-1967 slot, `None` when the contract is not a
+/// proxy or the slot could not be read.
+async fn proxy_implementation(web3: &Web3<RpcTransport>, address: Address) -> Option<Address> {
+    let slot = U256::from_str_radix(EIP_1967_IMPLEMENTATION_SLOT, 16).unwrap();
+
+    let value = web3.eth().storage(address, slot, None).await.ok()?;
+    let implementation_address = Address::from(value);
+
+    if implementation_address == Address::default() {
+        None
+    } else {
+        Some(implementation_address)
+    }
+}
+
+/// Outcome of an `eth_call` made to probe a contract.
+enum Probe {
+    Returned(Vec<u8>),
+    Reverted,
+    /// The provider could not be reached, so nothing is known about the contract.
+    Failed(web3::Error),
+}
+
+async fn probe(web3: &Web3<RpcTransport>, address: Address, data: Vec<u8>) -> Probe {
+    let call = CallRequest {
+        to: Some(address),
+        data: Some(Bytes(data)),
+        ..Default::default()
+    };
+
+    match web3.eth().call(call, None).await {
+        Ok(output) => Probe::Returned(output.0),
+        Err(web3::Error::Rpc(_)) => Probe::Reverted,
+        Err(error) => Probe::Failed(error),
+    }
+}
+
+/// Calls a view function taking words as arguments, at the latest block unless `block` is given.
+/// Returns `None` when the call reverts or returns less than a word.
+pub(crate) async fn call(
+    web3: &Web3<RpcTransport>,
+    address: Address,
+    signature: &str,
+    arguments: &[H256],
+    block: Option<BlockId>,
+) -> Result<Option<Vec<u8>>, web3::Error> {
+    let mut data = keccak256(signature.as_bytes())[..4].to_vec();
+    for argument in arguments {
+        data.extend_from_slice(argument.as_bytes());
+    }
+
+    let call = CallRequest {
+        to: Some(address),
+        data: Some(Bytes(data)),
+        ..Default::default()
+    };
+
+    match web3.eth().call(call, block).await {
+        Ok(output) if output.0.len() >= 32 => Ok(Some(output.0)),
+        Ok(_) | Err(web3::Error::Rpc(_)) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// `decimals()` of a fungible token contract, `None` when the contract does not implement it or
+/// reports more decimals than fit in the `uint8` the standard declares.
+pub(crate) async fn decimals(
+    web3: &Web3<RpcTransport>,
+    address: Address,
+) -> Result<Option<i32>, web3::Error> {
+    Ok(call(web3, address, "decimals()", &[], None)
+        .await?
+        .map(|output| U256::from_big_endian(&output[..32]))
+        .filter(|decimals| *decimals <= U256::from(u8::MAX))
+        .map(|decimals| decimals.as_u32() as i32))
+}
+
+/// Encodes an integer argument of `call`.
+pub(crate) fn word(value: U256) -> H256 {
+    let mut word = [0u8; 32];
+    value.to_big_endian(&mut word);
+    H256::from(word)
+}
+
+/// Classifies a contract emitting a `Transfer` with an indexed third parameter, as ERC721 does,
+/// without relying on ERC165. ERC721 transfers carry no data, and the contract has to answer
+/// `ownerOf(tokenId)` with an address, or `balanceOf(address)` with a single word when the token
+/// has been burned since.
+///
+/// Contracts that fail the probes are classified as `UNKNOWN` so they are not probed again.
+async fn classify_erc_721_heuristically(
+    web3: &Web3<RpcTransport>,
+    log: &Log,
+) -> Result<Option<Classification>, web3::Error> {
+    let heuristic = |token_type: &str| {
+        Ok(Some(Classification::new(
+            token_type,
+            ClassificationMethod::Heuristic,
+        )))
+    };
+
+    if !log.data.0.is_empty() {
+        return heuristic(UNKNOWN_TOKEN_TYPE);
+    }
+
+    let owner_of = [
+        &keccak256(b"ownerOf(uint256)")[..4],
+        log.topics[3].as_bytes(),
+    ]
+    .concat();
+
+    match probe(web3, log.address, owner_of).await {
+        Probe::Returned(output)
+            if output.len() == 32 && output[..12].iter().all(|byte| *byte == 0) =>
+        {
+            return heuristic("ERC721");
+        }
+        Probe::Failed(error) => return Err(error),
+        _ => {}
+    }
+
+    let holder = if Address::from(log.topics[2]) != Address::default() {
+        log.topics[2]
+    } else {
+        log.topics[1]
+    };
+
+    let balance_of = [&keccak256(b"balanceOf(address)")[..4], holder.as_bytes()].concat();
+
+    match probe(web3, log.address, balance_of).await {
+        Probe::Returned(output) if output.len() == 32 => heuristic("ERC721"),
+        Probe::Failed(error) => Err(error),
+        _ => {
+            println!(
+                "Classifying {:#x} as unknown, it emits Transfer events but is not an ERC721 contract",
+                log.address
+            );
+            heuristic(UNKNOWN_TOKEN_TYPE)
+        }
+    }
+}
+
+/// Decodes the transfers of a log without classifying its contract, inferring the token type from
+/// the shape of the event instead. Logs of no token standard's shape are skipped rather than
+/// failing, since they come from contracts nothing is known about.
+pub(crate) fn decode_transfers_by_shape(signatures: &Signatures, log: &Log) -> Vec<Transfer> {
+    let topic = match log.topics.first() {
+        Some(topic) => *topic,
+        None => return Vec::new(),
+    };
+
+    let token_type = if topic == signatures.erc_20_and_721_transfer {
+        match transfer_shape(signatures, log) {
+            Some(token_type) => token_type,
+            None => return Vec::new(),
+        }
+    } else if topic == signatures.erc_1155_transfer_single {
+        if log.topics.len() != 4 || log.data.0.len() != 64 {
+            return Vec::new();
+        }
+        "ERC1155"
+    } else if topic == signatures.erc_1155_transfer_batch {
+        let decoded = decode(
+            &[
+                ParamType::Array(Box::new(ParamType::Uint(256))),
+                ParamType::Array(Box::new(ParamType::Uint(256))),
+            ],
+            &log.data.0,
+        );
+        match decoded.as_deref() {
+            Ok([Token::Array(token_ids), Token::Array(quantities)])
+                if log.topics.len() == 4 && token_ids.len() == quantities.len() => {}
+            _ => return Vec::new(),
+        }
+        "ERC1155"
+    } else if signatures.is_erc_777(topic) {
+        let topics = if topic == signatures.erc_777_sent {
+            4
+        } else {
+            3
+        };
+        let decoded = decode(
+            &[ParamType::Uint(256), ParamType::Bytes, ParamType::Bytes],
+            &log.data.0,
+        );
+        if log.topics.len() != topics || decoded.is_err() {
+            return Vec::new();
+        }
+        "ERC20"
+    } else {
+        return Vec::new();
+    };
+
+    decode_transfers(signatures, log, token_type).unwrap_or_default()
+}
+
+/// Token type of a `Transfer` event shared by ERC20 and ERC721 by its shape: an amount in the data
+/// for ERC20, and a token id as a third indexed parameter for ERC721.
+pub(crate) fn transfer_shape(signatures: &Signatures, log: &Log) -> Option<&'static str> {
+    if log.topics.first() != Some(&signatures.erc_20_and_721_transfer) {
+        return None;
+    }
+
+    match (log.topics.len(), log.data.0.len()) {
+        (3, 32) => Some("ERC20"),
+        (4, 0) => Some("ERC721"),
+        _ => None,
+    }
+}
+
+/// Decodes a single `uint256` word.
+fn decode_uint(data: &[u8]) -> Result<U256, String> {
+    match decode(&[ParamType::Uint(256)], data).map_err(|error| error.to_string())?[..] {
+        [Token::Uint(value)] => Ok(value),
+        _ => Err("Expected a uint256".to_string()),
+    }
+}
+
+/// Decodes the transfers of a log of a contract of type `token_type`, failing when the log does
+/// not have the layout of the event.
+fn decode_transfers(
+    signatures: &Signatures,
+    log: &Log,
+    token_type: &str,
+) -> Result<Vec<Transfer>, String> {
+    let topics = |count: usize| {
+        if log.topics.len() < count {
+            Err(format!(
+                "{} log with {} topics instead of {}",
+                token_type,
+                log.topics.len(),
+                count
+            ))
+        } else {
+            Ok(())
+        }
+    };
+
+    let transfer = |token_id: Option<String>, from: Address, to: Address, amount: U256| Transfer {
+        contract_address: log.address,
+        token_type: token_type.to_string(),
+        token_id,
+        from,
+        to,
+        amount,
+    };
+
+    topics(1)?;
+
+    if token_type == "ERC20" && signatures.is_erc_777(log.topics[0]) {
+        topics(if log.topics[0] == signatures.erc_777_sent {
+            4
+        } else {
+            3
+        })?;
+
+        let decoded_quantity = match decode(
+            &[ParamType::Uint(256), ParamType::Bytes, ParamType::Bytes],
+            &log.data.0,
+        ) {
+            Ok(decoded) => match decoded[0] {
+                Token::Uint(decoded_quantity) => decoded_quantity,
+                _ => return Err("Expected a uint256 ERC777 amount".to_string()),
+            },
+            Err(error) => return Err(format!("Invalid ERC777 event data... {}", error)),
+        };
+
+        let (from, to) = if log.topics[0] == signatures.erc_777_sent {
+            (Address::from(log.topics[2]), Address::from(log.topics[3]))
+        } else if log.topics[0] == signatures.erc_777_minted {
+            (Address::default(), Address::from(log.topics[2]))
+        } else {
+            (Address::from(log.topics[2]), Address::default())
+        };
+
+        Ok(vec![transfer(None, from, to, decoded_quantity)])
+    } else if token_type == "ERC20" {
+        topics(3)?;
+
+        let decoded_quantity = decode_uint(&log.data.0)
+            .map_err(|error| format!("Invalid ERC20 Transfer amount... {}", error))?;
+
+        Ok(vec![transfer(
+            None,
+            Address::from(log.topics[1]),
+            Address::from(log.topics[2]),
+            decoded_quantity,
+        )])
+    } else if token_type == "ERC721" {
+        topics(4)?;
+
+        let decoded_token_id = U256::from_big_endian(log.topics[3].as_bytes());
+
+        Ok(vec![transfer(
+            Some(decoded_token_id.to_string()),
+            Address::from(log.topics[1]),
+            Address::from(log.topics[2]),
+            U256::one(),
+        )])
+    } else if token_type == "ERC1155" {
+        topics(4)?;
+
+        let from = Address::from(log.topics[2]);
+        let to = Address::from(log.topics[3]);
+
+        let mut transferred_tokens = Vec::new();
+
+        if log.topics[0] == signatures.erc_1155_transfer_single {
+            match decode(&[ParamType::Uint(256), ParamType::Uint(256)], &log.data.0) {
+                Ok(decoded) => {
+                    if let (Token::Uint(token_id), Token::Uint(quantity)) =
+                        (decoded[0].to_owned(), decoded[1].to_owned())
+                    {
+                        transferred_tokens.push(transfer(
+                            Some(token_id.to_string()),
+                            from,
+                            to,
+                            quantity,
+                        ))
+                    }
+                }
+                Err(error) => return Err(format!("Invalid TransferSingle data... {}", error)),
+            };
+        } else if log.topics[0] == signatures.erc_1155_transfer_batch {
+            match decode(
+                &[
+                    ParamType::Array(Box::new(ParamType::Uint(256))),
+                    ParamType::Array(Box::new(ParamType::Uint(256))),
+                ],
+                &log.data.0,
+            ) {
+                Ok(decoded) => {
+                    let (token_ids, quantities) = match &decoded[..] {
+                        [Token::Array(token_ids), Token::Array(quantities)] => {
+                            (token_ids, quantities)
+                        }
+                        _ => return Err("Expected uint256 arrays of TransferBatch".to_string()),
+                    };
+
+                    // Pairing ids and values of arrays of different lengths would drop or
+                    // misattribute transfers, so the whole batch is left undecoded instead.
+                    if token_ids.len() != quantities.len() {
+                        return Err(format!(
+                            "TransferBatch with {} ids and {} values",
+                            token_ids.len(),
+                            quantities.len()
+                        ));
+                    }
+
+                    for (token_id, quantity) in token_ids.iter().zip(quantities) {
+                        if let (Token::Uint(token_id), Token::Uint(quantity)) = (token_id, quantity)
+                        {
+                            transferred_tokens.push(transfer(
+                                Some(token_id.to_string()),
+                                from,
+                                to,
+                                *quantity,
+                            ))
+                        }
+                    }
+                }
+                Err(error) => return Err(format!("Invalid TransferBatch data... {}", error)),
+            };
+        }
+
+        Ok(transferred_tokens)
+    } else {
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,40 @@
+//! Periodic cleanup of the ERC20 and ERC1155 ownerships left with a zero balance, for zero balance
+//! policies that do not prune them as soon as their balance drops to zero.
+
+use crate::store::Store;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Maximum number of ownerships pruned by a single query.
+const PRUNE_BATCH_SIZE: i64 = 1000;
+
+/// Prunes the ownerships left with a zero balance every `interval`, following the zero balance
+/// policy of `store`.
+pub(crate) async fn prune_zero_balances(store: Store, interval: Duration) {
+    loop {
+        sleep(interval).await;
+
+        let mut pruned = 0;
+        loop {
+            match store.prune_zero_ownerships(PRUNE_BATCH_SIZE).await {
+                Ok(count) => {
+                    pruned += count;
+                    if count < PRUNE_BATCH_SIZE as usize {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not prune the ownerships with a zero balance, retrying... {}",
+                        error
+                    );
+                    break;
+                }
+            }
+        }
+
+        if pruned > 0 {
+            println!("Pruned {} ownerships with a zero balance", pruned);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{
+    store::{QuorumMismatch, Store},
+    RpcTransport,
+};
+use mongodb::bson::DateTime;
+use std::{collections::BTreeSet, error};
+use web3::{
+    types::{Filter, Log, H160, H256, U256, U64},
+    Web3,
+};
+
+/// Fields of a log compared between providers.
+type LogKey = (
+    Option<H256>,
+    Option<H256>,
+    Option<U256>,
+    H160,
+    Vec<H256>,
+    Vec<u8>,
+);
+
+/// A second, independent provider every fetched log is checked against.
+#[derive(Debug, Clone)]
+pub(crate) struct Quorum {
+    pub web3: Web3<RpcTransport>,
+}
+
+impl Quorum {
+    /// Fetches the logs matching `filter` from the quorum provider and fails unless they are the
+    /// same as `logs`, recording the mismatch in `quorum_mismatches`.
+    pub(crate) async fn verify(
+        &self,
+        store: &Store,
+        filter: Filter,
+        from_block: U64,
+        to_block: U64,
+        logs: &[Log],
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let quorum_logs = self.web3.eth().logs(filter).await?;
+
+        let primary_keys: BTreeSet<LogKey> = logs.iter().map(log_key).collect();
+        let quorum_keys: BTreeSet<LogKey> = quorum_logs.iter().map(log_key).collect();
+
+        if primary_keys == quorum_keys {
+            return Ok(());
+        }
+
+        let mismatch = QuorumMismatch {
+            from_block: from_block.as_u64() as i64,
+            to_block: to_block.as_u64() as i64,
+            primary_only: primary_keys.difference(&quorum_keys).map(log_id).collect(),
+            quorum_only: quorum_keys.difference(&primary_keys).map(log_id).collect(),
+            contract_addresses: primary_keys
+                .symmetric_difference(&quorum_keys)
+                .map(|key| key.3)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+            detected_at: DateTime::now(),
+        };
+
+        eprintln!(
+            "Error: Providers disagree on the logs of blocks {} to {}, {} only from the primary and {} only from the quorum provider",
+            from_block,
+            to_block,
+            mismatch.primary_only.len(),
+            mismatch.quorum_only.len()
+        );
+
+        store.record_quorum_mismatch(&mismatch).await?;
+
+        Err(format!("Quorum mismatch on blocks {} to {}", from_block, to_block).into())
+    }
+}
+
+fn log_key(log: &Log) -> LogKey {
+    (
+        log.block_hash,
+        log.transaction_hash,
+        log.log_index,
+        log.address,
+        log.topics.clone(),
+        log.data.0.clone(),
+    )
+}
+
+/// Identifies a log as `<transaction hash>:<log index>` in mismatch reports.
+fn log_id(key: &LogKey) -> String {
+    format!(
+        "{:#x}:{}",
+        key.1.unwrap_or_default(),
+        key.2.unwrap_or_default()
+    )
+}
@@ -0,0 +1,254 @@
+use crate::{
+    amount::Amount,
+    decimals::DecimalsRegistry,
+    privacy::OwnerProtection,
+    schema::OwnershipSchema,
+    store::{PortfolioEntry, Store, TokenOwnership},
+};
+use mongodb::{options::ClientOptions, Client, Database};
+use std::{
+    collections::HashMap,
+    error,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use web3::types::H160;
+
+/// Maximum number of cached quantities before expired entries are evicted.
+const DEFAULT_CAPACITY: usize = 100_000;
+
+type CacheKey = (H160, Option<String>, H160);
+
+/// Read-only access to the ownership model for other Rust services, answering repeated questions
+/// such as "does address X own token Y" from an in-memory cache.
+///
+/// Quantities are cached for `ttl`, so answers can lag behind the worker by up to that long.
+/// Absent ownerships are cached as a quantity of 0.
+#[derive(Debug)]
+pub struct OwnershipReader {
+    store: Store,
+    owner_protection: OwnerProtection,
+    ttl: Duration,
+    capacity: usize,
+    cache: Mutex<HashMap<CacheKey, (f64, Instant)>>,
+    decimals: DecimalsRegistry,
+}
+
+impl OwnershipReader {
+    pub fn new(database: &Database, ttl: Duration) -> Self {
+        let store = Store::new(database);
+
+        Self {
+            decimals: DecimalsRegistry::new(&store, &[]),
+            store,
+            owner_protection: OwnerProtection::default(),
+            ttl,
+            capacity: DEFAULT_CAPACITY,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn connect(
+        database_host: String,
+        database_name: String,
+        ttl: Duration,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let client = Client::with_options(ClientOptions::parse(database_host).await?)?;
+
+        Ok(Self::new(&client.database(&database_name), ttl))
+    }
+
+    /// Protection the worker applies to owner addresses, so queried owners match stored ones.
+    pub fn with_owner_protection(self, owner_protection: OwnerProtection) -> Self {
+        Self {
+            owner_protection,
+            ..self
+        }
+    }
+
+    /// Prefix the worker's collection names start with.
+    pub fn with_collection_prefix(self, collection_prefix: &str) -> Self {
+        let store = self.store.clone().with_collection_prefix(collection_prefix);
+
+        Self {
+            decimals: self.decimals.with_store(&store),
+            store,
+            ..self
+        }
+    }
+
+    /// Collection and field names the worker stores the ownership model under.
+    pub fn with_ownership_schema(self, ownership_schema: OwnershipSchema) -> Self {
+        let store = self.store.clone().with_ownership_schema(ownership_schema);
+
+        Self {
+            decimals: self.decimals.with_store(&store),
+            store,
+            ..self
+        }
+    }
+
+    /// Decimals the worker is configured to override, `--decimals-override`, so balances are
+    /// rendered as its outputs render them.
+    pub fn with_decimals_overrides(self, decimals_overrides: &[(H160, u32)]) -> Self {
+        Self {
+            decimals: DecimalsRegistry::new(&self.store, decimals_overrides),
+            ..self
+        }
+    }
+
+    /// Maximum number of cached quantities, expired entries are evicted once it is reached.
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+
+    /// Quantity of an NFT held by an owner.
+    pub async fn quantity(
+        &self,
+        contract_address: H160,
+        token_id: &str,
+        owner: H160,
+    ) -> mongodb::error::Result<f64> {
+        self.cached_quantity(contract_address, Some(token_id), owner)
+            .await
+    }
+
+    /// Whether an owner holds at least one unit of an NFT.
+    pub async fn owns(
+        &self,
+        contract_address: H160,
+        token_id: &str,
+        owner: H160,
+    ) -> mongodb::error::Result<bool> {
+        Ok(self.quantity(contract_address, token_id, owner).await? > 0.0)
+    }
+
+    /// Holders of the tokens a creator minted on a shared ERC1155 contract, such as the OpenSea
+    /// Shared Storefront, read from the database without going through the cache.
+    pub async fn creator_ownerships(
+        &self,
+        contract_address: H160,
+        creator: H160,
+    ) -> mongodb::error::Result<Vec<TokenOwnership>> {
+        self.store
+            .creator_ownerships(contract_address, creator)
+            .await
+    }
+
+    /// ERC20 balance of an owner.
+    pub async fn balance(
+        &self,
+        contract_address: H160,
+        owner: H160,
+    ) -> mongodb::error::Result<f64> {
+        self.cached_quantity(contract_address, None, owner).await
+    }
+
+    /// Exact ERC20 balance of an owner, read from the database without going through the cache.
+    pub async fn exact_balance(
+        &self,
+        contract_address: H160,
+        owner: H160,
+    ) -> mongodb::error::Result<Amount> {
+        self.store
+            .ownership_amount(contract_address, None, self.owner_protection.protect(owner))
+            .await
+    }
+
+    /// ERC20 balance of an owner divided by `10^decimals` of the contract, as wallets display it.
+    /// `None` when the decimals of the contract are unknown.
+    pub async fn normalized_balance(
+        &self,
+        contract_address: H160,
+        owner: H160,
+    ) -> mongodb::error::Result<Option<f64>> {
+        let decimals = match self.decimals(contract_address).await? {
+            Some(decimals) => decimals,
+            None => return Ok(None),
+        };
+
+        let balance = self.balance(contract_address, owner).await?;
+
+        Ok(Some(balance / 10f64.powi(decimals as i32)))
+    }
+
+    /// Exact ERC20 balance of an owner in whole units, such as `1.5`, rendered as the worker's API
+    /// and exports render it. `None` when the decimals of the contract are unknown.
+    pub async fn display_balance(
+        &self,
+        contract_address: H160,
+        owner: H160,
+    ) -> mongodb::error::Result<Option<String>> {
+        let balance = self.exact_balance(contract_address, owner).await?;
+
+        self.decimals
+            .display_amount(contract_address, balance)
+            .await
+    }
+
+    /// Number of owners holding an ERC1155 token, or the tokens of an ERC20 or ERC721 contract when
+    /// `token_id` is `None`, read from the database without going through the cache.
+    pub async fn holder_count(
+        &self,
+        contract_address: H160,
+        token_id: Option<&str>,
+    ) -> mongodb::error::Result<i64> {
+        self.store
+            .token_holder_count(contract_address, token_id)
+            .await
+    }
+
+    /// Everything an owner holds, one entry per contract with the token ids held, read from the
+    /// database with a single indexed query without going through the cache.
+    pub async fn portfolio(&self, owner: H160) -> mongodb::error::Result<Vec<PortfolioEntry>> {
+        self.store
+            .portfolio(self.owner_protection.protect(owner))
+            .await
+    }
+
+    /// Decimals of a contract, its override or the `decimals()` cached by the worker.
+    pub async fn decimals(&self, contract_address: H160) -> mongodb::error::Result<Option<u32>> {
+        self.decimals.decimals(contract_address).await
+    }
+
+    /// Drops every cached quantity.
+    pub fn invalidate_all(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    async fn cached_quantity(
+        &self,
+        contract_address: H160,
+        token_id: Option<&str>,
+        owner: H160,
+    ) -> mongodb::error::Result<f64> {
+        let owner = self.owner_protection.protect(owner);
+        let key = (contract_address, token_id.map(str::to_string), owner);
+
+        if let Some((quantity, expires_at)) = self.cache.lock().unwrap().get(&key) {
+            if *expires_at > Instant::now() {
+                return Ok(*quantity);
+            }
+        }
+
+        let quantity = self
+            .store
+            .ownership_quantity(contract_address, token_id, owner)
+            .await?;
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.len() >= self.capacity {
+            cache.retain(|_, (_, expires_at)| *expires_at > now);
+
+            if cache.len() >= self.capacity {
+                cache.clear();
+            }
+        }
+
+        cache.insert(key, (quantity, now + self.ttl));
+
+        Ok(quantity)
+    }
+}
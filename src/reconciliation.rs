@@ -0,0 +1,387 @@
+//! Balance reconciliation of contracts emitting more events than the volume cap allows.
+//!
+//! The logs of a capped contract are not applied as deltas. The balances they touch are queued
+//! instead, and read from the contract every `reconciliation_interval` blocks, so the ownership
+//! model follows the contract at the cost of a call per queued balance.
+
+use crate::{
+    amount::Amount,
+    legacy,
+    ownership::{OwnershipDelta, Transfer},
+    privacy::OwnerProtection,
+    processor::{call, word},
+    store::{ReconciliationEntry, Store, TokenOwnership},
+    RpcTransport,
+};
+use futures::{future, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    error,
+};
+use web3::{
+    types::{Address, BlockId, BlockNumber, Log, H256, U256, U64},
+    Web3,
+};
+
+/// Maximum number of queued balances read by a single reconciliation.
+const RECONCILIATION_BATCH_SIZE: i64 = 1000;
+
+/// Number of balances read from the provider at the same time.
+const RECONCILIATION_CONCURRENCY: usize = 16;
+
+/// Contracts emitting more than `cap` of the logs of a block. Removed logs are not counted, and
+/// legacy contracts are never capped since their balances cannot be read in a standard way.
+pub(crate) fn over_cap(logs: &[Log], cap: u64) -> HashSet<Address> {
+    let mut counts: HashMap<Address, u64> = HashMap::new();
+
+    for log in logs.iter().filter(|log| !log.is_removed()) {
+        *counts.entry(log.address).or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .filter(|(address, count)| *count > cap && legacy::find(*address).is_none())
+        .map(|(address, _)| address)
+        .collect()
+}
+
+/// The balances touched by the transfers of a capped contract, whose owners are already
+/// protected. ERC721 tokens are queued once per token, whoever holds them.
+pub(crate) fn queue_entries(transfers: &[Transfer], block_number: U64) -> Vec<ReconciliationEntry> {
+    let entry = |transfer: &Transfer, owner: Option<Address>| ReconciliationEntry {
+        id: format!(
+            "{:#x}:{}:{}",
+            transfer.contract_address,
+            transfer.token_id.as_deref().unwrap_or_default(),
+            owner
+                .map(|owner| format!("{:#x}", owner))
+                .unwrap_or_default()
+        ),
+        contract_address: transfer.contract_address,
+        token_type: transfer.token_type.clone(),
+        token_id: transfer.token_id.clone(),
+        owner,
+        queued_at_block: block_number.as_u64() as i64,
+    };
+
+    transfers
+        .iter()
+        .flat_map(|transfer| {
+            if transfer.token_type == "ERC721" {
+                vec![entry(transfer, None)]
+            } else {
+                [transfer.from, transfer.to]
+                    .into_iter()
+                    .filter(|owner| *owner != Address::zero())
+                    .map(|owner| entry(transfer, Some(owner)))
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// Correction deltas bringing the queued balances to what the contracts report at `block_number`,
+/// together with the ids of the queue entries they settle.
+///
+/// Entries whose balance the contract refuses to report are settled without a delta. Supplies are
+/// not reconciled.
+pub(crate) async fn reconcile(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    owner_protection: &OwnerProtection,
+    block_number: U64,
+) -> Result<(Vec<OwnershipDelta>, Vec<String>), Box<dyn error::Error + Send + Sync>> {
+    let entries = store
+        .reconciliation_queue(RECONCILIATION_BATCH_SIZE)
+        .await?;
+
+    let mut deltas = Vec::new();
+
+    for chunk in entries.chunks(RECONCILIATION_CONCURRENCY) {
+        let corrections = future::try_join_all(
+            chunk
+                .iter()
+                .map(|entry| reconcile_entry(web3, store, owner_protection, entry, block_number)),
+        )
+        .await?;
+
+        deltas.extend(corrections.into_iter().flatten());
+    }
+
+    Ok((deltas, entries.into_iter().map(|entry| entry.id).collect()))
+}
+
+async fn reconcile_entry(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    owner_protection: &OwnerProtection,
+    entry: &ReconciliationEntry,
+    block_number: U64,
+) -> Result<Vec<OwnershipDelta>, Box<dyn error::Error + Send + Sync>> {
+    let delta = |owner: Address, amount: Amount| OwnershipDelta {
+        contract_address: entry.contract_address,
+        token_type: entry.token_type.clone(),
+        token_id: entry.token_id.clone(),
+        owner,
+        quantity: amount.to_f64(),
+        amount: Some(amount),
+        custody: false,
+    };
+
+    let at = Some(BlockId::Number(BlockNumber::Number(block_number)));
+
+    let token_id = match &entry.token_id {
+        Some(token_id) => Some(U256::from_dec_str(token_id).map_err(|error| error.to_string())?),
+        None => None,
+    };
+
+    let owner = match entry.owner {
+        Some(owner) => owner,
+        None => {
+            let token_id = token_id.ok_or("ERC721 reconciliation entry without a token id")?;
+
+            // A reverting `ownerOf` means the token has been burned.
+            let holder = call(
+                web3,
+                entry.contract_address,
+                "ownerOf(uint256)",
+                &[word(token_id)],
+                at,
+            )
+            .await?
+            .map(|output| Address::from(H256::from_slice(&output[..32])))
+            .filter(|holder| *holder != Address::zero())
+            .map(|holder| owner_protection.protect(holder))
+            .unwrap_or_default();
+
+            let mut deltas: Vec<_> = store
+                .token_owners(
+                    entry.contract_address,
+                    entry.token_id.as_deref().unwrap_or_default(),
+                )
+                .await?
+                .into_iter()
+                .filter(|ownership| ownership.owner != holder)
+                .map(|ownership| delta(ownership.owner, -ownership.exact_amount()))
+                .collect();
+
+            let held = store
+                .ownership_amount(entry.contract_address, entry.token_id.as_deref(), holder)
+                .await?;
+
+            if holder != Address::zero() && held.is_zero() {
+                deltas.push(delta(holder, Amount::from(U256::one())));
+            }
+
+            return Ok(deltas);
+        }
+    };
+
+    let revealed = owner_protection
+        .reveal(owner)
+        .ok_or("Owner protection does not allow revealing owners for reconciliation")?;
+
+    let output = match token_id {
+        Some(token_id) => {
+            call(
+                web3,
+                entry.contract_address,
+                "balanceOf(address,uint256)",
+                &[H256::from(revealed), word(token_id)],
+                at,
+            )
+            .await?
+        }
+        None => {
+            call(
+                web3,
+                entry.contract_address,
+                "balanceOf(address)",
+                &[H256::from(revealed)],
+                at,
+            )
+            .await?
+        }
+    };
+
+    let balance = match output {
+        Some(output) => Amount::from(U256::from_big_endian(&output[..32])),
+        None => return Ok(Vec::new()),
+    };
+
+    let stored = store
+        .ownership_amount(entry.contract_address, entry.token_id.as_deref(), owner)
+        .await?;
+
+    if balance == stored {
+        Ok(Vec::new())
+    } else {
+        Ok(vec![delta(owner, balance - stored)])
+    }
+}
+
+/// An ownership whose stored amount differs from the balance its contract reports.
+#[derive(Debug, Clone)]
+pub struct OwnershipMismatch {
+    pub token_id: Option<String>,
+    pub owner: Address,
+    pub stored: Amount,
+    pub on_chain: Amount,
+}
+
+/// Outcome of comparing the stored ownerships of a contract with the balances it reports.
+#[derive(Debug, Clone)]
+pub struct ContractVerification {
+    pub contract_address: Address,
+    pub token_type: String,
+    /// Block the balances were read at.
+    pub block_number: U64,
+    pub checked: usize,
+    /// Ownerships whose balance the contract refused to report.
+    pub unanswered: usize,
+    pub mismatches: Vec<OwnershipMismatch>,
+}
+
+/// Compares the stored ownerships of `contract_address` with a positive amount, the first `limit`
+/// of them when given, with the balances the contract reports at `block_number`. Custody
+/// ownerships are skipped, their tokens being held by the custodian on chain.
+///
+/// Ownerships moved by blocks after `block_number` while it runs are reported as mismatches.
+pub(crate) async fn verify_contract(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    owner_protection: &OwnerProtection,
+    contract_address: Address,
+    block_number: U64,
+    limit: Option<usize>,
+) -> Result<ContractVerification, Box<dyn error::Error + Send + Sync>> {
+    if legacy::find(contract_address).is_some() {
+        return Err("Legacy contracts cannot be verified since their balances cannot be read in a standard way".into());
+    }
+
+    let token_type = store
+        .contract_address(contract_address)
+        .await?
+        .ok_or("The contract has not been classified")?
+        .token_type;
+
+    let mut ownerships = Vec::new();
+    let mut stream = store.contract_ownerships(contract_address).await?;
+    while let Some(ownership) = stream.next().await {
+        let ownership = ownership?;
+        if ownership.custody || !ownership.exact_amount().is_positive() {
+            continue;
+        }
+
+        ownerships.push(ownership);
+        if limit.is_some_and(|limit| ownerships.len() >= limit) {
+            break;
+        }
+    }
+
+    let mut verification = ContractVerification {
+        contract_address,
+        token_type,
+        block_number,
+        checked: 0,
+        unanswered: 0,
+        mismatches: Vec::new(),
+    };
+
+    for chunk in ownerships.chunks(RECONCILIATION_CONCURRENCY) {
+        let balances = future::try_join_all(chunk.iter().map(|ownership| {
+            on_chain_balance(
+                web3,
+                owner_protection,
+                &verification.token_type,
+                ownership,
+                block_number,
+            )
+        }))
+        .await?;
+
+        for (ownership, balance) in chunk.iter().zip(balances) {
+            verification.checked += 1;
+
+            match balance {
+                None => verification.unanswered += 1,
+                Some(balance) if balance == ownership.exact_amount() => {}
+                Some(balance) => verification.mismatches.push(OwnershipMismatch {
+                    token_id: ownership.token_id.clone(),
+                    owner: ownership.owner,
+                    stored: ownership.exact_amount(),
+                    on_chain: balance,
+                }),
+            }
+        }
+    }
+
+    Ok(verification)
+}
+
+/// The balance of the ownership's token the contract reports for its owner, `None` when the call
+/// reverts. ERC721 ownerships are checked with `ownerOf`, which works with hashed owners too.
+async fn on_chain_balance(
+    web3: &Web3<RpcTransport>,
+    owner_protection: &OwnerProtection,
+    token_type: &str,
+    ownership: &TokenOwnership,
+    block_number: U64,
+) -> Result<Option<Amount>, Box<dyn error::Error + Send + Sync>> {
+    let at = Some(BlockId::Number(BlockNumber::Number(block_number)));
+
+    let token_id = match &ownership.token_id {
+        Some(token_id) => Some(U256::from_dec_str(token_id).map_err(|error| error.to_string())?),
+        None => None,
+    };
+
+    if token_type == "ERC721" {
+        let token_id = token_id.ok_or("ERC721 ownership without a token id")?;
+
+        return Ok(call(
+            web3,
+            ownership.contract_address,
+            "ownerOf(uint256)",
+            &[word(token_id)],
+            at,
+        )
+        .await?
+        .map(|output| {
+            let holder = Address::from(H256::from_slice(&output[..32]));
+            if owner_protection.protect(holder) == ownership.owner {
+                Amount::from(U256::one())
+            } else {
+                Amount::zero()
+            }
+        }));
+    }
+
+    let owner = owner_protection
+        .reveal(ownership.owner)
+        .ok_or("Owner protection does not allow revealing owners for verification")?;
+
+    let output = match token_id {
+        Some(token_id) => {
+            call(
+                web3,
+                ownership.contract_address,
+                "balanceOf(address,uint256)",
+                &[H256::from(owner), word(token_id)],
+                at,
+            )
+            .await?
+        }
+        None => {
+            call(
+                web3,
+                ownership.contract_address,
+                "balanceOf(address)",
+                &[H256::from(owner)],
+                at,
+            )
+            .await?
+        }
+    };
+
+    Ok(output.map(|output| Amount::from(U256::from_big_endian(&output[..32]))))
+}
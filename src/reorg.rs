@@ -0,0 +1,222 @@
+use crate::{
+    store::{DeltaBatch, DeltaFeedKind, JournalEntry, Store},
+    RpcTransport, WorkerConfig,
+};
+use std::error;
+use web3::{
+    types::{BlockId, BlockNumber, H256, U64},
+    Web3,
+};
+
+/// A block being reverted, recording how far the revert got like `PendingBlock` records how far a
+/// block write got, so a rollback retried after an error resumes after the writes that went
+/// through instead of reverting them twice.
+#[derive(Debug)]
+pub(crate) struct PendingRevert {
+    block_hash: H256,
+    /// Inverse deltas written without a transaction.
+    batch: Option<DeltaBatch>,
+    #[cfg(feature = "nats")]
+    nats_published: bool,
+}
+
+impl PendingRevert {
+    fn new(block_hash: H256) -> Self {
+        Self {
+            block_hash,
+            batch: None,
+            #[cfg(feature = "nats")]
+            nats_published: false,
+        }
+    }
+}
+
+/// Reverts the journaled blocks that are no longer part of the canonical chain, walking back from
+/// `block_number` until a journaled hash matches the chain again. Returns the first block that has
+/// to be re-indexed.
+///
+/// `pending` keeps the progress of the block being reverted when the rollback fails, for the next
+/// attempt to resume.
+pub(crate) async fn rollback(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    config: &WorkerConfig,
+    mut block_number: U64,
+    pending: &mut Option<PendingRevert>,
+) -> Result<U64, Box<dyn error::Error + Send + Sync>> {
+    while let Some(entry) = store.journal_entry(block_number).await? {
+        let canonical_block = web3
+            .eth()
+            .block(BlockId::Number(BlockNumber::Number(block_number)))
+            .await?;
+
+        if canonical_block.and_then(|block| block.hash) == Some(entry.block_hash) {
+            break;
+        }
+
+        println!(
+            "Rolling back orphaned block {} ({:#x})",
+            block_number, entry.block_hash
+        );
+
+        store
+            .record_reorg_coverage(block_number, entry.block_hash)
+            .await?;
+        revert_block(store, config, &entry, pending).await?;
+
+        block_number -= U64::from(1u8);
+    }
+
+    if store.journal_entry(block_number).await?.is_none() {
+        eprintln!(
+            "Warning: Reorg reached block {} which is older than the journal, ownerships may be inconsistent",
+            block_number
+        );
+    }
+
+    Ok(block_number + 1)
+}
+
+/// Reverts the journaled blocks after `to_block`, newest first, whatever the canonical chain, and
+/// moves the checkpoint back to `to_block` so they are indexed again. Every block after `to_block`
+/// up to the checkpoint has to be in the journal. Returns the number of blocks reverted.
+pub(crate) async fn rollback_to(
+    store: &Store,
+    config: &WorkerConfig,
+    to_block: U64,
+) -> Result<u64, Box<dyn error::Error + Send + Sync>> {
+    let last_processed_block = match store.last_processed_block().await? {
+        Some(last_processed_block) => last_processed_block,
+        None => return Err("No block is processed yet".into()),
+    };
+
+    if to_block >= last_processed_block {
+        return Err(format!(
+            "Block {} is not before the checkpoint at block {}",
+            to_block, last_processed_block
+        )
+        .into());
+    }
+
+    // Checked up front, so a rollback past the journal leaves the ownership model untouched.
+    let mut entries = Vec::new();
+    let mut block_number = last_processed_block;
+    while block_number > to_block {
+        match store.journal_entry(block_number).await? {
+            Some(entry) => entries.push(entry),
+            None => {
+                return Err(format!(
+                    "Block {} is not in the journal, only the last {} blocks can be rolled back",
+                    block_number,
+                    entries.len()
+                )
+                .into())
+            }
+        }
+        block_number -= U64::from(1u8);
+    }
+
+    let mut pending = None;
+    for entry in &entries {
+        println!(
+            "Rolling back block {} ({:#x})",
+            entry.block_number, entry.block_hash
+        );
+
+        revert_block(store, config, entry, &mut pending).await?;
+    }
+
+    Ok(entries.len() as u64)
+}
+
+/// Reverts the deltas of a journaled block and removes it from the journal, the transfer history,
+/// the balance history and the checkpoint.
+///
+/// Reverts are appended to the delta feed when it is enabled, published to NATS when configured,
+/// and applied to the mirrored ownership stores, before the deltas are reverted. With transactions,
+/// the deltas are reverted with the journal entry, the applied block record and the checkpoint in
+/// one. Without, the applied block record is removed once the deltas are reverted, so a block no
+/// longer recorded as applied is not reverted again, and `pending` resumes a revert that failed
+/// midway. Deltas written before a crash in the middle of a revert are reverted again, as the
+/// deltas of a block written without transactions are applied again.
+async fn revert_block(
+    store: &Store,
+    config: &WorkerConfig,
+    entry: &JournalEntry,
+    pending: &mut Option<PendingRevert>,
+) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+    let block_number = U64::from(entry.block_number as u64);
+
+    let revert = match pending {
+        Some(revert) if revert.block_hash == entry.block_hash => revert,
+        _ => pending.insert(PendingRevert::new(entry.block_hash)),
+    };
+
+    let inverse_deltas: Vec<_> = entry
+        .deltas
+        .iter()
+        .rev()
+        .map(|delta| delta.inverse())
+        .collect();
+
+    // Mirrors record the blocks they reverted, so a mirror reverted before is skipped.
+    for ownership_store in &config.ownership_stores {
+        ownership_store
+            .revert_deltas(entry.block_hash, &inverse_deltas)
+            .await?;
+        ownership_store.set_checkpoint(block_number - 1).await?;
+    }
+
+    if config.delta_feed_retention.is_some() {
+        store
+            .append_feed_entry(
+                DeltaFeedKind::Revert,
+                block_number,
+                entry.block_hash,
+                inverse_deltas.clone(),
+            )
+            .await?;
+    }
+
+    #[cfg(feature = "nats")]
+    if let Some(nats) = &config.nats {
+        if !revert.nats_published {
+            nats.publish_block(
+                DeltaFeedKind::Revert,
+                block_number,
+                entry.block_hash,
+                &entry.logs,
+                &entry.deltas,
+            )
+            .await?;
+            revert.nats_published = true;
+        }
+    }
+
+    store.mark_block_transfers_removed(entry.block_hash).await?;
+    store
+        .mark_block_raw_logs_removed(block_number, entry.block_hash)
+        .await?;
+    store.remove_balance_history(block_number).await?;
+
+    if store.applies_blocks_in_transactions() {
+        store
+            .revert_block_in_transaction(block_number, entry.block_hash, &inverse_deltas)
+            .await?;
+    } else {
+        if store.is_block_applied(entry.block_hash).await? {
+            let batch = revert
+                .batch
+                .get_or_insert_with(|| DeltaBatch::new(&inverse_deltas));
+            store.apply_delta_batch(batch).await?;
+            store.unmark_block_applied(entry.block_hash).await?;
+        }
+
+        store.remove_journal_entry(block_number).await?;
+        store.set_last_processed_block(block_number - 1).await?;
+    }
+
+    *pending = None;
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use mongodb::bson::{Bson, Document};
+use std::{collections::HashMap, str::FromStr};
+
+/// Fields of the ownership documents, as the worker names them.
+const OWNERSHIP_FIELDS: [&str; 7] = [
+    "contract_address",
+    "token_id",
+    "owner",
+    "quantity",
+    "amount",
+    "custody",
+    "creator",
+];
+
+/// Case of the ownership document fields that are not renamed explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FieldCase {
+    #[default]
+    Snake,
+    Camel,
+}
+
+impl FromStr for FieldCase {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "snake" => Ok(FieldCase::Snake),
+            "camel" => Ok(FieldCase::Camel),
+            _ => Err(format!("Unknown field case {}", value)),
+        }
+    }
+}
+
+/// Layout of the ownership documents, so the worker can write into the collection of an existing
+/// application instead of the application migrating to the worker's layout.
+///
+/// Only the ownership model is affected, every other collection keeps the worker's layout.
+#[derive(Debug, Clone)]
+pub struct OwnershipSchema {
+    collection: String,
+    /// Stored name of every ownership field.
+    fields: HashMap<&'static str, String>,
+    compatibility: bool,
+}
+
+impl Default for OwnershipSchema {
+    fn default() -> Self {
+        Self::new("token_ownerships".to_string(), FieldCase::Snake, &[], false).unwrap()
+    }
+}
+
+impl OwnershipSchema {
+    /// `renames` holds `field=name` pairs taking precedence over `case`. Fails on fields the
+    /// ownership documents do not have and on two fields stored under the same name.
+    ///
+    /// In `compatibility` mode, fields specific to the worker, such as `amount` and `custody`, are
+    /// not written, so the documents only carry the fields an application tracking balances
+    /// already has.
+    pub fn new(
+        collection: String,
+        case: FieldCase,
+        renames: &[String],
+        compatibility: bool,
+    ) -> Result<Self, String> {
+        let mut fields: HashMap<&'static str, String> = OWNERSHIP_FIELDS
+            .iter()
+            .map(|field| {
+                let name = match case {
+                    FieldCase::Snake => field.to_string(),
+                    FieldCase::Camel => camel_case(field),
+                };
+                (*field, name)
+            })
+            .collect();
+
+        for rename in renames {
+            let (field, name) = rename
+                .split_once('=')
+                .ok_or_else(|| format!("Field rename {} is not of the form field=name", rename))?;
+
+            let field = OWNERSHIP_FIELDS
+                .iter()
+                .find(|known_field| **known_field == field)
+                .ok_or_else(|| format!("Ownership documents have no field {}", field))?;
+
+            fields.insert(field, name.to_string());
+        }
+
+        let mut names: Vec<_> = fields.values().collect();
+        names.sort();
+        names.dedup();
+        if names.len() < fields.len() || names.iter().any(|name| *name == "_id") {
+            return Err("Ownership fields must be stored under distinct names".to_string());
+        }
+
+        Ok(Self {
+            collection,
+            fields,
+            compatibility,
+        })
+    }
+
+    pub fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    /// Stored name of an ownership field.
+    pub(crate) fn field(&self, field: &str) -> String {
+        self.fields
+            .get(field)
+            .cloned()
+            .unwrap_or_else(|| field.to_string())
+    }
+
+    /// Whether worker specific fields are left out of the stored documents.
+    pub(crate) fn is_compatibility(&self) -> bool {
+        self.compatibility
+    }
+
+    /// Renames the fields of a document, filter or update to their stored names, including the
+    /// fields of update operators such as `$inc`.
+    pub(crate) fn stored_document(&self, document: Document) -> Document {
+        document
+            .into_iter()
+            .map(|(key, value)| match value {
+                Bson::Document(operand) if key.starts_with('$') => {
+                    (key, Bson::Document(self.stored_document(operand)))
+                }
+                value => (self.field(&key), value),
+            })
+            .collect()
+    }
+
+    /// Renames the fields of a stored document back to the worker's names. Application fields
+    /// named like an ownership field stored under another name are dropped.
+    pub(crate) fn canonical_document(&self, document: Document) -> Document {
+        document
+            .into_iter()
+            .filter_map(
+                |(key, value)| match self.fields.iter().find(|(_, name)| **name == key) {
+                    Some((field, _)) => Some((field.to_string(), value)),
+                    None if OWNERSHIP_FIELDS.contains(&key.as_str()) => None,
+                    None => Some((key, value)),
+                },
+            )
+            .collect()
+    }
+}
+
+fn camel_case(field: &str) -> String {
+    field
+        .split('_')
+        .enumerate()
+        .map(|(index, word)| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) if index > 0 => first.to_uppercase().chain(chars).collect(),
+                _ => word.to_string(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,1099 @@
+//! Description of the documents the worker stores and the payloads it sends, as JSON Schema and as
+//! protobuf definitions, so consumers outside Rust can validate them and generate code.
+//!
+//! Every description lists the fields of its Rust type and is checked against the type when
+//! compiled, so a field added, removed or retyped without updating its description fails the
+//! build instead of drifting from the data model.
+
+use crate::{
+    amount::Amount,
+    approvals::ApprovalChange,
+    auth::Role,
+    coverage::{BlockRange, CoverageReport, ReorgRange, RestrictedRange},
+    discovery::ContractDiscovered,
+    ownership::{OwnershipDelta, SelfTransferPolicy},
+    schema::OwnershipSchema,
+    snapshot::SnapshotHolding,
+    store::{
+        Approval, ApprovalKind, BalanceChange, BlockJob, BlockJobStatus, ClassificationMethod,
+        ContractAddress, ContractStats, CoverageKind, CoverageSegment, DeadLetter, DeltaFeedEntry,
+        DeltaFeedKind, JournalEntry, JournaledLog, MetadataStatus, OperatorAction, Pin, PinStatus,
+        PortfolioEntry, QuorumMismatch, RawLog, ReconciliationEntry, StoredApiKey,
+        TokenHolderCount, TokenMetadata, TokenOwnership, TokenSupply, TopHolder, TopHolders,
+        TransferRecord,
+    },
+    verification::OwnershipAssertion,
+    webhooks::{WebhookCondition, WebhookMetric, WebhookRule},
+    WorkerConfig,
+};
+use mongodb::bson::{oid::ObjectId, Binary, DateTime, Document};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fmt::Write;
+use web3::types::{Log, H160, H256, U256, U64};
+
+/// Package of the protobuf definitions.
+const PROTOBUF_PACKAGE: &str = "token_ownership_worker.v1";
+
+/// Ownership fields left out of the stored documents in compatibility mode.
+const COMPATIBILITY_OMITTED_FIELDS: [&str; 3] = ["amount", "custody", "creator"];
+
+/// How a value is serialized.
+#[derive(Debug, Clone)]
+enum Shape {
+    /// `0x` followed by 40 lowercase hex digits.
+    Address,
+    /// `0x` followed by 64 lowercase hex digits.
+    Hash,
+    /// `0x` followed by hex digits, without leading zeros.
+    Quantity,
+    /// Exact decimal integer, possibly negative.
+    Amount,
+    /// MongoDB object id, `{ "$oid": ... }` in Extended JSON.
+    ObjectId,
+    /// MongoDB date, `{ "$date": ... }` in Extended JSON.
+    DateTime,
+    /// MongoDB binary data, `{ "$binary": ... }` in Extended JSON.
+    Binary,
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// An object of any shape.
+    Object,
+    /// A string among the names of an enum.
+    Enum(Vec<String>),
+    Nullable(Box<Shape>),
+    Array(Box<Shape>),
+    /// A type with its own definition.
+    Ref(&'static str),
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    /// Name of the Rust field.
+    name: &'static str,
+    /// Name the field is serialized under.
+    key: String,
+    shape: Shape,
+    /// Whether every serialized value has the field. Fields older documents may lack are not.
+    required: bool,
+}
+
+impl Field {
+    fn new(name: &'static str, key: &str, shape: Shape, required: bool) -> Self {
+        Self {
+            name,
+            key: key.to_string(),
+            shape,
+            required,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DefinitionKind {
+    Struct(Vec<Field>),
+    /// An enum internally tagged with `tag`, with the tag value and fields of every variant.
+    Tagged {
+        tag: &'static str,
+        variants: Vec<(&'static str, &'static str, Vec<Field>)>,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Definition {
+    name: &'static str,
+    description: &'static str,
+    kind: DefinitionKind,
+}
+
+/// A type whose serialized form can be described.
+trait Described {
+    fn shape() -> Shape;
+
+    /// Definition of the types `shape` refers to by name.
+    fn definition() -> Option<Definition> {
+        None
+    }
+}
+
+macro_rules! described_as {
+    ($shape:expr, $($type:ty),+) => {
+        $(
+            impl Described for $type {
+                fn shape() -> Shape {
+                    $shape
+                }
+            }
+        )+
+    };
+}
+
+described_as!(Shape::Address, H160);
+described_as!(Shape::Hash, H256);
+described_as!(Shape::Quantity, U64, U256);
+described_as!(Shape::Amount, Amount);
+described_as!(Shape::ObjectId, ObjectId);
+described_as!(Shape::DateTime, DateTime);
+described_as!(Shape::Binary, Binary);
+described_as!(Shape::String, String);
+described_as!(Shape::Integer, i32, i64, u64);
+described_as!(Shape::Number, f64);
+described_as!(Shape::Boolean, bool);
+described_as!(Shape::Object, Document, Log);
+
+impl<T: Described> Described for Option<T> {
+    fn shape() -> Shape {
+        Shape::Nullable(Box::new(T::shape()))
+    }
+}
+
+impl<T: Described> Described for Vec<T> {
+    fn shape() -> Shape {
+        Shape::Array(Box::new(T::shape()))
+    }
+}
+
+/// Name an enum variant is serialized under.
+fn variant_name(variant: impl Serialize) -> String {
+    match serde_json::to_value(variant) {
+        Ok(Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// Describes an enum of unit variants, serialized as the names of its variants.
+macro_rules! describe_enum {
+    ($type:ident { $($variant:ident),* $(,)? }) => {
+        impl Described for $type {
+            fn shape() -> Shape {
+                #[allow(dead_code)]
+                fn variants(value: $type) {
+                    match value {
+                        $($type::$variant => {})*
+                    }
+                }
+
+                Shape::Enum(vec![$(variant_name($type::$variant)),*])
+            }
+        }
+    };
+}
+
+/// Describes a struct field. `optional` marks the fields serialized values may lack, `id` the
+/// field serialized as `_id` and `skip` the fields that are not serialized.
+macro_rules! describe_field {
+    (skip $field:ident: $type:ty) => {
+        None
+    };
+    (optional $field:ident: $type:ty) => {
+        Some(Field::new(
+            stringify!($field),
+            stringify!($field),
+            <$type as Described>::shape(),
+            false,
+        ))
+    };
+    (id $field:ident: $type:ty) => {
+        Some(Field::new(
+            stringify!($field),
+            "_id",
+            <$type as Described>::shape(),
+            true,
+        ))
+    };
+    ($field:ident: $type:ty) => {
+        Some(Field::new(
+            stringify!($field),
+            stringify!($field),
+            <$type as Described>::shape(),
+            true,
+        ))
+    };
+}
+
+/// Describes a struct from every one of its fields, which must all be listed with their types.
+macro_rules! describe_struct {
+    ($type:ident, $description:literal, { $($([$marker:ident])? $field:ident: $field_type:ty),* $(,)? }) => {
+        impl Described for $type {
+            fn shape() -> Shape {
+                Shape::Ref(stringify!($type))
+            }
+
+            fn definition() -> Option<Definition> {
+                #[allow(dead_code)]
+                fn fields(value: &$type) {
+                    let $type { $($field: _),* } = value;
+                    $(let _: &$field_type = &value.$field;)*
+                }
+
+                let fields = [$(describe_field!($($marker)? $field: $field_type)),*];
+
+                Some(Definition {
+                    name: stringify!($type),
+                    description: $description,
+                    kind: DefinitionKind::Struct(fields.into_iter().flatten().collect()),
+                })
+            }
+        }
+    };
+}
+
+/// Describes an internally tagged enum from every one of its variants and their fields.
+macro_rules! describe_tagged_enum {
+    (
+        $type:ident,
+        $description:literal,
+        $tag:literal,
+        { $($variant:ident $value:literal { $($field:ident: $field_type:ty),* $(,)? }),* $(,)? }
+    ) => {
+        impl Described for $type {
+            fn shape() -> Shape {
+                Shape::Ref(stringify!($type))
+            }
+
+            fn definition() -> Option<Definition> {
+                #[allow(dead_code)]
+                fn variants(value: &$type) {
+                    match value {
+                        $($type::$variant { $($field),* } => {
+                            $(let _: &$field_type = $field;)*
+                        })*
+                    }
+                }
+
+                Some(Definition {
+                    name: stringify!($type),
+                    description: $description,
+                    kind: DefinitionKind::Tagged {
+                        tag: $tag,
+                        variants: vec![$((
+                            stringify!($variant),
+                            $value,
+                            vec![$(Field::new(
+                                stringify!($field),
+                                stringify!($field),
+                                <$field_type as Described>::shape(),
+                                true,
+                            )),*],
+                        )),*],
+                    },
+                })
+            }
+        }
+    };
+}
+
+describe_enum!(ClassificationMethod {
+    Event,
+    Erc165,
+    Heuristic,
+    Legacy,
+    Manual
+});
+describe_enum!(SelfTransferPolicy {
+    Owner,
+    Burn,
+    Custody
+});
+describe_enum!(DeltaFeedKind { Apply, Revert });
+describe_enum!(ApprovalKind {
+    Allowance,
+    Token,
+    Operator
+});
+describe_enum!(CoverageKind {
+    Live,
+    Backfill,
+    Reorg
+});
+describe_enum!(BlockJobStatus {
+    Pending,
+    Claimed,
+    Done,
+    Failed
+});
+describe_enum!(MetadataStatus {
+    Pending,
+    Fetched,
+    Failed
+});
+describe_enum!(PinStatus {
+    Queued,
+    Pinning,
+    Pinned,
+    Failed
+});
+describe_enum!(WebhookMetric {
+    HolderCount,
+    Supply
+});
+describe_enum!(Role {
+    Viewer,
+    Operator,
+    Admin
+});
+
+describe_struct!(ContractAddress, "Classification of a contract.", {
+    address: H160,
+    token_type: String,
+    [optional] additional_token_types: Vec<String>,
+    [optional] self_transfer_policy: SelfTransferPolicy,
+    [optional] classified_by: Option<ClassificationMethod>,
+    [optional] implementation_address: Option<H160>,
+    [optional] volume_capped: bool,
+    [optional] decimals: Option<i32>,
+    [optional] start_block: Option<i64>,
+});
+
+describe_struct!(TokenOwnership, "Quantity of a token held by an owner.", {
+    contract_address: H160,
+    [optional] token_id: Option<String>,
+    owner: H160,
+    quantity: f64,
+    [optional] amount: Option<Amount>,
+    [optional] custody: bool,
+    [optional] creator: Option<H160>,
+});
+
+describe_struct!(
+    TokenSupply,
+    "Circulating supply of the tokens of a contract, or of a single ERC1155 token.",
+    {
+        contract_address: H160,
+        token_type: String,
+        [optional] token_id: Option<String>,
+        supply: f64,
+        [optional] amount: Option<Amount>,
+    }
+);
+
+describe_struct!(
+    TokenHolderCount,
+    "Number of owners holding an ERC1155 token, or the tokens of another contract.",
+    {
+        contract_address: H160,
+        token_type: String,
+        [optional] token_id: Option<String>,
+        holder_count: i64,
+    }
+);
+
+describe_struct!(
+    BalanceChange,
+    "Balance of a token an owner was left with by a block.",
+    {
+        block_number: i64,
+        contract_address: H160,
+        token_type: String,
+        [optional] token_id: Option<String>,
+        owner: H160,
+        amount: Amount,
+        quantity: f64,
+    }
+);
+
+describe_struct!(
+    PortfolioEntry,
+    "Tokens of a contract held by an owner, in the owner keyed portfolios view.",
+    {
+        owner: H160,
+        contract_address: H160,
+        token_type: String,
+        [optional] token_ids: Vec<String>,
+        token_count: i64,
+    }
+);
+
+describe_struct!(ContractStats, "Aggregates of a contract.", {
+    [id] contract_address: H160,
+    holder_count: i64,
+    supply: f64,
+    [optional] checksum: i64,
+});
+
+describe_struct!(
+    TopHolders,
+    "Largest holders of a contract, materialized by the top holders refresh.",
+    {
+        [id] contract_address: H160,
+        holders: Vec<TopHolder>,
+        holder_count: i64,
+        supply: f64,
+        checksum: i64,
+        block_number: Option<i64>,
+        refreshed_at: DateTime,
+    }
+);
+
+describe_struct!(
+    TopHolder,
+    "Quantity of a contract's tokens held by an owner, across every token id.",
+    {
+        owner: H160,
+        quantity: f64,
+        [optional] amount: Option<Amount>,
+    }
+);
+
+describe_struct!(
+    OwnershipDelta,
+    "A single change to the quantity of a token held by an owner.",
+    {
+        contract_address: H160,
+        token_type: String,
+        [optional] token_id: Option<String>,
+        owner: H160,
+        quantity: f64,
+        [optional] amount: Option<Amount>,
+        [optional] custody: bool,
+    }
+);
+
+describe_tagged_enum!(
+    ApprovalChange,
+    "A change to who may move the tokens of an owner.",
+    "kind",
+    {
+        Allowance "allowance" {
+            contract_address: H160,
+            owner: H160,
+            spender: H160,
+            amount: f64,
+        },
+        Token "token" {
+            contract_address: H160,
+            token_id: String,
+            owner: H160,
+            approved: H160,
+        },
+        Operator "operator" {
+            contract_address: H160,
+            owner: H160,
+            operator: H160,
+            approved: bool,
+        },
+    }
+);
+
+describe_struct!(
+    JournaledLog,
+    "The deltas applied for a single log of a journaled block.",
+    {
+        transaction_hash: H256,
+        log_index: U256,
+        [optional] removed: bool,
+        deltas: Vec<OwnershipDelta>,
+        [optional] approvals: Vec<ApprovalChange>,
+        [skip] transfers: Vec<TransferRecord>,
+        [skip] reconciliations: Vec<ReconciliationEntry>,
+    }
+);
+
+describe_struct!(
+    JournalEntry,
+    "The deltas applied while processing a recent block, kept to revert it after a reorg.",
+    {
+        [id] block_number: i64,
+        block_hash: H256,
+        parent_hash: H256,
+        deltas: Vec<OwnershipDelta>,
+        [optional] logs: Vec<JournaledLog>,
+    }
+);
+
+describe_struct!(
+    DeltaFeedEntry,
+    "The deltas applied or reverted with a block, ordered by sequence.",
+    {
+        [id] sequence: i64,
+        kind: DeltaFeedKind,
+        block_number: i64,
+        block_hash: H256,
+        deltas: Vec<OwnershipDelta>,
+    }
+);
+
+describe_struct!(
+    OperatorAction,
+    "A data mutating action taken through the control API or the CLI.",
+    {
+        actor: String,
+        [optional] role: Option<Role>,
+        action: String,
+        outcome: String,
+        performed_at: DateTime,
+    }
+);
+
+describe_struct!(
+    StoredApiKey,
+    "An API key minted with the create-api-key command, stored by the hash of its secret.",
+    {
+        [id] name: String,
+        role: Role,
+        key_hash: String,
+        [optional] rate_limit: Option<f64>,
+        created_at: DateTime,
+        [optional] revoked_at: Option<DateTime>,
+    }
+);
+
+describe_struct!(
+    QuorumMismatch,
+    "Logs on which the primary and the quorum providers disagreed.",
+    {
+        from_block: i64,
+        to_block: i64,
+        primary_only: Vec<String>,
+        quorum_only: Vec<String>,
+        [optional] contract_addresses: Vec<H160>,
+        detected_at: DateTime,
+    }
+);
+
+describe_struct!(Approval, "An approval currently in effect.", {
+    contract_address: H160,
+    kind: ApprovalKind,
+    owner: H160,
+    operator: H160,
+    [optional] token_id: Option<String>,
+    [optional] amount: Option<f64>,
+    block_number: i64,
+});
+
+describe_struct!(CoverageSegment, "A contiguous range of processed blocks.", {
+    [id] id: ObjectId,
+    kind: CoverageKind,
+    from_block: i64,
+    to_block: i64,
+    [optional] contracts: Vec<H160>,
+    [optional] orphaned_blocks: Vec<H256>,
+    recorded_at: DateTime,
+    updated_at: DateTime,
+});
+
+describe_struct!(DeadLetter, "A log that could not be decoded.", {
+    [id] id: String,
+    block_number: i64,
+    block_hash: H256,
+    transaction_hash: H256,
+    log_index: U256,
+    contract_address: H160,
+    log: Log,
+    error: String,
+    attempts: i32,
+    recorded_at: DateTime,
+    updated_at: DateTime,
+});
+
+describe_struct!(BlockJob, "A block range of the work queue.", {
+    [id] id: ObjectId,
+    from_block: i64,
+    to_block: i64,
+    next_block: i64,
+    [optional] contracts: Vec<H160>,
+    status: BlockJobStatus,
+    [optional] claimed_by: Option<String>,
+    [optional] lease_expires_at: Option<DateTime>,
+    attempts: i32,
+    [optional] error: Option<String>,
+    [optional] head_handoff: bool,
+});
+
+describe_struct!(TransferRecord, "A decoded transfer of the transfer history.", {
+    [id] id: String,
+    transaction_hash: H256,
+    log_index: i64,
+    block_number: i64,
+    block_hash: H256,
+    contract_address: H160,
+    token_type: String,
+    [optional] token_id: Option<String>,
+    from: H160,
+    to: H160,
+    quantity: f64,
+    [optional] amount: Option<Amount>,
+    [optional] removed: bool,
+});
+
+describe_struct!(RawLog, "A log archived as the provider returned it.", {
+    [id] id: String,
+    block_number: i64,
+    block_hash: H256,
+    transaction_hash: H256,
+    transaction_index: i64,
+    log_index: i64,
+    address: H160,
+    topics: Vec<H256>,
+    data: Binary,
+    [optional] removed: bool,
+});
+
+describe_struct!(
+    ReconciliationEntry,
+    "A balance of a volume capped contract waiting to be read from the contract.",
+    {
+        [id] id: String,
+        contract_address: H160,
+        token_type: String,
+        [optional] token_id: Option<String>,
+        [optional] owner: Option<H160>,
+        queued_at_block: i64,
+    }
+);
+
+describe_struct!(Pin, "An IPFS CID referenced by token metadata.", {
+    cid: String,
+    [optional] request_id: Option<String>,
+    status: PinStatus,
+    [optional] error: Option<String>,
+});
+
+describe_struct!(
+    TokenMetadata,
+    "Name, symbol and decimals of a contract, or the URI and document of a single token.",
+    {
+        [id] id: String,
+        contract_address: H160,
+        token_type: String,
+        [optional] token_id: Option<String>,
+        status: MetadataStatus,
+        [optional] name: Option<String>,
+        [optional] symbol: Option<String>,
+        [optional] decimals: Option<i32>,
+        [optional] token_uri: Option<String>,
+        [optional] document: Option<Document>,
+        [optional] image: Option<String>,
+        [optional] pins: Vec<Pin>,
+        attempts: i32,
+        [optional] next_attempt_at: Option<DateTime>,
+        [optional] fetched_at: Option<DateTime>,
+        [optional] error: Option<String>,
+    }
+);
+
+describe_tagged_enum!(WebhookCondition, "When a webhook rule fires.", "kind", {
+    Crosses "crosses" { threshold: f64 },
+    ChangesBy "changes_by" { percent: f64 },
+});
+
+describe_struct!(WebhookRule, "A registered webhook notification.", {
+    [id] id: ObjectId,
+    contract_address: H160,
+    metric: WebhookMetric,
+    condition: WebhookCondition,
+    url: String,
+    reference_value: f64,
+});
+
+describe_struct!(
+    ContractDiscovered,
+    "A contract classified for the first time.",
+    {
+        contract_address: H160,
+        token_type: String,
+        classified_by: ClassificationMethod,
+        implementation_address: Option<H160>,
+        block_number: u64,
+        transaction_hash: H256,
+        log_index: U256,
+        discovered_at: u64,
+    }
+);
+
+describe_struct!(
+    OwnershipAssertion,
+    "Whether an owner holds the tokens of a contract, as evaluated at a block.",
+    {
+        owner: H160,
+        contract_address: H160,
+        token_id: Option<String>,
+        min_quantity: Option<f64>,
+        owns: bool,
+        block_number: Option<u64>,
+        issued_at: i64,
+    }
+);
+
+describe_struct!(BlockRange, "A range of blocks, both ends included.", {
+    from_block: u64,
+    to_block: u64,
+});
+
+describe_struct!(
+    RestrictedRange,
+    "Blocks a backfill applied for some contracts only.",
+    {
+        from_block: u64,
+        to_block: u64,
+        contracts: Vec<H160>,
+    }
+);
+
+describe_struct!(ReorgRange, "Blocks rolled back after a reorg orphaned them.", {
+    from_block: u64,
+    to_block: u64,
+    orphaned_blocks: Vec<H256>,
+    reindexed: bool,
+});
+
+describe_struct!(CoverageReport, "Which blocks of a range were processed.", {
+    from_block: u64,
+    to_block: u64,
+    last_processed_block: Option<u64>,
+    contiguous: bool,
+    indexed: Vec<BlockRange>,
+    gaps: Vec<BlockRange>,
+    restricted: Vec<RestrictedRange>,
+    reorgs: Vec<ReorgRange>,
+    generated_at: u64,
+});
+
+describe_struct!(
+    SnapshotHolding,
+    "Quantity of a token an owner held at the block of a snapshot.",
+    {
+        [optional] token_id: Option<String>,
+        owner: H160,
+        amount: Amount,
+        [optional] display_amount: Option<String>,
+    }
+);
+
+/// The definitions of every described type, in the order they are written.
+fn definitions(ownership_schema: &OwnershipSchema) -> Vec<Definition> {
+    [
+        ContractAddress::definition(),
+        TokenOwnership::definition(),
+        stored_ownership_definition(ownership_schema),
+        TokenSupply::definition(),
+        ContractStats::definition(),
+        TopHolders::definition(),
+        TopHolder::definition(),
+        TokenHolderCount::definition(),
+        PortfolioEntry::definition(),
+        BalanceChange::definition(),
+        OwnershipDelta::definition(),
+        ApprovalChange::definition(),
+        JournaledLog::definition(),
+        JournalEntry::definition(),
+        DeltaFeedEntry::definition(),
+        OperatorAction::definition(),
+        StoredApiKey::definition(),
+        QuorumMismatch::definition(),
+        Approval::definition(),
+        CoverageSegment::definition(),
+        DeadLetter::definition(),
+        BlockJob::definition(),
+        TransferRecord::definition(),
+        RawLog::definition(),
+        ReconciliationEntry::definition(),
+        Pin::definition(),
+        TokenMetadata::definition(),
+        WebhookCondition::definition(),
+        WebhookRule::definition(),
+        ContractDiscovered::definition(),
+        OwnershipAssertion::definition(),
+        BlockRange::definition(),
+        RestrictedRange::definition(),
+        ReorgRange::definition(),
+        CoverageReport::definition(),
+        SnapshotHolding::definition(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// The ownership documents as stored, under the configured field names and without the worker
+/// specific fields in compatibility mode. The API and the journal use the worker's names.
+fn stored_ownership_definition(ownership_schema: &OwnershipSchema) -> Option<Definition> {
+    let mut definition = TokenOwnership::definition()?;
+
+    if let DefinitionKind::Struct(fields) = &mut definition.kind {
+        fields.retain(|field| {
+            !ownership_schema.is_compatibility()
+                || !COMPATIBILITY_OMITTED_FIELDS.contains(&field.name)
+        });
+        for field in fields {
+            field.key = ownership_schema.field(field.name);
+        }
+    }
+
+    definition.name = "StoredTokenOwnership";
+    definition.description =
+        "Document of the ownership collection, under its configured field names.";
+
+    Some(definition)
+}
+
+/// The definition each collection holds, by collection name.
+fn collections(config: &WorkerConfig) -> Vec<(String, &'static str)> {
+    let ownership_collection = config.ownership_schema.collection();
+
+    [
+        ("contract_addresses", "ContractAddress"),
+        (ownership_collection, "StoredTokenOwnership"),
+        (
+            &format!("archived_{}", ownership_collection),
+            "StoredTokenOwnership",
+        ),
+        ("token_supplies", "TokenSupply"),
+        ("contract_stats", "ContractStats"),
+        ("top_holders", "TopHolders"),
+        ("token_holder_counts", "TokenHolderCount"),
+        ("portfolios", "PortfolioEntry"),
+        ("balance_history", "BalanceChange"),
+        ("block_journal", "JournalEntry"),
+        ("delta_feed", "DeltaFeedEntry"),
+        ("operator_actions", "OperatorAction"),
+        ("api_keys", "StoredApiKey"),
+        ("quorum_mismatches", "QuorumMismatch"),
+        ("approvals", "Approval"),
+        ("block_coverage", "CoverageSegment"),
+        ("dead_letters", "DeadLetter"),
+        ("block_jobs", "BlockJob"),
+        ("transfers", "TransferRecord"),
+        ("raw_logs", "RawLog"),
+        ("reconciliation_queue", "ReconciliationEntry"),
+        ("token_metadata", "TokenMetadata"),
+        ("webhook_rules", "WebhookRule"),
+    ]
+    .into_iter()
+    .map(|(collection, definition)| {
+        (
+            format!("{}{}", config.collection_prefix, collection),
+            definition,
+        )
+    })
+    .collect()
+}
+
+/// The definition each payload of the API, the webhooks and the CLI exports is made of.
+const PAYLOADS: [(&str, &str); 6] = [
+    (
+        "GET /sync/snapshot lines after the header",
+        "TokenOwnership",
+    ),
+    ("GET /sync/deltas entries", "DeltaFeedEntry"),
+    ("GET /verify-ownership assertion", "OwnershipAssertion"),
+    ("contract discovery webhook", "ContractDiscovered"),
+    ("coverage command output", "CoverageReport"),
+    ("snapshot command lines", "SnapshotHolding"),
+];
+
+/// JSON Schema (draft 2020-12) of the stored documents and of the payloads, with a definition per
+/// type under `$defs`. `x-collections` and `x-payloads` map every collection, prefixed as
+/// configured, and every payload to its definition.
+///
+/// Stored documents are described in relaxed Extended JSON, the form `mongoexport` writes, where
+/// object ids and dates are `{ "$oid": ... }` and `{ "$date": ... }` objects.
+pub fn json_schema(config: &WorkerConfig) -> Value {
+    let definitions: Map<_, _> = definitions(&config.ownership_schema)
+        .iter()
+        .map(|definition| (definition.name.to_string(), json_definition(definition)))
+        .collect();
+
+    let collections: Map<_, _> = collections(config)
+        .into_iter()
+        .map(|(collection, definition)| (collection, json_ref(definition)))
+        .collect();
+
+    let payloads: Map<_, _> = PAYLOADS
+        .iter()
+        .map(|(payload, definition)| (payload.to_string(), json_ref(definition)))
+        .collect();
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Token ownership worker data model",
+        "$defs": definitions,
+        "x-collections": collections,
+        "x-payloads": payloads,
+    })
+}
+
+fn json_ref(definition: &str) -> Value {
+    json!({ "$ref": format!("#/$defs/{}", definition) })
+}
+
+fn json_definition(definition: &Definition) -> Value {
+    let mut schema = match &definition.kind {
+        DefinitionKind::Struct(fields) => json_object(fields),
+        DefinitionKind::Tagged { tag, variants } => json!({
+            "oneOf": variants
+                .iter()
+                .map(|(_, value, fields)| {
+                    let mut fields = fields.clone();
+                    fields.insert(0, Field::new(tag, tag, Shape::Enum(vec![value.to_string()]), true));
+                    json_object(&fields)
+                })
+                .collect::<Vec<_>>(),
+        }),
+    };
+
+    schema["description"] = json!(definition.description);
+    schema
+}
+
+fn json_object(fields: &[Field]) -> Value {
+    let properties: Map<_, _> = fields
+        .iter()
+        .map(|field| (field.key.clone(), json_shape(&field.shape)))
+        .collect();
+    let required: Vec<_> = fields
+        .iter()
+        .filter(|field| field.required)
+        .map(|field| field.key.clone())
+        .collect();
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+fn json_shape(shape: &Shape) -> Value {
+    match shape {
+        Shape::Address => json!({ "type": "string", "pattern": "^0x[0-9a-f]{40}$" }),
+        Shape::Hash => json!({ "type": "string", "pattern": "^0x[0-9a-f]{64}$" }),
+        Shape::Quantity => json!({ "type": "string", "pattern": "^0x(0|[1-9a-f][0-9a-f]*)$" }),
+        Shape::Amount => json!({ "type": "string", "pattern": "^(0|-?[1-9][0-9]*)$" }),
+        Shape::ObjectId => json!({
+            "type": "object",
+            "properties": { "$oid": { "type": "string", "pattern": "^[0-9a-f]{24}$" } },
+            "required": ["$oid"],
+        }),
+        Shape::DateTime => json!({
+            "type": "object",
+            "properties": { "$date": { "type": "string", "format": "date-time" } },
+            "required": ["$date"],
+        }),
+        Shape::Binary => json!({
+            "type": "object",
+            "properties": {
+                "$binary": {
+                    "type": "object",
+                    "properties": {
+                        "base64": { "type": "string", "contentEncoding": "base64" },
+                        "subType": { "type": "string", "pattern": "^[0-9a-f]{2}$" },
+                    },
+                    "required": ["base64", "subType"],
+                },
+            },
+            "required": ["$binary"],
+        }),
+        Shape::String => json!({ "type": "string" }),
+        Shape::Integer => json!({ "type": "integer" }),
+        Shape::Number => json!({ "type": "number" }),
+        Shape::Boolean => json!({ "type": "boolean" }),
+        Shape::Object => json!({ "type": "object" }),
+        Shape::Enum(names) => json!({ "type": "string", "enum": names }),
+        Shape::Nullable(shape) => json!({ "anyOf": [json_shape(shape), { "type": "null" }] }),
+        Shape::Array(shape) => json!({ "type": "array", "items": json_shape(shape) }),
+        Shape::Ref(definition) => json_ref(definition),
+    }
+}
+
+/// Protobuf (proto3) definitions of the same types as `json_schema`, a message per definition, for
+/// generating code in languages without JSON Schema tooling.
+///
+/// Fields serialized under another name, such as `_id`, carry it as their `json_name`. Addresses,
+/// hashes, amounts and enum names are strings, object ids are hex strings, dates are
+/// `google.protobuf.Timestamp` and free-form objects, such as raw logs, `google.protobuf.Struct`.
+/// Internally tagged enums become a message with a `oneof` of their variants.
+pub fn protobuf_definitions(config: &WorkerConfig) -> String {
+    let mut proto = format!(
+        "syntax = \"proto3\";\n\npackage {};\n\nimport \"google/protobuf/struct.proto\";\nimport \"google/protobuf/timestamp.proto\";\n",
+        PROTOBUF_PACKAGE
+    );
+
+    for definition in definitions(&config.ownership_schema) {
+        writeln!(proto).unwrap();
+        writeln!(proto, "// {}", definition.description).unwrap();
+
+        match &definition.kind {
+            DefinitionKind::Struct(fields) => {
+                writeln!(proto, "message {} {{", definition.name).unwrap();
+                write_protobuf_fields(&mut proto, fields, "  ");
+                writeln!(proto, "}}").unwrap();
+            }
+            DefinitionKind::Tagged { tag, variants } => {
+                writeln!(proto, "message {} {{", definition.name).unwrap();
+                for (variant, _, fields) in variants {
+                    writeln!(proto, "  message {} {{", variant).unwrap();
+                    write_protobuf_fields(&mut proto, fields, "    ");
+                    writeln!(proto, "  }}").unwrap();
+                }
+                writeln!(proto).unwrap();
+                writeln!(proto, "  oneof {} {{", tag).unwrap();
+                for (number, (variant, value, _)) in variants.iter().enumerate() {
+                    writeln!(proto, "    {} {} = {};", variant, value, number + 1).unwrap();
+                }
+                writeln!(proto, "  }}").unwrap();
+                writeln!(proto, "}}").unwrap();
+            }
+        }
+    }
+
+    proto
+}
+
+fn write_protobuf_fields(proto: &mut String, fields: &[Field], indent: &str) {
+    for (number, field) in fields.iter().enumerate() {
+        let (label, shape) = match &field.shape {
+            Shape::Array(shape) => ("repeated ", shape.as_ref()),
+            Shape::Nullable(shape) => ("optional ", shape.as_ref()),
+            shape if !field.required => ("optional ", shape),
+            shape => ("", shape),
+        };
+
+        write!(
+            proto,
+            "{}{}{} {} = {}",
+            indent,
+            label,
+            protobuf_type(shape),
+            field.name,
+            number + 1
+        )
+        .unwrap();
+
+        if field.key != field.name {
+            write!(proto, " [json_name = \"{}\"]", field.key).unwrap();
+        }
+
+        write!(proto, ";").unwrap();
+
+        if let Shape::Enum(names) = shape {
+            write!(proto, " // {}", names.join(", ")).unwrap();
+        }
+
+        writeln!(proto).unwrap();
+    }
+}
+
+fn protobuf_type(shape: &Shape) -> String {
+    match shape {
+        Shape::Address
+        | Shape::Hash
+        | Shape::Quantity
+        | Shape::Amount
+        | Shape::ObjectId
+        | Shape::String
+        | Shape::Enum(_) => "string".to_string(),
+        Shape::DateTime => "google.protobuf.Timestamp".to_string(),
+        Shape::Binary => "bytes".to_string(),
+        Shape::Integer => "int64".to_string(),
+        Shape::Number => "double".to_string(),
+        Shape::Boolean => "bool".to_string(),
+        Shape::Object => "google.protobuf.Struct".to_string(),
+        Shape::Ref(definition) => definition.to_string(),
+        // Protobuf has no nested optional or repeated fields, which only arrays of optional
+        // values would need.
+        Shape::Nullable(shape) | Shape::Array(shape) => protobuf_type(shape),
+    }
+}
@@ -0,0 +1,212 @@
+//! Recording and replaying of RPC traffic, only compiled with the `simulation` feature.
+//!
+//! A recording holds every request sent to the providers with the response it got, one JSON
+//! object per line. Replaying it answers the same requests with the same responses without
+//! reaching a provider, so a run can be reproduced on identical inputs.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use jsonrpc_core::{Call, Params, Value};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use web3::{RequestId, Transport};
+
+/// Whether RPC traffic is recorded to or replayed from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimulationMode {
+    /// Responses of the providers are appended to the file.
+    Record(PathBuf),
+    /// Requests are answered from the file, and never reach a provider.
+    Replay(PathBuf),
+}
+
+/// A request and the response it got.
+#[derive(Debug, Serialize, Deserialize)]
+struct Exchange {
+    /// Provider the request was sent to, so the quorum provider replays its own responses.
+    provider: String,
+    method: String,
+    params: Params,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Outcome {
+    Result(Value),
+    /// Error returned by the provider, such as a reverted call or a rejected log range.
+    Error(jsonrpc_core::Error),
+    /// Failure to reach the provider.
+    TransportError(String),
+}
+
+impl Outcome {
+    fn new(response: &web3::Result<Value>) -> Self {
+        match response {
+            Ok(value) => Outcome::Result(value.clone()),
+            Err(web3::Error::Rpc(error)) => Outcome::Error(error.clone()),
+            Err(error) => Outcome::TransportError(error.to_string()),
+        }
+    }
+
+    fn into_response(self) -> web3::Result<Value> {
+        match self {
+            Outcome::Result(value) => Ok(value),
+            Outcome::Error(error) => Err(web3::Error::Rpc(error)),
+            Outcome::TransportError(error) => Err(web3::Error::Transport(error)),
+        }
+    }
+}
+
+/// The recording shared by the transports of every provider.
+#[derive(Debug)]
+pub(crate) enum Simulation {
+    Record(Mutex<File>),
+    /// Recorded outcomes of each request, in the order they were recorded.
+    Replay(Mutex<HashMap<String, VecDeque<Outcome>>>),
+}
+
+impl Simulation {
+    /// Opens the recording, appending to the file of a previous recording.
+    pub(crate) fn open(mode: &SimulationMode) -> io::Result<Self> {
+        match mode {
+            SimulationMode::Record(path) => Ok(Simulation::Record(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ))),
+            SimulationMode::Replay(path) => {
+                let mut outcomes: HashMap<String, VecDeque<Outcome>> = HashMap::new();
+
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let exchange: Exchange = serde_json::from_str(&line?)?;
+                    outcomes
+                        .entry(key(&exchange.provider, &exchange.method, &exchange.params))
+                        .or_default()
+                        .push_back(exchange.outcome);
+                }
+
+                Ok(Simulation::Replay(Mutex::new(outcomes)))
+            }
+        }
+    }
+
+    /// The next recorded outcome of a request. The last outcome is repeated once the others have
+    /// been replayed, since a replay may poll the head more often than the recorded run did.
+    fn replay(&self, key: &str) -> Option<Outcome> {
+        match self {
+            Simulation::Record(_) => None,
+            Simulation::Replay(outcomes) => {
+                let mut outcomes = outcomes.lock().unwrap();
+                let recorded = outcomes.get_mut(key)?;
+
+                if recorded.len() > 1 {
+                    recorded.pop_front()
+                } else {
+                    recorded.front().cloned()
+                }
+            }
+        }
+    }
+
+    fn record(&self, exchange: &Exchange) {
+        if let Simulation::Record(file) = self {
+            let written = serde_json::to_string(exchange)
+                .map_err(io::Error::from)
+                .and_then(|line| writeln!(file.lock().unwrap(), "{}", line));
+
+            if let Err(error) = written {
+                eprintln!(
+                    "Error: Could not record {} request... {}",
+                    exchange.method, error
+                );
+            }
+        }
+    }
+}
+
+/// Transport wrapper recording the responses of a provider, or answering from a recording instead
+/// of reaching it. Requests pass through untouched when no simulation is set.
+#[derive(Debug, Clone)]
+pub(crate) struct SimulationTransport<T> {
+    inner: T,
+    provider: &'static str,
+    simulation: Option<Arc<Simulation>>,
+}
+
+impl<T> SimulationTransport<T> {
+    pub(crate) fn new(
+        inner: T,
+        provider: &'static str,
+        simulation: Option<Arc<Simulation>>,
+    ) -> Self {
+        Self {
+            inner,
+            provider,
+            simulation,
+        }
+    }
+}
+
+impl<T> Transport for SimulationTransport<T>
+where
+    T: Transport,
+    T::Out: Send + 'static,
+{
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        self.inner.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        let (simulation, call) = match (&self.simulation, &request) {
+            (Some(simulation), Call::MethodCall(call)) => (simulation.clone(), call.clone()),
+            _ => return self.inner.send(id, request).boxed(),
+        };
+
+        if let Simulation::Replay(_) = *simulation {
+            let response = simulation
+                .replay(&key(self.provider, &call.method, &call.params))
+                .map(Outcome::into_response)
+                .unwrap_or_else(|| {
+                    Err(web3::Error::Transport(format!(
+                        "simulation: no recorded response to {} {:?}",
+                        call.method, call.params
+                    )))
+                });
+
+            return future::ready(response).boxed();
+        }
+
+        let provider = self.provider.to_string();
+        let response = self.inner.send(id, request);
+
+        async move {
+            let response = response.await;
+
+            simulation.record(&Exchange {
+                provider,
+                method: call.method,
+                params: call.params,
+                outcome: Outcome::new(&response),
+            });
+
+            response
+        }
+        .boxed()
+    }
+}
+
+fn key(provider: &str, method: &str, params: &Params) -> String {
+    format!(
+        "{}:{}:{}",
+        provider,
+        method,
+        serde_json::to_string(params).unwrap_or_default()
+    )
+}
@@ -0,0 +1,233 @@
+#[cfg(feature = "api")]
+use crate::openapi;
+use crate::{
+    amount::Amount,
+    decimals::DecimalsRegistry,
+    store::{HolderSort, Store},
+};
+use futures::TryStreamExt;
+use serde::Serialize;
+use std::{collections::BTreeMap, error};
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+use web3::types::{H160, U64};
+
+/// What the holdings of a contract at a past block are computed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotSource {
+    /// The current ownership model, with the transfers recorded after the block undone. Needs the
+    /// transfer history of the blocks after it.
+    Rewind,
+    /// The transfers recorded up to the block, replayed from genesis. Needs the transfer history of
+    /// every block up to it.
+    Replay,
+}
+
+/// Quantity of a token an owner held at the block of a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct SnapshotHolding {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub owner: H160,
+    pub amount: Amount,
+    /// The amount in whole units of the token, when the decimals of the contract are known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_amount: Option<String>,
+}
+
+/// Quantity of a token of a contract an owner holds, for listing everything an owner holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OwnerHolding {
+    pub contract_address: H160,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    pub amount: Amount,
+    /// The amount in whole units of the token, when the decimals of the contract are known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_amount: Option<String>,
+}
+
+/// The positive holdings of a contract in the current ownership model, ordered by token id and
+/// owner.
+pub(crate) async fn current_holdings(
+    store: &Store,
+    decimals: &DecimalsRegistry,
+    contract_address: H160,
+) -> Result<Vec<SnapshotHolding>, Box<dyn error::Error + Send + Sync>> {
+    let decimals = decimals.decimals(contract_address).await?;
+    let mut holdings: BTreeMap<(Option<String>, H160), Amount> = BTreeMap::new();
+
+    store
+        .contract_ownerships(contract_address)
+        .await?
+        .try_for_each(|ownership| {
+            let amount = ownership.exact_amount();
+            if amount.is_positive() {
+                holdings.insert((ownership.token_id, ownership.owner), amount);
+            }
+            async { Ok(()) }
+        })
+        .await?;
+
+    Ok(holdings
+        .into_iter()
+        .map(|((token_id, owner), amount)| SnapshotHolding {
+            token_id,
+            owner,
+            amount,
+            display_amount: decimals.map(|decimals| amount.to_decimal_string(decimals)),
+        })
+        .collect())
+}
+
+/// The holdings of a contract in the current ownership model with at least `min_quantity`, and a
+/// positive quantity in any case, largest first, up to `limit` of them when set.
+pub(crate) async fn largest_holdings(
+    store: &Store,
+    decimals: &DecimalsRegistry,
+    contract_address: H160,
+    min_quantity: f64,
+    limit: Option<i64>,
+) -> Result<Vec<SnapshotHolding>, Box<dyn error::Error + Send + Sync>> {
+    let decimals = decimals.decimals(contract_address).await?;
+
+    // A limit of 0 reads every holder.
+    let ownerships = store
+        .contract_holders_page(
+            contract_address,
+            HolderSort::Balance,
+            min_quantity,
+            None,
+            limit.unwrap_or(0),
+        )
+        .await?;
+
+    Ok(ownerships
+        .into_iter()
+        .map(|ownership| {
+            let amount = ownership.exact_amount();
+            SnapshotHolding {
+                token_id: ownership.token_id,
+                owner: ownership.owner,
+                amount,
+                display_amount: decimals.map(|decimals| amount.to_decimal_string(decimals)),
+            }
+        })
+        .collect())
+}
+
+/// The positive holdings of an owner in the current ownership model, of `contract_address` only
+/// when set, ordered by contract and token id. `owner` is the stored owner.
+pub(crate) async fn owner_holdings(
+    store: &Store,
+    decimals: &DecimalsRegistry,
+    owner: H160,
+    contract_address: Option<H160>,
+) -> Result<Vec<OwnerHolding>, Box<dyn error::Error + Send + Sync>> {
+    let mut holdings: BTreeMap<(H160, Option<String>), Amount> = BTreeMap::new();
+
+    for ownership in store.owner_ownerships(owner, contract_address).await? {
+        let amount = ownership.exact_amount();
+        if amount.is_positive() {
+            holdings.insert((ownership.contract_address, ownership.token_id), amount);
+        }
+    }
+
+    let mut owner_holdings = Vec::with_capacity(holdings.len());
+
+    for ((contract_address, token_id), amount) in holdings {
+        owner_holdings.push(OwnerHolding {
+            contract_address,
+            token_id,
+            amount,
+            display_amount: decimals.display_amount(contract_address, amount).await?,
+        });
+    }
+
+    Ok(owner_holdings)
+}
+
+/// Computes the positive holdings of a contract after `block_number`, ordered by token id and
+/// owner. Mints and burns leave the zero address out of the holdings.
+pub(crate) async fn holdings_at(
+    store: &Store,
+    decimals: &DecimalsRegistry,
+    contract_address: H160,
+    block_number: U64,
+    source: SnapshotSource,
+) -> Result<Vec<SnapshotHolding>, Box<dyn error::Error + Send + Sync>> {
+    let last_processed_block = store.last_processed_block().await?.unwrap_or_default();
+
+    if block_number > last_processed_block {
+        return Err(format!(
+            "Block {} is not processed yet, the last processed block is {}",
+            block_number, last_processed_block
+        )
+        .into());
+    }
+
+    let mut holdings: BTreeMap<(Option<String>, H160), Amount> = BTreeMap::new();
+
+    let transfers = match source {
+        SnapshotSource::Rewind => {
+            store
+                .contract_ownerships(contract_address)
+                .await?
+                .try_for_each(|ownership| {
+                    *holdings
+                        .entry((ownership.token_id.clone(), ownership.owner))
+                        .or_default() = ownership.exact_amount();
+                    async { Ok(()) }
+                })
+                .await?;
+
+            store
+                .contract_transfers(contract_address, Some(block_number), None)
+                .await?
+        }
+        SnapshotSource::Replay => {
+            store
+                .contract_transfers(contract_address, None, Some(block_number))
+                .await?
+        }
+    };
+
+    transfers
+        .try_for_each(|transfer| {
+            let amount = transfer
+                .amount
+                .unwrap_or_else(|| Amount::from_f64(transfer.quantity));
+            // Rewinding moves the amount back from the recipient to the sender.
+            let amount = match source {
+                SnapshotSource::Rewind => -amount,
+                SnapshotSource::Replay => amount,
+            };
+
+            for (owner, change) in [(transfer.from, -amount), (transfer.to, amount)] {
+                if owner != H160::zero() {
+                    let holding = holdings
+                        .entry((transfer.token_id.clone(), owner))
+                        .or_default();
+                    *holding = *holding + change;
+                }
+            }
+
+            async { Ok(()) }
+        })
+        .await?;
+
+    let decimals = decimals.decimals(contract_address).await?;
+
+    Ok(holdings
+        .into_iter()
+        .filter(|(_, amount)| amount.is_positive())
+        .map(|((token_id, owner), amount)| SnapshotHolding {
+            token_id,
+            owner,
+            amount,
+            display_amount: decimals.map(|decimals| amount.to_decimal_string(decimals)),
+        })
+        .collect())
+}
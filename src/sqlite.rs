@@ -0,0 +1,493 @@
+//! The ownership model in a SQLite file, only compiled with the `sqlite` feature.
+//!
+//! SQLite has no integers wide enough for `uint256` amounts, so they are stored as decimal strings
+//! and summed by the worker, next to their float `quantity` for queries.
+
+use crate::{
+    amount::Amount,
+    ownership::OwnershipDelta,
+    storage::{JournaledBlock, OwnershipStore, StorageResult},
+    store::ClassificationMethod,
+};
+use futures::future::{BoxFuture, FutureExt};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Row, Sqlite, SqlitePool, Transaction,
+};
+use std::{path::Path, str::FromStr};
+use web3::types::{H160, H256, U64};
+
+const SCHEMA: [&str; 6] = [
+    "CREATE TABLE IF NOT EXISTS token_ownerships (
+        contract_address TEXT NOT NULL,
+        token_id TEXT NOT NULL DEFAULT '',
+        owner TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        quantity REAL NOT NULL,
+        custody INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (contract_address, token_id, owner)
+    )",
+    "CREATE TABLE IF NOT EXISTS token_supplies (
+        contract_address TEXT NOT NULL,
+        token_id TEXT NOT NULL DEFAULT '',
+        token_type TEXT NOT NULL,
+        supply TEXT NOT NULL,
+        quantity REAL NOT NULL,
+        PRIMARY KEY (contract_address, token_id)
+    )",
+    "CREATE TABLE IF NOT EXISTS applied_blocks (
+        block_hash TEXT PRIMARY KEY,
+        block_number INTEGER NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS sync_state (
+        id TEXT PRIMARY KEY,
+        block_number INTEGER NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS block_journal (
+        block_number INTEGER PRIMARY KEY,
+        block_hash TEXT NOT NULL,
+        parent_hash TEXT NOT NULL,
+        deltas TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS contract_addresses (
+        address TEXT PRIMARY KEY,
+        token_type TEXT NOT NULL,
+        classified_by TEXT NOT NULL
+    )",
+];
+
+/// `id` of the checkpoint row in `sync_state`.
+const CHECKPOINT_ID: &str = "logs_worker";
+
+/// Writes the ownership model to a SQLite file. Fungible tokens are stored with an empty
+/// `token_id`, like in PostgreSQL.
+///
+/// The pool holds a single connection, since SQLite lets one writer in at a time anyway.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Opens the file, creating it and the tables that do not exist yet.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+
+        Ok(Self { pool })
+    }
+
+    /// Records the block as applied and applies its deltas, unless it was applied already.
+    async fn apply_block_deltas(
+        transaction: &mut Transaction<'_, Sqlite>,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &[OwnershipDelta],
+    ) -> StorageResult<()> {
+        if let Some(block_hash) = block_hash {
+            let marked = sqlx::query(
+                "INSERT INTO applied_blocks (block_hash, block_number) VALUES (?, ?)
+                ON CONFLICT DO NOTHING",
+            )
+            .bind(format!("{:#x}", block_hash))
+            .bind(block_number.as_u64() as i64)
+            .execute(&mut **transaction)
+            .await?
+            .rows_affected();
+
+            if marked == 0 {
+                return Ok(());
+            }
+        }
+
+        for delta in deltas {
+            Self::apply_delta(transaction, delta).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the applied block record and applies the inverse deltas, unless the block was not
+    /// recorded as applied.
+    async fn revert_block_deltas(
+        transaction: &mut Transaction<'_, Sqlite>,
+        block_hash: H256,
+        inverse_deltas: &[OwnershipDelta],
+    ) -> StorageResult<()> {
+        let unmarked = sqlx::query("DELETE FROM applied_blocks WHERE block_hash = ?")
+            .bind(format!("{:#x}", block_hash))
+            .execute(&mut **transaction)
+            .await?
+            .rows_affected();
+
+        if unmarked == 0 {
+            return Ok(());
+        }
+
+        for delta in inverse_deltas {
+            Self::apply_delta(transaction, delta).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_checkpoint(
+        transaction: &mut Transaction<'_, Sqlite>,
+        block_number: U64,
+    ) -> StorageResult<()> {
+        sqlx::query(
+            "INSERT INTO sync_state (id, block_number) VALUES (?, ?)
+            ON CONFLICT (id) DO UPDATE SET block_number = excluded.block_number",
+        )
+        .bind(CHECKPOINT_ID)
+        .bind(block_number.as_u64() as i64)
+        .execute(&mut **transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn apply_delta(
+        transaction: &mut Transaction<'_, Sqlite>,
+        delta: &OwnershipDelta,
+    ) -> StorageResult<()> {
+        let contract_address = format!("{:#x}", delta.contract_address);
+        let token_id = delta.token_id.clone().unwrap_or_default();
+
+        if delta.owner == H160::zero() {
+            let token_id = if delta.token_type == "ERC1155" {
+                token_id
+            } else {
+                String::new()
+            };
+
+            let supply: Option<String> = sqlx::query_scalar(
+                "SELECT supply FROM token_supplies WHERE contract_address = ? AND token_id = ?",
+            )
+            .bind(&contract_address)
+            .bind(&token_id)
+            .fetch_optional(&mut **transaction)
+            .await?;
+            let supply = stored_amount(supply)? - delta.exact_amount();
+
+            sqlx::query(
+                "INSERT INTO token_supplies (contract_address, token_id, token_type, supply, quantity)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT (contract_address, token_id)
+                DO UPDATE SET supply = excluded.supply, quantity = excluded.quantity",
+            )
+            .bind(&contract_address)
+            .bind(&token_id)
+            .bind(&delta.token_type)
+            .bind(supply.to_string())
+            .bind(supply.to_f64())
+            .execute(&mut **transaction)
+            .await?;
+
+            return Ok(());
+        }
+
+        let owner = format!("{:#x}", delta.owner);
+
+        let amount: Option<String> = sqlx::query_scalar(
+            "SELECT amount FROM token_ownerships
+            WHERE contract_address = ? AND token_id = ? AND owner = ?",
+        )
+        .bind(&contract_address)
+        .bind(&token_id)
+        .bind(&owner)
+        .fetch_optional(&mut **transaction)
+        .await?;
+        let amount = stored_amount(amount)? + delta.exact_amount();
+
+        if delta.token_type == "ERC721" && amount.is_zero() {
+            sqlx::query(
+                "DELETE FROM token_ownerships
+                WHERE contract_address = ? AND token_id = ? AND owner = ?",
+            )
+            .bind(&contract_address)
+            .bind(&token_id)
+            .bind(&owner)
+            .execute(&mut **transaction)
+            .await?;
+
+            return Ok(());
+        }
+
+        sqlx::query(
+            "INSERT INTO token_ownerships (contract_address, token_id, owner, amount, quantity, custody)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (contract_address, token_id, owner)
+            DO UPDATE SET
+                amount = excluded.amount,
+                quantity = excluded.quantity,
+                custody = token_ownerships.custody OR excluded.custody",
+        )
+        .bind(&contract_address)
+        .bind(&token_id)
+        .bind(&owner)
+        .bind(amount.to_string())
+        .bind(amount.to_f64())
+        .bind(delta.custody)
+        .execute(&mut **transaction)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl OwnershipStore for SqliteStore {
+    fn apply_deltas<'a>(
+        &'a self,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+            Self::apply_block_deltas(&mut transaction, block_number, block_hash, deltas).await?;
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn revert_deltas<'a>(
+        &'a self,
+        block_hash: H256,
+        inverse_deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+            Self::revert_block_deltas(&mut transaction, block_hash, inverse_deltas).await?;
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn ownership_amount<'a>(
+        &'a self,
+        contract_address: H160,
+        token_id: Option<&'a str>,
+        owner: H160,
+    ) -> BoxFuture<'a, StorageResult<Amount>> {
+        async move {
+            let amount: Option<String> = sqlx::query_scalar(
+                "SELECT amount FROM token_ownerships
+                WHERE contract_address = ? AND token_id = ? AND owner = ?",
+            )
+            .bind(format!("{:#x}", contract_address))
+            .bind(token_id.unwrap_or_default())
+            .bind(format!("{:#x}", owner))
+            .fetch_optional(&self.pool)
+            .await?;
+
+            stored_amount(amount)
+        }
+        .boxed()
+    }
+
+    fn checkpoint(&self) -> BoxFuture<'_, StorageResult<Option<U64>>> {
+        async move {
+            let block_number: Option<i64> =
+                sqlx::query_scalar("SELECT block_number FROM sync_state WHERE id = ?")
+                    .bind(CHECKPOINT_ID)
+                    .fetch_optional(&self.pool)
+                    .await?;
+
+            Ok(block_number.map(|block_number| U64::from(block_number as u64)))
+        }
+        .boxed()
+    }
+
+    fn set_checkpoint(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+            Self::write_checkpoint(&mut transaction, block_number).await?;
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn apply_block<'a>(&'a self, block: &'a JournaledBlock) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+
+            Self::apply_block_deltas(
+                &mut transaction,
+                block.block_number,
+                Some(block.block_hash),
+                &block.deltas,
+            )
+            .await?;
+
+            sqlx::query(
+                "INSERT INTO block_journal (block_number, block_hash, parent_hash, deltas)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT (block_number) DO UPDATE SET
+                    block_hash = excluded.block_hash,
+                    parent_hash = excluded.parent_hash,
+                    deltas = excluded.deltas",
+            )
+            .bind(block.block_number.as_u64() as i64)
+            .bind(format!("{:#x}", block.block_hash))
+            .bind(format!("{:#x}", block.parent_hash))
+            .bind(serde_json::to_string(&block.deltas)?)
+            .execute(&mut *transaction)
+            .await?;
+
+            Self::write_checkpoint(&mut transaction, block.block_number).await?;
+
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn journaled_block(
+        &self,
+        block_number: U64,
+    ) -> BoxFuture<'_, StorageResult<Option<JournaledBlock>>> {
+        async move {
+            let row = sqlx::query(
+                "SELECT block_hash, parent_hash, deltas FROM block_journal WHERE block_number = ?",
+            )
+            .bind(block_number.as_u64() as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+
+            match row {
+                Some(row) => Ok(Some(JournaledBlock {
+                    block_number,
+                    block_hash: H256::from_str(&row.try_get::<String, _>("block_hash")?)?,
+                    parent_hash: H256::from_str(&row.try_get::<String, _>("parent_hash")?)?,
+                    deltas: serde_json::from_str(&row.try_get::<String, _>("deltas")?)?,
+                })),
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    fn revert_block(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let block = match self.journaled_block(block_number).await? {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+
+            let mut transaction = self.pool.begin().await?;
+
+            Self::revert_block_deltas(&mut transaction, block.block_hash, &block.inverse_deltas())
+                .await?;
+
+            sqlx::query("DELETE FROM block_journal WHERE block_number = ?")
+                .bind(block_number.as_u64() as i64)
+                .execute(&mut *transaction)
+                .await?;
+
+            Self::write_checkpoint(&mut transaction, block_number - U64::from(1u8)).await?;
+
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn prune_journal(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            sqlx::query("DELETE FROM block_journal WHERE block_number < ?")
+                .bind(block_number.as_u64() as i64)
+                .execute(&self.pool)
+                .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn token_type(&self, contract_address: H160) -> BoxFuture<'_, StorageResult<Option<String>>> {
+        async move {
+            Ok(
+                sqlx::query_scalar("SELECT token_type FROM contract_addresses WHERE address = ?")
+                    .bind(format!("{:#x}", contract_address))
+                    .fetch_optional(&self.pool)
+                    .await?,
+            )
+        }
+        .boxed()
+    }
+
+    fn set_token_type<'a>(
+        &'a self,
+        contract_address: H160,
+        token_type: &'a str,
+        classified_by: ClassificationMethod,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            sqlx::query(
+                "INSERT INTO contract_addresses (address, token_type, classified_by)
+                VALUES (?, ?, ?)
+                ON CONFLICT (address) DO UPDATE SET
+                    token_type = excluded.token_type,
+                    classified_by = excluded.classified_by",
+            )
+            .bind(format!("{:#x}", contract_address))
+            .bind(token_type)
+            .bind(classified_by.to_string())
+            .execute(&self.pool)
+            .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn reset(&self) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let mut transaction = self.pool.begin().await?;
+
+            // SQLite has no TRUNCATE, and deletes without a WHERE clause are just as fast.
+            for table in [
+                "token_ownerships",
+                "token_supplies",
+                "applied_blocks",
+                "sync_state",
+                "block_journal",
+            ] {
+                sqlx::query(&format!("DELETE FROM {}", table))
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+
+            transaction.commit().await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// The stored decimal amount, zero when there is no row.
+fn stored_amount(amount: Option<String>) -> StorageResult<Amount> {
+    match amount {
+        Some(amount) => Ok(amount.parse()?),
+        None => Ok(Amount::zero()),
+    }
+}
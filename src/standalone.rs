@@ -0,0 +1,468 @@
+//! The worker indexing into an `OwnershipStore` alone, without MongoDB.
+//!
+//! Only the ownership model is indexed: the journal the reorgs are rolled back from, the
+//! checkpoint and the classifications of contracts live in the store along with it, and nothing
+//! else the MongoDB worker maintains, such as aggregates, queues or the delta feed, is kept.
+
+use crate::{
+    backoff::Backoff,
+    filter::ContractAllowlist,
+    instrumentation::Instrumentation,
+    legacy, logs_worker,
+    ownership::{OwnershipDelta, SelfTransferPolicy, Transfer},
+    processor::{self, Classification, Signatures},
+    storage::{JournaledBlock, OwnershipStore, StorageResult},
+    store::{ClassificationMethod, UNKNOWN_TOKEN_TYPE},
+    RpcTransport, WorkerConfig, START_BLOCK,
+};
+use std::{collections::BTreeMap, error, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use web3::{
+    types::{BlockId, BlockNumber, FilterBuilder, Log, H256, U64},
+    Web3,
+};
+
+/// Time waited for a new block once the worker reached the head.
+const NEW_BLOCK_POLL_INTERVAL: Duration = Duration::from_millis(5000);
+
+/// What indexing the next blocks did.
+enum Progress {
+    Indexed,
+    /// The worker reached the head, or the block to index is not available yet.
+    Waiting,
+    /// The checkpoint reached the last block to index.
+    Done,
+}
+
+/// Indexes the ownership model into an `OwnershipStore`, which keeps everything the worker needs
+/// to resume after a restart and to roll back reorgs.
+///
+/// Blocks deeper than the reorg depth are fetched in ranges and applied without being journaled,
+/// while the blocks within the reorg depth are fetched one by one, checked against the hash of
+/// their journaled parent, and applied together with their journal entry and the checkpoint.
+///
+/// Of the tunables of `WorkerConfig`, the reorg depth, the log range size, the wrapped tokens, the
+/// contract filter, owner protection and the retry policy of `storage_retry` apply. Contracts
+/// registered by operators are stored in MongoDB, so the allowlist cannot be
+/// `ContractAllowlist::Registered`.
+#[derive(Debug)]
+pub struct StandaloneWorker {
+    web3: Web3<RpcTransport>,
+    store: Arc<dyn OwnershipStore>,
+    signatures: Signatures,
+    start_block: U64,
+    config: WorkerConfig,
+}
+
+impl StandaloneWorker {
+    pub async fn new(
+        ethereum_json_rpc_api_endpoint: String,
+        store: Arc<dyn OwnershipStore>,
+        config: WorkerConfig,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        if let Some(ContractAllowlist::Registered) = config.contract_filter.allowlist {
+            return Err(
+                "Registered contracts are read from MongoDB, which the standalone worker does not use"
+                    .into(),
+            );
+        }
+
+        let instrumentation = Arc::new(Instrumentation::new(config.slow_operation_threshold));
+
+        #[cfg(not(feature = "simulation"))]
+        let simulation = ();
+        #[cfg(feature = "simulation")]
+        let simulation = match &config.simulation {
+            Some(mode) => Some(Arc::new(crate::simulation::Simulation::open(mode)?)),
+            None => None,
+        };
+
+        let web3 = crate::get_web3_http(
+            ethereum_json_rpc_api_endpoint,
+            "primary",
+            &config,
+            &instrumentation,
+            &simulation,
+        )
+        .await?;
+
+        #[cfg(feature = "chaos")]
+        let store: Arc<dyn OwnershipStore> = Arc::new(crate::chaos::ChaosStore::new(
+            store,
+            Arc::new(config.chaos.clone()),
+        ));
+
+        Ok(Self {
+            web3,
+            store,
+            signatures: Signatures::new(),
+            start_block: U64::from(START_BLOCK),
+            config,
+        })
+    }
+
+    /// Sets the block indexed first when the store has no checkpoint yet, the block of the first
+    /// mainnet contract the worker indexes by default.
+    pub fn with_start_block(self, start_block: U64) -> Self {
+        Self {
+            start_block,
+            ..self
+        }
+    }
+
+    /// Indexes the blocks after the checkpoint up to `to_block`, or follows the head forever when
+    /// unset. Failures are logged and retried with the delays of `storage_retry`.
+    pub async fn run(&self, to_block: Option<U64>) {
+        let mut backoff = Backoff::new(self.config.storage_retry);
+
+        loop {
+            match self.index_next_blocks(to_block).await {
+                Ok(Progress::Indexed) => backoff.reset(),
+                Ok(Progress::Waiting) => sleep(NEW_BLOCK_POLL_INTERVAL).await,
+                Ok(Progress::Done) => return,
+                Err(error) => {
+                    eprintln!(
+                        "Error: Could not index the next blocks, retrying... {}",
+                        error
+                    );
+                    backoff.wait().await;
+                }
+            }
+        }
+    }
+
+    async fn index_next_blocks(&self, to_block: Option<U64>) -> StorageResult<Progress> {
+        let next_block = match self.store.checkpoint().await? {
+            Some(checkpoint) => checkpoint + 1,
+            None => self.start_block,
+        };
+
+        if to_block.is_some_and(|to_block| next_block > to_block) {
+            return Ok(Progress::Done);
+        }
+
+        let head = self.web3.eth().block_number().await?;
+        let last_block = to_block.map_or(head, |to_block| to_block.min(head));
+
+        if next_block > last_block {
+            return Ok(Progress::Waiting);
+        }
+
+        // The block at the reorg depth is the common ancestor of the deepest reorg, which a
+        // rollback stops at, so it is journaled along with the blocks after it.
+        let final_block = head.saturating_sub(U64::from(self.config.reorg_depth + 1));
+
+        if next_block <= final_block {
+            let range_end = (next_block + self.config.log_range_size.max(1) - 1)
+                .min(final_block)
+                .min(last_block);
+
+            self.index_range(next_block, range_end).await?;
+
+            return Ok(Progress::Indexed);
+        }
+
+        self.index_block(next_block).await
+    }
+
+    /// Applies the blocks of a range too deep to be orphaned, then moves the checkpoint to its
+    /// end. Each block is applied once, even when the range is indexed again after a failure.
+    async fn index_range(&self, from_block: U64, to_block: U64) -> StorageResult<()> {
+        println!("Indexing blocks {} to {}", from_block, to_block);
+
+        let mut blocks: BTreeMap<U64, (H256, Vec<Log>)> = BTreeMap::new();
+
+        for log in self.range_logs(from_block, to_block).await? {
+            if let (Some(block_number), Some(block_hash)) = (log.block_number, log.block_hash) {
+                blocks
+                    .entry(block_number)
+                    .or_insert_with(|| (block_hash, Vec::new()))
+                    .1
+                    .push(log);
+            }
+        }
+
+        for (block_number, (block_hash, logs)) in blocks {
+            let deltas = self.decode_logs(logs).await?;
+
+            self.store
+                .apply_deltas(block_number, Some(block_hash), &deltas)
+                .await?;
+        }
+
+        self.store.set_checkpoint(to_block).await
+    }
+
+    /// Applies a block within the reorg depth, rolling back the journaled blocks it orphaned
+    /// first.
+    async fn index_block(&self, block_number: U64) -> StorageResult<Progress> {
+        let block = match self
+            .web3
+            .eth()
+            .block(BlockId::Number(BlockNumber::Number(block_number)))
+            .await?
+        {
+            Some(block) => block,
+            None => {
+                println!("Waiting for block {}", block_number);
+                return Ok(Progress::Waiting);
+            }
+        };
+
+        let block_hash = block.hash.ok_or("The provider returned a pending block")?;
+        let parent_block = block_number - 1;
+
+        if let Some(parent) = self.store.journaled_block(parent_block).await? {
+            if parent.block_hash != block.parent_hash {
+                println!("Reorg detected at block {}", block_number);
+                self.rollback(parent_block).await?;
+
+                return Ok(Progress::Indexed);
+            }
+        }
+
+        let logs = match self.logs_filter() {
+            Some(filter) => {
+                self.web3
+                    .eth()
+                    .logs(filter.block_hash(block_hash).build())
+                    .await?
+            }
+            None => Vec::new(),
+        };
+
+        let deltas = self.decode_logs(logs).await?;
+
+        self.store
+            .apply_block(&JournaledBlock {
+                block_number,
+                block_hash,
+                parent_hash: block.parent_hash,
+                deltas,
+            })
+            .await?;
+
+        self.store
+            .prune_journal(block_number.saturating_sub(U64::from(self.config.reorg_depth)))
+            .await?;
+
+        #[cfg(feature = "chaos")]
+        if self.config.chaos.reorg() {
+            println!("Chaos: Orphaning block {}", block_number);
+            self.store.revert_block(block_number).await?;
+        }
+
+        Ok(Progress::Indexed)
+    }
+
+    /// Reverts the journaled blocks that are no longer part of the canonical chain, walking back
+    /// from `block_number` until a journaled hash matches the chain again. Each revert moves the
+    /// checkpoint back, so a rollback failing midway resumes from the block it stopped at.
+    async fn rollback(&self, mut block_number: U64) -> StorageResult<()> {
+        while let Some(block) = self.store.journaled_block(block_number).await? {
+            let canonical_block = self
+                .web3
+                .eth()
+                .block(BlockId::Number(BlockNumber::Number(block_number)))
+                .await?;
+
+            if canonical_block.and_then(|block| block.hash) == Some(block.block_hash) {
+                return Ok(());
+            }
+
+            println!(
+                "Rolling back orphaned block {} ({:#x})",
+                block_number, block.block_hash
+            );
+
+            self.store.revert_block(block_number).await?;
+
+            block_number -= U64::from(1u8);
+        }
+
+        eprintln!(
+            "Warning: Reorg reached block {} which is older than the journal, ownerships may be inconsistent",
+            block_number
+        );
+
+        Ok(())
+    }
+
+    /// Fetches the logs of a range, splitting it in halves whenever the provider rejects it for
+    /// returning too many results.
+    async fn range_logs(&self, from_block: U64, to_block: U64) -> StorageResult<Vec<Log>> {
+        let base_filter = match self.logs_filter() {
+            Some(filter) => filter,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut pending_ranges = vec![(from_block, to_block)];
+        let mut logs = Vec::new();
+
+        while let Some((from_block, to_block)) = pending_ranges.pop() {
+            let filter = base_filter
+                .clone()
+                .from_block(BlockNumber::Number(from_block))
+                .to_block(BlockNumber::Number(to_block))
+                .build();
+
+            match self.web3.eth().logs(filter).await {
+                Ok(range_logs) => logs.extend(range_logs),
+                Err(error) if from_block < to_block && logs_worker::is_too_many_results(&error) => {
+                    let middle_block = from_block + (to_block - from_block) / 2;
+
+                    pending_ranges.push((middle_block + 1, to_block));
+                    pending_ranges.push((from_block, middle_block));
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(logs)
+    }
+
+    /// Filter matching the transfer events of the allowed contracts, and the wrapped token events
+    /// when there are wrapped tokens, `None` when no contract is allowed.
+    fn logs_filter(&self) -> Option<FilterBuilder> {
+        let mut topics = self.signatures.topics();
+
+        if !self.config.wrapped_tokens.is_empty() {
+            topics.extend([
+                self.signatures.wrapped_token_deposit,
+                self.signatures.wrapped_token_withdrawal,
+            ]);
+        }
+
+        let filter = FilterBuilder::default().topics(Some(topics), None, None, None);
+
+        match &self.config.contract_filter.allowlist {
+            Some(ContractAllowlist::Addresses(addresses)) if addresses.is_empty() => None,
+            Some(ContractAllowlist::Addresses(addresses)) => {
+                Some(filter.address(addresses.clone()))
+            }
+            _ => Some(filter),
+        }
+    }
+
+    /// Decodes the logs of a block into the ownership deltas they imply, with owners protected.
+    /// Logs that cannot be decoded are skipped with a warning, there being no dead letter queue
+    /// without MongoDB.
+    async fn decode_logs(&self, logs: Vec<Log>) -> StorageResult<Vec<OwnershipDelta>> {
+        #[cfg(feature = "chaos")]
+        let logs = self.config.chaos.duplicate_logs(logs);
+
+        let logs = processor::dedup_logs(self.config.contract_filter.retain_logs(logs));
+        let logs = processor::drop_paired_erc_777_logs(&self.signatures, logs);
+
+        let mut deltas = Vec::new();
+
+        for log in &logs {
+            let transfers = match processor::decode_wrapped_token_log(
+                &self.signatures,
+                &self.config.wrapped_tokens,
+                log,
+            ) {
+                Some(transfers) => transfers,
+                None => self.decode_transfers(log, &logs).await?,
+            };
+
+            let transfers = match transfers {
+                Ok(transfers) => transfers,
+                Err(error) => {
+                    eprintln!(
+                        "Warning: Skipping log {:#x}:{} of {:#x} which could not be decoded... {}",
+                        log.transaction_hash.unwrap_or_default(),
+                        log.log_index.unwrap_or_default(),
+                        log.address,
+                        error
+                    );
+                    continue;
+                }
+            };
+
+            let filter = &self.config.contract_filter;
+            deltas.extend(
+                transfers
+                    .iter()
+                    .flat_map(|transfer| transfer.deltas(SelfTransferPolicy::default()))
+                    .filter(|delta| {
+                        filter.retains_token(delta.contract_address, delta.token_id.as_deref())
+                    })
+                    .map(|delta| self.config.owner_protection.protect_delta(delta)),
+            );
+        }
+
+        Ok(deltas)
+    }
+
+    /// Classifies the contract that emitted the log when it is not classified yet, then decodes
+    /// its transfers as the token type of the contract.
+    async fn decode_transfers(
+        &self,
+        log: &Log,
+        block_logs: &[Log],
+    ) -> StorageResult<Result<Vec<Transfer>, String>> {
+        let legacy_contract = legacy::find(log.address);
+
+        let token_type = match self.store.token_type(log.address).await? {
+            Some(token_type) => token_type,
+            None => match self.classify(log, legacy_contract.is_some()).await? {
+                Some(token_type) => token_type,
+                None => return Ok(Ok(Vec::new())),
+            },
+        };
+
+        if token_type == UNKNOWN_TOKEN_TYPE {
+            return Ok(Ok(Vec::new()));
+        }
+
+        // Semi-fungible contracts emit ERC20 and ERC721 transfers alike, each decoded as the type
+        // of its shape.
+        let token_type = match processor::transfer_shape(&self.signatures, log) {
+            Some(shape)
+                if legacy_contract.is_none()
+                    && matches!(token_type.as_str(), "ERC20" | "ERC721") =>
+            {
+                shape.to_string()
+            }
+            _ => token_type,
+        };
+
+        Ok(processor::decode_classified_log(
+            &self.signatures,
+            log,
+            block_logs,
+            &token_type,
+        ))
+    }
+
+    /// Classifies and stores the token type of the contract that emitted the log, `None` when it
+    /// could not be determined for now.
+    async fn classify(&self, log: &Log, legacy: bool) -> StorageResult<Option<String>> {
+        let classification = if legacy {
+            Some(Classification::new("ERC721", ClassificationMethod::Legacy))
+        } else {
+            processor::classify(&self.web3, &self.signatures, log).await?
+        };
+
+        let classification = match classification {
+            Some(classification) => classification,
+            None => return Ok(None),
+        };
+
+        println!(
+            "Classified {:#x} as {} by {}",
+            log.address, classification.token_type, classification.classified_by
+        );
+
+        self.store
+            .set_token_type(
+                log.address,
+                &classification.token_type,
+                classification.classified_by,
+            )
+            .await?;
+
+        Ok(Some(classification.token_type))
+    }
+}
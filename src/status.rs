@@ -0,0 +1,91 @@
+//! Sync status of the worker as the `status` command reports it.
+
+use crate::{store::Store, RpcTransport};
+use mongodb::bson::DateTime;
+use std::{error, time::Duration};
+use web3::{
+    types::{BlockId, BlockNumber, U64},
+    Web3,
+};
+
+/// How far the ownership model is behind the chain and how fast it moves.
+#[derive(Debug, Clone)]
+pub struct SyncStatus {
+    /// Last block applied by the live worker, `None` before the first block.
+    pub last_processed_block: Option<u64>,
+    /// Latest block of the provider.
+    pub latest_block: u64,
+    /// Seconds between the timestamps of the last processed block and of the latest block.
+    pub lag_seconds: Option<u64>,
+    /// Blocks the live worker moved through per second over the throughput window, `None` when
+    /// it marked fewer than two blocks as applied within it.
+    pub blocks_per_second: Option<f64>,
+    /// Estimated number of documents of every collection of the worker.
+    pub collection_counts: Vec<(String, u64)>,
+}
+
+impl SyncStatus {
+    /// Blocks the provider has that the live worker has not applied yet.
+    pub fn lag(&self) -> Option<u64> {
+        self.last_processed_block
+            .map(|last_processed_block| self.latest_block.saturating_sub(last_processed_block))
+    }
+}
+
+/// Reads the checkpoint and the head of the chain, the throughput of the live worker from the
+/// blocks it marked as applied during the last `window`, and the collection counts.
+pub(crate) async fn sync_status(
+    web3: &Web3<RpcTransport>,
+    store: &Store,
+    window: Duration,
+) -> Result<SyncStatus, Box<dyn error::Error + Send + Sync>> {
+    let since =
+        DateTime::from_millis(DateTime::now().timestamp_millis() - window.as_millis() as i64);
+
+    let (last_processed_block, latest_block, applied, collection_counts) = futures::try_join!(
+        async { Ok(store.last_processed_block().await?) },
+        async { Ok(web3.eth().block_number().await?) },
+        async { Ok(store.applied_blocks_since(since).await?) },
+        async { Ok::<_, Box<dyn error::Error + Send + Sync>>(store.collection_counts().await?) },
+    )?;
+
+    let lag_seconds = match last_processed_block {
+        Some(last_processed_block) => {
+            let (processed_at, latest_at) = futures::try_join!(
+                block_timestamp(web3, last_processed_block),
+                block_timestamp(web3, latest_block)
+            )?;
+
+            processed_at
+                .zip(latest_at)
+                .map(|(processed_at, latest_at)| latest_at.saturating_sub(processed_at))
+        }
+        None => None,
+    };
+
+    let blocks_per_second = applied.and_then(|((first_block, first_at), (last_block, last_at))| {
+        let elapsed = (last_at.timestamp_millis() - first_at.timestamp_millis()) as f64 / 1000.0;
+
+        (elapsed > 0.0).then(|| last_block.saturating_sub(first_block).as_u64() as f64 / elapsed)
+    });
+
+    Ok(SyncStatus {
+        last_processed_block: last_processed_block.map(|block_number| block_number.as_u64()),
+        latest_block: latest_block.as_u64(),
+        lag_seconds,
+        blocks_per_second,
+        collection_counts,
+    })
+}
+
+/// Unix time in seconds a block was mined at, `None` when the provider does not know the block.
+async fn block_timestamp(
+    web3: &Web3<RpcTransport>,
+    block_number: U64,
+) -> Result<Option<u64>, web3::Error> {
+    Ok(web3
+        .eth()
+        .block(BlockId::Number(BlockNumber::Number(block_number)))
+        .await?
+        .map(|block| block.timestamp.as_u64()))
+}
@@ -0,0 +1,269 @@
+use crate::{
+    amount::Amount,
+    ownership::OwnershipDelta,
+    store::{ClassificationMethod, DeltaBatch, JournalEntry, Store},
+};
+use futures::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::{error, fmt};
+use web3::types::{H160, H256, U64};
+
+pub type StorageResult<T> = Result<T, Box<dyn error::Error + Send + Sync>>;
+
+/// A block within the reorg depth of the head, journaled with the deltas applied for it so they
+/// can be reverted when a reorg orphans it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournaledBlock {
+    pub block_number: U64,
+    pub block_hash: H256,
+    pub parent_hash: H256,
+    pub deltas: Vec<OwnershipDelta>,
+}
+
+impl JournaledBlock {
+    /// The deltas undoing the block, newest first.
+    pub fn inverse_deltas(&self) -> Vec<OwnershipDelta> {
+        self.deltas
+            .iter()
+            .rev()
+            .map(OwnershipDelta::inverse)
+            .collect()
+    }
+}
+
+/// A database the ownership model is written to, either next to the MongoDB collections of the
+/// worker or on its own. Along with the ownership model, a store keeps a checkpoint, a journal of
+/// the blocks within the reorg depth and the token types of classified contracts, which is all
+/// `StandaloneWorker` needs to index without MongoDB.
+///
+/// Applying deltas upserts fungible balances, moves NFTs between owners and deletes the ownerships
+/// of ERC721 tokens once they are emptied. Deltas of the zero address, which mints debit and burns
+/// credit, change the supply of the token instead.
+pub trait OwnershipStore: fmt::Debug + Send + Sync {
+    /// Applies the deltas of a block. With a block hash, the deltas of a block are applied at most
+    /// once, even when the worker writes the block again after a crash.
+    fn apply_deltas<'a>(
+        &'a self,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>>;
+
+    /// Applies the inverse deltas of an orphaned block, unless the block was never applied or was
+    /// reverted already.
+    fn revert_deltas<'a>(
+        &'a self,
+        block_hash: H256,
+        inverse_deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>>;
+
+    /// Exact quantity of a token held by an owner, zero when there is no ownership. `token_id` is
+    /// `None` for fungible tokens.
+    fn ownership_amount<'a>(
+        &'a self,
+        contract_address: H160,
+        token_id: Option<&'a str>,
+        owner: H160,
+    ) -> BoxFuture<'a, StorageResult<Amount>>;
+
+    /// Last block the ownership model is complete up to.
+    fn checkpoint(&self) -> BoxFuture<'_, StorageResult<Option<U64>>>;
+
+    fn set_checkpoint(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>>;
+
+    /// Applies the deltas of a block within the reorg depth, unless they were applied already,
+    /// journals the block and moves the checkpoint to it, so that a block is either indexed or not
+    /// when a write fails.
+    fn apply_block<'a>(&'a self, block: &'a JournaledBlock) -> BoxFuture<'a, StorageResult<()>>;
+
+    /// The journaled block at a height, `None` when none is.
+    fn journaled_block(
+        &self,
+        block_number: U64,
+    ) -> BoxFuture<'_, StorageResult<Option<JournaledBlock>>>;
+
+    /// Reverts the deltas of the block journaled at a height, unless they were reverted already,
+    /// drops it from the journal and moves the checkpoint back before it, like `apply_block`
+    /// writes them. Does nothing when no block is journaled at the height.
+    fn revert_block(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>>;
+
+    /// Drops the journaled blocks below `block_number`, which are too deep to be orphaned.
+    fn prune_journal(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>>;
+
+    /// Token type a contract was classified as, `None` when it was not classified yet.
+    fn token_type(&self, contract_address: H160) -> BoxFuture<'_, StorageResult<Option<String>>>;
+
+    fn set_token_type<'a>(
+        &'a self,
+        contract_address: H160,
+        token_type: &'a str,
+        classified_by: ClassificationMethod,
+    ) -> BoxFuture<'a, StorageResult<()>>;
+
+    /// Drops the ownership model, the applied blocks, the journal and the checkpoint for a
+    /// reindex. Contract classifications are kept.
+    fn reset(&self) -> BoxFuture<'_, StorageResult<()>>;
+}
+
+/// The ownership model in MongoDB, as the worker stores it itself.
+impl OwnershipStore for Store {
+    fn apply_deltas<'a>(
+        &'a self,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            if let Some(block_hash) = block_hash {
+                if self.is_block_applied(block_hash).await? {
+                    return Ok(());
+                }
+            }
+
+            self.apply_delta_batch(&mut DeltaBatch::new(deltas).at_block(block_number))
+                .await?;
+
+            if let Some(block_hash) = block_hash {
+                self.mark_block_applied(block_number, block_hash).await?;
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn revert_deltas<'a>(
+        &'a self,
+        block_hash: H256,
+        inverse_deltas: &'a [OwnershipDelta],
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            if !self.is_block_applied(block_hash).await? {
+                return Ok(());
+            }
+
+            self.apply_delta_batch(&mut DeltaBatch::new(inverse_deltas))
+                .await?;
+
+            self.unmark_block_applied(block_hash).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn ownership_amount<'a>(
+        &'a self,
+        contract_address: H160,
+        token_id: Option<&'a str>,
+        owner: H160,
+    ) -> BoxFuture<'a, StorageResult<Amount>> {
+        async move { Ok(Store::ownership_amount(self, contract_address, token_id, owner).await?) }
+            .boxed()
+    }
+
+    fn checkpoint(&self) -> BoxFuture<'_, StorageResult<Option<U64>>> {
+        async move { Ok(self.last_processed_block().await?) }.boxed()
+    }
+
+    fn set_checkpoint(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move { Ok(self.set_last_processed_block(block_number).await?) }.boxed()
+    }
+
+    fn apply_block<'a>(&'a self, block: &'a JournaledBlock) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            self.apply_deltas(block.block_number, Some(block.block_hash), &block.deltas)
+                .await?;
+
+            self.push_journal_entry(&JournalEntry {
+                block_number: block.block_number.as_u64() as i64,
+                block_hash: block.block_hash,
+                parent_hash: block.parent_hash,
+                deltas: block.deltas.clone(),
+                logs: Vec::new(),
+            })
+            .await?;
+
+            Ok(self.set_last_processed_block(block.block_number).await?)
+        }
+        .boxed()
+    }
+
+    fn journaled_block(
+        &self,
+        block_number: U64,
+    ) -> BoxFuture<'_, StorageResult<Option<JournaledBlock>>> {
+        async move {
+            Ok(self
+                .journal_entry(block_number)
+                .await?
+                .map(|entry| JournaledBlock {
+                    block_number,
+                    block_hash: entry.block_hash,
+                    parent_hash: entry.parent_hash,
+                    deltas: entry.deltas,
+                }))
+        }
+        .boxed()
+    }
+
+    fn revert_block(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move {
+            let block = match OwnershipStore::journaled_block(self, block_number).await? {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+
+            if self.applies_blocks_in_transactions() {
+                return Ok(self
+                    .revert_block_in_transaction(
+                        block_number,
+                        block.block_hash,
+                        &block.inverse_deltas(),
+                    )
+                    .await?);
+            }
+
+            self.revert_deltas(block.block_hash, &block.inverse_deltas())
+                .await?;
+            self.remove_journal_entry(block_number).await?;
+
+            Ok(self
+                .set_last_processed_block(block_number - U64::from(1u8))
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn prune_journal(&self, block_number: U64) -> BoxFuture<'_, StorageResult<()>> {
+        async move { Ok(Store::prune_journal(self, block_number).await?) }.boxed()
+    }
+
+    fn token_type(&self, contract_address: H160) -> BoxFuture<'_, StorageResult<Option<String>>> {
+        async move {
+            Ok(self
+                .contract_address(contract_address)
+                .await?
+                .map(|contract_address| contract_address.token_type))
+        }
+        .boxed()
+    }
+
+    fn set_token_type<'a>(
+        &'a self,
+        contract_address: H160,
+        token_type: &'a str,
+        classified_by: ClassificationMethod,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        async move {
+            Store::set_token_type(self, contract_address, token_type, classified_by, None).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn reset(&self) -> BoxFuture<'_, StorageResult<()>> {
+        async move { Ok(Store::reset(self).await?) }.boxed()
+    }
+}
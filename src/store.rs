@@ -0,0 +1,5947 @@
+#[cfg(feature = "api")]
+use crate::openapi;
+use crate::{
+    amount::Amount,
+    approvals::ApprovalChange,
+    auth::Role,
+    cache::ClassificationCache,
+    checksum::checksum_change,
+    ownership::{OwnershipDelta, SelfTransferPolicy, ZeroBalancePolicy},
+    privacy::OwnerProtection,
+    schema::OwnershipSchema,
+    webhooks::WebhookRule,
+};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+#[cfg(feature = "backups")]
+use mongodb::SessionCursor;
+use mongodb::{
+    bson::{doc, oid::ObjectId, spec::BinarySubtype, Binary, Bson, DateTime, Document},
+    error::{
+        BulkWriteFailure, ErrorKind, Result, WriteFailure, TRANSIENT_TRANSACTION_ERROR,
+        UNKNOWN_TRANSACTION_COMMIT_RESULT,
+    },
+    options::{
+        AggregateOptions, ClientOptions, CreateCollectionOptions, FindOneAndUpdateOptions,
+        FindOneOptions, FindOptions, IndexOptions, InsertManyOptions, ReplaceOptions,
+        ReturnDocument, UpdateOptions,
+    },
+    Client, ClientSession, Collection, Cursor, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+use web3::types::{Index, Log, H160, H256, U256, U64};
+
+/// Token type cached for contracts that were probed and found not to be token contracts, so they
+/// are not probed again on every log.
+pub(crate) const UNKNOWN_TOKEN_TYPE: &str = "UNKNOWN";
+
+/// `_id` of the `sync_state` document holding the logs worker checkpoint.
+const LOGS_WORKER_SYNC_STATE_ID: &str = "logs_worker";
+
+/// `_id` of the `sync_state` document holding the version of the data model.
+const SCHEMA_VERSION_SYNC_STATE_ID: &str = "schema_version";
+
+/// `_id` of the `sync_state` document counting the sequences allocated to delta feed entries.
+const DELTA_FEED_SYNC_STATE_ID: &str = "delta_feed";
+
+/// Statements sent per batched write command, so the command stays well under the 16 MB a command
+/// may take.
+const WRITE_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct ContractAddress {
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub address: H160,
+    pub token_type: String,
+    /// Token types besides `token_type` of the `Transfer` events the contract emitted, such as
+    /// `ERC721` for an ERC20 contract also emitting transfers with an indexed token id. Its events
+    /// are decoded by their shape as any of its types.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_token_types: Vec<String>,
+    #[serde(default)]
+    pub self_transfer_policy: SelfTransferPolicy,
+    /// How the token type was determined, `None` for contracts classified before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub classified_by: Option<ClassificationMethod>,
+    /// Implementation behind `address` when it is an EIP-1967 proxy whose implementation reported
+    /// the token interface.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "api", schema(value_type = Option<openapi::Address>))]
+    pub implementation_address: Option<H160>,
+    /// Set once the contract emitted more events in a block than the volume cap, after which its
+    /// balances are reconciled instead of following its events.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub volume_capped: bool,
+    /// `decimals()` of ERC20 contracts, dividing quantities by `10^decimals` gives the amounts
+    /// wallets display. Unset when the contract does not implement it or they are not cached yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<i32>,
+    /// Block before which the logs of the contract are ignored, set when it is registered by an
+    /// operator.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_block: Option<i64>,
+}
+
+/// How the token type of a contract was determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ClassificationMethod {
+    /// The type is implied by the event, as for ERC20 and ERC777 transfers.
+    Event,
+    /// The contract reported the interface through ERC165 `supportsInterface`.
+    Erc165,
+    /// The contract does not implement ERC165, so the shape of its transfer event and of its
+    /// `ownerOf` and `balanceOf` calls were probed instead.
+    Heuristic,
+    /// The contract is in the legacy contract table.
+    Legacy,
+    /// An operator registered the token type, overriding any classification.
+    Manual,
+}
+
+impl std::fmt::Display for ClassificationMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClassificationMethod::Event => write!(f, "event"),
+            ClassificationMethod::Erc165 => write!(f, "erc165"),
+            ClassificationMethod::Heuristic => write!(f, "heuristic"),
+            ClassificationMethod::Legacy => write!(f, "legacy"),
+            ClassificationMethod::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct TokenOwnership {
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub contract_address: H160,
+    #[serde(default)]
+    pub token_id: Option<String>,
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub owner: H160,
+    /// `amount` as a float, for queries and aggregations.
+    pub quantity: f64,
+    /// Exact quantity held, `None` for ownerships stored before exact amounts were and in
+    /// compatibility mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Amount>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub custody: bool,
+    /// Creator the token id of a shared ERC1155 contract encodes, which identifies the collection
+    /// the token belongs to on the platform.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "api", schema(value_type = Option<openapi::Address>))]
+    pub creator: Option<H160>,
+}
+
+impl TokenOwnership {
+    /// The exact quantity held, rounded from `quantity` when no amount is stored.
+    pub fn exact_amount(&self) -> Amount {
+        self.amount
+            .unwrap_or_else(|| Amount::from_f64(self.quantity))
+    }
+}
+
+/// Circulating supply of a contract's tokens, or of a single ERC1155 token, raised by mints and
+/// lowered by burns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct TokenSupply {
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub contract_address: H160,
+    pub token_type: String,
+    /// Set for ERC1155 tokens only, whose ids are distinct tokens with their own supply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    pub supply: f64,
+    /// Exact supply, `None` for supplies stored before exact amounts were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Amount>,
+}
+
+/// Aggregates of a contract, updated with every applied delta. The zero address is not counted as
+/// a holder and its balance is not part of the supply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContractStats {
+    #[serde(rename = "_id")]
+    pub contract_address: H160,
+    pub holder_count: i64,
+    pub supply: f64,
+    /// XOR of the hashes of the contract's ownerships, for comparing deployments. Not maintained
+    /// in compatibility mode, which does not store exact amounts.
+    #[serde(default)]
+    pub checksum: i64,
+}
+
+/// Number of owners holding a token, updated with every applied delta. ERC1155 tokens are counted
+/// per token id, and the tokens of other contracts together, with `token_id` unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenHolderCount {
+    pub contract_address: H160,
+    pub token_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    pub holder_count: i64,
+}
+
+/// The tokens of a contract held by an owner, in the owner keyed `portfolios` view, so everything
+/// an owner holds is read with a single indexed query. Updated with every applied delta.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortfolioEntry {
+    pub owner: H160,
+    pub contract_address: H160,
+    pub token_type: String,
+    /// Token ids held, empty for ERC20 contracts.
+    #[serde(default)]
+    pub token_ids: Vec<String>,
+    /// Number of tokens held, the number of token ids, or 1 for an ERC20 balance.
+    pub token_count: i64,
+}
+
+/// Balance of a token an owner was left with by a block, recorded with `--balance-history` for
+/// every ownership the block changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceChange {
+    pub block_number: i64,
+    pub contract_address: H160,
+    pub token_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    pub owner: H160,
+    /// Exact balance after the block.
+    pub amount: Amount,
+    /// `amount` as a float, for charts and aggregations.
+    pub quantity: f64,
+}
+
+/// The quantity of a contract's tokens held by an owner, across every token id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ContractHolding {
+    contract_address: H160,
+    owner: H160,
+    quantity: f64,
+}
+
+/// The largest holders of a contract, materialized from `contract_holdings` by the top holders
+/// refresh so leaderboards are read as a single document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopHolders {
+    #[serde(rename = "_id")]
+    pub contract_address: H160,
+    /// Largest first, then by owner. The zero address is left out.
+    pub holders: Vec<TopHolder>,
+    /// Aggregates of the contract the holders were read with, which a refresh compares with the
+    /// current ones to skip unchanged contracts.
+    pub holder_count: i64,
+    pub supply: f64,
+    pub checksum: i64,
+    /// Last block applied when the holders were read.
+    pub block_number: Option<i64>,
+    pub refreshed_at: DateTime,
+}
+
+/// Quantity of a contract's tokens held by an owner, across every token id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopHolder {
+    pub owner: H160,
+    pub quantity: f64,
+    /// Exact quantity held, `None` for holdings stored before exact amounts were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Amount>,
+}
+
+impl TopHolder {
+    /// The exact quantity held, rounded from `quantity` when no amount is stored.
+    pub fn exact_amount(&self) -> Amount {
+        self.amount
+            .unwrap_or_else(|| Amount::from_f64(self.quantity))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncState {
+    #[serde(rename = "_id")]
+    id: String,
+    last_processed_block: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FeedSequence {
+    #[serde(rename = "_id")]
+    id: String,
+    sequence: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SchemaVersion {
+    #[serde(rename = "_id")]
+    id: String,
+    version: i64,
+    migrated_at: DateTime,
+}
+
+/// The ownership deltas applied while processing a block, kept for the last few blocks so they can be
+/// reverted when the block is orphaned by a reorg.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    #[serde(rename = "_id")]
+    pub block_number: i64,
+    pub block_hash: H256,
+    pub parent_hash: H256,
+    pub deltas: Vec<OwnershipDelta>,
+    /// The deltas of `deltas` grouped by the log they were decoded from.
+    #[serde(default)]
+    pub logs: Vec<JournaledLog>,
+}
+
+/// The deltas applied for a single log of a journaled block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournaledLog {
+    pub transaction_hash: H256,
+    pub log_index: U256,
+    /// Set when the provider reported the log as removed and `deltas` revert the ones applied when
+    /// it was first seen.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+    pub deltas: Vec<OwnershipDelta>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub approvals: Vec<ApprovalChange>,
+    /// Transfers decoded from the log, appended to the transfer history but not journaled.
+    #[serde(skip)]
+    pub(crate) transfers: Vec<TransferRecord>,
+    /// Balances touched by the log of a volume capped contract, queued instead of `deltas`.
+    #[serde(skip)]
+    pub(crate) reconciliations: Vec<ReconciliationEntry>,
+}
+
+/// Whether a delta feed entry records a processed block or the rollback of an orphaned one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DeltaFeedKind {
+    Apply,
+    Revert,
+}
+
+/// An append-only record of the deltas applied to the ownership model, ordered by `sequence`.
+///
+/// Unlike the journal, reverted blocks are not removed from the feed but followed by an entry with
+/// the inverse deltas, so consumers replaying it never miss a change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct DeltaFeedEntry {
+    #[serde(rename = "_id")]
+    pub sequence: i64,
+    pub kind: DeltaFeedKind,
+    pub block_number: i64,
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Hash))]
+    pub block_hash: H256,
+    pub deltas: Vec<OwnershipDelta>,
+}
+
+/// A data mutating action taken by an operator through the control API or the CLI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperatorAction {
+    /// Name of the API key, or `cli:<user>` for CLI commands.
+    pub actor: String,
+    /// Role the action was authorized with, absent for CLI commands.
+    #[serde(default)]
+    pub role: Option<Role>,
+    pub action: String,
+    pub outcome: String,
+    pub performed_at: DateTime,
+}
+
+/// An API key minted with the `create-api-key` command, stored by the SHA-256 hash of its secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredApiKey {
+    #[serde(rename = "_id")]
+    pub name: String,
+    pub role: Role,
+    /// Lowercase hex SHA-256 hash of the key.
+    pub key_hash: String,
+    /// Requests per second the key is allowed, the `--api-rate-limit` when `None`.
+    #[serde(default)]
+    pub rate_limit: Option<f64>,
+    pub created_at: DateTime,
+    /// Set once the key is revoked, after which it is rejected.
+    #[serde(default)]
+    pub revoked_at: Option<DateTime>,
+}
+
+/// Logs on which the primary and the quorum providers disagreed, identified as
+/// `<transaction hash>:<log index>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuorumMismatch {
+    pub from_block: i64,
+    pub to_block: i64,
+    pub primary_only: Vec<String>,
+    pub quorum_only: Vec<String>,
+    /// Contracts that emitted the logs only one of the providers returned.
+    #[serde(default)]
+    pub contract_addresses: Vec<H160>,
+    pub detected_at: DateTime,
+}
+
+/// Number of documents of an anomaly report matching a contract.
+#[cfg(feature = "api")]
+#[derive(Debug, Deserialize)]
+struct ContractCount {
+    #[serde(rename = "_id")]
+    contract_address: H160,
+    count: i64,
+}
+
+/// Marks a block whose deltas were fully applied, so they are never applied a second time after a
+/// restart or by a racing worker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AppliedBlock {
+    #[serde(rename = "_id")]
+    block_hash: H256,
+    block_number: i64,
+    applied_at: DateTime,
+}
+
+/// Marks a log whose deltas were applied, so a block written again after a crash midway skips the
+/// logs it had already applied. Dropped once the whole block is marked as applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AppliedLog {
+    /// The block hash, transaction hash and log index, since the same transaction mined in another
+    /// block after a reorg emits other logs.
+    #[serde(rename = "_id")]
+    id: String,
+    block_hash: H256,
+    block_number: i64,
+    applied_at: DateTime,
+}
+
+/// Exclusive right of a single process to a role, such as writing the logs worker checkpoint,
+/// held until `expires_at` unless renewed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Lease {
+    #[serde(rename = "_id")]
+    name: String,
+    holder: String,
+    expires_at: DateTime,
+    /// Process that asked the holder to hand the lease over to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    successor: Option<String>,
+}
+
+/// What an approval document grants `operator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalKind {
+    /// Spending up to `amount` of an ERC20 balance.
+    Allowance,
+    /// Moving the ERC721 token `token_id`.
+    Token,
+    /// Moving every token of the contract held by `owner`.
+    Operator,
+}
+
+/// An approval currently in effect. Revoked approvals are removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub contract_address: H160,
+    pub kind: ApprovalKind,
+    pub owner: H160,
+    /// Spender, approved address or operator, depending on the kind.
+    pub operator: H160,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<f64>,
+    /// Block of the event that set the approval.
+    pub block_number: i64,
+}
+
+/// How the blocks of a coverage segment were processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoverageKind {
+    /// Applied by the live worker, up to its checkpoint.
+    Live,
+    /// Applied by a backfill or a block job.
+    Backfill,
+    /// Rolled back after a reorg orphaned them, and indexed again by the live worker.
+    Reorg,
+}
+
+/// A contiguous range of blocks recorded in `block_coverage` once processed. Segments of the same
+/// kind and contracts are extended as adjacent blocks are processed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoverageSegment {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub kind: CoverageKind,
+    pub from_block: i64,
+    pub to_block: i64,
+    /// Contracts a backfill was restricted to, every contract of the worker's filter when empty.
+    #[serde(default)]
+    pub contracts: Vec<H160>,
+    /// Hashes of the blocks a reorg orphaned, oldest first.
+    #[serde(default)]
+    pub orphaned_blocks: Vec<H256>,
+    pub recorded_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+/// A log that could not be decoded, kept with the error instead of being applied so indexing can
+/// go on, and processed again by `retry-dead-letters` once the decoder is fixed. Identified as
+/// `<block hash>:<log index>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub block_number: i64,
+    pub block_hash: H256,
+    pub transaction_hash: H256,
+    pub log_index: U256,
+    pub contract_address: H160,
+    /// The log as the provider returned it, with its address topics protected under owner
+    /// protection.
+    pub log: Log,
+    /// Error of the last attempt to decode the log.
+    pub error: String,
+    pub attempts: i32,
+    pub recorded_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+/// Progress of a block job through the work queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockJobStatus {
+    Pending,
+    /// Held by a worker process until `lease_expires_at`, after which any worker may claim it.
+    Claimed,
+    Done,
+    /// Gave up on after too many attempts.
+    Failed,
+}
+
+/// A block range backfilled by whichever worker process claims it first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockJob {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub from_block: i64,
+    pub to_block: i64,
+    /// First block not applied yet, so a job claimed again resumes where the last worker stopped.
+    pub next_block: i64,
+    /// Contracts the job is restricted to, every contract of the worker's filter when empty.
+    #[serde(default)]
+    pub contracts: Vec<H160>,
+    pub status: BlockJobStatus,
+    #[serde(default)]
+    pub claimed_by: Option<String>,
+    #[serde(default)]
+    pub lease_expires_at: Option<DateTime>,
+    pub attempts: i32,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Set on the jobs the head worker hands its backlog off with.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub head_handoff: bool,
+}
+
+/// A decoded transfer, kept in the append-only transfer history whatever ownership policy applied
+/// to it. Mints and burns are transfers from and to the zero address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRecord {
+    /// `block_hash:log_index:position`, so a transfer recorded again is recognized as a duplicate
+    /// while the same log included in another block after a reorg is not.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub transaction_hash: H256,
+    pub log_index: i64,
+    pub block_number: i64,
+    pub block_hash: H256,
+    pub contract_address: H160,
+    pub token_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    pub from: H160,
+    pub to: H160,
+    pub quantity: f64,
+    /// Exact transferred amount, `None` for transfers recorded before exact amounts were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<Amount>,
+    /// Set once the provider reported the log as removed or its block was orphaned.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+}
+
+/// A log as the provider returned it, kept in the `raw_logs` archive so its decoding can be
+/// replayed without querying the provider again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawLog {
+    /// `block_hash:log_index`, so a log archived again is recognized as a duplicate while the same
+    /// log included in another block after a reorg is not.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub block_number: i64,
+    pub block_hash: H256,
+    pub transaction_hash: H256,
+    pub transaction_index: i64,
+    pub log_index: i64,
+    pub address: H160,
+    pub topics: Vec<H256>,
+    pub data: Binary,
+    /// Set once the provider reported the log as removed or its block was orphaned.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+}
+
+impl RawLog {
+    /// The archived form of a log, `None` for logs of pending blocks.
+    pub(crate) fn new(log: &Log) -> Option<Self> {
+        let block_hash = log.block_hash?;
+        let log_index = log.log_index?.as_u64() as i64;
+
+        Some(Self {
+            id: format!("{:#x}:{}", block_hash, log_index),
+            block_number: log.block_number?.as_u64() as i64,
+            block_hash,
+            transaction_hash: log.transaction_hash?,
+            transaction_index: log.transaction_index.unwrap_or_default().as_u64() as i64,
+            log_index,
+            address: log.address,
+            topics: log.topics.clone(),
+            data: Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: log.data.0.clone(),
+            },
+            removed: log.is_removed(),
+        })
+    }
+
+    /// The log as the provider returned it.
+    pub fn to_log(&self) -> Log {
+        Log {
+            address: self.address,
+            topics: self.topics.clone(),
+            data: self.data.bytes.clone().into(),
+            block_hash: Some(self.block_hash),
+            block_number: Some(U64::from(self.block_number as u64)),
+            transaction_hash: Some(self.transaction_hash),
+            transaction_index: Some(Index::from(self.transaction_index as u64)),
+            log_index: Some(U256::from(self.log_index as u64)),
+            transaction_log_index: None,
+            log_type: None,
+            removed: Some(self.removed),
+        }
+    }
+}
+
+/// A balance of a volume capped contract waiting to be read from the contract. ERC721 entries have
+/// no owner, since the token is looked up instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconciliationEntry {
+    /// `contract_address:token_id:owner`, so a balance touched again before it is reconciled is
+    /// only queued once.
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub contract_address: H160,
+    pub token_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<H160>,
+    pub queued_at_block: i64,
+}
+
+/// Progress of fetching the metadata of a contract or token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataStatus {
+    Pending,
+    Fetched,
+    /// Every attempt failed to reach the provider.
+    Failed,
+}
+
+/// Progress of a pin request, as reported by the pinning service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum PinStatus {
+    Queued,
+    Pinning,
+    Pinned,
+    Failed,
+}
+
+impl PinStatus {
+    /// Whether the service may still change the status.
+    pub fn is_settled(&self) -> bool {
+        matches!(self, PinStatus::Pinned | PinStatus::Failed)
+    }
+}
+
+/// An IPFS CID referenced by token metadata, and the request pinning it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct Pin {
+    pub cid: String,
+    /// `requestid` the pinning service assigned, unset when the request failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub status: PinStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Name, symbol and decimals of a contract, or the URI of a single NFT when `token_id` is set,
+/// along with the document it points to when token URIs are resolved. Fields the contract does
+/// not implement stay unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+pub struct TokenMetadata {
+    /// `contract_address`, followed by `:token_id` for the metadata of a single token.
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[cfg_attr(feature = "api", schema(value_type = openapi::Address))]
+    pub contract_address: H160,
+    pub token_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    pub status: MetadataStatus,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub decimals: Option<i32>,
+    #[serde(default)]
+    pub token_uri: Option<String>,
+    /// JSON document `token_uri` points to, with the `name`, `image` and `attributes` of the token
+    /// as the marketplaces read them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "api", schema(value_type = Option<Object>))]
+    pub document: Option<Document>,
+    /// `image` of the document, usually an `ipfs://` or HTTP link to the artwork.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// IPFS content of `token_uri` and `image` pinned through the pinning service.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<Pin>,
+    pub attempts: i32,
+    /// When a pending fetch is due, unset until the first attempt.
+    #[serde(default)]
+    #[cfg_attr(feature = "api", schema(value_type = Option<openapi::DateTime>))]
+    pub next_attempt_at: Option<DateTime>,
+    #[serde(default)]
+    #[cfg_attr(feature = "api", schema(value_type = Option<openapi::DateTime>))]
+    pub fetched_at: Option<DateTime>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl TokenMetadata {
+    pub(crate) fn pending(
+        contract_address: H160,
+        token_type: &str,
+        token_id: Option<String>,
+    ) -> Self {
+        Self {
+            id: match &token_id {
+                Some(token_id) => format!("{:#x}:{}", contract_address, token_id),
+                None => format!("{:#x}", contract_address),
+            },
+            contract_address,
+            token_type: token_type.to_string(),
+            token_id,
+            status: MetadataStatus::Pending,
+            name: None,
+            symbol: None,
+            decimals: None,
+            token_uri: None,
+            document: None,
+            image: None,
+            pins: Vec::new(),
+            attempts: 0,
+            next_attempt_at: None,
+            fetched_at: None,
+            error: None,
+        }
+    }
+}
+
+/// The writes of a block made in a transaction by `Store::in_transaction`.
+#[derive(Debug, Clone, Copy)]
+enum BlockWrite<'a> {
+    Apply {
+        block_hash: Option<H256>,
+        deltas: &'a [OwnershipDelta],
+    },
+    Revert {
+        block_hash: H256,
+        inverse_deltas: &'a [OwnershipDelta],
+    },
+}
+
+/// Deltas of a block collapsed per ownership, holding and supply, and written with a few batched
+/// commands per collection instead of a few commands per delta. Tokens moving back and forth
+/// within the block are written once.
+///
+/// The batch records the writes that went through, so applying it again after an error resumes
+/// after them instead of applying them twice.
+#[derive(Debug, Default)]
+pub(crate) struct DeltaBatch {
+    /// Deltas summed per contract, token and owner, in the order they first appear.
+    ownerships: Vec<OwnershipDelta>,
+    /// Deltas summed per contract and owner, for `contract_holdings`.
+    holdings: Vec<OwnershipDelta>,
+    /// Deltas of the zero address summed per supply.
+    supplies: Vec<OwnershipDelta>,
+    /// Amounts before and after the ownership writes that went through.
+    ownership_amounts: Vec<(Amount, Amount)>,
+    /// Number of ownership increments that went through, in compatibility mode.
+    incremented_ownerships: usize,
+    emptied_ownerships_deleted: bool,
+    holding_amounts: Vec<(Amount, Amount)>,
+    emptied_holdings_deleted: bool,
+    updated_stats: usize,
+    updated_token_holder_counts: usize,
+    updated_portfolios: usize,
+    emptied_portfolios_deleted: bool,
+    supply_amounts: Vec<(Amount, Amount)>,
+    /// Block the deltas belong to, whose balances are recorded in the balance history.
+    block_number: Option<U64>,
+    balance_history_appended: bool,
+    written: bool,
+}
+
+impl DeltaBatch {
+    pub(crate) fn new(deltas: &[OwnershipDelta]) -> Self {
+        let mut batch = Self::default();
+        let mut ownerships = HashMap::new();
+        let mut holdings = HashMap::new();
+        let mut supplies = HashMap::new();
+
+        for delta in deltas {
+            if delta.owner == H160::zero() {
+                let supply = OwnershipDelta {
+                    token_id: delta
+                        .token_id
+                        .clone()
+                        .filter(|_| delta.token_type == "ERC1155"),
+                    ..delta.clone()
+                };
+                collapse_delta(&mut batch.supplies, &mut supplies, supply);
+                continue;
+            }
+
+            let holding = OwnershipDelta {
+                token_id: None,
+                custody: false,
+                ..delta.clone()
+            };
+            collapse_delta(&mut batch.ownerships, &mut ownerships, delta.clone());
+            collapse_delta(&mut batch.holdings, &mut holdings, holding);
+        }
+
+        batch
+    }
+
+    /// Records the balances the deltas of block `block_number` leave in the balance history, when
+    /// the store keeps one.
+    pub(crate) fn at_block(self, block_number: U64) -> Self {
+        Self {
+            block_number: Some(block_number),
+            ..self
+        }
+    }
+
+    /// Splits the deltas of block `block_number` into `partitions` batches by their contract, so the
+    /// deltas of a contract all land in the same batch and in order. Contracts share no ownership,
+    /// holding, stats or supply document, so the batches can be written concurrently.
+    pub(crate) fn partitioned(
+        deltas: &[OwnershipDelta],
+        partitions: usize,
+        block_number: U64,
+    ) -> Vec<Self> {
+        let partitions = partitions.max(1);
+        let mut partitioned_deltas = vec![Vec::new(); partitions];
+
+        for delta in deltas {
+            partitioned_deltas[contract_partition(delta.contract_address, partitions)]
+                .push(delta.clone());
+        }
+
+        partitioned_deltas
+            .iter()
+            .filter(|deltas| !deltas.is_empty())
+            .map(|deltas| Self::new(deltas).at_block(block_number))
+            .collect()
+    }
+
+    /// Whether every write of the batch went through.
+    pub(crate) fn is_written(&self) -> bool {
+        self.written
+    }
+}
+
+/// Partition of a contract among `partitions`, from the low bytes of its address, which are
+/// uniformly distributed and stable across restarts.
+fn contract_partition(contract_address: H160, partitions: usize) -> usize {
+    let mut low_bytes = [0; 8];
+    low_bytes.copy_from_slice(&contract_address.as_bytes()[12..]);
+
+    (u64::from_be_bytes(low_bytes) % partitions as u64) as usize
+}
+
+/// Adds a delta to the delta of `deltas` with the same contract, token and owner, or appends it.
+fn collapse_delta(
+    deltas: &mut Vec<OwnershipDelta>,
+    positions: &mut HashMap<(H160, String, Option<String>, H160), usize>,
+    delta: OwnershipDelta,
+) {
+    let key = (
+        delta.contract_address,
+        delta.token_type.clone(),
+        delta.token_id.clone(),
+        delta.owner,
+    );
+
+    match positions.get(&key) {
+        Some(&position) => {
+            let collapsed = &mut deltas[position];
+            collapsed.amount = Some(collapsed.exact_amount() + delta.exact_amount());
+            collapsed.quantity += delta.quantity;
+            collapsed.custody |= delta.custody;
+        }
+        None => {
+            positions.insert(key, deltas.len());
+            deltas.push(delta);
+        }
+    }
+}
+
+/// MongoDB collections backing the ownership model.
+#[derive(Debug, Clone)]
+pub(crate) struct Store {
+    database: Database,
+    /// Prefix of the name of every collection.
+    collection_prefix: String,
+    contract_addresses: Collection<ContractAddress>,
+    /// Ownership documents, in the layout of `ownership_schema`.
+    token_ownerships: Collection<Document>,
+    ownership_schema: OwnershipSchema,
+    /// Ownerships removed under the `Archive` zero balance policy, in the layout of
+    /// `ownership_schema`.
+    archived_ownerships: Collection<Document>,
+    zero_balance_policy: ZeroBalancePolicy,
+    /// Whether ownerships are pruned as soon as their balance drops to zero, rather than by
+    /// `prune_zero_ownerships`.
+    prunes_zero_balances_inline: bool,
+    /// Largest float quantity counted as a zero balance in compatibility mode.
+    zero_balance_tolerance: f64,
+    /// Shared ERC1155 contracts whose ownerships record the creator of their token.
+    shared_contracts: Vec<H160>,
+    classification_cache: Option<std::sync::Arc<ClassificationCache>>,
+    /// Client of `database` blocks are applied in transactions with.
+    transaction_client: Option<Client>,
+    sync_state: Collection<SyncState>,
+    block_journal: Collection<JournalEntry>,
+    delta_feed: Collection<DeltaFeedEntry>,
+    operator_actions: Collection<OperatorAction>,
+    api_keys: Collection<StoredApiKey>,
+    quorum_mismatches: Collection<QuorumMismatch>,
+    contract_holdings: Collection<ContractHolding>,
+    contract_stats: Collection<ContractStats>,
+    top_holders: Collection<TopHolders>,
+    token_holder_counts: Collection<TokenHolderCount>,
+    portfolios: Collection<PortfolioEntry>,
+    balance_history: Collection<BalanceChange>,
+    /// Whether the balances left by the deltas of a block are recorded in `balance_history`.
+    records_balance_history: bool,
+    webhook_rules: Collection<WebhookRule>,
+    block_jobs: Collection<BlockJob>,
+    approvals: Collection<Approval>,
+    leases: Collection<Lease>,
+    applied_blocks: Collection<AppliedBlock>,
+    applied_logs: Collection<AppliedLog>,
+    token_supplies: Collection<TokenSupply>,
+    transfers: Collection<TransferRecord>,
+    raw_logs: Collection<RawLog>,
+    reconciliation_queue: Collection<ReconciliationEntry>,
+    token_metadata: Collection<TokenMetadata>,
+    block_coverage: Collection<CoverageSegment>,
+    dead_letters: Collection<DeadLetter>,
+    #[cfg(feature = "chaos")]
+    chaos: std::sync::Arc<crate::chaos::ChaosConfig>,
+}
+
+impl Store {
+    pub(crate) fn new(database: &Database) -> Self {
+        Self::open(database, "", OwnershipSchema::default())
+    }
+
+    fn open(
+        database: &Database,
+        collection_prefix: &str,
+        ownership_schema: OwnershipSchema,
+    ) -> Self {
+        let collection = |name: &str| format!("{}{}", collection_prefix, name);
+
+        Self {
+            database: database.clone(),
+            collection_prefix: collection_prefix.to_string(),
+            contract_addresses: database.collection(&collection("contract_addresses")),
+            token_ownerships: database.collection(&collection(ownership_schema.collection())),
+            archived_ownerships: database.collection(&collection(&format!(
+                "archived_{}",
+                ownership_schema.collection()
+            ))),
+            ownership_schema,
+            zero_balance_policy: ZeroBalancePolicy::Keep,
+            prunes_zero_balances_inline: false,
+            zero_balance_tolerance: 0.0,
+            shared_contracts: Vec::new(),
+            classification_cache: None,
+            transaction_client: None,
+            sync_state: database.collection(&collection("sync_state")),
+            block_journal: database.collection(&collection("block_journal")),
+            delta_feed: database.collection(&collection("delta_feed")),
+            operator_actions: database.collection(&collection("operator_actions")),
+            api_keys: database.collection(&collection("api_keys")),
+            quorum_mismatches: database.collection(&collection("quorum_mismatches")),
+            contract_holdings: database.collection(&collection("contract_holdings")),
+            contract_stats: database.collection(&collection("contract_stats")),
+            top_holders: database.collection(&collection("top_holders")),
+            token_holder_counts: database.collection(&collection("token_holder_counts")),
+            portfolios: database.collection(&collection("portfolios")),
+            balance_history: database.collection(&collection("balance_history")),
+            records_balance_history: false,
+            webhook_rules: database.collection(&collection("webhook_rules")),
+            block_jobs: database.collection(&collection("block_jobs")),
+            approvals: database.collection(&collection("approvals")),
+            leases: database.collection(&collection("leases")),
+            applied_blocks: database.collection(&collection("applied_blocks")),
+            applied_logs: database.collection(&collection("applied_logs")),
+            token_supplies: database.collection(&collection("token_supplies")),
+            transfers: database.collection(&collection("transfers")),
+            raw_logs: database.collection(&collection("raw_logs")),
+            reconciliation_queue: database.collection(&collection("reconciliation_queue")),
+            token_metadata: database.collection(&collection("token_metadata")),
+            block_coverage: database.collection(&collection("block_coverage")),
+            dead_letters: database.collection(&collection("dead_letters")),
+            #[cfg(feature = "chaos")]
+            chaos: Default::default(),
+        }
+    }
+
+    /// Prefixes the name of every collection with `collection_prefix`, so workers indexing other
+    /// chains or environments can share the database.
+    pub(crate) fn with_collection_prefix(self, collection_prefix: &str) -> Self {
+        Self {
+            zero_balance_policy: self.zero_balance_policy,
+            prunes_zero_balances_inline: self.prunes_zero_balances_inline,
+            zero_balance_tolerance: self.zero_balance_tolerance,
+            shared_contracts: self.shared_contracts,
+            classification_cache: self.classification_cache,
+            transaction_client: self.transaction_client,
+            #[cfg(feature = "chaos")]
+            chaos: self.chaos,
+            ..Self::open(&self.database, collection_prefix, self.ownership_schema)
+        }
+    }
+
+    /// Reads and writes the ownership model in the layout of `ownership_schema`.
+    pub(crate) fn with_ownership_schema(self, ownership_schema: OwnershipSchema) -> Self {
+        Self {
+            token_ownerships: self.database.collection(&format!(
+                "{}{}",
+                self.collection_prefix,
+                ownership_schema.collection()
+            )),
+            archived_ownerships: self.database.collection(&format!(
+                "{}archived_{}",
+                self.collection_prefix,
+                ownership_schema.collection()
+            )),
+            ownership_schema,
+            ..self
+        }
+    }
+
+    /// Deletes or archives the ERC20 and ERC1155 ownerships whose balance drops to zero, as soon as
+    /// it does when `inline`, or when `prune_zero_ownerships` runs otherwise. Float quantities up to
+    /// `tolerance` away from zero count as zero in compatibility mode.
+    pub(crate) fn with_zero_balance_pruning(
+        self,
+        policy: ZeroBalancePolicy,
+        inline: bool,
+        tolerance: f64,
+    ) -> Self {
+        Self {
+            zero_balance_policy: policy,
+            prunes_zero_balances_inline: inline && policy != ZeroBalancePolicy::Keep,
+            zero_balance_tolerance: tolerance,
+            ..self
+        }
+    }
+
+    /// Records the creator of the tokens of these shared ERC1155 contracts on their ownerships.
+    pub(crate) fn with_shared_contracts(self, shared_contracts: Vec<H160>) -> Self {
+        Self {
+            shared_contracts,
+            ..self
+        }
+    }
+
+    /// Looks contract classifications up in `classification_cache` before MongoDB.
+    pub(crate) fn with_classification_cache(
+        self,
+        classification_cache: Option<std::sync::Arc<ClassificationCache>>,
+    ) -> Self {
+        Self {
+            classification_cache,
+            ..self
+        }
+    }
+
+    /// Records the balances left by the deltas of every block in `balance_history`.
+    pub(crate) fn with_balance_history(self, records_balance_history: bool) -> Self {
+        Self {
+            records_balance_history,
+            ..self
+        }
+    }
+
+    /// Applies blocks in transactions started with `transaction_client`, the client of the
+    /// database, which must be a replica set or sharded cluster.
+    pub(crate) fn with_transactions(self, transaction_client: Option<Client>) -> Self {
+        Self {
+            transaction_client,
+            ..self
+        }
+    }
+
+    /// Whether `apply_block_in_transaction` can be used.
+    pub(crate) fn applies_blocks_in_transactions(&self) -> bool {
+        self.transaction_client.is_some()
+    }
+
+    #[cfg(feature = "chaos")]
+    pub(crate) fn with_chaos(self, chaos: std::sync::Arc<crate::chaos::ChaosConfig>) -> Self {
+        Self { chaos, ..self }
+    }
+
+    /// Fails the operation about to be made when the chaos layer injects a timeout.
+    fn inject_fault(&self) -> Result<()> {
+        #[cfg(feature = "chaos")]
+        if let Some(error) = self.chaos.mongo_timeout() {
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Creates the indexes the queries of the worker rely on, the unique ones guarding the upserts
+    /// of concurrent writers against inserting a document twice. Creating an index that exists
+    /// already does nothing. A query index that cannot be created, because an index of the same
+    /// keys exists with other options, is left out with a warning, while failing to create a
+    /// unique index fails, exact sums detecting conflicting writes by its duplicate keys.
+    pub(crate) async fn ensure_indexes(&self) -> Result<()> {
+        self.inject_fault()?;
+
+        let schema = &self.ownership_schema;
+        let ownership_keys = |fields: &[&str]| {
+            fields
+                .iter()
+                .map(|field| (schema.field(field), Bson::Int32(1)))
+                .collect::<Document>()
+        };
+
+        let indexes = [
+            (self.contract_addresses.name(), doc! { "address": 1 }, true),
+            (
+                self.token_ownerships.name(),
+                ownership_keys(&["contract_address", "owner", "token_id"]),
+                true,
+            ),
+            (
+                self.token_ownerships.name(),
+                ownership_keys(&["owner"]),
+                false,
+            ),
+            (
+                self.token_ownerships.name(),
+                ownership_keys(&["contract_address", "creator"]),
+                false,
+            ),
+            (
+                self.token_ownerships.name(),
+                doc! {
+                    schema.field("contract_address"): 1,
+                    schema.field("quantity"): -1,
+                    schema.field("owner"): 1,
+                    schema.field("token_id"): 1,
+                },
+                false,
+            ),
+            (
+                self.contract_holdings.name(),
+                doc! { "contract_address": 1, "owner": 1 },
+                true,
+            ),
+            (
+                self.contract_holdings.name(),
+                doc! { "contract_address": 1, "quantity": -1, "owner": 1 },
+                false,
+            ),
+            (
+                self.token_supplies.name(),
+                doc! { "contract_address": 1, "token_id": 1 },
+                true,
+            ),
+            (
+                self.token_holder_counts.name(),
+                doc! { "contract_address": 1, "token_id": 1 },
+                true,
+            ),
+            (
+                self.portfolios.name(),
+                doc! { "owner": 1, "contract_address": 1 },
+                true,
+            ),
+            (
+                self.balance_history.name(),
+                doc! { "contract_address": 1, "owner": 1, "token_id": 1, "block_number": 1 },
+                false,
+            ),
+            (
+                self.balance_history.name(),
+                doc! { "block_number": 1 },
+                false,
+            ),
+            (self.raw_logs.name(), doc! { "block_number": 1 }, false),
+            (
+                self.raw_logs.name(),
+                doc! { "address": 1, "block_number": 1 },
+                false,
+            ),
+            (
+                self.approvals.name(),
+                doc! { "contract_address": 1, "kind": 1, "owner": 1, "operator": 1 },
+                false,
+            ),
+            (
+                self.approvals.name(),
+                doc! { "contract_address": 1, "kind": 1, "token_id": 1 },
+                false,
+            ),
+            (
+                self.transfers.name(),
+                doc! { "contract_address": 1, "block_number": 1 },
+                false,
+            ),
+            (
+                self.transfers.name(),
+                doc! { "transaction_hash": 1, "log_index": 1 },
+                false,
+            ),
+            (self.transfers.name(), doc! { "block_hash": 1 }, false),
+            (
+                self.transfers.name(),
+                doc! { "block_number": 1, "log_index": 1 },
+                false,
+            ),
+            (
+                self.transfers.name(),
+                doc! { "from": 1, "block_number": 1 },
+                false,
+            ),
+            (
+                self.transfers.name(),
+                doc! { "to": 1, "block_number": 1 },
+                false,
+            ),
+            (self.applied_blocks.name(), doc! { "applied_at": 1 }, false),
+            (self.applied_logs.name(), doc! { "block_hash": 1 }, false),
+            (self.delta_feed.name(), doc! { "block_number": 1 }, false),
+            (self.delta_feed.name(), doc! { "block_hash": 1 }, false),
+            (
+                self.reconciliation_queue.name(),
+                doc! { "queued_at_block": 1 },
+                false,
+            ),
+            (
+                self.token_metadata.name(),
+                doc! { "status": 1, "next_attempt_at": 1 },
+                false,
+            ),
+            (
+                self.block_jobs.name(),
+                doc! { "status": 1, "from_block": 1 },
+                false,
+            ),
+            (
+                self.webhook_rules.name(),
+                doc! { "contract_address": 1 },
+                false,
+            ),
+            (
+                self.quorum_mismatches.name(),
+                doc! { "contract_addresses": 1, "detected_at": -1 },
+                false,
+            ),
+            (
+                self.operator_actions.name(),
+                doc! { "actor": 1, "performed_at": -1 },
+                false,
+            ),
+            (self.api_keys.name(), doc! { "key_hash": 1 }, true),
+            (
+                self.block_coverage.name(),
+                doc! { "kind": 1, "from_block": 1 },
+                false,
+            ),
+            (
+                self.dead_letters.name(),
+                doc! { "contract_address": 1, "block_number": 1 },
+                false,
+            ),
+        ];
+
+        for (collection, keys, unique) in indexes {
+            let index = IndexModel::builder()
+                .keys(keys.clone())
+                .options(unique.then(|| IndexOptions::builder().unique(true).build()))
+                .build();
+
+            let result = self
+                .database
+                .collection::<Document>(collection)
+                .create_index(index, None)
+                .await;
+
+            match result {
+                Ok(_) => {}
+                Err(error) if !unique && matches!(*error.kind, ErrorKind::Command(_)) => eprintln!(
+                    "Warning: Could not create the index {} of {}... {}",
+                    keys, collection, error
+                ),
+                Err(error) => return Err(error),
+            }
+        }
+
+        // Sparse, since entries appended before they were keyed have no key.
+        self.delta_feed
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "key": 1 })
+                    .options(IndexOptions::builder().unique(true).sparse(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Makes a round trip to the database without reading or writing anything.
+    pub(crate) async fn ping(&self) -> Result<()> {
+        self.inject_fault()?;
+
+        self.database.run_command(doc! { "ping": 1 }, None).await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn contract_address(&self, address: H160) -> Result<Option<ContractAddress>> {
+        self.inject_fault()?;
+
+        if let Some(cache) = &self.classification_cache {
+            if let Some(contract_address) = cache.get(address).await {
+                return Ok(Some(contract_address));
+            }
+        }
+
+        let contract_address = self
+            .contract_addresses
+            .find_one(doc! { "address": format!("{:#x}", address) }, None)
+            .await?;
+
+        if let (Some(cache), Some(contract_address)) =
+            (&self.classification_cache, &contract_address)
+        {
+            cache.put(contract_address).await;
+        }
+
+        Ok(contract_address)
+    }
+
+    async fn evict_classification(&self, address: H160) {
+        if let Some(cache) = &self.classification_cache {
+            cache.evict(address).await;
+        }
+    }
+
+    /// Addresses of the classified contracts, leaving out the ones that are not token contracts.
+    pub(crate) async fn registered_contract_addresses(&self) -> Result<Vec<H160>> {
+        Ok(self
+            .token_contracts()
+            .await?
+            .into_iter()
+            .map(|contract_address| contract_address.address)
+            .collect())
+    }
+
+    /// The classified contracts, leaving out the ones that are not token contracts.
+    pub(crate) async fn token_contracts(&self) -> Result<Vec<ContractAddress>> {
+        self.inject_fault()?;
+
+        self.contract_addresses
+            .find(doc! { "token_type": { "$ne": UNKNOWN_TOKEN_TYPE } }, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Stores the classification of a contract, returning whether the contract was not stored
+    /// before.
+    pub(crate) async fn set_token_type(
+        &self,
+        address: H160,
+        token_type: &str,
+        classified_by: ClassificationMethod,
+        implementation_address: Option<H160>,
+    ) -> Result<bool> {
+        self.inject_fault()?;
+
+        let result = self
+            .contract_addresses
+            .update_one(
+                doc! {
+                    "address": format!("{:#x}", address),
+                },
+                doc! {
+                    "$set": {
+                        "token_type": token_type,
+                        "classified_by": mongodb::bson::to_bson(&classified_by)?,
+                        "implementation_address": implementation_address
+                            .map(|address| format!("{:#x}", address)),
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        self.evict_classification(address).await;
+
+        Ok(result.upserted_id.is_some())
+    }
+
+    /// Registers the token type of a contract on behalf of an operator, overriding its
+    /// classification, and the block before which its logs are ignored. Returns the stored
+    /// classification.
+    pub(crate) async fn register_contract(
+        &self,
+        address: H160,
+        token_type: &str,
+        start_block: Option<U64>,
+    ) -> Result<ContractAddress> {
+        self.inject_fault()?;
+
+        let contract_address = self
+            .contract_addresses
+            .find_one_and_update(
+                doc! { "address": format!("{:#x}", address) },
+                doc! {
+                    "$set": {
+                        "token_type": token_type,
+                        "classified_by": mongodb::bson::to_bson(&ClassificationMethod::Manual)?,
+                        "start_block": start_block.map(|block_number| block_number.as_u64() as i64),
+                    },
+                    "$unset": { "implementation_address": "" },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .expect("An upsert returning the document after the update always returns one");
+
+        self.evict_classification(address).await;
+
+        Ok(contract_address)
+    }
+
+    /// Adds a token type to the ones of a contract, for a `Transfer` event of another shape than
+    /// its `token_type`.
+    pub(crate) async fn add_token_type(&self, address: H160, token_type: &str) -> Result<()> {
+        self.inject_fault()?;
+
+        self.contract_addresses
+            .update_one(
+                doc! { "address": format!("{:#x}", address) },
+                doc! { "$addToSet": { "additional_token_types": token_type } },
+                None,
+            )
+            .await?;
+
+        self.evict_classification(address).await;
+
+        Ok(())
+    }
+
+    /// Caches the decimals of a contract, stored as `null` when it does not implement
+    /// `decimals()` so it is not asked again.
+    pub(crate) async fn set_decimals(&self, address: H160, decimals: Option<i32>) -> Result<()> {
+        self.inject_fault()?;
+
+        self.contract_addresses
+            .update_one(
+                doc! { "address": format!("{:#x}", address) },
+                doc! { "$set": { "decimals": decimals } },
+                None,
+            )
+            .await?;
+
+        self.evict_classification(address).await;
+
+        Ok(())
+    }
+
+    /// ERC20 contracts classified before their decimals were cached.
+    pub(crate) async fn contracts_without_decimals(&self) -> Result<Vec<H160>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .contract_addresses
+            .find(
+                doc! { "token_type": "ERC20", "decimals": { "$exists": false } },
+                None,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|contract| contract.address)
+            .collect())
+    }
+
+    /// Flags a contract as volume capped, so its balances are reconciled from now on.
+    pub(crate) async fn set_volume_capped(&self, address: H160) -> Result<()> {
+        self.inject_fault()?;
+
+        self.contract_addresses
+            .update_one(
+                doc! { "address": format!("{:#x}", address) },
+                doc! { "$set": { "volume_capped": true } },
+                None,
+            )
+            .await?;
+
+        self.evict_classification(address).await;
+
+        Ok(())
+    }
+
+    pub(crate) async fn volume_capped_contracts(&self) -> Result<Vec<H160>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .contract_addresses
+            .find(doc! { "volume_capped": true }, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|contract_address| contract_address.address)
+            .collect())
+    }
+
+    /// Whether the ownership of a delta is removed as soon as its balance drops to zero.
+    fn prunes_inline(&self, delta: &OwnershipDelta) -> bool {
+        delta.token_type == "ERC721" || self.prunes_zero_balances_inline
+    }
+
+    /// Condition on the float quantity of an emptied ownership in compatibility mode.
+    fn zero_quantity(&self) -> Bson {
+        if self.zero_balance_tolerance > 0.0 {
+            Bson::Document(doc! {
+                "$gte": -self.zero_balance_tolerance,
+                "$lte": self.zero_balance_tolerance,
+            })
+        } else {
+            Bson::Double(0.0)
+        }
+    }
+
+    /// Removes at most one ownership matching each filter, which has to require a zero balance,
+    /// archiving them first under the `Archive` zero balance policy.
+    async fn remove_emptied_ownerships(
+        &self,
+        filters: Vec<Document>,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<()> {
+        if filters.is_empty() {
+            return Ok(());
+        }
+
+        if self.zero_balance_policy == ZeroBalancePolicy::Archive {
+            for filters in filters.chunks(WRITE_BATCH_SIZE) {
+                let filter = doc! { "$or": filters.to_vec() };
+                let emptied: Vec<Document> = match session.as_deref_mut() {
+                    Some(session) => {
+                        self.token_ownerships
+                            .find_with_session(filter, None, session)
+                            .await?
+                            .stream(session)
+                            .try_collect()
+                            .await?
+                    }
+                    None => {
+                        self.token_ownerships
+                            .find(filter, None)
+                            .await?
+                            .try_collect()
+                            .await?
+                    }
+                };
+
+                self.archive_ownerships(emptied, session.as_deref_mut())
+                    .await?;
+            }
+        }
+
+        self.delete_all(self.token_ownerships.name(), filters, session)
+            .await
+    }
+
+    /// Copies ownerships to `archived_ownerships` with the time they were archived, replacing the
+    /// archived copy of the same ownership.
+    async fn archive_ownerships(
+        &self,
+        ownerships: Vec<Document>,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<()> {
+        let archived_at = DateTime::now();
+        let statements: Vec<_> = ownerships
+            .into_iter()
+            .filter_map(|mut ownership| {
+                let id = ownership.get("_id")?.clone();
+                ownership.insert("archived_at", archived_at);
+                Some(doc! { "q": { "_id": id }, "u": ownership, "upsert": true })
+            })
+            .collect();
+
+        let mut archived = 0;
+        while archived < statements.len() {
+            archived += self
+                .update_in_order(
+                    self.archived_ownerships.name(),
+                    &statements[archived..],
+                    session.as_deref_mut(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes or archives, following the zero balance policy, up to `limit` ERC20 and ERC1155
+    /// ownerships left with a zero balance. Returns how many were pruned.
+    pub(crate) async fn prune_zero_ownerships(&self, limit: i64) -> Result<usize> {
+        self.inject_fault()?;
+
+        if self.zero_balance_policy == ZeroBalancePolicy::Keep {
+            return Ok(0);
+        }
+
+        let schema = &self.ownership_schema;
+        let emptied = if schema.is_compatibility() {
+            doc! { schema.field("quantity"): self.zero_quantity() }
+        } else {
+            doc! { schema.field("amount"): Amount::zero().to_string() }
+        };
+
+        let ownerships: Vec<Document> = self
+            .token_ownerships
+            .find(emptied.clone(), FindOptions::builder().limit(limit).build())
+            .await?
+            .try_collect()
+            .await?;
+
+        let filters: Vec<_> = ownerships
+            .iter()
+            .filter_map(|ownership| {
+                let mut filter = doc! { "_id": ownership.get("_id")?.clone() };
+                filter.extend(emptied.clone());
+                Some(filter)
+            })
+            .collect();
+        let pruned = filters.len();
+
+        if self.zero_balance_policy == ZeroBalancePolicy::Archive {
+            self.archive_ownerships(ownerships, None).await?;
+        }
+        self.delete_all(self.token_ownerships.name(), filters, None)
+            .await?;
+
+        Ok(pruned)
+    }
+
+    /// Creator of the token of a delta, for shared ERC1155 contracts.
+    fn creator(&self, delta: &OwnershipDelta) -> Option<H160> {
+        if delta.token_type != "ERC1155" || !self.shared_contracts.contains(&delta.contract_address)
+        {
+            return None;
+        }
+
+        let token_id = U256::from_dec_str(delta.token_id.as_deref()?).ok()?;
+
+        Some(shared_token_creator(token_id))
+    }
+
+    /// Ownerships of the tokens a creator minted on a shared ERC1155 contract.
+    pub(crate) async fn creator_ownerships(
+        &self,
+        contract_address: H160,
+        creator: H160,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.inject_fault()?;
+
+        let filter = doc! {
+            "contract_address": format!("{:#x}", contract_address),
+            "creator": format!("{:#x}", creator),
+        };
+
+        self.token_ownerships
+            .find(self.ownership_schema.stored_document(filter), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|document| self.stored_ownership(document))
+            .collect()
+    }
+
+    /// Applies a batch of deltas like `apply_delta` applies them one by one, resuming after the
+    /// writes that went through when the batch was applied before.
+    pub(crate) async fn apply_delta_batch(&self, batch: &mut DeltaBatch) -> Result<()> {
+        self.inject_fault()?;
+
+        self.write_delta_batch(batch, None).await
+    }
+
+    /// Applies the batches of `DeltaBatch::partitioned`, each in a writer task of its own, waiting
+    /// for all of them. Batches whose writes went through are skipped, so after an error only the
+    /// failed ones are applied again.
+    pub(crate) async fn apply_delta_batches(&self, batches: &mut [DeltaBatch]) -> Result<()> {
+        if let [batch] = batches {
+            return self.apply_delta_batch(batch).await;
+        }
+
+        let writers: Vec<_> = batches
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, batch)| !batch.is_written())
+            .map(|(position, batch)| {
+                let store = self.clone();
+                let mut batch = std::mem::take(batch);
+
+                tokio::spawn(async move {
+                    let result = store.apply_delta_batch(&mut batch).await;
+                    (position, batch, result)
+                })
+            })
+            .collect();
+
+        let mut result = Ok(());
+
+        for writer in writers {
+            let (position, batch, writer_result) = writer.await.unwrap();
+            batches[position] = batch;
+
+            if result.is_ok() {
+                result = writer_result;
+            }
+        }
+
+        result
+    }
+
+    /// Writes a batch of deltas, within the transaction of `session` when given.
+    async fn write_delta_batch(
+        &self,
+        batch: &mut DeltaBatch,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<()> {
+        if batch.written {
+            return Ok(());
+        }
+
+        let schema = &self.ownership_schema;
+
+        if schema.is_compatibility() {
+            let statements: Vec<_> = batch
+                .ownerships
+                .iter()
+                .map(|delta| {
+                    doc! {
+                        "q": schema.stored_document(ownership_filter(delta)),
+                        "u": schema.stored_document(doc! { "$inc": { "quantity": delta.quantity } }),
+                        "upsert": true,
+                    }
+                })
+                .collect();
+
+            while batch.incremented_ownerships < statements.len() {
+                batch.incremented_ownerships += self
+                    .update_in_order(
+                        self.token_ownerships.name(),
+                        &statements[batch.incremented_ownerships..],
+                        session.as_deref_mut(),
+                    )
+                    .await?;
+            }
+        } else {
+            let additions: Vec<_> = batch
+                .ownerships
+                .iter()
+                .map(|delta| {
+                    let mut set = doc! {};
+                    if delta.custody {
+                        set.insert("custody", true);
+                    }
+                    if let Some(creator) = self.creator(delta) {
+                        set.insert("creator", format!("{:#x}", creator));
+                    }
+
+                    (
+                        schema.stored_document(ownership_filter(delta)),
+                        delta.exact_amount(),
+                        schema.stored_document(set),
+                    )
+                })
+                .collect();
+
+            self.add_all_exactly(
+                &self.token_ownerships,
+                &additions,
+                &schema.field("amount"),
+                &schema.field("quantity"),
+                &mut batch.ownership_amounts,
+                session.as_deref_mut(),
+            )
+            .await?;
+        }
+
+        if !batch.emptied_ownerships_deleted {
+            let emptied = batch
+                .ownerships
+                .iter()
+                .filter(|delta| self.prunes_inline(delta))
+                .map(|delta| {
+                    let mut filter = ownership_filter(delta);
+                    if schema.is_compatibility() {
+                        filter.insert("quantity", self.zero_quantity());
+                    } else {
+                        filter.insert("amount", Amount::zero().to_string());
+                    }
+                    schema.stored_document(filter)
+                })
+                .collect();
+
+            self.remove_emptied_ownerships(emptied, session.as_deref_mut())
+                .await?;
+            batch.emptied_ownerships_deleted = true;
+        }
+
+        let additions: Vec<_> = batch
+            .holdings
+            .iter()
+            .map(|delta| (holding_filter(delta), delta.exact_amount(), doc! {}))
+            .collect();
+
+        self.add_all_exactly(
+            &self.contract_holdings.clone_with_type(),
+            &additions,
+            "amount",
+            "quantity",
+            &mut batch.holding_amounts,
+            session.as_deref_mut(),
+        )
+        .await?;
+
+        if !batch.emptied_holdings_deleted {
+            let emptied = batch
+                .holdings
+                .iter()
+                .zip(&batch.holding_amounts)
+                .filter(|(_, (_, amount))| amount.is_zero())
+                .map(|(delta, (_, amount))| {
+                    let mut filter = holding_filter(delta);
+                    filter.insert("amount", amount.to_string());
+                    filter
+                })
+                .collect();
+
+            self.delete_all(
+                self.contract_holdings.name(),
+                emptied,
+                session.as_deref_mut(),
+            )
+            .await?;
+            batch.emptied_holdings_deleted = true;
+        }
+
+        let mut stats: Vec<(H160, i64, f64, i64)> = Vec::new();
+        for (delta, (previous_amount, amount)) in batch.holdings.iter().zip(&batch.holding_amounts)
+        {
+            let holder_count_change = holder_count_change(*previous_amount, *amount);
+
+            match stats
+                .iter_mut()
+                .find(|(contract_address, ..)| *contract_address == delta.contract_address)
+            {
+                Some((_, holder_count, supply, _)) => {
+                    *holder_count += holder_count_change;
+                    *supply += delta.quantity;
+                }
+                None => stats.push((
+                    delta.contract_address,
+                    holder_count_change,
+                    delta.quantity,
+                    0,
+                )),
+            }
+        }
+
+        for (delta, (previous, amount)) in batch.ownerships.iter().zip(&batch.ownership_amounts) {
+            if let Some((.., checksum)) = stats
+                .iter_mut()
+                .find(|(contract_address, ..)| *contract_address == delta.contract_address)
+            {
+                *checksum ^= checksum_change(
+                    delta.contract_address,
+                    delta.token_id.as_deref(),
+                    delta.owner,
+                    *previous,
+                    *amount,
+                );
+            }
+        }
+
+        let statements: Vec<_> = stats
+            .into_iter()
+            .map(|(contract_address, holder_count, supply, checksum)| {
+                doc! {
+                    "q": { "_id": format!("{:#x}", contract_address) },
+                    "u": {
+                        "$inc": { "holder_count": holder_count, "supply": supply },
+                        "$bit": { "checksum": { "xor": checksum } },
+                    },
+                    "upsert": true,
+                }
+            })
+            .collect();
+
+        // An upsert racing another process's fails on the duplicate key, and updates once retried.
+        while batch.updated_stats < statements.len() {
+            batch.updated_stats += self
+                .update_in_order(
+                    self.contract_stats.name(),
+                    &statements[batch.updated_stats..],
+                    session.as_deref_mut(),
+                )
+                .await?;
+        }
+
+        // ERC1155 tokens are counted from their ownerships, whose amounts compatibility mode does not
+        // read, and the tokens of other contracts from the holdings.
+        let mut token_holders: Vec<(&OwnershipDelta, i64)> = Vec::new();
+        let changes = batch
+            .ownerships
+            .iter()
+            .zip(&batch.ownership_amounts)
+            .filter(|(delta, _)| delta.token_type == "ERC1155")
+            .chain(
+                batch
+                    .holdings
+                    .iter()
+                    .zip(&batch.holding_amounts)
+                    .filter(|(delta, _)| delta.token_type != "ERC1155"),
+            );
+        for (delta, (previous, amount)) in changes {
+            let change = holder_count_change(*previous, *amount);
+
+            match token_holders
+                .iter_mut()
+                .find(|(counted, _)| token_holder_filter(counted) == token_holder_filter(delta))
+            {
+                Some((_, holder_count)) => *holder_count += change,
+                None => token_holders.push((delta, change)),
+            }
+        }
+
+        let statements: Vec<_> = token_holders
+            .into_iter()
+            .filter(|(_, holder_count)| *holder_count != 0)
+            .map(|(delta, holder_count)| {
+                doc! {
+                    "q": token_holder_filter(delta),
+                    "u": {
+                        "$inc": { "holder_count": holder_count },
+                        "$set": { "token_type": &delta.token_type },
+                    },
+                    "upsert": true,
+                }
+            })
+            .collect();
+
+        while batch.updated_token_holder_counts < statements.len() {
+            batch.updated_token_holder_counts += self
+                .update_in_order(
+                    self.token_holder_counts.name(),
+                    &statements[batch.updated_token_holder_counts..],
+                    session.as_deref_mut(),
+                )
+                .await?;
+        }
+
+        // Like ERC1155 holder counts, portfolios follow the ownership amounts.
+        if !schema.is_compatibility() {
+            let (statements, emptied) =
+                portfolio_writes(batch.ownerships.iter().zip(&batch.ownership_amounts).map(
+                    |(delta, (previous, amount))| (delta, holder_count_change(*previous, *amount)),
+                ));
+
+            while batch.updated_portfolios < statements.len() {
+                batch.updated_portfolios += self
+                    .update_in_order(
+                        self.portfolios.name(),
+                        &statements[batch.updated_portfolios..],
+                        session.as_deref_mut(),
+                    )
+                    .await?;
+            }
+
+            if !batch.emptied_portfolios_deleted {
+                self.delete_all(self.portfolios.name(), emptied, session.as_deref_mut())
+                    .await?;
+                batch.emptied_portfolios_deleted = true;
+            }
+        }
+
+        if let (true, Some(block_number), false) = (
+            self.records_balance_history && !schema.is_compatibility(),
+            batch.block_number,
+            batch.balance_history_appended,
+        ) {
+            let changes: Vec<_> = batch
+                .ownerships
+                .iter()
+                .zip(&batch.ownership_amounts)
+                .filter(|(_, (previous, amount))| previous != amount)
+                .map(|(delta, (_, amount))| BalanceChange {
+                    block_number: block_number.as_u64() as i64,
+                    contract_address: delta.contract_address,
+                    token_type: delta.token_type.clone(),
+                    token_id: delta.token_id.clone(),
+                    owner: delta.owner,
+                    amount: *amount,
+                    quantity: amount.to_f64(),
+                })
+                .collect();
+
+            for chunk in changes.chunks(WRITE_BATCH_SIZE) {
+                match session.as_deref_mut() {
+                    Some(session) => {
+                        self.balance_history
+                            .insert_many_with_session(chunk, None, session)
+                            .await?;
+                    }
+                    None => {
+                        self.balance_history.insert_many(chunk, None).await?;
+                    }
+                }
+            }
+            batch.balance_history_appended = true;
+        }
+
+        let additions: Vec<_> = batch
+            .supplies
+            .iter()
+            .map(|delta| {
+                let mut filter = doc! {
+                    "contract_address": format!("{:#x}", delta.contract_address),
+                };
+                if let Some(token_id) = &delta.token_id {
+                    filter.insert("token_id", token_id);
+                }
+
+                (
+                    filter,
+                    -delta.exact_amount(),
+                    doc! { "token_type": &delta.token_type },
+                )
+            })
+            .collect();
+
+        self.add_all_exactly(
+            &self.token_supplies.clone_with_type(),
+            &additions,
+            "amount",
+            "supply",
+            &mut batch.supply_amounts,
+            session,
+        )
+        .await?;
+
+        batch.written = true;
+
+        Ok(())
+    }
+
+    /// Batched `add_exactly`, reading the stored amounts with one query and writing the sums with
+    /// one command per `WRITE_BATCH_SIZE` additions. Additions are `(filter, amount, set)`, and the
+    /// amounts before and after those that went through are appended to `written`, from which a
+    /// retry resumes.
+    ///
+    /// A sum whose stored amount changed since it was read fails on the duplicate key of its upsert
+    /// and is added again on its own, before the following ones are read and written again. Within a
+    /// transaction the duplicate key fails the transaction instead.
+    async fn add_all_exactly(
+        &self,
+        collection: &Collection<Document>,
+        additions: &[(Document, Amount, Document)],
+        amount_field: &str,
+        quantity_field: &str,
+        written: &mut Vec<(Amount, Amount)>,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<()> {
+        while written.len() < additions.len() {
+            let pending =
+                &additions[written.len()..additions.len().min(written.len() + WRITE_BATCH_SIZE)];
+
+            let filters: Vec<_> = pending
+                .iter()
+                .map(|(filter, _, _)| filter.clone())
+                .collect();
+            let filter = doc! { "$or": filters };
+            let stored: Vec<Document> = match session.as_deref_mut() {
+                Some(session) => {
+                    collection
+                        .find_with_session(filter, None, session)
+                        .await?
+                        .stream(session)
+                        .try_collect()
+                        .await?
+                }
+                None => collection.find(filter, None).await?.try_collect().await?,
+            };
+
+            // Stored documents by the fields of each shape of filter, ERC20 ownerships having no
+            // token id.
+            let mut shapes: Vec<Vec<&str>> = Vec::new();
+            for (filter, _, _) in pending {
+                let shape: Vec<_> = filter.keys().map(String::as_str).collect();
+                if !shapes.contains(&shape) {
+                    shapes.push(shape);
+                }
+            }
+            let mut stored_by_filter = HashMap::new();
+            for document in &stored {
+                for shape in &shapes {
+                    stored_by_filter.insert(filter_key(shape, document), document);
+                }
+            }
+
+            let mut statements = Vec::with_capacity(pending.len());
+            let mut amounts = Vec::with_capacity(pending.len());
+
+            for (filter, amount, set) in pending {
+                let shape: Vec<_> = filter.keys().map(String::as_str).collect();
+                let stored = stored_by_filter.get(&filter_key(&shape, filter));
+
+                let previous = stored
+                    .map(|document| stored_amount(document, amount_field, quantity_field))
+                    .unwrap_or_default();
+                let updated = previous + *amount;
+
+                let mut unchanged = filter.clone();
+                unchanged.insert(
+                    amount_field,
+                    stored
+                        .and_then(|document| document.get(amount_field).cloned())
+                        .unwrap_or(Bson::Null),
+                );
+
+                let mut set = set.clone();
+                set.insert(amount_field, updated.to_string());
+                set.insert(quantity_field, updated.to_f64());
+
+                statements.push(doc! { "q": unchanged, "u": { "$set": set }, "upsert": true });
+                amounts.push((previous, updated));
+            }
+
+            let applied = self
+                .update_in_order(collection.name(), &statements, session.as_deref_mut())
+                .await?;
+            written.extend_from_slice(&amounts[..applied]);
+
+            if let Some((filter, amount, set)) = pending.get(applied) {
+                written.push(
+                    add_exactly(
+                        collection,
+                        filter.clone(),
+                        amount_field,
+                        quantity_field,
+                        *amount,
+                        set.clone(),
+                    )
+                    .await?,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs update statements in order, returning how many went through before one failed on a
+    /// duplicate key, as an upsert does when its filter no longer matches the stored document.
+    /// Other write errors fail the command, and so do duplicate keys within a transaction, which
+    /// they abort.
+    async fn update_in_order(
+        &self,
+        collection: &str,
+        statements: &[Document],
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<usize> {
+        let mut applied = 0;
+
+        for statements in statements.chunks(WRITE_BATCH_SIZE) {
+            let command = doc! {
+                "update": collection,
+                "updates": statements.to_vec(),
+                "ordered": true,
+            };
+            let reply = match session.as_deref_mut() {
+                Some(session) => {
+                    self.database
+                        .run_command_with_session(command, None, session)
+                        .await?
+                }
+                None => self.database.run_command(command, None).await?,
+            };
+
+            let failure: BulkWriteFailure = mongodb::bson::from_document(reply)?;
+
+            match failure
+                .write_errors
+                .as_ref()
+                .and_then(|errors| errors.first())
+            {
+                _ if failure.write_concern_error.is_some() => {
+                    return Err(ErrorKind::BulkWrite(failure).into())
+                }
+                Some(error) if error.code == 11000 && session.is_none() => {
+                    return Ok(applied + error.index)
+                }
+                Some(_) => return Err(ErrorKind::BulkWrite(failure).into()),
+                None => applied += statements.len(),
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// Deletes at most one document matching each filter.
+    async fn delete_all(
+        &self,
+        collection: &str,
+        filters: Vec<Document>,
+        mut session: Option<&mut ClientSession>,
+    ) -> Result<()> {
+        for filters in filters.chunks(WRITE_BATCH_SIZE) {
+            let deletes: Vec<_> = filters
+                .iter()
+                .map(|filter| doc! { "q": filter, "limit": 1 })
+                .collect();
+
+            let command = doc! {
+                "delete": collection,
+                "deletes": deletes,
+                "ordered": false,
+            };
+            let reply = match session.as_deref_mut() {
+                Some(session) => {
+                    self.database
+                        .run_command_with_session(command, None, session)
+                        .await?
+                }
+                None => self.database.run_command(command, None).await?,
+            };
+
+            let failure: BulkWriteFailure = mongodb::bson::from_document(reply)?;
+
+            if failure.write_errors.is_some() || failure.write_concern_error.is_some() {
+                return Err(ErrorKind::BulkWrite(failure).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(any(feature = "api", feature = "webhooks"))]
+    pub(crate) async fn contract_stats(&self, contracts: &[H160]) -> Result<Vec<ContractStats>> {
+        self.inject_fault()?;
+
+        let contracts: Vec<_> = contracts
+            .iter()
+            .map(|contract| format!("{:#x}", contract))
+            .collect();
+
+        self.contract_stats
+            .find(doc! { "_id": { "$in": contracts } }, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Stats of every contract.
+    pub(crate) async fn all_contract_stats(&self) -> Result<Vec<ContractStats>> {
+        self.inject_fault()?;
+
+        self.contract_stats
+            .find(None, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Up to `limit` owners holding the most of a contract's tokens across every token id, largest
+    /// first, then by owner. The zero address is left out.
+    pub(crate) async fn largest_contract_holdings(
+        &self,
+        contract_address: H160,
+        limit: i64,
+    ) -> Result<Vec<TopHolder>> {
+        self.inject_fault()?;
+
+        self.contract_holdings
+            .clone_with_type::<TopHolder>()
+            .find(
+                doc! {
+                    "contract_address": format!("{:#x}", contract_address),
+                    "owner": { "$ne": format!("{:#x}", H160::zero()) },
+                    "quantity": { "$gt": 0 },
+                },
+                FindOptions::builder()
+                    .sort(doc! { "quantity": -1, "owner": 1 })
+                    .projection(doc! { "_id": 0, "owner": 1, "quantity": 1, "amount": 1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// The materialized top holders of a contract, `None` until a refresh materialized them.
+    #[cfg(feature = "api")]
+    pub(crate) async fn top_holders(&self, contract_address: H160) -> Result<Option<TopHolders>> {
+        self.inject_fault()?;
+
+        self.top_holders
+            .find_one(doc! { "_id": format!("{:#x}", contract_address) }, None)
+            .await
+    }
+
+    /// The aggregates every materialized top holders were read with, keyed by contract.
+    pub(crate) async fn top_holders_versions(&self) -> Result<HashMap<H160, (i64, f64, i64)>> {
+        self.inject_fault()?;
+
+        self.top_holders
+            .find(
+                None,
+                FindOptions::builder()
+                    .projection(doc! { "holders": { "$slice": 0 } })
+                    .build(),
+            )
+            .await?
+            .map_ok(|top_holders| {
+                (
+                    top_holders.contract_address,
+                    (
+                        top_holders.holder_count,
+                        top_holders.supply,
+                        top_holders.checksum,
+                    ),
+                )
+            })
+            .try_collect()
+            .await
+    }
+
+    pub(crate) async fn replace_top_holders(&self, top_holders: &TopHolders) -> Result<()> {
+        self.inject_fault()?;
+
+        self.top_holders
+            .replace_one(
+                doc! { "_id": format!("{:#x}", top_holders.contract_address) },
+                top_holders,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Drops the materialized top holders of contracts.
+    pub(crate) async fn remove_top_holders(&self, contracts: &[H160]) -> Result<()> {
+        self.inject_fault()?;
+
+        let contracts: Vec<_> = contracts
+            .iter()
+            .map(|contract| format!("{:#x}", contract))
+            .collect();
+
+        self.top_holders
+            .delete_many(doc! { "_id": { "$in": contracts } }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Number of owners holding an ERC1155 token, or the tokens of another contract when
+    /// `token_id` is `None`.
+    pub(crate) async fn token_holder_count(
+        &self,
+        contract_address: H160,
+        token_id: Option<&str>,
+    ) -> Result<i64> {
+        self.inject_fault()?;
+
+        let mut filter = doc! { "contract_address": format!("{:#x}", contract_address) };
+        if let Some(token_id) = token_id {
+            filter.insert("token_id", token_id);
+        }
+
+        Ok(self
+            .token_holder_counts
+            .find_one(filter, None)
+            .await?
+            .map(|count| count.holder_count)
+            .unwrap_or_default())
+    }
+
+    /// The tokens an owner holds, one entry per contract, in no particular order.
+    pub(crate) async fn portfolio(&self, owner: H160) -> Result<Vec<PortfolioEntry>> {
+        self.inject_fault()?;
+
+        self.portfolios
+            .find(doc! { "owner": format!("{:#x}", owner) }, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Removes the balances recorded for a block from the balance history, once it is reverted.
+    pub(crate) async fn remove_balance_history(&self, block_number: U64) -> Result<()> {
+        self.inject_fault()?;
+
+        self.balance_history
+            .delete_many(doc! { "block_number": block_number.as_u64() as i64 }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stats of every contract with a checksum, which contracts without ownerships left have not.
+    #[cfg(feature = "api")]
+    pub(crate) async fn checksummed_contract_stats(&self) -> Result<Vec<ContractStats>> {
+        self.inject_fault()?;
+
+        self.contract_stats
+            .find(
+                doc! { "checksum": { "$exists": true, "$ne": 0_i64 } },
+                FindOptions::builder().sort(doc! { "_id": 1 }).build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Quantity of a token held by an owner, 0 when there is no ownership document. `token_id` is
+    /// `None` for ERC20 tokens.
+    pub(crate) async fn ownership_quantity(
+        &self,
+        contract_address: H160,
+        token_id: Option<&str>,
+        owner: H160,
+    ) -> Result<f64> {
+        Ok(self
+            .ownership(contract_address, token_id, owner)
+            .await?
+            .map(|ownership| ownership.quantity)
+            .unwrap_or(0.0))
+    }
+
+    /// Exact quantity of a token held by an owner, zero when there is no ownership document.
+    pub(crate) async fn ownership_amount(
+        &self,
+        contract_address: H160,
+        token_id: Option<&str>,
+        owner: H160,
+    ) -> Result<Amount> {
+        Ok(self
+            .ownership(contract_address, token_id, owner)
+            .await?
+            .map(|ownership| ownership.exact_amount())
+            .unwrap_or_default())
+    }
+
+    async fn ownership(
+        &self,
+        contract_address: H160,
+        token_id: Option<&str>,
+        owner: H160,
+    ) -> Result<Option<TokenOwnership>> {
+        self.inject_fault()?;
+
+        let mut filter = doc! {
+            "contract_address": format!("{:#x}", contract_address),
+            "owner": format!("{:#x}", owner),
+        };
+
+        if let Some(token_id) = token_id {
+            filter.insert("token_id", token_id);
+        }
+
+        self.token_ownerships
+            .find_one(self.ownership_schema.stored_document(filter), None)
+            .await?
+            .map(|document| self.stored_ownership(document))
+            .transpose()
+    }
+
+    /// Ownerships of a single token id of a contract.
+    pub(crate) async fn token_owners(
+        &self,
+        contract_address: H160,
+        token_id: &str,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.inject_fault()?;
+
+        let filter = doc! {
+            "contract_address": format!("{:#x}", contract_address),
+            "token_id": token_id,
+        };
+
+        self.token_ownerships
+            .find(self.ownership_schema.stored_document(filter), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|document| self.stored_ownership(document))
+            .collect()
+    }
+
+    /// Ownerships of an owner, across every contract or of `contract_address` when set.
+    pub(crate) async fn owner_ownerships(
+        &self,
+        owner: H160,
+        contract_address: Option<H160>,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.inject_fault()?;
+
+        let mut filter = doc! { "owner": format!("{:#x}", owner) };
+        if let Some(contract_address) = contract_address {
+            filter.insert("contract_address", format!("{:#x}", contract_address));
+        }
+
+        self.token_ownerships
+            .find(self.ownership_schema.stored_document(filter), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|document| self.stored_ownership(document))
+            .collect()
+    }
+
+    /// Up to `limit` ownerships of a contract with a positive quantity, ordered by owner and token
+    /// id, following `after`.
+    pub(crate) async fn contract_ownerships_page(
+        &self,
+        contract_address: H160,
+        after: Option<&PageCursor>,
+        limit: i64,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.ownerships_page("contract_address", contract_address, "owner", after, limit)
+            .await
+    }
+
+    /// Up to `limit` ownerships of an owner with a positive quantity, ordered by contract and token
+    /// id, following `after`.
+    pub(crate) async fn owner_ownerships_page(
+        &self,
+        owner: H160,
+        after: Option<&PageCursor>,
+        limit: i64,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.ownerships_page("owner", owner, "contract_address", after, limit)
+            .await
+    }
+
+    /// Keyset pagination, so a page far into a large contract walks the `contract_address, owner,
+    /// token_id` index from where the previous one stopped instead of skipping documents.
+    async fn ownerships_page(
+        &self,
+        key_field: &str,
+        key: H160,
+        order_field: &str,
+        after: Option<&PageCursor>,
+        limit: i64,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.inject_fault()?;
+
+        let schema = &self.ownership_schema;
+        let (order, token_id) = (schema.field(order_field), schema.field("token_id"));
+
+        let mut filter = doc! {
+            schema.field(key_field): format!("{:#x}", key),
+            schema.field("quantity"): { "$gt": 0 },
+        };
+        if let Some(after) = after {
+            let position = format!("{:#x}", after.position);
+
+            // Fungible ownerships have no token id, and no token id compares greater than null, so
+            // the next page starts at the next owner or contract.
+            filter.insert(
+                "$or",
+                vec![
+                    doc! { &order: { "$gt": &position } },
+                    doc! { &order: &position, &token_id: { "$gt": after.token_id.clone() } },
+                ],
+            );
+        }
+
+        self.token_ownerships
+            .find(
+                filter,
+                FindOptions::builder()
+                    .sort(doc! { &order: 1, &token_id: 1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|document| self.stored_ownership(document))
+            .collect()
+    }
+
+    /// Up to `limit` holders of a contract holding at least `min_quantity`, and a positive
+    /// quantity in any case, in the order of `sort`, following `after`.
+    pub(crate) async fn contract_holders_page(
+        &self,
+        contract_address: H160,
+        sort: HolderSort,
+        min_quantity: f64,
+        after: Option<&HolderCursor>,
+        limit: i64,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.inject_fault()?;
+
+        let schema = &self.ownership_schema;
+        let (quantity, owner, token_id) = (
+            schema.field("quantity"),
+            schema.field("owner"),
+            schema.field("token_id"),
+        );
+
+        let quantity_filter = match min_quantity > 0.0 {
+            true => doc! { "$gte": min_quantity },
+            false => doc! { "$gt": 0 },
+        };
+        let mut filter = doc! {
+            schema.field("contract_address"): format!("{:#x}", contract_address),
+            &quantity: quantity_filter,
+        };
+        if let Some(after) = after {
+            let position = format!("{:#x}", after.page.position);
+
+            // As for `ownerships_page`, the next page of fungible ownerships starts at the next
+            // owner, and holders of the same quantity are ordered by owner and token id.
+            let mut clauses = vec![
+                doc! { &owner: { "$gt": &position } },
+                doc! { &owner: &position, &token_id: { "$gt": after.page.token_id.clone() } },
+            ];
+            if let Some(after_quantity) = after.quantity {
+                for clause in &mut clauses {
+                    clause.insert(&quantity, after_quantity);
+                }
+                clauses.insert(0, doc! { &quantity: { "$lt": after_quantity } });
+            }
+            filter.insert("$or", clauses);
+        }
+
+        let sort = match sort {
+            HolderSort::Owner => doc! { &owner: 1, &token_id: 1 },
+            HolderSort::Balance => doc! { &quantity: -1, &owner: 1, &token_id: 1 },
+        };
+
+        self.token_ownerships
+            .find(
+                filter,
+                FindOptions::builder().sort(sort).limit(limit).build(),
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|document| self.stored_ownership(document))
+            .collect()
+    }
+
+    /// Every ownership of a contract.
+    pub(crate) async fn contract_ownerships(
+        &self,
+        contract_address: H160,
+    ) -> Result<BoxStream<'static, Result<TokenOwnership>>> {
+        self.inject_fault()?;
+
+        let store = self.clone();
+        let filter = doc! { "contract_address": format!("{:#x}", contract_address) };
+
+        Ok(self
+            .token_ownerships
+            .find(self.ownership_schema.stored_document(filter), None)
+            .await?
+            .map(move |document| store.stored_ownership(document?))
+            .boxed())
+    }
+
+    /// Recorded transfers of a contract that were not removed, in blocks after `after_block` when
+    /// set and up to `to_block` when set.
+    pub(crate) async fn contract_transfers(
+        &self,
+        contract_address: H160,
+        after_block: Option<U64>,
+        to_block: Option<U64>,
+    ) -> Result<BoxStream<'static, Result<TransferRecord>>> {
+        self.inject_fault()?;
+
+        let mut block_number = Document::new();
+        if let Some(after_block) = after_block {
+            block_number.insert("$gt", after_block.as_u64() as i64);
+        }
+        if let Some(to_block) = to_block {
+            block_number.insert("$lte", to_block.as_u64() as i64);
+        }
+
+        let mut filter = doc! {
+            "contract_address": format!("{:#x}", contract_address),
+            "removed": { "$ne": true },
+        };
+        if !block_number.is_empty() {
+            filter.insert("block_number", block_number);
+        }
+
+        Ok(self.transfers.find(filter, None).await?.boxed())
+    }
+
+    /// Recorded transfers of the block of `block_hash`, removed or not, in the order they happened.
+    #[cfg(feature = "kafka")]
+    pub(crate) async fn block_transfers(&self, block_hash: H256) -> Result<Vec<TransferRecord>> {
+        self.inject_fault()?;
+
+        self.transfers
+            .find(
+                doc! { "block_hash": format!("{:#x}", block_hash) },
+                FindOptions::builder()
+                    .sort(doc! { "log_index": 1, "_id": 1 })
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Recorded transfers of the blocks from `from_block` to `to_block` that were not removed, in the
+    /// order they happened.
+    #[cfg(feature = "clickhouse")]
+    pub(crate) async fn block_range_transfers(
+        &self,
+        from_block: U64,
+        to_block: U64,
+    ) -> Result<BoxStream<'static, Result<TransferRecord>>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .transfers
+            .find(
+                doc! {
+                    "block_number": {
+                        "$gte": from_block.as_u64() as i64,
+                        "$lte": to_block.as_u64() as i64,
+                    },
+                    "removed": { "$ne": true },
+                },
+                FindOptions::builder()
+                    .sort(doc! { "block_number": 1, "log_index": 1, "_id": 1 })
+                    .build(),
+            )
+            .await?
+            .boxed())
+    }
+
+    /// Recorded transfers from or to an owner that were not removed, restricted to `contracts` when
+    /// any are given and up to `to_block` when set, in the order they happened.
+    pub(crate) async fn owner_transfers(
+        &self,
+        owner: H160,
+        contracts: &[H160],
+        to_block: Option<U64>,
+    ) -> Result<BoxStream<'static, Result<TransferRecord>>> {
+        self.inject_fault()?;
+
+        let owner = format!("{:#x}", owner);
+        let mut filter = doc! {
+            "$or": [{ "from": &owner }, { "to": &owner }],
+            "removed": { "$ne": true },
+        };
+        if !contracts.is_empty() {
+            let contracts: Vec<_> = contracts
+                .iter()
+                .map(|contract| format!("{:#x}", contract))
+                .collect();
+            filter.insert("contract_address", doc! { "$in": contracts });
+        }
+        if let Some(to_block) = to_block {
+            filter.insert("block_number", doc! { "$lte": to_block.as_u64() as i64 });
+        }
+
+        Ok(self
+            .transfers
+            .find(
+                filter,
+                FindOptions::builder()
+                    .sort(doc! { "block_number": 1, "log_index": 1, "_id": 1 })
+                    .build(),
+            )
+            .await?
+            .boxed())
+    }
+
+    /// Replaces the approval a change applies to, or removes it when the change revokes it.
+    pub(crate) async fn apply_approval(
+        &self,
+        change: &ApprovalChange,
+        block_number: U64,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        let (filter, approval) = approval_record(change, block_number);
+
+        match approval {
+            Some(approval) => {
+                self.approvals
+                    .replace_one(
+                        filter,
+                        approval,
+                        ReplaceOptions::builder().upsert(true).build(),
+                    )
+                    .await?;
+            }
+            None => {
+                self.approvals.delete_one(filter, None).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Total exact amount of a contract's tokens held by an owner, across every token id.
+    pub(crate) async fn owner_amount(&self, contract_address: H160, owner: H160) -> Result<Amount> {
+        self.inject_fault()?;
+
+        let filter = doc! {
+            "contract_address": format!("{:#x}", contract_address),
+            "owner": format!("{:#x}", owner),
+        };
+
+        self.token_ownerships
+            .find(self.ownership_schema.stored_document(filter), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .try_fold(Amount::default(), |total, document| {
+                Ok(total + self.stored_ownership(document)?.exact_amount())
+            })
+    }
+
+    /// Total quantity of a contract's tokens held by an owner, across every token id.
+    #[cfg(feature = "api")]
+    pub(crate) async fn owner_quantity(&self, contract_address: H160, owner: H160) -> Result<f64> {
+        self.inject_fault()?;
+
+        let filter = doc! {
+            "contract_address": format!("{:#x}", contract_address),
+            "owner": format!("{:#x}", owner),
+        };
+
+        self.token_ownerships
+            .find(self.ownership_schema.stored_document(filter), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .try_fold(0.0, |total, document| {
+                Ok(total + self.stored_ownership(document)?.quantity)
+            })
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) async fn ownerships(&self) -> Result<BoxStream<'static, Result<TokenOwnership>>> {
+        self.inject_fault()?;
+
+        let store = self.clone();
+
+        Ok(self
+            .token_ownerships
+            .find(None, None)
+            .await?
+            .map(move |document| store.stored_ownership(document?))
+            .boxed())
+    }
+
+    /// Opens a change stream on the ownership collection, resuming after `resume_token` when given.
+    /// Updated documents are looked up in full, and with `pre_images` the documents before updates
+    /// and deletes are requested too, which needs pre-images enabled on the collection.
+    ///
+    /// Opened as an aggregation, which the driver iterates like any cursor, waiting up to
+    /// `max_await_time` on the server for each batch of changes.
+    pub(crate) async fn watch_ownerships(
+        &self,
+        resume_token: Option<Document>,
+        pre_images: bool,
+        max_await_time: Duration,
+    ) -> Result<Cursor<Document>> {
+        self.inject_fault()?;
+
+        let mut change_stream = doc! { "fullDocument": "updateLookup" };
+        if pre_images {
+            change_stream.insert("fullDocumentBeforeChange", "whenAvailable");
+        }
+        if let Some(resume_token) = resume_token {
+            change_stream.insert("resumeAfter", resume_token);
+        }
+
+        self.token_ownerships
+            .aggregate(
+                [doc! { "$changeStream": change_stream }],
+                AggregateOptions::builder()
+                    .max_await_time(max_await_time)
+                    .build(),
+            )
+            .await
+    }
+
+    /// Reads an ownership document stored in the layout of the ownership schema.
+    pub(crate) fn stored_ownership(&self, document: Document) -> Result<TokenOwnership> {
+        Ok(mongodb::bson::from_document(
+            self.ownership_schema.canonical_document(document),
+        )?)
+    }
+
+    pub(crate) async fn last_processed_block(&self) -> Result<Option<U64>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .sync_state
+            .find_one(doc! { "_id": LOGS_WORKER_SYNC_STATE_ID }, None)
+            .await?
+            .map(|sync_state| U64::from(sync_state.last_processed_block as u64)))
+    }
+
+    /// `last_processed_block` read in `session`, such as a snapshot the ownership model is read in.
+    #[cfg(feature = "backups")]
+    pub(crate) async fn last_processed_block_with_session(
+        &self,
+        session: &mut ClientSession,
+    ) -> Result<Option<U64>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .sync_state
+            .find_one_with_session(doc! { "_id": LOGS_WORKER_SYNC_STATE_ID }, None, session)
+            .await?
+            .map(|sync_state| U64::from(sync_state.last_processed_block as u64)))
+    }
+
+    pub(crate) async fn set_last_processed_block(&self, block_number: U64) -> Result<()> {
+        self.inject_fault()?;
+
+        self.sync_state
+            .update_one(
+                doc! { "_id": LOGS_WORKER_SYNC_STATE_ID },
+                doc! {
+                    "$set": {
+                        "last_processed_block": block_number.as_u64() as i64,
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Last block an export to another system went through, recorded in `sync_state` under `id`.
+    #[cfg(any(feature = "clickhouse", feature = "kafka"))]
+    pub(crate) async fn export_checkpoint(&self, id: &str) -> Result<Option<U64>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .sync_state
+            .find_one(doc! { "_id": id }, None)
+            .await?
+            .map(|sync_state| U64::from(sync_state.last_processed_block as u64)))
+    }
+
+    #[cfg(any(feature = "clickhouse", feature = "kafka"))]
+    pub(crate) async fn set_export_checkpoint(&self, id: &str, block_number: U64) -> Result<()> {
+        self.inject_fault()?;
+
+        self.sync_state
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": {
+                        "last_processed_block": block_number.as_u64() as i64,
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Version of the data model the database was last migrated to, `None` for databases written
+    /// before versions were recorded and for new ones.
+    pub(crate) async fn schema_version(&self) -> Result<Option<u32>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .sync_state
+            .clone_with_type::<SchemaVersion>()
+            .find_one(doc! { "_id": SCHEMA_VERSION_SYNC_STATE_ID }, None)
+            .await?
+            .map(|schema_version| schema_version.version as u32))
+    }
+
+    pub(crate) async fn set_schema_version(&self, version: u32) -> Result<()> {
+        self.inject_fault()?;
+
+        self.sync_state
+            .update_one(
+                doc! { "_id": SCHEMA_VERSION_SYNC_STATE_ID },
+                doc! {
+                    "$set": {
+                        "version": version as i64,
+                        "migrated_at": DateTime::now(),
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Whether the worker wrote anything yet, a checkpoint or an ownership.
+    pub(crate) async fn is_empty(&self) -> Result<bool> {
+        Ok(self.last_processed_block().await?.is_none()
+            && self.token_ownerships.find_one(None, None).await?.is_none())
+    }
+
+    /// The collections of the ownership model, by their name without the collection prefix: the
+    /// classifications, ownerships, supplies, aggregates, portfolios and checkpoint.
+    #[cfg(feature = "backups")]
+    pub(crate) fn ownership_model_collections(&self) -> Vec<(String, Collection<Document>)> {
+        [
+            self.contract_addresses.clone_with_type(),
+            self.token_ownerships.clone_with_type(),
+            self.token_supplies.clone_with_type(),
+            self.contract_holdings.clone_with_type(),
+            self.contract_stats.clone_with_type(),
+            self.token_holder_counts.clone_with_type(),
+            self.portfolios.clone_with_type(),
+            self.sync_state.clone_with_type(),
+        ]
+        .into_iter()
+        .map(|collection| {
+            let name = collection.name();
+            let name = name.strip_prefix(&self.collection_prefix).unwrap_or(name);
+            (name.to_string(), collection)
+        })
+        .collect()
+    }
+
+    /// Cursor over every document of a collection read in `session`, in the order of their `_id`.
+    #[cfg(feature = "backups")]
+    pub(crate) async fn documents_with_session(
+        &self,
+        collection: &Collection<Document>,
+        session: &mut ClientSession,
+    ) -> Result<SessionCursor<Document>> {
+        self.inject_fault()?;
+
+        collection
+            .find_with_session(
+                None,
+                FindOptions::builder().sort(doc! { "_id": 1 }).build(),
+                session,
+            )
+            .await
+    }
+
+    /// Writes the exact amount of the ownerships, archived ownerships, holdings and supplies
+    /// stored before exact amounts were, rounded from their float quantity like reads round it.
+    /// Ownerships are left as they are in compatibility mode. Returns the number of documents
+    /// updated.
+    pub(crate) async fn backfill_exact_amounts(&self) -> Result<u64> {
+        self.inject_fault()?;
+
+        let schema = &self.ownership_schema;
+        let mut collections = vec![
+            (
+                self.contract_holdings.clone_with_type(),
+                "amount".to_string(),
+                "quantity".to_string(),
+            ),
+            (
+                self.token_supplies.clone_with_type(),
+                "amount".to_string(),
+                "supply".to_string(),
+            ),
+        ];
+        if !schema.is_compatibility() {
+            for ownerships in [&self.token_ownerships, &self.archived_ownerships] {
+                collections.push((
+                    ownerships.clone(),
+                    schema.field("amount"),
+                    schema.field("quantity"),
+                ));
+            }
+        }
+
+        let mut updated = 0;
+
+        for (collection, amount_field, quantity_field) in collections {
+            let missing = doc! { &amount_field: { "$exists": false } };
+            let mut documents = collection.find(missing.clone(), None).await?;
+
+            while let Some(document) = documents.try_next().await? {
+                let mut filter = missing.clone();
+                filter.insert("_id", document.get("_id").cloned().unwrap_or(Bson::Null));
+
+                let amount = stored_amount(&document, &amount_field, &quantity_field);
+                let result = collection
+                    .update_one(
+                        filter,
+                        doc! { "$set": { &amount_field: amount.to_string() } },
+                        None,
+                    )
+                    .await?;
+                updated += result.modified_count;
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Counts the holders of every token again, ERC1155 tokens from their ownerships and the
+    /// tokens of other contracts from the holder counts of `contract_stats`. ERC1155 tokens are
+    /// left out in compatibility mode, whose ownerships may lack exact amounts. Returns the number
+    /// of holder counts written.
+    pub(crate) async fn rebuild_token_holder_counts(&self) -> Result<u64> {
+        self.inject_fault()?;
+
+        self.token_holder_counts.delete_many(doc! {}, None).await?;
+
+        let mut counts = Vec::new();
+        let mut stats = self.contract_stats.find(None, None).await?;
+
+        while let Some(stats) = stats.try_next().await? {
+            let token_type = match self
+                .contract_addresses
+                .find_one(
+                    doc! { "address": format!("{:#x}", stats.contract_address) },
+                    None,
+                )
+                .await?
+            {
+                Some(contract) => contract.token_type,
+                None => continue,
+            };
+
+            if token_type == "ERC1155" {
+                if self.ownership_schema.is_compatibility() {
+                    continue;
+                }
+
+                let mut holders: Vec<(String, i64)> = Vec::new();
+                let mut ownerships = self
+                    .token_ownerships
+                    .find(
+                        self.ownership_schema.stored_document(doc! {
+                            "contract_address": format!("{:#x}", stats.contract_address),
+                        }),
+                        None,
+                    )
+                    .await?;
+
+                while let Some(document) = ownerships.try_next().await? {
+                    let ownership = self.stored_ownership(document)?;
+                    let token_id = match ownership.token_id {
+                        Some(token_id) if ownership.exact_amount().is_positive() => token_id,
+                        _ => continue,
+                    };
+
+                    match holders.iter_mut().find(|(counted, _)| *counted == token_id) {
+                        Some((_, holder_count)) => *holder_count += 1,
+                        None => holders.push((token_id, 1)),
+                    }
+                }
+
+                counts.extend(holders.into_iter().map(|(token_id, holder_count)| {
+                    TokenHolderCount {
+                        contract_address: stats.contract_address,
+                        token_type: token_type.clone(),
+                        token_id: Some(token_id),
+                        holder_count,
+                    }
+                }));
+            } else if stats.holder_count != 0 {
+                counts.push(TokenHolderCount {
+                    contract_address: stats.contract_address,
+                    token_type,
+                    token_id: None,
+                    holder_count: stats.holder_count,
+                });
+            }
+        }
+
+        for chunk in counts.chunks(WRITE_BATCH_SIZE) {
+            self.token_holder_counts.insert_many(chunk, None).await?;
+        }
+
+        Ok(counts.len() as u64)
+    }
+
+    /// Builds the portfolio of every owner again from the ownerships with a positive amount, one
+    /// contract at a time. Does nothing in compatibility mode, whose ownerships may lack exact
+    /// amounts. Returns the number of portfolio entries written.
+    pub(crate) async fn rebuild_portfolios(&self) -> Result<u64> {
+        self.inject_fault()?;
+
+        if self.ownership_schema.is_compatibility() {
+            return Ok(0);
+        }
+
+        self.portfolios.delete_many(doc! {}, None).await?;
+
+        let mut written = 0;
+        let mut stats = self.contract_stats.find(None, None).await?;
+
+        while let Some(stats) = stats.try_next().await? {
+            let token_type = match self
+                .contract_addresses
+                .find_one(
+                    doc! { "address": format!("{:#x}", stats.contract_address) },
+                    None,
+                )
+                .await?
+            {
+                Some(contract) => contract.token_type,
+                None => continue,
+            };
+
+            let mut entries: HashMap<H160, PortfolioEntry> = HashMap::new();
+            let mut ownerships = self
+                .token_ownerships
+                .find(
+                    self.ownership_schema.stored_document(doc! {
+                        "contract_address": format!("{:#x}", stats.contract_address),
+                    }),
+                    None,
+                )
+                .await?;
+
+            while let Some(document) = ownerships.try_next().await? {
+                let ownership = self.stored_ownership(document)?;
+                if !ownership.exact_amount().is_positive() {
+                    continue;
+                }
+
+                let entry = entries
+                    .entry(ownership.owner)
+                    .or_insert_with(|| PortfolioEntry {
+                        owner: ownership.owner,
+                        contract_address: stats.contract_address,
+                        token_type: token_type.clone(),
+                        token_ids: Vec::new(),
+                        token_count: 0,
+                    });
+                entry.token_ids.extend(ownership.token_id);
+                entry.token_count += 1;
+            }
+
+            let entries: Vec<_> = entries.into_values().collect();
+            for chunk in entries.chunks(WRITE_BATCH_SIZE) {
+                self.portfolios.insert_many(chunk, None).await?;
+            }
+            written += entries.len() as u64;
+        }
+
+        Ok(written)
+    }
+
+    /// Recomputes the checksum of every contract in `contract_stats` from its ownerships, covering
+    /// the ownerships stored before checksums were maintained. Does nothing in compatibility mode.
+    /// Returns the number of checksums changed.
+    pub(crate) async fn recompute_checksums(&self) -> Result<u64> {
+        self.inject_fault()?;
+
+        if self.ownership_schema.is_compatibility() {
+            return Ok(0);
+        }
+
+        let mut checksums: HashMap<H160, i64> = HashMap::new();
+        let mut ownerships = self.token_ownerships.find(None, None).await?;
+
+        while let Some(document) = ownerships.try_next().await? {
+            let ownership = self.stored_ownership(document)?;
+
+            *checksums.entry(ownership.contract_address).or_default() ^= checksum_change(
+                ownership.contract_address,
+                ownership.token_id.as_deref(),
+                ownership.owner,
+                Amount::zero(),
+                ownership.exact_amount(),
+            );
+        }
+
+        let mut updated = 0;
+        let mut stats = self.contract_stats.find(None, None).await?;
+
+        while let Some(stats) = stats.try_next().await? {
+            let checksum = checksums
+                .get(&stats.contract_address)
+                .copied()
+                .unwrap_or_default();
+
+            if stats.checksum != checksum {
+                self.contract_stats
+                    .update_one(
+                        doc! { "_id": format!("{:#x}", stats.contract_address) },
+                        doc! { "$set": { "checksum": checksum } },
+                        None,
+                    )
+                    .await?;
+                updated += 1;
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Applies the deltas of a block, records it as applied when it has a hash and moves the
+    /// checkpoint to it in one transaction, so a crash leaves either all or none of them. The block
+    /// is left as it is when another writer applied it already.
+    ///
+    /// Transactions failing on a transient error, such as a write conflict with another writer or
+    /// an election, are retried.
+    pub(crate) async fn apply_block_in_transaction(
+        &self,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &[OwnershipDelta],
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        self.in_transaction(block_number, BlockWrite::Apply { block_hash, deltas })
+            .await
+    }
+
+    /// Reverts the inverse deltas of an orphaned block, removes its applied block record and its
+    /// journal entry and moves the checkpoint back before it in one transaction, so a crash leaves
+    /// either all or none of them. The deltas are left as they are when the block is no longer
+    /// recorded as applied, having been reverted already.
+    pub(crate) async fn revert_block_in_transaction(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+        inverse_deltas: &[OwnershipDelta],
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        self.in_transaction(
+            block_number,
+            BlockWrite::Revert {
+                block_hash,
+                inverse_deltas,
+            },
+        )
+        .await?;
+
+        self.forget_applied_logs(block_hash).await
+    }
+
+    /// Runs the writes of a block in a transaction, retrying them when the transaction fails on a
+    /// transient error.
+    async fn in_transaction(&self, block_number: U64, write: BlockWrite<'_>) -> Result<()> {
+        let client = self
+            .transaction_client
+            .as_ref()
+            .expect("Blocks are only written in transactions with a transaction client");
+        let mut session = client.start_session(None).await?;
+
+        loop {
+            session.start_transaction(None).await?;
+
+            let result = match write {
+                BlockWrite::Apply { block_hash, deltas } => {
+                    self.write_block_in_session(block_number, block_hash, deltas, &mut session)
+                        .await
+                }
+                BlockWrite::Revert {
+                    block_hash,
+                    inverse_deltas,
+                } => {
+                    self.write_revert_in_session(
+                        block_number,
+                        block_hash,
+                        inverse_deltas,
+                        &mut session,
+                    )
+                    .await
+                }
+            };
+
+            if let Err(error) = result {
+                // Aborting is best effort, the server aborts the transaction once it times out.
+                let _ = session.abort_transaction().await;
+
+                if error.contains_label(TRANSIENT_TRANSACTION_ERROR) || is_duplicate_key(&error) {
+                    eprintln!(
+                        "Error: Transaction of block {} failed, retrying... {}",
+                        block_number, error
+                    );
+                    continue;
+                }
+
+                return Err(error);
+            }
+
+            loop {
+                match session.commit_transaction().await {
+                    Ok(()) => return Ok(()),
+                    Err(error) if error.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) => {
+                        continue
+                    }
+                    Err(error) if error.contains_label(TRANSIENT_TRANSACTION_ERROR) => {
+                        eprintln!(
+                            "Error: Commit of the transaction of block {} failed, retrying... {}",
+                            block_number, error
+                        );
+                        break;
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+    }
+
+    /// The writes of `apply_block_in_transaction`, within the transaction of `session`.
+    async fn write_block_in_session(
+        &self,
+        block_number: U64,
+        block_hash: Option<H256>,
+        deltas: &[OwnershipDelta],
+        session: &mut ClientSession,
+    ) -> Result<()> {
+        let applied = match block_hash {
+            Some(block_hash) => self
+                .applied_blocks
+                .find_one_with_session(doc! { "_id": format!("{:#x}", block_hash) }, None, session)
+                .await?
+                .is_some(),
+            None => false,
+        };
+
+        if !applied {
+            self.write_delta_batch(
+                &mut DeltaBatch::new(deltas).at_block(block_number),
+                Some(&mut *session),
+            )
+            .await?;
+
+            if let Some(block_hash) = block_hash {
+                self.applied_blocks
+                    .insert_one_with_session(
+                        AppliedBlock {
+                            block_hash,
+                            block_number: block_number.as_u64() as i64,
+                            applied_at: DateTime::now(),
+                        },
+                        None,
+                        session,
+                    )
+                    .await?;
+            }
+        }
+
+        self.sync_state
+            .update_one_with_session(
+                doc! { "_id": LOGS_WORKER_SYNC_STATE_ID },
+                doc! {
+                    "$set": {
+                        "last_processed_block": block_number.as_u64() as i64,
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+                session,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The writes of `revert_block_in_transaction`, within the transaction of `session`.
+    async fn write_revert_in_session(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+        inverse_deltas: &[OwnershipDelta],
+        session: &mut ClientSession,
+    ) -> Result<()> {
+        let unmarked = self
+            .applied_blocks
+            .delete_one_with_session(doc! { "_id": format!("{:#x}", block_hash) }, None, session)
+            .await?;
+
+        if unmarked.deleted_count > 0 {
+            self.write_delta_batch(&mut DeltaBatch::new(inverse_deltas), Some(&mut *session))
+                .await?;
+        }
+
+        self.block_journal
+            .delete_one_with_session(doc! { "_id": block_number.as_u64() as i64 }, None, session)
+            .await?;
+
+        self.sync_state
+            .update_one_with_session(
+                doc! { "_id": LOGS_WORKER_SYNC_STATE_ID },
+                doc! {
+                    "$set": {
+                        "last_processed_block": (block_number.as_u64() - 1) as i64,
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+                session,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records that the blocks from `from_block` to `to_block` were processed, extending the
+    /// segment of the same kind and contracts they overlap or follow, if any.
+    pub(crate) async fn record_coverage(
+        &self,
+        kind: CoverageKind,
+        from_block: U64,
+        to_block: U64,
+        contracts: &[H160],
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        let mut contracts = contracts.to_vec();
+        contracts.sort();
+        let contract_addresses: Vec<_> = contracts
+            .iter()
+            .map(|contract| format!("{:#x}", contract))
+            .collect();
+
+        let result = self
+            .block_coverage
+            .update_one(
+                doc! {
+                    "kind": mongodb::bson::to_bson(&kind)?,
+                    "contracts": &contract_addresses,
+                    "from_block": { "$lte": from_block.as_u64() as i64 },
+                    "to_block": { "$gte": from_block.as_u64() as i64 - 1 },
+                },
+                doc! {
+                    "$max": { "to_block": to_block.as_u64() as i64 },
+                    "$set": { "updated_at": DateTime::now() },
+                },
+                None,
+            )
+            .await?;
+
+        if result.matched_count == 0 {
+            self.block_coverage
+                .insert_one(
+                    CoverageSegment {
+                        id: ObjectId::new(),
+                        kind,
+                        from_block: from_block.as_u64() as i64,
+                        to_block: to_block.as_u64() as i64,
+                        contracts,
+                        orphaned_blocks: Vec::new(),
+                        recorded_at: DateTime::now(),
+                        updated_at: DateTime::now(),
+                    },
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a block rolled back after a reorg, by the hash it was orphaned with.
+    pub(crate) async fn record_reorg_coverage(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        self.block_coverage
+            .insert_one(
+                CoverageSegment {
+                    id: ObjectId::new(),
+                    kind: CoverageKind::Reorg,
+                    from_block: block_number.as_u64() as i64,
+                    to_block: block_number.as_u64() as i64,
+                    contracts: Vec::new(),
+                    orphaned_blocks: vec![block_hash],
+                    recorded_at: DateTime::now(),
+                    updated_at: DateTime::now(),
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Every coverage segment, by first block.
+    pub(crate) async fn coverage_segments(&self) -> Result<Vec<CoverageSegment>> {
+        self.inject_fault()?;
+
+        self.block_coverage
+            .find(
+                None,
+                FindOptions::builder()
+                    .sort(doc! { "from_block": 1, "to_block": 1 })
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Records a log that could not be decoded, or the error of another attempt to decode it.
+    pub(crate) async fn record_dead_letter(&self, log: &Log, error: &str) -> Result<()> {
+        self.inject_fault()?;
+
+        let block_hash = log.block_hash.unwrap_or_default();
+        let log_index = log.log_index.unwrap_or_default();
+        let now = DateTime::now();
+
+        self.dead_letters
+            .update_one(
+                doc! { "_id": format!("{:#x}:{}", block_hash, log_index) },
+                doc! {
+                    "$set": {
+                        "block_number": log.block_number.unwrap_or_default().as_u64() as i64,
+                        "block_hash": format!("{:#x}", block_hash),
+                        "transaction_hash": format!("{:#x}", log.transaction_hash.unwrap_or_default()),
+                        "log_index": mongodb::bson::to_bson(&log_index)?,
+                        "contract_address": format!("{:#x}", log.address),
+                        "log": mongodb::bson::to_bson(log)?,
+                        "error": error,
+                        "updated_at": now,
+                    },
+                    "$setOnInsert": { "recorded_at": now },
+                    "$inc": { "attempts": 1 },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Dead letters of the blocks up to `to_block`, in the order their logs were emitted.
+    pub(crate) async fn dead_letters(&self, to_block: U64) -> Result<Vec<DeadLetter>> {
+        self.inject_fault()?;
+
+        let mut dead_letters: Vec<_> = self
+            .dead_letters
+            .find(
+                doc! { "block_number": { "$lte": to_block.as_u64() as i64 } },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?;
+
+        // Log indexes are stored as hex strings, which do not sort by value.
+        dead_letters.sort_by_key(|dead_letter: &DeadLetter| {
+            (dead_letter.block_number, dead_letter.log_index)
+        });
+
+        Ok(dead_letters)
+    }
+
+    pub(crate) async fn remove_dead_letter(&self, id: &str) -> Result<()> {
+        self.inject_fault()?;
+
+        self.dead_letters
+            .delete_one(doc! { "_id": id }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Number of dead letters of each contract.
+    #[cfg(feature = "api")]
+    pub(crate) async fn dead_letter_counts(&self) -> Result<HashMap<H160, i64>> {
+        self.inject_fault()?;
+
+        count_per_contract(&self.dead_letters, doc! {}, "contract_address", false).await
+    }
+
+    /// Dead letters of a contract, most recent first.
+    #[cfg(feature = "api")]
+    pub(crate) async fn contract_dead_letters(
+        &self,
+        contract_address: H160,
+        limit: i64,
+    ) -> Result<Vec<DeadLetter>> {
+        self.inject_fault()?;
+
+        self.dead_letters
+            .find(
+                doc! { "contract_address": format!("{:#x}", contract_address) },
+                FindOptions::builder()
+                    .sort(doc! { "block_number": -1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// The first and the last block marked as applied since `since`, with the time they were
+    /// applied, `None` when no block was.
+    pub(crate) async fn applied_blocks_since(
+        &self,
+        since: DateTime,
+    ) -> Result<Option<((U64, DateTime), (U64, DateTime))>> {
+        self.inject_fault()?;
+
+        let filter = doc! { "applied_at": { "$gte": since } };
+        let applied = |order: i32| {
+            self.applied_blocks.find_one(
+                filter.clone(),
+                FindOneOptions::builder()
+                    .sort(doc! { "applied_at": order })
+                    .build(),
+            )
+        };
+
+        let (first, last) = futures::try_join!(applied(1), applied(-1))?;
+
+        Ok(first.zip(last).map(|(first, last)| {
+            (
+                (U64::from(first.block_number as u64), first.applied_at),
+                (U64::from(last.block_number as u64), last.applied_at),
+            )
+        }))
+    }
+
+    /// The estimated number of documents of every collection of the worker, from the collection
+    /// metadata rather than a scan.
+    pub(crate) async fn collection_counts(&self) -> Result<Vec<(String, u64)>> {
+        self.inject_fault()?;
+
+        let names = [
+            self.contract_addresses.name(),
+            self.token_ownerships.name(),
+            self.archived_ownerships.name(),
+            self.contract_holdings.name(),
+            self.contract_stats.name(),
+            self.top_holders.name(),
+            self.token_holder_counts.name(),
+            self.token_supplies.name(),
+            self.portfolios.name(),
+            self.balance_history.name(),
+            self.approvals.name(),
+            self.transfers.name(),
+            self.raw_logs.name(),
+            self.token_metadata.name(),
+            self.reconciliation_queue.name(),
+            self.sync_state.name(),
+            self.block_journal.name(),
+            self.delta_feed.name(),
+            self.applied_blocks.name(),
+            self.applied_logs.name(),
+            self.block_coverage.name(),
+            self.block_jobs.name(),
+            self.dead_letters.name(),
+            self.webhook_rules.name(),
+            self.leases.name(),
+            self.quorum_mismatches.name(),
+            self.operator_actions.name(),
+            self.api_keys.name(),
+        ];
+
+        futures::future::try_join_all(names.into_iter().map(|name| async move {
+            let count = self
+                .database
+                .collection::<Document>(name)
+                .estimated_document_count(None)
+                .await?;
+
+            Ok((name.to_string(), count))
+        }))
+        .await
+    }
+
+    /// Whether the deltas of the block with this hash were already fully applied.
+    pub(crate) async fn is_block_applied(&self, block_hash: H256) -> Result<bool> {
+        self.inject_fault()?;
+
+        Ok(self
+            .applied_blocks
+            .find_one(doc! { "_id": format!("{:#x}", block_hash) }, None)
+            .await?
+            .is_some())
+    }
+
+    /// Records that the deltas of a block were fully applied. Returns `false` when the block had
+    /// already been recorded, meaning another writer applied it as well.
+    pub(crate) async fn mark_block_applied(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+    ) -> Result<bool> {
+        self.inject_fault()?;
+
+        let result = self
+            .applied_blocks
+            .insert_one(
+                AppliedBlock {
+                    block_hash,
+                    block_number: block_number.as_u64() as i64,
+                    applied_at: DateTime::now(),
+                },
+                None,
+            )
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(error) if is_duplicate_key(&error) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Forgets that an orphaned block was applied, so it is applied again should it become
+    /// canonical again.
+    pub(crate) async fn unmark_block_applied(&self, block_hash: H256) -> Result<()> {
+        self.inject_fault()?;
+
+        self.applied_blocks
+            .delete_one(doc! { "_id": format!("{:#x}", block_hash) }, None)
+            .await?;
+        self.forget_applied_logs(block_hash).await?;
+
+        Ok(())
+    }
+
+    /// The logs of the block with this hash whose deltas were not applied yet.
+    pub(crate) async fn unapplied_logs<'a>(
+        &self,
+        block_hash: H256,
+        logs: &'a [JournaledLog],
+    ) -> Result<Vec<&'a JournaledLog>> {
+        self.inject_fault()?;
+
+        let applied: Vec<_> = self
+            .applied_logs
+            .find(doc! { "block_hash": format!("{:#x}", block_hash) }, None)
+            .await?
+            .map_ok(|applied_log| applied_log.id)
+            .try_collect()
+            .await?;
+
+        Ok(logs
+            .iter()
+            .filter(|log| !applied.contains(&applied_log_id(block_hash, log)))
+            .collect())
+    }
+
+    /// Records that the deltas of logs were applied. Recording a log twice is not an error.
+    pub(crate) async fn mark_logs_applied(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+        logs: &[&JournaledLog],
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        if logs.is_empty() {
+            return Ok(());
+        }
+
+        let applied_logs = logs.iter().map(|log| AppliedLog {
+            id: applied_log_id(block_hash, log),
+            block_hash,
+            block_number: block_number.as_u64() as i64,
+            applied_at: DateTime::now(),
+        });
+
+        match self
+            .applied_logs
+            .insert_many(
+                applied_logs,
+                InsertManyOptions::builder().ordered(false).build(),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) if is_duplicate_key(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Drops the markers of the logs of a block, once the block is marked as applied as a whole or
+    /// orphaned.
+    pub(crate) async fn forget_applied_logs(&self, block_hash: H256) -> Result<()> {
+        self.inject_fault()?;
+
+        self.applied_logs
+            .delete_many(doc! { "block_hash": format!("{:#x}", block_hash) }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Appends transfers to the transfer history. Transfers recorded before are left as they are,
+    /// so a block written again never duplicates its transfers.
+    pub(crate) async fn append_transfers(&self, transfers: &[TransferRecord]) -> Result<()> {
+        self.inject_fault()?;
+
+        if transfers.is_empty() {
+            return Ok(());
+        }
+
+        match self
+            .transfers
+            .insert_many(
+                transfers,
+                InsertManyOptions::builder().ordered(false).build(),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) if is_duplicate_key(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Flags the transfers of a log the provider reported as removed.
+    pub(crate) async fn mark_log_transfers_removed(
+        &self,
+        transaction_hash: H256,
+        log_index: U256,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        self.transfers
+            .update_many(
+                doc! {
+                    "transaction_hash": format!("{:#x}", transaction_hash),
+                    "log_index": log_index.as_u64() as i64,
+                },
+                doc! { "$set": { "removed": true } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Creates the `raw_logs` collection compressed with zstd, which compresses log data far better
+    /// than the default snappy. A collection that exists already is left as it is.
+    pub(crate) async fn create_raw_log_archive(&self) -> Result<()> {
+        self.inject_fault()?;
+
+        let result = self
+            .database
+            .create_collection(
+                self.raw_logs.name(),
+                CreateCollectionOptions::builder()
+                    .storage_engine(doc! {
+                        "wiredTiger": { "configString": "block_compressor=zstd" }
+                    })
+                    .build(),
+            )
+            .await;
+
+        match result {
+            Ok(()) => Ok(()),
+            // NamespaceExists
+            Err(error) if matches!(&*error.kind, ErrorKind::Command(error) if error.code == 48) => {
+                Ok(())
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Archives logs in `raw_logs`, flagging the archived logs the provider reports as removed.
+    /// Logs archived before are kept as they are.
+    pub(crate) async fn archive_raw_logs(&self, logs: &[Log]) -> Result<()> {
+        self.inject_fault()?;
+
+        let (removed, raw_logs): (Vec<_>, Vec<_>) = logs
+            .iter()
+            .filter_map(RawLog::new)
+            .partition(|raw_log| raw_log.removed);
+
+        if !raw_logs.is_empty() {
+            match self
+                .raw_logs
+                .insert_many(
+                    &raw_logs,
+                    InsertManyOptions::builder().ordered(false).build(),
+                )
+                .await
+            {
+                Ok(_) => {}
+                Err(error) if is_duplicate_key(&error) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        if !removed.is_empty() {
+            let ids: Vec<_> = removed.into_iter().map(|raw_log| raw_log.id).collect();
+            self.raw_logs
+                .update_many(
+                    doc! { "_id": { "$in": ids } },
+                    doc! { "$set": { "removed": true } },
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Flags the archived logs of an orphaned block.
+    pub(crate) async fn mark_block_raw_logs_removed(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        self.raw_logs
+            .update_many(
+                doc! {
+                    "block_number": block_number.as_u64() as i64,
+                    "block_hash": format!("{:#x}", block_hash),
+                },
+                doc! { "$set": { "removed": true } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The archived logs of the blocks from `from_block` to `to_block`, ordered by block and log
+    /// index, including the removed ones.
+    pub(crate) async fn raw_logs(&self, from_block: U64, to_block: U64) -> Result<Vec<RawLog>> {
+        self.inject_fault()?;
+
+        self.raw_logs
+            .find(
+                doc! {
+                    "block_number": {
+                        "$gte": from_block.as_u64() as i64,
+                        "$lte": to_block.as_u64() as i64,
+                    }
+                },
+                FindOptions::builder()
+                    .sort(doc! { "block_number": 1, "log_index": 1 })
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Flags the transfers of an orphaned block.
+    pub(crate) async fn mark_block_transfers_removed(&self, block_hash: H256) -> Result<()> {
+        self.inject_fault()?;
+
+        self.transfers
+            .update_many(
+                doc! { "block_hash": format!("{:#x}", block_hash) },
+                doc! { "$set": { "removed": true } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Queues balances for reconciliation. Balances already queued keep their entry.
+    pub(crate) async fn queue_reconciliations(
+        &self,
+        entries: &[ReconciliationEntry],
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        match self
+            .reconciliation_queue
+            .insert_many(entries, InsertManyOptions::builder().ordered(false).build())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) if is_duplicate_key(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Oldest queued balances first.
+    pub(crate) async fn reconciliation_queue(
+        &self,
+        limit: i64,
+    ) -> Result<Vec<ReconciliationEntry>> {
+        self.inject_fault()?;
+
+        self.reconciliation_queue
+            .find(
+                None,
+                FindOptions::builder()
+                    .sort(doc! { "queued_at_block": 1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub(crate) async fn remove_reconciliations(&self, ids: &[String]) -> Result<()> {
+        self.inject_fault()?;
+
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        self.reconciliation_queue
+            .delete_many(doc! { "_id": { "$in": ids } }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Queues metadata to fetch. Metadata queued or fetched before is left as it is.
+    pub(crate) async fn queue_token_metadata(&self, metadata: &[TokenMetadata]) -> Result<()> {
+        self.inject_fault()?;
+
+        if metadata.is_empty() {
+            return Ok(());
+        }
+
+        match self
+            .token_metadata
+            .insert_many(
+                metadata,
+                InsertManyOptions::builder().ordered(false).build(),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) if is_duplicate_key(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Pending metadata whose next attempt is due, contracts first.
+    pub(crate) async fn due_token_metadata(&self, limit: i64) -> Result<Vec<TokenMetadata>> {
+        self.inject_fault()?;
+
+        self.token_metadata
+            .find(
+                doc! {
+                    "status": "pending",
+                    "$or": [
+                        { "next_attempt_at": null },
+                        { "next_attempt_at": { "$lte": DateTime::now() } },
+                    ],
+                },
+                FindOptions::builder()
+                    .sort(doc! { "token_id": 1, "next_attempt_at": 1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Token metadata with pins the pinning service has not settled yet.
+    #[cfg(feature = "ipfs")]
+    pub(crate) async fn unsettled_pins(&self, limit: i64) -> Result<Vec<TokenMetadata>> {
+        self.inject_fault()?;
+
+        self.token_metadata
+            .find(
+                doc! { "pins.status": { "$in": ["queued", "pinning"] } },
+                FindOptions::builder().limit(limit).build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    #[cfg(feature = "ipfs")]
+    pub(crate) async fn set_pins(&self, id: &str, pins: &[Pin]) -> Result<()> {
+        self.inject_fault()?;
+
+        self.token_metadata
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "pins": mongodb::bson::to_bson(pins)? } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Metadata of a contract, or of a single token with `id` `contract_address:token_id`.
+    #[cfg(feature = "api")]
+    pub(crate) async fn token_metadata(&self, id: &str) -> Result<Option<TokenMetadata>> {
+        self.inject_fault()?;
+
+        self.token_metadata.find_one(doc! { "_id": id }, None).await
+    }
+
+    pub(crate) async fn save_token_metadata(&self, metadata: &TokenMetadata) -> Result<()> {
+        self.inject_fault()?;
+
+        self.token_metadata
+            .replace_one(doc! { "_id": &metadata.id }, metadata, None)
+            .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn journal_entry(&self, block_number: U64) -> Result<Option<JournalEntry>> {
+        self.inject_fault()?;
+
+        self.block_journal
+            .find_one(doc! { "_id": block_number.as_u64() as i64 }, None)
+            .await
+    }
+
+    /// Deltas applied for a journaled log, or `None` when the log is not in the journal or was
+    /// already reverted after being reported as removed.
+    pub(crate) async fn journaled_log_deltas(
+        &self,
+        transaction_hash: H256,
+        log_index: U256,
+    ) -> Result<Option<Vec<OwnershipDelta>>> {
+        self.inject_fault()?;
+
+        let transaction_hash = format!("{:#x}", transaction_hash);
+        let log_index = mongodb::bson::to_bson(&log_index)?;
+
+        let entries: Vec<JournalEntry> = self
+            .block_journal
+            .find(
+                doc! {
+                    "logs": {
+                        "$elemMatch": {
+                            "transaction_hash": &transaction_hash,
+                            "log_index": &log_index,
+                        }
+                    }
+                },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut applied_deltas = None;
+
+        for log in entries.into_iter().flat_map(|entry| entry.logs) {
+            if format!("{:#x}", log.transaction_hash) != transaction_hash
+                || mongodb::bson::to_bson(&log.log_index)? != log_index
+            {
+                continue;
+            }
+
+            if log.removed {
+                return Ok(None);
+            }
+
+            applied_deltas = Some(log.deltas);
+        }
+
+        Ok(applied_deltas)
+    }
+
+    /// Stores the journal entry of a block, replacing any entry left behind for the same height.
+    pub(crate) async fn push_journal_entry(&self, entry: &JournalEntry) -> Result<()> {
+        self.inject_fault()?;
+
+        self.block_journal
+            .delete_one(doc! { "_id": entry.block_number }, None)
+            .await?;
+        self.block_journal.insert_one(entry, None).await?;
+
+        Ok(())
+    }
+
+    /// Overwrites the journaled hash of a block, making it look orphaned to the next reorg check.
+    #[cfg(feature = "chaos")]
+    pub(crate) async fn set_journal_block_hash(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+    ) -> Result<()> {
+        self.block_journal
+            .update_one(
+                doc! { "_id": block_number.as_u64() as i64 },
+                doc! { "$set": { "block_hash": format!("{:#x}", block_hash) } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Adds the deltas of a log to the journal entry of its block, when the block is still
+    /// journaled with this hash, so a rollback reverts them along with the block.
+    pub(crate) async fn journal_log(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+        log: &JournaledLog,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        self.block_journal
+            .update_one(
+                doc! {
+                    "_id": block_number.as_u64() as i64,
+                    "block_hash": format!("{:#x}", block_hash),
+                },
+                doc! {
+                    "$push": {
+                        "deltas": { "$each": mongodb::bson::to_bson(&log.deltas)? },
+                        "logs": mongodb::bson::to_bson(log)?,
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn remove_journal_entry(&self, block_number: U64) -> Result<()> {
+        self.inject_fault()?;
+
+        self.block_journal
+            .delete_one(doc! { "_id": block_number.as_u64() as i64 }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Drops the journal entries of blocks below `block_number`.
+    pub(crate) async fn prune_journal(&self, block_number: U64) -> Result<()> {
+        self.inject_fault()?;
+
+        self.block_journal
+            .delete_many(
+                doc! { "_id": { "$lt": block_number.as_u64() as i64 } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sequence of the newest delta feed entry, or 0 when the feed is empty.
+    pub(crate) async fn latest_feed_sequence(&self) -> Result<i64> {
+        self.inject_fault()?;
+
+        Ok(self
+            .delta_feed
+            .find_one(
+                None,
+                FindOneOptions::builder().sort(doc! { "_id": -1 }).build(),
+            )
+            .await?
+            .map(|entry| entry.sequence)
+            .unwrap_or(0))
+    }
+
+    /// Sequence of the oldest delta feed entry still retained.
+    #[cfg(any(feature = "api", feature = "grpc"))]
+    pub(crate) async fn oldest_feed_sequence(&self) -> Result<Option<i64>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .delta_feed
+            .find_one(
+                None,
+                FindOneOptions::builder().sort(doc! { "_id": 1 }).build(),
+            )
+            .await?
+            .map(|entry| entry.sequence))
+    }
+
+    /// Appends the entry applying or reverting a block, unless it was appended already, as it is
+    /// when the block is written again after a crash.
+    ///
+    /// Entries are keyed by their kind, block hash and the number of times the block was applied
+    /// or reverted before, so a block reverted and applied again by a later reorg gets entries
+    /// again.
+    pub(crate) async fn append_feed_entry(
+        &self,
+        kind: DeltaFeedKind,
+        block_number: U64,
+        block_hash: H256,
+        deltas: Vec<OwnershipDelta>,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        let block_hash_hex = format!("{:#x}", block_hash);
+        let occurrence = match kind {
+            DeltaFeedKind::Apply => {
+                self.delta_feed
+                    .count_documents(
+                        doc! { "block_hash": &block_hash_hex, "key": { "$regex": "^revert:" } },
+                        None,
+                    )
+                    .await?
+            }
+            DeltaFeedKind::Revert => self
+                .delta_feed
+                .count_documents(
+                    doc! { "block_hash": &block_hash_hex, "key": { "$regex": "^apply:" } },
+                    None,
+                )
+                .await?
+                .saturating_sub(1),
+        };
+        let kind_name = match kind {
+            DeltaFeedKind::Apply => "apply",
+            DeltaFeedKind::Revert => "revert",
+        };
+
+        self.insert_feed_entry(
+            format!("{}:{}:{}", kind_name, block_hash_hex, occurrence),
+            kind,
+            block_number,
+            block_hash,
+            deltas,
+        )
+        .await
+    }
+
+    /// Appends the entry applying a dead letter that decodes now, unless it was appended already.
+    pub(crate) async fn append_dead_letter_feed_entry(
+        &self,
+        dead_letter_id: &str,
+        block_number: U64,
+        block_hash: H256,
+        deltas: Vec<OwnershipDelta>,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        self.insert_feed_entry(
+            format!("dead_letter:{}", dead_letter_id),
+            DeltaFeedKind::Apply,
+            block_number,
+            block_hash,
+            deltas,
+        )
+        .await
+    }
+
+    /// Inserts a delta feed entry under an idempotency key, numbered by the next sequence of the
+    /// `delta_feed` counter, when no entry has that key yet.
+    ///
+    /// Sequences are allocated by incrementing the counter, so concurrent appends never number two
+    /// entries alike. An append interrupted between the two leaves a sequence unused, which
+    /// consumers skip like any other.
+    async fn insert_feed_entry(
+        &self,
+        key: String,
+        kind: DeltaFeedKind,
+        block_number: U64,
+        block_hash: H256,
+        deltas: Vec<OwnershipDelta>,
+    ) -> Result<()> {
+        let entries = self.delta_feed.clone_with_type::<Document>();
+
+        if entries
+            .find_one(doc! { "key": &key }, None)
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let entry = mongodb::bson::to_document(&DeltaFeedEntry {
+            sequence: self.next_feed_sequence().await?,
+            kind,
+            block_number: block_number.as_u64() as i64,
+            block_hash,
+            deltas,
+        })?;
+
+        // The key is set from the filter when inserting.
+        match entries
+            .update_one(
+                doc! { "key": &key },
+                doc! { "$setOnInsert": entry },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) if is_duplicate_key(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Increments the `delta_feed` counter of `sync_state`, returning the sequence allocated. A
+    /// feed written before sequences were counted continues after its newest entry.
+    async fn next_feed_sequence(&self) -> Result<i64> {
+        let counters = self.sync_state.clone_with_type::<FeedSequence>();
+
+        loop {
+            let counter = counters
+                .find_one_and_update(
+                    doc! { "_id": DELTA_FEED_SYNC_STATE_ID },
+                    doc! { "$inc": { "sequence": 1_i64 } },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                )
+                .await?;
+
+            if let Some(counter) = counter {
+                return Ok(counter.sequence);
+            }
+
+            counters
+                .update_one(
+                    doc! { "_id": DELTA_FEED_SYNC_STATE_ID },
+                    doc! { "$max": { "sequence": self.latest_feed_sequence().await? } },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await?;
+        }
+    }
+
+    pub(crate) async fn feed_entries(&self, after: i64, limit: i64) -> Result<Vec<DeltaFeedEntry>> {
+        self.inject_fault()?;
+
+        self.delta_feed
+            .find(
+                doc! { "_id": { "$gt": after } },
+                FindOptions::builder()
+                    .sort(doc! { "_id": 1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Drops the delta feed entries of blocks below `block_number`.
+    pub(crate) async fn prune_feed(&self, block_number: U64) -> Result<()> {
+        self.inject_fault()?;
+
+        self.delta_feed
+            .delete_many(
+                doc! { "block_number": { "$lt": block_number.as_u64() as i64 } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Drops the ownership model and its archive, its aggregates and supplies, the approvals, the transfer history,
+    /// the reconciliation queue, the logs worker checkpoint, the journal, the delta feed, the applied block tokens, the
+    /// block coverage and the jobs handed off by the head worker, keeping the contract classifications.
+    pub(crate) async fn reset(&self) -> Result<()> {
+        self.inject_fault()?;
+
+        self.token_ownerships.delete_many(doc! {}, None).await?;
+        self.archived_ownerships.delete_many(doc! {}, None).await?;
+        self.contract_holdings.delete_many(doc! {}, None).await?;
+        self.contract_stats.delete_many(doc! {}, None).await?;
+        self.top_holders.delete_many(doc! {}, None).await?;
+        self.token_holder_counts.delete_many(doc! {}, None).await?;
+        self.portfolios.delete_many(doc! {}, None).await?;
+        self.balance_history.delete_many(doc! {}, None).await?;
+        self.token_supplies.delete_many(doc! {}, None).await?;
+        self.approvals.delete_many(doc! {}, None).await?;
+        self.transfers.delete_many(doc! {}, None).await?;
+        self.reconciliation_queue.delete_many(doc! {}, None).await?;
+        self.block_jobs
+            .delete_many(doc! { "head_handoff": true }, None)
+            .await?;
+        self.applied_blocks.delete_many(doc! {}, None).await?;
+        self.applied_logs.delete_many(doc! {}, None).await?;
+        self.block_journal.delete_many(doc! {}, None).await?;
+        self.delta_feed.delete_many(doc! {}, None).await?;
+        self.block_coverage.delete_many(doc! {}, None).await?;
+        self.dead_letters.delete_many(doc! {}, None).await?;
+        // Exports start over with the rebuilt transfer history.
+        self.sync_state
+            .delete_many(
+                doc! {
+                    "$or": [
+                        { "_id": LOGS_WORKER_SYNC_STATE_ID },
+                        { "_id": DELTA_FEED_SYNC_STATE_ID },
+                        { "_id": { "$regex": "^export:" } },
+                    ]
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Drops the ownerships of a contract and their archive, its aggregates and supplies, its
+    /// approvals, its transfer history and its queued reconciliations, keeping its classification.
+    /// The checkpoint, the journal and the delta feed are left alone.
+    pub(crate) async fn reset_contract(&self, contract_address: H160) -> Result<()> {
+        self.inject_fault()?;
+
+        let address = format!("{:#x}", contract_address);
+        let filter = doc! { "contract_address": &address };
+
+        for ownerships in [&self.token_ownerships, &self.archived_ownerships] {
+            ownerships
+                .delete_many(self.ownership_schema.stored_document(filter.clone()), None)
+                .await?;
+        }
+        self.contract_holdings
+            .delete_many(filter.clone(), None)
+            .await?;
+        self.contract_stats
+            .delete_many(doc! { "_id": &address }, None)
+            .await?;
+        self.top_holders
+            .delete_many(doc! { "_id": &address }, None)
+            .await?;
+        self.token_holder_counts
+            .delete_many(filter.clone(), None)
+            .await?;
+        self.portfolios.delete_many(filter.clone(), None).await?;
+        self.balance_history
+            .delete_many(filter.clone(), None)
+            .await?;
+        self.token_supplies
+            .delete_many(filter.clone(), None)
+            .await?;
+        self.approvals.delete_many(filter.clone(), None).await?;
+        self.transfers.delete_many(filter.clone(), None).await?;
+        self.reconciliation_queue.delete_many(filter, None).await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn record_operator_action(&self, action: &OperatorAction) -> Result<()> {
+        self.inject_fault()?;
+
+        self.operator_actions.insert_one(action, None).await?;
+
+        Ok(())
+    }
+
+    /// Most recent operator actions first, optionally restricted to a single actor.
+    #[cfg(feature = "api")]
+    pub(crate) async fn operator_actions(
+        &self,
+        actor: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<OperatorAction>> {
+        self.inject_fault()?;
+
+        self.operator_actions
+            .find(
+                actor.map(|actor| doc! { "actor": actor }),
+                FindOptions::builder()
+                    .sort(doc! { "performed_at": -1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Stores a new API key, failing when a key of the same name exists, revoked or not.
+    #[cfg(feature = "api")]
+    pub(crate) async fn insert_api_key(&self, api_key: &StoredApiKey) -> Result<()> {
+        self.inject_fault()?;
+
+        self.api_keys.insert_one(api_key, None).await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) async fn api_key(&self, name: &str) -> Result<Option<StoredApiKey>> {
+        self.inject_fault()?;
+
+        self.api_keys.find_one(doc! { "_id": name }, None).await
+    }
+
+    /// The unrevoked API key whose secret hashes to `key_hash`.
+    #[cfg(feature = "api")]
+    pub(crate) async fn active_api_key(&self, key_hash: &str) -> Result<Option<StoredApiKey>> {
+        self.inject_fault()?;
+
+        self.api_keys
+            .find_one(doc! { "key_hash": key_hash, "revoked_at": null }, None)
+            .await
+    }
+
+    /// Every stored API key, revoked ones included, ordered by name.
+    #[cfg(feature = "api")]
+    pub(crate) async fn api_keys(&self) -> Result<Vec<StoredApiKey>> {
+        self.inject_fault()?;
+
+        self.api_keys
+            .find(None, FindOptions::builder().sort(doc! { "_id": 1 }).build())
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Revokes an API key. Returns whether an unrevoked key of that name existed.
+    #[cfg(feature = "api")]
+    pub(crate) async fn revoke_api_key(&self, name: &str) -> Result<bool> {
+        self.inject_fault()?;
+
+        let result = self
+            .api_keys
+            .update_one(
+                doc! { "_id": name, "revoked_at": null },
+                doc! { "$set": { "revoked_at": DateTime::now() } },
+                None,
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Number of ownerships with a negative quantity per contract, which the deltas of a consistent
+    /// chain never produce.
+    #[cfg(feature = "api")]
+    pub(crate) async fn negative_balance_counts(&self) -> Result<HashMap<H160, i64>> {
+        self.inject_fault()?;
+
+        count_per_contract(
+            &self.token_ownerships,
+            self.ownership_schema
+                .stored_document(doc! { "quantity": { "$lt": 0 } }),
+            &self.ownership_schema.field("contract_address"),
+            false,
+        )
+        .await
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) async fn negative_balances(
+        &self,
+        contract_address: H160,
+        limit: i64,
+    ) -> Result<Vec<TokenOwnership>> {
+        self.inject_fault()?;
+
+        let filter = doc! {
+            "contract_address": format!("{:#x}", contract_address),
+            "quantity": { "$lt": 0 },
+        };
+
+        self.token_ownerships
+            .find(
+                self.ownership_schema.stored_document(filter),
+                FindOptions::builder().limit(limit).build(),
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .map(|document| self.stored_ownership(document))
+            .collect()
+    }
+
+    /// Number of negative supplies per contract, more than one only for ERC1155 contracts.
+    #[cfg(feature = "api")]
+    pub(crate) async fn negative_supply_counts(&self) -> Result<HashMap<H160, i64>> {
+        self.inject_fault()?;
+
+        count_per_contract(
+            &self.token_supplies,
+            doc! { "supply": { "$lt": 0 } },
+            "contract_address",
+            false,
+        )
+        .await
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) async fn negative_supplies(
+        &self,
+        contract_address: H160,
+        limit: i64,
+    ) -> Result<Vec<TokenSupply>> {
+        self.inject_fault()?;
+
+        self.token_supplies
+            .find(
+                doc! {
+                    "contract_address": format!("{:#x}", contract_address),
+                    "supply": { "$lt": 0 },
+                },
+                FindOptions::builder().limit(limit).build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Number of contract and token metadata given up on per contract.
+    #[cfg(feature = "api")]
+    pub(crate) async fn failed_metadata_counts(&self) -> Result<HashMap<H160, i64>> {
+        self.inject_fault()?;
+
+        count_per_contract(
+            &self.token_metadata,
+            doc! { "status": "failed" },
+            "contract_address",
+            false,
+        )
+        .await
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) async fn failed_metadata(
+        &self,
+        contract_address: H160,
+        limit: i64,
+    ) -> Result<Vec<TokenMetadata>> {
+        self.inject_fault()?;
+
+        self.token_metadata
+            .find(
+                doc! {
+                    "contract_address": format!("{:#x}", contract_address),
+                    "status": "failed",
+                },
+                FindOptions::builder().limit(limit).build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Number of quorum mismatches involving each contract. Mismatches recorded before the
+    /// contracts were stored along with them are not counted.
+    #[cfg(feature = "api")]
+    pub(crate) async fn quorum_mismatch_counts(&self) -> Result<HashMap<H160, i64>> {
+        self.inject_fault()?;
+
+        count_per_contract(&self.quorum_mismatches, doc! {}, "contract_addresses", true).await
+    }
+
+    /// Quorum mismatches involving a contract, most recent first.
+    #[cfg(feature = "api")]
+    pub(crate) async fn quorum_mismatches(
+        &self,
+        contract_address: H160,
+        limit: i64,
+    ) -> Result<Vec<QuorumMismatch>> {
+        self.inject_fault()?;
+
+        self.quorum_mismatches
+            .find(
+                doc! { "contract_addresses": format!("{:#x}", contract_address) },
+                FindOptions::builder()
+                    .sort(doc! { "detected_at": -1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub(crate) async fn record_quorum_mismatch(&self, mismatch: &QuorumMismatch) -> Result<()> {
+        self.inject_fault()?;
+
+        self.quorum_mismatches.insert_one(mismatch, None).await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "webhooks")]
+    pub(crate) async fn webhook_rules(&self, contracts: &[H160]) -> Result<Vec<WebhookRule>> {
+        self.inject_fault()?;
+
+        let contracts: Vec<_> = contracts
+            .iter()
+            .map(|contract| format!("{:#x}", contract))
+            .collect();
+
+        self.webhook_rules
+            .find(doc! { "contract_address": { "$in": contracts } }, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) async fn all_webhook_rules(&self) -> Result<Vec<WebhookRule>> {
+        self.inject_fault()?;
+
+        self.webhook_rules
+            .find(None, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) async fn insert_webhook_rule(&self, rule: &WebhookRule) -> Result<()> {
+        self.inject_fault()?;
+
+        self.webhook_rules.insert_one(rule, None).await?;
+
+        Ok(())
+    }
+
+    /// Returns whether a rule was deleted.
+    #[cfg(feature = "api")]
+    pub(crate) async fn delete_webhook_rule(&self, id: ObjectId) -> Result<bool> {
+        self.inject_fault()?;
+
+        let result = self
+            .webhook_rules
+            .delete_one(doc! { "_id": id }, None)
+            .await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    #[cfg(feature = "webhooks")]
+    pub(crate) async fn set_webhook_reference_value(&self, id: ObjectId, value: f64) -> Result<()> {
+        self.inject_fault()?;
+
+        self.webhook_rules
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "reference_value": value } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn insert_block_jobs(&self, jobs: &[BlockJob]) -> Result<()> {
+        self.inject_fault()?;
+
+        if !jobs.is_empty() {
+            self.block_jobs.insert_many(jobs, None).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Claims the pending job, or the claimed job whose lease expired, with the lowest blocks.
+    pub(crate) async fn claim_block_job(
+        &self,
+        worker_id: &str,
+        lease: Duration,
+    ) -> Result<Option<BlockJob>> {
+        self.inject_fault()?;
+
+        let now = DateTime::now();
+
+        self.block_jobs
+            .find_one_and_update(
+                doc! {
+                    "$or": [
+                        { "status": "pending" },
+                        { "status": "claimed", "lease_expires_at": { "$lt": now } },
+                    ]
+                },
+                doc! {
+                    "$set": {
+                        "status": "claimed",
+                        "claimed_by": worker_id,
+                        "lease_expires_at": lease_expiry(lease),
+                    },
+                    "$inc": { "attempts": 1 },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .sort(doc! { "from_block": 1 })
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+    }
+
+    /// Last block of the jobs the head worker handed off, so a restarted head worker does not hand
+    /// the same blocks off twice.
+    pub(crate) async fn last_head_handoff_block(&self) -> Result<Option<U64>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .block_jobs
+            .find_one(
+                doc! { "head_handoff": true },
+                FindOneOptions::builder()
+                    .sort(doc! { "to_block": -1 })
+                    .build(),
+            )
+            .await?
+            .map(|job| U64::from(job.to_block as u64)))
+    }
+
+    /// Whether any job is still pending or claimed.
+    pub(crate) async fn has_open_block_jobs(&self) -> Result<bool> {
+        self.inject_fault()?;
+
+        Ok(self
+            .block_jobs
+            .find_one(doc! { "status": { "$in": ["pending", "claimed"] } }, None)
+            .await?
+            .is_some())
+    }
+
+    /// Records the progress of a claimed job and extends its lease. Returns `false` when the worker
+    /// no longer holds the job, which another worker claimed after the lease expired.
+    pub(crate) async fn advance_block_job(
+        &self,
+        id: ObjectId,
+        worker_id: &str,
+        next_block: U64,
+        lease: Duration,
+    ) -> Result<bool> {
+        self.inject_fault()?;
+
+        let result = self
+            .block_jobs
+            .update_one(
+                doc! { "_id": id, "status": "claimed", "claimed_by": worker_id },
+                doc! {
+                    "$set": {
+                        "next_block": next_block.as_u64() as i64,
+                        "lease_expires_at": lease_expiry(lease),
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    pub(crate) async fn complete_block_job(&self, id: ObjectId, worker_id: &str) -> Result<()> {
+        self.inject_fault()?;
+
+        self.block_jobs
+            .update_one(
+                doc! { "_id": id, "status": "claimed", "claimed_by": worker_id },
+                doc! {
+                    "$set": { "status": "done", "lease_expires_at": null, "error": null }
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Hands a job that failed back to the queue, or marks it failed once it has been attempted
+    /// `max_attempts` times.
+    pub(crate) async fn release_block_job(
+        &self,
+        job: &BlockJob,
+        worker_id: &str,
+        error: &str,
+        max_attempts: i32,
+    ) -> Result<()> {
+        self.inject_fault()?;
+
+        let status = if job.attempts >= max_attempts {
+            "failed"
+        } else {
+            "pending"
+        };
+
+        self.block_jobs
+            .update_one(
+                doc! { "_id": job.id, "status": "claimed", "claimed_by": worker_id },
+                doc! {
+                    "$set": {
+                        "status": status,
+                        "claimed_by": null,
+                        "lease_expires_at": null,
+                        "error": error,
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Acquires or renews the lease `name` for `holder`. Returns `false` while another holder's
+    /// lease has not expired.
+    pub(crate) async fn acquire_lease(
+        &self,
+        name: &str,
+        holder: &str,
+        duration: Duration,
+    ) -> Result<bool> {
+        self.inject_fault()?;
+
+        let result = self
+            .leases
+            .update_one(
+                doc! {
+                    "_id": name,
+                    "$or": [
+                        { "holder": holder },
+                        { "expires_at": { "$lt": DateTime::now() } },
+                    ],
+                },
+                doc! {
+                    "$set": { "holder": holder, "expires_at": lease_expiry(duration) }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            // The lease exists but is held by someone else, so the upsert tried to insert it again.
+            Err(error) if is_duplicate_key(&error) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Asks the holder of the lease `name` to hand it over to `successor`, replacing the successor
+    /// asked for before.
+    pub(crate) async fn request_lease(&self, name: &str, successor: &str) -> Result<()> {
+        self.inject_fault()?;
+
+        self.leases
+            .update_one(
+                doc! { "_id": name, "holder": { "$ne": successor } },
+                doc! { "$set": { "successor": successor } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The process the lease `name` held by `holder` is asked to be handed over to, other than
+    /// `holder` itself.
+    pub(crate) async fn lease_successor(&self, name: &str, holder: &str) -> Result<Option<String>> {
+        self.inject_fault()?;
+
+        Ok(self
+            .leases
+            .find_one(doc! { "_id": name, "holder": holder }, None)
+            .await?
+            .and_then(|lease| lease.successor)
+            .filter(|successor| successor != holder))
+    }
+
+    /// Hands the lease `name` over from `holder` to `successor`, for `duration`. Returns `false`
+    /// when `holder` no longer held it.
+    pub(crate) async fn hand_over_lease(
+        &self,
+        name: &str,
+        holder: &str,
+        successor: &str,
+        duration: Duration,
+    ) -> Result<bool> {
+        self.inject_fault()?;
+
+        let result = self
+            .leases
+            .update_one(
+                doc! { "_id": name, "holder": holder },
+                doc! {
+                    "$set": { "holder": successor, "expires_at": lease_expiry(duration) },
+                    "$unset": { "successor": "" },
+                },
+                None,
+            )
+            .await?;
+
+        Ok(result.matched_count == 1)
+    }
+
+    /// Gives the lease `name` up, if `holder` still holds it, so other holders need not wait for
+    /// it to expire.
+    pub(crate) async fn release_lease(&self, name: &str, holder: &str) -> Result<()> {
+        self.inject_fault()?;
+
+        self.leases
+            .delete_one(doc! { "_id": name, "holder": holder }, None)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Position after the last ownership of a page, to read the next page from. Renders as the owner,
+/// or contract, and token id of that ownership, such as `0x…:1234`, and parses back from it, so it
+/// can be handed to clients as an opaque string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageCursor {
+    position: H160,
+    token_id: Option<String>,
+}
+
+impl std::fmt::Display for PageCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.token_id {
+            Some(token_id) => write!(f, "{:#x}:{}", self.position, token_id),
+            None => write!(f, "{:#x}", self.position),
+        }
+    }
+}
+
+impl std::str::FromStr for PageCursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> std::result::Result<Self, Self::Err> {
+        let (position, token_id) = match cursor.split_once(':') {
+            Some((position, token_id)) => (position, Some(token_id.to_string())),
+            None => (cursor, None),
+        };
+
+        Ok(Self {
+            position: position
+                .parse()
+                .map_err(|_| format!("Invalid page cursor {}", cursor))?,
+            token_id,
+        })
+    }
+}
+
+/// Order the holders of a contract are listed in by `contract_holders_page`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HolderSort {
+    /// By owner, then token id.
+    Owner,
+    /// By quantity, largest first, then owner and token id.
+    Balance,
+}
+
+/// Where a page of holders starts: after the `PageCursor` of the last holder of the previous page
+/// and, sorted by balance, its quantity, rendered as `<quantity>@<page cursor>`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HolderCursor {
+    quantity: Option<f64>,
+    page: PageCursor,
+}
+
+impl HolderCursor {
+    /// The cursor of the page following `ownership` in the order of `sort`.
+    #[cfg(feature = "api")]
+    pub(crate) fn following(sort: HolderSort, ownership: &TokenOwnership) -> Self {
+        Self {
+            quantity: (sort == HolderSort::Balance).then_some(ownership.quantity),
+            page: PageCursor {
+                position: ownership.owner,
+                token_id: ownership.token_id.clone(),
+            },
+        }
+    }
+
+    /// Whether the cursor was taken from a page in the order of `sort`.
+    #[cfg(feature = "api")]
+    pub(crate) fn matches(&self, sort: HolderSort) -> bool {
+        self.quantity.is_some() == (sort == HolderSort::Balance)
+    }
+}
+
+impl std::fmt::Display for HolderCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.quantity {
+            Some(quantity) => write!(f, "{}@{}", quantity, self.page),
+            None => write!(f, "{}", self.page),
+        }
+    }
+}
+
+impl std::str::FromStr for HolderCursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> std::result::Result<Self, Self::Err> {
+        let (quantity, page) = match cursor.split_once('@') {
+            Some((quantity, page)) => (
+                Some(
+                    quantity
+                        .parse()
+                        .map_err(|_| format!("Invalid page cursor {}", cursor))?,
+                ),
+                page,
+            ),
+            None => (None, cursor),
+        };
+
+        Ok(Self {
+            quantity,
+            page: page.parse()?,
+        })
+    }
+}
+
+/// A page of query results, with the cursor of the next page, `None` on the last page.
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<PageCursor>,
+}
+
+impl Page<TokenOwnership> {
+    /// The first `limit` of `ownerships`, read with one more than `limit` to tell whether a next
+    /// page exists, which starts after the ownership `position` picks the key of.
+    fn of_ownerships(
+        mut ownerships: Vec<TokenOwnership>,
+        limit: usize,
+        position: impl Fn(&TokenOwnership) -> H160,
+    ) -> Self {
+        let next = (ownerships.len() > limit).then(|| {
+            ownerships.truncate(limit);
+            let last = &ownerships[limit - 1];
+
+            PageCursor {
+                position: position(last),
+                token_id: last.token_id.clone(),
+            }
+        });
+
+        Self {
+            items: ownerships,
+            next,
+        }
+    }
+}
+
+/// Typed queries over the ownership model for Rust services embedding the crate, so they need not
+/// write filters against the stored documents. Every call reads the database, unlike the cached
+/// point lookups of `OwnershipReader`.
+///
+/// Only ownerships with a positive quantity are listed. Owners are returned as stored, protected
+/// when the worker protects them.
+#[derive(Debug, Clone)]
+pub struct OwnershipQueries {
+    store: Store,
+    owner_protection: OwnerProtection,
+}
+
+impl OwnershipQueries {
+    pub fn new(database: &Database) -> Self {
+        Self {
+            store: Store::new(database),
+            owner_protection: OwnerProtection::default(),
+        }
+    }
+
+    pub async fn connect(
+        database_host: String,
+        database_name: String,
+    ) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let client = Client::with_options(ClientOptions::parse(database_host).await?)?;
+
+        Ok(Self::new(&client.database(&database_name)))
+    }
+
+    /// Protection the worker applies to owner addresses, so queried owners match stored ones.
+    pub fn with_owner_protection(self, owner_protection: OwnerProtection) -> Self {
+        Self {
+            owner_protection,
+            ..self
+        }
+    }
+
+    /// Prefix the worker's collection names start with.
+    pub fn with_collection_prefix(self, collection_prefix: &str) -> Self {
+        Self {
+            store: self.store.with_collection_prefix(collection_prefix),
+            ..self
+        }
+    }
+
+    /// Collection and field names the worker stores the ownership model under.
+    pub fn with_ownership_schema(self, ownership_schema: OwnershipSchema) -> Self {
+        Self {
+            store: self.store.with_ownership_schema(ownership_schema),
+            ..self
+        }
+    }
+
+    /// Up to `limit` ownerships of a contract, ordered by owner and token id, from the start or
+    /// following the cursor of the previous page.
+    pub async fn owners_of(
+        &self,
+        contract_address: H160,
+        after: Option<&PageCursor>,
+        limit: usize,
+    ) -> Result<Page<TokenOwnership>> {
+        let limit = limit.max(1);
+        let ownerships = self
+            .store
+            .contract_ownerships_page(contract_address, after, limit as i64 + 1)
+            .await?;
+
+        Ok(Page::of_ownerships(ownerships, limit, |ownership| {
+            ownership.owner
+        }))
+    }
+
+    /// Exact amount of a contract's tokens an owner holds, across every token id.
+    pub async fn balance_of(&self, contract_address: H160, owner: H160) -> Result<Amount> {
+        self.store
+            .owner_amount(contract_address, self.owner_protection.protect(owner))
+            .await
+    }
+
+    /// Up to `limit` ownerships of an owner, ordered by contract and token id, from the start or
+    /// following the cursor of the previous page.
+    pub async fn tokens_of(
+        &self,
+        owner: H160,
+        after: Option<&PageCursor>,
+        limit: usize,
+    ) -> Result<Page<TokenOwnership>> {
+        let limit = limit.max(1);
+        let ownerships = self
+            .store
+            .owner_ownerships_page(
+                self.owner_protection.protect(owner),
+                after,
+                limit as i64 + 1,
+            )
+            .await?;
+
+        Ok(Page::of_ownerships(ownerships, limit, |ownership| {
+            ownership.contract_address
+        }))
+    }
+}
+
+/// The filter of the approval a change applies to, and the approval replacing it, `None` when the
+/// change revokes it.
+pub(crate) fn approval_record(
+    change: &ApprovalChange,
+    block_number: U64,
+) -> (Document, Option<Approval>) {
+    let block_number = block_number.as_u64() as i64;
+
+    match change {
+        ApprovalChange::Allowance {
+            contract_address,
+            owner,
+            spender,
+            amount,
+        } => (
+            doc! {
+                "contract_address": format!("{:#x}", contract_address),
+                "kind": "allowance",
+                "owner": format!("{:#x}", owner),
+                "operator": format!("{:#x}", spender),
+            },
+            (*amount > 0.0).then_some(Approval {
+                contract_address: *contract_address,
+                kind: ApprovalKind::Allowance,
+                owner: *owner,
+                operator: *spender,
+                token_id: None,
+                amount: Some(*amount),
+                block_number,
+            }),
+        ),
+        ApprovalChange::Token {
+            contract_address,
+            token_id,
+            owner,
+            approved,
+        } => (
+            doc! {
+                "contract_address": format!("{:#x}", contract_address),
+                "kind": "token",
+                "token_id": token_id,
+            },
+            (*approved != H160::default()).then_some(Approval {
+                contract_address: *contract_address,
+                kind: ApprovalKind::Token,
+                owner: *owner,
+                operator: *approved,
+                token_id: Some(token_id.clone()),
+                amount: None,
+                block_number,
+            }),
+        ),
+        ApprovalChange::Operator {
+            contract_address,
+            owner,
+            operator,
+            approved,
+        } => (
+            doc! {
+                "contract_address": format!("{:#x}", contract_address),
+                "kind": "operator",
+                "owner": format!("{:#x}", owner),
+                "operator": format!("{:#x}", operator),
+            },
+            approved.then_some(Approval {
+                contract_address: *contract_address,
+                kind: ApprovalKind::Operator,
+                owner: *owner,
+                operator: *operator,
+                token_id: None,
+                amount: None,
+                block_number,
+            }),
+        ),
+    }
+}
+
+/// Adds `amount` to the exact amount in `amount_field` of the document matching `filter`, creating
+/// it when missing, and mirrors the sum as a float in `quantity_field`. `set` holds further fields
+/// to set. Returns the amount before and after.
+///
+/// MongoDB cannot add decimal strings, so the sum is computed here and only written if the stored
+/// amount has not changed since it was read, or read again otherwise. Documents stored before
+/// exact amounts were start from their rounded float quantity.
+async fn add_exactly(
+    collection: &Collection<Document>,
+    filter: Document,
+    amount_field: &str,
+    quantity_field: &str,
+    amount: Amount,
+    set: Document,
+) -> Result<(Amount, Amount)> {
+    loop {
+        let stored = collection.find_one(filter.clone(), None).await?;
+
+        let previous = stored
+            .as_ref()
+            .map(|document| stored_amount(document, amount_field, quantity_field))
+            .unwrap_or_default();
+
+        let updated = previous + amount;
+
+        let mut unchanged = filter.clone();
+        unchanged.insert(
+            amount_field,
+            stored
+                .as_ref()
+                .and_then(|document| document.get(amount_field).cloned())
+                .unwrap_or(Bson::Null),
+        );
+
+        let mut set = set.clone();
+        set.insert(amount_field, updated.to_string());
+        set.insert(quantity_field, updated.to_f64());
+
+        let result = collection
+            .update_one(
+                unchanged,
+                doc! { "$set": set },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await;
+
+        match result {
+            Ok(result) if result.matched_count > 0 || result.upserted_id.is_some() => {
+                return Ok((previous, updated))
+            }
+            Ok(_) => continue,
+            // A concurrent write inserted the document between the read and the upsert.
+            Err(error) if is_duplicate_key(&error) => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// The exact amount of a document, rounded from its float quantity when it has none.
+fn stored_amount(document: &Document, amount_field: &str, quantity_field: &str) -> Amount {
+    if let Ok(amount) = document.get_str(amount_field) {
+        if let Ok(amount) = amount.parse() {
+            return amount;
+        }
+    }
+
+    let quantity = match document.get(quantity_field) {
+        Some(Bson::Double(quantity)) => *quantity,
+        Some(Bson::Int32(quantity)) => *quantity as f64,
+        Some(Bson::Int64(quantity)) => *quantity as f64,
+        _ => 0.0,
+    };
+
+    Amount::from_f64(quantity)
+}
+
+/// Number of documents of `collection` matching `filter` per contract, grouped by the contract
+/// address in `field`. With `unwind`, `field` holds an array of contract addresses, and documents
+/// are counted once for each of them.
+#[cfg(feature = "api")]
+async fn count_per_contract<T>(
+    collection: &Collection<T>,
+    filter: Document,
+    field: &str,
+    unwind: bool,
+) -> Result<HashMap<H160, i64>> {
+    let mut pipeline = vec![doc! { "$match": filter }];
+
+    if unwind {
+        pipeline.push(doc! { "$unwind": format!("${}", field) });
+    }
+
+    pipeline.push(doc! {
+        "$group": {
+            "_id": format!("${}", field),
+            "count": { "$sum": 1 },
+        }
+    });
+
+    collection
+        .aggregate(pipeline, None)
+        .await?
+        .map(|document| {
+            let count: ContractCount = mongodb::bson::from_document(document?)?;
+            Ok((count.contract_address, count.count))
+        })
+        .try_collect()
+        .await
+}
+
+/// The creator a token id of a shared ERC1155 contract encodes in its upper 160 bits, followed by
+/// the index of the token within the creator's tokens and its maximum supply.
+pub(crate) fn shared_token_creator(token_id: U256) -> H160 {
+    let mut bytes = [0u8; 32];
+    token_id.to_big_endian(&mut bytes);
+
+    H160::from_slice(&bytes[..20])
+}
+
+/// `_id` of the marker of an applied log. Reverting a removed log is marked apart from applying it.
+fn applied_log_id(block_hash: H256, log: &JournaledLog) -> String {
+    format!(
+        "{:#x}:{:#x}:{}{}",
+        block_hash,
+        log.transaction_hash,
+        log.log_index,
+        if log.removed { ":removed" } else { "" }
+    )
+}
+
+/// Whether a write failed because a document with the same `_id` exists.
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    match &*error.kind {
+        ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == 11000,
+        ErrorKind::Command(error) => error.code == 11000,
+        // Unordered bulk inserts only fail on duplicates when every failed write was one.
+        ErrorKind::BulkWrite(failure) => {
+            failure.write_concern_error.is_none()
+                && failure
+                    .write_errors
+                    .as_ref()
+                    .is_some_and(|errors| errors.iter().all(|error| error.code == 11000))
+        }
+        _ => false,
+    }
+}
+
+fn lease_expiry(lease: Duration) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + lease.as_millis() as i64)
+}
+
+/// Whether an owner gained a holder's place, going from no tokens to some, or lost it.
+fn holder_count_change(previous: Amount, amount: Amount) -> i64 {
+    match (previous.is_positive(), amount.is_positive()) {
+        (false, true) => 1,
+        (true, false) => -1,
+        _ => 0,
+    }
+}
+
+/// The holder count a delta changes, of its ERC1155 token or of the tokens of its contract.
+fn token_holder_filter(delta: &OwnershipDelta) -> Document {
+    let mut filter = doc! {
+        "contract_address": format!("{:#x}", delta.contract_address),
+    };
+
+    if delta.token_type == "ERC1155" {
+        if let Some(token_id) = &delta.token_id {
+            filter.insert("token_id", token_id);
+        }
+    }
+
+    filter
+}
+
+/// The writes of the tokens owners gained and lost, from deltas paired with the change of the
+/// holder count of their ownership: update statements adding the token ids an owner gained to the
+/// portfolio entry of their contract and removing the ones it lost, and filters of the entries
+/// left holding nothing. Gains and losses are written by separate statements, since an update
+/// cannot both add to and pull from `token_ids`.
+fn portfolio_writes<'a>(
+    changes: impl IntoIterator<Item = (&'a OwnershipDelta, i64)>,
+) -> (Vec<Document>, Vec<Document>) {
+    /// Tokens of a contract an owner gained and lost.
+    struct PortfolioChange<'a> {
+        delta: &'a OwnershipDelta,
+        gained: Vec<&'a str>,
+        gained_count: i64,
+        lost: Vec<&'a str>,
+        lost_count: i64,
+    }
+
+    let mut entries: Vec<PortfolioChange> = Vec::new();
+
+    for (delta, change) in changes {
+        if change == 0 {
+            continue;
+        }
+
+        let position = match entries.iter().position(|entry| {
+            entry.delta.owner == delta.owner
+                && entry.delta.contract_address == delta.contract_address
+        }) {
+            Some(position) => position,
+            None => {
+                entries.push(PortfolioChange {
+                    delta,
+                    gained: Vec::new(),
+                    gained_count: 0,
+                    lost: Vec::new(),
+                    lost_count: 0,
+                });
+                entries.len() - 1
+            }
+        };
+        let entry = &mut entries[position];
+
+        if change > 0 {
+            entry.gained.extend(delta.token_id.as_deref());
+            entry.gained_count += change;
+        } else {
+            entry.lost.extend(delta.token_id.as_deref());
+            entry.lost_count -= change;
+        }
+    }
+
+    let mut statements = Vec::new();
+    let mut emptied = Vec::new();
+
+    for PortfolioChange {
+        delta,
+        gained,
+        gained_count,
+        lost,
+        lost_count,
+    } in entries
+    {
+        let filter = holding_filter(delta);
+
+        if gained_count > 0 {
+            statements.push(doc! {
+                "q": &filter,
+                "u": {
+                    "$addToSet": { "token_ids": { "$each": gained } },
+                    "$inc": { "token_count": gained_count },
+                    "$set": { "token_type": &delta.token_type },
+                },
+                "upsert": true,
+            });
+        }
+
+        if lost_count > 0 {
+            statements.push(doc! {
+                "q": &filter,
+                "u": {
+                    "$pull": { "token_ids": { "$in": lost } },
+                    "$inc": { "token_count": -lost_count },
+                },
+            });
+
+            let mut filter = filter;
+            filter.insert("token_count", doc! { "$lte": 0 });
+            emptied.push(filter);
+        }
+    }
+
+    (statements, emptied)
+}
+
+fn holding_filter(delta: &OwnershipDelta) -> Document {
+    doc! {
+        "contract_address": format!("{:#x}", delta.contract_address),
+        "owner": format!("{:#x}", delta.owner),
+    }
+}
+
+/// The values of `fields` in a document, identifying the stored document a filter matches.
+fn filter_key(fields: &[&str], document: &Document) -> String {
+    fields
+        .iter()
+        .map(|field| document.get(field).map(Bson::to_string).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn ownership_filter(delta: &OwnershipDelta) -> Document {
+    let mut filter = doc! {
+        "contract_address": format!("{:#x}", delta.contract_address),
+        "owner": format!("{:#x}", delta.owner),
+    };
+
+    if let Some(token_id) = &delta.token_id {
+        filter.insert("token_id", token_id);
+    }
+
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+
+    #[test]
+    fn page_cursors_round_trip() {
+        let with_token_id = PageCursor {
+            position: OWNER.parse().unwrap(),
+            token_id: Some("1234".to_string()),
+        };
+        assert_eq!(with_token_id.to_string(), format!("{}:1234", OWNER));
+        assert_eq!(with_token_id.to_string().parse(), Ok(with_token_id));
+
+        let without_token_id = PageCursor {
+            position: OWNER.parse().unwrap(),
+            token_id: None,
+        };
+        assert_eq!(without_token_id.to_string(), OWNER);
+        assert_eq!(without_token_id.to_string().parse(), Ok(without_token_id));
+    }
+
+    #[test]
+    fn holder_cursors_round_trip() {
+        let page = PageCursor {
+            position: OWNER.parse().unwrap(),
+            token_id: Some("1234".to_string()),
+        };
+
+        let by_balance = HolderCursor {
+            quantity: Some(1.5),
+            page: page.clone(),
+        };
+        assert_eq!(by_balance.to_string(), format!("1.5@{}:1234", OWNER));
+        assert_eq!(by_balance.to_string().parse(), Ok(by_balance));
+
+        let by_owner = HolderCursor {
+            quantity: None,
+            page,
+        };
+        assert_eq!(by_owner.to_string().parse(), Ok(by_owner));
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        for cursor in [
+            "",
+            ":1234",
+            "0x1234",
+            "not a cursor",
+            "0x70997970c51812dc3a010c7d01b50e0d17dc79c8z",
+        ] {
+            assert!(cursor.parse::<PageCursor>().is_err(), "{}", cursor);
+            assert!(cursor.parse::<HolderCursor>().is_err(), "{}", cursor);
+        }
+
+        for cursor in [
+            format!("many@{}", OWNER),
+            "1.5@".to_string(),
+            format!("1.5@@{}", OWNER),
+            "@".to_string(),
+        ] {
+            assert!(cursor.parse::<HolderCursor>().is_err(), "{}", cursor);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::openapi;
+use hmac::{Hmac, Mac};
+use secp256k1::SecretKey;
+use serde::Serialize;
+use sha2::Sha256;
+use utoipa::ToSchema;
+use web3::{
+    signing::{keccak256, Key, SecretKeyRef},
+    types::H160,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The answer to whether an owner holds a contract's tokens, as evaluated at a block.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub(crate) struct OwnershipAssertion {
+    #[schema(value_type = openapi::Address)]
+    pub owner: H160,
+    #[schema(value_type = openapi::Address)]
+    pub contract_address: H160,
+    pub token_id: Option<String>,
+    /// Quantity the owner must hold at least, any positive quantity when `None`.
+    pub min_quantity: Option<f64>,
+    pub owns: bool,
+    /// Last block applied to the ownership model when the assertion was evaluated, `None` before
+    /// the first block.
+    pub block_number: Option<u64>,
+    /// Unix time in seconds the assertion was issued at.
+    pub issued_at: i64,
+}
+
+impl OwnershipAssertion {
+    /// The canonical form of the assertion that is signed, one `field:value` pair per line.
+    pub(crate) fn message(&self) -> String {
+        format!(
+            "owner:{:#x}\ncontract_address:{:#x}\ntoken_id:{}\nmin_quantity:{}\nowns:{}\nblock_number:{}\nissued_at:{}",
+            self.owner,
+            self.contract_address,
+            self.token_id.as_deref().unwrap_or(""),
+            self.min_quantity
+                .map(|min_quantity| min_quantity.to_string())
+                .unwrap_or_default(),
+            self.owns,
+            self.block_number
+                .map(|block_number| block_number.to_string())
+                .unwrap_or_default(),
+            self.issued_at,
+        )
+    }
+}
+
+/// Signs ownership assertions so token gating integrations can check an answer was issued by the
+/// worker.
+#[derive(Clone)]
+pub struct AssertionSigner {
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for AssertionSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AssertionSigner").finish_non_exhaustive()
+    }
+}
+
+impl AssertionSigner {
+    /// Fails on an empty key.
+    pub fn new(key: String) -> Result<Self, String> {
+        if key.is_empty() {
+            return Err("The verification key must not be empty".to_string());
+        }
+
+        Ok(Self {
+            key: key.into_bytes(),
+        })
+    }
+
+    /// Hex encoded HMAC-SHA256 of the assertion's message.
+    pub(crate) fn sign(&self, assertion: &OwnershipAssertion) -> String {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(assertion.message().as_bytes());
+        format!("0x{}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+/// Signs ownership assertions as EIP-191 `personal_sign` messages with the worker's secp256k1
+/// key, so anyone can recover the signer and check the answer without trusting the transport.
+#[derive(Clone)]
+pub struct AttestationSigner {
+    key: SecretKey,
+    address: H160,
+}
+
+impl std::fmt::Debug for AttestationSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttestationSigner")
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AttestationSigner {
+    /// Parses a hex encoded private key, with or without the `0x` prefix.
+    pub fn new(key: &str) -> Result<Self, String> {
+        let key = hex::decode(key.trim_start_matches("0x"))
+            .ok()
+            .and_then(|key| SecretKey::from_slice(&key).ok())
+            .ok_or_else(|| {
+                "The attestation key must be a hex encoded secp256k1 private key".to_string()
+            })?;
+
+        let address = SecretKeyRef::new(&key).address();
+
+        Ok(Self { key, address })
+    }
+
+    /// Address attestations are recovered to.
+    pub fn address(&self) -> H160 {
+        self.address
+    }
+
+    /// Hex encoded 65 byte `r || s || v` signature of the assertion's message, with `v` being 27
+    /// or 28.
+    pub(crate) fn sign(&self, assertion: &OwnershipAssertion) -> String {
+        let message = assertion.message();
+        let hash = keccak256(
+            &[
+                format!("\x19Ethereum Signed Message:\n{}", message.len()).as_bytes(),
+                message.as_bytes(),
+            ]
+            .concat(),
+        );
+
+        let signature = SecretKeyRef::new(&self.key)
+            .sign(&hash, None)
+            .expect("Keccak256 hashes are 32 bytes long");
+
+        format!(
+            "0x{}{}{:02x}",
+            hex::encode(signature.r),
+            hex::encode(signature.s),
+            signature.v
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web3::signing::recover;
+
+    #[test]
+    fn attestations_recover_to_the_signer() {
+        // The first development account of Hardhat and Anvil.
+        let signer = AttestationSigner::new(
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        )
+        .unwrap();
+        assert_eq!(
+            signer.address(),
+            "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+                .parse()
+                .unwrap()
+        );
+
+        let assertion = OwnershipAssertion {
+            owner: "0x70997970c51812dc3a010c7d01b50e0d17dc79c8"
+                .parse()
+                .unwrap(),
+            contract_address: "0x5fbdb2315678afecb367f032d93f642f64180aa3"
+                .parse()
+                .unwrap(),
+            token_id: Some("1".to_string()),
+            min_quantity: None,
+            owns: true,
+            block_number: Some(17_000_000),
+            issued_at: 1_700_000_000,
+        };
+
+        let signature = hex::decode(signer.sign(&assertion).trim_start_matches("0x")).unwrap();
+        assert_eq!(signature.len(), 65);
+
+        let v = signature[64];
+        assert!(v == 27 || v == 28);
+
+        let message = assertion.message();
+        let hash = keccak256(
+            format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message).as_bytes(),
+        );
+
+        assert_eq!(
+            recover(&hash, &signature[..64], v as i32 - 27).unwrap(),
+            signer.address()
+        );
+    }
+}
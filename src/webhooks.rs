@@ -0,0 +1,174 @@
+#[cfg(any(feature = "api", feature = "webhooks"))]
+use crate::store::ContractStats;
+#[cfg(feature = "webhooks")]
+use crate::store::Store;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "webhooks")]
+use serde_json::json;
+#[cfg(feature = "webhooks")]
+use std::collections::BTreeSet;
+#[cfg(feature = "api")]
+use utoipa::ToSchema;
+use web3::types::H160;
+#[cfg(feature = "webhooks")]
+use web3::types::U64;
+
+/// Contract aggregate a webhook rule watches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookMetric {
+    HolderCount,
+    Supply,
+}
+
+#[cfg(any(feature = "api", feature = "webhooks"))]
+impl WebhookMetric {
+    pub(crate) fn value(self, stats: &ContractStats) -> f64 {
+        match self {
+            WebhookMetric::HolderCount => stats.holder_count as f64,
+            WebhookMetric::Supply => stats.supply,
+        }
+    }
+}
+
+/// When a webhook rule fires.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "api", derive(ToSchema))]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum WebhookCondition {
+    /// The metric moved from one side of the threshold to the other, in either direction.
+    Crosses { threshold: f64 },
+    /// The metric moved by more than `percent` percent since the rule last fired.
+    ChangesBy { percent: f64 },
+}
+
+/// A registered notification, posted to `url` when its condition is met by a committed block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookRule {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub contract_address: H160,
+    pub metric: WebhookMetric,
+    pub condition: WebhookCondition,
+    pub url: String,
+    /// Value the condition is evaluated against: the last value seen for `crosses` rules, and
+    /// the value when the rule last fired for `changes_by` rules.
+    pub reference_value: f64,
+}
+
+#[cfg(feature = "webhooks")]
+impl WebhookRule {
+    fn fires(&self, value: f64) -> bool {
+        match self.condition {
+            WebhookCondition::Crosses { threshold } => {
+                (self.reference_value < threshold) != (value < threshold)
+            }
+            WebhookCondition::ChangesBy { percent } if self.reference_value == 0.0 => {
+                percent >= 0.0 && value != 0.0
+            }
+            WebhookCondition::ChangesBy { percent } => {
+                ((value - self.reference_value) / self.reference_value).abs() * 100.0 > percent
+            }
+        }
+    }
+}
+
+/// Evaluates the webhook rules of the contracts touched by committed blocks.
+#[cfg(feature = "webhooks")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WebhookNotifier {
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "webhooks")]
+impl WebhookNotifier {
+    /// Checks the rules of `contracts` against their current aggregates and posts a notification
+    /// for every rule that fires. Notifications are sent in the background and failures are only
+    /// logged.
+    pub(crate) async fn evaluate(
+        &self,
+        store: &Store,
+        block_number: U64,
+        contracts: BTreeSet<H160>,
+    ) -> mongodb::error::Result<()> {
+        let contracts: Vec<_> = contracts.into_iter().collect();
+
+        let rules = store.webhook_rules(&contracts).await?;
+
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        let stats = store.contract_stats(&contracts).await?;
+
+        for rule in rules {
+            let value = match stats
+                .iter()
+                .find(|stats| stats.contract_address == rule.contract_address)
+            {
+                Some(stats) => rule.metric.value(stats),
+                None => continue,
+            };
+
+            let fires = rule.fires(value);
+
+            let reference_value = match rule.condition {
+                WebhookCondition::Crosses { .. } => value,
+                WebhookCondition::ChangesBy { .. } if fires => value,
+                WebhookCondition::ChangesBy { .. } => rule.reference_value,
+            };
+
+            if reference_value != rule.reference_value {
+                store
+                    .set_webhook_reference_value(rule.id, reference_value)
+                    .await?;
+            }
+
+            if fires {
+                self.notify(&rule, value, block_number);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn notify(&self, rule: &WebhookRule, value: f64, block_number: U64) {
+        println!(
+            "Notifying webhook {} of {:?} {} for contract {:#x}",
+            rule.id, rule.metric, value, rule.contract_address
+        );
+
+        self.post(
+            &rule.url,
+            json!({
+                "rule_id": rule.id.to_hex(),
+                "contract_address": rule.contract_address,
+                "metric": rule.metric,
+                "condition": rule.condition,
+                "previous_value": rule.reference_value,
+                "value": value,
+                "block_number": block_number.as_u64(),
+            }),
+            format!("webhook {}", rule.id),
+        );
+    }
+
+    /// Posts `body` to `url` in the background, logging failures as failures to notify
+    /// `recipient`.
+    pub(crate) fn post(&self, url: &str, body: serde_json::Value, recipient: String) {
+        let request = self.client.post(url).json(&body);
+
+        tokio::spawn(async move {
+            match request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+            {
+                Ok(_) => {}
+                Err(error) => eprintln!("Error: Could not notify {}... {}", recipient, error),
+            }
+        });
+    }
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::*;
+use std::{sync::Arc, time::Duration};
+use token_ownership_worker::{chaos::ChaosConfig, MemoryStore, WorkerConfig};
+
+/// Every fault the standalone worker recovers from, frequent enough to hit each indexing step of the
+/// fixture chain several times.
+fn chaos_config() -> WorkerConfig {
+    WorkerConfig {
+        chaos: ChaosConfig {
+            rpc_failure_rate: 0.1,
+            store_failure_rate: 0.05,
+            duplicate_log_rate: 0.3,
+            reorg_rate: 0.3,
+            ..ChaosConfig::default()
+        },
+        ..config()
+    }
+}
+
+/// Indexes the fixture chain into memory, failing the test when the faults keep the worker from
+/// reaching its last block.
+async fn index_in_memory(reorg: bool, config: WorkerConfig) -> Arc<MemoryStore> {
+    let memory = Arc::new(MemoryStore::new());
+
+    tokio::time::timeout(
+        Duration::from_secs(60),
+        index(serve_chain(reorg).await, memory.clone(), config),
+    )
+    .await
+    .expect("The worker did not index the fixture chain");
+
+    memory
+}
+
+#[tokio::test]
+async fn converges_on_the_fault_free_ownerships() {
+    let fault_free = index_in_memory(false, config()).await;
+
+    for _ in 0..5 {
+        let chaos = index_in_memory(false, chaos_config()).await;
+
+        assert_eq!(chaos.ownerships(), fault_free.ownerships());
+        assert_eq!(chaos.supplies(), fault_free.supplies());
+    }
+    assert_eq!(fault_free.ownerships(), expected_ownerships());
+}
+
+#[tokio::test]
+async fn converges_on_the_fault_free_ownerships_through_a_reorg() {
+    let fault_free = index_in_memory(true, config()).await;
+
+    for _ in 0..5 {
+        let chaos = index_in_memory(true, chaos_config()).await;
+
+        assert_eq!(chaos.ownerships(), fault_free.ownerships());
+        assert_eq!(chaos.supplies(), fault_free.supplies());
+    }
+    assert_eq!(fault_free.ownerships(), expected_ownerships());
+}
@@ -0,0 +1,361 @@
+//! The chain of `fixtures/chain.json` served over JSON-RPC, and the ownerships indexing it leaves.
+//!
+//! The fixture holds blocks 100 to 106 with transfers of an ERC20 token, an ERC721 contract
+//! answering ERC165, one that does not and is classified by its `ownerOf`, an ERC1155 contract and
+//! a contract emitting `Transfer` events of no standard, along with a log that cannot be decoded
+//! and a log the provider returns twice. Blocks 104 and 105 also have orphaned versions.
+
+#![allow(dead_code)]
+
+use axum::{extract::State, routing::post, Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use token_ownership_worker::{
+    Amount, OwnershipKey, OwnershipStore, RetryPolicy, StandaloneWorker, SupplyKey, WorkerConfig,
+};
+use web3::types::{H160, U256, U64};
+
+pub const FIRST_BLOCK: u64 = 100;
+pub const LAST_BLOCK: u64 = 106;
+
+pub const ERC_20: &str = "0x1111111111111111111111111111111111111111";
+pub const ERC_721: &str = "0x2222222222222222222222222222222222222222";
+pub const HEURISTIC_ERC_721: &str = "0x3333333333333333333333333333333333333333";
+pub const ERC_1155: &str = "0x4444444444444444444444444444444444444444";
+pub const UNKNOWN: &str = "0x5555555555555555555555555555555555555555";
+
+pub const ALICE: &str = "0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+pub const BOB: &str = "0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+pub const CAROL: &str = "0xcccccccccccccccccccccccccccccccccccccccc";
+
+const ZERO_HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Deserialize)]
+struct Chain {
+    blocks: Vec<Block>,
+    orphaned_blocks: Vec<Block>,
+    calls: Vec<Call>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Block {
+    number: u64,
+    hash: String,
+    parent_hash: String,
+    logs: Vec<Log>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Log {
+    address: String,
+    topics: Vec<String>,
+    data: String,
+    transaction_hash: String,
+    log_index: u64,
+}
+
+/// The output of an `eth_call` the contracts answer, every other call reverting.
+#[derive(Debug, Deserialize)]
+struct Call {
+    to: String,
+    data: String,
+    result: String,
+}
+
+/// The fixture chain as the provider serves it. Until the last orphaned block is fetched, the
+/// orphaned blocks take the place of the canonical ones and the head is the last of them, and the
+/// canonical chain replaces them from then on, like a reorg.
+#[derive(Debug)]
+struct Provider {
+    chain: Chain,
+    reorged: AtomicBool,
+}
+
+impl Provider {
+    fn blocks(&self) -> Vec<&Block> {
+        if self.reorged.load(Ordering::SeqCst) {
+            return self.chain.blocks.iter().collect();
+        }
+
+        let fork_block = self.chain.orphaned_blocks[0].number;
+
+        self.chain
+            .blocks
+            .iter()
+            .filter(|block| block.number < fork_block)
+            .chain(&self.chain.orphaned_blocks)
+            .collect()
+    }
+
+    fn answer(&self, method: &str, params: &[Value]) -> Result<Value, Value> {
+        match method {
+            "eth_blockNumber" => Ok(json!(format!(
+                "{:#x}",
+                self.blocks().last().unwrap().number
+            ))),
+            "eth_getBlockByNumber" => {
+                let number =
+                    u64::from_str_radix(params[0].as_str().unwrap().trim_start_matches("0x"), 16)
+                        .unwrap();
+
+                Ok(self
+                    .blocks()
+                    .into_iter()
+                    .find(|block| block.number == number)
+                    .map_or(Value::Null, block_json))
+            }
+            "eth_getLogs" => Ok(Value::Array(self.logs(&params[0]))),
+            "eth_call" => {
+                let to = params[0]["to"].as_str().unwrap_or_default();
+                let data = params[0]["data"].as_str().unwrap_or_default();
+
+                self.chain
+                    .calls
+                    .iter()
+                    .find(|call| call.to == to && call.data == data)
+                    .map(|call| json!(call.result))
+                    .ok_or_else(|| json!({ "code": 3, "message": "execution reverted" }))
+            }
+            "eth_getStorageAt" => Ok(json!(ZERO_HASH)),
+            _ => Err(json!({ "code": -32601, "message": "Method not found" })),
+        }
+    }
+
+    fn logs(&self, filter: &Value) -> Vec<Value> {
+        let blocks: Vec<&Block> = match filter["blockHash"].as_str() {
+            Some(block_hash) => {
+                let block = self
+                    .chain
+                    .blocks
+                    .iter()
+                    .chain(&self.chain.orphaned_blocks)
+                    .find(|block| block.hash == block_hash);
+
+                if block.map(|block| &block.hash)
+                    == self.chain.orphaned_blocks.last().map(|block| &block.hash)
+                {
+                    self.reorged.store(true, Ordering::SeqCst);
+                }
+
+                block.into_iter().collect()
+            }
+            None => {
+                let bound = |field: &str| {
+                    u64::from_str_radix(
+                        filter[field].as_str().unwrap().trim_start_matches("0x"),
+                        16,
+                    )
+                    .unwrap()
+                };
+                let (from_block, to_block) = (bound("fromBlock"), bound("toBlock"));
+
+                self.blocks()
+                    .into_iter()
+                    .filter(|block| from_block <= block.number && block.number <= to_block)
+                    .collect()
+            }
+        };
+
+        let addresses: Option<Vec<&str>> = match &filter["address"] {
+            Value::String(address) => Some(vec![address.as_str()]),
+            Value::Array(addresses) => Some(addresses.iter().filter_map(Value::as_str).collect()),
+            _ => None,
+        };
+        let topics: Option<Vec<&str>> = match &filter["topics"][0] {
+            Value::String(topic) => Some(vec![topic.as_str()]),
+            Value::Array(topics) => Some(topics.iter().filter_map(Value::as_str).collect()),
+            _ => None,
+        };
+
+        blocks
+            .into_iter()
+            .flat_map(|block| block.logs.iter().map(move |log| (block, log)))
+            .filter(|(_, log)| {
+                addresses
+                    .as_ref()
+                    .is_none_or(|addresses| addresses.contains(&log.address.as_str()))
+                    && topics
+                        .as_ref()
+                        .is_none_or(|topics| topics.contains(&log.topics[0].as_str()))
+            })
+            .map(|(block, log)| log_json(block, log))
+            .collect()
+    }
+}
+
+fn block_json(block: &Block) -> Value {
+    json!({
+        "hash": block.hash,
+        "parentHash": block.parent_hash,
+        "sha3Uncles": ZERO_HASH,
+        "miner": format!("{:#x}", H160::zero()),
+        "stateRoot": ZERO_HASH,
+        "transactionsRoot": ZERO_HASH,
+        "receiptsRoot": ZERO_HASH,
+        "number": format!("{:#x}", block.number),
+        "gasUsed": "0x0",
+        "gasLimit": "0x0",
+        "extraData": "0x",
+        "logsBloom": format!("0x{}", "00".repeat(256)),
+        "timestamp": format!("{:#x}", 1_650_000_000 + block.number * 12),
+        "difficulty": "0x0",
+        "totalDifficulty": "0x0",
+        "sealFields": [],
+        "uncles": [],
+        "transactions": [],
+        "size": "0x0",
+        "mixHash": ZERO_HASH,
+        "nonce": "0x0000000000000000",
+    })
+}
+
+fn log_json(block: &Block, log: &Log) -> Value {
+    json!({
+        "address": log.address,
+        "topics": log.topics,
+        "data": log.data,
+        "blockHash": block.hash,
+        "blockNumber": format!("{:#x}", block.number),
+        "transactionHash": log.transaction_hash,
+        "transactionIndex": "0x0",
+        "logIndex": format!("{:#x}", log.log_index),
+        "removed": false,
+    })
+}
+
+async fn rpc(State(provider): State<Arc<Provider>>, Json(request): Json<Value>) -> Json<Value> {
+    let params = request["params"].as_array().cloned().unwrap_or_default();
+
+    Json(
+        match provider.answer(request["method"].as_str().unwrap_or_default(), &params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+            Err(error) => json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }),
+        },
+    )
+}
+
+/// Serves the fixture chain on a local port, with the orphaned blocks first when `reorg` is set.
+/// Returns the endpoint of the provider.
+pub async fn serve_chain(reorg: bool) -> String {
+    let chain: Chain = serde_json::from_str(include_str!("../fixtures/chain.json"))
+        .expect("Invalid fixture chain");
+    let provider = Arc::new(Provider {
+        chain,
+        reorged: AtomicBool::new(!reorg),
+    });
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            Router::new().route("/", post(rpc)).with_state(provider),
+        )
+        .await
+        .unwrap()
+    });
+
+    endpoint
+}
+
+/// A reorg depth of 2 and ranges of 2 blocks, so the fixture is indexed in ranges and then block by
+/// block, and retries that do not wait.
+pub fn config() -> WorkerConfig {
+    WorkerConfig {
+        reorg_depth: 2,
+        log_range_size: 2,
+        storage_retry: RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        },
+        ..WorkerConfig::default()
+    }
+}
+
+/// Indexes the fixture chain into the store.
+pub async fn index(endpoint: String, store: Arc<dyn OwnershipStore>, config: WorkerConfig) {
+    StandaloneWorker::new(endpoint, store, config)
+        .await
+        .unwrap()
+        .with_start_block(U64::from(FIRST_BLOCK))
+        .run(Some(U64::from(LAST_BLOCK)))
+        .await;
+}
+
+pub fn address(address: &str) -> H160 {
+    address.parse().unwrap()
+}
+
+fn amount(amount: u64) -> Amount {
+    Amount::from(U256::from(amount))
+}
+
+/// The ownerships of the canonical chain.
+pub fn expected_ownerships() -> BTreeMap<OwnershipKey, Amount> {
+    let ownership = |contract: &str, token_id: Option<&str>, owner: &str, quantity: u64| {
+        (
+            (
+                address(contract),
+                token_id.map(str::to_string),
+                address(owner),
+            ),
+            amount(quantity),
+        )
+    };
+
+    BTreeMap::from([
+        ownership(ERC_20, None, ALICE, 500),
+        ownership(ERC_20, None, BOB, 250),
+        ownership(ERC_20, None, CAROL, 250),
+        ownership(ERC_721, Some("1"), BOB, 1),
+        ownership(ERC_721, Some("2"), CAROL, 1),
+        ownership(HEURISTIC_ERC_721, Some("5"), BOB, 1),
+        ownership(ERC_1155, Some("7"), ALICE, 6),
+        ownership(ERC_1155, Some("7"), BOB, 4),
+        ownership(ERC_1155, Some("8"), ALICE, 5),
+        // Emptied ERC1155 balances are kept, like ERC20 ones.
+        ownership(ERC_1155, Some("8"), CAROL, 0),
+        ownership(ERC_1155, Some("9"), CAROL, 6),
+    ])
+}
+
+/// The supplies of the canonical chain.
+pub fn expected_supplies() -> BTreeMap<SupplyKey, Amount> {
+    let supply = |contract: &str, token_id: Option<&str>, quantity: u64| {
+        (
+            (address(contract), token_id.map(str::to_string)),
+            amount(quantity),
+        )
+    };
+
+    BTreeMap::from([
+        supply(ERC_20, None, 1000),
+        supply(ERC_721, None, 2),
+        supply(HEURISTIC_ERC_721, None, 1),
+        supply(ERC_1155, Some("7"), 10),
+        supply(ERC_1155, Some("8"), 5),
+        supply(ERC_1155, Some("9"), 6),
+    ])
+}
+
+/// How each contract of the fixture chain is classified.
+pub fn expected_token_types() -> HashMap<H160, String> {
+    let token_type = |contract: &str, token_type: &str| (address(contract), token_type.to_string());
+
+    HashMap::from([
+        token_type(ERC_20, "ERC20"),
+        token_type(ERC_721, "ERC721"),
+        token_type(HEURISTIC_ERC_721, "ERC721"),
+        token_type(ERC_1155, "ERC1155"),
+        token_type(UNKNOWN, "UNKNOWN"),
+    ])
+}
@@ -0,0 +1,105 @@
+use arrow_array::{Array, Float64Array, StringArray};
+use arrow_schema::DataType;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::{fs::File, io::BufWriter};
+use token_ownership_worker::{write_holders, Amount, ExportFormat, HolderColumn, SnapshotHolding};
+use web3::types::{H160, U256};
+
+#[test]
+fn reads_back_parquet_holder_exports() {
+    let contract_address: H160 = "0x1111111111111111111111111111111111111111"
+        .parse()
+        .unwrap();
+    let holdings = [
+        SnapshotHolding {
+            token_id: None,
+            owner: "0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+                .parse()
+                .unwrap(),
+            amount: Amount::from(U256::from(1500)),
+            display_amount: Some("1.5".to_string()),
+        },
+        SnapshotHolding {
+            token_id: None,
+            owner: "0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"
+                .parse()
+                .unwrap(),
+            amount: Amount::from(U256::MAX),
+            display_amount: None,
+        },
+    ];
+    let columns = [
+        HolderColumn::TokenId,
+        HolderColumn::Owner,
+        HolderColumn::Amount,
+        HolderColumn::Quantity,
+        HolderColumn::DisplayAmount,
+    ];
+
+    let path = std::env::temp_dir().join(format!("holders-{}.parquet", std::process::id()));
+    let mut file = BufWriter::new(File::create(&path).unwrap());
+    write_holders(
+        contract_address,
+        &holdings,
+        &columns,
+        ExportFormat::Parquet,
+        &mut file,
+    )
+    .unwrap();
+    drop(file);
+
+    let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    let schema = batch.schema();
+    let fields: Vec<_> = schema
+        .fields()
+        .iter()
+        .map(|field| (field.name().as_str(), field.data_type().clone()))
+        .collect();
+    assert_eq!(
+        fields,
+        [
+            ("token_id", DataType::Utf8),
+            ("owner", DataType::Utf8),
+            ("amount", DataType::Utf8),
+            ("quantity", DataType::Float64),
+            ("display_amount", DataType::Utf8),
+        ]
+    );
+
+    let strings = |index: usize| {
+        let column = batch.column(index);
+        let column = column.as_any().downcast_ref::<StringArray>().unwrap();
+        (0..column.len())
+            .map(|row| (!column.is_null(row)).then(|| column.value(row).to_string()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(strings(0), [None, None]);
+    assert_eq!(
+        strings(1),
+        [
+            Some("0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_string()),
+            Some("0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string()),
+        ]
+    );
+    assert_eq!(
+        strings(2),
+        [Some("1500".to_string()), Some(U256::MAX.to_string())]
+    );
+    assert_eq!(strings(4), [Some("1.5".to_string()), None]);
+
+    let quantities = batch
+        .column(3)
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap();
+    assert_eq!(quantities.value(0), 1500.0);
+}
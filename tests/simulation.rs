@@ -0,0 +1,56 @@
+mod common;
+
+use common::*;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use token_ownership_worker::{MemoryStore, SimulationMode, WorkerConfig};
+
+/// RPC traffic of indexing the fixture chain through its reorg, recorded by
+/// `records_the_fixture_chain`.
+fn recording() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/recording.jsonl")
+}
+
+/// Records the fixture again, after the fixture chain or the requests of the worker changed, with
+/// `cargo test --features simulation --test simulation -- --ignored`.
+#[tokio::test]
+#[ignore]
+async fn records_the_fixture_chain() {
+    let _ = std::fs::remove_file(recording());
+    let memory = Arc::new(MemoryStore::new());
+
+    index(
+        serve_chain(true).await,
+        memory.clone(),
+        WorkerConfig {
+            simulation: Some(SimulationMode::Record(recording())),
+            ..config()
+        },
+    )
+    .await;
+
+    assert_eq!(memory.ownerships(), expected_ownerships());
+}
+
+#[tokio::test]
+async fn replays_the_recorded_fixture_chain() {
+    let memory = Arc::new(MemoryStore::new());
+
+    // Nothing listens on the discard port, so any request missing from the recording fails.
+    tokio::time::timeout(
+        Duration::from_secs(60),
+        index(
+            "http://127.0.0.1:9".to_string(),
+            memory.clone(),
+            WorkerConfig {
+                simulation: Some(SimulationMode::Replay(recording())),
+                ..config()
+            },
+        ),
+    )
+    .await
+    .expect("The replay did not index the fixture chain");
+
+    assert_eq!(memory.ownerships(), expected_ownerships());
+    assert_eq!(memory.supplies(), expected_supplies());
+    assert_eq!(memory.token_types(), expected_token_types());
+}
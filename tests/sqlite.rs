@@ -0,0 +1,38 @@
+mod common;
+
+use common::*;
+use std::sync::Arc;
+use token_ownership_worker::{OwnershipStore, SqliteStore};
+use web3::types::U64;
+
+#[tokio::test]
+async fn indexes_the_fixture_chain_into_sqlite() {
+    let path = std::env::temp_dir().join(format!("ownerships-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let sqlite = Arc::new(SqliteStore::open(&path).await.unwrap());
+
+    index(serve_chain(true).await, sqlite.clone(), config()).await;
+
+    for ((contract_address, token_id, owner), amount) in expected_ownerships() {
+        assert_eq!(
+            sqlite
+                .ownership_amount(contract_address, token_id.as_deref(), owner)
+                .await
+                .unwrap(),
+            amount
+        );
+    }
+    assert_eq!(
+        sqlite.checkpoint().await.unwrap(),
+        Some(U64::from(LAST_BLOCK))
+    );
+
+    for (contract_address, token_type) in expected_token_types() {
+        assert_eq!(
+            sqlite.token_type(contract_address).await.unwrap(),
+            Some(token_type)
+        );
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
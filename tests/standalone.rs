@@ -0,0 +1,34 @@
+mod common;
+
+use common::*;
+use std::sync::Arc;
+use token_ownership_worker::MemoryStore;
+
+#[tokio::test]
+async fn indexes_the_fixture_chain() {
+    let memory = Arc::new(MemoryStore::new());
+
+    index(serve_chain(false).await, memory.clone(), config()).await;
+
+    assert_eq!(memory.ownerships(), expected_ownerships());
+    assert_eq!(memory.supplies(), expected_supplies());
+}
+
+#[tokio::test]
+async fn rolls_back_orphaned_blocks() {
+    let memory = Arc::new(MemoryStore::new());
+
+    index(serve_chain(true).await, memory.clone(), config()).await;
+
+    assert_eq!(memory.ownerships(), expected_ownerships());
+    assert_eq!(memory.supplies(), expected_supplies());
+}
+
+#[tokio::test]
+async fn classifies_contracts() {
+    let memory = Arc::new(MemoryStore::new());
+
+    index(serve_chain(false).await, memory.clone(), config()).await;
+
+    assert_eq!(memory.token_types(), expected_token_types());
+}